@@ -0,0 +1,1317 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Facade to the blockchain, handling the chain state and serializing access
+//! to the block processing pipeline.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use byteorder::{BigEndian, ByteOrder};
+use secp::pedersen::Commitment;
+use time;
+
+use core::consensus;
+use core::core::{Block, BlockHeader, OutputFeatures};
+use core::core::transaction::output_hash;
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
+use core::core::mmr::{MerkleProof, MountainRange};
+use core::genesis::{ChainParams, ChainTypes};
+use core::global;
+use core::ser;
+use grin_store;
+use checkpoints::Checkpoints;
+use deferred::DeferredVerifier;
+use headers::HeaderCache;
+use pool::PowPool;
+use pipe;
+use snapshot::Snapshot;
+use store::{CachedChainStore, ChainKVStore};
+use types::{self, Tip, BlockStatus, BlockProvider, ChainStore, ChainStoreRead, ChainStoreWrite,
+            ChainBatch, ChainAdapter, AncestorIter, DifficultyIter, ReadOnlyStore, Direction,
+            NoopAdapter, OutputRecord};
+use unspent::{StoreNodes, UnspentView};
+use util::LruCache;
+
+/// Number of recently processed block hashes kept around to fast-reject
+/// duplicates.
+const RECENT_BLOCKS_CAP: usize = 1000;
+
+/// Number of blocks known to be invalid kept around to fast-reject them,
+/// along with the reason.
+const INVALID_BLOCKS_CAP: usize = 1000;
+
+/// Maximum number of blocks received before their parent we keep around,
+/// waiting for the parent.
+const MAX_ORPHANS: usize = 100;
+
+/// Number of blocks found to be ancestors of a checkpointed block kept
+/// around, so they don't have to be linked to the checkpoint again.
+const CHECKPOINTED_CAP: usize = 10_000;
+
+/// Number of blocks behind the highest one whose headers are kept in memory
+/// for validation.
+const HEADER_CACHE_WINDOW: u64 = 100;
+
+/// Default number of headers the store opened by `Chain::init` keeps in
+/// memory, recently read or written.
+const STORE_CACHED_HEADERS: usize = 1000;
+
+/// Default number of blocks the store opened by `Chain::init` keeps in memory,
+/// recently read or written, mostly for peers asking for the latest ones.
+const STORE_CACHED_BLOCKS: usize = 50;
+
+/// Number of most recent blocks included one after the other in a locator,
+/// before the gaps between them start doubling.
+const LOCATOR_DENSE: usize = 10;
+
+/// Maximum number of blocks too far in the future we hold until they're not.
+const MAX_FUTURE_BLOCKS: usize = 50;
+
+/// Maximum number of blocks queued for processing on the chain thread, see
+/// `Chain::queue_block`.
+const MAX_QUEUED_BLOCKS: usize = 64;
+
+/// How far past the pipeline limit a block can be in the future and still be
+/// held for later, in seconds. Anything further ahead gets refused.
+const FUTURE_HOLD_LIMIT: i64 = 2 * pipe::FUTURE_TIME_LIMIT;
+
+/// Number of main chain blocks back from the head the statistics on block
+/// intervals cover, see `Chain::stats`.
+const STATS_INTERVALS: usize = 60;
+
+/// Configuration of the chain, mostly how far it's willing to trust what it
+/// already has.
+#[derive(Debug, Clone)]
+pub struct ChainConfig {
+	/// Network the chain is for, with its genesis block and consensus
+	/// parameters
+	pub params: ChainParams,
+	/// Blocks known to be on the main chain
+	pub checkpoints: Checkpoints,
+	/// Maximum number of blocks of our head a reorganization can undo. Any fork
+	/// branching off further back gets refused, even if heavier, as the blocks
+	/// the horizon covers are considered final.
+	pub max_reorg_depth: u64,
+	/// Number of threads verifying proofs of work, blocks queued for
+	/// processing get theirs verified in parallel ahead of time
+	pub pow_threads: usize,
+	/// Height below which compaction prunes the bodies of main chain blocks,
+	/// keeping only their headers. Blocks within the maximum reorg depth of
+	/// the head always keep their body. None keeps all bodies.
+	pub archive_height: Option<u64>,
+	/// Database the chain is stored in when opened with `Chain::init`
+	pub store_backend: grin_store::Backend,
+	/// Directory of a backup, as written by `Chain::backup`, replacing the
+	/// store when opened with `Chain::init`
+	pub restore_from: Option<String>,
+	/// Whether to check the store for broken records, and move them out of
+	/// the way, when opened with `Chain::init` after the node didn't shut
+	/// down cleanly
+	pub check_after_crash: bool,
+	/// Number of headers and of blocks kept in memory by the store opened
+	/// with `Chain::init`, see `CachedChainStore`
+	pub store_cache_sizes: (usize, usize),
+	/// Height up to which blocks processed with the SYNC option get their
+	/// range proofs verified in the background, a block failing those then
+	/// getting undone. None for the height of the latest checkpoint.
+	pub sync_height: Option<u64>,
+}
+
+impl ChainConfig {
+	/// Default configuration of a chain for the provided network. Only the
+	/// main network has hard-coded checkpoints.
+	pub fn for_chain(chain_type: ChainTypes) -> ChainConfig {
+		let params = chain_type.params();
+		let checkpoints = match chain_type {
+			ChainTypes::Mainnet => Checkpoints::hard_coded(),
+			_ => Checkpoints::new(vec![]),
+		};
+		ChainConfig {
+			max_reorg_depth: params.cut_through_horizon,
+			params: params,
+			checkpoints: checkpoints,
+			pow_threads: 2,
+			archive_height: None,
+			store_backend: grin_store::Backend::default(),
+			restore_from: None,
+			check_after_crash: false,
+			store_cache_sizes: (STORE_CACHED_HEADERS, STORE_CACHED_BLOCKS),
+			sync_height: None,
+		}
+	}
+}
+
+/// Default configuration of a chain for the chain type of the process, see
+/// `core::global`.
+impl Default for ChainConfig {
+	fn default() -> ChainConfig {
+		ChainConfig::for_chain(global::chain_type())
+	}
+}
+
+/// Where the stored chain stopped being valid, see `Chain::validate`.
+#[derive(Debug, Clone)]
+pub struct Inconsistency {
+	/// Height of the first invalid block
+	pub height: u64,
+	/// Hash of the first invalid block
+	pub hash: Hash,
+	/// Why the block isn't valid
+	pub error: pipe::Error,
+}
+
+/// State of the chain at some point, for status reporting, see
+/// `Chain::stats`.
+#[derive(Debug, Clone)]
+pub struct ChainStats {
+	/// Height of the chain head
+	pub height: u64,
+	/// Total difficulty of the chain head
+	pub total_difficulty: Difficulty,
+	/// Number of orphans held until their parent shows up
+	pub orphans: usize,
+	/// Space the chain store takes on disk, in bytes
+	pub store_size: u64,
+	/// Time between the most recent blocks of the main chain
+	pub intervals: BlockIntervals,
+	/// Time spent validating and saving the blocks processed so far
+	pub pipeline: pipe::PipelineStats,
+}
+
+/// Statistics on the time between consecutive blocks, going by their
+/// timestamps, all in seconds. Intervals are negative for blocks timestamped
+/// before their parent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockIntervals {
+	/// Number of intervals the statistics are over
+	pub count: u64,
+	/// Mean interval
+	pub mean: i64,
+	/// Shortest interval
+	pub min: i64,
+	/// Longest interval
+	pub max: i64,
+}
+
+/// Facade to the blockchain block processing pipeline and storage. Keeps the
+/// reference copy of the chain head and makes sure blocks are processed one
+/// at a time, so it can safely be shared between the threads handling our
+/// peers and the miner. Clones share the same chain, the thread processing
+/// queued blocks ending once they're all dropped.
+#[derive(Clone)]
+pub struct Chain {
+	store: Arc<ChainStore>,
+	adapter: Arc<ChainAdapter>,
+	head: Arc<Mutex<Tip>>,
+	block_process_lock: Arc<Mutex<()>>,
+	recent_blocks: Arc<Mutex<LruCache<Hash, ()>>>,
+	invalid_blocks: Arc<Mutex<LruCache<Hash, pipe::Error>>>,
+	// orphans with the peer they came from
+	orphans: Arc<Mutex<LruCache<Hash, (Block, Option<SocketAddr>)>>>,
+	// blocks from the future, ordered by timestamp
+	future_blocks: Arc<Mutex<BTreeMap<(i64, Hash), (Block, pipe::Options, Option<SocketAddr>)>>>,
+	params: Arc<ChainParams>,
+	checkpoints: Arc<Checkpoints>,
+	checkpointed: Arc<Mutex<LruCache<Hash, ()>>>,
+	max_reorg_depth: u64,
+	archive_height: Option<u64>,
+	sync_height: u64,
+	deferred: Arc<DeferredVerifier>,
+	headers: Arc<HeaderCache>,
+	pow: Arc<PowPool>,
+	stats: Arc<Mutex<pipe::PipelineStats>>,
+	// none for the clone owned by the chain thread, so the thread ends once
+	// all the other clones are gone
+	queue: Option<Arc<BlockQueue>>,
+	head_subscribers: Arc<Mutex<Vec<Sender<Tip>>>>,
+}
+
+// a block waiting for the chain thread, along with where its result goes
+struct QueuedBlock {
+	block: Block,
+	opts: pipe::Options,
+	source: Option<SocketAddr>,
+	result: Sender<Result<BlockStatus, pipe::Error>>,
+}
+
+// queue of the blocks waiting for the chain thread, closed when dropped,
+// waiting for the thread to finish the block it's on
+struct BlockQueue {
+	sender: Mutex<Option<SyncSender<QueuedBlock>>>,
+	thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for BlockQueue {
+	fn drop(&mut self) {
+		self.sender.lock().unwrap().take();
+		if let Some(thread) = self.thread.lock().unwrap().take() {
+			if thread.thread().id() != thread::current().id() {
+				let _ = thread.join();
+			}
+		}
+	}
+}
+
+/// Handle to the result of a block queued for processing, see
+/// `Chain::queue_block`.
+pub struct BlockHandle {
+	result: Receiver<Result<BlockStatus, pipe::Error>>,
+}
+
+impl BlockHandle {
+	/// Waits until the block has been processed and returns the result.
+	pub fn wait(self) -> Result<BlockStatus, pipe::Error> {
+		self.result.recv().expect("chain processing thread is gone")
+	}
+
+	/// Returns the result if the block has been processed already.
+	pub fn try_result(&self) -> Option<Result<BlockStatus, pipe::Error>> {
+		self.result.try_recv().ok()
+	}
+}
+
+impl Chain {
+	/// Opens the chain stored under the provided directory, starting it from
+	/// the genesis block of the configured network if there's nothing there
+	/// yet. Fails if the stored chain has another genesis, being for another
+	/// network.
+	pub fn init(db_root: String,
+	            adapter: Arc<ChainAdapter>,
+	            config: ChainConfig)
+	            -> Result<Chain, types::Error> {
+		if let Some(ref backup) = config.restore_from {
+			info!("Restoring the chain store from the backup in {}.", backup);
+			try!(ChainKVStore::restore(&db_root, backup, config.store_backend));
+		}
+		let store = try!(ChainKVStore::with_backend(db_root, config.store_backend));
+		if config.check_after_crash && store.unclean_shutdown() {
+			warn!("Chain store wasn't closed cleanly, checking its records.");
+			let broken = try!(store.check_records(true));
+			if !broken.is_empty() {
+				warn!("Quarantined {} broken records of the chain store.", broken.len());
+			}
+		}
+
+		// check if we have a head in store, otherwise the genesis block is it
+		let genesis = config.params.genesis.hash();
+		match store.head() {
+			Ok(_) => {}
+			Err(types::Error::NotFoundErr) => {
+				debug!("No genesis block found, saving the one of {:?}.",
+				       config.params.chain_type);
+				let batch = store.batch();
+				try!(batch.save_block(&config.params.genesis));
+				try!(batch.save_head(&Tip::new(genesis)));
+				try!(batch.commit());
+			}
+			Err(e) => return Err(e),
+		}
+		let (headers, blocks) = config.store_cache_sizes;
+		let store = CachedChainStore::new(store, headers, blocks);
+		let chain = try!(Chain::with_config(Arc::new(store), adapter, config));
+		let stored = try!(chain.get_header_by_height(0)).hash();
+		if stored != genesis {
+			return Err(types::Error::StorageErr(format!("stored chain starts at genesis {} instead \
+			                                             of {}",
+			                                            stored,
+			                                            genesis)));
+		}
+		Ok(chain)
+	}
+
+	/// Builds a new chain on top of the provided store, which is expected to
+	/// already have a head (at least the genesis block).
+	pub fn new(store: Arc<ChainStore>, adapter: Arc<ChainAdapter>) -> Result<Chain, types::Error> {
+		Chain::with_config(store, adapter, ChainConfig::default())
+	}
+
+	/// Builds a new chain like new, validating blocks against the provided
+	/// checkpoints instead of the hard-coded ones.
+	pub fn with_checkpoints(store: Arc<ChainStore>,
+	                        adapter: Arc<ChainAdapter>,
+	                        checkpoints: Checkpoints)
+	                        -> Result<Chain, types::Error> {
+		let config = ChainConfig { checkpoints: checkpoints, ..ChainConfig::default() };
+		Chain::with_config(store, adapter, config)
+	}
+
+	/// Builds a new chain like new, with the provided configuration.
+	pub fn with_config(store: Arc<ChainStore>,
+	                   adapter: Arc<ChainAdapter>,
+	                   config: ChainConfig)
+	                   -> Result<Chain, types::Error> {
+		let head = try!(store.head());
+		try!(index_heights(&*store, &head));
+		let (queue_tx, queue_rx) = sync_channel(MAX_QUEUED_BLOCKS);
+		let sync_height = config.sync_height.unwrap_or(config.checkpoints.last_height());
+		let chain = Chain {
+			store: store,
+			adapter: adapter,
+			head: Arc::new(Mutex::new(head)),
+			block_process_lock: Arc::new(Mutex::new(())),
+			recent_blocks: Arc::new(Mutex::new(LruCache::new(RECENT_BLOCKS_CAP))),
+			invalid_blocks: Arc::new(Mutex::new(LruCache::new(INVALID_BLOCKS_CAP))),
+			orphans: Arc::new(Mutex::new(LruCache::new(MAX_ORPHANS))),
+			future_blocks: Arc::new(Mutex::new(BTreeMap::new())),
+			params: Arc::new(config.params),
+			checkpoints: Arc::new(config.checkpoints),
+			checkpointed: Arc::new(Mutex::new(LruCache::new(CHECKPOINTED_CAP))),
+			max_reorg_depth: config.max_reorg_depth,
+			archive_height: config.archive_height,
+			sync_height: sync_height,
+			deferred: Arc::new(DeferredVerifier::new()),
+			headers: Arc::new(HeaderCache::new(HEADER_CACHE_WINDOW)),
+			pow: Arc::new(PowPool::new(config.pow_threads)),
+			stats: Arc::new(Mutex::new(pipe::PipelineStats::default())),
+			queue: None,
+			head_subscribers: Arc::new(Mutex::new(vec![])),
+		};
+
+		let queue_chain = chain.clone();
+		let thread = thread::Builder::new()
+			.name("chain_queue".to_string())
+			.spawn(move || queue_chain.process_queue(queue_rx))
+			.unwrap();
+		Ok(Chain {
+			queue: Some(Arc::new(BlockQueue {
+				sender: Mutex::new(Some(queue_tx)),
+				thread: Mutex::new(Some(thread)),
+			})),
+			..chain
+		})
+	}
+
+	/// Attempt to add a new block to the chain. Returns what happened to the
+	/// block, whether it extended the chain head, went on a fork or is kept for
+	/// later. Orphans are kept until their parent gets added, at which point
+	/// they're processed as well and the status of the last one to change the
+	/// head is returned. Blocks a little too far in the future are kept until
+	/// their time comes.
+	///
+	/// Takes either a full block or a provider of its body, only asked for when
+	/// the header is valid or the block needs to be kept for later.
+	pub fn process_block<P: BlockProvider>(&self,
+	                                       p: P,
+	                                       opts: pipe::Options)
+	                                       -> Result<BlockStatus, pipe::Error> {
+		self.process_block_from(p, opts, None)
+	}
+
+	/// Same as process_block for a block received from the provided peer. The
+	/// peer is passed along to the chain adapter with the outcome.
+	pub fn process_block_from<P: BlockProvider>(&self,
+	                                            mut p: P,
+	                                            opts: pipe::Options,
+	                                            source: Option<SocketAddr>)
+	                                            -> Result<BlockStatus, pipe::Error> {
+		// only one block goes through the pipeline at a time, otherwise two blocks
+		// could be appended to the same head concurrently
+		let _lock = self.block_process_lock.lock().unwrap();
+		self.undo_failed_deferred();
+
+		// held blocks could be what this one builds on
+		self.process_ready_future_blocks();
+		self.process_block_cascade(&mut p, opts, source)
+	}
+
+	/// Queues the block to be processed on the chain thread instead of the
+	/// caller's, returning a handle to get the result once done. Fails right
+	/// away with a QueueFull error if too many blocks are already waiting.
+	/// The proof of work of a queued block starts being verified right away,
+	/// in parallel with the blocks ahead of it. The source is the peer the
+	/// block came from, if any.
+	pub fn queue_block(&self,
+	                   b: Block,
+	                   opts: pipe::Options,
+	                   source: Option<SocketAddr>)
+	                   -> Result<BlockHandle, pipe::Error> {
+		let header = b.header.clone();
+		let (result_tx, result_rx) = channel();
+		let queued = QueuedBlock {
+			block: b,
+			opts: opts,
+			source: source,
+			result: result_tx,
+		};
+		let sender = self.queue.as_ref().and_then(|q| q.sender.lock().unwrap().clone());
+		match sender.map(|s| s.try_send(queued)) {
+			Some(Ok(())) => {
+				self.pow.submit(&header, pipe::cuckoo_size(&header, opts));
+				Ok(BlockHandle { result: result_rx })
+			}
+			Some(Err(TrySendError::Full(_))) |
+			Some(Err(TrySendError::Disconnected(_))) |
+			None => Err(pipe::Error::QueueFull),
+		}
+	}
+
+	// processes queued blocks one after the other, until the queue is closed
+	// with the last clone of the chain outside of the chain thread
+	fn process_queue(&self, queue: Receiver<QueuedBlock>) {
+		for queued in queue {
+			let res = self.process_block_from(&queued.block, queued.opts, queued.source);
+			// nobody may be waiting for the result anymore
+			let _ = queued.result.send(res);
+		}
+	}
+
+	/// Processes the blocks that were too far in the future when received but
+	/// aren't anymore. Already done every time a new block is processed.
+	pub fn process_future_blocks(&self) {
+		let _lock = self.block_process_lock.lock().unwrap();
+		self.process_ready_future_blocks();
+	}
+
+	fn process_ready_future_blocks(&self) {
+		let limit = time::now_utc().to_timespec().sec + pipe::FUTURE_TIME_LIMIT;
+		let ready = {
+			let mut future_blocks = self.future_blocks.lock().unwrap();
+			let keys = future_blocks.keys()
+				.take_while(|&&(ts, _)| ts <= limit)
+				.cloned()
+				.collect::<Vec<_>>();
+			keys.iter().filter_map(|k| future_blocks.remove(k)).collect::<Vec<_>>()
+		};
+		for (b, opts, source) in ready {
+			match self.process_block_cascade(&mut &b, opts, source) {
+				Ok(status) => debug!("Future block {} processed: {:?}", b.hash(), status),
+				Err(e) => debug!("Future block {} rejected: {:?}", b.hash(), e),
+			}
+		}
+	}
+
+	// processes the block and any orphan that was waiting for it, or holds on
+	// to it for later when it can't be processed yet
+	fn process_block_cascade(&self,
+	                         p: &mut BlockProvider,
+	                         opts: pipe::Options,
+	                         source: Option<SocketAddr>)
+	                         -> Result<BlockStatus, pipe::Error> {
+		let mut status = match self.process_block_single(p, opts, source) {
+			Ok(status) => status,
+			Err(pipe::Error::Orphan) => {
+				let b = try!(p.body()).clone();
+				self.orphans.lock().unwrap().insert(b.hash(), (b, source));
+				return Ok(BlockStatus::Orphan);
+			}
+			Err(pipe::Error::FutureBlock) => return self.hold_future_block(p, opts, source),
+			Err(e) => return Err(e),
+		};
+
+		// any orphan building on an added block can now be processed as well,
+		// which can in turn unlock more orphans
+		let mut added = vec![p.header().hash()];
+		while let Some(h) = added.pop() {
+			for (orphan, orphan_source) in self.take_orphans(h) {
+				match self.process_block_single(&mut &orphan, opts, orphan_source) {
+					Ok(orphan_status) => {
+						if orphan_status.head().is_some() {
+							status = orphan_status;
+						}
+						added.push(orphan.hash());
+					}
+					Err(e) => {
+						debug!("Orphan block {} rejected: {:?}", orphan.hash(), e);
+					}
+				}
+			}
+		}
+		Ok(status)
+	}
+
+	// keeps a block from the future until it can be processed, unless it's way
+	// too far ahead
+	fn hold_future_block(&self,
+	                     p: &mut BlockProvider,
+	                     opts: pipe::Options,
+	                     source: Option<SocketAddr>)
+	                     -> Result<BlockStatus, pipe::Error> {
+		let ts = p.header().timestamp.to_timespec().sec;
+		if ts > time::now_utc().to_timespec().sec + FUTURE_HOLD_LIMIT {
+			self.adapter.block_rejected(p.header(), &pipe::Error::FutureBlock, source);
+			return Err(pipe::Error::FutureBlock);
+		}
+		let b = try!(p.body());
+		let mut future_blocks = self.future_blocks.lock().unwrap();
+		future_blocks.insert((ts, b.hash()), (b.clone(), opts, source));
+		if future_blocks.len() > MAX_FUTURE_BLOCKS {
+			// drop whatever is the furthest ahead
+			let last = future_blocks.keys().next_back().cloned().unwrap();
+			future_blocks.remove(&last);
+		}
+		Ok(BlockStatus::Future)
+	}
+
+	fn process_block_single(&self,
+	                        p: &mut BlockProvider,
+	                        opts: pipe::Options,
+	                        source: Option<SocketAddr>)
+	                        -> Result<BlockStatus, pipe::Error> {
+		let head = try!(self.store.head().map_err(&pipe::Error::StoreErr));
+		let mut ctx = self.ctx_from_head(head, self.adapter.clone(), opts);
+		ctx.source = source;
+
+		let res = pipe::process_block(p, ctx);
+		if let Some(tip) = res.as_ref().ok().and_then(|s| s.head()) {
+			let mut head = self.head.lock().unwrap();
+			*head = tip.clone();
+			self.notify_head(tip);
+		}
+		res
+	}
+
+	// sends the new head to all subscribers, forgetting those that went away
+	fn notify_head(&self, tip: &Tip) {
+		let mut subscribers = self.head_subscribers.lock().unwrap();
+		subscribers.retain(|s| s.send(tip.clone()).is_ok());
+	}
+
+	// removes and returns all the orphans whose parent is the provided block
+	fn take_orphans(&self, prev: Hash) -> Vec<(Block, Option<SocketAddr>)> {
+		let mut orphans = self.orphans.lock().unwrap();
+		let children = orphans.iter()
+			.filter(|&(_, &(ref b, _))| b.header.previous == prev)
+			.map(|(h, _)| *h)
+			.collect::<Vec<Hash>>();
+		children.iter().filter_map(|h| orphans.remove(h)).collect()
+	}
+
+	/// Attempt to add a new header to the header chain. Returns the new header
+	/// chain tip if updated.
+	pub fn process_block_header(&self,
+	                            bh: &BlockHeader,
+	                            opts: pipe::Options)
+	                            -> Result<Option<Tip>, pipe::Error> {
+		let _lock = self.block_process_lock.lock().unwrap();
+
+		let head = try!(self.store.get_header_head().map_err(&pipe::Error::StoreErr));
+		let ctx = self.ctx_from_head(head, Arc::new(NoopAdapter {}), opts);
+
+		pipe::process_block_header(bh, ctx)
+	}
+
+	fn ctx_from_head(&self,
+	                 head: Tip,
+	                 adapter: Arc<ChainAdapter>,
+	                 opts: pipe::Options)
+	                 -> pipe::BlockContext {
+		pipe::BlockContext {
+			opts: opts,
+			store: self.store.clone(),
+			adapter: adapter,
+			source: None,
+			head: head,
+			tip: None,
+			recent_blocks: self.recent_blocks.clone(),
+			invalid_blocks: self.invalid_blocks.clone(),
+			params: self.params.clone(),
+			checkpoints: self.checkpoints.clone(),
+			checkpointed: self.checkpointed.clone(),
+			max_reorg_depth: self.max_reorg_depth,
+			sync_height: self.sync_height,
+			deferred: self.deferred.clone(),
+			headers: self.headers.clone(),
+			pow: self.pow.clone(),
+			times: pipe::StageTimes::default(),
+			stats: self.stats.clone(),
+		}
+	}
+
+	/// Walks the whole stored chain from the genesis to the head, validating
+	/// every block again with the provided options and recomputing the total
+	/// difficulty along the way. Returns the first inconsistency found, if any.
+	/// No block gets processed in the meantime.
+	pub fn validate(&self, opts: pipe::Options) -> Result<(), Inconsistency> {
+		let _lock = self.block_process_lock.lock().unwrap();
+		let head = self.head();
+		let inconsistency = |height: u64, hash: Hash, e: pipe::Error| {
+			error!("Stored chain invalid at {} with hash {}: {:?}", height, hash, e);
+			Inconsistency {
+				height: height,
+				hash: hash,
+				error: e,
+			}
+		};
+
+		// blocks only point to their parent, walk back from the head first
+		let mut hashes = vec![];
+		let mut h = head.last_block_h;
+		for height in (1..head.height + 1).rev() {
+			let header = try!(self.store
+				.get_block_header(&h)
+				.map_err(|e| inconsistency(height, h, pipe::Error::StoreErr(e))));
+			if header.height != height {
+				return Err(inconsistency(height, h, pipe::Error::InvalidBlockHeight));
+			}
+			hashes.push(h);
+			h = header.previous;
+		}
+		let genesis = try!(self.store
+			.get_block_header(&h)
+			.map_err(|e| inconsistency(0, h, pipe::Error::StoreErr(e))));
+
+		let mut ctx = self.ctx_from_head(head.clone(), Arc::new(NoopAdapter {}), opts);
+		let mut total_difficulty = genesis.total_difficulty;
+		let mut pruned = true;
+		for (n, h) in hashes.into_iter().rev().enumerate() {
+			let height = n as u64 + 1;
+			let header = match self.store.get_block(&h) {
+				// the bodies of the oldest blocks may have been pruned, only their
+				// headers can be checked
+				Err(types::Error::NotFoundErr) if pruned => {
+					let header = try!(self.store
+						.get_block_header(&h)
+						.map_err(|e| inconsistency(height, h, pipe::Error::StoreErr(e))));
+					try!(pipe::revalidate_header(&header, &mut ctx)
+						.map_err(|e| inconsistency(height, h, e)));
+					header
+				}
+				res => {
+					pruned = false;
+					let b = try!(res.map_err(|e| inconsistency(height, h, pipe::Error::StoreErr(e))));
+					if b.hash() != h {
+						let e = types::Error::StorageErr("block doesn't match its hash".to_string());
+						return Err(inconsistency(height, h, pipe::Error::StoreErr(e)));
+					}
+					try!(pipe::revalidate_block(&b, &mut ctx).map_err(|e| inconsistency(height, h, e)));
+					b.header
+				}
+			};
+			total_difficulty = total_difficulty + header.difficulty.clone();
+			if total_difficulty != header.total_difficulty {
+				return Err(inconsistency(height, h, pipe::Error::WrongTotalDifficulty));
+			}
+		}
+		if total_difficulty != head.total_difficulty {
+			return Err(inconsistency(head.height,
+			                         head.last_block_h,
+			                         pipe::Error::WrongTotalDifficulty));
+		}
+		Ok(())
+	}
+
+	/// Rolls the chain back to the block at the provided height on the main
+	/// chain, undoing everything the blocks above it did to the head, the
+	/// indices and the output set in one go. The blocks stay in store and can
+	/// be processed again. Reorgs do the same within their batch, this is
+	/// mostly for operators to get away from a corrupted head.
+	pub fn rewind_to(&self, height: u64) -> Result<Tip, pipe::Error> {
+		let _lock = self.block_process_lock.lock().unwrap();
+		self.rewind_head_to(height)
+	}
+
+	// rolls the head back, the block processing lock being held
+	fn rewind_head_to(&self, height: u64) -> Result<Tip, pipe::Error> {
+		let head = self.head();
+		if height > head.height {
+			return Err(pipe::Error::Unfit("can't rewind past the head".to_string()));
+		}
+		let to = try!(self.store.get_header_by_height(height).map_err(&pipe::Error::StoreErr));
+		let mut undone = vec![];
+		for h in (height + 1)..(head.height + 1) {
+			undone.push(try!(self.store.get_header_by_height(h).map_err(&pipe::Error::StoreErr)).hash());
+		}
+
+		let ctx = self.ctx_from_head(head, self.adapter.clone(), pipe::NONE);
+		let tip = try!(pipe::rewind_head(&to, &ctx));
+		*self.head.lock().unwrap() = tip.clone();
+		self.notify_head(&tip);
+
+		// so the undone blocks aren't fast-rejected if they come again
+		let mut recent_blocks = self.recent_blocks.lock().unwrap();
+		for h in &undone {
+			recent_blocks.remove(h);
+		}
+		Ok(tip)
+	}
+
+	/// Writes a snapshot of the chain state as of our head, see the snapshot
+	/// module. Block bodies aren't included.
+	pub fn export_snapshot(&self, w: &mut Write) -> Result<(), types::Error> {
+		let _lock = self.block_process_lock.lock().unwrap();
+		let head = self.head();
+		let mut headers = vec![];
+		for height in 0..(head.height + 1) {
+			headers.push(try!(self.store.get_header_by_height(height)));
+		}
+		let mut outputs = vec![];
+		for (h, pos) in try!(self.store.get_unspent_outputs()) {
+			let leaf = try!(self.store.get_output_leaf(&h));
+			outputs.push((h, pos, leaf));
+		}
+		let snapshot = Snapshot {
+			headers: headers,
+			mmr_size: try!(self.store.get_output_mmr_size()),
+			outputs: outputs,
+			kernels: try!(self.store.get_kernels()),
+			recent_kernels: try!(self.store.get_recent_kernels()),
+		};
+		ser::serialize(w, &snapshot).map_err(|e| types::Error::StorageErr(format!("{:?}", e)))
+	}
+
+	/// Imports a snapshot of the chain state as of the block with the
+	/// provided hash, obtained from a source we trust to tell the block is on
+	/// the main chain. The snapshot headers go through validation like any
+	/// other and the outputs have to match the output MMR the block commits
+	/// to. Only works on a chain that's still at its genesis, the head becomes
+	/// the snapshot block. Bodies of the blocks up to it are never needed.
+	pub fn import_snapshot(&self,
+	                       r: &mut Read,
+	                       trusted: Hash,
+	                       opts: pipe::Options)
+	                       -> Result<Tip, pipe::Error> {
+		let _lock = self.block_process_lock.lock().unwrap();
+		if self.head().height > 0 {
+			return Err(pipe::Error::Unfit("chain already past its genesis".to_string()));
+		}
+		let snapshot: Snapshot = try!(ser::deserialize(r)
+			.map_err(|e| pipe::Error::InvalidSnapshot(format!("{:?}", e))));
+		let genesis = self.head().last_block_h;
+		match (snapshot.headers.first(), snapshot.headers.last()) {
+			(Some(first), Some(last)) if first.hash() == genesis && last.hash() == trusted => {}
+			_ => {
+				return Err(pipe::Error::InvalidSnapshot("not from our genesis to the trusted block"
+					.to_string()))
+			}
+		}
+		let mmr = try!(snapshot.output_mmr());
+
+		// the headers we don't have yet get validated and saved as usual
+		for (prev, bh) in snapshot.headers.iter().zip(snapshot.headers.iter().skip(1)) {
+			if bh.previous != prev.hash() {
+				return Err(pipe::Error::InvalidSnapshot("headers not linked".to_string()));
+			}
+			match self.store.get_block_header(&bh.hash()) {
+				Ok(_) => continue,
+				Err(types::Error::NotFoundErr) => {}
+				Err(e) => return Err(pipe::Error::StoreErr(e)),
+			}
+			let header_head = try!(self.store.get_header_head().map_err(&pipe::Error::StoreErr));
+			let ctx = self.ctx_from_head(header_head, Arc::new(NoopAdapter {}), opts);
+			try!(pipe::process_block_header(bh, ctx));
+		}
+
+		let tip = Tip::from_header(snapshot.headers.last().unwrap());
+		let header_head = try!(self.store.get_header_head().map_err(&pipe::Error::StoreErr));
+		let batch = self.store.batch();
+		try!(save_snapshot(&*batch, &snapshot, &mmr).map_err(&pipe::Error::StoreErr));
+		try!(batch.save_head(&tip).map_err(&pipe::Error::StoreErr));
+		if header_head.total_difficulty < tip.total_difficulty {
+			try!(batch.save_header_head(&tip).map_err(&pipe::Error::StoreErr));
+		}
+		try!(batch.commit().map_err(&pipe::Error::StoreErr));
+		info!("Imported chain state snapshot at {} with hash {}, {} unspent outputs.",
+		      tip.height,
+		      tip.last_block_h,
+		      snapshot.outputs.len());
+
+		*self.head.lock().unwrap() = tip.clone();
+		self.notify_head(&tip);
+		Ok(tip)
+	}
+
+	/// Writes all the blocks of the main chain after the genesis to the file
+	/// at the provided path, in order. Each block is serialized after its
+	/// length in bytes, as a big endian u64. Returns the number of blocks
+	/// written, fails if any of them had its body pruned.
+	pub fn export_blocks(&self, path: &str) -> Result<u64, types::Error> {
+		// keeps the main chain from changing under us
+		let _lock = self.block_process_lock.lock().unwrap();
+		let head = self.head();
+		let to_io_err = |e: io::Error| types::Error::StorageErr(format!("{:?}", e));
+		let file = try!(File::create(path).map_err(&to_io_err));
+		let mut w = BufWriter::new(file);
+		for height in 1..(head.height + 1) {
+			let bh = try!(self.store.get_header_by_height(height)).hash();
+			let b = try!(self.store.get_block(&bh));
+			let data = try!(ser::ser_vec(&b).map_err(|e| types::Error::StorageErr(format!("{:?}", e))));
+			let mut len = [0; 8];
+			BigEndian::write_u64(&mut len, data.len() as u64);
+			try!(w.write_all(&len).map_err(&to_io_err));
+			try!(w.write_all(&data).map_err(&to_io_err));
+		}
+		try!(w.flush().map_err(&to_io_err));
+		Ok(head.height)
+	}
+
+	/// Processes all the blocks in the file at the provided path, written by
+	/// export_blocks, skipping the ones already on our main chain. Stops at
+	/// the first block that fails, or that's longer than any message a peer
+	/// could send. Returns the number of blocks processed.
+	pub fn import_blocks(&self, path: &str, opts: pipe::Options) -> Result<u64, pipe::Error> {
+		let to_io_err = |e: io::Error| pipe::Error::StoreErr(types::Error::StorageErr(format!("{:?}", e)));
+		let file = try!(File::open(path).map_err(&to_io_err));
+		let mut r = BufReader::new(file);
+		let mut count = 0;
+		loop {
+			// the file can only end between blocks
+			let mut len = [0; 8];
+			if try!(r.read(&mut len[..1]).map_err(&to_io_err)) == 0 {
+				break;
+			}
+			try!(r.read_exact(&mut len[1..]).map_err(&to_io_err));
+			// checked before allocating, a corrupted length could be anything
+			let len = BigEndian::read_u64(&len);
+			if len > consensus::MAX_MSG_LEN {
+				let msg = format!("block of {} bytes in {}", len, path);
+				return Err(pipe::Error::StoreErr(types::Error::StorageErr(msg)));
+			}
+			let mut data = vec![0; len as usize];
+			try!(r.read_exact(&mut data).map_err(&to_io_err));
+			let b: Block = try!(ser::deserialize(&mut &data[..])
+				.map_err(|e| pipe::Error::StoreErr(types::Error::StorageErr(format!("{:?}", e)))));
+
+			if try!(self.is_on_current_chain(&b.header).map_err(&pipe::Error::StoreErr)) {
+				continue;
+			}
+			try!(self.process_block(&b, opts));
+			count += 1;
+		}
+		Ok(count)
+	}
+
+	/// Root of the output MMR once the provided block is added on top of our
+	/// head, which its header has to commit to. Used to build new blocks.
+	pub fn utxo_root(&self, b: &Block) -> Result<Hash, pipe::Error> {
+		let _lock = self.block_process_lock.lock().unwrap();
+		if b.header.previous != self.head().last_block_h {
+			return Err(pipe::Error::Unfit("not built on the head".to_string()));
+		}
+		let mut view = try!(UnspentView::new(self.store.clone()));
+		try!(view.apply(b, self.params.coinbase_maturity));
+		view.root()
+	}
+
+	/// Removes what the chain doesn't need anymore from store. Forks branching
+	/// off further back than the maximum reorg depth can't take over anymore,
+	/// their blocks, tips and the outputs only they created are removed. If an
+	/// archive height is configured, main chain blocks below it lose their
+	/// body as well, unless within the maximum reorg depth or the genesis. The
+	/// space they took is reclaimed from the store right away.
+	pub fn compact(&self) -> Result<(), types::Error> {
+		let _lock = self.block_process_lock.lock().unwrap();
+		let head = self.head();
+		let horizon = head.height.saturating_sub(self.max_reorg_depth);
+		let batch = self.store.batch();
+
+		let mut stale = 0;
+		for tip in try!(self.store.get_tips()) {
+			if tip.last_block_h == head.last_block_h {
+				continue;
+			}
+			// walk back to where the fork branches off, stopping short if another
+			// abandoned fork sharing these blocks already got them removed
+			let mut fork_blocks = vec![];
+			let mut fork_height = 0;
+			let mut h = tip.last_block_h;
+			loop {
+				let header = match self.store.get_block_header(&h) {
+					Ok(header) => header,
+					Err(types::Error::NotFoundErr) => break,
+					Err(e) => return Err(e),
+				};
+				if try!(self.is_on_current_chain(&header)) {
+					fork_height = header.height;
+					break;
+				}
+				fork_blocks.push(h);
+				h = header.previous;
+			}
+			if fork_height >= horizon {
+				continue;
+			}
+			for h in &fork_blocks {
+				try!(self.forget_fork_outputs(&*batch, h));
+				try!(batch.delete_block(h));
+				try!(batch.delete_block_header(h));
+			}
+			try!(batch.delete_tip(&tip));
+			stale += fork_blocks.len();
+		}
+
+		let mut pruned = 0;
+		if let Some(archive_height) = self.archive_height {
+			// bodies are pruned from the bottom up, anything below the first body
+			// already missing was pruned before, the genesis one being kept
+			let mut height = cmp::min(archive_height, horizon);
+			while height > 1 {
+				height -= 1;
+				let h = try!(self.store.get_header_by_height(height)).hash();
+				match self.store.get_block(&h) {
+					Ok(_) => try!(batch.delete_block(&h)),
+					Err(types::Error::NotFoundErr) => break,
+					Err(e) => return Err(e),
+				}
+				pruned += 1;
+			}
+		}
+		if stale == 0 && pruned == 0 {
+			batch.rollback();
+			return Ok(());
+		}
+		try!(batch.commit());
+		try!(self.store.compact());
+		info!("Compacted chain, removed {} stale fork blocks and pruned {} block bodies, store \
+		       now {} bytes.",
+		      stale,
+		      pruned,
+		      try!(self.store.disk_usage()));
+		Ok(())
+	}
+
+	// removes where the outputs of the fork block were created from the index,
+	// unless our main chain creates them as well
+	fn forget_fork_outputs(&self, batch: &ChainBatch, h: &Hash) -> Result<(), types::Error> {
+		let b = match self.store.get_block(h) {
+			Ok(b) => b,
+			Err(types::Error::NotFoundErr) => return Ok(()),
+			Err(e) => return Err(e),
+		};
+		for out in &b.outputs {
+			match self.store.get_output_record(&out.hash()) {
+				Ok(_) => {}
+				Err(types::Error::NotFoundErr) => try!(batch.delete_output_pos(&out.hash())),
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+
+	/// Writes a backup of the chain store under the provided directory, while
+	/// blocks keep being processed. The chain can later be restored from it
+	/// on startup, see `ChainConfig::restore_from`.
+	pub fn backup(&self, path: &str) -> Result<(), types::Error> {
+		try!(self.store.backup(path));
+		info!("Backed up the chain store to {}.", path);
+		Ok(())
+	}
+
+	/// Hashes of the blocks accepted while syncing whose range proofs later
+	/// failed verification in the background.
+	pub fn failed_deferred(&self) -> Vec<Hash> {
+		self.deferred.failed()
+	}
+
+	// rewinds the head to the parent of any block still on the main chain
+	// whose range proofs failed verification in the background since last
+	// time, undoing the outputs they made up along with everything built on
+	// them. The header doesn't cover range proofs, so the block isn't denied:
+	// it may come again with the right ones. Done before processing any other
+	// block, the block processing lock being held.
+	fn undo_failed_deferred(&self) {
+		for (h, e) in self.deferred.take_failed() {
+			warn!("Range proofs of block {} failed: {:?}", h, e);
+			let on_chain = self.store
+				.get_block_header(&h)
+				.and_then(|header| self.is_on_current_chain(&header).map(|on| (header, on)));
+			match on_chain {
+				Ok((header, true)) => {
+					warn!("Rewinding to the parent of block {} at {}.", h, header.height);
+					if let Err(e) = self.rewind_head_to(header.height - 1) {
+						error!("Could not rewind the chain below block {}: {:?}", h, e);
+					}
+				}
+				Ok((_, false)) => {}
+				Err(e) => error!("Could not look up block {} that failed: {:?}", h, e),
+			}
+		}
+	}
+
+	/// Statistics on the chain: where its head is, what it's holding onto,
+	/// how fast blocks have been coming and how long processing them took.
+	pub fn stats(&self) -> Result<ChainStats, types::Error> {
+		let head = self.head();
+		let times: Vec<i64> = try!(self.store
+			.headers_iter(head.height, Direction::Backward)
+			.take(STATS_INTERVALS + 1)
+			.map(|res| res.map(|bh| bh.timestamp.to_timespec().sec))
+			.collect::<Result<Vec<_>, _>>());
+		let intervals: Vec<i64> = times.windows(2).map(|w| w[0] - w[1]).collect();
+		let mut block_intervals = BlockIntervals::default();
+		if !intervals.is_empty() {
+			block_intervals = BlockIntervals {
+				count: intervals.len() as u64,
+				mean: intervals.iter().sum::<i64>() / intervals.len() as i64,
+				min: *intervals.iter().min().unwrap(),
+				max: *intervals.iter().max().unwrap(),
+			};
+		}
+
+		Ok(ChainStats {
+			height: head.height,
+			total_difficulty: head.total_difficulty,
+			orphans: self.orphans.lock().unwrap().len(),
+			store_size: try!(self.store.disk_usage()),
+			intervals: block_intervals,
+			pipeline: self.stats.lock().unwrap().clone(),
+		})
+	}
+
+	/// Genesis block and consensus parameters of the network the chain is for
+	pub fn params(&self) -> &ChainParams {
+		&self.params
+	}
+
+	/// Iterates over the timestamp and difficulty of the block with the
+	/// provided hash and the blocks before it, what the difficulty of the
+	/// block following it depends on.
+	pub fn difficulty_iter<'a>(&'a self, h: Hash) -> DifficultyIter<'a, ChainStore> {
+		DifficultyIter::new(&*self.store, h)
+	}
+
+	/// Difficulty and minimum Cuckoo size shift a block built on top of the
+	/// provided header has to meet, see `consensus::next_difficulty` and
+	/// `consensus::graduated_sizeshift`.
+	pub fn next_difficulty(&self, prev: &BlockHeader) -> Result<(Difficulty, u8), types::Error> {
+		let window = try!(self.difficulty_iter(prev.hash())
+			.take(consensus::DIFFICULTY_ADJUST_WINDOW + 1)
+			.collect::<Result<Vec<_>, _>>());
+		Ok((self.params.next_difficulty(&window), self.params.min_sizeshift_at(prev.height + 1)))
+	}
+
+	/// Scaling of the difficulty of secondary proofs of work a block built on
+	/// top of the provided header has to carry, see
+	/// `consensus::next_secondary_scaling`.
+	pub fn next_secondary_scaling(&self, prev: &BlockHeader) -> Result<u32, types::Error> {
+		let window = try!(AncestorIter::new(&*self.store, prev.hash())
+			.take(consensus::DIFFICULTY_ADJUST_WINDOW)
+			.map(|res| res.map(|bh| (bh.pow_family, bh.secondary_scaling)))
+			.collect::<Result<Vec<_>, _>>());
+		Ok(consensus::next_secondary_scaling(prev.height + 1, &window))
+	}
+
+	/// Read-only handle on the store of the chain, for other threads to look
+	/// up the chain without being able to change it.
+	pub fn read_only_store(&self) -> ReadOnlyStore {
+		ReadOnlyStore::new(self.store.clone())
+	}
+
+	/// The tip of the current best chain
+	pub fn head(&self) -> Tip {
+		self.head.lock().unwrap().clone()
+	}
+
+	/// Subscribes to changes of the chain head. The returned receiver gets the
+	/// new head every time it changes, whether extended or switched to another
+	/// fork by a reorg, which a previous block not matching the last head
+	/// tells apart. Dropping the receiver ends the subscription.
+	pub fn subscribe_head(&self) -> Receiver<Tip> {
+		let (tx, rx) = channel();
+		self.head_subscribers.lock().unwrap().push(tx);
+		rx
+	}
+
+	/// Block header for the chain head
+	pub fn head_header(&self) -> Result<BlockHeader, types::Error> {
+		self.store.get_block_header(&self.head().last_block_h)
+	}
+
+	/// Gets a block by hash, whether on the main chain or a fork
+	pub fn get_block(&self, h: &Hash) -> Result<Block, types::Error> {
+		self.store.get_block(h)
+	}
+
+	/// Gets a block header by hash, whether on the main chain or a fork
+	pub fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, types::Error> {
+		self.store.get_block_header(h)
+	}
+
+	/// Gets the header of the block at the provided height on the main chain.
+	pub fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, types::Error> {
+		self.store.get_header_by_height(height)
+	}
+
+	/// Where the output with the provided commitment is on the main chain:
+	/// the block creating it and the height of the one spending it, if any.
+	pub fn get_output_record(&self, commit: &Commitment) -> Result<OutputRecord, types::Error> {
+		// the hash of an output is the hash of its features and commitment
+		let plain = output_hash(OutputFeatures::Plain, commit);
+		match self.store.get_output_record(&plain) {
+			Err(types::Error::NotFoundErr) => {
+				self.store.get_output_record(&output_hash(OutputFeatures::Coinbase, commit))
+			}
+			res => res,
+		}
+	}
+
+	/// Proof that the unspent output with the provided commitment is in the
+	/// output MMR of our head, whose root the head header commits to in its
+	/// utxo_merkle. Lets light clients and wallets check the output exists
+	/// with only the headers. Spent outputs don't have any.
+	pub fn merkle_proof(&self, commit: &Commitment) -> Result<MerkleProof, types::Error> {
+		let _lock = self.block_process_lock.lock().unwrap();
+		for features in &[OutputFeatures::Plain, OutputFeatures::Coinbase] {
+			let h = output_hash(*features, commit);
+			match self.store.get_unspent(&h) {
+				Ok(_) => {}
+				Err(types::Error::NotFoundErr) => continue,
+				Err(e) => return Err(e),
+			}
+			let leaf = try!(self.store.get_output_leaf(&h));
+			let size = try!(self.store.get_output_mmr_size());
+			let mmr = MountainRange::new(StoreNodes(self.store.clone()), size);
+			return try!(mmr.merkle_proof(leaf)).ok_or(types::Error::NotFoundErr);
+		}
+		Err(types::Error::NotFoundErr)
+	}
+
+	/// Height and hash of the block on the main chain having the kernel with
+	/// the provided excess, proving the transaction it's from made it there.
+	/// Of a kernel with a relative height, the most recent block having it.
+	pub fn get_kernel(&self, excess: &Commitment) -> Result<(u64, Hash), types::Error> {
+		match self.store.get_kernel(excess) {
+			Ok(h) => {
+				let header = try!(self.store.get_block_header(&h));
+				Ok((header.height, h))
+			}
+			Err(types::Error::NotFoundErr) => {
+				let recent = try!(self.store.get_recent_kernel(excess));
+				recent.0.last().cloned().ok_or(types::Error::NotFoundErr)
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Whether the provided header is the one at its height on the main chain.
+	pub fn is_on_current_chain(&self, header: &BlockHeader) -> Result<bool, types::Error> {
+		match self.store.get_header_by_height(header.height) {
+			Ok(main) => Ok(main.hash() == header.hash()),
+			Err(types::Error::NotFoundErr) => Ok(false),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// All the tips we know of, one for each fork, the head included. The
+	/// heaviest come first.
+	pub fn tips(&self) -> Result<Vec<Tip>, types::Error> {
+		let mut tips = try!(self.store.get_tips());
+		tips.sort_by(|a, b| b.total_difficulty.cmp(&a.total_difficulty));
+		Ok(tips)
+	}
+
+	/// The last block the chains ending with the two provided blocks have in
+	/// common. That's one of the two blocks if the other builds on it.
+	pub fn fork_point(&self, a: &Hash, b: &Hash) -> Result<BlockHeader, pipe::Error> {
+		let ctx = self.ctx_from_head(self.head(), Arc::new(NoopAdapter {}), pipe::NONE);
+		pipe::fork_point(*a, *b, &ctx)
+	}
+
+	/// Hashes of main chain blocks going back from the head, the first few
+	/// one after the other then twice as far back every time, down to the
+	/// genesis. A peer on a different fork can find the most recent block we
+	/// have in common in there, however far back we forked.
+	pub fn get_locator(&self) -> Result<Vec<Hash>, types::Error> {
+		let head = self.head();
+		let mut locator = vec![];
+		let mut height = head.height;
+		let mut step = 1;
+		loop {
+			locator.push(try!(self.store.get_header_by_height(height)).hash());
+			if height == 0 {
+				break;
+			}
+			if locator.len() >= LOCATOR_DENSE {
+				step *= 2;
+			}
+			height = height.saturating_sub(step);
+		}
+		Ok(locator)
+	}
+
+	/// Headers of the main chain following the most recent block of the
+	/// provided locator that's on it, the last one we have in common with the
+	/// peer that built the locator, up to the provided maximum number. Those
+	/// following the genesis when we have none of the locator blocks.
+	pub fn locate_headers(&self,
+	                      locator: &[Hash],
+	                      max: u64)
+	                      -> Result<Vec<BlockHeader>, types::Error> {
+		let head = self.head();
+		let mut common = 0;
+		for h in locator {
+			match self.store.get_block_header(h) {
+				Ok(header) => {
+					if try!(self.is_on_current_chain(&header)) {
+						common = header.height;
+						break;
+					}
+				}
+				Err(types::Error::NotFoundErr) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		let to = cmp::min(head.height + 1, common + 1 + max);
+		self.store.headers_range(common + 1, to).collect()
+	}
+}
+
+// adds the chain state of the snapshot to the batch, with the output MMR
+// rebuilt out of it
+fn save_snapshot(batch: &ChainBatch,
+                 snapshot: &Snapshot,
+                 mmr: &MountainRange<()>)
+                 -> Result<(), types::Error> {
+	for (height, bh) in snapshot.headers.iter().enumerate() {
+		try!(batch.save_header_height(height as u64, &bh.hash()));
+	}
+	for &(ref h, ref pos, leaf) in &snapshot.outputs {
+		try!(batch.save_output_pos(h, pos));
+		try!(batch.save_unspent(h, pos));
+		try!(batch.save_output_leaf(h, leaf));
+	}
+	for (&(height, idx), h) in mmr.changes() {
+		if *h != ZERO_HASH {
+			try!(batch.save_output_mmr_node(height, idx, h));
+		}
+	}
+	try!(batch.save_output_mmr_size(mmr.size()));
+	for &(ref excess, ref bh) in &snapshot.kernels {
+		try!(batch.save_kernel(excess, bh));
+	}
+	for &(ref excess, ref recent) in &snapshot.recent_kernels {
+		try!(batch.save_recent_kernel(excess, recent));
+	}
+	Ok(())
+}
+
+// Indexes the main chain by height down from the provided head, until the
+// index agrees with it. Covers chains saved before the index existed as well
+// as a genesis saved directly in store.
+fn index_heights(store: &ChainStore, head: &Tip) -> Result<(), types::Error> {
+	let batch = store.batch();
+	let mut h = head.last_block_h;
+	let mut height = head.height;
+	loop {
+		match store.get_header_by_height(height) {
+			Ok(ref header) if header.hash() == h => break,
+			Ok(_) | Err(types::Error::NotFoundErr) => {}
+			Err(e) => return Err(e),
+		}
+		try!(batch.save_header_height(height, &h));
+		if height == 0 {
+			break;
+		}
+		h = try!(store.get_block_header(&h)).previous;
+		height -= 1;
+	}
+	batch.commit()
+}
@@ -0,0 +1,60 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checkpoints, the hashes of blocks known to be on the main chain at given
+//! heights. Any block at a checkpointed height has to match, which rules out
+//! deep fake chains. When syncing, blocks linking to a checkpoint can also skip
+//! the most expensive validations.
+
+use std::collections::BTreeMap;
+
+use core::core::hash::Hash;
+
+/// Height and hash of the hard-coded checkpoints. Nothing to check against
+/// before the network launches.
+const HARD_CODED: &'static [(u64, Hash)] = &[];
+
+/// Table of checkpoints, indexed by height.
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+	table: BTreeMap<u64, Hash>,
+}
+
+impl Checkpoints {
+	/// Builds a checkpoint table from the provided heights and hashes.
+	pub fn new(checkpoints: Vec<(u64, Hash)>) -> Checkpoints {
+		Checkpoints { table: checkpoints.into_iter().collect() }
+	}
+
+	/// The checkpoints hard-coded for the network.
+	pub fn hard_coded() -> Checkpoints {
+		Checkpoints::new(HARD_CODED.to_vec())
+	}
+
+	/// Hash of the block checkpointed at the provided height, if any.
+	pub fn at_height(&self, height: u64) -> Option<Hash> {
+		self.table.get(&height).map(|h| *h)
+	}
+
+	/// Height and hash of the first checkpoint at or above the provided
+	/// height, if any.
+	pub fn following(&self, height: u64) -> Option<(u64, Hash)> {
+		self.table.range(height..).next().map(|(height, h)| (*height, *h))
+	}
+
+	/// Height of the latest checkpoint, zero (the genesis) if there are none.
+	pub fn last_height(&self) -> u64 {
+		self.table.keys().next_back().map(|h| *h).unwrap_or(0)
+	}
+}
@@ -0,0 +1,100 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of block range proofs in the background, used when syncing
+//! historical blocks so range proofs don't hold up the pipeline.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+use secp;
+
+use core::core::Output;
+use core::core::block::verify_range_proofs;
+use core::core::hash::Hash;
+
+/// Verifies the range proofs of already accepted blocks on its own thread,
+/// batching together all the blocks that were queued while it was busy.
+/// Keeps track of the blocks that failed, for the chain to undo them.
+pub struct DeferredVerifier {
+	queue: Mutex<Sender<(Hash, Vec<Output>)>>,
+	failed: Arc<Mutex<Failures>>,
+}
+
+// blocks whose range proofs failed verification, with the error
+#[derive(Default)]
+struct Failures {
+	all: Vec<(Hash, secp::Error)>,
+	// how many of them were taken already
+	taken: usize,
+}
+
+impl DeferredVerifier {
+	/// Starts a new verifier and its thread.
+	pub fn new() -> DeferredVerifier {
+		let (tx, rx) = channel();
+		let failed = Arc::new(Mutex::new(Failures::default()));
+		let thread_failed = failed.clone();
+		thread::Builder::new()
+			.name("deferred_verifier".to_string())
+			.spawn(move || verify_loop(rx, thread_failed))
+			.unwrap();
+		DeferredVerifier {
+			queue: Mutex::new(tx),
+			failed: failed,
+		}
+	}
+
+	/// Queues the outputs of the block with the provided hash for range proof
+	/// verification.
+	pub fn verify(&self, bh: Hash, outputs: Vec<Output>) {
+		if let Err(e) = self.queue.lock().unwrap().send((bh, outputs)) {
+			error!("Could not queue outputs for deferred verification: {:?}", e);
+		}
+	}
+
+	/// Hashes of the blocks whose range proofs failed verification.
+	pub fn failed(&self) -> Vec<Hash> {
+		self.failed.lock().unwrap().all.iter().map(|&(h, _)| h).collect()
+	}
+
+	/// The blocks whose range proofs failed verification since the last call,
+	/// with the error, in the order they were queued.
+	pub fn take_failed(&self) -> Vec<(Hash, secp::Error)> {
+		let mut failed = self.failed.lock().unwrap();
+		let new = failed.all[failed.taken..].to_vec();
+		failed.taken = failed.all.len();
+		new
+	}
+}
+
+fn verify_loop(rx: Receiver<(Hash, Vec<Output>)>, failed: Arc<Mutex<Failures>>) {
+	let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	while let Ok(first) = rx.recv() {
+		// take everything queued up since the last round in one batch
+		let mut batch = vec![first];
+		while let Ok(next) = rx.try_recv() {
+			batch.push(next);
+		}
+		for (bh, outputs) in batch {
+			if let Err(e) = verify_range_proofs(&outputs, &curve) {
+				error!("Deferred range proof verification failed for block {}: {:?}",
+				       bh,
+				       e);
+				failed.lock().unwrap().all.push((bh, e));
+			}
+		}
+	}
+}
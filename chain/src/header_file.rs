@@ -0,0 +1,134 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Flat files holding the headers of the main chain one after the other, by
+//! height, apart from the block bodies. Reading a header by height is a read
+//! of its end offset in the index file and one of the header itself in the
+//! data file, without going through the db.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use core::core::BlockHeader;
+use core::ser;
+
+const DATA_FILE: &'static str = "headers.bin";
+const INDEX_FILE: &'static str = "headers.idx";
+
+/// The headers of the main chain from the genesis up, the index file having
+/// the offset in the data file where each header ends.
+pub struct HeaderFile {
+	data: File,
+	index: File,
+	len: u64,
+}
+
+impl HeaderFile {
+	/// Opens the header files in the provided directory, creating them if
+	/// they don't exist. Headers only partially written, by a crash while
+	/// appending, get dropped.
+	pub fn open(dir: &str) -> io::Result<HeaderFile> {
+		try!(fs::create_dir_all(dir));
+		let open = |name| {
+			OpenOptions::new().read(true).write(true).create(true).open(Path::new(dir).join(name))
+		};
+		let mut file = HeaderFile {
+			data: try!(open(DATA_FILE)),
+			index: try!(open(INDEX_FILE)),
+			len: 0,
+		};
+		let data_len = try!(file.data.metadata()).len();
+		let mut len = try!(file.index.metadata()).len() / 8;
+		while len > 0 && try!(file.end(len - 1)) > data_len {
+			len -= 1;
+		}
+		try!(file.truncate(len));
+		Ok(file)
+	}
+
+	/// Number of headers in the files, the height of the last one plus one.
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+
+	/// The header at the provided height, if there's one that far.
+	pub fn get(&mut self, height: u64) -> io::Result<Option<BlockHeader>> {
+		if height >= self.len {
+			return Ok(None);
+		}
+		let start = if height == 0 {
+			0
+		} else {
+			try!(self.end(height - 1))
+		};
+		let end = try!(self.end(height));
+		let mut buf = vec![0; (end - start) as usize];
+		try!(self.data.seek(SeekFrom::Start(start)));
+		try!(self.data.read_exact(&mut buf));
+		ser::deserialize(&mut &buf[..]).map(Some).map_err(to_io_err)
+	}
+
+	/// Appends the provided header, expected to be at the height following
+	/// the last one.
+	pub fn append(&mut self, header: &BlockHeader) -> io::Result<()> {
+		let start = if self.len == 0 {
+			0
+		} else {
+			try!(self.end(self.len - 1))
+		};
+		let bytes = try!(ser::ser_vec(header).map_err(to_io_err));
+		try!(self.data.seek(SeekFrom::Start(start)));
+		try!(self.data.write_all(&bytes));
+		// the header is only there once its end is in the index
+		let mut end = [0; 8];
+		BigEndian::write_u64(&mut end, start + bytes.len() as u64);
+		try!(self.index.seek(SeekFrom::Start(self.len * 8)));
+		try!(self.index.write_all(&end));
+		self.len += 1;
+		Ok(())
+	}
+
+	/// Removes the headers from the provided height up.
+	pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+		let data_len = if len == 0 {
+			0
+		} else {
+			try!(self.end(len - 1))
+		};
+		try!(self.index.set_len(len * 8));
+		try!(self.data.set_len(data_len));
+		self.len = len;
+		Ok(())
+	}
+
+	/// Space the files take on disk, in bytes.
+	pub fn size_on_disk(&self) -> io::Result<u64> {
+		Ok(try!(self.data.metadata()).len() + try!(self.index.metadata()).len())
+	}
+
+	// offset in the data file where the header at the provided height ends
+	fn end(&mut self, height: u64) -> io::Result<u64> {
+		let mut end = [0; 8];
+		try!(self.index.seek(SeekFrom::Start(height * 8)));
+		try!(self.index.read_exact(&mut end));
+		Ok(BigEndian::read_u64(&end))
+	}
+}
+
+fn to_io_err(e: ser::Error) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
@@ -0,0 +1,80 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory cache of the most recent block headers, so validating a block
+//! doesn't have to go to the store for its parent and close ancestors.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::Mutex;
+
+use core::core::BlockHeader;
+use core::core::hash::{Hash, Hashed};
+
+/// Headers of the blocks in a window of heights behind the highest header
+/// cached so far. Anything further behind gets evicted. Only headers that are
+/// already in store should get cached.
+pub struct HeaderCache {
+	window: u64,
+	inner: Mutex<Headers>,
+}
+
+struct Headers {
+	by_hash: HashMap<Hash, BlockHeader>,
+	by_height: BTreeMap<u64, Vec<Hash>>,
+	top: u64,
+}
+
+impl HeaderCache {
+	/// A new empty cache keeping the headers at most window blocks behind the
+	/// highest one.
+	pub fn new(window: u64) -> HeaderCache {
+		HeaderCache {
+			window: window,
+			inner: Mutex::new(Headers {
+				by_hash: HashMap::new(),
+				by_height: BTreeMap::new(),
+				top: 0,
+			}),
+		}
+	}
+
+	/// The cached header with the provided hash, if any.
+	pub fn get(&self, h: &Hash) -> Option<BlockHeader> {
+		self.inner.lock().unwrap().by_hash.get(h).cloned()
+	}
+
+	/// Caches the provided header, unless it's too far behind already.
+	pub fn insert(&self, header: &BlockHeader) {
+		let mut inner = self.inner.lock().unwrap();
+		if header.height + self.window < inner.top {
+			return;
+		}
+		let h = header.hash();
+		if inner.by_hash.insert(h, header.clone()).is_none() {
+			inner.by_height.entry(header.height).or_insert(vec![]).push(h);
+		}
+
+		if header.height > inner.top {
+			inner.top = header.height;
+			// evict all the heights that fell out of the window
+			let keep = inner.top.saturating_sub(self.window);
+			let recent = inner.by_height.split_off(&keep);
+			let old = mem::replace(&mut inner.by_height, recent);
+			for h in old.values().flat_map(|hs| hs.iter()) {
+				inner.by_hash.remove(h);
+			}
+		}
+	}
+}
@@ -29,13 +29,27 @@ extern crate time;
 
 extern crate grin_core as core;
 extern crate grin_store;
+extern crate grin_util as util;
 extern crate secp256k1zkp as secp;
 
+mod chain;
+pub mod checkpoints;
+mod deferred;
+mod header_file;
+mod headers;
 pub mod pipe;
+mod pool;
+pub mod snapshot;
 pub mod store;
 pub mod types;
+mod unspent;
 
 // Re-export the base interface
 
-pub use types::{ChainStore, Tip, ChainAdapter};
-pub use pipe::{NONE, process_block};
+pub use chain::{Chain, ChainConfig, ChainStats, BlockIntervals, BlockHandle, Inconsistency};
+pub use checkpoints::Checkpoints;
+pub use types::{ChainStore, ChainStoreRead, ChainStoreWrite, ReadOnlyStore, Tip, BlockStatus,
+                BlockProvider, ChainAdapter, MultiAdapter, Direction, AncestorIter,
+                DifficultyIter};
+pub use pipe::NONE;
+pub use grin_store::Backend as StoreBackend;
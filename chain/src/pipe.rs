@@ -14,19 +14,26 @@
 
 //! Implementation of the chain block acceptance (or refusal) pipeline.
 
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use secp;
 use time;
 
 use core::consensus;
 use core::core::hash::{Hash, Hashed};
-use core::core::target::Difficulty;
 use core::core::{BlockHeader, Block, Proof};
-use core::pow;
+use core::genesis::ChainParams;
 use types;
-use types::{Tip, ChainStore, ChainAdapter, NoopAdapter};
+use checkpoints::Checkpoints;
+use deferred::DeferredVerifier;
+use headers::HeaderCache;
+use pool::PowPool;
+use types::{Tip, BlockStatus, BlockProvider, ChainStore, ChainBatch, ChainAdapter, AncestorIter};
+use unspent::{self, UnspentView};
 use store;
+use util::LruCache;
 
 bitflags! {
   /// Options for block validation
@@ -34,169 +41,879 @@ bitflags! {
     const NONE = 0b00000001,
     /// Runs with the easier version of the Proof of Work, mostly to make testing easier.
     const EASY_POW = 0b00000010,
+    /// Blocks proven to be on the checkpointed chain, up to the latest
+    /// checkpoint, skip proof of work and range proofs verification, mostly
+    /// to speed up the initial sync.
+    const TRUST_CHECKPOINTS = 0b00000100,
+    /// Historical blocks, up to the height the chain is trusted to sync to,
+    /// skip the timestamp check against our time and get their range proofs
+    /// verified in the background. Used for the initial sync.
+    const SYNC = 0b00001000,
   }
 }
 
+/// How far in the future a block timestamp can be compared to our time, in
+/// seconds. 12 block intervals, as in bitcoin.
+pub const FUTURE_TIME_LIMIT: i64 = 12 * (consensus::BLOCK_TIME_SEC as i64);
+
 /// Contextual information required to process a new block and either reject or
-/// accept it.
+/// accept it. Built by the chain for each call into the pipeline.
 pub struct BlockContext {
-	opts: Options,
-	store: Arc<ChainStore>,
-	adapter: Arc<ChainAdapter>,
-	head: Tip,
-	tip: Option<Tip>,
+	/// Block validation options
+	pub opts: Options,
+	/// The store blocks and tips are read from and saved to
+	pub store: Arc<ChainStore>,
+	/// Notified of every accepted or rejected block
+	pub adapter: Arc<ChainAdapter>,
+	/// Peer the block being processed came from, none if it's our own
+	pub source: Option<SocketAddr>,
+	/// Head of the chain we're processing against, either the block or the
+	/// header chain
+	pub head: Tip,
+	/// Tip of the fork the block being processed goes on, set by the pipeline
+	pub tip: Option<Tip>,
+	/// Hashes of the blocks that recently went through the pipeline, accepted
+	/// or rejected, shared across calls
+	pub recent_blocks: Arc<Mutex<LruCache<Hash, ()>>>,
+	/// Hashes of the blocks that failed validation with the reason, shared
+	/// across calls
+	pub invalid_blocks: Arc<Mutex<LruCache<Hash, Error>>>,
+	/// Genesis block and consensus parameters of the network
+	pub params: Arc<ChainParams>,
+	/// Blocks known to be on the main chain
+	pub checkpoints: Arc<Checkpoints>,
+	/// Hashes of the blocks found to be ancestors of a checkpointed block,
+	/// shared across calls
+	pub checkpointed: Arc<Mutex<LruCache<Hash, ()>>>,
+	/// Maximum number of head blocks a reorganization can undo
+	pub max_reorg_depth: u64,
+	/// Height up to which blocks processed with the SYNC option are
+	/// historical, see `ChainConfig::sync_height`
+	pub sync_height: u64,
+	/// Verifies range proofs in the background when syncing
+	pub deferred: Arc<DeferredVerifier>,
+	/// Recent headers, shared across calls to save store reads
+	pub headers: Arc<HeaderCache>,
+	/// Verifies proofs of work, possibly ahead of the pipeline
+	pub pow: Arc<PowPool>,
+	/// Time spent in each stage for the block being processed, set by the
+	/// pipeline
+	pub times: StageTimes,
+	/// Statistics on all the blocks processed so far, shared across calls
+	pub stats: Arc<Mutex<PipelineStats>>,
+}
+
+/// Time spent in each stage of the pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct StageTimes {
+	/// Header validation, except for the proof of work
+	pub header: Duration,
+	/// Proof of work verification
+	pub pow: Duration,
+	/// Body validation, mostly signatures and range proofs
+	pub body: Duration,
+	/// Saving the block and updating the chain state, unspent outputs included
+	pub commit: Duration,
+}
+
+impl StageTimes {
+	fn add(&mut self, other: &StageTimes) {
+		self.header += other.header;
+		self.pow += other.pow;
+		self.body += other.body;
+		self.commit += other.commit;
+	}
+
+	fn max(&mut self, other: &StageTimes) {
+		self.header = self.header.max(other.header);
+		self.pow = self.pow.max(other.pow);
+		self.body = self.body.max(other.body);
+		self.commit = self.commit.max(other.commit);
+	}
+}
+
+/// Statistics on the blocks that went through the pipeline, accepted or not,
+/// to find out where the block processing time goes.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineStats {
+	/// Number of blocks processed
+	pub blocks: u64,
+	/// Total time spent in each stage
+	pub total: StageTimes,
+	/// Longest time a single block spent in each stage
+	pub max: StageTimes,
 }
 
-#[derive(Debug)]
+impl PipelineStats {
+	fn record(&mut self, times: &StageTimes) {
+		self.blocks += 1;
+		self.total.add(times);
+		self.max.max(times);
+	}
+}
+
+#[derive(Debug, Clone)]
 pub enum Error {
 	/// The block doesn't fit anywhere in our chain
 	Unfit(String),
+	/// We don't know the block's parent (yet)
+	Orphan,
 	/// Difficulty is too low either compared to ours or the block PoW hash
 	DifficultyTooLow,
 	/// Addition of difficulties on all previous block is wrong
 	WrongTotalDifficulty,
-	/// Size of the Cuckoo graph in block header doesn't match PoW requirements
+	/// Size of the Cuckoo graph in block header is below the graduation
+	/// schedule for its height or above the maximum
 	WrongCuckooSize,
+	/// The scaling of secondary proofs of work in the block header isn't the
+	/// one following its parents
+	WrongSecondaryScaling,
 	/// The proof of work is invalid
 	InvalidPow,
 	/// The block doesn't sum correctly or a tx signature is invalid
 	InvalidBlockProof(secp::Error),
 	/// Block time is too old
 	InvalidBlockTime,
+	/// Block time is too far in the future, it could be fine later on
+	FutureBlock,
+	/// The block height isn't the one right after its parent
+	InvalidBlockHeight,
+	/// The block version isn't the one scheduled for its height
+	WrongVersion,
+	/// The block inputs and outputs don't match the Merkle root in its header
+	InvalidMerkleRoot,
+	/// The block inputs, outputs or proofs aren't sorted by hash
+	UnsortedBody,
+	/// The output MMR after the block doesn't match the root in its header
+	InvalidUtxoRoot,
+	/// The block has more inputs, outputs and proofs than allowed by consensus
+	TooHeavy,
+	/// The block is at a checkpointed height but isn't the checkpointed block
+	CheckpointMismatch,
+	/// The block transactions don't pay the minimum fees
+	InsufficientFees,
+	/// A block transaction has a lock height above the block height
+	LockedKernel,
+	/// The block doesn't pay exactly its reward, subsidy plus fees, to a
+	/// coinbase output, or has other outputs flagged as coinbase
+	InvalidCoinbase,
+	/// The block spends a coinbase output before it's mature
+	ImmatureCoinbase,
+	/// The block spends an output that doesn't exist or was already spent
+	MissingOutput,
+	/// The block creates an output that already exists unspent
+	DuplicateOutput,
+	/// The block has a kernel already on the chain, replaying a transaction
+	DuplicateKernel,
+	/// The block has a kernel with a relative height also in a block less
+	/// than that relative height before it
+	RecentDuplicateKernel,
+	/// The block makes a fork heavier than our head but switching to it would
+	/// undo more blocks than the configured maximum reorg depth
+	ReorgTooDeep,
+	/// A chain state snapshot can't be read or doesn't match the chain it's
+	/// for
+	InvalidSnapshot(String),
 	/// Internal issue when trying to save or load data from store
 	StoreErr(types::Error),
+	/// Too many blocks are already waiting to be processed
+	QueueFull,
+	/// The block body couldn't be obtained from its provider
+	MissingBody(String),
+	/// The block was already found invalid with the wrapped error, which
+	/// doesn't make whoever sends it again at fault
+	KnownInvalid(Box<Error>),
 }
 
-/// Runs the block processing pipeline, including validation and finding a
-/// place for the new block in the chain. Returns the new
-/// chain head if updated.
-pub fn process_block(b: &Block,
-                     store: Arc<ChainStore>,
-                     adapter: Arc<ChainAdapter>,
-                     opts: Options)
-                     -> Result<Option<Tip>, Error> {
-	// TODO should just take a promise for a block with a full header so we don't
-	// spend resources reading the full block when its header is invalid
-
-	let head = try!(store.head().map_err(&Error::StoreErr));
-
-	let mut ctx = BlockContext {
-		opts: opts,
-		store: store,
-		adapter: adapter,
-		head: head,
-		tip: None,
-	};
+impl Error {
+	/// Whether the error means the block itself is invalid, so whoever sent it
+	/// is either broken or malicious. Blocks we already know, can't place
+	/// (yet) or couldn't save don't say anything bad about their sender. Nor
+	/// does a valid fork we refuse to reorg to.
+	pub fn is_bad_data(&self) -> bool {
+		match *self {
+			Error::Unfit(_) |
+			Error::Orphan |
+			Error::FutureBlock |
+			Error::ReorgTooDeep |
+			Error::StoreErr(_) |
+			Error::QueueFull |
+			Error::MissingBody(_) |
+			Error::KnownInvalid(_) => false,
+			_ => true,
+		}
+	}
+}
 
+/// Runs the block processing pipeline, including validation and finding a
+/// place for the new block in the chain. Returns where the block ended up,
+/// either extending the chain head, on a fork or on a fork now heavier than
+/// our current head. Concurrent calls must be serialized by the caller, which
+/// `Chain` takes care of.
+/// The block body is only requested from the provider once the header is
+/// known to be valid, so an invalid header never costs reading or fetching a
+/// body.
+pub fn process_block(p: &mut BlockProvider, mut ctx: BlockContext) -> Result<BlockStatus, Error> {
+	let bh = p.header().hash();
 	info!("Starting validation pipeline for block {} at {}.",
-	      b.hash(),
-	      b.header.height);
-	try!(check_known(b.hash(), &mut ctx));
-	try!(validate_header(&b, &mut ctx));
-	try!(set_tip(&b.header, &mut ctx));
-	try!(validate_block(b, &mut ctx));
+	      bh,
+	      p.header().height);
+	let res = check_known(bh, &mut ctx).and_then(|_| {
+		let res = validate_and_add(p, &mut ctx);
+		if remember(&res) {
+			ctx.recent_blocks.lock().unwrap().insert(bh, ());
+		}
+		if let Err(ref e) = res {
+			deny_if_invalid(bh, e, &ctx);
+		}
+		ctx.stats.lock().unwrap().record(&ctx.times);
+		res
+	});
+
+	// orphans and blocks from the future aren't refused yet, the chain may
+	// still process them later on
+	match res {
+		Err(Error::Orphan) |
+		Err(Error::FutureBlock) => {}
+		Err(ref e) => ctx.adapter.block_rejected(p.header(), e, ctx.source),
+		Ok(_) => {}
+	}
+	res
+}
+
+fn validate_and_add(p: &mut BlockProvider, ctx: &mut BlockContext) -> Result<BlockStatus, Error> {
+	// the header may already have been validated and saved on its own
+	let start = Instant::now();
+	if !try!(header_known(p.header(), ctx)) {
+		try!(validate_header(p.header(), ctx));
+	}
+	try!(set_tip(p.header(), ctx));
+	ctx.times.header = start.elapsed() - ctx.times.pow;
+
+	// the header is fine, worth getting the body
+	let b = try!(p.body());
+	let start = Instant::now();
+	try!(validate_block(b, ctx));
+	ctx.times.body = start.elapsed();
 	info!("Block at {} with hash {} is valid, going to save and append.",
 	      b.header.height,
 	      b.hash());
-	try!(add_block(b, &mut ctx));
-	// TODO a global lock should be set before that step or even earlier
-	try!(update_tips(&mut ctx));
 
-	// TODO make sure we always return the head, and not a fork that just got longer
-	Ok(ctx.tip)
+	// the block, its tip and the head all get saved at once or not at all
+	let start = Instant::now();
+	let store = ctx.store.clone();
+	let batch = store.batch();
+	let status = match append_block(b, &*batch, ctx) {
+		Ok(status) => status,
+		Err(e) => {
+			batch.rollback();
+			return Err(e);
+		}
+	};
+	try!(batch.commit().map_err(&Error::StoreErr));
+	ctx.times.commit = start.elapsed();
+	ctx.headers.insert(&b.header);
+
+	// broadcast the block only once it's safely stored
+	ctx.adapter.block_accepted(b, ctx.source);
+	Ok(status)
+}
+
+/// Whether the block should be remembered as recently seen given its
+/// processing outcome. Orphans, blocks from the future and blocks that don't
+/// fit anywhere yet could be fine later on and store errors say nothing about
+/// the block, so those can come again. Neither does a body not matching its
+/// header or not sorted, or too heavy to even check that. Nor do proofs,
+/// fees, lock heights or a coinbase that are wrong, the header doesn't cover
+/// them. Spending or creating outputs wrongly or replaying kernels may be the
+/// fault of another block on the same fork when it takes over. A missing body
+/// can be found later on. Anything else gives the same result if processed
+/// again.
+fn remember(res: &Result<BlockStatus, Error>) -> bool {
+	match *res {
+		Err(Error::Unfit(_)) |
+		Err(Error::Orphan) |
+		Err(Error::FutureBlock) |
+		Err(Error::InvalidMerkleRoot) |
+		Err(Error::UnsortedBody) |
+		Err(Error::TooHeavy) |
+		Err(Error::InvalidBlockProof(_)) |
+		Err(Error::InsufficientFees) |
+		Err(Error::LockedKernel) |
+		Err(Error::InvalidCoinbase) |
+		Err(Error::MissingOutput) |
+		Err(Error::ImmatureCoinbase) |
+		Err(Error::DuplicateOutput) |
+		Err(Error::DuplicateKernel) |
+		Err(Error::RecentDuplicateKernel) |
+		Err(Error::MissingBody(_)) |
+		Err(Error::StoreErr(_)) => false,
+		_ => true,
+	}
+}
+
+/// Adds the block to the set of known invalid blocks if the error means it
+/// will never be valid. Only errors depending on the header alone qualify, the
+/// header doesn't cover the whole body so the same block with the right body
+/// could still come along.
+fn deny_if_invalid(bh: Hash, e: &Error, ctx: &BlockContext) {
+	match *e {
+		Error::DifficultyTooLow |
+		Error::WrongTotalDifficulty |
+		Error::WrongCuckooSize |
+		Error::WrongSecondaryScaling |
+		Error::InvalidPow |
+		Error::InvalidBlockHeight |
+		Error::CheckpointMismatch => {
+			ctx.invalid_blocks.lock().unwrap().insert(bh, e.clone());
+		}
+		_ => {}
+	}
+}
+
+/// Runs the header part of the block processing pipeline, validating the
+/// header and saving it so the full block can be requested and processed
+/// later. Allows syncing and validating headers before downloading any block
+/// body. Returns the new head of the header chain if updated.
+pub fn process_block_header(bh: &BlockHeader, mut ctx: BlockContext) -> Result<Option<Tip>, Error> {
+
+	info!("Starting validation pipeline for block header {} at {}.",
+	      bh.hash(),
+	      bh.height);
+	try!(check_known(bh.hash(), &mut ctx));
+	if try!(header_known(bh, &ctx)) {
+		return Err(Error::Unfit("already known".to_string()));
+	}
+	if let Err(e) = validate_header(bh, &mut ctx) {
+		deny_if_invalid(bh.hash(), &e, &ctx);
+		return Err(e);
+	}
+
+	let store = ctx.store.clone();
+	let batch = store.batch();
+	try!(batch.save_block_header(bh).map_err(&Error::StoreErr));
+	let head = try!(update_header_head(bh, &*batch, &mut ctx));
+	try!(batch.commit().map_err(&Error::StoreErr));
+	ctx.headers.insert(bh);
+	Ok(head)
+}
+
+/// Runs a block already in store through header and body validation again,
+/// against its parent in store. Checks nothing changed since the block got
+/// accepted, like after a crash or disk corruption.
+pub fn revalidate_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
+	try!(validate_header(&b.header, ctx));
+	validate_block(b, ctx)
+}
+
+/// Same as revalidate_block for a block whose body was pruned, only its
+/// header is left to check.
+pub fn revalidate_header(bh: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
+	validate_header(bh, ctx)
 }
 
 /// Quick in-memory check to fast-reject any block we've already handled
-/// recently. Keeps duplicates from the network in check.
+/// recently. Keeps duplicates from the network in check. A block known to be
+/// invalid may be relayed by peers that didn't check it yet, so it isn't held
+/// against them.
 fn check_known(bh: Hash, ctx: &mut BlockContext) -> Result<(), Error> {
 	if bh == ctx.head.last_block_h || bh == ctx.head.prev_block_h {
 		return Err(Error::Unfit("already known".to_string()));
 	}
+	if let Some(e) = ctx.invalid_blocks.lock().unwrap().get(&bh) {
+		return Err(Error::KnownInvalid(Box::new(e.clone())));
+	}
+	if ctx.recent_blocks.lock().unwrap().contains_key(&bh) {
+		return Err(Error::Unfit("already seen".to_string()));
+	}
 	Ok(())
 }
 
-/// First level of black validation that only needs to act on the block header
+/// Whether we already have the provided header in store. Only headers that
+/// have been fully validated are ever saved.
+fn header_known(bh: &BlockHeader, ctx: &BlockContext) -> Result<bool, Error> {
+	match get_header(&bh.hash(), ctx) {
+		Ok(_) => Ok(true),
+		Err(types::Error::NotFoundErr) => Ok(false),
+		Err(e) => Err(Error::StoreErr(e)),
+	}
+}
+
+/// Header with the provided hash, from the cache if it's recent enough and the
+/// store otherwise.
+fn get_header(h: &Hash, ctx: &BlockContext) -> Result<BlockHeader, types::Error> {
+	if let Some(header) = ctx.headers.get(h) {
+		return Ok(header);
+	}
+	let header = try!(ctx.store.get_block_header(h));
+	ctx.headers.insert(&header);
+	Ok(header)
+}
+
+/// First level of block validation that only needs to act on the block header
 /// to make it as cheap as possible. The different validations are also
 /// arranged by order of cost to have as little DoS surface as possible.
-/// TODO require only the block header (with length information)
-fn validate_header(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
-	let header = &b.header;
-	if header.height > ctx.head.height + 1 {
-		// TODO actually handle orphans and add them to a size-limited set
-		return Err(Error::Unfit("orphan".to_string()));
+fn validate_header(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
+	if let Some(h) = ctx.checkpoints.at_height(header.height) {
+		if h != header.hash() {
+			return Err(Error::CheckpointMismatch);
+		}
 	}
 
-	let prev = try!(ctx.store.get_block_header(&header.previous).map_err(&Error::StoreErr));
+	// the graph size graduates by height, never past the maximum
+	let min_sizeshift = ctx.params.min_sizeshift_at(header.height);
+	if (header.cuckoo_len < min_sizeshift || header.cuckoo_len > ctx.params.max_sizeshift) &&
+	   !ctx.opts.intersects(EASY_POW) {
+		return Err(Error::WrongCuckooSize);
+	}
+
+	// forks can get ahead of our head so the height alone doesn't say much,
+	// a block is only an orphan when we don't have its parent. Orphans get held
+	// until it comes, not without proof of work though.
+	let prev = match get_header(&header.previous, ctx) {
+		Ok(prev) => prev,
+		Err(types::Error::NotFoundErr) => {
+			try!(verify_pow(header, ctx));
+			return Err(Error::Orphan);
+		}
+		Err(e) => return Err(Error::StoreErr(e)),
+	};
+
+	if header.height != prev.height + 1 {
+		return Err(Error::InvalidBlockHeight);
+	}
+	// consensus upgrades are scheduled by height, the block has to say it
+	// follows the rules of its own
+	if header.version != ctx.params.header_version(header.height) {
+		return Err(Error::WrongVersion);
+	}
 
 	if header.timestamp <= prev.timestamp {
 		// prevent time warp attacks and some timestamp manipulations by forcing strict
 		// time progression
 		return Err(Error::InvalidBlockTime);
 	}
-	if header.timestamp >
-	   time::now() + time::Duration::seconds(12 * (consensus::BLOCK_TIME_SEC as i64)) {
-		// refuse blocks more than 12 blocks intervals in future (as in bitcoin)
+	if !syncing(header, ctx) &&
+	   header.timestamp > time::now() + time::Duration::seconds(FUTURE_TIME_LIMIT) {
+		// refuse blocks more than 12 blocks intervals in future (as in bitcoin),
+		// holding those with a valid proof of work until they aren't anymore
 		// TODO add warning in p2p code if local time is too different from peers
-		return Err(Error::InvalidBlockTime);
+		try!(verify_pow(header, ctx));
+		return Err(Error::FutureBlock);
 	}
 
-	if b.header.total_difficulty !=
-	   prev.total_difficulty.clone() + Difficulty::from_hash(&prev.hash()) {
+	// the work on a chain is the sum of the difficulties its blocks had to meet
+	if header.total_difficulty != prev.total_difficulty.clone() + header.difficulty.clone() {
 		return Err(Error::WrongTotalDifficulty);
 	}
 
 	// verify the proof of work and related parameters
-	let (difficulty, cuckoo_sz) = consensus::next_target(header.timestamp.to_timespec().sec,
-	                                                     prev.timestamp.to_timespec().sec,
-	                                                     prev.difficulty,
-	                                                     prev.cuckoo_len);
-	if header.difficulty < difficulty {
+	let ancestors = try!(AncestorIter::new(&*ctx.store, prev.hash())
+		.take(consensus::DIFFICULTY_ADJUST_WINDOW + 1)
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(&Error::StoreErr));
+	let window = ancestors.iter()
+		.map(|bh| (bh.timestamp.to_timespec().sec, bh.difficulty.clone()))
+		.collect::<Vec<_>>();
+	if header.difficulty < ctx.params.next_difficulty(&window) {
 		return Err(Error::DifficultyTooLow);
 	}
-	if header.cuckoo_len != cuckoo_sz && !ctx.opts.intersects(EASY_POW) {
-		return Err(Error::WrongCuckooSize);
+	// every block carries the scaling of secondary proofs, whatever its family
+	let scalings = ancestors.iter()
+		.map(|bh| (bh.pow_family, bh.secondary_scaling))
+		.collect::<Vec<_>>();
+	if header.secondary_scaling != consensus::next_secondary_scaling(header.height, &scalings) {
+		return Err(Error::WrongSecondaryScaling);
 	}
+	verify_pow(header, ctx)
+}
 
-	if ctx.opts.intersects(EASY_POW) {
-		if !pow::verify_size(b, 16) {
-			return Err(Error::InvalidPow);
-		}
-	} else if !pow::verify(b) {
+// verifies the proof of work of the header, unless on the checkpointed chain
+fn verify_pow(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
+	if try!(trusted(header, ctx)) {
+		return Ok(());
+	}
+	// the proof of work may already have been verified ahead of time, in which
+	// case this is only waiting on the result
+	let start = Instant::now();
+	let valid = ctx.pow.verify(header, cuckoo_size(header, ctx.opts));
+	ctx.times.pow = start.elapsed();
+	if !valid {
 		return Err(Error::InvalidPow);
 	}
-
 	Ok(())
 }
 
+/// Size of the Cuckoo graph the proof of work of the header is verified
+/// against given the validation options.
+pub fn cuckoo_size(header: &BlockHeader, opts: Options) -> u32 {
+	if opts.intersects(EASY_POW) {
+		16
+	} else {
+		header.cuckoo_len as u32
+	}
+}
+
+/// Whether we've been asked to trust checkpoints and the block is on the
+/// checkpointed chain, either being the block of the next checkpoint or an
+/// ancestor of it we already have. Being below a checkpoint isn't enough, a
+/// fork there goes through full validation like any other block.
+fn trusted(header: &BlockHeader, ctx: &BlockContext) -> Result<bool, Error> {
+	if !ctx.opts.intersects(TRUST_CHECKPOINTS) {
+		return Ok(false);
+	}
+	let checkpoint = match ctx.checkpoints.following(header.height) {
+		Some((_, h)) => h,
+		None => return Ok(false),
+	};
+	let h = header.hash();
+	if h == checkpoint || ctx.checkpointed.lock().unwrap().contains_key(&h) {
+		return Ok(true);
+	}
+	let mut ancestor = match get_header(&checkpoint, ctx) {
+		Ok(bh) => bh,
+		Err(types::Error::NotFoundErr) => return Ok(false),
+		Err(e) => return Err(Error::StoreErr(e)),
+	};
+	// walk back from the checkpointed block, remembering the blocks on the way
+	// so the ones following this one don't have to
+	let mut checkpointed = ctx.checkpointed.lock().unwrap();
+	while ancestor.height > header.height {
+		ancestor = try!(get_header(&ancestor.previous, ctx).map_err(&Error::StoreErr));
+		checkpointed.insert(ancestor.hash(), ());
+	}
+	Ok(ancestor.hash() == h)
+}
+
+/// Whether we're syncing and the block is historical, at or below the height
+/// we trust the chain up to. The head of the header chain won't do, any peer
+/// can push it up.
+fn syncing(header: &BlockHeader, ctx: &BlockContext) -> bool {
+	ctx.opts.intersects(SYNC) && header.height <= ctx.sync_height
+}
+
+/// Finds the tip of the fork the block is building on. The block either
+/// extends our head, one of our other fork tips or starts a new fork off a
+/// block in the middle of a known fork.
 fn set_tip(h: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
-	// TODO actually support more than one branch
-	if h.previous != ctx.head.last_block_h {
-		return Err(Error::Unfit("Just don't know where to put it right now".to_string()));
+	if h.previous == ctx.head.last_block_h {
+		ctx.tip = Some(ctx.head.clone());
+		return Ok(());
 	}
-	// TODO validate block header height
-	ctx.tip = Some(ctx.head.clone());
-	Ok(())
+
+	let tips = try!(ctx.store.get_tips().map_err(&Error::StoreErr));
+	if let Some(tip) = tips.iter().find(|t| t.last_block_h == h.previous) {
+		ctx.tip = Some(tip.clone());
+		return Ok(());
+	}
+
+	// new fork, find which fork contains the previous block so the lineage of
+	// the new one can be derived from it
+	let prev = try!(get_header(&h.previous, ctx).map_err(&Error::StoreErr));
+	let next_branch = tips.iter().map(|t| t.lineage.last_branch()).max().unwrap_or(0) + 1;
+	for tip in &tips {
+		if try!(in_fork(h.previous, prev.height, tip, ctx)) {
+			ctx.tip = Some(Tip {
+				height: prev.height,
+				last_block_h: h.previous,
+				prev_block_h: prev.previous,
+				total_difficulty: prev.total_difficulty.clone(),
+				lineage: tip.lineage.branch(next_branch),
+			});
+			return Ok(());
+		}
+	}
+	// we only have the header of the previous block, its body should follow
+	Err(Error::Orphan)
+}
+
+/// Whether the block with the provided hash and height is part of the fork
+/// ending with the provided tip. Walks back the fork from its tip.
+// TODO will get expensive with many long forks, maintain an index instead
+fn in_fork(bh: Hash, height: u64, tip: &Tip, ctx: &BlockContext) -> Result<bool, Error> {
+	if tip.height < height {
+		return Ok(false);
+	}
+	let mut header = try!(get_header(&tip.last_block_h, ctx).map_err(&Error::StoreErr));
+	while header.height > height {
+		header = try!(get_header(&header.previous, ctx).map_err(&Error::StoreErr));
+	}
+	Ok(header.hash() == bh)
+}
+
+/// Finds the last block two forks have in common, provided the last block of
+/// each.
+pub fn fork_point(a: Hash, b: Hash, ctx: &BlockContext) -> Result<BlockHeader, Error> {
+	let mut ha = try!(get_header(&a, ctx).map_err(&Error::StoreErr));
+	let mut hb = try!(get_header(&b, ctx).map_err(&Error::StoreErr));
+	while ha.hash() != hb.hash() {
+		if ha.height > hb.height {
+			ha = try!(get_header(&ha.previous, ctx).map_err(&Error::StoreErr));
+		} else {
+			hb = try!(get_header(&hb.previous, ctx).map_err(&Error::StoreErr));
+		}
+	}
+	Ok(ha)
 }
 
 fn validate_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
-	// TODO check tx merkle tree
+	if consensus::exceeds_block_limits(b.inputs.len(), b.outputs.len(), b.proofs.len()) {
+		return Err(Error::TooHeavy);
+	}
+	// the header is what got validated, the body has to be the one it commits to
+	if b.merkle_root() != b.header.tx_merkle {
+		return Err(Error::InvalidMerkleRoot);
+	}
+	// and in its only accepted ordering
+	if !b.is_sorted() {
+		return Err(Error::UnsortedBody);
+	}
+	if !b.pays_min_fees() {
+		return Err(Error::InsufficientFees);
+	}
+	if !b.kernels_unlocked() {
+		return Err(Error::LockedKernel);
+	}
+
 	let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	try!(b.verify(&curve).map_err(&Error::InvalidBlockProof));
+	// only an output worth the block subsidy and fees is taken as its coinbase
+	match b.coinbase_output(&curve) {
+		Ok(Some(_)) => {}
+		Ok(None) => return Err(Error::InvalidCoinbase),
+		Err(e) => return Err(Error::InvalidBlockProof(e)),
+	}
+	// and it's the only one flagged as coinbase, maturity is enforced on those
+	if b.outputs.iter().filter(|out| out.is_coinbase()).count() != 1 {
+		return Err(Error::InvalidCoinbase);
+	}
+	// a checkpoint vouches for the outputs its header covers, not for the
+	// kernels, so only range proofs get skipped
+	if try!(trusted(&b.header, ctx)) {
+		try!(b.verify_kernels(&curve).map_err(&Error::InvalidBlockProof));
+	} else if syncing(&b.header, ctx) {
+		try!(b.verify_kernels(&curve).map_err(&Error::InvalidBlockProof));
+		ctx.deferred.verify(b.hash(), b.outputs.clone());
+	} else {
+		try!(b.verify_parallel(&curve).map_err(&Error::InvalidBlockProof));
+	}
 	Ok(())
 }
 
-fn add_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
+// adds the block to the batch along with everything it changes, from the
+// unspent outputs to the tips
+fn append_block(b: &Block,
+                batch: &ChainBatch,
+                ctx: &mut BlockContext)
+                -> Result<BlockStatus, Error> {
+	try!(add_block(b, batch, ctx));
+	let fork = try!(reorg_fork_point(ctx));
+	try!(update_unspent(b, batch, fork.as_ref(), ctx));
+	update_tips(batch, fork.as_ref(), ctx)
+}
+
+fn add_block(b: &Block, batch: &ChainBatch, ctx: &mut BlockContext) -> Result<(), Error> {
 	// save the block and appends it to the selected tip
-	ctx.tip = ctx.tip.as_ref().map(|t| t.append(b.hash()));
-	ctx.store.save_block(b).map_err(&Error::StoreErr);
+	ctx.tip = ctx.tip.as_ref().map(|t| t.append(&b.header));
+	try!(batch.save_block(b).map_err(&Error::StoreErr));
 
-	// broadcast the block
-	let adapter = ctx.adapter.clone();
-	adapter.block_accepted(b);
+	// and index where its outputs were created
+	for (h, pos) in unspent::output_positions(b) {
+		try!(batch.save_output_pos(&h, &pos).map_err(&Error::StoreErr));
+	}
 	Ok(())
 }
 
-fn update_tips(ctx: &mut BlockContext) -> Result<(), Error> {
+/// Where the fork the block goes on branches off our head, if the block makes
+/// that fork the heaviest and our head has to be switched to it. Refuses the
+/// switch if it undoes more of our head than the maximum reorg depth allows.
+fn reorg_fork_point(ctx: &BlockContext) -> Result<Option<BlockHeader>, Error> {
 	let tip = ctx.tip.as_ref().unwrap();
-	ctx.store.save_head(tip).map_err(&Error::StoreErr)
+	if tip.prev_block_h == ctx.head.last_block_h ||
+	   tip.total_difficulty <= ctx.head.total_difficulty {
+		return Ok(None);
+	}
+	// the new block isn't in store until the batch is committed, so start from
+	// its parent
+	let fork = try!(fork_point(tip.prev_block_h, ctx.head.last_block_h, ctx));
+	let depth = ctx.head.height - fork.height;
+	if depth > ctx.max_reorg_depth {
+		warn!("Refusing reorg from {} at {} to {} at {}, forked at {} (depth {}, max {}).",
+		      ctx.head.last_block_h,
+		      ctx.head.height,
+		      tip.last_block_h,
+		      tip.height,
+		      fork.hash(),
+		      depth,
+		      ctx.max_reorg_depth);
+		return Err(Error::ReorgTooDeep);
+	}
+	Ok(Some(fork))
+}
+
+/// Keeps the set of unspent outputs, the output MMR and the kernels in line
+/// with the head, checking the block spends and creates outputs properly,
+/// doesn't replay kernels and commits to the output MMR root. The root can't
+/// be checked with the rest of the block as it depends on the fork the block
+/// is on. A block extending the head is applied on top of it. A block making
+/// its fork the heaviest is applied after undoing the current head blocks
+/// down to the fork point and replaying the rest of its fork. Blocks going on
+/// a lighter fork leave the set alone, they only get checked if their fork
+/// takes over.
+fn update_unspent(b: &Block,
+                  batch: &ChainBatch,
+                  fork: Option<&BlockHeader>,
+                  ctx: &BlockContext)
+                  -> Result<(), Error> {
+	let tip = ctx.tip.as_ref().unwrap();
+	let extends_head = tip.prev_block_h == ctx.head.last_block_h;
+	if !extends_head && fork.is_none() {
+		return Ok(());
+	}
+
+	let mut view = try!(UnspentView::new(ctx.store.clone()));
+	if let Some(fork) = fork.map(|f| f.hash()) {
+		try!(rewind_view(&mut view, ctx.head.last_block_h, fork, ctx));
+		let mut replay = vec![];
+		let mut h = tip.prev_block_h;
+		while h != fork {
+			let fb = try!(ctx.store.get_block(&h).map_err(&Error::StoreErr));
+			h = fb.header.previous;
+			replay.push(fb);
+		}
+		for fb in replay.iter().rev() {
+			try!(apply_block(fb, &mut view, ctx));
+		}
+	}
+	try!(apply_block(b, &mut view, ctx));
+	view.save(batch)
+}
+
+/// Undoes the blocks from the provided head back to the provided block in the
+/// view, the block being an ancestor of the head.
+fn rewind_view(view: &mut UnspentView, head: Hash, to: Hash, ctx: &BlockContext) -> Result<(), Error> {
+	let mut h = head;
+	while h != to {
+		let old = try!(ctx.store.get_block(&h).map_err(&Error::StoreErr));
+		try!(view.rewind(&old));
+		h = old.header.previous;
+	}
+	Ok(())
+}
+
+/// Rolls the head back to the provided block, an ancestor of the head. The
+/// blocks above it get undone from the unspent outputs, the output MMR and
+/// the kernels and their heights removed from the index. The header chain is
+/// rolled back along with the head. Everything is saved at once, returns the
+/// new head.
+pub fn rewind_head(to: &BlockHeader, ctx: &BlockContext) -> Result<Tip, Error> {
+	let mut view = try!(UnspentView::new(ctx.store.clone()));
+	try!(rewind_view(&mut view, ctx.head.last_block_h, to.hash(), ctx));
+	let tip = Tip {
+		height: to.height,
+		last_block_h: to.hash(),
+		prev_block_h: to.previous,
+		total_difficulty: to.total_difficulty.clone(),
+		lineage: ctx.head.lineage.clone(),
+	};
+
+	let batch = ctx.store.batch();
+	try!(view.save(&*batch));
+	for height in (to.height + 1)..(ctx.head.height + 1) {
+		try!(batch.delete_header_height(height).map_err(&Error::StoreErr));
+	}
+	try!(batch.save_head(&tip).map_err(&Error::StoreErr));
+	try!(batch.save_header_head(&tip).map_err(&Error::StoreErr));
+	try!(batch.commit().map_err(&Error::StoreErr));
+	info!("Rewound head from {} at {} to {} at {}.",
+	      ctx.head.last_block_h,
+	      ctx.head.height,
+	      tip.last_block_h,
+	      tip.height);
+	Ok(tip)
+}
+
+/// Applies the block to the view, checking its header commits to the output
+/// MMR it results in.
+fn apply_block(b: &Block, view: &mut UnspentView, ctx: &BlockContext) -> Result<(), Error> {
+	try!(view.apply(b, ctx.params.coinbase_maturity));
+	if try!(view.root()) != b.header.utxo_merkle {
+		return Err(Error::InvalidUtxoRoot);
+	}
+	Ok(())
+}
+
+/// Saves the updated tip of the fork the block was added to. If the block
+/// extends our head or the fork is now heavier than our current head, it
+/// becomes the new head.
+fn update_tips(batch: &ChainBatch,
+               fork: Option<&BlockHeader>,
+               ctx: &mut BlockContext)
+               -> Result<BlockStatus, Error> {
+	let tip = ctx.tip.as_ref().unwrap();
+	let extends_head = tip.prev_block_h == ctx.head.last_block_h;
+	if !extends_head && fork.is_none() {
+		try!(batch.save_tip(tip).map_err(&Error::StoreErr));
+		return Ok(BlockStatus::Fork(tip.clone()));
+	}
+
+	if let Some(fork) = fork {
+		// the head will now be on a different fork, whose unspent outputs,
+		// output MMR and kernels update_unspent already rewound and replayed,
+		// leaving its blocks to index by height along with the new head
+		info!("Reorg from {} at {} to {} at {}, forked at {} (depth {}).",
+		      ctx.head.last_block_h,
+		      ctx.head.height,
+		      tip.last_block_h,
+		      tip.height,
+		      fork.hash(),
+		      ctx.head.height - fork.height);
+		try!(index_fork(batch, fork, ctx));
+	}
+	try!(batch.save_head(tip).map_err(&Error::StoreErr));
+	try!(batch.save_header_height(tip.height, &tip.last_block_h).map_err(&Error::StoreErr));
+
+	// the header chain can't be behind the chain of full blocks
+	let header_head = try!(ctx.store.get_header_head().map_err(&Error::StoreErr));
+	if tip.prev_block_h == header_head.last_block_h ||
+	   tip.total_difficulty > header_head.total_difficulty {
+		try!(batch.save_header_head(tip).map_err(&Error::StoreErr));
+	}
+	if extends_head {
+		Ok(BlockStatus::Next(tip.clone()))
+	} else {
+		Ok(BlockStatus::Reorg(tip.clone()))
+	}
+}
+
+/// Points the heights of the main chain above the fork point to the blocks
+/// of the fork the new tip is on, below the tip itself. Heights the old head
+/// reached but the new tip doesn't are removed.
+fn index_fork(batch: &ChainBatch, fork: &BlockHeader, ctx: &BlockContext) -> Result<(), Error> {
+	let tip = ctx.tip.as_ref().unwrap();
+	let mut h = tip.prev_block_h;
+	let mut height = tip.height - 1;
+	while height > fork.height {
+		try!(batch.save_header_height(height, &h).map_err(&Error::StoreErr));
+		h = try!(get_header(&h, ctx).map_err(&Error::StoreErr)).previous;
+		height -= 1;
+	}
+	for height in (tip.height + 1)..(ctx.head.height + 1) {
+		try!(batch.delete_header_height(height).map_err(&Error::StoreErr));
+	}
+	Ok(())
+}
+
+/// Makes the provided header the new head of the header chain if it extends
+/// it or is heavier.
+fn update_header_head(bh: &BlockHeader,
+                      batch: &ChainBatch,
+                      ctx: &mut BlockContext)
+                      -> Result<Option<Tip>, Error> {
+	if bh.previous != ctx.head.last_block_h &&
+	   bh.total_difficulty <= ctx.head.total_difficulty {
+		return Ok(None);
+	}
+	let tip = Tip::from_header(bh);
+	try!(batch.save_header_head(&tip).map_err(&Error::StoreErr));
+	Ok(Some(tip))
 }
@@ -0,0 +1,127 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool of threads verifying proofs of work, so the proofs of several blocks
+//! can be checked in parallel ahead of the pipeline getting to them.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+use core::core::BlockHeader;
+use core::core::hash::{Hash, Hashed};
+use core::pow;
+use util::LruCache;
+
+/// Maximum number of verification results kept around until the pipeline
+/// asks for them. Results evicted before that are just computed again.
+const MAX_PENDING: usize = 128;
+
+/// Maximum number of verification results kept around after the pipeline got
+/// them, for the same headers coming again from other peers or forks.
+const MAX_VERIFIED: usize = 1024;
+
+// a header to verify the proof of work of, at the provided cuckoo size
+struct Job {
+	header: BlockHeader,
+	cuckoo_sz: u32,
+	result: Sender<bool>,
+}
+
+/// Verifies proofs of work on a fixed number of threads. Verifications can be
+/// submitted early, waiting for the result of an already submitted one picks
+/// it up instead of verifying again. Results are kept for a while after that,
+/// headers getting verified again being common during a sync with forks.
+/// Both are keyed by the header hash, which covers the pre-PoW header along
+/// with the proof and difficulty the result depends on.
+pub struct PowPool {
+	jobs: Mutex<Sender<Job>>,
+	pending: Mutex<LruCache<(Hash, u32), Receiver<bool>>>,
+	verified: Mutex<LruCache<(Hash, u32), bool>>,
+}
+
+impl PowPool {
+	/// Starts a new pool and its threads, at least one.
+	pub fn new(threads: usize) -> PowPool {
+		let (tx, rx) = channel();
+		let rx = Arc::new(Mutex::new(rx));
+		for n in 0..cmp::max(threads, 1) {
+			let thread_rx = rx.clone();
+			thread::Builder::new()
+				.name(format!("pow_verifier_{}", n))
+				.spawn(move || verify_loop(thread_rx))
+				.unwrap();
+		}
+		PowPool {
+			jobs: Mutex::new(tx),
+			pending: Mutex::new(LruCache::new(MAX_PENDING)),
+			verified: Mutex::new(LruCache::new(MAX_VERIFIED)),
+		}
+	}
+
+	/// Schedules the verification of the proof of work of the provided header,
+	/// unless already scheduled or verified.
+	pub fn submit(&self, header: &BlockHeader, cuckoo_sz: u32) {
+		let key = (header.hash(), cuckoo_sz);
+		if self.verified.lock().unwrap().contains_key(&key) {
+			return;
+		}
+		let mut pending = self.pending.lock().unwrap();
+		if pending.contains_key(&key) {
+			return;
+		}
+		let (tx, rx) = channel();
+		let job = Job {
+			header: header.clone(),
+			cuckoo_sz: cuckoo_sz,
+			result: tx,
+		};
+		if let Err(e) = self.jobs.lock().unwrap().send(job) {
+			error!("Could not schedule proof of work verification: {:?}", e);
+			return;
+		}
+		pending.insert(key, rx);
+	}
+
+	/// Whether the proof of work of the provided header is valid. Waits for
+	/// the verification to complete, scheduling it first if needed, unless
+	/// already verified.
+	pub fn verify(&self, header: &BlockHeader, cuckoo_sz: u32) -> bool {
+		let key = (header.hash(), cuckoo_sz);
+		if let Some(&valid) = self.verified.lock().unwrap().get(&key) {
+			return valid;
+		}
+		self.submit(header, cuckoo_sz);
+		let rx = self.pending.lock().unwrap().remove(&key);
+		let valid = match rx.and_then(|rx| rx.recv().ok()) {
+			Some(valid) => valid,
+			// no thread to verify, do it ourselves
+			None => pow::verify_size(header, cuckoo_sz),
+		};
+		self.verified.lock().unwrap().insert(key, valid);
+		valid
+	}
+}
+
+fn verify_loop(jobs: Arc<Mutex<Receiver<Job>>>) {
+	loop {
+		let job = match jobs.lock().unwrap().recv() {
+			Ok(job) => job,
+			Err(_) => return,
+		};
+		// nobody may be waiting for the result anymore
+		let _ = job.result.send(pow::verify_size(&job.header, job.cuckoo_sz));
+	}
+}
@@ -0,0 +1,154 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshots of the chain state as of a block, all a new node needs to carry
+//! on from that block without downloading the ones before: the main chain
+//! headers up to it, the unspent outputs with their place in the output MMR
+//! and the kernels. The outputs can be checked against the output MMR root
+//! the block header commits to.
+
+use std::collections::{HashMap, HashSet};
+
+use secp::pedersen::Commitment;
+
+use core::consensus;
+use core::core::BlockHeader;
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::mmr::MountainRange;
+use core::ser::{self, Readable, Writeable};
+use pipe::Error;
+use types::{OutputPos, RecentKernels};
+
+/// The chain state as of the block with the last header.
+pub struct Snapshot {
+	/// Headers of the main chain from the genesis to the block the state is at
+	pub headers: Vec<BlockHeader>,
+	/// Number of leaves in the output MMR, spent ones included
+	pub mmr_size: u64,
+	/// Unspent outputs by hash, with where they were created and the index of
+	/// their leaf in the output MMR
+	pub outputs: Vec<(Hash, OutputPos, u64)>,
+	/// Kernels by excess, with the block they're in
+	pub kernels: Vec<(Commitment, Hash)>,
+	/// Kernels with a relative height by excess, with where they are
+	pub recent_kernels: Vec<(Commitment, RecentKernels)>,
+}
+
+impl Snapshot {
+	/// Rebuilds the output MMR from the unspent outputs, every other leaf
+	/// being pruned. Checks its root is the one the last header commits to,
+	/// and that the outputs are where the headers allow: no block creates more
+	/// than the maximum number of outputs, leaves are appended block after
+	/// block and each block has a single reward.
+	pub fn output_mmr(&self) -> Result<MountainRange<()>, Error> {
+		let last = match self.headers.last() {
+			Some(bh) => bh,
+			None => return Err(Error::InvalidSnapshot("no header".to_string())),
+		};
+		// checked before pushing anything, the genesis has no outputs
+		let max_leaves = |height: u64| height.saturating_mul(consensus::MAX_BLOCK_OUTPUTS as u64);
+		if self.mmr_size > max_leaves(last.height) {
+			let msg = format!("too many output leaves {}", self.mmr_size);
+			return Err(Error::InvalidSnapshot(msg));
+		}
+		let mut leaves = HashMap::new();
+		let mut by_leaf = Vec::with_capacity(self.outputs.len());
+		for &(h, ref pos, leaf) in &self.outputs {
+			if leaf >= self.mmr_size || pos.height > last.height || leaf >= max_leaves(pos.height) ||
+			   leaves.insert(leaf, h).is_some() {
+				return Err(Error::InvalidSnapshot(format!("invalid output leaf {}", leaf)));
+			}
+			by_leaf.push((leaf, pos));
+		}
+		by_leaf.sort_by_key(|&(leaf, _)| leaf);
+		let mut rewarded = HashSet::new();
+		for (i, &(leaf, pos)) in by_leaf.iter().enumerate() {
+			let out_of_order = i > 0 && by_leaf[i - 1].1.height > pos.height;
+			if out_of_order || (pos.coinbase && !rewarded.insert(pos.height)) {
+				return Err(Error::InvalidSnapshot(format!("invalid output position {}", leaf)));
+			}
+		}
+		let mut mmr = MountainRange::new((), 0);
+		for leaf in 0..self.mmr_size {
+			// pushing the zero hash is the same as pushing and pruning
+			mmr.push(leaves.get(&leaf).cloned().unwrap_or(ZERO_HASH)).unwrap();
+		}
+		if mmr.root().unwrap() != last.utxo_merkle {
+			return Err(Error::InvalidUtxoRoot);
+		}
+		Ok(mmr)
+	}
+}
+
+impl Writeable for Snapshot {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.headers.len() as u64));
+		for bh in &self.headers {
+			try!(bh.write(writer));
+		}
+		try!(writer.write_u64(self.mmr_size));
+		try!(writer.write_u64(self.outputs.len() as u64));
+		for &(ref h, ref pos, leaf) in &self.outputs {
+			try!(h.write(writer));
+			try!(pos.write(writer));
+			try!(writer.write_u64(leaf));
+		}
+		try!(writer.write_u64(self.kernels.len() as u64));
+		for &(ref excess, ref bh) in &self.kernels {
+			try!(writer.write_fixed_bytes(excess));
+			try!(bh.write(writer));
+		}
+		try!(writer.write_u64(self.recent_kernels.len() as u64));
+		for &(ref excess, ref recent) in &self.recent_kernels {
+			try!(writer.write_fixed_bytes(excess));
+			try!(recent.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<Snapshot> for Snapshot {
+	fn read(reader: &mut ser::Reader) -> Result<Snapshot, ser::Error> {
+		// lengths come from whoever made the snapshot, nothing gets allocated
+		// upfront
+		let mut headers = vec![];
+		for _ in 0..try!(reader.read_u64()) {
+			headers.push(try!(BlockHeader::read(reader)));
+		}
+		let mmr_size = try!(reader.read_u64());
+		let mut outputs = vec![];
+		for _ in 0..try!(reader.read_u64()) {
+			let h = try!(Hash::read(reader));
+			let pos = try!(OutputPos::read(reader));
+			outputs.push((h, pos, try!(reader.read_u64())));
+		}
+		let mut kernels = vec![];
+		for _ in 0..try!(reader.read_u64()) {
+			let excess = try!(Commitment::read(reader));
+			kernels.push((excess, try!(Hash::read(reader))));
+		}
+		let mut recent_kernels = vec![];
+		for _ in 0..try!(reader.read_u64()) {
+			let excess = try!(Commitment::read(reader));
+			recent_kernels.push((excess, try!(RecentKernels::read(reader))));
+		}
+		Ok(Snapshot {
+			headers: headers,
+			mmr_size: mmr_size,
+			outputs: outputs,
+			kernels: kernels,
+			recent_kernels: recent_kernels,
+		})
+	}
+}
@@ -14,14 +14,28 @@
 
 //! Implements storage primitives required by the chain
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
 use byteorder::{WriteBytesExt, BigEndian};
 
 use types::*;
-use core::core::hash::Hash;
-use core::core::{Block, BlockHeader};
+use core::consensus;
+use core::core::hash::{Hash, Hashed};
+use core::core::{Block, BlockHeader, Input, Output, TxProof};
+use core::ser::{self, Readable, Writeable};
 use grin_store;
+use header_file::HeaderFile;
+use secp::pedersen::Commitment;
+use util::LruCache;
 
 const STORE_SUBPATH: &'static str = "chain";
+const HEADERS_SUBPATH: &'static str = "headers";
 
 const SEP: u8 = ':' as u8;
 
@@ -29,22 +43,532 @@ const BLOCK_HEADER_PREFIX: u8 = 'h' as u8;
 const BLOCK_PREFIX: u8 = 'b' as u8;
 const TIP_PREFIX: u8 = 'T' as u8;
 const HEAD_PREFIX: u8 = 'H' as u8;
+const HEADER_HEAD_PREFIX: u8 = 'I' as u8;
+const OUTPUT_POS_PREFIX: u8 = 'o' as u8;
+const UNSPENT_PREFIX: u8 = 'u' as u8;
+const KERNEL_PREFIX: u8 = 'k' as u8;
+const HEADER_HEIGHT_PREFIX: u8 = '8' as u8;
+const OUTPUT_MMR_SIZE_PREFIX: u8 = 'M' as u8;
+const OUTPUT_MMR_PREFIX: u8 = 'm' as u8;
+const OUTPUT_LEAF_PREFIX: u8 = 'l' as u8;
+const OUTPUT_RECORD_PREFIX: u8 = 'c' as u8;
+const SCHEMA_VERSION_PREFIX: u8 = 'v' as u8;
+const RUNNING_PREFIX: u8 = 'R' as u8;
+const QUARANTINE_PREFIX: u8 = 'q' as u8;
+const RECENT_KERNEL_PREFIX: u8 = 'n' as u8;
+
+/// Version of the layout of the chain data in store. Gets bumped whenever an
+/// index changes, along with a new migration from the previous version.
+pub const SCHEMA_VERSION: u64 = 7;
+
+// upgrades the data in store from a schema version to the next one, reading
+// from the db and writing to the batch the version bump also goes in
+type Migration = fn(&grin_store::Store, &grin_store::Batch) -> Result<(), Error>;
+
+// the migrations from each version, the first one being from version 0
+const MIGRATIONS: [Migration; 7] = [migrate_unversioned,
+                                    migrate_output_records,
+                                    migrate_header_versions,
+                                    migrate_pow_families,
+                                    migrate_kernel_lock_heights,
+                                    migrate_kernel_relative_heights,
+                                    migrate_output_features];
+
+// column families the indexes written the most go in, when the backend keeps
+// them apart
+const COLUMNS: [(u8, &'static str); 4] = [(BLOCK_PREFIX, "blocks"),
+                                          (BLOCK_HEADER_PREFIX, "headers"),
+                                          (HEADER_HEIGHT_PREFIX, "heights"),
+                                          (OUTPUT_POS_PREFIX, "outputs")];
 
 /// An implementation of the ChainStore trait backed by a simple key-value
-/// store.
+/// store. The headers of the main chain are also kept in flat files, in
+/// height order, so reading them doesn't involve the db.
 pub struct ChainKVStore {
 	db: grin_store::Store,
+	headers: Mutex<HeaderFile>,
+	unclean: bool,
+}
+
+/// Why a record of the store is broken, see `ChainKVStore::check_records`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordFault {
+	/// The record doesn't deserialize
+	Unreadable,
+	/// The record is of another block than the one its key is for
+	WrongHash,
+	/// The header doesn't link up to the genesis through stored headers
+	MissingParent,
+	/// The block body doesn't have a valid header stored with it
+	MissingHeader,
+}
+
+/// A block or header record found broken in store, with its key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokenRecord {
+	/// Key of the record in the db
+	pub key: Vec<u8>,
+	/// What's wrong with it
+	pub fault: RecordFault,
 }
 
 impl ChainKVStore {
 	pub fn new(root_path: String) -> Result<ChainKVStore, Error> {
-		let db = try!(grin_store::Store::open(format!("{}/{}", root_path, STORE_SUBPATH).as_str())
+		ChainKVStore::with_backend(root_path, grin_store::Backend::RocksDb)
+	}
+
+	/// Opens the store under the provided directory like new, keeping the data
+	/// in the provided backend.
+	pub fn with_backend(root_path: String,
+	                    backend: grin_store::Backend)
+	                    -> Result<ChainKVStore, Error> {
+		let path = format!("{}/{}", root_path, STORE_SUBPATH);
+		let db = try!(grin_store::Store::open_with_columns(path.as_str(), backend, &COLUMNS)
+			.map_err(to_store_err));
+		let headers_path = format!("{}/{}", root_path, HEADERS_SUBPATH);
+		let headers = try!(HeaderFile::open(&headers_path).map_err(&to_io_err));
+		// the running flag only goes away when the store gets dropped
+		let unclean = try!(db.get(&vec![RUNNING_PREFIX]).map_err(to_store_err)).is_some();
+		try!(db.put(&vec![RUNNING_PREFIX], vec![]).map_err(to_store_err));
+		let store = ChainKVStore {
+			db: db,
+			headers: Mutex::new(headers),
+			unclean: unclean,
+		};
+		try!(store.migrate());
+		// the header files may have been left behind the db by a crash
+		let len = try!(store.agreed_headers());
+		store.sync_headers(len);
+		Ok(store)
+	}
+
+	/// Replaces the store under the provided directory with the backup under
+	/// the second one, as written by `backup`. Whatever was in the store
+	/// before is lost.
+	pub fn restore(root_path: &str,
+	               backup_path: &str,
+	               backend: grin_store::Backend)
+	               -> Result<(), Error> {
+		let backup_db = format!("{}/{}", backup_path, STORE_SUBPATH);
+		if !Path::new(&backup_db).exists() {
+			return Err(Error::StorageErr(format!("no backup under {}", backup_path)));
+		}
+		let backup = try!(grin_store::Store::open_with_columns(&backup_db, backend, &COLUMNS)
+			.map_err(to_store_err));
+		for subpath in &[STORE_SUBPATH, HEADERS_SUBPATH] {
+			match fs::remove_dir_all(format!("{}/{}", root_path, subpath)) {
+				Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+				res => try!(res.map_err(&to_io_err)),
+			}
+		}
+		backup.backup(&format!("{}/{}", root_path, STORE_SUBPATH)).map_err(to_store_err)
+	}
+
+	/// Whether the store was still open when the process it was opened by
+	/// ended, like on a crash.
+	pub fn unclean_shutdown(&self) -> bool {
+		self.unclean
+	}
+
+	/// Checks that every header and block in store deserializes, hashes to
+	/// the key it's under and links up to the genesis through stored headers,
+	/// returning the ones that don't. With repair, broken records get moved
+	/// out of the way, kept under a quarantine key for later inspection.
+	/// Indexes pointing to them are left for `Chain::validate` to report.
+	pub fn check_records(&self, repair: bool) -> Result<Vec<BrokenRecord>, Error> {
+		let mut broken = vec![];
+		let mut headers = vec![];
+		for (key, value) in self.db.iter_prefix(&[BLOCK_HEADER_PREFIX, SEP]) {
+			match ser::deserialize::<BlockHeader>(&mut &value[..]) {
+				Ok(bh) => {
+					if bh.hash().to_slice() == &key[2..] {
+						headers.push((bh, key, value));
+					} else {
+						broken.push((key, value, RecordFault::WrongHash));
+					}
+				}
+				Err(_) => broken.push((key, value, RecordFault::Unreadable)),
+			}
+		}
+		// parents come first, whether they link up is known by their children
+		headers.sort_by_key(|&(ref bh, _, _)| bh.height);
+		let mut linked = HashSet::new();
+		for (bh, key, value) in headers {
+			if bh.height == 0 || linked.contains(&bh.previous) {
+				linked.insert(bh.hash());
+			} else {
+				broken.push((key, value, RecordFault::MissingParent));
+			}
+		}
+		for (key, value) in self.db.iter_prefix(&[BLOCK_PREFIX, SEP]) {
+			let fault = match ser::deserialize::<Block>(&mut &value[..]) {
+				Ok(ref b) if b.hash().to_slice() != &key[2..] => RecordFault::WrongHash,
+				Ok(ref b) if !linked.contains(&b.hash()) => RecordFault::MissingHeader,
+				Ok(_) => continue,
+				Err(_) => RecordFault::Unreadable,
+			};
+			broken.push((key, value, fault));
+		}
+
+		for &(ref key, _, ref fault) in &broken {
+			error!("Broken record in chain store under key {:?}: {:?}", key, fault);
+		}
+		if repair && !broken.is_empty() {
+			let batch = self.db.batch();
+			for &(ref key, ref value, _) in &broken {
+				let mut quarantine_key = key.clone();
+				try!(batch.put(to_key(QUARANTINE_PREFIX, &mut quarantine_key), value.clone())
+					.map_err(to_store_err));
+				try!(batch.delete(key).map_err(to_store_err));
+			}
+			try!(batch.write().map_err(to_store_err));
+			// quarantined headers may have been in the header files
+			self.sync_headers(0);
+		}
+		Ok(broken.into_iter()
+			.map(|(key, _, fault)| {
+				BrokenRecord {
+					key: key,
+					fault: fault,
+				}
+			})
+			.collect())
+	}
+
+	// number of headers at the bottom of the header files matching the height
+	// index. Above the fork they may be on, headers differ at every height.
+	fn agreed_headers(&self) -> Result<u64, Error> {
+		let mut headers = self.headers.lock().unwrap();
+		let mut len = headers.len();
+		while len > 0 {
+			let in_file = try!(headers.get(len - 1).map_err(&to_io_err)).map(|bh| bh.hash());
+			match self.db.get_ser::<Hash>(&height_key(len - 1)) {
+				Ok(Some(h)) if Some(h) == in_file => break,
+				Ok(_) => len -= 1,
+				Err(e) => return Err(to_store_err(e)),
+			}
+		}
+		Ok(len)
+	}
+
+	// brings the header files in line with the height index from the provided
+	// height up. The db stays the reference, on failure the files are emptied
+	// and the db read instead.
+	fn sync_headers(&self, from: u64) {
+		let mut headers = self.headers.lock().unwrap();
+		let mut height = cmp::min(from, headers.len());
+		let mut res = headers.truncate(height).map_err(&to_io_err);
+		while res.is_ok() {
+			match self.header_from_db(height) {
+				Ok(bh) => res = headers.append(&bh).map_err(&to_io_err),
+				Err(Error::NotFoundErr) => break,
+				Err(e) => res = Err(e),
+			}
+			height += 1;
+		}
+		if let Err(e) = res {
+			error!("Could not write the header files, reading headers from the db: {:?}", e);
+			let _ = headers.truncate(0);
+		}
+	}
+
+	fn header_from_db(&self, height: u64) -> Result<BlockHeader, Error> {
+		let h: Hash = try!(option_to_not_found(self.db.get_ser(&height_key(height))));
+		self.get_block_header(&h)
+	}
+
+	/// Version of the layout the chain data is in, `SCHEMA_VERSION` once
+	/// opened. Data saved before versioning is version 0.
+	pub fn schema_version(&self) -> Result<u64, Error> {
+		match self.db.get_ser::<StoredU64>(&vec![SCHEMA_VERSION_PREFIX]) {
+			Ok(Some(version)) => Ok(version.0),
+			// an empty store can start with the current layout right away
+			Ok(None) => {
+				match self.db.get(&vec![HEAD_PREFIX]) {
+					Ok(Some(_)) => Ok(0),
+					Ok(None) => Ok(SCHEMA_VERSION),
+					Err(e) => Err(to_store_err(e)),
+				}
+			}
+			Err(e) => Err(to_store_err(e)),
+		}
+	}
+
+	// brings the data in store up to the current schema version, one version
+	// at a time, refusing data from a newer version
+	fn migrate(&self) -> Result<(), Error> {
+		let mut version = try!(self.schema_version());
+		if version > SCHEMA_VERSION {
+			error!("Chain data is in schema version {}, only up to {} is supported.",
+			       version,
+			       SCHEMA_VERSION);
+			return Err(Error::SchemaErr(version));
+		}
+		while version < SCHEMA_VERSION {
+			info!("Migrating chain data from schema version {} to {}.",
+			      version,
+			      version + 1);
+			let batch = self.db.batch();
+			try!(MIGRATIONS[version as usize](&self.db, &batch));
+			try!(batch.put_ser(&vec![SCHEMA_VERSION_PREFIX], &StoredU64(version + 1))
+				.map_err(to_store_err));
+			try!(batch.write().map_err(to_store_err));
+			version += 1;
+		}
+		// a new store gets the version recorded right away
+		match self.db.get(&vec![SCHEMA_VERSION_PREFIX]) {
+			Ok(Some(_)) => Ok(()),
+			Ok(None) => {
+				self.db
+					.put_ser(&vec![SCHEMA_VERSION_PREFIX], &StoredU64(version))
+					.map_err(to_store_err)
+			}
+			Err(e) => Err(to_store_err(e)),
+		}
+	}
+}
+
+// data saved before versioning has the same layout as version 1, the height
+// index it may lack gets rebuilt by the chain on startup
+fn migrate_unversioned(_: &grin_store::Store, _: &grin_store::Batch) -> Result<(), Error> {
+	Ok(())
+}
+
+// version 2 adds the output commitment index, built from the main chain
+// blocks that still have their body
+fn migrate_output_records(db: &grin_store::Store, batch: &grin_store::Batch) -> Result<(), Error> {
+	let head: Tip = match try!(db.get_ser(&vec![HEAD_PREFIX]).map_err(to_store_err)) {
+		Some(head) => head,
+		None => return Ok(()),
+	};
+	let mut main_chain = vec![];
+	let mut h = head.last_block_h;
+	loop {
+		let header_key = to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec()).clone();
+		let bh: BlockHeader = try!(option_to_not_found(db.get_ser(&header_key)));
+		main_chain.push(h);
+		if bh.height == 0 {
+			break;
+		}
+		h = bh.previous;
+	}
+
+	let mut records: HashMap<Hash, OutputRecord> = HashMap::new();
+	for h in main_chain.iter().rev() {
+		let block_key = to_key(BLOCK_PREFIX, &mut h.to_vec()).clone();
+		let b: Block = match try!(db.get_ser(&block_key).map_err(to_store_err)) {
+			Some(b) => b,
+			None => continue,
+		};
+		for input in &b.inputs {
+			if let Some(rec) = records.get_mut(&input.output_hash()) {
+				rec.spent_height = Some(b.header.height);
+			}
+		}
+		for out in &b.outputs {
+			let leaf_key = to_key(OUTPUT_LEAF_PREFIX, &mut out.hash().to_vec()).clone();
+			if let Some(leaf) = try!(db.get_ser::<StoredU64>(&leaf_key).map_err(to_store_err)) {
+				records.insert(out.hash(),
+				               OutputRecord {
+					               block_h: *h,
+					               height: b.header.height,
+					               mmr_pos: leaf.0,
+					               spent_height: None,
+				               });
+			}
+		}
+	}
+	for (h, rec) in records {
+		try!(batch.put_ser(&to_key(OUTPUT_RECORD_PREFIX, &mut h.to_vec())[..], &rec)
 			.map_err(to_store_err));
-		Ok(ChainKVStore { db: db })
+	}
+	Ok(())
+}
+
+// version 3 starts block headers with their version, which headers saved
+// before can't be converted to as it changes all their hashes and proofs of
+// work: the chain has to be synced again from scratch
+fn migrate_header_versions(db: &grin_store::Store, _: &grin_store::Batch) -> Result<(), Error> {
+	let head: Tip = match try!(db.get_ser(&vec![HEAD_PREFIX]).map_err(to_store_err)) {
+		Some(head) => head,
+		None => return Ok(()),
+	};
+	let header_key = to_key(BLOCK_HEADER_PREFIX, &mut head.last_block_h.to_vec()).clone();
+	match try!(db.get(&header_key).map_err(to_store_err)) {
+		// older headers start with their height, never large enough to fill the
+		// two bytes the version now takes
+		Some(ref bytes) if bytes.len() >= 2 && bytes[0] == 0 && bytes[1] == 0 => {
+			error!("Chain data predates header versions, it has to be synced again.");
+			Err(Error::StorageErr("block headers without version".to_string()))
+		}
+		_ => Ok(()),
+	}
+}
+
+// version 4 adds the proof of work family and secondary scaling to block
+// headers, the hashes changing again: older headers don't read back to the
+// hash they're saved under and the chain has to be synced again from scratch
+fn migrate_pow_families(db: &grin_store::Store, _: &grin_store::Batch) -> Result<(), Error> {
+	let head: Tip = match try!(db.get_ser(&vec![HEAD_PREFIX]).map_err(to_store_err)) {
+		Some(head) => head,
+		None => return Ok(()),
+	};
+	let header_key = to_key(BLOCK_HEADER_PREFIX, &mut head.last_block_h.to_vec()).clone();
+	match db.get_ser::<BlockHeader>(&header_key) {
+		Ok(Some(ref bh)) if bh.hash() == head.last_block_h => Ok(()),
+		Ok(None) => Ok(()),
+		_ => {
+			error!("Chain data predates proof of work families, it has to be synced again.");
+			Err(Error::StorageErr("block headers without proof of work family".to_string()))
+		}
+	}
+}
+
+// version 5 adds lock heights to the transaction proofs of blocks, the blocks
+// saved before get rewritten with lock heights of 0, which their signatures
+// still verify with
+fn migrate_kernel_lock_heights(db: &grin_store::Store,
+                               batch: &grin_store::Batch)
+                               -> Result<(), Error> {
+	for (key, value) in db.iter_prefix(&[BLOCK_PREFIX, SEP]) {
+		let b = try!(ser::deserialize::<BlockWithoutLockHeights>(&mut &value[..])
+			.map_err(|e| Error::StorageErr(format!("{:?}", e))));
+		let b = BlockWithoutRelativeHeights(b.0);
+		try!(batch.put_ser(&key, &b).map_err(to_store_err));
+	}
+	Ok(())
+}
+
+// version 6 adds relative heights to the transaction proofs of blocks, the
+// blocks saved before get rewritten with relative heights of 0, plain kernels
+// their signatures still verify as. No kernel had one, so the recent kernel
+// index starts empty.
+fn migrate_kernel_relative_heights(db: &grin_store::Store,
+                                   batch: &grin_store::Batch)
+                                   -> Result<(), Error> {
+	for (key, value) in db.iter_prefix(&[BLOCK_PREFIX, SEP]) {
+		let b = try!(ser::deserialize::<BlockWithoutRelativeHeights>(&mut &value[..])
+			.map_err(|e| Error::StorageErr(format!("{:?}", e))));
+		try!(batch.put_ser(&key, &b.0).map_err(to_store_err));
+	}
+	Ok(())
+}
+
+// version 7 starts outputs with their features, which output hashes commit
+// to: outputs saved before don't read anymore, as their commitment comes where
+// the features now are, and can't be converted as their hashes, the indexes
+// keyed by them and the headers committing to them would all change. The chain
+// has to be synced again from scratch.
+fn migrate_output_features(db: &grin_store::Store, _: &grin_store::Batch) -> Result<(), Error> {
+	for (_, value) in db.iter_prefix(&[BLOCK_PREFIX, SEP]) {
+		if ser::deserialize::<Block>(&mut &value[..]).is_err() {
+			error!("Chain data predates output features, it has to be synced again.");
+			return Err(Error::StorageErr("outputs without features".to_string()));
+		}
+	}
+	Ok(())
+}
+
+// a block as saved before transaction proofs had a lock height
+struct BlockWithoutLockHeights(Block);
+
+impl ser::Readable<BlockWithoutLockHeights> for BlockWithoutLockHeights {
+	fn read(reader: &mut ser::Reader) -> Result<BlockWithoutLockHeights, ser::Error> {
+		let b = try!(read_legacy_block(reader, |reader| {
+			let remainder = try!(Commitment::read(reader));
+			let sig = try!(reader.read_vec());
+			let fee = try!(reader.read_u64());
+			Ok(TxProof {
+				remainder: remainder,
+				sig: sig,
+				fee: fee,
+				lock_height: 0,
+				relative_height: 0,
+			})
+		}));
+		Ok(BlockWithoutLockHeights(b))
+	}
+}
+
+// a block as saved before transaction proofs had a relative height
+struct BlockWithoutRelativeHeights(Block);
+
+impl ser::Readable<BlockWithoutRelativeHeights> for BlockWithoutRelativeHeights {
+	fn read(reader: &mut ser::Reader) -> Result<BlockWithoutRelativeHeights, ser::Error> {
+		let b = try!(read_legacy_block(reader, |reader| {
+			let remainder = try!(Commitment::read(reader));
+			let sig = try!(reader.read_vec());
+			let fee = try!(reader.read_u64());
+			let lock_height = try!(reader.read_u64());
+			Ok(TxProof {
+				remainder: remainder,
+				sig: sig,
+				fee: fee,
+				lock_height: lock_height,
+				relative_height: 0,
+			})
+		}));
+		Ok(BlockWithoutRelativeHeights(b))
+	}
+}
+
+impl ser::Writeable for BlockWithoutRelativeHeights {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		let b = &self.0;
+		try!(b.header.write(writer));
+		try!(writer.write_u64(b.inputs.len() as u64));
+		try!(writer.write_u64(b.outputs.len() as u64));
+		try!(writer.write_u64(b.proofs.len() as u64));
+		for inp in &b.inputs {
+			try!(inp.write(writer));
+		}
+		for out in &b.outputs {
+			try!(out.write(writer));
+		}
+		for proof in &b.proofs {
+			try!(writer.write_fixed_bytes(&proof.remainder));
+			try!(writer.write_bytes(&proof.sig));
+			try!(writer.write_u64(proof.fee));
+			try!(writer.write_u64(proof.lock_height));
+		}
+		Ok(())
 	}
 }
 
-impl ChainStore for ChainKVStore {
+// reads a block as saved by an older schema version, its proofs being read
+// with the provided function
+fn read_legacy_block<F>(reader: &mut ser::Reader, read_proof: F) -> Result<Block, ser::Error>
+	where F: Fn(&mut ser::Reader) -> Result<TxProof, ser::Error>
+{
+	let header = try!(BlockHeader::read(reader));
+	let input_len = try!(reader.read_u64());
+	let output_len = try!(reader.read_u64());
+	let proof_len = try!(reader.read_u64());
+	if consensus::exceeds_block_limits(input_len as usize,
+	                                   output_len as usize,
+	                                   proof_len as usize) {
+		return Err(ser::Error::TooLargeReadErr);
+	}
+	let inputs = try!((0..input_len).map(|_| Input::read(reader)).collect());
+	let outputs = try!((0..output_len).map(|_| Output::read(reader)).collect());
+	let proofs = try!((0..proof_len).map(|_| read_proof(reader)).collect());
+	Ok(Block {
+		header: header,
+		inputs: inputs,
+		outputs: outputs,
+		proofs: proofs,
+	})
+}
+
+impl Drop for ChainKVStore {
+	fn drop(&mut self) {
+		if let Err(e) = self.db.delete(&vec![RUNNING_PREFIX]) {
+			error!("Could not flag the chain store as closed: {:?}", e);
+		}
+	}
+}
+
+impl ChainStoreRead for ChainKVStore {
 	fn head(&self) -> Result<Tip, Error> {
 		option_to_not_found(self.db.get_ser(&vec![HEAD_PREFIX]))
 	}
@@ -54,30 +578,1149 @@ impl ChainStore for ChainKVStore {
 		self.get_block_header(&head.last_block_h)
 	}
 
+	fn get_block(&self, h: &Hash) -> Result<Block, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(BLOCK_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
+		let in_file = self.headers.lock().unwrap().get(height);
+		match in_file {
+			Ok(Some(bh)) => Ok(bh),
+			Ok(None) => self.header_from_db(height),
+			Err(e) => Err(to_io_err(e)),
+		}
+	}
+
+	fn headers_iter<'a>(&'a self,
+	                    from: u64,
+	                    dir: Direction)
+	                    -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		Box::new(MainChainIter::new(self, from, dir, |store, height| {
+			store.get_header_by_height(height)
+		}))
+	}
+
+	fn blocks_iter<'a>(&'a self,
+	                   from: u64,
+	                   dir: Direction)
+	                   -> Box<Iterator<Item = Result<Block, Error>> + 'a> {
+		Box::new(MainChainIter::new(self, from, dir, |store, height| {
+			store.get_header_by_height(height).and_then(|bh| store.get_block(&bh.hash()))
+		}))
+	}
+
+	fn headers_range<'a>(&'a self,
+	                     from: u64,
+	                     to: u64)
+	                     -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		Box::new((from..to)
+			.map(move |height| self.get_header_by_height(height))
+			.take_while(|res| match *res {
+				Err(Error::NotFoundErr) => false,
+				_ => true,
+			}))
+	}
+
+	fn get_header_head(&self) -> Result<Tip, Error> {
+		match self.db.get_ser(&vec![HEADER_HEAD_PREFIX]) {
+			Ok(None) => self.head(),
+			res => option_to_not_found(res),
+		}
+	}
+
+	fn get_tips(&self) -> Result<Vec<Tip>, Error> {
+		self.db.iter(&vec![TIP_PREFIX, SEP]).collect::<Result<_, _>>().map_err(&to_store_err)
+	}
+
+	fn get_output_pos(&self, h: &Hash) -> Result<OutputPos, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(OUTPUT_POS_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_unspent(&self, h: &Hash) -> Result<OutputPos, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(UNSPENT_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_output_record(&self, h: &Hash) -> Result<OutputRecord, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(OUTPUT_RECORD_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_kernel(&self, excess: &Commitment) -> Result<Hash, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(KERNEL_PREFIX, &mut excess.bytes().to_vec())))
+	}
+
+	fn get_unspent_outputs(&self) -> Result<Vec<(Hash, OutputPos)>, Error> {
+		let mut outputs = vec![];
+		for res in self.db.iter_with_keys(&vec![UNSPENT_PREFIX, SEP]) {
+			let (k, pos) = try!(res.map_err(&to_store_err));
+			let h = try!(ser::deserialize(&mut &k[2..]).map_err(&to_ser_err));
+			outputs.push((h, pos));
+		}
+		Ok(outputs)
+	}
+
+	fn unspent_iter<'a>(&'a self,
+	                    prefix: &[u8])
+	                    -> Box<Iterator<Item = Result<(Hash, OutputPos), Error>> + 'a> {
+		let iter = self.db.iter_prefix(&to_key(UNSPENT_PREFIX, &mut prefix.to_vec()));
+		Box::new(iter.map(|(k, pos)| {
+			let h = ser::deserialize(&mut &k[2..]);
+			h.and_then(|h| ser::deserialize(&mut &pos[..]).map(|pos| (h, pos))).map_err(to_ser_err)
+		}))
+	}
+
+	fn get_kernels(&self) -> Result<Vec<(Commitment, Hash)>, Error> {
+		let mut kernels = vec![];
+		for res in self.db.iter_with_keys(&vec![KERNEL_PREFIX, SEP]) {
+			let (k, bh) = try!(res.map_err(&to_store_err));
+			let excess = try!(ser::deserialize(&mut &k[2..]).map_err(&to_ser_err));
+			kernels.push((excess, bh));
+		}
+		Ok(kernels)
+	}
+
+	fn get_recent_kernel(&self, excess: &Commitment) -> Result<RecentKernels, Error> {
+		let mut excess = excess.bytes().to_vec();
+		option_to_not_found(self.db.get_ser(&to_key(RECENT_KERNEL_PREFIX, &mut excess)))
+	}
+
+	fn get_recent_kernels(&self) -> Result<Vec<(Commitment, RecentKernels)>, Error> {
+		let mut kernels = vec![];
+		for res in self.db.iter_with_keys(&vec![RECENT_KERNEL_PREFIX, SEP]) {
+			let (k, recent) = try!(res.map_err(&to_store_err));
+			let excess = try!(ser::deserialize(&mut &k[2..]).map_err(&to_ser_err));
+			kernels.push((excess, recent));
+		}
+		Ok(kernels)
+	}
+
+	fn get_output_mmr_size(&self) -> Result<u64, Error> {
+		match self.db.get_ser::<StoredU64>(&vec![OUTPUT_MMR_SIZE_PREFIX]) {
+			Ok(None) => Ok(0),
+			res => option_to_not_found(res).map(|size| size.0),
+		}
+	}
+
+	fn get_output_mmr_node(&self, height: u8, idx: u64) -> Result<Hash, Error> {
+		option_to_not_found(self.db.get_ser(&mmr_node_key(height, idx)))
+	}
+
+	fn get_output_leaf(&self, h: &Hash) -> Result<u64, Error> {
+		let leaf: StoredU64 = try!(option_to_not_found(self.db
+			.get_ser(&to_key(OUTPUT_LEAF_PREFIX, &mut h.to_vec()))));
+		Ok(leaf.0)
+	}
+
+	fn disk_usage(&self) -> Result<u64, Error> {
+		let db_size = try!(self.db.size_on_disk().map_err(&to_store_err));
+		let headers_size = try!(self.headers.lock().unwrap().size_on_disk().map_err(&to_io_err));
+		Ok(db_size + headers_size)
+	}
+
+	fn backup(&self, path: &str) -> Result<(), Error> {
+		// the header files get rebuilt from the db when opened
+		self.db.backup(&format!("{}/{}", path, STORE_SUBPATH)).map_err(&to_store_err)
+	}
+}
+
+impl ChainStoreWrite for ChainKVStore {
 	fn save_block(&self, b: &Block) -> Result<(), Error> {
-		try!(self.db
+		let batch = self.batch();
+		try!(batch.save_block(b));
+		batch.commit()
+	}
+
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_block_header(bh));
+		batch.commit()
+	}
+
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_header_head(t));
+		batch.commit()
+	}
+
+	fn save_head(&self, t: &Tip) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_head(t));
+		batch.commit()
+	}
+
+	fn save_tip(&self, t: &Tip) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_tip(t));
+		batch.commit()
+	}
+
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_output_pos(h, pos));
+		batch.commit()
+	}
+
+	fn compact(&self) -> Result<(), Error> {
+		self.db.compact().map_err(&to_store_err)
+	}
+
+	fn batch<'a>(&'a self) -> Box<ChainBatch + 'a> {
+		Box::new(ChainKVBatch {
+			store: self,
+			batch: self.db.batch(),
+			lowest_height: Cell::new(None),
+		})
+	}
+}
+
+/// Batch of writes to a ChainKVStore, directly backed by a batch of the
+/// underlying key-value store. The header files get updated once the batch
+/// is written to the db.
+pub struct ChainKVBatch<'a> {
+	store: &'a ChainKVStore,
+	batch: grin_store::Batch<'a>,
+	// lowest height of the main chain changed by the batch
+	lowest_height: Cell<Option<u64>>,
+}
+
+impl<'a> ChainKVBatch<'a> {
+	fn height_changed(&self, height: u64) {
+		let lowest = self.lowest_height.get().map_or(height, |h| cmp::min(h, height));
+		self.lowest_height.set(Some(lowest));
+	}
+}
+
+impl<'a> ChainBatch for ChainKVBatch<'a> {
+	fn save_block(&self, b: &Block) -> Result<(), Error> {
+		try!(self.batch
 			.put_ser(&to_key(BLOCK_PREFIX, &mut b.hash().to_vec())[..], b)
 			.map_err(&to_store_err));
-		self.db
-			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut b.hash().to_vec())[..],
-			         &b.header)
+		self.save_block_header(&b.header)
+	}
+
+	fn delete_block(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete(&to_key(BLOCK_PREFIX, &mut h.to_vec())[..]).map_err(&to_store_err)
+	}
+
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
+		self.batch
+			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut bh.hash().to_vec())[..], bh)
+			.map_err(&to_store_err)
+	}
+
+	fn delete_block_header(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete(&to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec())[..]).map_err(&to_store_err)
+	}
+
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error> {
+		self.batch.put_ser(&vec![HEADER_HEAD_PREFIX], t).map_err(&to_store_err)
+	}
+
+	fn save_head(&self, t: &Tip) -> Result<(), Error> {
+		try!(self.save_tip(t));
+		self.batch.put_ser(&vec![HEAD_PREFIX], t).map_err(&to_store_err)
+	}
+
+	fn save_tip(&self, t: &Tip) -> Result<(), Error> {
+		self.batch.put_ser(&tip_key(t)[..], t).map_err(&to_store_err)
+	}
+
+	fn delete_tip(&self, t: &Tip) -> Result<(), Error> {
+		self.batch.delete(&tip_key(t)[..]).map_err(&to_store_err)
+	}
+
+	fn save_header_height(&self, height: u64, bh: &Hash) -> Result<(), Error> {
+		self.height_changed(height);
+		self.batch.put_ser(&height_key(height)[..], bh).map_err(&to_store_err)
+	}
+
+	fn delete_header_height(&self, height: u64) -> Result<(), Error> {
+		self.height_changed(height);
+		self.batch.delete(&height_key(height)[..]).map_err(&to_store_err)
+	}
+
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		self.batch
+			.put_ser(&to_key(OUTPUT_POS_PREFIX, &mut h.to_vec())[..], pos)
+			.map_err(&to_store_err)
+	}
+
+	fn delete_output_pos(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete(&to_key(OUTPUT_POS_PREFIX, &mut h.to_vec())[..]).map_err(&to_store_err)
+	}
+
+	fn save_unspent(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		self.batch
+			.put_ser(&to_key(UNSPENT_PREFIX, &mut h.to_vec())[..], pos)
+			.map_err(&to_store_err)
+	}
+
+	fn delete_unspent(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete(&to_key(UNSPENT_PREFIX, &mut h.to_vec())[..]).map_err(&to_store_err)
+	}
+
+	fn save_output_record(&self, h: &Hash, rec: &OutputRecord) -> Result<(), Error> {
+		self.batch
+			.put_ser(&to_key(OUTPUT_RECORD_PREFIX, &mut h.to_vec())[..], rec)
+			.map_err(&to_store_err)
+	}
+
+	fn delete_output_record(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete(&to_key(OUTPUT_RECORD_PREFIX, &mut h.to_vec())[..]).map_err(&to_store_err)
+	}
+
+	fn save_kernel(&self, excess: &Commitment, bh: &Hash) -> Result<(), Error> {
+		self.batch
+			.put_ser(&to_key(KERNEL_PREFIX, &mut excess.bytes().to_vec())[..], bh)
+			.map_err(&to_store_err)
+	}
+
+	fn delete_kernel(&self, excess: &Commitment) -> Result<(), Error> {
+		self.batch.delete(&to_key(KERNEL_PREFIX, &mut excess.bytes().to_vec())[..]).map_err(&to_store_err)
+	}
+
+	fn save_recent_kernel(&self, excess: &Commitment, kernels: &RecentKernels)
+	                      -> Result<(), Error> {
+		self.batch
+			.put_ser(&to_key(RECENT_KERNEL_PREFIX, &mut excess.bytes().to_vec())[..], kernels)
+			.map_err(&to_store_err)
+	}
+
+	fn delete_recent_kernel(&self, excess: &Commitment) -> Result<(), Error> {
+		self.batch
+			.delete(&to_key(RECENT_KERNEL_PREFIX, &mut excess.bytes().to_vec())[..])
+			.map_err(&to_store_err)
+	}
+
+	fn save_output_mmr_size(&self, size: u64) -> Result<(), Error> {
+		self.batch.put_ser(&vec![OUTPUT_MMR_SIZE_PREFIX], &StoredU64(size)).map_err(&to_store_err)
+	}
+
+	fn save_output_mmr_node(&self, height: u8, idx: u64, h: &Hash) -> Result<(), Error> {
+		self.batch.put_ser(&mmr_node_key(height, idx)[..], h).map_err(&to_store_err)
+	}
+
+	fn delete_output_mmr_node(&self, height: u8, idx: u64) -> Result<(), Error> {
+		self.batch.delete(&mmr_node_key(height, idx)[..]).map_err(&to_store_err)
+	}
+
+	fn save_output_leaf(&self, h: &Hash, idx: u64) -> Result<(), Error> {
+		self.batch
+			.put_ser(&to_key(OUTPUT_LEAF_PREFIX, &mut h.to_vec())[..], &StoredU64(idx))
 			.map_err(&to_store_err)
 	}
 
+	fn delete_output_leaf(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete(&to_key(OUTPUT_LEAF_PREFIX, &mut h.to_vec())[..]).map_err(&to_store_err)
+	}
+
+	fn commit(self: Box<Self>) -> Result<(), Error> {
+		let ChainKVBatch { store, batch, lowest_height } = *self;
+		try!(batch.write().map_err(&to_store_err));
+		if let Some(height) = lowest_height.get() {
+			store.sync_headers(height);
+		}
+		Ok(())
+	}
+
+	fn rollback(self: Box<Self>) {
+		// nothing got to the db yet
+	}
+}
+
+/// An implementation of the ChainStore trait keeping everything in memory,
+/// for tests and simulations. Nothing is written to disk, so all of it is
+/// gone once the store is dropped.
+pub struct MemoryChainStore {
+	state: RwLock<MemoryState>,
+}
+
+// everything a ChainKVStore has in its db, ordered the same way where the
+// order shows
+#[derive(Default)]
+struct MemoryState {
+	blocks: HashMap<Hash, Block>,
+	headers: HashMap<Hash, BlockHeader>,
+	heights: HashMap<u64, Hash>,
+	head: Option<Tip>,
+	header_head: Option<Tip>,
+	tips: BTreeMap<u32, Tip>,
+	output_pos: HashMap<Hash, OutputPos>,
+	unspent: BTreeMap<Hash, OutputPos>,
+	output_records: HashMap<Hash, OutputRecord>,
+	kernels: BTreeMap<Vec<u8>, (Commitment, Hash)>,
+	recent_kernels: BTreeMap<Vec<u8>, (Commitment, RecentKernels)>,
+	output_mmr_size: u64,
+	output_mmr: HashMap<(u8, u64), Hash>,
+	output_leaves: HashMap<Hash, u64>,
+}
+
+impl MemoryChainStore {
+	/// A new empty store.
+	pub fn new() -> MemoryChainStore {
+		MemoryChainStore { state: RwLock::new(MemoryState::default()) }
+	}
+}
+
+impl ChainStoreRead for MemoryChainStore {
+	fn head(&self) -> Result<Tip, Error> {
+		self.state.read().unwrap().head.clone().ok_or(Error::NotFoundErr)
+	}
+
+	fn head_header(&self) -> Result<BlockHeader, Error> {
+		let head = try!(self.head());
+		self.get_block_header(&head.last_block_h)
+	}
+
+	fn get_block(&self, h: &Hash) -> Result<Block, Error> {
+		self.state.read().unwrap().blocks.get(h).cloned().ok_or(Error::NotFoundErr)
+	}
+
 	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error> {
-		option_to_not_found(self.db.get_ser(&to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec())))
+		self.state.read().unwrap().headers.get(h).cloned().ok_or(Error::NotFoundErr)
+	}
+
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
+		let h = try!(self.state.read().unwrap().heights.get(&height).cloned().ok_or(Error::NotFoundErr));
+		self.get_block_header(&h)
+	}
+
+	fn headers_iter<'a>(&'a self,
+	                    from: u64,
+	                    dir: Direction)
+	                    -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		Box::new(MainChainIter::new(self, from, dir, |store, height| {
+			store.get_header_by_height(height)
+		}))
+	}
+
+	fn blocks_iter<'a>(&'a self,
+	                   from: u64,
+	                   dir: Direction)
+	                   -> Box<Iterator<Item = Result<Block, Error>> + 'a> {
+		Box::new(MainChainIter::new(self, from, dir, |store, height| {
+			store.get_header_by_height(height).and_then(|bh| store.get_block(&bh.hash()))
+		}))
+	}
+
+	fn headers_range<'a>(&'a self,
+	                     from: u64,
+	                     to: u64)
+	                     -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		Box::new((from..to)
+			.map(move |height| self.get_header_by_height(height))
+			.take_while(|res| match *res {
+				Err(Error::NotFoundErr) => false,
+				_ => true,
+			}))
+	}
+
+	fn get_header_head(&self) -> Result<Tip, Error> {
+		match self.state.read().unwrap().header_head.clone() {
+			Some(t) => Ok(t),
+			None => self.head(),
+		}
+	}
+
+	fn get_tips(&self) -> Result<Vec<Tip>, Error> {
+		Ok(self.state.read().unwrap().tips.values().cloned().collect())
+	}
+
+	fn get_output_pos(&self, h: &Hash) -> Result<OutputPos, Error> {
+		self.state.read().unwrap().output_pos.get(h).cloned().ok_or(Error::NotFoundErr)
+	}
+
+	fn get_unspent(&self, h: &Hash) -> Result<OutputPos, Error> {
+		self.state.read().unwrap().unspent.get(h).cloned().ok_or(Error::NotFoundErr)
+	}
+
+	fn get_output_record(&self, h: &Hash) -> Result<OutputRecord, Error> {
+		self.state.read().unwrap().output_records.get(h).cloned().ok_or(Error::NotFoundErr)
+	}
+
+	fn get_kernel(&self, excess: &Commitment) -> Result<Hash, Error> {
+		let state = self.state.read().unwrap();
+		state.kernels.get(&excess.bytes().to_vec()).map(|&(_, bh)| bh).ok_or(Error::NotFoundErr)
+	}
+
+	fn get_unspent_outputs(&self) -> Result<Vec<(Hash, OutputPos)>, Error> {
+		let state = self.state.read().unwrap();
+		Ok(state.unspent.iter().map(|(h, pos)| (*h, pos.clone())).collect())
+	}
+
+	fn unspent_iter<'a>(&'a self,
+	                    prefix: &[u8])
+	                    -> Box<Iterator<Item = Result<(Hash, OutputPos), Error>> + 'a> {
+		// the lowest hash having the prefix, if any can
+		let mut start = [0; 32];
+		if prefix.len() > start.len() {
+			return Box::new(vec![].into_iter());
+		}
+		start[..prefix.len()].copy_from_slice(prefix);
+		let state = self.state.read().unwrap();
+		let outputs = state.unspent
+			.range(Hash(start)..)
+			.take_while(|&(h, _)| h.0.starts_with(prefix))
+			.map(|(h, pos)| Ok((*h, pos.clone())))
+			.collect::<Vec<_>>();
+		Box::new(outputs.into_iter())
+	}
+
+	fn get_kernels(&self) -> Result<Vec<(Commitment, Hash)>, Error> {
+		Ok(self.state.read().unwrap().kernels.values().cloned().collect())
+	}
+
+	fn get_recent_kernel(&self, excess: &Commitment) -> Result<RecentKernels, Error> {
+		let state = self.state.read().unwrap();
+		let kernels = state.recent_kernels.get(excess.bytes());
+		kernels.map(|&(_, ref recent)| recent.clone()).ok_or(Error::NotFoundErr)
+	}
+
+	fn get_recent_kernels(&self) -> Result<Vec<(Commitment, RecentKernels)>, Error> {
+		Ok(self.state.read().unwrap().recent_kernels.values().cloned().collect())
+	}
+
+	fn get_output_mmr_size(&self) -> Result<u64, Error> {
+		Ok(self.state.read().unwrap().output_mmr_size)
+	}
+
+	fn get_output_mmr_node(&self, height: u8, idx: u64) -> Result<Hash, Error> {
+		let state = self.state.read().unwrap();
+		state.output_mmr.get(&(height, idx)).cloned().ok_or(Error::NotFoundErr)
+	}
+
+	fn get_output_leaf(&self, h: &Hash) -> Result<u64, Error> {
+		self.state.read().unwrap().output_leaves.get(h).cloned().ok_or(Error::NotFoundErr)
+	}
+
+	fn disk_usage(&self) -> Result<u64, Error> {
+		Ok(0)
+	}
+
+	fn backup(&self, _: &str) -> Result<(), Error> {
+		Err(Error::StorageErr("nothing on disk to back up".to_string()))
+	}
+}
+
+impl ChainStoreWrite for MemoryChainStore {
+	fn save_block(&self, b: &Block) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_block(b));
+		batch.commit()
+	}
+
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_block_header(bh));
+		batch.commit()
+	}
+
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_header_head(t));
+		batch.commit()
+	}
+
+	fn save_head(&self, t: &Tip) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_head(t));
+		batch.commit()
+	}
+
+	fn save_tip(&self, t: &Tip) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_tip(t));
+		batch.commit()
+	}
+
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_output_pos(h, pos));
+		batch.commit()
+	}
+
+	fn compact(&self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn batch<'a>(&'a self) -> Box<ChainBatch + 'a> {
+		Box::new(MemoryBatch {
+			store: self,
+			ops: RefCell::new(vec![]),
+		})
+	}
+}
+
+/// Batch of writes to a MemoryChainStore, applied under a single write lock
+/// when committed.
+pub struct MemoryBatch<'a> {
+	store: &'a MemoryChainStore,
+	ops: RefCell<Vec<Box<Fn(&mut MemoryState)>>>,
+}
+
+impl<'a> MemoryBatch<'a> {
+	fn push<F>(&self, op: F) -> Result<(), Error>
+		where F: Fn(&mut MemoryState) + 'static
+	{
+		self.ops.borrow_mut().push(Box::new(op));
+		Ok(())
+	}
+}
+
+impl<'a> ChainBatch for MemoryBatch<'a> {
+	fn save_block(&self, b: &Block) -> Result<(), Error> {
+		let b = b.clone();
+		try!(self.save_block_header(&b.header));
+		self.push(move |s| {
+			s.blocks.insert(b.hash(), b.clone());
+		})
+	}
+
+	fn delete_block(&self, h: &Hash) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.blocks.remove(&h);
+		})
+	}
+
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
+		let bh = bh.clone();
+		self.push(move |s| {
+			s.headers.insert(bh.hash(), bh.clone());
+		})
+	}
+
+	fn delete_block_header(&self, h: &Hash) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.headers.remove(&h);
+		})
+	}
+
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error> {
+		let t = t.clone();
+		self.push(move |s| s.header_head = Some(t.clone()))
 	}
 
 	fn save_head(&self, t: &Tip) -> Result<(), Error> {
 		try!(self.save_tip(t));
-		self.db.put_ser(&vec![HEAD_PREFIX], t).map_err(&to_store_err)
+		let t = t.clone();
+		self.push(move |s| s.head = Some(t.clone()))
+	}
+
+	fn save_tip(&self, t: &Tip) -> Result<(), Error> {
+		let t = t.clone();
+		self.push(move |s| {
+			s.tips.insert(t.lineage.last_branch(), t.clone());
+		})
+	}
+
+	fn delete_tip(&self, t: &Tip) -> Result<(), Error> {
+		let branch = t.lineage.last_branch();
+		self.push(move |s| {
+			s.tips.remove(&branch);
+		})
+	}
+
+	fn save_header_height(&self, height: u64, bh: &Hash) -> Result<(), Error> {
+		let bh = *bh;
+		self.push(move |s| {
+			s.heights.insert(height, bh);
+		})
+	}
+
+	fn delete_header_height(&self, height: u64) -> Result<(), Error> {
+		self.push(move |s| {
+			s.heights.remove(&height);
+		})
+	}
+
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		let (h, pos) = (*h, pos.clone());
+		self.push(move |s| {
+			s.output_pos.insert(h, pos.clone());
+		})
+	}
+
+	fn delete_output_pos(&self, h: &Hash) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.output_pos.remove(&h);
+		})
+	}
+
+	fn save_unspent(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		let (h, pos) = (*h, pos.clone());
+		self.push(move |s| {
+			s.unspent.insert(h, pos.clone());
+		})
+	}
+
+	fn delete_unspent(&self, h: &Hash) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.unspent.remove(&h);
+		})
+	}
+
+	fn save_output_record(&self, h: &Hash, rec: &OutputRecord) -> Result<(), Error> {
+		let (h, rec) = (*h, rec.clone());
+		self.push(move |s| {
+			s.output_records.insert(h, rec.clone());
+		})
+	}
+
+	fn delete_output_record(&self, h: &Hash) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.output_records.remove(&h);
+		})
+	}
+
+	fn save_kernel(&self, excess: &Commitment, bh: &Hash) -> Result<(), Error> {
+		let (excess, bh) = (*excess, *bh);
+		self.push(move |s| {
+			s.kernels.insert(excess.bytes().to_vec(), (excess, bh));
+		})
+	}
+
+	fn delete_kernel(&self, excess: &Commitment) -> Result<(), Error> {
+		let key = excess.bytes().to_vec();
+		self.push(move |s| {
+			s.kernels.remove(&key);
+		})
+	}
+
+	fn save_recent_kernel(&self, excess: &Commitment, kernels: &RecentKernels)
+	                      -> Result<(), Error> {
+		let (excess, kernels) = (*excess, kernels.clone());
+		self.push(move |s| {
+			s.recent_kernels.insert(excess.bytes().to_vec(), (excess, kernels.clone()));
+		})
+	}
+
+	fn delete_recent_kernel(&self, excess: &Commitment) -> Result<(), Error> {
+		let key = excess.bytes().to_vec();
+		self.push(move |s| {
+			s.recent_kernels.remove(&key);
+		})
+	}
+
+	fn save_output_mmr_size(&self, size: u64) -> Result<(), Error> {
+		self.push(move |s| s.output_mmr_size = size)
+	}
+
+	fn save_output_mmr_node(&self, height: u8, idx: u64, h: &Hash) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.output_mmr.insert((height, idx), h);
+		})
+	}
+
+	fn delete_output_mmr_node(&self, height: u8, idx: u64) -> Result<(), Error> {
+		self.push(move |s| {
+			s.output_mmr.remove(&(height, idx));
+		})
+	}
+
+	fn save_output_leaf(&self, h: &Hash, idx: u64) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.output_leaves.insert(h, idx);
+		})
+	}
+
+	fn delete_output_leaf(&self, h: &Hash) -> Result<(), Error> {
+		let h = *h;
+		self.push(move |s| {
+			s.output_leaves.remove(&h);
+		})
+	}
+
+	fn commit(self: Box<Self>) -> Result<(), Error> {
+		let mut state = self.store.state.write().unwrap();
+		for op in self.ops.into_inner() {
+			op(&mut state);
+		}
+		Ok(())
+	}
+
+	fn rollback(self: Box<Self>) {
+		// nothing got to the state yet
+	}
+}
+
+/// Decorator of any ChainStore keeping the most recently read or written
+/// headers and blocks in memory, serving reads by hash from there. Only
+/// writes going through the decorator keep what it caches up to date.
+pub struct CachedChainStore<S: ChainStore> {
+	store: S,
+	caches: Mutex<Caches>,
+}
+
+struct Caches {
+	headers: LruCache<Hash, BlockHeader>,
+	blocks: LruCache<Hash, Block>,
+	// bumped on every removal, so what's removed while being read from the
+	// underlying store doesn't get cached again
+	removals: u64,
+}
+
+// change made to the caches once the batch doing it is committed
+enum CacheOp {
+	SaveHeader(BlockHeader),
+	SaveBlock(Block),
+	DeleteHeader(Hash),
+	DeleteBlock(Hash),
+}
+
+impl<S: ChainStore> CachedChainStore<S> {
+	/// Caches up to the provided numbers of headers and blocks read from or
+	/// written to the provided store.
+	pub fn new(store: S, header_capacity: usize, block_capacity: usize) -> CachedChainStore<S> {
+		CachedChainStore {
+			store: store,
+			caches: Mutex::new(Caches {
+				headers: LruCache::new(header_capacity),
+				blocks: LruCache::new(block_capacity),
+				removals: 0,
+			}),
+		}
+	}
+
+	/// The decorated store
+	pub fn inner(&self) -> &S {
+		&self.store
+	}
+}
+
+impl Caches {
+	fn apply(&mut self, op: CacheOp) {
+		match op {
+			CacheOp::SaveHeader(bh) => self.headers.insert(bh.hash(), bh),
+			CacheOp::SaveBlock(b) => self.blocks.insert(b.hash(), b),
+			CacheOp::DeleteHeader(h) => {
+				self.headers.remove(&h);
+				self.removals += 1;
+			}
+			CacheOp::DeleteBlock(h) => {
+				self.blocks.remove(&h);
+				self.removals += 1;
+			}
+		}
+	}
+}
+
+impl<S: ChainStore> ChainStoreRead for CachedChainStore<S> {
+	fn head(&self) -> Result<Tip, Error> {
+		self.store.head()
+	}
+
+	fn head_header(&self) -> Result<BlockHeader, Error> {
+		let head = try!(self.store.head());
+		self.get_block_header(&head.last_block_h)
+	}
+
+	fn get_block(&self, h: &Hash) -> Result<Block, Error> {
+		let removals = {
+			let mut caches = self.caches.lock().unwrap();
+			if let Some(b) = caches.blocks.get(h) {
+				return Ok(b.clone());
+			}
+			caches.removals
+		};
+		let b = try!(self.store.get_block(h));
+		let mut caches = self.caches.lock().unwrap();
+		if caches.removals == removals {
+			caches.blocks.insert(*h, b.clone());
+		}
+		Ok(b)
+	}
+
+	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error> {
+		let removals = {
+			let mut caches = self.caches.lock().unwrap();
+			if let Some(bh) = caches.headers.get(h) {
+				return Ok(bh.clone());
+			}
+			caches.removals
+		};
+		let bh = try!(self.store.get_block_header(h));
+		let mut caches = self.caches.lock().unwrap();
+		if caches.removals == removals {
+			caches.headers.insert(*h, bh.clone());
+		}
+		Ok(bh)
+	}
+
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
+		self.store.get_header_by_height(height)
+	}
+
+	fn headers_iter<'a>(&'a self,
+	                    from: u64,
+	                    dir: Direction)
+	                    -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		self.store.headers_iter(from, dir)
+	}
+
+	fn blocks_iter<'a>(&'a self,
+	                   from: u64,
+	                   dir: Direction)
+	                   -> Box<Iterator<Item = Result<Block, Error>> + 'a> {
+		self.store.blocks_iter(from, dir)
+	}
+
+	fn headers_range<'a>(&'a self,
+	                     from: u64,
+	                     to: u64)
+	                     -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		self.store.headers_range(from, to)
+	}
+
+	fn get_header_head(&self) -> Result<Tip, Error> {
+		self.store.get_header_head()
+	}
+
+	fn get_tips(&self) -> Result<Vec<Tip>, Error> {
+		self.store.get_tips()
+	}
+
+	fn get_output_pos(&self, h: &Hash) -> Result<OutputPos, Error> {
+		self.store.get_output_pos(h)
+	}
+
+	fn get_unspent(&self, h: &Hash) -> Result<OutputPos, Error> {
+		self.store.get_unspent(h)
+	}
+
+	fn get_output_record(&self, h: &Hash) -> Result<OutputRecord, Error> {
+		self.store.get_output_record(h)
+	}
+
+	fn get_kernel(&self, excess: &Commitment) -> Result<Hash, Error> {
+		self.store.get_kernel(excess)
+	}
+
+	fn get_unspent_outputs(&self) -> Result<Vec<(Hash, OutputPos)>, Error> {
+		self.store.get_unspent_outputs()
+	}
+
+	fn unspent_iter<'a>(&'a self,
+	                    prefix: &[u8])
+	                    -> Box<Iterator<Item = Result<(Hash, OutputPos), Error>> + 'a> {
+		self.store.unspent_iter(prefix)
+	}
+
+	fn get_kernels(&self) -> Result<Vec<(Commitment, Hash)>, Error> {
+		self.store.get_kernels()
+	}
+
+	fn get_recent_kernel(&self, excess: &Commitment) -> Result<RecentKernels, Error> {
+		self.store.get_recent_kernel(excess)
+	}
+
+	fn get_recent_kernels(&self) -> Result<Vec<(Commitment, RecentKernels)>, Error> {
+		self.store.get_recent_kernels()
+	}
+
+	fn get_output_mmr_size(&self) -> Result<u64, Error> {
+		self.store.get_output_mmr_size()
+	}
+
+	fn get_output_mmr_node(&self, height: u8, idx: u64) -> Result<Hash, Error> {
+		self.store.get_output_mmr_node(height, idx)
+	}
+
+	fn get_output_leaf(&self, h: &Hash) -> Result<u64, Error> {
+		self.store.get_output_leaf(h)
+	}
+
+	fn disk_usage(&self) -> Result<u64, Error> {
+		self.store.disk_usage()
+	}
+
+	fn backup(&self, path: &str) -> Result<(), Error> {
+		self.store.backup(path)
+	}
+}
+
+impl<S: ChainStore> ChainStoreWrite for CachedChainStore<S> {
+	fn save_block(&self, b: &Block) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_block(b));
+		batch.commit()
+	}
+
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
+		let batch = self.batch();
+		try!(batch.save_block_header(bh));
+		batch.commit()
+	}
+
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error> {
+		self.store.save_header_head(t)
+	}
+
+	fn save_head(&self, t: &Tip) -> Result<(), Error> {
+		self.store.save_head(t)
+	}
+
+	fn save_tip(&self, t: &Tip) -> Result<(), Error> {
+		self.store.save_tip(t)
+	}
+
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		self.store.save_output_pos(h, pos)
+	}
+
+	fn compact(&self) -> Result<(), Error> {
+		self.store.compact()
+	}
+
+	fn batch<'a>(&'a self) -> Box<ChainBatch + 'a> {
+		Box::new(CachedBatch {
+			caches: &self.caches,
+			batch: self.store.batch(),
+			ops: RefCell::new(vec![]),
+		})
+	}
+}
+
+/// Batch of writes to a CachedChainStore, updating the caches once the batch
+/// of the decorated store it wraps is committed.
+pub struct CachedBatch<'a> {
+	caches: &'a Mutex<Caches>,
+	batch: Box<ChainBatch + 'a>,
+	ops: RefCell<Vec<CacheOp>>,
+}
+
+impl<'a> ChainBatch for CachedBatch<'a> {
+	fn save_block(&self, b: &Block) -> Result<(), Error> {
+		try!(self.batch.save_block(b));
+		self.ops.borrow_mut().push(CacheOp::SaveHeader(b.header.clone()));
+		self.ops.borrow_mut().push(CacheOp::SaveBlock(b.clone()));
+		Ok(())
+	}
+
+	fn delete_block(&self, h: &Hash) -> Result<(), Error> {
+		try!(self.batch.delete_block(h));
+		self.ops.borrow_mut().push(CacheOp::DeleteBlock(*h));
+		Ok(())
+	}
+
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
+		try!(self.batch.save_block_header(bh));
+		self.ops.borrow_mut().push(CacheOp::SaveHeader(bh.clone()));
+		Ok(())
+	}
+
+	fn delete_block_header(&self, h: &Hash) -> Result<(), Error> {
+		try!(self.batch.delete_block_header(h));
+		self.ops.borrow_mut().push(CacheOp::DeleteHeader(*h));
+		Ok(())
+	}
+
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error> {
+		self.batch.save_header_head(t)
+	}
+
+	fn save_head(&self, t: &Tip) -> Result<(), Error> {
+		self.batch.save_head(t)
 	}
 
 	fn save_tip(&self, t: &Tip) -> Result<(), Error> {
-		let last_branch = t.lineage.last_branch();
-		let mut k = vec![TIP_PREFIX, SEP];
-		k.write_u32::<BigEndian>(last_branch);
-		self.db.put_ser(&mut k, t).map_err(&to_store_err)
+		self.batch.save_tip(t)
+	}
+
+	fn delete_tip(&self, t: &Tip) -> Result<(), Error> {
+		self.batch.delete_tip(t)
+	}
+
+	fn save_header_height(&self, height: u64, bh: &Hash) -> Result<(), Error> {
+		self.batch.save_header_height(height, bh)
+	}
+
+	fn delete_header_height(&self, height: u64) -> Result<(), Error> {
+		self.batch.delete_header_height(height)
+	}
+
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		self.batch.save_output_pos(h, pos)
+	}
+
+	fn delete_output_pos(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete_output_pos(h)
+	}
+
+	fn save_unspent(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error> {
+		self.batch.save_unspent(h, pos)
+	}
+
+	fn delete_unspent(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete_unspent(h)
+	}
+
+	fn save_output_record(&self, h: &Hash, rec: &OutputRecord) -> Result<(), Error> {
+		self.batch.save_output_record(h, rec)
+	}
+
+	fn delete_output_record(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete_output_record(h)
+	}
+
+	fn save_kernel(&self, excess: &Commitment, bh: &Hash) -> Result<(), Error> {
+		self.batch.save_kernel(excess, bh)
+	}
+
+	fn delete_kernel(&self, excess: &Commitment) -> Result<(), Error> {
+		self.batch.delete_kernel(excess)
+	}
+
+	fn save_recent_kernel(&self, excess: &Commitment, kernels: &RecentKernels)
+	                      -> Result<(), Error> {
+		self.batch.save_recent_kernel(excess, kernels)
+	}
+
+	fn delete_recent_kernel(&self, excess: &Commitment) -> Result<(), Error> {
+		self.batch.delete_recent_kernel(excess)
+	}
+
+	fn save_output_mmr_size(&self, size: u64) -> Result<(), Error> {
+		self.batch.save_output_mmr_size(size)
+	}
+
+	fn save_output_mmr_node(&self, height: u8, idx: u64, h: &Hash) -> Result<(), Error> {
+		self.batch.save_output_mmr_node(height, idx, h)
+	}
+
+	fn delete_output_mmr_node(&self, height: u8, idx: u64) -> Result<(), Error> {
+		self.batch.delete_output_mmr_node(height, idx)
+	}
+
+	fn save_output_leaf(&self, h: &Hash, idx: u64) -> Result<(), Error> {
+		self.batch.save_output_leaf(h, idx)
+	}
+
+	fn delete_output_leaf(&self, h: &Hash) -> Result<(), Error> {
+		self.batch.delete_output_leaf(h)
+	}
+
+	fn commit(self: Box<Self>) -> Result<(), Error> {
+		let batch = *self;
+		try!(batch.batch.commit());
+		let mut caches = batch.caches.lock().unwrap();
+		for op in batch.ops.into_inner() {
+			caches.apply(op);
+		}
+		Ok(())
+	}
+
+	fn rollback(self: Box<Self>) {
+		self.batch.rollback();
 	}
 }
 
@@ -87,10 +1730,52 @@ fn to_key(prefix: u8, val: &mut Vec<u8>) -> &mut Vec<u8> {
 	val
 }
 
+// tips are keyed by the last branch of their lineage, unique to each fork
+fn tip_key(t: &Tip) -> Vec<u8> {
+	let mut k = vec![TIP_PREFIX, SEP];
+	k.write_u32::<BigEndian>(t.lineage.last_branch()).unwrap();
+	k
+}
+
+fn height_key(height: u64) -> Vec<u8> {
+	let mut k = vec![HEADER_HEIGHT_PREFIX, SEP];
+	k.write_u64::<BigEndian>(height).unwrap();
+	k
+}
+
+fn mmr_node_key(height: u8, idx: u64) -> Vec<u8> {
+	let mut k = vec![OUTPUT_MMR_PREFIX, SEP, height];
+	k.write_u64::<BigEndian>(idx).unwrap();
+	k
+}
+
+// the output MMR size and leaf indexes are saved as plain numbers
+struct StoredU64(u64);
+
+impl ser::Writeable for StoredU64 {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		writer.write_u64(self.0)
+	}
+}
+
+impl ser::Readable<StoredU64> for StoredU64 {
+	fn read(reader: &mut ser::Reader) -> Result<StoredU64, ser::Error> {
+		Ok(StoredU64(try!(reader.read_u64())))
+	}
+}
+
 fn to_store_err(e: grin_store::Error) -> Error {
 	Error::StorageErr(format!("{:?}", e))
 }
 
+fn to_io_err(e: io::Error) -> Error {
+	Error::StorageErr(format!("{:?}", e))
+}
+
+fn to_ser_err(e: ser::Error) -> Error {
+	Error::StorageErr(format!("{:?}", e))
+}
+
 /// unwraps the inner option by converting the none case to a not found error
 fn option_to_not_found<T>(res: Result<Option<T>, grin_store::Error>) -> Result<T, Error> {
 	match res {
@@ -14,9 +14,15 @@
 
 //! Base types that the block chain pipeline requires.
 
-use core::core::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use core::core::hash::{Hash, Hashed};
 use core::core::{Block, BlockHeader};
+use core::core::target::Difficulty;
 use core::ser;
+use secp::pedersen::Commitment;
+use pipe;
 
 /// The lineage of a fork, defined as a series of numbers. Each new branch gets
 /// a new number that gets added to a fork's ancestry to form a new fork.
@@ -37,6 +43,13 @@ impl Lineage {
 	pub fn last_branch(&self) -> u32 {
 		*self.0.last().unwrap()
 	}
+	/// Derives the lineage of a new fork branching off this one, identified
+	/// by the provided branch number.
+	pub fn branch(&self, n: u32) -> Lineage {
+		let mut branches = self.0.clone();
+		branches.push(n);
+		Lineage(branches)
+	}
 }
 
 /// Serialization for lineage, necessary to serialize fork tips.
@@ -63,7 +76,8 @@ impl ser::Readable<Lineage> for Lineage {
 
 /// The tip of a fork. A handle to the fork ancestry from its leaf in the
 /// blockchain tree. References both the lineage of the fork as well as its max
-/// height and its latest and previous blocks for convenience.
+/// height, its latest and previous blocks and its total difficulty for
+/// convenience.
 #[derive(Debug, Clone)]
 pub struct Tip {
 	/// Height of the tip (max height of the fork)
@@ -72,38 +86,87 @@ pub struct Tip {
 	pub last_block_h: Hash,
 	/// Block previous to last
 	pub prev_block_h: Hash,
+	/// Total difficulty accumulated on the fork, used to select the heaviest
+	/// fork as our head
+	pub total_difficulty: Difficulty,
 	/// Lineage in branch numbers of the fork
 	pub lineage: Lineage,
 }
 
 impl Tip {
-	/// Creates a new tip at height zero and the provided genesis hash.
+	/// Creates a new tip at height zero and the provided genesis hash. The
+	/// genesis block always has a total difficulty of one.
 	pub fn new(gbh: Hash) -> Tip {
 		Tip {
 			height: 0,
 			last_block_h: gbh,
 			prev_block_h: gbh,
+			total_difficulty: Difficulty::one(),
+			lineage: Lineage::new(),
+		}
+	}
+
+	/// Creates a tip pointing to the provided header. Used for the header
+	/// chain, which doesn't keep track of fork lineages.
+	pub fn from_header(bh: &BlockHeader) -> Tip {
+		Tip {
+			height: bh.height,
+			last_block_h: bh.hash(),
+			prev_block_h: bh.previous,
+			total_difficulty: bh.total_difficulty.clone(),
 			lineage: Lineage::new(),
 		}
 	}
 
-	/// Append a new block hash to this tip, returning a new updated tip.
-	pub fn append(&self, bh: Hash) -> Tip {
+	/// Append a new block to this tip, returning a new updated tip with the
+	/// difficulty of the new block added to the total.
+	pub fn append(&self, bh: &BlockHeader) -> Tip {
 		Tip {
 			height: self.height + 1,
-			last_block_h: bh,
+			last_block_h: bh.hash(),
 			prev_block_h: self.last_block_h,
+			total_difficulty: self.total_difficulty.clone() + bh.difficulty.clone(),
 			lineage: self.lineage.clone(),
 		}
 	}
 }
 
+/// What happened to a block once it went through the chain pipeline, with the
+/// resulting tip.
+#[derive(Debug, Clone)]
+pub enum BlockStatus {
+	/// The block extended our head, carries the new head
+	Next(Tip),
+	/// The block was added to a fork that's still lighter than our head,
+	/// carries the fork tip
+	Fork(Tip),
+	/// The block made a fork heavier than our head, which became the new head
+	Reorg(Tip),
+	/// We don't have the block's parent yet, the block is kept until we do
+	Orphan,
+	/// The block timestamp is too far in the future, the block is kept until
+	/// it's not
+	Future,
+}
+
+impl BlockStatus {
+	/// The new chain head, if the block changed it.
+	pub fn head(&self) -> Option<&Tip> {
+		match *self {
+			BlockStatus::Next(ref t) |
+			BlockStatus::Reorg(ref t) => Some(t),
+			_ => None,
+		}
+	}
+}
+
 /// Serialization of a tip, required to save to datastore.
 impl ser::Writeable for Tip {
 	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
 		try!(writer.write_u64(self.height));
 		try!(writer.write_fixed_bytes(&self.last_block_h));
 		try!(writer.write_fixed_bytes(&self.prev_block_h));
+		try!(self.total_difficulty.write(writer));
 		self.lineage.write(writer)
 	}
 }
@@ -113,56 +176,702 @@ impl ser::Readable<Tip> for Tip {
 		let height = try!(reader.read_u64());
 		let last = try!(Hash::read(reader));
 		let prev = try!(Hash::read(reader));
+		let diff = try!(Difficulty::read(reader));
 		let line = try!(Lineage::read(reader));
 		Ok(Tip {
 			height: height,
 			last_block_h: last,
 			prev_block_h: prev,
+			total_difficulty: diff,
 			lineage: line,
 		})
 	}
 }
 
-#[derive(Debug)]
+/// Where an output was created, as kept in the output index. Allows checking
+/// the maturity of coinbase outputs when they get spent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputPos {
+	/// Height of the block the output was created in
+	pub height: u64,
+	/// Whether the output is the block reward
+	pub coinbase: bool,
+}
+
+impl ser::Writeable for OutputPos {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.height));
+		writer.write_u8(self.coinbase as u8)
+	}
+}
+
+impl ser::Readable<OutputPos> for OutputPos {
+	fn read(reader: &mut ser::Reader) -> Result<OutputPos, ser::Error> {
+		let height = try!(reader.read_u64());
+		let coinbase = try!(reader.read_u8());
+		Ok(OutputPos {
+			height: height,
+			coinbase: coinbase != 0,
+		})
+	}
+}
+
+/// Where an output is on our main chain, as kept in the output commitment
+/// index. Spent outputs stay in the index, with the height of the block that
+/// spent them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputRecord {
+	/// Hash of the block the output was created in
+	pub block_h: Hash,
+	/// Height of the block the output was created in
+	pub height: u64,
+	/// Index of the leaf of the output in the output MMR
+	pub mmr_pos: u64,
+	/// Height of the block that spent the output, if spent
+	pub spent_height: Option<u64>,
+}
+
+impl ser::Writeable for OutputRecord {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_fixed_bytes(&self.block_h));
+		try!(writer.write_u64(self.height));
+		try!(writer.write_u64(self.mmr_pos));
+		match self.spent_height {
+			Some(height) => {
+				try!(writer.write_u8(1));
+				writer.write_u64(height)
+			}
+			None => writer.write_u8(0),
+		}
+	}
+}
+
+impl ser::Readable<OutputRecord> for OutputRecord {
+	fn read(reader: &mut ser::Reader) -> Result<OutputRecord, ser::Error> {
+		let block_h = try!(Hash::read(reader));
+		let height = try!(reader.read_u64());
+		let mmr_pos = try!(reader.read_u64());
+		let spent_height = match try!(reader.read_u8()) {
+			0 => None,
+			_ => Some(try!(reader.read_u64())),
+		};
+		Ok(OutputRecord {
+			block_h: block_h,
+			height: height,
+			mmr_pos: mmr_pos,
+			spent_height: spent_height,
+		})
+	}
+}
+
+/// Where the kernels with a relative height and the same excess are on our
+/// main chain, as kept in the recent kernel index: the height and hash of
+/// each block having one, lowest first. Tells whether a kernel with a
+/// relative height is replayed too soon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentKernels(pub Vec<(u64, Hash)>);
+
+impl ser::Writeable for RecentKernels {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.0.len() as u64));
+		for &(height, ref bh) in &self.0 {
+			try!(writer.write_u64(height));
+			try!(writer.write_fixed_bytes(bh));
+		}
+		Ok(())
+	}
+}
+
+impl ser::Readable<RecentKernels> for RecentKernels {
+	fn read(reader: &mut ser::Reader) -> Result<RecentKernels, ser::Error> {
+		let mut kernels = vec![];
+		for _ in 0..try!(reader.read_u64()) {
+			let height = try!(reader.read_u64());
+			kernels.push((height, try!(Hash::read(reader))));
+		}
+		Ok(RecentKernels(kernels))
+	}
+}
+
+/// Which way to walk our main chain, see `ChainStoreRead::headers_iter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+	/// From the genesis towards the head
+	Forward,
+	/// From the head back towards the genesis
+	Backward,
+}
+
+/// Iterator over our main chain by height, reading what's at each height
+/// with the provided function. Stops past either end of the chain, or at the
+/// first height where nothing is found. Any other error reading a height is
+/// produced and ends the iteration. Store implementations can use it for
+/// their iterators.
+pub struct MainChainIter<'a, T> {
+	store: &'a ChainStoreRead,
+	next: Option<u64>,
+	dir: Direction,
+	read: fn(&ChainStoreRead, u64) -> Result<T, Error>,
+}
+
+impl<'a, T> MainChainIter<'a, T> {
+	/// Iterator starting at the provided height.
+	pub fn new(store: &'a ChainStoreRead,
+	           from: u64,
+	           dir: Direction,
+	           read: fn(&ChainStoreRead, u64) -> Result<T, Error>)
+	           -> MainChainIter<'a, T> {
+		MainChainIter {
+			store: store,
+			next: Some(from),
+			dir: dir,
+			read: read,
+		}
+	}
+}
+
+impl<'a, T> Iterator for MainChainIter<'a, T> {
+	type Item = Result<T, Error>;
+
+	fn next(&mut self) -> Option<Result<T, Error>> {
+		let height = match self.next.take() {
+			Some(height) => height,
+			None => return None,
+		};
+		match (self.read)(self.store, height) {
+			Ok(item) => {
+				self.next = match self.dir {
+					Direction::Forward => height.checked_add(1),
+					Direction::Backward => height.checked_sub(1),
+				};
+				Some(Ok(item))
+			}
+			Err(Error::NotFoundErr) => None,
+			Err(e) => Some(Err(e)),
+		}
+	}
+}
+
+/// Iterator over the header of a block and its ancestors, down to the
+/// genesis. Follows the parent of each block so works for forks as well.
+pub struct AncestorIter<'a, S: ChainStoreRead + ?Sized + 'a> {
+	store: &'a S,
+	next: Option<Hash>,
+}
+
+impl<'a, S: ChainStoreRead + ?Sized> AncestorIter<'a, S> {
+	/// Iterator starting at the block with the provided hash.
+	pub fn new(store: &'a S, h: Hash) -> AncestorIter<'a, S> {
+		AncestorIter {
+			store: store,
+			next: Some(h),
+		}
+	}
+}
+
+impl<'a, S: ChainStoreRead + ?Sized> Iterator for AncestorIter<'a, S> {
+	type Item = Result<BlockHeader, Error>;
+
+	fn next(&mut self) -> Option<Result<BlockHeader, Error>> {
+		let h = match self.next.take() {
+			Some(h) => h,
+			None => return None,
+		};
+		match self.store.get_block_header(&h) {
+			Ok(bh) => {
+				if bh.height > 0 {
+					self.next = Some(bh.previous);
+				}
+				Some(Ok(bh))
+			}
+			Err(e) => Some(Err(e)),
+		}
+	}
+}
+
+/// Iterator over the timestamp and difficulty of a block and its ancestors,
+/// down to the genesis, feeding the difficulty adjustment of the block that
+/// follows, see `consensus::next_difficulty`. Follows the parent of each block
+/// so works for forks as well.
+pub struct DifficultyIter<'a, S: ChainStoreRead + ?Sized + 'a> {
+	ancestors: AncestorIter<'a, S>,
+}
+
+impl<'a, S: ChainStoreRead + ?Sized> DifficultyIter<'a, S> {
+	/// Iterator starting at the block with the provided hash.
+	pub fn new(store: &'a S, h: Hash) -> DifficultyIter<'a, S> {
+		DifficultyIter { ancestors: AncestorIter::new(store, h) }
+	}
+}
+
+impl<'a, S: ChainStoreRead + ?Sized> Iterator for DifficultyIter<'a, S> {
+	type Item = Result<(i64, Difficulty), Error>;
+
+	fn next(&mut self) -> Option<Result<(i64, Difficulty), Error>> {
+		self.ancestors
+			.next()
+			.map(|res| res.map(|bh| (bh.timestamp.to_timespec().sec, bh.difficulty)))
+	}
+}
+
+#[derive(Debug, Clone)]
 pub enum Error {
 	/// Couldn't find what we were looking for
 	NotFoundErr,
 	/// Error generated by the underlying storage layer
 	StorageErr(String),
+	/// The data in store is laid out in the provided schema version, newer
+	/// than the ones we know of
+	SchemaErr(u64),
 }
 
-/// Trait the chain pipeline requires an implementor for in order to process
-/// blocks.
-pub trait ChainStore: Send + Sync {
+/// Reading side of the chain store, all that's needed to look up the chain
+/// without changing it. What API and wallet threads get, see
+/// `ReadOnlyStore`.
+pub trait ChainStoreRead: Send + Sync {
 	/// Get the tip that's also the head of the chain
 	fn head(&self) -> Result<Tip, Error>;
 
 	/// Block header for the chain head
 	fn head_header(&self) -> Result<BlockHeader, Error>;
 
+	/// Gets a block by hash
+	fn get_block(&self, h: &Hash) -> Result<Block, Error>;
+
 	/// Gets a block header by hash
 	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error>;
 
+	/// Gets the header of the block at the provided height on our main chain
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error>;
+
+	/// Iterates over the headers of our main chain from the provided height,
+	/// in either direction, see `MainChainIter`
+	fn headers_iter<'a>(&'a self,
+	                    from: u64,
+	                    dir: Direction)
+	                    -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a>;
+
+	/// Iterates over the blocks of our main chain from the provided height, in
+	/// either direction. Stops at the first block whose body was pruned.
+	fn blocks_iter<'a>(&'a self,
+	                   from: u64,
+	                   dir: Direction)
+	                   -> Box<Iterator<Item = Result<Block, Error>> + 'a>;
+
+	/// Iterates over the headers of our main chain with a height from the
+	/// first one included up to the second one excluded, scanning the height
+	/// index. Stops at the first height where nothing is found.
+	fn headers_range<'a>(&'a self,
+	                     from: u64,
+	                     to: u64)
+	                     -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a>;
+
+	/// Get the tip of the header chain, which can be ahead of the head when
+	/// headers are processed before their blocks. Defaults to the head.
+	fn get_header_head(&self) -> Result<Tip, Error>;
+
+	/// Get all the tips we know of, one for each fork, including the head
+	fn get_tips(&self) -> Result<Vec<Tip>, Error>;
+
+	/// Gets where the output with the provided hash was created
+	fn get_output_pos(&self, h: &Hash) -> Result<OutputPos, Error>;
+
+	/// Gets where the output with the provided hash was created if it's
+	/// unspent as of our chain head
+	fn get_unspent(&self, h: &Hash) -> Result<OutputPos, Error>;
+
+	/// Gets where the output with the provided hash is on our main chain,
+	/// spent or not. An output hash is the hash of its features and
+	/// commitment, so this indexes outputs by commitment. Outputs only known
+	/// from a snapshot aren't indexed.
+	fn get_output_record(&self, h: &Hash) -> Result<OutputRecord, Error>;
+
+	/// Gets the hash of the block on our chain having the kernel with the
+	/// provided excess
+	fn get_kernel(&self, excess: &Commitment) -> Result<Hash, Error>;
+
+	/// Gets all the outputs unspent as of our chain head, with where they
+	/// were created
+	fn get_unspent_outputs(&self) -> Result<Vec<(Hash, OutputPos)>, Error>;
+
+	/// Iterates over the outputs unspent as of our chain head whose hash
+	/// starts with the provided bytes, in hash order, with where they were
+	/// created. No prefix iterates over all of them.
+	fn unspent_iter<'a>(&'a self,
+	                    prefix: &[u8])
+	                    -> Box<Iterator<Item = Result<(Hash, OutputPos), Error>> + 'a>;
+
+	/// Gets the excess of all the kernels on our chain, with the hash of the
+	/// block each is in
+	fn get_kernels(&self) -> Result<Vec<(Commitment, Hash)>, Error>;
+
+	/// Gets where the kernels with a relative height and the provided excess
+	/// are on our chain
+	fn get_recent_kernel(&self, excess: &Commitment) -> Result<RecentKernels, Error>;
+
+	/// Gets the excess of all the kernels with a relative height on our
+	/// chain, with where the kernels having each are
+	fn get_recent_kernels(&self) -> Result<Vec<(Commitment, RecentKernels)>, Error>;
+
+	/// Number of leaves in the output MMR of our chain head, see
+	/// `core::core::mmr`
+	fn get_output_mmr_size(&self) -> Result<u64, Error>;
+
+	/// Gets the node of the output MMR at the provided height and index
+	fn get_output_mmr_node(&self, height: u8, idx: u64) -> Result<Hash, Error>;
+
+	/// Gets the index of the leaf of the output with the provided hash in the
+	/// output MMR, kept after the output is spent
+	fn get_output_leaf(&self, h: &Hash) -> Result<u64, Error>;
+
+	/// Space the store takes on disk, in bytes
+	fn disk_usage(&self) -> Result<u64, Error>;
+
+	/// Writes a copy of the store under the provided directory, consistent
+	/// even with blocks getting added in the meantime. The directory can be
+	/// opened as a store of its own or restored from.
+	fn backup(&self, path: &str) -> Result<(), Error>;
+}
+
+/// Writing side of the chain store, only ever used by the chain itself.
+pub trait ChainStoreWrite: ChainStoreRead {
 	/// Save the provided block in store
 	fn save_block(&self, b: &Block) -> Result<(), Error>;
 
+	/// Save the provided block header in store, without its block
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error>;
+
+	/// Save the provided tip as the head of the header chain
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error>;
+
 	/// Save the provided tip as the current head of our chain
 	fn save_head(&self, t: &Tip) -> Result<(), Error>;
 
 	/// Save the provided tip without setting it as head
 	fn save_tip(&self, t: &Tip) -> Result<(), Error>;
+
+	/// Save where the output with the provided hash was created
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error>;
+
+	/// Reclaims the disk space still taken by removed entries, like the blocks
+	/// of forks that were compacted away
+	fn compact(&self) -> Result<(), Error>;
+
+	/// Starts a batch of writes that only get applied, all at once, when the
+	/// batch is committed. Keeps the chain state consistent when several
+	/// related entries need to be saved together.
+	fn batch<'a>(&'a self) -> Box<ChainBatch + 'a>;
+}
+
+/// Trait the chain pipeline requires an implementor for in order to process
+/// blocks, reading and writing. Implemented by every store implementing both
+/// sides.
+pub trait ChainStore: ChainStoreRead + ChainStoreWrite {}
+
+impl<T: ChainStoreRead + ChainStoreWrite> ChainStore for T {}
+
+/// Handle on a chain store that can only read from it, for threads that have
+/// no business changing the chain, like the API server's.
+#[derive(Clone)]
+pub struct ReadOnlyStore {
+	store: Arc<ChainStore>,
+}
+
+impl ReadOnlyStore {
+	/// Read-only handle on the provided store.
+	pub fn new(store: Arc<ChainStore>) -> ReadOnlyStore {
+		ReadOnlyStore { store: store }
+	}
+}
+
+impl ChainStoreRead for ReadOnlyStore {
+	fn head(&self) -> Result<Tip, Error> {
+		self.store.head()
+	}
+
+	fn head_header(&self) -> Result<BlockHeader, Error> {
+		self.store.head_header()
+	}
+
+	fn get_block(&self, h: &Hash) -> Result<Block, Error> {
+		self.store.get_block(h)
+	}
+
+	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error> {
+		self.store.get_block_header(h)
+	}
+
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
+		self.store.get_header_by_height(height)
+	}
+
+	fn headers_iter<'a>(&'a self,
+	                    from: u64,
+	                    dir: Direction)
+	                    -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		self.store.headers_iter(from, dir)
+	}
+
+	fn blocks_iter<'a>(&'a self,
+	                   from: u64,
+	                   dir: Direction)
+	                   -> Box<Iterator<Item = Result<Block, Error>> + 'a> {
+		self.store.blocks_iter(from, dir)
+	}
+
+	fn headers_range<'a>(&'a self,
+	                     from: u64,
+	                     to: u64)
+	                     -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		self.store.headers_range(from, to)
+	}
+
+	fn get_header_head(&self) -> Result<Tip, Error> {
+		self.store.get_header_head()
+	}
+
+	fn get_tips(&self) -> Result<Vec<Tip>, Error> {
+		self.store.get_tips()
+	}
+
+	fn get_output_pos(&self, h: &Hash) -> Result<OutputPos, Error> {
+		self.store.get_output_pos(h)
+	}
+
+	fn get_unspent(&self, h: &Hash) -> Result<OutputPos, Error> {
+		self.store.get_unspent(h)
+	}
+
+	fn get_output_record(&self, h: &Hash) -> Result<OutputRecord, Error> {
+		self.store.get_output_record(h)
+	}
+
+	fn get_kernel(&self, excess: &Commitment) -> Result<Hash, Error> {
+		self.store.get_kernel(excess)
+	}
+
+	fn get_unspent_outputs(&self) -> Result<Vec<(Hash, OutputPos)>, Error> {
+		self.store.get_unspent_outputs()
+	}
+
+	fn unspent_iter<'a>(&'a self,
+	                    prefix: &[u8])
+	                    -> Box<Iterator<Item = Result<(Hash, OutputPos), Error>> + 'a> {
+		self.store.unspent_iter(prefix)
+	}
+
+	fn get_kernels(&self) -> Result<Vec<(Commitment, Hash)>, Error> {
+		self.store.get_kernels()
+	}
+
+	fn get_recent_kernel(&self, excess: &Commitment) -> Result<RecentKernels, Error> {
+		self.store.get_recent_kernel(excess)
+	}
+
+	fn get_recent_kernels(&self) -> Result<Vec<(Commitment, RecentKernels)>, Error> {
+		self.store.get_recent_kernels()
+	}
+
+	fn get_output_mmr_size(&self) -> Result<u64, Error> {
+		self.store.get_output_mmr_size()
+	}
+
+	fn get_output_mmr_node(&self, height: u8, idx: u64) -> Result<Hash, Error> {
+		self.store.get_output_mmr_node(height, idx)
+	}
+
+	fn get_output_leaf(&self, h: &Hash) -> Result<u64, Error> {
+		self.store.get_output_leaf(h)
+	}
+
+	fn disk_usage(&self) -> Result<u64, Error> {
+		self.store.disk_usage()
+	}
+
+	fn backup(&self, path: &str) -> Result<(), Error> {
+		self.store.backup(path)
+	}
+}
+
+/// Batch of writes to the chain store, see `ChainStoreWrite::batch`. The save
+/// operations mirror the ones on `ChainStoreWrite`. The set of unspent outputs is
+/// only ever updated along with the head, so only through a batch, same for
+/// the kernels and the output MMR.
+pub trait ChainBatch {
+	/// Save the provided block
+	fn save_block(&self, b: &Block) -> Result<(), Error>;
+
+	/// Removes the body of the block with the provided hash, keeping its
+	/// header
+	fn delete_block(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Save the provided block header, without its block
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error>;
+
+	/// Removes the header of the block with the provided hash
+	fn delete_block_header(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Save the provided tip as the head of the header chain
+	fn save_header_head(&self, t: &Tip) -> Result<(), Error>;
+
+	/// Save the provided tip as the current head of our chain
+	fn save_head(&self, t: &Tip) -> Result<(), Error>;
+
+	/// Save the provided tip without setting it as head
+	fn save_tip(&self, t: &Tip) -> Result<(), Error>;
+
+	/// Removes the provided tip, once its fork is abandoned
+	fn delete_tip(&self, t: &Tip) -> Result<(), Error>;
+
+	/// Save the hash of the block at the provided height on our main chain
+	fn save_header_height(&self, height: u64, bh: &Hash) -> Result<(), Error>;
+
+	/// Removes the provided height from our main chain, when a reorg makes
+	/// it shorter
+	fn delete_header_height(&self, height: u64) -> Result<(), Error>;
+
+	/// Save where the output with the provided hash was created
+	fn save_output_pos(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error>;
+
+	/// Forgets where the output with the provided hash was created, when the
+	/// only fork creating it gets removed
+	fn delete_output_pos(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Adds the output with the provided hash to the unspent outputs
+	fn save_unspent(&self, h: &Hash, pos: &OutputPos) -> Result<(), Error>;
+
+	/// Removes the output with the provided hash from the unspent outputs
+	fn delete_unspent(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Save where the output with the provided hash is on our main chain
+	fn save_output_record(&self, h: &Hash, rec: &OutputRecord) -> Result<(), Error>;
+
+	/// Removes the output with the provided hash from the output commitment
+	/// index, when the block creating it leaves our main chain
+	fn delete_output_record(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Save the hash of the block on our chain having the kernel with the
+	/// provided excess
+	fn save_kernel(&self, excess: &Commitment, bh: &Hash) -> Result<(), Error>;
+
+	/// Removes the kernel with the provided excess from our chain
+	fn delete_kernel(&self, excess: &Commitment) -> Result<(), Error>;
+
+	/// Save where the kernels with a relative height and the provided excess
+	/// are on our chain
+	fn save_recent_kernel(&self, excess: &Commitment, kernels: &RecentKernels)
+	                      -> Result<(), Error>;
+
+	/// Removes the kernels with a relative height and the provided excess
+	/// from our chain
+	fn delete_recent_kernel(&self, excess: &Commitment) -> Result<(), Error>;
+
+	/// Save the number of leaves in the output MMR
+	fn save_output_mmr_size(&self, size: u64) -> Result<(), Error>;
+
+	/// Save the node of the output MMR at the provided height and index
+	fn save_output_mmr_node(&self, height: u8, idx: u64, h: &Hash) -> Result<(), Error>;
+
+	/// Removes the node of the output MMR at the provided height and index
+	fn delete_output_mmr_node(&self, height: u8, idx: u64) -> Result<(), Error>;
+
+	/// Save the index of the leaf of the output with the provided hash in the
+	/// output MMR
+	fn save_output_leaf(&self, h: &Hash, idx: u64) -> Result<(), Error>;
+
+	/// Removes the leaf index of the output with the provided hash
+	fn delete_output_leaf(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Applies all the writes in the batch in a single atomic operation
+	fn commit(self: Box<Self>) -> Result<(), Error>;
+
+	/// Discards all the writes in the batch, leaving the store as it was.
+	/// Same as dropping the batch without committing it.
+	fn rollback(self: Box<Self>);
 }
 
 /// Bridge between the chain pipeline and the rest of the system. Handles
 /// downstream processing of valid blocks by the rest of the system, most
 /// importantly the broadcasting of blocks to our peers.
-pub trait ChainAdapter {
+pub trait ChainAdapter: Send + Sync {
 	/// The blockchain pipeline has accepted this block as valid and added
-	/// it to our chain.
-	fn block_accepted(&self, b: &Block);
+	/// it to our chain. The source is the peer the block came from, if any.
+	fn block_accepted(&self, b: &Block, source: Option<SocketAddr>);
+
+	/// The blockchain pipeline has refused the block with this header. The
+	/// error tells whether the block is actually invalid (see
+	/// `pipe::Error::is_bad_data`), in which case whoever sent it, the source
+	/// peer, shouldn't be trusted, or just couldn't be added, as is the case
+	/// for duplicates.
+	fn block_rejected(&self, bh: &BlockHeader, e: &pipe::Error, source: Option<SocketAddr>);
 }
 
 pub struct NoopAdapter { }
 impl ChainAdapter for NoopAdapter {
-	fn block_accepted(&self, b: &Block) {}
+	fn block_accepted(&self, b: &Block, source: Option<SocketAddr>) {}
+	fn block_rejected(&self, bh: &BlockHeader, e: &pipe::Error, source: Option<SocketAddr>) {}
+}
+
+/// Adapter forwarding everything to all the adapters registered with it, in
+/// registration order. Lets several components get notified by the chain,
+/// which only takes one adapter.
+pub struct MultiAdapter {
+	adapters: RwLock<Vec<Arc<ChainAdapter>>>,
+}
+
+impl MultiAdapter {
+	/// A new adapter without anything registered yet.
+	pub fn new() -> MultiAdapter {
+		MultiAdapter { adapters: RwLock::new(vec![]) }
+	}
+
+	/// Registers an adapter to notify, can be done at any time.
+	pub fn add(&self, adapter: Arc<ChainAdapter>) {
+		self.adapters.write().unwrap().push(adapter);
+	}
+}
+
+impl ChainAdapter for MultiAdapter {
+	fn block_accepted(&self, b: &Block, source: Option<SocketAddr>) {
+		for adapter in self.adapters.read().unwrap().iter() {
+			adapter.block_accepted(b, source);
+		}
+	}
+	fn block_rejected(&self, bh: &BlockHeader, e: &pipe::Error, source: Option<SocketAddr>) {
+		for adapter in self.adapters.read().unwrap().iter() {
+			adapter.block_rejected(bh, e, source);
+		}
+	}
+}
+
+/// Provides a block to the pipeline in two steps, the header right away and
+/// the body only if the header is valid. Allows not reading or fetching
+/// bodies for invalid headers.
+pub trait BlockProvider {
+	/// Header of the block
+	fn header(&self) -> &BlockHeader;
+
+	/// The full block, only asked for once the header has been validated
+	fn body(&mut self) -> Result<&Block, pipe::Error>;
+}
+
+/// A block we already have in full is its own provider.
+impl<'a> BlockProvider for &'a Block {
+	fn header(&self) -> &BlockHeader {
+		&self.header
+	}
+
+	fn body(&mut self) -> Result<&Block, pipe::Error> {
+		Ok(*self)
+	}
+}
+
+impl<'a, P: BlockProvider + ?Sized> BlockProvider for &'a mut P {
+	fn header(&self) -> &BlockHeader {
+		(**self).header()
+	}
+
+	fn body(&mut self) -> Result<&Block, pipe::Error> {
+		(**self).body()
+	}
 }
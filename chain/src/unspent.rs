@@ -0,0 +1,311 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking of the set of unspent outputs, of the output MMR, of the output
+//! commitment index and of the kernels on the chain, the recent kernel index
+//! included, as blocks get applied to or undone from the chain head.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use secp::pedersen::Commitment;
+
+use core::core::Block;
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::mmr::{MountainRange, NodeSource};
+use pipe::Error;
+use types::{self, ChainStore, ChainBatch, OutputPos, OutputRecord, RecentKernels};
+
+/// The set of unspent outputs, the output MMR and the kernels (transaction
+/// proofs) of the chain, with the changes made by the blocks applied or undone
+/// so far. Changes are only kept in memory until saved, anything they don't
+/// cover is read from the store.
+pub struct UnspentView {
+	store: Arc<ChainStore>,
+	changes: HashMap<Hash, Option<OutputPos>>,
+	outputs: MountainRange<StoreNodes>,
+	// leaf index in the output MMR by output hash
+	leaf_changes: HashMap<Hash, Option<u64>>,
+	// where outputs are on the chain by output hash, spent ones included
+	record_changes: HashMap<Hash, Option<OutputRecord>>,
+	// kernels by excess, with the block they're in
+	kernel_changes: HashMap<Vec<u8>, (Commitment, Option<Hash>)>,
+	// kernels with a relative height by excess, with where they are
+	recent_changes: HashMap<Vec<u8>, (Commitment, RecentKernels)>,
+}
+
+impl UnspentView {
+	/// A view of the set of unspent outputs saved in the provided store.
+	pub fn new(store: Arc<ChainStore>) -> Result<UnspentView, Error> {
+		let size = try!(store.get_output_mmr_size().map_err(&Error::StoreErr));
+		Ok(UnspentView {
+			store: store.clone(),
+			changes: HashMap::new(),
+			outputs: MountainRange::new(StoreNodes(store), size),
+			leaf_changes: HashMap::new(),
+			record_changes: HashMap::new(),
+			kernel_changes: HashMap::new(),
+			recent_changes: HashMap::new(),
+		})
+	}
+
+	/// Applies the block on top of the view, spending its inputs and adding its
+	/// outputs and kernels. Spent outputs get pruned from the output MMR, new
+	/// ones appended to it, and both recorded in the output commitment index.
+	/// Fails if an input doesn't spend an unspent output, or spends a coinbase
+	/// output less than the provided maturity blocks old, if an output is
+	/// already unspent or a kernel is already on the chain, which would be a
+	/// replayed transaction. A kernel with a relative height can be on the
+	/// chain again, only not within its relative height.
+	pub fn apply(&mut self, b: &Block, coinbase_maturity: u64) -> Result<(), Error> {
+		for input in &b.inputs {
+			let h = input.output_hash();
+			let pos = match try!(self.get(&h)) {
+				Some(pos) => pos,
+				None => return Err(Error::MissingOutput),
+			};
+			// where the output is unspent on this fork, not where any fork
+			// last created it
+			if pos.coinbase && b.header.height < pos.height.saturating_add(coinbase_maturity) {
+				return Err(Error::ImmatureCoinbase);
+			}
+			self.changes.insert(h, None);
+			let leaf = try!(self.get_leaf(&h));
+			try!(self.outputs.prune(leaf).map_err(&Error::StoreErr));
+			try!(self.set_spent(&h, Some(b.header.height)));
+		}
+		let bh = b.hash();
+		for (h, pos) in output_positions(b) {
+			if try!(self.get(&h)).is_some() {
+				return Err(Error::DuplicateOutput);
+			}
+			self.changes.insert(h, Some(pos));
+			let leaf = try!(self.outputs.push(h).map_err(&Error::StoreErr));
+			self.leaf_changes.insert(h, Some(leaf));
+			self.record_changes.insert(h,
+			                           Some(OutputRecord {
+				                           block_h: bh,
+				                           height: b.header.height,
+				                           mmr_pos: leaf,
+				                           spent_height: None,
+			                           }));
+		}
+		for proof in &b.proofs {
+			if proof.relative_height > 0 {
+				let mut recent = try!(self.get_recent(&proof.remainder));
+				// the heights are in order, the last one is the most recent
+				if let Some(&(height, _)) = recent.0.last() {
+					if b.header.height.saturating_sub(height) < proof.relative_height {
+						return Err(Error::RecentDuplicateKernel);
+					}
+				}
+				recent.0.push((b.header.height, bh));
+				let key = proof.remainder.bytes().to_vec();
+				self.recent_changes.insert(key, (proof.remainder, recent));
+				continue;
+			}
+			if try!(self.get_kernel(&proof.remainder)).is_some() {
+				return Err(Error::DuplicateKernel);
+			}
+			self.kernel_changes.insert(proof.remainder.bytes().to_vec(), (proof.remainder, Some(bh)));
+		}
+		Ok(())
+	}
+
+	/// Undoes the block, which is expected to be the last one applied. Its
+	/// outputs and kernels go away and the outputs its inputs spent are
+	/// unspent again.
+	pub fn rewind(&mut self, b: &Block) -> Result<(), Error> {
+		for out in &b.outputs {
+			self.changes.insert(out.hash(), None);
+			self.leaf_changes.insert(out.hash(), None);
+			self.record_changes.insert(out.hash(), None);
+		}
+		let size = self.outputs.size() - b.outputs.len() as u64;
+		self.outputs.truncate(size);
+		for input in &b.inputs {
+			let h = input.output_hash();
+			// the index has where any fork last created the output, the record
+			// where it is on this chain
+			let mut pos = try!(self.store.get_output_pos(&h).map_err(&Error::StoreErr));
+			if let Some(rec) = try!(self.get_record(&h)) {
+				pos.height = rec.height;
+			}
+			self.changes.insert(h, Some(pos));
+			let leaf = try!(self.get_leaf(&h));
+			try!(self.outputs.restore(leaf, h).map_err(&Error::StoreErr));
+			try!(self.set_spent(&h, None));
+		}
+		let bh = b.hash();
+		for proof in &b.proofs {
+			let key = proof.remainder.bytes().to_vec();
+			if proof.relative_height > 0 {
+				let mut recent = try!(self.get_recent(&proof.remainder));
+				recent.0.retain(|&(_, h)| h != bh);
+				self.recent_changes.insert(key, (proof.remainder, recent));
+			} else {
+				self.kernel_changes.insert(key, (proof.remainder, None));
+			}
+		}
+		Ok(())
+	}
+
+	/// Root of the output MMR, what the header of the last block applied has
+	/// to commit to.
+	pub fn root(&self) -> Result<Hash, Error> {
+		self.outputs.root().map_err(&Error::StoreErr)
+	}
+
+	/// Adds all the changes to the provided batch.
+	pub fn save(&self, batch: &ChainBatch) -> Result<(), Error> {
+		for (h, change) in &self.changes {
+			let res = match *change {
+				Some(ref pos) => batch.save_unspent(h, pos),
+				None => batch.delete_unspent(h),
+			};
+			try!(res.map_err(&Error::StoreErr));
+		}
+		// nodes with only pruned leaves beneath them aren't worth keeping
+		for (&(height, idx), h) in self.outputs.changes() {
+			let res = if *h == ZERO_HASH {
+				batch.delete_output_mmr_node(height, idx)
+			} else {
+				batch.save_output_mmr_node(height, idx, h)
+			};
+			try!(res.map_err(&Error::StoreErr));
+		}
+		try!(batch.save_output_mmr_size(self.outputs.size()).map_err(&Error::StoreErr));
+		for (h, change) in &self.leaf_changes {
+			let res = match *change {
+				Some(leaf) => batch.save_output_leaf(h, leaf),
+				None => batch.delete_output_leaf(h),
+			};
+			try!(res.map_err(&Error::StoreErr));
+		}
+		for (h, change) in &self.record_changes {
+			let res = match *change {
+				Some(ref rec) => batch.save_output_record(h, rec),
+				None => batch.delete_output_record(h),
+			};
+			try!(res.map_err(&Error::StoreErr));
+		}
+		for &(ref excess, ref change) in self.kernel_changes.values() {
+			let res = match *change {
+				Some(ref bh) => batch.save_kernel(excess, bh),
+				None => batch.delete_kernel(excess),
+			};
+			try!(res.map_err(&Error::StoreErr));
+		}
+		for &(ref excess, ref recent) in self.recent_changes.values() {
+			let res = if recent.0.is_empty() {
+				batch.delete_recent_kernel(excess)
+			} else {
+				batch.save_recent_kernel(excess, recent)
+			};
+			try!(res.map_err(&Error::StoreErr));
+		}
+		Ok(())
+	}
+
+	fn get(&self, h: &Hash) -> Result<Option<OutputPos>, Error> {
+		if let Some(change) = self.changes.get(h) {
+			return Ok(change.clone());
+		}
+		match self.store.get_unspent(h) {
+			Ok(pos) => Ok(Some(pos)),
+			Err(types::Error::NotFoundErr) => Ok(None),
+			Err(e) => Err(Error::StoreErr(e)),
+		}
+	}
+
+	fn get_leaf(&self, h: &Hash) -> Result<u64, Error> {
+		match self.leaf_changes.get(h) {
+			Some(&Some(leaf)) => Ok(leaf),
+			Some(&None) => Err(Error::StoreErr(types::Error::NotFoundErr)),
+			None => self.store.get_output_leaf(h).map_err(&Error::StoreErr),
+		}
+	}
+
+	// updates the spent height in the record of the output, outputs only
+	// known from a snapshot not having one
+	fn set_spent(&mut self, h: &Hash, spent_height: Option<u64>) -> Result<(), Error> {
+		if let Some(mut rec) = try!(self.get_record(h)) {
+			rec.spent_height = spent_height;
+			self.record_changes.insert(*h, Some(rec));
+		}
+		Ok(())
+	}
+
+	fn get_record(&self, h: &Hash) -> Result<Option<OutputRecord>, Error> {
+		if let Some(change) = self.record_changes.get(h) {
+			return Ok(change.clone());
+		}
+		match self.store.get_output_record(h) {
+			Ok(rec) => Ok(Some(rec)),
+			Err(types::Error::NotFoundErr) => Ok(None),
+			Err(e) => Err(Error::StoreErr(e)),
+		}
+	}
+
+	fn get_kernel(&self, excess: &Commitment) -> Result<Option<Hash>, Error> {
+		if let Some(&(_, change)) = self.kernel_changes.get(excess.bytes()) {
+			return Ok(change);
+		}
+		match self.store.get_kernel(excess) {
+			Ok(bh) => Ok(Some(bh)),
+			Err(types::Error::NotFoundErr) => Ok(None),
+			Err(e) => Err(Error::StoreErr(e)),
+		}
+	}
+
+	fn get_recent(&self, excess: &Commitment) -> Result<RecentKernels, Error> {
+		if let Some(&(_, ref recent)) = self.recent_changes.get(excess.bytes()) {
+			return Ok(recent.clone());
+		}
+		match self.store.get_recent_kernel(excess) {
+			Ok(recent) => Ok(recent),
+			Err(types::Error::NotFoundErr) => Ok(RecentKernels(vec![])),
+			Err(e) => Err(Error::StoreErr(e)),
+		}
+	}
+}
+
+/// Reads the nodes of the output MMR from the store.
+pub struct StoreNodes(pub Arc<ChainStore>);
+
+impl NodeSource for StoreNodes {
+	type Error = types::Error;
+
+	fn node(&self, height: u8, idx: u64) -> Result<Option<Hash>, types::Error> {
+		match self.0.get_output_mmr_node(height, idx) {
+			Ok(h) => Ok(Some(h)),
+			Err(types::Error::NotFoundErr) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+/// Where each output of the block is created, telling coinbase outputs apart.
+pub fn output_positions(b: &Block) -> Vec<(Hash, OutputPos)> {
+	b.outputs
+		.iter()
+		.map(|out| {
+			let pos = OutputPos {
+				height: b.header.height,
+				coinbase: out.is_coinbase(),
+			};
+			(out.hash(), pos)
+		})
+		.collect()
+}
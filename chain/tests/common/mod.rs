@@ -0,0 +1,168 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers shared by the chain tests, to set up a chain and mine blocks on it.
+
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use rand;
+use rand::os::OsRng;
+use secp;
+use time;
+
+use grin_chain;
+use grin_chain::types::*;
+use grin_core;
+use grin_core::core::hash::{Hash, Hashed};
+use grin_core::core::mmr::MountainRange;
+use grin_core::pow;
+use grin_core::core;
+use grin_core::consensus;
+
+// backend the chain is stored in, LMDB when the GRIN_TEST_BACKEND environment
+// variable is lmdb, which also requires the lmdb_backend feature, or RocksDB
+// with column families when it's rocksdb_columns
+pub fn test_backend() -> grin_chain::StoreBackend {
+  match env::var("GRIN_TEST_BACKEND") {
+    Ok(ref backend) if backend == "lmdb" => grin_chain::StoreBackend::Lmdb,
+    Ok(ref backend) if backend == "rocksdb_columns" => grin_chain::StoreBackend::RocksDbColumns,
+    _ => grin_chain::StoreBackend::RocksDb,
+  }
+}
+
+// a new directory under the temporary one, for a single test
+pub fn test_dir(name: &str) -> String {
+  let dir = env::temp_dir().join(format!("grin-chain-{}-{}", name, rand::random::<u32>()));
+  dir.to_str().unwrap().to_string()
+}
+
+// opens the chain store under the provided directory with the backend under
+// test
+pub fn new_store(root: &str) -> grin_chain::store::ChainKVStore {
+  grin_chain::store::ChainKVStore::with_backend(root.to_string(), test_backend()).unwrap()
+}
+
+// the genesis the tests mine on, with the smallest graph size
+pub fn test_genesis() -> core::Block {
+  let mut gen = grin_core::genesis::genesis();
+  gen.header.cuckoo_len = 16;
+  gen
+}
+
+// a new store for a single test, holding the test genesis as its head
+pub fn genesis_store(name: &str) -> (grin_chain::store::ChainKVStore, core::Block) {
+  let store = new_store(&test_dir(name));
+  let gen = test_genesis();
+  store.save_block(&gen).unwrap();
+  store.save_head(&Tip::new(gen.hash())).unwrap();
+  (store, gen)
+}
+
+// a new chain over a store from genesis_store, with no adapter
+pub fn new_chain(name: &str) -> (grin_chain::Chain, core::Block) {
+  let (store, gen) = genesis_store(name);
+  let chain = grin_chain::Chain::new(Arc::new(store), Arc::new(NoopAdapter{})).unwrap();
+  (chain, gen)
+}
+
+// builds and mines a new empty block on top of the provided header, with a
+// timestamp ahead of it by the provided amount of seconds
+pub fn prepare_block(prev: &core::BlockHeader, secs: i64) -> core::Block {
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+  prepare_block_with(prev, secs, vec![], reward_key)
+}
+
+// same as prepare_block but including the provided transactions and paying
+// the reward to the provided key
+pub fn prepare_block_with(prev: &core::BlockHeader,
+                      secs: i64,
+                      txs: Vec<&mut core::Transaction>,
+                      reward_key: secp::key::SecretKey)
+                      -> core::Block {
+  let mut b = core::Block::new(prev, txs, reward_key).unwrap();
+  b.header.timestamp = prev.timestamp + time::Duration::seconds(secs);
+  let outputs = apply_outputs(prev, &b);
+  b.header.utxo_merkle = outputs.0.root().unwrap();
+
+  let ancestors = ancestors(prev);
+  let window = ancestors.iter()
+    .map(|bh| (bh.timestamp.to_timespec().sec, bh.difficulty.clone()))
+    .collect::<Vec<_>>();
+  let difficulty = consensus::next_difficulty(&window);
+  let scalings = ancestors.iter()
+    .map(|bh| (bh.pow_family, bh.secondary_scaling))
+    .collect::<Vec<_>>();
+  b.header.secondary_scaling = consensus::next_secondary_scaling(b.header.height, &scalings);
+  let (proof, nonce) = pow::pow_size(&b, difficulty.clone(), prev.cuckoo_len as u32).unwrap();
+  b.header.pow = proof;
+  b.header.nonce = nonce;
+  b.header.total_difficulty = prev.total_difficulty.clone() + difficulty.clone();
+  b.header.difficulty = difficulty;
+  OUTPUTS.with(|all| all.borrow_mut().insert(b.hash(), outputs));
+  b
+}
+
+// headers of the blocks built so far and of their parents, for the blocks
+// built on top to adjust their difficulty
+thread_local!(static HEADERS: RefCell<HashMap<Hash, core::BlockHeader>> =
+  RefCell::new(HashMap::new()));
+
+// the provided header and the ones before it, as far back as the difficulty
+// adjustment goes
+fn ancestors(prev: &core::BlockHeader) -> Vec<core::BlockHeader> {
+  HEADERS.with(|all| {
+    let mut all = all.borrow_mut();
+    all.insert(prev.hash(), prev.clone());
+    let mut window = vec![];
+    let mut next = Some(prev.hash());
+    while let Some(bh) = next.and_then(|h| all.get(&h)) {
+      if window.len() > consensus::DIFFICULTY_ADJUST_WINDOW {
+        break;
+      }
+      window.push(bh.clone());
+      next = if bh.height > 0 { Some(bh.previous) } else { None };
+    }
+    window
+  })
+}
+
+// output MMR and leaf of each output after each block built so far, for the
+// blocks built on top to commit to the right root
+thread_local!(pub static OUTPUTS: RefCell<HashMap<Hash, (MountainRange<()>, HashMap<Hash, u64>)>> =
+  RefCell::new(HashMap::new()));
+
+// the output MMR and leaves once the block is added on top of its parent
+fn apply_outputs(prev: &core::BlockHeader,
+                 b: &core::Block)
+                 -> (MountainRange<()>, HashMap<Hash, u64>) {
+  // the genesis has no output
+  let (mut mmr, mut leaves) = OUTPUTS.with(|all| all.borrow().get(&prev.hash()).cloned())
+    .unwrap_or((MountainRange::new((), 0), HashMap::new()));
+  for input in &b.inputs {
+    if let Some(&leaf) = leaves.get(&input.output_hash()) {
+      mmr.prune(leaf).unwrap();
+    }
+  }
+  for out in &b.outputs {
+    let leaf = mmr.push(out.hash()).unwrap();
+    leaves.insert(out.hash(), leaf);
+  }
+  (mmr, leaves)
+}
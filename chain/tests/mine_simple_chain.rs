@@ -18,20 +18,30 @@ extern crate rand;
 extern crate time;
 extern crate secp256k1zkp as secp;
 
-use std::sync::Arc;
+mod common;
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use rand::os::OsRng;
 
 use grin_chain::types::*;
-use grin_core::core::hash::Hashed;
-use grin_core::core::target::Difficulty;
+use grin_core::core::hash::{Hash, Hashed};
+use grin_core::core::mmr::MountainRange;
+use grin_core::genesis::ChainTypes;
 use grin_core::pow;
+use grin_core::testing::ChainGenerator;
+use grin_core::ser;
 use grin_core::core;
 use grin_core::consensus;
 
+use common::*;
+
 #[test]
 fn mine_empty_chain() {
 	let mut rng = OsRng::new().unwrap();
-	let store = grin_chain::store::ChainKVStore::new(".grin".to_string()).unwrap();
+	let store = new_store(&test_dir("mine_empty_chain"));
 
   // save a genesis block
   let mut gen = grin_core::genesis::genesis(); 
@@ -45,30 +55,1543 @@ fn mine_empty_chain() {
   // mine and add a few blocks
   let mut prev = gen;
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
-  let arc_store = Arc::new(store);
-  let adapter = Arc::new(NoopAdapter{});
+  let chain = grin_chain::Chain::new(Arc::new(store), Arc::new(NoopAdapter{})).unwrap();
 
   for n in 1..4 {
+    // a new key each time, the same reward output can't be created twice
+    let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
     let mut b = core::Block::new(&prev.header, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.header.timestamp + time::Duration::seconds(60);
+    b.header.utxo_merkle = chain.utxo_root(&b).unwrap();
 
-    let (difficulty, _) = consensus::next_target(b.header.timestamp.to_timespec().sec,
-                                                      prev.header.timestamp.to_timespec().sec,
-                                                      prev.header.difficulty.clone(),
-                                                      prev.header.cuckoo_len);
+    let (difficulty, _) = chain.next_difficulty(&prev.header).unwrap();
+    b.header.secondary_scaling = chain.next_secondary_scaling(&prev.header).unwrap();
 
     let (proof, nonce) = pow::pow_size(&b, difficulty.clone(), prev.header.cuckoo_len as u32).unwrap();
     b.header.pow = proof;
     b.header.nonce = nonce;
+    b.header.total_difficulty = prev.header.total_difficulty.clone() + difficulty.clone();
     b.header.difficulty = difficulty;
-    grin_chain::pipe::process_block(&b, arc_store.clone(), adapter.clone(), grin_chain::pipe::EASY_POW).unwrap();
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
 
     // checking our new head
-    let head = arc_store.clone().head().unwrap();
+    let head = chain.head();
     assert_eq!(head.height, n);
     assert_eq!(head.last_block_h, b.hash());
 
     prev = b;
   }
+
+  // every block went through each stage of the pipeline
+  let stats = chain.stats().unwrap();
+  assert_eq!(stats.pipeline.blocks, 3);
+  assert!(stats.pipeline.total.pow > std::time::Duration::new(0, 0));
+  assert!(stats.pipeline.max.commit <= stats.pipeline.total.commit);
+
+  assert_eq!(stats.height, 3);
+  assert_eq!(stats.total_difficulty, chain.head().total_difficulty);
+  assert_eq!(stats.orphans, 0);
+  assert_eq!(stats.intervals,
+             grin_chain::BlockIntervals {
+               count: 3,
+               mean: 60,
+               min: 60,
+               max: 60,
+             });
+}
+
+#[test]
+fn mine_forks() {
+  let (store, gen) = genesis_store("mine_forks");
+
+  let arc_store = Arc::new(store);
+  let chain = grin_chain::Chain::new(arc_store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  // main chain of 2 blocks
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+
+  // a fork off b1, as heavy as the main chain so it shouldn't become the head
+  let fork2 = prepare_block(&b1.header, 61);
+  match chain.process_block(&fork2, grin_chain::pipe::EASY_POW).unwrap() {
+    BlockStatus::Fork(tip) => assert_eq!(tip.last_block_h, fork2.hash()),
+    status => panic!("unexpected status {:?}", status),
+  }
+  assert_eq!(arc_store.head().unwrap().last_block_h, b2.hash());
+  assert_eq!(arc_store.get_tips().unwrap().len(), 2);
+
+  // extending the fork makes it heavier, triggering a reorg
+  let fork3 = prepare_block(&fork2.header, 60);
+  match chain.process_block(&fork3, grin_chain::pipe::EASY_POW).unwrap() {
+    BlockStatus::Reorg(tip) => assert_eq!(tip.last_block_h, fork3.hash()),
+    status => panic!("unexpected status {:?}", status),
+  }
+  let head = arc_store.head().unwrap();
+  assert_eq!(head.height, 3);
+  assert_eq!(head.last_block_h, fork3.hash());
+  assert_eq!(head.prev_block_h, fork2.hash());
+  assert_eq!(head.total_difficulty, fork3.header.total_difficulty);
+
+  // an older duplicate is rejected without creating a new fork
+  assert!(chain.process_block(&b1, grin_chain::pipe::EASY_POW).is_err());
+  assert_eq!(arc_store.get_tips().unwrap().len(), 2);
+}
+
+#[test]
+fn mine_headers_first() {
+  let (store, gen) = genesis_store("mine_headers_first");
+
+  let arc_store = Arc::new(store);
+  let chain = grin_chain::Chain::new(arc_store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+
+  // headers get validated and extend the header chain only
+  for b in vec![&b1, &b2] {
+    let tip = chain.process_block_header(&b.header, grin_chain::pipe::EASY_POW).unwrap().unwrap();
+    assert_eq!(tip.last_block_h, b.hash());
+  }
+  assert_eq!(arc_store.get_header_head().unwrap().height, 2);
+  assert_eq!(arc_store.head().unwrap().height, 0);
+  assert!(chain.process_block_header(&b2.header, grin_chain::pipe::EASY_POW).is_err());
+
+  // then the full blocks can follow
+  for b in vec![&b1, &b2] {
+    let status = chain.process_block(b, grin_chain::pipe::EASY_POW).unwrap();
+    assert_eq!(status.head().unwrap().last_block_h, b.hash());
+  }
+  assert_eq!(arc_store.head().unwrap().height, 2);
+  assert_eq!(arc_store.get_header_head().unwrap().height, 2);
+}
+
+#[test]
+fn concurrent_submit() {
+  let (store, gen) = genesis_store("concurrent_submit");
+
+  let chain = Arc::new(grin_chain::Chain::new(Arc::new(store), Arc::new(NoopAdapter{})).unwrap());
+  let b1 = Arc::new(prepare_block(&gen.header, 60));
+
+  // the same block submitted from several threads at once should only be
+  // added once
+  let mut handles = vec![];
+  for _ in 0..4 {
+    let chain = chain.clone();
+    let b1 = b1.clone();
+    handles.push(thread::spawn(move || chain.process_block(&*b1, grin_chain::pipe::EASY_POW)));
+  }
+  let accepted = handles.into_iter()
+    .map(|h| h.join().unwrap())
+    .filter(|res| res.is_ok())
+    .count();
+  assert_eq!(accepted, 1);
+  assert_eq!(chain.head().height, 1);
+  assert_eq!(chain.head().last_block_h, b1.hash());
+}
+
+#[test]
+fn orphans_cascade() {
+  let (chain, gen) = new_chain("orphans_cascade");
+
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let b3 = prepare_block(&b2.header, 60);
+
+  // an orphan without proof of work isn't held
+  let mut broken3 = b3.clone();
+  broken3.header.nonce += 1;
+  match chain.process_block(&broken3, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidPow) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.stats().unwrap().orphans, 0);
+
+  // received in reverse order, the last two are orphans until b1 shows up
+  for b in vec![&b3, &b2] {
+    match chain.process_block(b, grin_chain::pipe::EASY_POW) {
+      Ok(BlockStatus::Orphan) => {}
+      res => panic!("unexpected result {:?}", res),
+    }
+  }
+  assert_eq!(chain.stats().unwrap().orphans, 2);
+  let status = chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let tip = status.head().unwrap();
+  assert_eq!(tip.height, 3);
+  assert_eq!(tip.last_block_h, b3.hash());
+  assert_eq!(chain.head().last_block_h, b3.hash());
+  assert_eq!(chain.stats().unwrap().orphans, 0);
+}
+
+#[test]
+fn reject_invalid_again() {
+  let (chain, gen) = new_chain("reject_invalid_again");
+
+  // a block with a broken proof of work is denied once found invalid, without
+  // holding it against whoever sends it again
+  let mut b1 = prepare_block(&gen.header, 60);
+  b1.header.nonce += 1;
+  match chain.process_block(&b1, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidPow) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  match chain.process_block(&b1, grin_chain::pipe::EASY_POW) {
+    Err(e @ grin_chain::pipe::Error::KnownInvalid(_)) => assert!(!e.is_bad_data()),
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 0);
+
+  // nor is a body the header doesn't cover remembered, the right one still
+  // gets in
+  let mut b1 = prepare_block(&gen.header, 60);
+  let honest = b1.clone();
+  b1.proofs[0].sig = vec![0; 10];
+  match chain.process_block(&b1, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidBlockProof(_)) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  chain.process_block(&honest, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().last_block_h, honest.hash());
+}
+
+#[test]
+fn sync_defers_range_proofs() {
+  let (store, gen) = genesis_store("sync_defers_range_proofs");
+
+  // only blocks up to the sync height are historical, however far ahead the
+  // header chain goes
+  let config = grin_chain::ChainConfig { sync_height: Some(1), ..Default::default() };
+  let chain = grin_chain::Chain::with_config(Arc::new(store), Arc::new(NoopAdapter{}), config).unwrap();
+
+  // output hashes only cover commitments so swapping the range proof of the
+  // reward output keeps the block header intact while breaking the block
+  let mut b1 = prepare_block(&gen.header, 60);
+  let honest = b1.clone();
+  let other = prepare_block(&gen.header, 61);
+  b1.outputs[0] = core::Output::BlindOutput {
+    features: b1.outputs[0].features(),
+    commit: b1.outputs[0].commitment().unwrap(),
+    proof: other.outputs[0].proof().unwrap(),
+  };
+  let b2 = prepare_block(&b1.header, 60);
+  for b in vec![&b1, &b2] {
+    chain.process_block_header(&b.header, grin_chain::pipe::EASY_POW).unwrap();
+  }
+
+  // b1 is historical and gets accepted, its range proofs are checked later
+  let opts = grin_chain::pipe::EASY_POW | grin_chain::pipe::SYNC;
+  chain.process_block(&b1, opts).unwrap();
+  assert_eq!(chain.head().last_block_h, b1.hash());
+  for _ in 0..50 {
+    if chain.failed_deferred().len() > 0 {
+      break;
+    }
+    thread::sleep(std::time::Duration::from_millis(100));
+  }
+  assert_eq!(chain.failed_deferred(), vec![b1.hash()]);
+
+  // the chain rewinds to its parent before processing anything else, b2 has
+  // nothing to build on anymore and the same block with the right range
+  // proofs can still come
+  match chain.process_block(&b2, opts) {
+    Ok(BlockStatus::Orphan) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().last_block_h, gen.hash());
+  assert!(chain.get_header_by_height(1).is_err());
+  chain.process_block(&honest, opts).unwrap();
+  assert_eq!(chain.head().last_block_h, b2.hash());
+}
+
+#[test]
+fn future_blocks() {
+  let (chain, gen) = new_chain("future_blocks");
+
+  // a block just past the future time limit is held until it isn't anymore,
+  // with enough margin for the mining to not eat it all
+  let ahead = time::now_utc().to_timespec().sec - gen.header.timestamp.to_timespec().sec +
+    grin_chain::pipe::FUTURE_TIME_LIMIT + 10;
+  let b1 = prepare_block(&gen.header, ahead);
+  // provided it has a valid proof of work
+  let mut broken1 = b1.clone();
+  broken1.header.nonce += 1;
+  match chain.process_block(&broken1, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidPow) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  match chain.process_block(&b1, grin_chain::pipe::EASY_POW) {
+    Ok(BlockStatus::Future) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 0);
+  for _ in 0..20 {
+    thread::sleep(std::time::Duration::from_secs(1));
+    chain.process_future_blocks();
+    if chain.head().height == 1 {
+      break;
+    }
+  }
+  assert_eq!(chain.head().last_block_h, b1.hash());
+}
+
+#[test]
+fn invalid_heights() {
+  let (chain, gen) = new_chain("invalid_heights");
+
+  // a block has to be right after its parent, neither off by one nor anywhere
+  let b1 = prepare_block(&gen.header, 60);
+  for height in vec![0, 2, u64::max_value()] {
+    let mut b = b1.clone();
+    b.header.height = height;
+    match chain.process_block(&b, grin_chain::pipe::EASY_POW) {
+      Err(grin_chain::pipe::Error::InvalidBlockHeight) => {}
+      res => panic!("unexpected result at height {}: {:?}", height, res),
+    }
+  }
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().height, 1);
+}
+
+#[test]
+fn checkpoints() {
+  let (store, gen) = genesis_store("checkpoints");
+
+  // a checkpointed block doesn't get its proof of work checked when trusting
+  // checkpoints, so it can even be broken
+  let b1 = prepare_block(&gen.header, 60);
+  let mut b2 = prepare_block(&b1.header, 60);
+  b2.header.nonce += 1;
+  let fork2 = prepare_block(&b1.header, 61);
+  let checkpoints = grin_chain::Checkpoints::new(vec![(2, b2.hash())]);
+  let chain = grin_chain::Chain::with_checkpoints(Arc::new(store), Arc::new(NoopAdapter{}), checkpoints).unwrap();
+
+  // only the checkpointed block can be at its height
+  match chain.process_block(&fork2, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::CheckpointMismatch) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  let opts = grin_chain::pipe::EASY_POW | grin_chain::pipe::TRUST_CHECKPOINTS;
+  chain.process_block_header(&b1.header, opts).unwrap();
+  chain.process_block_header(&b2.header, opts).unwrap();
+
+  // below the checkpoint, a fork that doesn't link to it gets fully validated
+  // and its broken proof of work found out
+  let mut fork1 = prepare_block(&gen.header, 61);
+  fork1.header.nonce += 1;
+  match chain.process_block(&fork1, opts) {
+    Err(grin_chain::pipe::Error::InvalidPow) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  chain.process_block(&b1, opts).unwrap();
+
+  // the checkpoint doesn't cover kernels, those still get verified
+  let mut broken2 = b2.clone();
+  broken2.proofs[0].sig = vec![0; 10];
+  match chain.process_block(&broken2, opts) {
+    Err(grin_chain::pipe::Error::InvalidBlockProof(_)) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  let status = chain.process_block(&b2, opts).unwrap();
+  assert_eq!(status.head().unwrap().last_block_h, b2.hash());
+}
+
+#[test]
+fn immature_coinbase() {
+  let (store, gen) = genesis_store("immature_coinbase");
+
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+  let b1 = prepare_block_with(&gen.header, 60, vec![], reward_key);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+
+  // the reward output gets indexed as such
+  let coinbase = b1.coinbase_output(&secp).unwrap().unwrap();
+  let pos = store.get_output_pos(&coinbase.hash()).unwrap();
+  assert_eq!(pos, OutputPos { height: 1, coinbase: true });
+
+  // and can't be spent right away
+  let mut tx = core::transaction::Builder::new()
+    .input(coinbase.hash(), consensus::REWARD, reward_key)
+    .output(consensus::REWARD - 1, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let reward_key2 = secp::key::SecretKey::new(&secp, &mut rng);
+  let b2 = prepare_block_with(&b1.header, 60, vec![&mut tx], reward_key2);
+  match chain.process_block(&b2, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::ImmatureCoinbase) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 1);
+}
+
+#[test]
+fn unspent_outputs() {
+  let (store, gen) = genesis_store("unspent_outputs");
+
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+  let b1 = prepare_block_with(&gen.header, 60, vec![], reward_key);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let coinbase = b1.coinbase_output(&secp).unwrap().unwrap();
+  assert!(store.get_unspent(&coinbase.hash()).is_ok());
+
+  // reusing the reward key creates the same output again
+  let b2 = prepare_block_with(&b1.header, 60, vec![], reward_key);
+  match chain.process_block(&b2, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::DuplicateOutput) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+
+  // and outputs have to exist to be spent
+  let mut tx = core::transaction::Builder::new()
+    .input(grin_core::core::hash::ZERO_HASH, 5, secp::key::SecretKey::new(&secp, &mut rng))
+    .output(4, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let b2 = prepare_block_with(&b1.header, 60, vec![&mut tx], secp::key::SecretKey::new(&secp, &mut rng));
+  match chain.process_block(&b2, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::MissingOutput) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 1);
+}
+
+#[test]
+fn replayed_kernels() {
+  let (store, gen) = genesis_store("replayed_kernels");
+
+  // an output to spend, skipping the wait for a coinbase to mature
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let out = core::Output::OvertOutput {
+      features: core::OutputFeatures::Plain,
+      value: 5,
+      blindkey: key,
+    }
+    .blind(&secp)
+    .unwrap();
+  let pos = OutputPos { height: 0, coinbase: false };
+  let seed_output = |store: &grin_chain::store::ChainKVStore| {
+    let batch = store.batch();
+    batch.save_output_pos(&out.hash(), &pos).unwrap();
+    batch.save_unspent(&out.hash(), &pos).unwrap();
+    batch.commit().unwrap();
+  };
+  seed_output(&store);
+
+  // it also has to be in the output MMR, including the one blocks get built
+  // with
+  let batch = store.batch();
+  batch.save_output_mmr_node(0, 0, &out.hash()).unwrap();
+  batch.save_output_mmr_size(1).unwrap();
+  batch.save_output_leaf(&out.hash(), 0).unwrap();
+  batch.commit().unwrap();
+  let mut mmr = MountainRange::new((), 0);
+  mmr.push(out.hash()).unwrap();
+  let leaves = vec![(out.hash(), 0)].into_iter().collect();
+  OUTPUTS.with(|all| all.borrow_mut().insert(gen.hash(), (mmr, leaves)));
+
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  // a first block spending our output, a second one spending its output
+  let key2 = secp::key::SecretKey::new(&secp, &mut rng);
+  let mut btx1 = core::transaction::Builder::new()
+    .input(out.hash(), 5, key)
+    .output(4, key2)
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let b1 = prepare_block_with(&gen.header, 60, vec![&mut btx1], secp::key::SecretKey::new(&secp, &mut rng));
+  let mut btx2 = core::transaction::Builder::new()
+    .input(btx1.outputs[0].hash(), 4, key2)
+    .output(3, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let b2 = prepare_block_with(&b1.header, 60, vec![&mut btx2], secp::key::SecretKey::new(&secp, &mut rng));
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  assert!(store.get_unspent(&out.hash()).is_err());
+
+  // even if the first spent output shows up again, the first transaction
+  // can't be replayed
+  seed_output(&store);
+  let b3 = prepare_block_with(&b2.header, 60, vec![&mut btx1], secp::key::SecretKey::new(&secp, &mut rng));
+  match chain.process_block(&b3, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::DuplicateKernel) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 2);
+}
+
+#[test]
+fn lazy_bodies() {
+  let (chain, gen) = new_chain("lazy_bodies");
+
+  // the body of a block with an invalid header is never read
+  let mut b1 = prepare_block(&gen.header, 60);
+  b1.header.nonce += 1;
+  let mut lazy = LazyBlock { block: b1, body_reads: 0 };
+  match chain.process_block(&mut lazy, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidPow) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(lazy.body_reads, 0);
+
+  // while a valid block gets read once
+  let mut lazy = LazyBlock { block: prepare_block(&gen.header, 60), body_reads: 0 };
+  chain.process_block(&mut lazy, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(lazy.body_reads, 1);
+  assert_eq!(chain.head().last_block_h, lazy.block.hash());
+}
+
+#[test]
+fn queued_blocks() {
+  let (store, gen) = genesis_store("queued_blocks");
+
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  // queued blocks get processed in order, each handle giving its own result
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let h1 = chain.queue_block(b1.clone(), grin_chain::pipe::EASY_POW, None).unwrap();
+  let h2 = chain.queue_block(b2.clone(), grin_chain::pipe::EASY_POW, None).unwrap();
+  let h3 = chain.queue_block(b1.clone(), grin_chain::pipe::EASY_POW, None).unwrap();
+  assert_eq!(h1.wait().unwrap().head().unwrap().last_block_h, b1.hash());
+  assert_eq!(h2.wait().unwrap().head().unwrap().last_block_h, b2.hash());
+  assert!(h3.wait().is_err());
+  assert_eq!(chain.head().height, 2);
+
+  // the chain thread ends with the last clone, letting go of the store
+  let chain2 = chain.clone();
+  drop(chain);
+  assert!(chain2.queue_block(b2.clone(), grin_chain::pipe::EASY_POW, None).is_ok());
+  drop(chain2);
+  assert_eq!(Arc::strong_count(&store), 1);
+}
+
+#[test]
+fn parallel_pow() {
+  let (store, gen) = genesis_store("parallel_pow");
+
+  let config = grin_chain::ChainConfig { pow_threads: 4, ..Default::default() };
+  let chain = grin_chain::Chain::with_config(Arc::new(store), Arc::new(NoopAdapter{}), config).unwrap();
+
+  // proofs of work checked ahead of time still get each block its own result
+  let mut blocks = vec![prepare_block(&gen.header, 60)];
+  for n in 1..5 {
+    let b = prepare_block(&blocks[n - 1].header, 60);
+    blocks.push(b);
+  }
+  blocks[3].header.nonce += 1;
+  let handles = blocks.iter()
+    .map(|b| chain.queue_block(b.clone(), grin_chain::pipe::EASY_POW, None).unwrap())
+    .collect::<Vec<_>>();
+  let results = handles.into_iter().map(|h| h.wait()).collect::<Vec<_>>();
+  for n in 0..3 {
+    assert_eq!(results[n].as_ref().unwrap().head().unwrap().last_block_h, blocks[n].hash());
+  }
+  match results[3] {
+    Err(grin_chain::pipe::Error::InvalidPow) => {}
+    ref res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 3);
+}
+
+#[test]
+fn rejections_reported() {
+  let (store, gen) = genesis_store("rejections_reported");
+
+  let adapter = Arc::new(OutcomesAdapter::new());
+  let chain = grin_chain::Chain::new(Arc::new(store), adapter.clone()).unwrap();
+
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let mut fork1 = prepare_block(&gen.header, 61);
+  fork1.header.nonce += 1;
+
+  // an orphan isn't rejected, neither is an accepted block
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  assert!(adapter.rejected.lock().unwrap().is_empty());
+
+  // a duplicate is harmless, a broken proof of work isn't
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap_err();
+  chain.process_block(&fork1, grin_chain::pipe::EASY_POW).unwrap_err();
+  let rejected = adapter.rejected.lock().unwrap();
+  assert_eq!(*rejected, vec![(b1.hash(), false, None), (fork1.hash(), true, None)]);
+}
+
+#[test]
+fn outcomes_attributed() {
+  let (store, gen) = genesis_store("outcomes_attributed");
+
+  let adapter = Arc::new(OutcomesAdapter::new());
+  let chain = grin_chain::Chain::new(Arc::new(store), adapter.clone()).unwrap();
+  let peer1 = Some("10.0.0.1:13414".parse::<SocketAddr>().unwrap());
+  let peer2 = Some("10.0.0.2:13414".parse::<SocketAddr>().unwrap());
+
+  // an orphan is still attributed to its own peer once its parent comes in
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block_from(&b2, grin_chain::pipe::EASY_POW, peer1).unwrap();
+  chain.process_block_from(&b1, grin_chain::pipe::EASY_POW, peer2).unwrap();
+  assert_eq!(*adapter.accepted.lock().unwrap(),
+             vec![(b1.hash(), peer2), (b2.hash(), peer1)]);
+
+  // so are rejected blocks, queued or not
+  let mut fork1 = prepare_block(&gen.header, 61);
+  fork1.header.nonce += 1;
+  chain.queue_block(fork1.clone(), grin_chain::pipe::EASY_POW, peer1).unwrap().wait().unwrap_err();
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap_err();
+  assert_eq!(*adapter.rejected.lock().unwrap(),
+             vec![(fork1.hash(), true, peer1), (b1.hash(), false, None)]);
+}
+
+#[test]
+fn multiple_adapters() {
+  let (store, gen) = genesis_store("multiple_adapters");
+
+  let adapters = Arc::new(grin_chain::MultiAdapter::new());
+  let first = Arc::new(OutcomesAdapter::new());
+  adapters.add(first.clone());
+  let chain = grin_chain::Chain::new(Arc::new(store), adapters.clone()).unwrap();
+
+  // adapters registered after the chain got built get notified as well
+  let second = Arc::new(OutcomesAdapter::new());
+  adapters.add(second.clone());
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap_err();
+  for adapter in vec![first, second] {
+    assert_eq!(*adapter.accepted.lock().unwrap(), vec![(b1.hash(), None)]);
+    assert_eq!(*adapter.rejected.lock().unwrap(), vec![(b1.hash(), false, None)]);
+  }
+}
+
+#[test]
+fn chain_facade() {
+  let gen = test_genesis();
+  let mut config = grin_chain::ChainConfig::default();
+  config.params.genesis = gen.clone();
+  config.store_backend = test_backend();
+  let chain = grin_chain::Chain::init(test_dir("chain_facade"),
+                                      Arc::new(NoopAdapter{}),
+                                      config).unwrap();
+  assert_eq!(chain.head().last_block_h, gen.hash());
+
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  let fork2 = prepare_block(&b1.header, 61);
+  chain.process_block(&fork2, grin_chain::pipe::EASY_POW).unwrap();
+
+  // blocks can be looked up by hash anywhere but by height on the main chain
+  assert_eq!(chain.get_block(&fork2.hash()).unwrap().hash(), fork2.hash());
+  assert_eq!(chain.get_block_header(&b1.hash()).unwrap().height, 1);
+  assert_eq!(chain.get_header_by_height(0).unwrap().hash(), gen.hash());
+  assert_eq!(chain.get_header_by_height(2).unwrap().hash(), b2.hash());
+  assert!(chain.get_header_by_height(3).is_err());
+}
+
+#[test]
+fn chain_types() {
+  let dir = test_dir("chain_types");
+  // a chain for another network can't be opened
+  {
+    let store = new_store(&dir);
+    let gen = grin_core::genesis::genesis();
+    store.save_block(&gen).unwrap();
+    store.save_head(&Tip::new(gen.hash())).unwrap();
+  }
+  let mut config = grin_chain::ChainConfig::for_chain(ChainTypes::Usernet);
+  config.store_backend = test_backend();
+  assert!(grin_chain::Chain::init(dir, Arc::new(NoopAdapter{}), config.clone())
+    .is_err());
+
+  // its own consensus parameters apply, starting with its genesis
+  let chain = grin_chain::Chain::init(test_dir("chain_types-usernet"),
+                                      Arc::new(NoopAdapter{}),
+                                      config)
+    .unwrap();
+  let gen = ChainTypes::Usernet.params().genesis;
+  assert_eq!(chain.head().last_block_h, gen.hash());
+  assert_eq!(chain.params().chain_type, ChainTypes::Usernet);
+  let b1 = prepare_block(&gen.header, 60);
+  assert_eq!(b1.header.cuckoo_len, 12);
+  chain.process_block(&b1, grin_chain::NONE).unwrap();
+  assert_eq!(chain.head().last_block_h, b1.hash());
+}
+
+// keeps track of the blocks accepted or refused by the chain with the peer
+// they came from, and whether refused ones were invalid
+struct OutcomesAdapter {
+  accepted: Mutex<Vec<(Hash, Option<SocketAddr>)>>,
+  rejected: Mutex<Vec<(Hash, bool, Option<SocketAddr>)>>,
+}
+
+impl OutcomesAdapter {
+  fn new() -> OutcomesAdapter {
+    OutcomesAdapter {
+      accepted: Mutex::new(vec![]),
+      rejected: Mutex::new(vec![]),
+    }
+  }
+}
+
+impl ChainAdapter for OutcomesAdapter {
+  fn block_accepted(&self, b: &core::Block, source: Option<SocketAddr>) {
+    self.accepted.lock().unwrap().push((b.hash(), source));
+  }
+  fn block_rejected(&self,
+                    bh: &core::BlockHeader,
+                    e: &grin_chain::pipe::Error,
+                    source: Option<SocketAddr>) {
+    self.rejected.lock().unwrap().push((bh.hash(), e.is_bad_data(), source));
+  }
+}
+
+// provides a block body only when asked for, counting the times it was
+struct LazyBlock {
+  block: core::Block,
+  body_reads: usize,
+}
+
+impl BlockProvider for LazyBlock {
+  fn header(&self) -> &core::BlockHeader {
+    &self.block.header
+  }
+  fn body(&mut self) -> Result<&core::Block, grin_chain::pipe::Error> {
+    self.body_reads += 1;
+    Ok(&self.block)
+  }
+}
+
+#[test]
+fn reorg_depth_limit() {
+  let (store, gen) = genesis_store("reorg_depth_limit");
+
+  let store = Arc::new(store);
+  let config = grin_chain::ChainConfig { max_reorg_depth: 1, ..Default::default() };
+  let chain = grin_chain::Chain::with_config(store.clone(), Arc::new(NoopAdapter{}), config).unwrap();
+
+  // main chain of 2 blocks
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+
+  // a fork off the genesis is fine while lighter or as heavy
+  let fork1 = prepare_block(&gen.header, 61);
+  let fork2 = prepare_block(&fork1.header, 60);
+  for fb in vec![&fork1, &fork2] {
+    match chain.process_block(fb, grin_chain::pipe::EASY_POW).unwrap() {
+      BlockStatus::Fork(_) => {}
+      status => panic!("unexpected status {:?}", status),
+    }
+  }
+
+  // but taking over would undo both blocks of the main chain
+  let fork3 = prepare_block(&fork2.header, 60);
+  match chain.process_block(&fork3, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::ReorgTooDeep) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(store.head().unwrap().last_block_h, b2.hash());
+  assert!(store.get_block(&fork3.hash()).is_err());
+}
+
+#[test]
+fn validate_stored_chain() {
+  let (store, gen) = genesis_store("validate_stored_chain");
+
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+  chain.validate(grin_chain::pipe::EASY_POW).unwrap();
+
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  let b3 = prepare_block(&b2.header, 60);
+  chain.process_block(&b3, grin_chain::pipe::EASY_POW).unwrap();
+  chain.validate(grin_chain::pipe::EASY_POW).unwrap();
+
+  // a body changed behind the chain's back is the first thing reported
+  let mut broken = b2.clone();
+  broken.outputs.clear();
+  store.save_block(&broken).unwrap();
+  let inconsistency = chain.validate(grin_chain::pipe::EASY_POW).unwrap_err();
+  assert_eq!(inconsistency.height, 2);
+  assert_eq!(inconsistency.hash, b2.hash());
+  match inconsistency.error {
+    grin_chain::pipe::Error::InvalidMerkleRoot => {}
+    e => panic!("unexpected error {:?}", e),
+  }
+}
+
+#[test]
+fn head_subscription() {
+  let (chain, gen) = new_chain("head_subscription");
+  let heads = chain.subscribe_head();
+  drop(chain.subscribe_head());
+
+  // every new head gets pushed, including reorgs but not lighter forks
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  let fork2 = prepare_block(&b1.header, 61);
+  chain.process_block(&fork2, grin_chain::pipe::EASY_POW).unwrap();
+  let fork3 = prepare_block(&fork2.header, 60);
+  chain.process_block(&fork3, grin_chain::pipe::EASY_POW).unwrap();
+
+  let mut pushed = vec![];
+  while let Ok(tip) = heads.try_recv() {
+    pushed.push(tip.last_block_h);
+  }
+  assert_eq!(pushed, vec![b1.hash(), b2.hash(), fork3.hash()]);
+}
+
+#[test]
+fn headers_by_height() {
+  let (store, gen) = genesis_store("headers_by_height");
+
+  let arc_store = Arc::new(store);
+  let chain = grin_chain::Chain::new(arc_store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  let b3 = prepare_block(&b2.header, 60);
+  chain.process_block(&b3, grin_chain::pipe::EASY_POW).unwrap();
+  for (height, b) in vec![&gen, &b1, &b2, &b3].into_iter().enumerate() {
+    assert_eq!(arc_store.get_header_by_height(height as u64).unwrap().hash(), b.hash());
+  }
+
+  // a lighter fork leaves the index alone
+  let fork2 = prepare_block(&b1.header, 61);
+  chain.process_block(&fork2, grin_chain::pipe::EASY_POW).unwrap();
+  let fork3 = prepare_block(&fork2.header, 60);
+  chain.process_block(&fork3, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.get_header_by_height(2).unwrap().hash(), b2.hash());
+
+  // once the fork takes over, all its heights point to it
+  let fork4 = prepare_block(&fork3.header, 60);
+  chain.process_block(&fork4, grin_chain::pipe::EASY_POW).unwrap();
+  for (height, b) in vec![&gen, &b1, &fork2, &fork3, &fork4].into_iter().enumerate() {
+    assert_eq!(chain.get_header_by_height(height as u64).unwrap().hash(), b.hash());
+  }
+  assert!(chain.get_header_by_height(5).is_err());
+}
+
+#[test]
+fn current_chain_and_locator() {
+  let (chain, gen) = new_chain("current_chain_and_locator");
+  assert_eq!(chain.get_locator().unwrap(), vec![gen.hash()]);
+
+  let mut blocks = vec![gen.clone()];
+  for _ in 0..20 {
+    let b = prepare_block(&blocks.last().unwrap().header, 60);
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
+    blocks.push(b);
+  }
+  let fork = prepare_block(&blocks[19].header, 61);
+  chain.process_block(&fork, grin_chain::pipe::EASY_POW).unwrap();
+  assert!(chain.is_on_current_chain(&blocks[20].header).unwrap());
+  assert!(chain.is_on_current_chain(&gen.header).unwrap());
+  assert!(!chain.is_on_current_chain(&fork.header).unwrap());
+
+  // the 10 most recent blocks, then doubling gaps down to the genesis
+  let heights: Vec<usize> = vec![20, 19, 18, 17, 16, 15, 14, 13, 12, 11, 9, 5, 0];
+  let expected: Vec<Hash> = heights.iter().map(|&h| blocks[h].hash()).collect();
+  assert_eq!(chain.get_locator().unwrap(), expected);
+}
+
+#[test]
+fn locate_headers() {
+  let (chain, gen) = new_chain("locate_headers");
+  let mut blocks = vec![gen.clone()];
+  for _ in 0..10 {
+    let b = prepare_block(&blocks.last().unwrap().header, 60);
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
+    blocks.push(b);
+  }
+  let hashes = |from: usize, to: usize| -> Vec<Hash> {
+    blocks[from..to].iter().map(|b| b.hash()).collect()
+  };
+  let located = |locator: &[Hash], max: u64| -> Vec<Hash> {
+    chain.locate_headers(locator, max).unwrap().iter().map(|bh| bh.hash()).collect()
+  };
+
+  // a peer on a shorter fork gets the headers after the block in common
+  let fork = prepare_block(&blocks[6].header, 61);
+  assert_eq!(located(&[fork.hash(), blocks[6].hash(), gen.hash()], 100), hashes(7, 11));
+  // capped at the provided maximum
+  assert_eq!(located(&[blocks[2].hash(), gen.hash()], 3), hashes(3, 6));
+  // from the genesis when nothing is in common
+  assert_eq!(located(&[fork.hash()], 100), hashes(1, 11));
+  // and nothing for a peer already at our head
+  assert!(located(&[blocks[10].hash()], 100).is_empty());
+}
+
+#[test]
+fn compaction() {
+  let (store, gen) = genesis_store("compaction");
+
+  let arc_store = Arc::new(store);
+  let config = grin_chain::ChainConfig {
+    max_reorg_depth: 2,
+    archive_height: Some(3),
+    ..Default::default()
+  };
+  let chain = grin_chain::Chain::with_config(arc_store.clone(), Arc::new(NoopAdapter{}), config)
+    .unwrap();
+
+  let mut blocks = vec![gen.clone()];
+  for _ in 0..6 {
+    let b = prepare_block(&blocks.last().unwrap().header, 60);
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
+    blocks.push(b);
+  }
+  // one fork too far back to ever take over, one recent enough
+  let stale = prepare_block(&blocks[1].header, 61);
+  chain.process_block(&stale, grin_chain::pipe::EASY_POW).unwrap();
+  let recent = prepare_block(&blocks[5].header, 61);
+  chain.process_block(&recent, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(arc_store.get_tips().unwrap().len(), 3);
+
+  chain.compact().unwrap();
+  assert_eq!(arc_store.get_tips().unwrap().len(), 2);
+  assert!(arc_store.get_block_header(&stale.hash()).is_err());
+  assert!(arc_store.get_output_pos(&stale.outputs[0].hash()).is_err());
+  assert!(arc_store.get_block(&recent.hash()).is_ok());
+  assert!(arc_store.get_output_pos(&recent.outputs[0].hash()).is_ok());
+
+  // bodies below the archive height are gone but the genesis one, their
+  // headers stay
+  assert!(arc_store.get_block(&gen.hash()).is_ok());
+  for b in &blocks[1..3] {
+    assert!(arc_store.get_block(&b.hash()).is_err());
+    assert!(arc_store.get_block_header(&b.hash()).is_ok());
+  }
+  for b in &blocks[3..] {
+    assert!(arc_store.get_block(&b.hash()).is_ok());
+  }
+
+  // compacting again changes nothing and the pruned chain is still valid
+  chain.compact().unwrap();
+  assert_eq!(arc_store.get_tips().unwrap().len(), 2);
+  chain.validate(grin_chain::pipe::EASY_POW).unwrap();
+
+  // what's left of the store on disk is in the chain stats
+  let usage = arc_store.disk_usage().unwrap();
+  assert!(usage > 0);
+  assert_eq!(chain.stats().unwrap().store_size, usage);
+}
+
+#[test]
+fn output_mmr_root() {
+  let (store, gen) = genesis_store("output_mmr_root");
+
+  let arc_store = Arc::new(store);
+  let chain = grin_chain::Chain::new(arc_store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  let b1 = prepare_block(&gen.header, 60);
+  assert_eq!(chain.utxo_root(&b1).unwrap(), b1.header.utxo_merkle);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  assert!(chain.utxo_root(&b1).is_err());
+
+  // a block committing to anything else is refused
+  let mut b2 = prepare_block(&b1.header, 60);
+  b2.header.utxo_merkle = b1.header.utxo_merkle;
+  let (proof, nonce) = pow::pow_size(&b2, b2.header.difficulty.clone(), 16).unwrap();
+  b2.header.pow = proof;
+  b2.header.nonce = nonce;
+  match chain.process_block(&b2, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidUtxoRoot) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+
+  // a reorg rewinds the MMR to the fork point before applying the fork
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  let fork1 = prepare_block(&gen.header, 61);
+  chain.process_block(&fork1, grin_chain::pipe::EASY_POW).unwrap();
+  let fork2 = prepare_block(&fork1.header, 60);
+  chain.process_block(&fork2, grin_chain::pipe::EASY_POW).unwrap();
+  let fork3 = prepare_block(&fork2.header, 60);
+  chain.process_block(&fork3, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().last_block_h, fork3.hash());
+  assert_eq!(arc_store.get_output_mmr_size().unwrap(), 3);
+}
+
+#[test]
+fn rewind() {
+  let (store, gen) = genesis_store("rewind");
+
+  let arc_store = Arc::new(store);
+  let chain = grin_chain::Chain::new(arc_store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  let mut blocks = vec![gen.clone()];
+  for _ in 0..4 {
+    let b = prepare_block(&blocks.last().unwrap().header, 60);
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
+    blocks.push(b);
+  }
+  assert!(chain.rewind_to(5).is_err());
+
+  let tip = chain.rewind_to(2).unwrap();
+  assert_eq!(tip.last_block_h, blocks[2].hash());
+  assert_eq!(chain.head().last_block_h, blocks[2].hash());
+  assert_eq!(arc_store.head().unwrap().last_block_h, blocks[2].hash());
+  assert_eq!(arc_store.get_header_head().unwrap().last_block_h, blocks[2].hash());
+  assert!(chain.get_header_by_height(3).is_err());
+  assert!(arc_store.get_unspent(&blocks[2].outputs[0].hash()).is_ok());
+  assert!(arc_store.get_unspent(&blocks[3].outputs[0].hash()).is_err());
+  assert_eq!(arc_store.get_output_mmr_size().unwrap(), 2);
+
+  // the undone blocks can come again, and new ones on top of them
+  chain.process_block(&blocks[3], grin_chain::pipe::EASY_POW).unwrap();
+  let b4 = prepare_block(&blocks[3].header, 61);
+  match chain.process_block(&b4, grin_chain::pipe::EASY_POW).unwrap() {
+    BlockStatus::Next(tip) => assert_eq!(tip.last_block_h, b4.hash()),
+    status => panic!("unexpected status {:?}", status),
+  }
+  chain.validate(grin_chain::pipe::EASY_POW).unwrap();
+}
+
+#[test]
+fn state_snapshot() {
+  use grin_chain::snapshot::Snapshot;
+
+  let (chain, gen) = new_chain("state_snapshot");
+  let mut blocks = vec![gen.clone()];
+  for _ in 0..4 {
+    let b = prepare_block(&blocks.last().unwrap().header, 60);
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
+    blocks.push(b);
+  }
+  let mut exported = vec![];
+  chain.export_snapshot(&mut exported).unwrap();
+  let last = blocks.last().unwrap().hash();
+
+  // the snapshot has to be for the block we trust and match its output MMR
+  let (store, _) = genesis_store("state_snapshot-synced");
+  let store = Arc::new(store);
+  let synced = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+  match synced.import_snapshot(&mut &exported[..], blocks[3].hash(), grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidSnapshot(_)) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  let mut tampered: Snapshot = ser::deserialize(&mut &exported[..]).unwrap();
+  tampered.outputs.pop();
+  let mut tampered_data = vec![];
+  ser::serialize(&mut tampered_data, &tampered).unwrap();
+  match synced.import_snapshot(&mut &tampered_data[..], last, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidUtxoRoot) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  // with no more leaves than blocks could create, in the order of the blocks
+  let tamperings: Vec<Box<Fn(&mut Snapshot)>> = vec![
+    Box::new(|s: &mut Snapshot| s.mmr_size = u64::max_value()),
+    Box::new(|s: &mut Snapshot| s.outputs[0].1.height = 5),
+    Box::new(|s: &mut Snapshot| {
+      let (h0, h1) = (s.outputs[0].1.height, s.outputs[1].1.height);
+      s.outputs[0].1.height = h1;
+      s.outputs[1].1.height = h0;
+    }),
+    Box::new(|s: &mut Snapshot| s.outputs[1].1.height = s.outputs[0].1.height),
+  ];
+  for (i, tamper) in tamperings.iter().enumerate() {
+    let mut tampered: Snapshot = ser::deserialize(&mut &exported[..]).unwrap();
+    tamper(&mut tampered);
+    let mut tampered_data = vec![];
+    ser::serialize(&mut tampered_data, &tampered).unwrap();
+    match synced.import_snapshot(&mut &tampered_data[..], last, grin_chain::pipe::EASY_POW) {
+      Err(grin_chain::pipe::Error::InvalidSnapshot(_)) => {}
+      res => panic!("unexpected result {:?} for tampering {}", res, i),
+    }
+  }
+  assert_eq!(synced.head().height, 0);
+
+  let tip = synced.import_snapshot(&mut &exported[..], last, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(tip.last_block_h, last);
+  assert_eq!(synced.head().last_block_h, last);
+  assert_eq!(synced.get_header_by_height(2).unwrap().hash(), blocks[2].hash());
+  for b in &blocks[1..] {
+    assert!(store.get_unspent(&b.outputs[0].hash()).is_ok());
+    assert_eq!(store.get_kernel(&b.proofs[0].remainder).unwrap(), b.hash());
+    assert!(store.get_block(&b.hash()).is_err());
+  }
+  assert!(synced.import_snapshot(&mut &exported[..], last, grin_chain::pipe::EASY_POW).is_err());
+
+  // and carry on from there
+  let b5 = prepare_block(&blocks[4].header, 60);
+  match synced.process_block(&b5, grin_chain::pipe::EASY_POW).unwrap() {
+    BlockStatus::Next(tip) => assert_eq!(tip.last_block_h, b5.hash()),
+    status => panic!("unexpected status {:?}", status),
+  }
+  synced.validate(grin_chain::pipe::EASY_POW).unwrap();
+}
+
+#[test]
+fn blocks_file() {
+  let (chain, gen) = new_chain("blocks_file");
+  let dir = test_dir("blocks_file");
+  let blocks = format!("{}.blocks", dir);
+  let mut prev = gen.header.clone();
+  for _ in 0..4 {
+    let b = prepare_block(&prev, 60);
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
+    prev = b.header;
+  }
+  assert_eq!(chain.export_blocks(&blocks).unwrap(), 4);
+
+  let (imported, _) = new_chain("blocks_file-imported");
+  assert_eq!(imported.import_blocks(&blocks, grin_chain::pipe::EASY_POW).unwrap(), 4);
+  assert_eq!(imported.head().last_block_h, chain.head().last_block_h);
+  imported.validate(grin_chain::pipe::EASY_POW).unwrap();
+
+  // blocks we already have are skipped
+  assert_eq!(imported.import_blocks(&blocks, grin_chain::pipe::EASY_POW).unwrap(), 0);
+  assert!(imported.import_blocks(&format!("{}.missing", dir), grin_chain::pipe::EASY_POW).is_err());
+
+  // a length no block can have is refused before reading any further
+  let corrupt = format!("{}.corrupt", dir);
+  std::fs::File::create(&corrupt).unwrap().write_all(&[0xff; 16]).unwrap();
+  assert!(imported.import_blocks(&corrupt, grin_chain::pipe::EASY_POW).is_err());
+
+  let b5 = prepare_block(&prev, 60);
+  match imported.process_block(&b5, grin_chain::pipe::EASY_POW).unwrap() {
+    BlockStatus::Next(tip) => assert_eq!(tip.last_block_h, b5.hash()),
+    status => panic!("unexpected status {:?}", status),
+  }
+}
+
+#[test]
+fn main_chain_iters() {
+  let (store, gen) = genesis_store("main_chain_iters");
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  let mut hashes = vec![gen.hash()];
+  let mut prev = gen.header.clone();
+  for _ in 0..4 {
+    let b = prepare_block(&prev, 60);
+    chain.process_block(&b, grin_chain::pipe::EASY_POW).unwrap();
+    hashes.push(b.hash());
+    prev = b.header;
+  }
+  // a fork that doesn't get walked
+  let fork = prepare_block(&gen.header, 61);
+  chain.process_block(&fork, grin_chain::pipe::EASY_POW).unwrap();
+
+  let forward: Vec<Hash> = store.headers_iter(0, Direction::Forward)
+    .map(|bh| bh.unwrap().hash())
+    .collect();
+  assert_eq!(forward, hashes);
+  let backward: Vec<Hash> = store.blocks_iter(4, Direction::Backward)
+    .map(|b| b.unwrap().hash())
+    .collect();
+  assert_eq!(backward, hashes.iter().rev().cloned().collect::<Vec<_>>());
+  let from_middle: Vec<Hash> = store.blocks_iter(2, Direction::Forward)
+    .map(|b| b.unwrap().hash())
+    .collect();
+  assert_eq!(from_middle, hashes[2..].to_vec());
+  assert_eq!(store.headers_iter(5, Direction::Backward).count(), 0);
+}
+
+#[test]
+fn fork_landscape() {
+  let (chain, gen) = new_chain("fork_landscape");
+
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let b3 = prepare_block(&b2.header, 60);
+  let fork2 = prepare_block(&b1.header, 61);
+  for b in vec![&b1, &b2, &b3, &fork2] {
+    chain.process_block(b, grin_chain::pipe::EASY_POW).unwrap();
+  }
+
+  let tips = chain.tips().unwrap();
+  assert_eq!(tips.len(), 2);
+  assert_eq!(tips[0].last_block_h, b3.hash());
+  assert_eq!(tips[0].height, 3);
+  assert_eq!(tips[1].last_block_h, fork2.hash());
+  assert_eq!(tips[1].height, 2);
+  assert!(tips[0].total_difficulty > tips[1].total_difficulty);
+
+  assert_eq!(chain.fork_point(&b3.hash(), &fork2.hash()).unwrap().hash(), b1.hash());
+  assert_eq!(chain.fork_point(&fork2.hash(), &b3.hash()).unwrap().hash(), b1.hash());
+  assert_eq!(chain.fork_point(&b2.hash(), &b3.hash()).unwrap().hash(), b2.hash());
+  assert_eq!(chain.fork_point(&b3.hash(), &b3.hash()).unwrap().hash(), b3.hash());
+  assert!(chain.fork_point(&b3.hash(), &prepare_block(&b3.header, 60).hash()).is_err());
+}
+
+#[test]
+fn coinbase_reward() {
+  let (chain, gen) = new_chain("coinbase_reward");
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+  // a block claiming fees it doesn't have pays its coinbase less than its reward
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let mut b = prepare_block_with(&gen.header, 60, vec![], key);
+  b.proofs[0].fee = 5;
+  match chain.process_block(&b, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidCoinbase) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+
+  // while the proper one, paid the subsidy of its height, is fine
+  let b1 = prepare_block_with(&gen.header, 60, vec![], secp::key::SecretKey::new(&secp, &mut rng));
+  assert_eq!(b1.reward(), Some(consensus::block_subsidy(1)));
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().height, 1);
+}
+
+#[test]
+fn coinbase_features() {
+  let (chain, gen) = new_chain("coinbase_features");
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+  // the reward output is flagged as coinbase, the index keeps it
+  let b1 = prepare_block(&gen.header, 60);
+  let coinbase = b1.coinbase_output(&secp).unwrap().unwrap();
+  assert!(coinbase.is_coinbase());
+  assert_eq!(b1.outputs.iter().filter(|out| out.is_coinbase()).count(), 1);
+
+  // while a transaction output passing for one doesn't make it in a block
+  let mut tx = core::transaction::Builder::new()
+    .input(grin_core::core::hash::ZERO_HASH, 6, secp::key::SecretKey::new(&secp, &mut rng))
+    .output(5, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  assert!(!tx.outputs[0].is_coinbase());
+  tx.outputs[0] = core::Output::BlindOutput {
+    features: core::OutputFeatures::Coinbase,
+    commit: tx.outputs[0].commitment().unwrap(),
+    proof: tx.outputs[0].proof().unwrap(),
+  };
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let b2 = prepare_block_with(&gen.header, 60, vec![&mut tx], key);
+  match chain.process_block(&b2, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InvalidCoinbase) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 0);
+}
+
+#[test]
+fn output_merkle_proofs() {
+  let (chain, gen) = new_chain("output_merkle_proofs");
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let out1 = b1.coinbase_output(&secp).unwrap().unwrap();
+  let proof1 = chain.merkle_proof(&out1.commitment().unwrap()).unwrap();
+  assert!(proof1.verify(&chain.head_header().unwrap().utxo_merkle, &out1.hash()));
+
+  // proofs are against the root of the head, the older ones don't hold anymore
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  let root = chain.head_header().unwrap().utxo_merkle;
+  assert!(!proof1.verify(&root, &out1.hash()));
+  let proof1 = chain.merkle_proof(&out1.commitment().unwrap()).unwrap();
+  assert!(proof1.verify(&root, &out1.hash()));
+  let out2 = b2.coinbase_output(&secp).unwrap().unwrap();
+  let proof2 = chain.merkle_proof(&out2.commitment().unwrap()).unwrap();
+  assert!(proof2.verify(&root, &out2.hash()));
+  assert!(!proof2.verify(&root, &out1.hash()));
+
+  // and outputs not on the chain have none
+  let key = secp::key::SecretKey::new(&secp, &mut OsRng::new().unwrap());
+  let commit = secp.commit(5, key).unwrap();
+  assert!(chain.merkle_proof(&commit).is_err());
+}
+
+#[test]
+fn min_fees() {
+  let (chain, gen) = new_chain("min_fees");
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+  // a transaction without fee doesn't make it in a block
+  let mut tx = core::transaction::Builder::new()
+    .input(grin_core::core::hash::ZERO_HASH, 5, secp::key::SecretKey::new(&secp, &mut rng))
+    .output(5, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(0)
+    .build(&secp)
+    .unwrap();
+  assert!(!tx.pays_min_fee());
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let b1 = prepare_block_with(&gen.header, 60, vec![&mut tx], key);
+  match chain.process_block(&b1, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::InsufficientFees) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 0);
+}
+
+#[test]
+fn header_versions() {
+  let gen = test_genesis();
+  let mut config = grin_chain::ChainConfig::default();
+  config.params.genesis = gen.clone();
+  config.params.hard_forks = vec![2];
+  config.store_backend = test_backend();
+  let chain = grin_chain::Chain::init(test_dir("header_versions"),
+                                      Arc::new(NoopAdapter{}),
+                                      config).unwrap();
+
+  // blocks before the fork keep the first version
+  let b1 = prepare_block(&gen.header, 60);
+  assert_eq!(b1.header.version, consensus::HEADER_VERSION);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+
+  // while from its height on they have to declare the next one
+  let mut b2 = prepare_block(&b1.header, 60);
+  match chain.process_block(&b2, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::WrongVersion) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  b2.header.version = chain.params().header_version(2);
+  assert_eq!(b2.header.version, consensus::HEADER_VERSION + 1);
+  let (proof, nonce) = pow::pow_size(&b2, b2.header.difficulty.clone(), 16).unwrap();
+  b2.header.pow = proof;
+  b2.header.nonce = nonce;
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().height, 2);
+}
+
+#[test]
+fn cuckoo_graduation() {
+  let (chain, gen) = new_chain("cuckoo_graduation");
+
+  // the size the genesis was mined at doesn't matter, the schedule does
+  let (_, sizeshift) = chain.next_difficulty(&gen.header).unwrap();
+  assert_eq!(sizeshift, consensus::min_sizeshift(1));
+  let b1 = prepare_block(&gen.header, 60);
+  match chain.process_block(&b1, grin_chain::pipe::NONE) {
+    Err(grin_chain::pipe::Error::WrongCuckooSize) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  let mut b1_big = b1.clone();
+  b1_big.header.cuckoo_len = consensus::MAX_SIZESHIFT + 1;
+  match chain.process_block(&b1_big, grin_chain::pipe::NONE) {
+    Err(grin_chain::pipe::Error::WrongCuckooSize) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 0);
+}
+
+#[test]
+fn secondary_pow() {
+  let (chain, gen) = new_chain("secondary_pow");
+
+  // a block mined with a secondary proof of work is just as good
+  let mut b1 = prepare_block(&gen.header, 60);
+  let primary_h = b1.hash();
+  b1.header.pow_family = core::PowFamily::Secondary;
+  let (proof, nonce) = pow::pow_size(&b1, b1.header.difficulty.clone(), 16).unwrap();
+  b1.header.pow = proof;
+  b1.header.nonce = nonce;
+  // the outputs after the block don't change with its proof of work
+  OUTPUTS.with(|all| {
+    let mut all = all.borrow_mut();
+    let outputs = all.remove(&primary_h).unwrap();
+    all.insert(b1.hash(), outputs);
+  });
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().last_block_h, b1.hash());
+
+  // the scaling it sets is the one all blocks on top have to carry
+  let scaling = chain.next_secondary_scaling(&b1.header).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  assert_eq!(b2.header.secondary_scaling, scaling);
+  let mut b2_wrong = b2.clone();
+  b2_wrong.header.secondary_scaling += 1;
+  match chain.process_block(&b2_wrong, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::WrongSecondaryScaling) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().height, 2);
+}
+
+#[test]
+fn unsorted_bodies() {
+  let (chain, gen) = new_chain("unsorted_bodies");
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+  // reordering the proofs leaves the header intact but the body malleated
+  let mut tx = core::transaction::Builder::new()
+    .input(grin_core::core::hash::ZERO_HASH, 5, secp::key::SecretKey::new(&secp, &mut rng))
+    .output(4, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let mut b1 = prepare_block_with(&gen.header, 60, vec![&mut tx], key);
+  assert!(b1.is_sorted());
+  b1.proofs.reverse();
+  assert!(!b1.is_sorted());
+  match chain.process_block(&b1, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::UnsortedBody) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 0);
+}
+
+#[test]
+fn locked_kernels() {
+  let (chain, gen) = new_chain("locked_kernels");
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+  // a transaction locked until the second block can't be in the first one
+  let mut tx = core::transaction::Builder::new()
+    .input(grin_core::core::hash::ZERO_HASH, 5, secp::key::SecretKey::new(&secp, &mut rng))
+    .output(4, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .lock_height(2)
+    .build(&secp)
+    .unwrap();
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let b1 = prepare_block_with(&gen.header, 60, vec![&mut tx], key);
+  assert!(!b1.kernels_unlocked());
+  match chain.process_block(&b1, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::LockedKernel) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 0);
+}
+
+#[test]
+fn recent_duplicate_kernels() {
+  let (store, gen) = genesis_store("recent_duplicate_kernels");
+
+  // an output to spend, skipping the wait for a coinbase to mature, seeded
+  // again each time its transaction gets replayed
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let out = core::Output::OvertOutput {
+      features: core::OutputFeatures::Plain,
+      value: 5,
+      blindkey: key,
+    }
+    .blind(&secp)
+    .unwrap();
+  let pos = OutputPos { height: 0, coinbase: false };
+  let seed_output = |store: &grin_chain::store::ChainKVStore| {
+    let batch = store.batch();
+    batch.save_output_pos(&out.hash(), &pos).unwrap();
+    batch.save_unspent(&out.hash(), &pos).unwrap();
+    batch.commit().unwrap();
+  };
+  seed_output(&store);
+  let batch = store.batch();
+  batch.save_output_mmr_node(0, 0, &out.hash()).unwrap();
+  batch.save_output_mmr_size(1).unwrap();
+  batch.save_output_leaf(&out.hash(), 0).unwrap();
+  batch.commit().unwrap();
+  let mut mmr = MountainRange::new((), 0);
+  mmr.push(out.hash()).unwrap();
+  let leaves = vec![(out.hash(), 0)].into_iter().collect();
+  OUTPUTS.with(|all| all.borrow_mut().insert(gen.hash(), (mmr, leaves)));
+
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  // a transaction whose kernel can't be on the chain twice within 3 blocks,
+  // its output spent by the second block
+  let key2 = secp::key::SecretKey::new(&secp, &mut rng);
+  let mut btx1 = core::transaction::Builder::new()
+    .input(out.hash(), 5, key)
+    .output(4, key2)
+    .fee(1)
+    .relative_height(3)
+    .build(&secp)
+    .unwrap();
+  let excess = btx1.verify_sig(&secp).unwrap().remainder;
+  let b1 = prepare_block_with(&gen.header, 60, vec![&mut btx1], secp::key::SecretKey::new(&secp, &mut rng));
+  let mut btx2 = core::transaction::Builder::new()
+    .input(btx1.outputs[0].hash(), 4, key2)
+    .output(3, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let b2 = prepare_block_with(&b1.header, 60, vec![&mut btx2], secp::key::SecretKey::new(&secp, &mut rng));
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  assert!(store.get_kernel(&excess).is_err());
+  assert_eq!(store.get_recent_kernel(&excess).unwrap().0, vec![(1, b1.hash())]);
+
+  // replayed 2 blocks later is too soon
+  seed_output(&store);
+  let b3 = prepare_block_with(&b2.header, 60, vec![&mut btx1], secp::key::SecretKey::new(&secp, &mut rng));
+  match chain.process_block(&b3, grin_chain::pipe::EASY_POW) {
+    Err(grin_chain::pipe::Error::RecentDuplicateKernel) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(chain.head().height, 2);
+
+  // 3 blocks later is fine
+  let b3 = prepare_block(&b2.header, 60);
+  chain.process_block(&b3, grin_chain::pipe::EASY_POW).unwrap();
+  let b4 = prepare_block_with(&b3.header, 60, vec![&mut btx1], secp::key::SecretKey::new(&secp, &mut rng));
+  chain.process_block(&b4, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.head().height, 4);
+  assert_eq!(chain.get_kernel(&excess).unwrap(), (4, b4.hash()));
+  assert_eq!(store.get_recent_kernel(&excess).unwrap().0,
+             vec![(1, b1.hash()), (4, b4.hash())]);
+}
+
+#[test]
+fn generated_chain() {
+  let mut config = grin_chain::ChainConfig::for_chain(ChainTypes::AutomatedTesting);
+  config.store_backend = test_backend();
+  let chain = grin_chain::Chain::init(test_dir("generated_chain"), Arc::new(NoopAdapter{}), config)
+    .unwrap();
+
+  // blocks with transactions spending the rewards and each other's outputs
+  let params = ChainTypes::AutomatedTesting.params();
+  let mut gen = ChainGenerator::new(params, 59).txs_per_block(3).block_secs(10);
+  let blocks = gen.blocks(8);
+  for b in &blocks {
+    chain.process_block(b, grin_chain::pipe::EASY_POW).unwrap();
+  }
+  assert_eq!(chain.head().height, 8);
+  assert_eq!(chain.head().last_block_h, gen.head().hash());
+  assert_eq!(blocks.iter().map(|b| b.proofs.len() - 1).sum::<usize>(), 1 + 3 * 6);
+
+  let stats = chain.stats().unwrap();
+  assert_eq!(stats.intervals.mean, 10);
 }
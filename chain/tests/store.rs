@@ -0,0 +1,572 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate rand;
+extern crate time;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::io::{Seek, Write};
+use std::sync::Arc;
+use std::thread;
+use rand::os::OsRng;
+
+use grin_chain::types::*;
+use grin_core::core::hash::{Hash, Hashed};
+use grin_core::core::mmr::MountainRange;
+use grin_core::ser;
+use grin_core::core;
+
+use common::*;
+
+#[test]
+fn memory_store() {
+  let gen = test_genesis();
+  let mem_store = Arc::new(grin_chain::store::MemoryChainStore::new());
+  let disk_store = Arc::new(new_store(&test_dir("memory_store")));
+  let stores: Vec<Arc<ChainStore>> = vec![mem_store.clone(), disk_store.clone()];
+  let chains = stores.iter().map(|store| {
+    store.save_block(&gen).unwrap();
+    store.save_head(&Tip::new(gen.hash())).unwrap();
+    grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap()
+  }).collect::<Vec<_>>();
+
+  // the same blocks, with a reorg, end up the same way in both
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let fork2 = prepare_block(&b1.header, 61);
+  let fork3 = prepare_block(&fork2.header, 60);
+  for chain in &chains {
+    for b in &[&b1, &b2, &fork2, &fork3] {
+      chain.process_block(*b, grin_chain::pipe::EASY_POW).unwrap();
+    }
+  }
+  assert_eq!(mem_store.head().unwrap().last_block_h, fork3.hash());
+  assert_eq!(mem_store.get_header_by_height(2).unwrap().hash(), fork2.hash());
+  assert_eq!(mem_store.headers_iter(0, Direction::Forward).count(), 4);
+  assert_eq!(mem_store.disk_usage().unwrap(), 0);
+  let tips = |store: &Arc<ChainStore>| {
+    store.get_tips().unwrap().iter().map(|t| t.last_block_h).collect::<Vec<_>>()
+  };
+  assert_eq!(tips(&stores[0]), tips(&stores[1]));
+  assert_eq!(mem_store.get_unspent_outputs().unwrap(),
+             disk_store.get_unspent_outputs().unwrap());
+  assert_eq!(mem_store.get_kernels().unwrap().len(), disk_store.get_kernels().unwrap().len());
+  assert_eq!(mem_store.get_output_mmr_size().unwrap(),
+             disk_store.get_output_mmr_size().unwrap());
+}
+
+#[test]
+fn batch_rollback() {
+  let gen = test_genesis();
+  let b1 = prepare_block(&gen.header, 60);
+  let stores: Vec<Arc<ChainStore>> = vec![Arc::new(new_store(&test_dir("batch_rollback"))),
+                                          Arc::new(grin_chain::store::MemoryChainStore::new())];
+  for store in stores {
+    store.save_block(&gen).unwrap();
+    store.save_head(&Tip::new(gen.hash())).unwrap();
+
+    // nothing from a rolled back batch makes it to the store
+    let batch = store.batch();
+    batch.save_block(&b1).unwrap();
+    batch.save_head(&Tip::new(gen.hash()).append(&b1.header)).unwrap();
+    batch.rollback();
+    assert!(store.get_block(&b1.hash()).is_err());
+    assert_eq!(store.head().unwrap().last_block_h, gen.hash());
+
+    let batch = store.batch();
+    batch.save_block(&b1).unwrap();
+    batch.save_head(&Tip::new(gen.hash()).append(&b1.header)).unwrap();
+    batch.commit().unwrap();
+    assert!(store.get_block(&b1.hash()).is_ok());
+    assert_eq!(store.head().unwrap().last_block_h, b1.hash());
+  }
+}
+
+#[test]
+fn prefix_scans() {
+  let gen = test_genesis();
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let b3 = prepare_block(&b2.header, 60);
+  let stores: Vec<Arc<ChainStore>> = vec![Arc::new(new_store(&test_dir("prefix_scans"))),
+                                          Arc::new(grin_chain::store::MemoryChainStore::new())];
+  for store in stores {
+    store.save_block(&gen).unwrap();
+    store.save_head(&Tip::new(gen.hash())).unwrap();
+    let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+    for b in &[&b1, &b2, &b3] {
+      chain.process_block(*b, grin_chain::pipe::EASY_POW).unwrap();
+    }
+
+    // headers by height range, stopping at the head
+    let range = |from, to| store.headers_range(from, to).map(|bh| bh.unwrap().hash()).collect::<Vec<_>>();
+    assert_eq!(range(1, 3), vec![b1.hash(), b2.hash()]);
+    assert_eq!(range(2, 10), vec![b2.hash(), b3.hash()]);
+    assert!(range(4, 10).is_empty());
+
+    // outputs by hash prefix, all of them without any
+    let all = store.unspent_iter(&[]).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(all, store.get_unspent_outputs().unwrap());
+    assert_eq!(all.len(), 3);
+    let h = all[1].0;
+    assert!(store.unspent_iter(&h.0[..1]).all(|res| (res.unwrap().0).0[0] == h.0[0]));
+    assert!(store.unspent_iter(&h.0[..1]).any(|res| res.unwrap().0 == h));
+    assert_eq!(store.unspent_iter(&h.0[..]).collect::<Result<Vec<_>, _>>().unwrap(),
+               vec![all[1].clone()]);
+  }
+}
+
+#[test]
+fn schema_versions() {
+  let dir = test_dir("schema_versions");
+  let db_dir = format!("{}/chain", dir);
+  let open = || grin_chain::store::ChainKVStore::with_backend(dir.clone(),
+                                                              grin_store::Backend::RocksDb);
+  let gen = test_genesis();
+  {
+    let store = open().unwrap();
+    assert_eq!(store.schema_version().unwrap(), grin_chain::store::SCHEMA_VERSION);
+    store.save_block(&gen).unwrap();
+    store.save_head(&Tip::new(gen.hash())).unwrap();
+  }
+
+  // data saved before versioning gets migrated
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.delete(&['v' as u8]).unwrap();
+  }
+  let store = open().unwrap();
+  assert_eq!(store.schema_version().unwrap(), grin_chain::store::SCHEMA_VERSION);
+  assert_eq!(store.head().unwrap().last_block_h, gen.hash());
+  drop(store);
+
+  // while data from a newer version is left alone
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&['v' as u8], vec![0, 0, 0, 0, 0, 0, 0, 99]).unwrap();
+  }
+  match open() {
+    Err(Error::SchemaErr(99)) => {}
+    Err(e) => panic!("unexpected error {:?}", e),
+    Ok(_) => panic!("newer schema version accepted"),
+  }
+
+  // and headers from before they had a version can't be converted
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&['v' as u8], vec![0, 0, 0, 0, 0, 0, 0, 2]).unwrap();
+    let mut key = vec!['h' as u8, ':' as u8];
+    key.extend_from_slice(&gen.hash().0[..]);
+    // skipping the serialization version and length too
+    let bytes = ser::ser_vec(&gen.header).unwrap();
+    db.put(&key, bytes[5..].to_vec()).unwrap();
+  }
+  match open() {
+    Err(Error::StorageErr(_)) => {}
+    Err(e) => panic!("unexpected error {:?}", e),
+    Ok(_) => panic!("unversioned headers accepted"),
+  }
+
+  // nor headers from before they had a proof of work family, whose family
+  // and scaling came right before the nonce
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&['v' as u8], vec![0, 0, 0, 0, 0, 0, 0, 3]).unwrap();
+    let mut key = vec!['h' as u8, ':' as u8];
+    key.extend_from_slice(&gen.hash().0[..]);
+    let mut bytes = ser::ser_vec(&gen.header).unwrap()[3..].to_vec();
+    bytes.drain(115..120);
+    db.put(&key, bytes).unwrap();
+  }
+  match open() {
+    Err(Error::StorageErr(_)) => {}
+    Err(e) => panic!("unexpected error {:?}", e),
+    Ok(_) => panic!("headers without proof of work family accepted"),
+  }
+
+  // while blocks from before transaction lock heights get them at 0, the
+  // lock and relative heights being the last fields of their only proof
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let key = secp::key::SecretKey::new(&secp, &mut OsRng::new().unwrap());
+  let b1 = core::Block::new(&gen.header, vec![], key).unwrap();
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&['v' as u8], vec![0, 0, 0, 0, 0, 0, 0, 4]).unwrap();
+    let mut key = vec!['h' as u8, ':' as u8];
+    key.extend_from_slice(&gen.hash().0[..]);
+    db.put(&key, ser::ser_vec(&gen.header).unwrap()).unwrap();
+    let mut key = vec!['b' as u8, ':' as u8];
+    key.extend_from_slice(&b1.hash().0[..]);
+    let bytes = ser::ser_vec(&b1).unwrap();
+    db.put(&key, bytes[..bytes.len() - 16].to_vec()).unwrap();
+  }
+  let store = open().unwrap();
+  assert_eq!(store.schema_version().unwrap(), grin_chain::store::SCHEMA_VERSION);
+  let db1 = store.get_block(&b1.hash()).unwrap();
+  assert_eq!(db1.proofs[0].lock_height, 0);
+  assert_eq!(db1.proofs[0].relative_height, 0);
+  db1.verify(&secp).unwrap();
+  drop(store);
+
+  // and blocks from before relative heights get them at 0, the last field
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&['v' as u8], vec![0, 0, 0, 0, 0, 0, 0, 5]).unwrap();
+    let mut key = vec!['b' as u8, ':' as u8];
+    key.extend_from_slice(&b1.hash().0[..]);
+    let bytes = ser::ser_vec(&b1).unwrap();
+    db.put(&key, bytes[..bytes.len() - 8].to_vec()).unwrap();
+  }
+  let store = open().unwrap();
+  assert_eq!(store.schema_version().unwrap(), grin_chain::store::SCHEMA_VERSION);
+  let db1 = store.get_block(&b1.hash()).unwrap();
+  assert_eq!(db1.proofs[0].relative_height, 0);
+  db1.verify(&secp).unwrap();
+  drop(store);
+
+  // but outputs from before they had features can't be converted, the
+  // features of the only output coming right after the header and lengths
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&['v' as u8], vec![0, 0, 0, 0, 0, 0, 0, 6]).unwrap();
+    let mut key = vec!['b' as u8, ':' as u8];
+    key.extend_from_slice(&b1.hash().0[..]);
+    let mut bytes = ser::ser_vec(&b1).unwrap();
+    bytes.remove(ser::ser_vec(&b1.header).unwrap().len() + 24);
+    db.put(&key, bytes).unwrap();
+  }
+  match open() {
+    Err(Error::StorageErr(_)) => {}
+    Err(e) => panic!("unexpected error {:?}", e),
+    Ok(_) => panic!("outputs without features accepted"),
+  }
+}
+
+#[test]
+fn output_records() {
+  let (store, gen) = genesis_store("output_records");
+
+  // an output to spend, skipping the wait for a coinbase to mature
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let key = secp::key::SecretKey::new(&secp, &mut rng);
+  let out = core::Output::OvertOutput {
+      features: core::OutputFeatures::Plain,
+      value: 5,
+      blindkey: key,
+    }
+    .blind(&secp)
+    .unwrap();
+  let pos = OutputPos { height: 0, coinbase: false };
+  let batch = store.batch();
+  batch.save_output_pos(&out.hash(), &pos).unwrap();
+  batch.save_unspent(&out.hash(), &pos).unwrap();
+  batch.save_output_mmr_node(0, 0, &out.hash()).unwrap();
+  batch.save_output_mmr_size(1).unwrap();
+  batch.save_output_leaf(&out.hash(), 0).unwrap();
+  batch.commit().unwrap();
+  let mut mmr = MountainRange::new((), 0);
+  mmr.push(out.hash()).unwrap();
+  let leaves = vec![(out.hash(), 0)].into_iter().collect();
+  OUTPUTS.with(|all| all.borrow_mut().insert(gen.hash(), (mmr, leaves)));
+
+  let store = Arc::new(store);
+  let chain = grin_chain::Chain::new(store.clone(), Arc::new(NoopAdapter{})).unwrap();
+
+  // b1 creates an output b2 spends
+  let key2 = secp::key::SecretKey::new(&secp, &mut rng);
+  let mut btx1 = core::transaction::Builder::new()
+    .input(out.hash(), 5, key)
+    .output(4, key2)
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let b1 = prepare_block_with(&gen.header, 60, vec![&mut btx1], secp::key::SecretKey::new(&secp, &mut rng));
+  let mut btx2 = core::transaction::Builder::new()
+    .input(btx1.outputs[0].hash(), 4, key2)
+    .output(3, secp::key::SecretKey::new(&secp, &mut rng))
+    .fee(1)
+    .build(&secp)
+    .unwrap();
+  let b2 = prepare_block_with(&b1.header, 60, vec![&mut btx2], secp::key::SecretKey::new(&secp, &mut rng));
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+
+  let commit = btx1.outputs[0].commitment().unwrap();
+  let rec = chain.get_output_record(&commit).unwrap();
+  assert_eq!(rec.block_h, b1.hash());
+  assert_eq!(rec.height, 1);
+  assert_eq!(rec.mmr_pos, store.get_output_leaf(&btx1.outputs[0].hash()).unwrap());
+  assert_eq!(rec.spent_height, None);
+  // coinbase outputs are found by commitment just the same
+  let coinbase = b1.coinbase_output(&secp).unwrap().unwrap();
+  assert_eq!(chain.get_output_record(&coinbase.commitment().unwrap()).unwrap().height, 1);
+
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(chain.get_output_record(&commit).unwrap().spent_height, Some(2));
+
+  // undoing the blocks undoes the spend, then the output itself
+  chain.rewind_to(1).unwrap();
+  assert_eq!(chain.get_output_record(&commit).unwrap(), rec);
+  chain.rewind_to(0).unwrap();
+  assert!(chain.get_output_record(&commit).is_err());
+}
+
+#[test]
+fn kernel_lookups() {
+  let (chain, gen) = new_chain("kernel_lookups");
+
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+
+  // the reward kernel of each block tells where it is
+  let excess = b2.proofs[0].remainder;
+  assert_eq!(chain.get_kernel(&b1.proofs[0].remainder).unwrap(), (1, b1.hash()));
+  assert_eq!(chain.get_kernel(&excess).unwrap(), (2, b2.hash()));
+
+  // and isn't found once its block leaves the main chain
+  chain.rewind_to(1).unwrap();
+  match chain.get_kernel(&excess) {
+    Err(Error::NotFoundErr) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+}
+
+#[test]
+fn header_files() {
+  let gen = test_genesis();
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let fork2 = prepare_block(&b1.header, 61);
+  let fork3 = prepare_block(&fork2.header, 60);
+  let dir = test_dir("header_files");
+  let index = format!("{}/headers/headers.idx", dir);
+  let index_len = || std::fs::metadata(&index).unwrap().len();
+  {
+    let store = new_store(&dir);
+    store.save_block(&gen).unwrap();
+    store.save_head(&Tip::new(gen.hash())).unwrap();
+    let chain = grin_chain::Chain::new(Arc::new(store), Arc::new(NoopAdapter{})).unwrap();
+    for b in &[&b1, &b2, &fork2, &fork3] {
+      chain.process_block(*b, grin_chain::pipe::EASY_POW).unwrap();
+    }
+    assert_eq!(chain.get_header_by_height(2).unwrap().hash(), fork2.hash());
+  }
+  // the main chain headers after the reorg, the end of each in the index
+  assert_eq!(index_len(), 4 * 8);
+
+  // header files left behind by a crash, in the middle of an append, catch up
+  // with the db when opened
+  {
+    let mut index = std::fs::OpenOptions::new()
+      .write(true)
+      .open(&index)
+      .unwrap();
+    index.set_len(8).unwrap();
+    index.seek(std::io::SeekFrom::End(0)).unwrap();
+    index.write_all(&[0xff; 8]).unwrap();
+  }
+  let store = new_store(&dir);
+  assert_eq!(index_len(), 4 * 8);
+  let hashes = store.headers_range(0, 4).map(|bh| bh.unwrap().hash()).collect::<Vec<_>>();
+  assert_eq!(hashes, vec![gen.hash(), b1.hash(), fork2.hash(), fork3.hash()]);
+}
+
+#[test]
+fn backup_restore() {
+  let gen = test_genesis();
+  let mut config = grin_chain::ChainConfig::default();
+  config.params.genesis = gen.clone();
+  config.store_backend = test_backend();
+  let dir = test_dir("backup_restore");
+  let backup = format!("{}_backup", dir);
+  let chain = grin_chain::Chain::init(dir.clone(),
+                                      Arc::new(NoopAdapter{}),
+                                      config.clone()).unwrap();
+  let b1 = prepare_block(&gen.header, 60);
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  let b2 = prepare_block(&b1.header, 60);
+  chain.process_block(&b2, grin_chain::pipe::EASY_POW).unwrap();
+
+  chain.backup(&backup).unwrap();
+  // an existing backup doesn't get overwritten
+  assert!(chain.backup(&backup).is_err());
+  let b3 = prepare_block(&b2.header, 60);
+  chain.process_block(&b3, grin_chain::pipe::EASY_POW).unwrap();
+
+  // restoring from a missing backup leaves the store alone
+  let restored_dir = test_dir("backup_restore-restored");
+  config.restore_from = Some(format!("{}_missing", dir));
+  assert!(grin_chain::Chain::init(restored_dir.clone(), Arc::new(NoopAdapter{}), config.clone())
+    .is_err());
+
+  // the restored chain is where it was when backed up
+  config.restore_from = Some(backup);
+  let restored = grin_chain::Chain::init(restored_dir, Arc::new(NoopAdapter{}), config)
+    .unwrap();
+  assert_eq!(restored.head().last_block_h, b2.hash());
+  assert_eq!(restored.get_header_by_height(2).unwrap().hash(), b2.hash());
+  assert!(restored.get_block(&b3.hash()).is_err());
+  restored.validate(grin_chain::pipe::EASY_POW).unwrap();
+}
+
+#[test]
+fn store_integrity() {
+  use grin_chain::store::{BrokenRecord, ChainKVStore, RecordFault};
+
+  let gen = test_genesis();
+  let b1 = prepare_block(&gen.header, 60);
+  let b2 = prepare_block(&b1.header, 60);
+  let fork2 = prepare_block(&b1.header, 61);
+  let fork3 = prepare_block(&fork2.header, 60);
+  let dir = test_dir("store_integrity");
+  let db_dir = format!("{}/chain", dir);
+  let open = || ChainKVStore::with_backend(dir.clone(), grin_store::Backend::RocksDb)
+    .unwrap();
+  {
+    let store = open();
+    store.save_block(&gen).unwrap();
+    store.save_head(&Tip::new(gen.hash())).unwrap();
+    assert!(!store.unclean_shutdown());
+    let chain = grin_chain::Chain::new(Arc::new(store), Arc::new(NoopAdapter{})).unwrap();
+    for b in &[&b1, &b2, &fork2] {
+      chain.process_block(*b, grin_chain::pipe::EASY_POW).unwrap();
+    }
+  }
+
+  // records broken by a crash in the middle of writing them
+  let key = |prefix: u8, h: Hash| {
+    let mut key = vec![prefix, ':' as u8];
+    key.extend(h.to_vec());
+    key
+  };
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&key('h' as u8, fork2.hash()), vec![1, 2, 3]).unwrap();
+    db.put_ser(&key('h' as u8, fork3.hash()), &fork3.header).unwrap();
+    db.put_ser(&key('b' as u8, fork3.hash()), &b2).unwrap();
+    // still flagged as running, as left by the crash
+    db.put(&vec!['R' as u8], vec![]).unwrap();
+  }
+  let store = open();
+  assert!(store.unclean_shutdown());
+  let mut expected = vec![
+    BrokenRecord{key: key('h' as u8, fork2.hash()), fault: RecordFault::Unreadable},
+    BrokenRecord{key: key('h' as u8, fork3.hash()), fault: RecordFault::MissingParent},
+    BrokenRecord{key: key('b' as u8, fork2.hash()), fault: RecordFault::MissingHeader},
+    BrokenRecord{key: key('b' as u8, fork3.hash()), fault: RecordFault::WrongHash},
+  ];
+  expected.sort_by(|a, b| a.key.cmp(&b.key));
+  let check = |repair| {
+    let mut broken = store.check_records(repair).unwrap();
+    broken.sort_by(|a, b| a.key.cmp(&b.key));
+    broken
+  };
+
+  // only repairing moves the broken records away, the rest of the chain stays
+  assert_eq!(check(false), expected);
+  assert_eq!(check(true), expected);
+  assert_eq!(check(false), vec![]);
+  assert!(store.get_block(&fork2.hash()).is_err());
+  assert!(store.get_block(&b2.hash()).is_ok());
+  assert_eq!(store.get_header_by_height(2).unwrap().hash(), b2.hash());
+  drop(store);
+  assert!(!open().unclean_shutdown());
+
+  // an unreadable record fails the reads going through it instead of ending them
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&key('T' as u8, b2.hash()), vec![1, 2, 3]).unwrap();
+  }
+  assert!(open().get_tips().is_err());
+  {
+    let db = grin_store::Store::open(&db_dir).unwrap();
+    db.put(&key('b' as u8, b1.hash()), vec![1, 2, 3]).unwrap();
+  }
+  let store = open();
+  let blocks = store.blocks_iter(0, Direction::Forward).collect::<Vec<_>>();
+  assert_eq!(blocks.len(), 2);
+  assert_eq!(blocks[0].as_ref().unwrap().hash(), gen.hash());
+  assert!(blocks[1].is_err());
+}
+
+#[test]
+fn cached_store() {
+  let gen = test_genesis();
+  // small enough for blocks to get evicted while processing
+  let store = grin_chain::store::CachedChainStore::new(new_store(&test_dir("cached_store")), 2, 1);
+  store.save_block(&gen).unwrap();
+  store.save_head(&Tip::new(gen.hash())).unwrap();
+  let arc_store = Arc::new(store);
+  let config = grin_chain::ChainConfig {
+    max_reorg_depth: 1,
+    ..Default::default()
+  };
+  let chain = grin_chain::Chain::with_config(arc_store.clone(), Arc::new(NoopAdapter{}), config)
+    .unwrap();
+
+  let b1 = prepare_block(&gen.header, 60);
+  let stale = prepare_block(&b1.header, 61);
+  let mut blocks = vec![b1];
+  for _ in 0..3 {
+    let b = prepare_block(&blocks.last().unwrap().header, 60);
+    blocks.push(b);
+  }
+  chain.process_block(&blocks[0], grin_chain::pipe::EASY_POW).unwrap();
+  chain.process_block(&stale, grin_chain::pipe::EASY_POW).unwrap();
+  for b in &blocks[1..] {
+    chain.process_block(b, grin_chain::pipe::EASY_POW).unwrap();
+  }
+  assert_eq!(arc_store.get_block(&stale.hash()).unwrap().hash(), stale.hash());
+  assert_eq!(arc_store.get_block_header(&stale.hash()).unwrap().hash(), stale.hash());
+
+  // what compaction removes isn't served from the caches anymore
+  chain.compact().unwrap();
+  assert!(arc_store.get_block(&stale.hash()).is_err());
+  assert!(arc_store.get_block_header(&stale.hash()).is_err());
+  assert!(arc_store.inner().get_block(&stale.hash()).is_err());
+  for b in &blocks {
+    assert_eq!(arc_store.get_block(&b.hash()).unwrap().hash(), b.hash());
+  }
+  chain.validate(grin_chain::pipe::EASY_POW).unwrap();
+}
+
+#[test]
+fn read_only_store() {
+  let (chain, gen) = new_chain("read_only_store");
+
+  // another thread follows the chain as it gets extended
+  let reader = chain.read_only_store();
+  let b1 = prepare_block(&gen.header, 60);
+  let b1_hash = b1.hash();
+  let follower = thread::spawn(move || {
+    while reader.head().unwrap().last_block_h != b1_hash {
+      thread::yield_now();
+    }
+    reader.get_block(&b1_hash).unwrap().header.height
+  });
+  chain.process_block(&b1, grin_chain::pipe::EASY_POW).unwrap();
+  assert_eq!(follower.join().unwrap(), 1);
+
+  let reader = chain.read_only_store();
+  let hashes = reader.headers_iter(0, Direction::Forward).map(|bh| bh.unwrap().hash())
+    .collect::<Vec<_>>();
+  assert_eq!(hashes, vec![gen.hash(), b1.hash()]);
+}
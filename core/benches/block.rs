@@ -0,0 +1,96 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of the validation of block bodies, sequential and parallel, by
+//! number of transactions.
+
+#[macro_use]
+extern crate criterion;
+extern crate grin_core;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+use criterion::{Bencher, Criterion, black_box};
+use rand::Rng;
+use rand::os::OsRng;
+
+use grin_core::core::{Block, BlockHeader, Input, Output, OutputFeatures, Transaction};
+use grin_core::core::hash::ZERO_HASH;
+use secp::Secp256k1;
+use secp::key::SecretKey;
+
+fn new_secp() -> Secp256k1 {
+	secp::Secp256k1::with_caps(secp::ContextFlag::Commit)
+}
+
+// a transaction with 2 inputs and a single output
+fn tx2i1o<R: Rng>(secp: &Secp256k1, rng: &mut R) -> Transaction {
+	Transaction::new(vec![Input::OvertInput {
+		                      output: ZERO_HASH,
+		                      value: 10,
+		                      blindkey: SecretKey::new(secp, rng),
+	                      },
+	                      Input::OvertInput {
+		                      output: ZERO_HASH,
+		                      value: 11,
+		                      blindkey: SecretKey::new(secp, rng),
+	                      }],
+	                 vec![Output::OvertOutput {
+		                      features: OutputFeatures::Plain,
+		                      value: 20,
+		                      blindkey: SecretKey::new(secp, rng),
+	                      }],
+	                 1)
+}
+
+// a valid block with the provided number of transactions
+fn block_with(txs: usize, secp: &Secp256k1) -> Block {
+	let mut rng = OsRng::new().unwrap();
+	let mut btxs = (0..txs)
+		.map(|_| tx2i1o(secp, &mut rng).blind(secp).unwrap())
+		.collect::<Vec<_>>();
+	let skey = SecretKey::new(secp, &mut rng);
+	Block::new(&BlockHeader::default(), btxs.iter_mut().collect(), skey).unwrap()
+}
+
+fn bench_verify(bh: &mut Bencher, txs: usize) {
+	let ref secp = new_secp();
+	let b = block_with(txs, secp);
+	bh.iter(|| {
+		let res = b.verify(secp);
+		black_box(res);
+	});
+}
+
+fn bench_verify_parallel(bh: &mut Bencher, txs: usize) {
+	let ref secp = new_secp();
+	let b = block_with(txs, secp);
+	bh.iter(|| {
+		let res = b.verify_parallel(secp);
+		black_box(res);
+	});
+}
+
+fn verify(c: &mut Criterion) {
+	c.bench_function_over_inputs("verify", |bh, &txs| bench_verify(bh, txs), vec![1, 10]);
+}
+
+fn verify_parallel(c: &mut Criterion) {
+	c.bench_function_over_inputs("verify_parallel",
+	                             |bh, &txs| bench_verify_parallel(bh, txs),
+	                             vec![10, 100]);
+}
+
+criterion_group!(benches, verify, verify_parallel);
+criterion_main!(benches);
@@ -0,0 +1,57 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of the difficulty and secondary scaling adjustments done for
+//! every block.
+
+#[macro_use]
+extern crate criterion;
+extern crate grin_core;
+
+use criterion::{Criterion, black_box};
+
+use grin_core::consensus::{DIFFICULTY_ADJUST_WINDOW, next_difficulty, next_secondary_scaling};
+use grin_core::core::PowFamily;
+use grin_core::core::target::Difficulty;
+
+// a full window of blocks with irregular intervals and difficulties
+fn window(diff: u32) -> Vec<(i64, Difficulty)> {
+	(0..DIFFICULTY_ADJUST_WINDOW as i64 + 1)
+		.map(|n| (1000000 - n * 60 - (n * 37) % 50, Difficulty::from_num(diff + n as u32)))
+		.collect()
+}
+
+fn difficulty(c: &mut Criterion) {
+	let low = window(1000);
+	c.bench_function("next_difficulty_low", move |bh| {
+		bh.iter(|| black_box(next_difficulty(&low)));
+	});
+	// difficulties past a single BigUint digit once summed
+	let high = window(u32::max_value() - 100);
+	c.bench_function("next_difficulty_high", move |bh| {
+		bh.iter(|| black_box(next_difficulty(&high)));
+	});
+}
+
+fn secondary_scaling(c: &mut Criterion) {
+	let scalings = (0..DIFFICULTY_ADJUST_WINDOW + 1)
+		.map(|n| (if n % 3 == 0 { PowFamily::Secondary } else { PowFamily::Primary }, 100))
+		.collect::<Vec<_>>();
+	c.bench_function("next_secondary_scaling_window", move |bh| {
+		bh.iter(|| black_box(next_secondary_scaling(1000, &scalings)));
+	});
+}
+
+criterion_group!(benches, difficulty, secondary_scaling);
+criterion_main!(benches);
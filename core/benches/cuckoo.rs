@@ -0,0 +1,72 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of the cuckoo cycle verification done for every header, across
+//! graph sizes.
+
+#[macro_use]
+extern crate criterion;
+extern crate grin_core;
+
+use criterion::{Bencher, Criterion, black_box};
+
+use grin_core::core::{PowFamily, Proof};
+use grin_core::pow::cuckoo::{Cuckoo, Miner};
+
+// the first header with a solution in the graph of the provided size and
+// family, along with its proof
+fn solved(sizeshift: u32, family: PowFamily) -> ([u8; 32], Proof) {
+	(0u8..)
+		.filter_map(|n| {
+			let h = [n; 32];
+			Miner::with_family(&h, 75, sizeshift, family).mine().ok().map(|proof| (h, proof))
+		})
+		.next()
+		.unwrap()
+}
+
+// verification as done for every header, seeding the graph included
+fn bench_verify(bh: &mut Bencher, sizeshift: u32, family: PowFamily) {
+	let (h, proof) = solved(sizeshift, family);
+	bh.iter(|| {
+		let res = Cuckoo::with_family(&h, sizeshift, family).verify(proof, 75);
+		black_box(res);
+	});
+}
+
+fn verify(c: &mut Criterion) {
+	c.bench_function_over_inputs("verify",
+	                             |bh, &sizeshift| bench_verify(bh, sizeshift, PowFamily::Primary),
+	                             vec![16, 18, 20]);
+}
+
+fn verify_secondary(c: &mut Criterion) {
+	c.bench_function_over_inputs("verify_secondary",
+	                             |bh, &sizeshift| bench_verify(bh, sizeshift, PowFamily::Secondary),
+	                             vec![16, 20]);
+}
+
+fn verify_fail(c: &mut Criterion) {
+	// a proof for another header, rejected at the first bad edge
+	let (_, proof) = solved(20, PowFamily::Primary);
+	c.bench_function("verify_fail20", move |bh| {
+		bh.iter(|| {
+			let res = Cuckoo::new(&[0xff; 32], 20).verify(proof, 75);
+			black_box(res);
+		});
+	});
+}
+
+criterion_group!(benches, verify, verify_secondary, verify_fail);
+criterion_main!(benches);
@@ -21,14 +21,81 @@
 
 use std::cmp;
 
-use bigint::{BigInt, Sign, BigUint};
+use bigint::BigUint;
 
+use core::PowFamily;
 use core::target::Difficulty;
 
-/// The block subsidy amount
+/// The block subsidy amount, before any halving
 pub const REWARD: u64 = 1_000_000_000;
 
-/// Block interval, in seconds, the network will tune its next_target for. Note
+/// Number of blocks after which the block subsidy halves, about four years of
+/// blocks at one every BLOCK_TIME_SEC.
+pub const REWARD_HALVING_INTERVAL: u64 = 2_100_000;
+
+/// The subsidy of a block at the provided height, starting at REWARD and
+/// halving every REWARD_HALVING_INTERVAL blocks until nothing's left.
+pub fn block_subsidy(height: u64) -> u64 {
+	let halvings = height / REWARD_HALVING_INTERVAL;
+	if halvings >= 64 {
+		0
+	} else {
+		REWARD >> halvings
+	}
+}
+
+/// Total amount issued by the subsidies of all the blocks from the genesis up
+/// to the provided height included.
+pub fn total_supply(height: u64) -> u64 {
+	let halvings = height / REWARD_HALVING_INTERVAL;
+	let full_epochs = (0..cmp::min(halvings, 64))
+		.fold(0, |acc, n| acc + (REWARD >> n) * REWARD_HALVING_INTERVAL);
+	full_epochs + block_subsidy(height) * (height % REWARD_HALVING_INTERVAL + 1)
+}
+
+/// Largest amount anything can be worth, fees and rewards included. Overages
+/// of commitment sums are signed, the amounts they carry have to fit an i64,
+/// which is way more than the total supply ever gets to.
+pub const MAX_AMOUNT: u64 = ::std::i64::MAX as u64;
+
+/// Sum of the provided amounts, none if it goes over MAX_AMOUNT, so summing
+/// amounts can never silently overflow.
+pub fn sum_amounts<I: IntoIterator<Item = u64>>(amounts: I) -> Option<u64> {
+	amounts.into_iter()
+		.fold(Some(0u64), |acc, amount| acc.and_then(|sum| sum.checked_add(amount)))
+		.and_then(|sum| if sum > MAX_AMOUNT { None } else { Some(sum) })
+}
+
+/// The total reward of a block at the provided height with the provided
+/// transaction fees, what its coinbase output has to be worth: its subsidy
+/// plus all the fees. None if that'd be over MAX_AMOUNT, no coinbase can be
+/// worth that much.
+pub fn reward(height: u64, fees: u64) -> Option<u64> {
+	sum_amounts(vec![block_subsidy(height), fees])
+}
+
+/// Version of the block headers from the genesis up to the first hard fork.
+pub const HEADER_VERSION: u16 = 1;
+
+/// Heights of the scheduled hard forks, in order. Each one bumps the version
+/// blocks have to declare in their header from its height on, the consensus
+/// rules changing along with it. None scheduled yet.
+pub const HARD_FORK_HEIGHTS: &'static [u64] = &[];
+
+/// Version the header of the block at the provided height has to have, given
+/// the provided hard fork heights, HEADER_VERSION plus the number of forks
+/// already passed.
+pub fn header_version_at(height: u64, fork_heights: &[u64]) -> u16 {
+	HEADER_VERSION + fork_heights.iter().filter(|&&fork| height >= fork).count() as u16
+}
+
+/// Version the header of the block at the provided height has to have on the
+/// main network, following HARD_FORK_HEIGHTS.
+pub fn header_version(height: u64) -> u16 {
+	header_version_at(height, HARD_FORK_HEIGHTS)
+}
+
+/// Block interval, in seconds, the network will tune its difficulty for. Note
 /// that we may reduce this value in the future as we get more data on mining
 /// with Cuckoo Cycle, networks improve and block propagation is optimized
 /// (adjusting the reward accordingly).
@@ -40,59 +107,171 @@ pub const PROOFSIZE: usize = 42;
 /// Origin Cuckoo Cycle size shift used by the genesis block.
 pub const DEFAULT_SIZESHIFT: u8 = 25;
 
-/// Maximum Cuckoo Cycle size shift we'll ever use. We adopt a schedule that
-/// progressively increases the size as the target becomes lower.
-///   Start => 25
-///   MAX_TARGET >> 12 => 26
-///   MAX_TARGET >> 20 => 27
-///   MAX_TARGET >> 28 => 28
-///   MAX_TARGET >> 36 => 29
+/// Maximum Cuckoo Cycle size shift we'll ever use, see
+/// `graduated_sizeshift` for the schedule up to it.
 pub const MAX_SIZESHIFT: u8 = 29;
 
+/// Number of blocks in each epoch of the Cuckoo Cycle size graduation, about
+/// a year of blocks at one every BLOCK_TIME_SEC.
+pub const SIZESHIFT_EPOCH: u64 = 365 * 24 * 3600 / (BLOCK_TIME_SEC as u64);
+
+/// Minimum Cuckoo Cycle size shift of the block at the provided height, given
+/// the size shift the chain starts at and its maximum. The size shift goes up
+/// by one every SIZESHIFT_EPOCH blocks until it reaches the maximum, so the
+/// graph keeps getting bigger than what fits in cheap memory as hardware
+/// improves:
+///   Year 1 => 25
+///   Year 2 => 26
+///   Year 3 => 27
+///   Year 4 => 28
+///   Later => 29
+pub fn graduated_sizeshift(height: u64, min_sizeshift: u8, max_sizeshift: u8) -> u8 {
+	let epochs = height / SIZESHIFT_EPOCH;
+	let room = max_sizeshift.saturating_sub(min_sizeshift) as u64;
+	min_sizeshift + cmp::min(epochs, room) as u8
+}
+
+/// Minimum Cuckoo Cycle size shift of the block at the provided height on the
+/// main network, from DEFAULT_SIZESHIFT up to MAX_SIZESHIFT.
+pub fn min_sizeshift(height: u64) -> u8 {
+	graduated_sizeshift(height, DEFAULT_SIZESHIFT, MAX_SIZESHIFT)
+}
+
 /// Default Cuckoo Cycle easiness, high enough to have good likeliness to find
 /// a solution.
 pub const EASINESS: u32 = 50;
 
-/// Difficulty adjustment somewhat inspired by Ethereum's. Tuned to add or
-/// remove 1/1024th of the target for each 10 seconds of deviation from the 30
-/// seconds block time. Increases Cuckoo size shift by one when next_target
-/// reaches soft max.
-pub fn next_target(ts: i64,
-                   prev_ts: i64,
-                   prev_diff: Difficulty,
-                   prev_cuckoo_sz: u8)
-                   -> (Difficulty, u8) {
-	let one = BigInt::new(Sign::Plus, vec![1]);
-	let two = BigInt::new(Sign::Plus, vec![2]);
-	let ten = BigInt::new(Sign::Plus, vec![10]);
-
-	// increase the cuckoo size when the target gets lower than the soft min as
-	// long as we're not at the max size already; target gets 2x to compensate for
-	// increased next_target
-	let soft_min = one.clone() <<
-	               (((prev_cuckoo_sz - cmp::min(DEFAULT_SIZESHIFT, prev_cuckoo_sz)) *
-	                 8 + 16) as usize);
-	let prev_diff = BigInt::from_biguint(Sign::Plus, prev_diff.num);
-	let (pdiff, clen) = if prev_diff > soft_min && prev_cuckoo_sz < MAX_SIZESHIFT {
-		(prev_diff / two, prev_cuckoo_sz + 1)
+/// Number of block intervals the difficulty adjustment averages over, the
+/// most recent ones weighing the most.
+pub const DIFFICULTY_ADJUST_WINDOW: usize = 23;
+
+/// Damping of the difficulty adjustment: the average block interval over the
+/// window only counts for this fraction of the interval the difficulty is
+/// adjusted for, the rest being the block time. Keeps the difficulty from
+/// swinging with short lived hashrate changes.
+pub const DIFFICULTY_DAMP_FACTOR: u64 = 3;
+
+/// Longest any single block interval counts for in the difficulty
+/// adjustment, in block times, a single timestamp far ahead can only pull it
+/// down so much.
+pub const MAX_BLOCK_INTERVALS: u64 = 6;
+
+/// Difficulty of the block following the window of blocks provided as
+/// timestamp and difficulty pairs, starting with its parent and going back,
+/// see `next_difficulty_with`.
+pub fn next_difficulty(window: &[(i64, Difficulty)]) -> Difficulty {
+	next_difficulty_with(window, BLOCK_TIME_SEC as u64)
+}
+
+/// Difficulty adjustment over the last DIFFICULTY_ADJUST_WINDOW block
+/// intervals towards the provided block time, the intervals provided as the
+/// timestamp and difficulty of the blocks ending them, starting with the
+/// parent of the new block and going back. Any block
+/// past the window gets ignored, a shorter window is fine for the first blocks
+/// of a chain. Each interval weighs linearly more than the one before it, the
+/// weighted average interval is damped towards the block time and bounded
+/// between half and twice the block time. The difficulty of the blocks in the
+/// window, on average, gets adjusted by the block time proportion of that
+/// interval. The difficulty never goes lower than one.
+pub fn next_difficulty_with(window: &[(i64, Difficulty)], block_time: u64) -> Difficulty {
+	let window = &window[..cmp::min(window.len(), DIFFICULTY_ADJUST_WINDOW + 1)];
+	let diff = if window.len() < 2 {
+		// nothing to adjust for yet, the parent's difficulty stays
+		window.first().map(|&(_, ref diff)| diff.num.clone()).unwrap_or(Difficulty::one().num)
 	} else {
-		(prev_diff, prev_cuckoo_sz)
+		let intervals = window.len() - 1;
+		let mut weighted_sum = 0;
+		let mut weights = 0;
+		let mut diff_sum = BigUint::new(vec![0]);
+		for (i, pair) in window.windows(2).enumerate() {
+			// timestamps only have to be later than the parent's
+			let interval = cmp::max(1, pair[0].0 - pair[1].0) as u64;
+			let weight = (intervals - i) as u64;
+			weighted_sum += cmp::min(interval, MAX_BLOCK_INTERVALS * block_time) * weight;
+			weights += weight;
+			diff_sum = diff_sum + pair[0].1.num.clone();
+		}
+		let avg_interval = weighted_sum / weights;
+		let damped = (avg_interval + (DIFFICULTY_DAMP_FACTOR - 1) * block_time) /
+		             DIFFICULTY_DAMP_FACTOR;
+		let bounded = cmp::max(block_time / 2, cmp::min(damped, block_time * 2));
+		let num = diff_sum * BigUint::new(vec![block_time as u32]);
+		let denom = BigUint::new(vec![intervals as u32]) * BigUint::new(vec![bounded as u32]);
+		// rounding up when raising, so even the lowest difficulties do go up
+		if bounded < block_time {
+			(num + denom.clone() - BigUint::new(vec![1])) / denom
+		} else {
+			num / denom
+		}
 	};
 
-	// signed deviation from desired value divided by ten and bounded in [-6, 6]
-	let delta = cmp::max(cmp::min((ts - prev_ts - (BLOCK_TIME_SEC as i64)), 60), -60);
-	let delta_bigi = BigInt::new(if delta >= 0 { Sign::Plus } else { Sign::Minus },
-	                             vec![delta.abs() as u32]);
-	let new_diff = pdiff.clone() - ((pdiff >> 10) + one.clone()) * delta_bigi / ten;
-
-	// cannot be lower than one
-	if new_diff < one {
-		(Difficulty::one(), clen)
+	if diff < BigUint::new(vec![1]) {
+		Difficulty::one()
 	} else {
-		(Difficulty { num: new_diff.to_biguint().unwrap() }, clen)
+		Difficulty { num: diff }
 	}
 }
 
+/// Share of the blocks, in percent, the secondary proof of work family is
+/// targeted at in the first SIZESHIFT_EPOCH, leaving GPUs time to mine while
+/// custom hardware for the primary family gets built.
+pub const SECONDARY_POW_START_SHARE: u64 = 90;
+
+/// Percents the target share of the secondary proof of work family goes down
+/// by with each SIZESHIFT_EPOCH, until only primary proofs are targeted.
+pub const SECONDARY_POW_SHARE_DECREASE: u64 = 10;
+
+/// Scaling of the difficulty of secondary proofs the genesis starts with.
+pub const INITIAL_SECONDARY_SCALING: u32 = 1;
+
+/// Highest the scaling of the difficulty of secondary proofs can go.
+pub const MAX_SECONDARY_SCALING: u32 = 1 << 16;
+
+/// Share of the blocks, in percent, targeted at the secondary proof of work
+/// family at the provided height:
+///   Year 1 => 90
+///   Year 2 => 80
+///   ...
+///   Year 10 and later => 0
+pub fn secondary_pow_share(height: u64) -> u64 {
+	let decrease = (height / SIZESHIFT_EPOCH).saturating_mul(SECONDARY_POW_SHARE_DECREASE);
+	SECONDARY_POW_START_SHARE.saturating_sub(decrease)
+}
+
+/// Scaling of the difficulty of secondary proofs for the block at the
+/// provided height, following the window of blocks provided as proof of work
+/// family and scaling pairs, starting with its parent and going back. Any
+/// block past the last DIFFICULTY_ADJUST_WINDOW gets ignored. The average
+/// scaling over the window gets adjusted by how far the share of secondary
+/// blocks in it is from the target share, damped like the difficulty,
+/// bounded between half and twice that average and kept between one and
+/// MAX_SECONDARY_SCALING. Too few secondary blocks raise the scaling, making
+/// each secondary proof count for more, too many lower it.
+pub fn next_secondary_scaling(height: u64, window: &[(PowFamily, u32)]) -> u32 {
+	let window = &window[..cmp::min(window.len(), DIFFICULTY_ADJUST_WINDOW)];
+	if window.is_empty() {
+		return INITIAL_SECONDARY_SCALING;
+	}
+	let len = window.len() as u64;
+	let secondary = window.iter().filter(|&&(family, _)| family == PowFamily::Secondary).count();
+	let avg_scaling = window.iter().map(|&(_, scaling)| scaling as u64).sum::<u64>() / len;
+
+	let target = secondary_pow_share(height);
+	let actual = secondary as u64 * 100 / len;
+	let damped = (actual + (DIFFICULTY_DAMP_FACTOR - 1) * target) / DIFFICULTY_DAMP_FACTOR;
+	let scaling = if damped == 0 {
+		// no secondary block, none wanted
+		0
+	} else if damped < target {
+		// rounding up when raising, so even the lowest scalings do go up
+		(avg_scaling * target + damped - 1) / damped
+	} else {
+		avg_scaling * target / damped
+	};
+	let bounded = cmp::max(avg_scaling / 2, cmp::min(scaling, avg_scaling * 2));
+	cmp::max(1, cmp::min(bounded, MAX_SECONDARY_SCALING as u64)) as u32
+}
+
 /// Default number of blocks in the past when cross-block cut-through will start
 /// happening. Needs to be long enough to not overlap with a long reorg.
 /// Rational
@@ -101,6 +280,103 @@ pub fn next_target(ts: i64,
 /// easier to reason about.
 pub const CUT_THROUGH_HORIZON: u32 = 48 * 3600 / (BLOCK_TIME_SEC as u32);
 
+/// Number of blocks a coinbase output has to wait before it can be spent.
+/// Spending the reward of a block that could still be undone by a reorg would
+/// also invalidate every transaction built on top of it. A day of blocks.
+pub const COINBASE_MATURITY: u64 = 24 * 3600 / (BLOCK_TIME_SEC as u64);
+
+/// Weight of an input when counted against the max block weight
+pub const BLOCK_INPUT_WEIGHT: usize = 1;
+
+/// Weight of an output when counted against the max block weight, outputs
+/// carry a range proof
+pub const BLOCK_OUTPUT_WEIGHT: usize = 10;
+
+/// Weight of a transaction proof when counted against the max block weight
+pub const BLOCK_PROOF_WEIGHT: usize = 2;
+
+/// Total maximum block weight, bounds the resources needed to validate and
+/// store a block
+pub const MAX_BLOCK_WEIGHT: usize = 80_000;
+
+/// Weight of a block made of the provided number of inputs, outputs and
+/// transaction proofs.
+pub fn block_weight(input_len: usize, output_len: usize, proof_len: usize) -> usize {
+	input_len.saturating_mul(BLOCK_INPUT_WEIGHT)
+		.saturating_add(output_len.saturating_mul(BLOCK_OUTPUT_WEIGHT))
+		.saturating_add(proof_len.saturating_mul(BLOCK_PROOF_WEIGHT))
+}
+
+/// Whether a block made of the provided number of inputs, outputs and
+/// transaction proofs is heavier than the max block weight.
+pub fn exceeds_weight(input_len: usize, output_len: usize, proof_len: usize) -> bool {
+	block_weight(input_len, output_len, proof_len) > MAX_BLOCK_WEIGHT
+}
+
+/// Maximum number of inputs a block can spend. Each input gets looked up
+/// among the unspent outputs, bounded well below what the weight alone
+/// would allow.
+pub const MAX_BLOCK_INPUTS: usize = 20_000;
+
+/// Maximum number of outputs a block can create, each with a range proof to
+/// verify.
+pub const MAX_BLOCK_OUTPUTS: usize = 5_000;
+
+/// Maximum number of transaction proofs a block can have, each with a
+/// signature to verify.
+pub const MAX_BLOCK_PROOFS: usize = 5_000;
+
+/// Whether a block made of the provided number of inputs, outputs and
+/// transaction proofs is heavier than the max block weight or has more of any
+/// of them than allowed in a block.
+pub fn exceeds_block_limits(input_len: usize, output_len: usize, proof_len: usize) -> bool {
+	exceeds_weight(input_len, output_len, proof_len) || input_len > MAX_BLOCK_INPUTS ||
+	output_len > MAX_BLOCK_OUTPUTS || proof_len > MAX_BLOCK_PROOFS
+}
+
+/// Maximum number of inputs a single transaction can spend.
+pub const MAX_TX_INPUTS: usize = 1_000;
+
+/// Maximum number of outputs a single transaction can create.
+pub const MAX_TX_OUTPUTS: usize = 500;
+
+/// Whether a transaction with the provided number of inputs and outputs has
+/// more of either than allowed in a single transaction.
+pub fn exceeds_tx_limits(input_len: usize, output_len: usize) -> bool {
+	input_len > MAX_TX_INPUTS || output_len > MAX_TX_OUTPUTS
+}
+
+/// Minimum fee any transaction has to pay, however light it is.
+pub const MIN_FEE: u64 = 1;
+
+/// Fee transactions have to pay for every thousand units of their weight,
+/// when that's more than MIN_FEE.
+pub const FEE_PER_KILO_WEIGHT: u64 = 50;
+
+/// Weight of a transaction with the provided number of inputs and outputs,
+/// including the proof it gets in a block.
+pub fn tx_weight(input_len: usize, output_len: usize) -> usize {
+	block_weight(input_len, output_len, 1)
+}
+
+/// Fee due for the provided weight, at FEE_PER_KILO_WEIGHT rounded up.
+pub fn weight_fee(weight: usize) -> u64 {
+	(weight as u64).saturating_mul(FEE_PER_KILO_WEIGHT).saturating_add(999) / 1000
+}
+
+/// Fee paid for every thousand units of the provided weight, in the same
+/// unit as FEE_PER_KILO_WEIGHT. Transactions get ranked by it, to pick the
+/// ones making it into a block or staying in a full pool.
+pub fn fee_rate(fee: u64, weight: usize) -> u64 {
+	fee.saturating_mul(1000) / cmp::max(weight, 1) as u64
+}
+
+/// Minimum fee of a transaction with the provided number of inputs and
+/// outputs, what both a transaction pool and block validation hold it to.
+pub fn min_fee(input_len: usize, output_len: usize) -> u64 {
+	cmp::max(MIN_FEE, weight_fee(tx_weight(input_len, output_len)))
+}
+
 /// The maximum size we're willing to accept for any message. Enforced by the
 /// peer-to-peer networking layer only for DoS protection.
 pub const MAX_MSG_LEN: u64 = 20_000_000;
@@ -111,42 +387,234 @@ mod test {
 
 	use super::*;
 
+	// a full window of blocks all the provided seconds apart, all at the
+	// provided difficulty
+	fn steady_window(secs: i64, diff: u32) -> Vec<(i64, Difficulty)> {
+		(0..DIFFICULTY_ADJUST_WINDOW as i64 + 1)
+			.map(|n| (1000000 - n * secs, Difficulty::from_num(diff)))
+			.collect()
+	}
+
+	#[test]
+	fn reward_schedule() {
+		assert_eq!(block_subsidy(0), REWARD);
+		assert_eq!(block_subsidy(REWARD_HALVING_INTERVAL - 1), REWARD);
+		assert_eq!(block_subsidy(REWARD_HALVING_INTERVAL), REWARD / 2);
+		assert_eq!(block_subsidy(3 * REWARD_HALVING_INTERVAL), REWARD / 8);
+		assert_eq!(block_subsidy(u64::max_value()), 0);
+
+		// fees all go to the miner on top of the subsidy
+		assert_eq!(reward(1, 0), Some(REWARD));
+		assert_eq!(reward(1, 12), Some(REWARD + 12));
+		assert_eq!(reward(REWARD_HALVING_INTERVAL, 12), Some(REWARD / 2 + 12));
+		assert_eq!(reward(0, MAX_AMOUNT - REWARD), Some(MAX_AMOUNT));
+		assert_eq!(reward(0, MAX_AMOUNT - REWARD + 1), None);
+		assert_eq!(reward(0, u64::max_value()), None);
+	}
+
+	#[test]
+	fn header_versions() {
+		assert_eq!(header_version(0), HEADER_VERSION);
+		assert_eq!(header_version(u64::max_value()),
+		           HEADER_VERSION + HARD_FORK_HEIGHTS.len() as u16);
+
+		// each fork bumps the version from its height on
+		let forks = [100, 250];
+		assert_eq!(header_version_at(0, &forks), HEADER_VERSION);
+		assert_eq!(header_version_at(99, &forks), HEADER_VERSION);
+		assert_eq!(header_version_at(100, &forks), HEADER_VERSION + 1);
+		assert_eq!(header_version_at(249, &forks), HEADER_VERSION + 1);
+		assert_eq!(header_version_at(250, &forks), HEADER_VERSION + 2);
+		assert_eq!(header_version_at(u64::max_value(), &forks), HEADER_VERSION + 2);
+	}
+
+	#[test]
+	fn min_fees() {
+		assert_eq!(weight_fee(0), 0);
+		assert_eq!(weight_fee(1), 1);
+		assert_eq!(weight_fee(20), 1);
+		assert_eq!(weight_fee(21), 2);
+		assert_eq!(weight_fee(MAX_BLOCK_WEIGHT), 4000);
+		assert_eq!(weight_fee(usize::max_value()), u64::max_value() / 1000);
+
+		// light transactions still pay the minimum, heavy ones for their weight
+		assert_eq!(min_fee(0, 0), MIN_FEE);
+		assert_eq!(min_fee(1, 1), MIN_FEE);
+		assert_eq!(min_fee(3, 2), 2);
+		assert_eq!(min_fee(10, 100), 51);
+
+		// paying the min fee is about paying FEE_PER_KILO_WEIGHT
+		assert_eq!(fee_rate(min_fee(10, 100), tx_weight(10, 100)), 50);
+		assert_eq!(fee_rate(1, 1000), 1);
+		assert_eq!(fee_rate(1, 1001), 0);
+		assert_eq!(fee_rate(5, 0), 5000);
+		assert_eq!(fee_rate(u64::max_value(), 2), u64::max_value() / 2);
+	}
+
+	#[test]
+	/// Checks different difficulty adjustments and difficulty boundaries
+	fn next_difficulty_adjustment() {
+		// on time, no adjustment
+		assert_eq!(next_difficulty(&steady_window(60, 1000)), Difficulty::from_num(1000));
+
+		// raise the difficulty if blocks come too fast, lower it if too slow,
+		// damped either way
+		assert_eq!(next_difficulty(&steady_window(30, 1000)), Difficulty::from_num(1200));
+		assert_eq!(next_difficulty(&steady_window(120, 1000)), Difficulty::from_num(750));
+
+		// timestamps far apart only count for so much, within bounds
+		assert_eq!(next_difficulty(&steady_window(100000, 1000)), Difficulty::from_num(500));
+		assert_eq!(next_difficulty(&steady_window(1, 1000)), Difficulty::from_num(1500));
+
+		// the most recent intervals weigh the most
+		let mut recent_gap = steady_window(60, 1000);
+		for n in 1..recent_gap.len() {
+			recent_gap[n].0 -= 300;
+		}
+		let mut old_gap = steady_window(60, 1000);
+		let last = old_gap.len() - 1;
+		old_gap[last].0 -= 300;
+		assert_eq!(next_difficulty(&recent_gap), Difficulty::from_num(882));
+		assert_eq!(next_difficulty(&old_gap), Difficulty::from_num(1000));
+
+		// blocks past the window are ignored
+		let mut longer = steady_window(60, 1000);
+		longer.push((0, Difficulty::from_num(1)));
+		assert_eq!(next_difficulty(&longer), Difficulty::from_num(1000));
+
+		// the parent's difficulty until there are intervals, never lower than one
+		assert_eq!(next_difficulty(&[(0, Difficulty::from_num(10))]), Difficulty::from_num(10));
+		assert_eq!(next_difficulty(&steady_window(360, 1)), Difficulty::one());
+		assert_eq!(next_difficulty(&steady_window(30, 1)), Difficulty::from_num(2));
+	}
+
 	#[test]
-	/// Checks different next_target adjustments and difficulty boundaries
-	fn next_target_adjustment() {
-		// can't do lower than min
-		assert_eq!(next_target(60, 0, Difficulty::one(), 26),
-		           (Difficulty::one(), 26));
-		assert_eq!(next_target(90, 30, Difficulty::one(), 26),
-		           (Difficulty::one(), 26));
-		assert_eq!(next_target(60, 0, Difficulty::one(), 26),
-		           (Difficulty::one(), 26));
-
-		// lower next_target if gap too short
-		assert_eq!(next_target(30, 0, Difficulty::one(), 26).0,
-		           Difficulty::from_num(4));
-		assert_eq!(next_target(50, 0, Difficulty::one(), 26).0,
-		           Difficulty::from_num(2));
-		assert_eq!(next_target(40, 0, Difficulty::from_num(1024 * 8), 26).0,
-		           Difficulty::from_num(1024 * 8 + 18));
-
-		// lower difficulty if gap too wide
-		assert_eq!(next_target(70, 0, Difficulty::from_num(10), 26).0,
-		           Difficulty::from_num(9));
-		assert_eq!(next_target(90, 0, Difficulty::from_num(1024 * 8), 26).0,
-		           Difficulty::from_num(1024 * 8 - 9 * 3));
-
-		// identical, no adjustment
-		assert_eq!(next_target(60, 0, Difficulty::from_num(1024 * 8), 26).0,
-		           Difficulty::from_num(1024 * 8));
-
-		// increase cuckoo size if next_target goes above soft max, target is doubled,
-		// up to 29
-		assert_eq!(next_target(60, 0, Difficulty::from_num(1 << 16), 25),
-		           (Difficulty::from_num(1 << 16), 25));
-		assert_eq!(next_target(60, 0, Difficulty::from_num((1 << 16) + 1), 25),
-		           (Difficulty::from_num(1 << 15), 26));
-		assert_eq!(next_target(60, 0, Difficulty::from_num((1 << 24) + 1), 26),
-		           (Difficulty::from_num(1 << 23), 27));
+	/// Checks the Cuckoo size graduation schedule, epoch by epoch
+	fn sizeshift_graduation() {
+		assert_eq!(min_sizeshift(0), DEFAULT_SIZESHIFT);
+		assert_eq!(min_sizeshift(SIZESHIFT_EPOCH - 1), DEFAULT_SIZESHIFT);
+		assert_eq!(min_sizeshift(SIZESHIFT_EPOCH), DEFAULT_SIZESHIFT + 1);
+		assert_eq!(min_sizeshift(2 * SIZESHIFT_EPOCH - 1), DEFAULT_SIZESHIFT + 1);
+		assert_eq!(min_sizeshift(2 * SIZESHIFT_EPOCH), DEFAULT_SIZESHIFT + 2);
+		assert_eq!(min_sizeshift(4 * SIZESHIFT_EPOCH - 1), MAX_SIZESHIFT - 1);
+		assert_eq!(min_sizeshift(4 * SIZESHIFT_EPOCH), MAX_SIZESHIFT);
+		assert_eq!(min_sizeshift(u64::max_value()), MAX_SIZESHIFT);
+
+		// a network starting closer to its maximum gets there sooner, one at its
+		// maximum stays there
+		assert_eq!(graduated_sizeshift(SIZESHIFT_EPOCH, 28, 29), 29);
+		assert_eq!(graduated_sizeshift(10 * SIZESHIFT_EPOCH, 28, 29), 29);
+		assert_eq!(graduated_sizeshift(10 * SIZESHIFT_EPOCH, 12, 12), 12);
+		assert_eq!(graduated_sizeshift(u64::max_value(), 13, 12), 13);
+	}
+
+	#[test]
+	/// Checks the adjustment is towards the provided block time
+	fn next_difficulty_with_block_time() {
+		assert_eq!(next_difficulty_with(&steady_window(10, 1000), 10),
+		           Difficulty::from_num(1000));
+		assert_eq!(next_difficulty_with(&steady_window(60, 1000), 10),
+		           Difficulty::from_num(500));
+		assert_eq!(next_difficulty_with(&steady_window(5, 1000), 10),
+		           Difficulty::from_num(1250));
+	}
+
+	#[test]
+	/// Checks the total supply over the whole emission schedule
+	fn supply_invariants() {
+		assert_eq!(total_supply(0), REWARD);
+		assert_eq!(total_supply(REWARD_HALVING_INTERVAL - 1), REWARD * REWARD_HALVING_INTERVAL);
+		assert_eq!(total_supply(REWARD_HALVING_INTERVAL),
+		           REWARD * REWARD_HALVING_INTERVAL + REWARD / 2);
+
+		// each block adds its subsidy, across all halvings
+		for n in 1..70 {
+			let height = n * REWARD_HALVING_INTERVAL;
+			assert_eq!(total_supply(height), total_supply(height - 1) + block_subsidy(height));
+		}
+
+		// the supply stops growing once the subsidy is gone, below twice the
+		// first epoch and way below the largest amount
+		let max_supply = total_supply(u64::max_value());
+		assert_eq!(max_supply, total_supply(64 * REWARD_HALVING_INTERVAL));
+		assert!(max_supply < 2 * REWARD * REWARD_HALVING_INTERVAL);
+		assert_eq!(sum_amounts(vec![max_supply, max_supply]), Some(2 * max_supply));
+
+		// sums going over the largest amount are refused, whether they'd
+		// overflow or not
+		assert_eq!(sum_amounts(vec![]), Some(0));
+		assert_eq!(sum_amounts(vec![MAX_AMOUNT, 0]), Some(MAX_AMOUNT));
+		assert_eq!(sum_amounts(vec![MAX_AMOUNT, 1]), None);
+		assert_eq!(sum_amounts(vec![u64::max_value(), 1]), None);
+	}
+
+	#[test]
+	/// Checks the target share of secondary blocks goes down with the years
+	fn secondary_share() {
+		assert_eq!(secondary_pow_share(0), 90);
+		assert_eq!(secondary_pow_share(SIZESHIFT_EPOCH - 1), 90);
+		assert_eq!(secondary_pow_share(SIZESHIFT_EPOCH), 80);
+		assert_eq!(secondary_pow_share(8 * SIZESHIFT_EPOCH), 10);
+		assert_eq!(secondary_pow_share(9 * SIZESHIFT_EPOCH), 0);
+		assert_eq!(secondary_pow_share(u64::max_value()), 0);
+	}
+
+	#[test]
+	/// Checks the secondary scaling follows the share of secondary blocks
+	fn secondary_scaling() {
+		// a window of blocks at the provided scaling, the provided number of the
+		// first ones secondary
+		let window = |secondary: usize, scaling: u32| {
+			(0..DIFFICULTY_ADJUST_WINDOW)
+				.map(|n| if n < secondary {
+					(PowFamily::Secondary, scaling)
+				} else {
+					(PowFamily::Primary, scaling)
+				})
+				.collect::<Vec<_>>()
+		};
+		let year10 = 9 * SIZESHIFT_EPOCH;
+
+		assert_eq!(next_secondary_scaling(0, &[]), INITIAL_SECONDARY_SCALING);
+		// no secondary block raises the scaling, even the lowest one
+		assert_eq!(next_secondary_scaling(0, &window(0, 100)), 150);
+		assert_eq!(next_secondary_scaling(0, &window(0, 1)), 2);
+		// the share being on target keeps the scaling
+		let on_target = DIFFICULTY_ADJUST_WINDOW * 9 / 10 + 1;
+		assert_eq!(next_secondary_scaling(0, &window(on_target, 100)), 100);
+		// only secondary blocks lower the scaling
+		assert_eq!(next_secondary_scaling(0, &window(DIFFICULTY_ADJUST_WINDOW, 100)), 96);
+		// when no share is targeted anymore the scaling goes down to one, halving
+		assert_eq!(next_secondary_scaling(year10, &window(0, 100)), 50);
+		assert_eq!(next_secondary_scaling(year10, &window(5, 100)), 50);
+		assert_eq!(next_secondary_scaling(year10, &window(0, 1)), 1);
+		// never past the maximum
+		assert_eq!(next_secondary_scaling(0, &window(0, MAX_SECONDARY_SCALING)),
+		           MAX_SECONDARY_SCALING);
+	}
+
+	#[test]
+	/// Checks the block weight limit, including absurd sizes
+	fn block_weight_limit() {
+		assert_eq!(block_weight(1, 2, 3), 27);
+		assert!(!exceeds_weight(0, 0, 0));
+		assert!(!exceeds_weight(0, MAX_BLOCK_WEIGHT / BLOCK_OUTPUT_WEIGHT, 0));
+		assert!(exceeds_weight(0, MAX_BLOCK_WEIGHT / BLOCK_OUTPUT_WEIGHT + 1, 0));
+		assert!(exceeds_weight(usize::max_value(), usize::max_value(), 1));
+	}
+
+	#[test]
+	/// Checks the limits on the number of inputs, outputs and proofs
+	fn composition_limits() {
+		assert!(!exceeds_block_limits(MAX_BLOCK_INPUTS, 0, 0));
+		assert!(exceeds_block_limits(MAX_BLOCK_INPUTS + 1, 0, 0));
+		assert!(!exceeds_block_limits(0, MAX_BLOCK_OUTPUTS, 0));
+		assert!(exceeds_block_limits(0, MAX_BLOCK_OUTPUTS + 1, 0));
+		assert!(!exceeds_block_limits(0, 0, MAX_BLOCK_PROOFS));
+		assert!(exceeds_block_limits(0, 0, MAX_BLOCK_PROOFS + 1));
+
+		assert!(!exceeds_tx_limits(MAX_TX_INPUTS, MAX_TX_OUTPUTS));
+		assert!(exceeds_tx_limits(MAX_TX_INPUTS + 1, 1));
+		assert!(exceeds_tx_limits(1, MAX_TX_OUTPUTS + 1));
 	}
 }
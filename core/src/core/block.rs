@@ -18,18 +18,23 @@ use time;
 use secp;
 use secp::{Secp256k1, Signature, Message};
 use secp::key::SecretKey;
-use std::collections::HashSet;
+use rayon::prelude::*;
 
 use core::Committed;
-use core::{Input, Output, Proof, TxProof, Transaction};
-use core::transaction::merkle_inputs_outputs;
-use consensus::{REWARD, DEFAULT_SIZESHIFT};
+use core::{Input, Output, OutputFeatures, PowFamily, Proof, TxProof, Transaction};
+use core::transaction::{cut_through, is_bullet_proof, merkle_inputs_outputs, sorted_by_hash};
+use consensus;
+use consensus::DEFAULT_SIZESHIFT;
 use core::hash::{Hash, Hashed, ZERO_HASH};
 use core::target::Difficulty;
 use ser::{self, Readable, Reader, Writeable, Writer};
 
 /// Block header, fairly standard compared to other blockchains.
+#[derive(Clone)]
 pub struct BlockHeader {
+	/// Version of the block, telling which consensus rules it follows, see
+	/// consensus::header_version
+	pub version: u16,
 	/// Height of this block since the genesis block (height 0)
 	pub height: u64,
 	/// Hash of the block previous to this in the chain.
@@ -38,21 +43,30 @@ pub struct BlockHeader {
 	pub timestamp: time::Tm,
 	/// Length of the cuckoo cycle used to mine this block.
 	pub cuckoo_len: u8,
+	/// Root of the Merkle Mountain Range of all the outputs on the chain up
+	/// to this block, the spent ones pruned.
 	pub utxo_merkle: Hash,
 	pub tx_merkle: Hash,
+	/// Family of the proof of work of this block.
+	pub pow_family: PowFamily,
+	/// Factor the difficulty of a secondary proof of work gets multiplied by,
+	/// set by consensus::next_secondary_scaling whatever the block family.
+	pub secondary_scaling: u32,
 	/// Nonce increment used to mine this block.
 	pub nonce: u64,
 	/// Proof of work data.
 	pub pow: Proof,
 	/// Difficulty used to mine the block.
 	pub difficulty: Difficulty,
-	/// Total accumulated difficulty since genesis block
+	/// Total accumulated difficulty since genesis block, the sum of the
+	/// difficulties of all the blocks up to this one
 	pub total_difficulty: Difficulty,
 }
 
 impl Default for BlockHeader {
 	fn default() -> BlockHeader {
 		BlockHeader {
+			version: consensus::HEADER_VERSION,
 			height: 0,
 			previous: ZERO_HASH,
 			timestamp: time::at_utc(time::Timespec { sec: 0, nsec: 0 }),
@@ -61,69 +75,136 @@ impl Default for BlockHeader {
 			total_difficulty: Difficulty::one(),
 			utxo_merkle: ZERO_HASH,
 			tx_merkle: ZERO_HASH,
+			pow_family: PowFamily::Primary,
+			secondary_scaling: consensus::INITIAL_SECONDARY_SCALING,
 			nonce: 0,
 			pow: Proof::zero(),
 		}
 	}
 }
 
-/// Serialization of a block header
+/// Serialization of a block header. Stored and sent with a leading
+/// serialization version and the length of the fields, so fields added at the
+/// end by newer versions can be skipped by older readers, and with the proof
+/// of work nonces packed. Only the fields go into the header hash, with the
+/// nonces in full.
 impl Writeable for BlockHeader {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		if writer.serialization_mode() != ser::SerializationMode::Full {
+			return HeaderFields(self, false).write(writer);
+		}
+		let fields = try!(ser::ser_vec(&HeaderFields(self, true)));
 		ser_multiwrite!(writer,
-		                [write_u64, self.height],
-		                [write_fixed_bytes, &self.previous],
-		                [write_i64, self.timestamp.to_timespec().sec],
-		                [write_u8, self.cuckoo_len]);
+		                [write_u8, ser::SER_VERSION],
+		                [write_u16, fields.len() as u16]);
+		writer.write_fixed_bytes(&fields)
+	}
+}
+
+/// Deserialization of a block header, of any serialization version.
+impl Readable<BlockHeader> for BlockHeader {
+	fn read(reader: &mut Reader) -> Result<BlockHeader, ser::Error> {
+		let ser_version = try!(reader.read_u8());
+		if ser_version == 0 {
+			// headers from before serialization versions start right away with
+			// their version, which never had its high byte set
+			let version = try!(reader.read_u8()) as u16;
+			return read_header_fields(reader, version, false);
+		}
+		let len = try!(reader.read_u16()) as usize;
+		let version = try!(reader.read_u16());
+		let packed = ser_version >= ser::PACKED_POW_VERSION;
+		let header = try!(read_header_fields(reader, version, packed));
+		// skips whatever newer versions added after the fields we know of
+		let known = try!(ser::ser_vec(&HeaderFields(&header, packed))).len();
+		if known > len {
+			return Err(ser::Error::CorruptedData);
+		}
+		try!(reader.read_fixed_bytes(len - known));
+		Ok(header)
+	}
+}
+
+// the fields of a block header, without serialization version, with packed
+// proof of work nonces or not
+struct HeaderFields<'a>(&'a BlockHeader, bool);
+
+impl<'a> Writeable for HeaderFields<'a> {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		let h = self.0;
+		ser_multiwrite!(writer,
+		                [write_u16, h.version],
+		                [write_u64, h.height],
+		                [write_fixed_bytes, &h.previous],
+		                [write_i64, h.timestamp.to_timespec().sec],
+		                [write_u8, h.cuckoo_len]);
 		ser_multiwrite!(writer,
-		                [write_fixed_bytes, &self.utxo_merkle],
-		                [write_fixed_bytes, &self.tx_merkle]);
+		                [write_fixed_bytes, &h.utxo_merkle],
+		                [write_fixed_bytes, &h.tx_merkle],
+		                [write_u8, h.pow_family.to_u8()],
+		                [write_u32, h.secondary_scaling]);
 		// make sure to not introduce any variable length data before the nonce to
 		// avoid complicating PoW
-		try!(writer.write_u64(self.nonce));
+		try!(writer.write_u64(h.nonce));
 		// proof
-		try!(self.pow.write(writer));
+		if self.1 {
+			try!(h.pow.write_packed(writer));
+		} else {
+			try!(h.pow.write(writer));
+		}
 		// block and total difficulty
-		try!(self.difficulty.write(writer));
-		self.total_difficulty.write(writer)
+		try!(h.difficulty.write(writer));
+		h.total_difficulty.write(writer)
 	}
 }
 
-/// Deserialization of a block header
-impl Readable<BlockHeader> for BlockHeader {
-	fn read(reader: &mut Reader) -> Result<BlockHeader, ser::Error> {
-		let height = try!(reader.read_u64());
-		let previous = try!(Hash::read(reader));
-		let (timestamp, cuckoo_len) = ser_multiread!(reader, read_i64, read_u8);
-		let utxo_merkle = try!(Hash::read(reader));
-		let tx_merkle = try!(Hash::read(reader));
-		let nonce = try!(reader.read_u64());
-		let pow = try!(Proof::read(reader));
-		let difficulty = try!(Difficulty::read(reader));
-		let total_difficulty = try!(Difficulty::read(reader));
-
-		Ok(BlockHeader {
-			height: height,
-			previous: previous,
-			timestamp: time::at_utc(time::Timespec {
-				sec: timestamp,
-				nsec: 0,
-			}),
-			cuckoo_len: cuckoo_len,
-			utxo_merkle: utxo_merkle,
-			tx_merkle: tx_merkle,
-			pow: pow,
-			nonce: nonce,
-			difficulty: difficulty,
-			total_difficulty: total_difficulty,
-		})
-	}
+// reads the fields of a block header following its version, with packed
+// proof of work nonces or not
+fn read_header_fields(reader: &mut Reader,
+                      version: u16,
+                      packed: bool)
+                      -> Result<BlockHeader, ser::Error> {
+	let height = try!(reader.read_u64());
+	let previous = try!(Hash::read(reader));
+	let (timestamp, cuckoo_len) = ser_multiread!(reader, read_i64, read_u8);
+	let utxo_merkle = try!(Hash::read(reader));
+	let tx_merkle = try!(Hash::read(reader));
+	let (family, secondary_scaling) = ser_multiread!(reader, read_u8, read_u32);
+	let pow_family = try!(PowFamily::from_u8(family).ok_or(ser::Error::CorruptedData));
+	let nonce = try!(reader.read_u64());
+	let pow = if packed {
+		try!(Proof::read_packed(reader))
+	} else {
+		try!(Proof::read(reader))
+	};
+	let difficulty = try!(Difficulty::read(reader));
+	let total_difficulty = try!(Difficulty::read(reader));
+
+	Ok(BlockHeader {
+		version: version,
+		height: height,
+		previous: previous,
+		timestamp: time::at_utc(time::Timespec {
+			sec: timestamp,
+			nsec: 0,
+		}),
+		cuckoo_len: cuckoo_len,
+		utxo_merkle: utxo_merkle,
+		tx_merkle: tx_merkle,
+		pow_family: pow_family,
+		secondary_scaling: secondary_scaling,
+		pow: pow,
+		nonce: nonce,
+		difficulty: difficulty,
+		total_difficulty: total_difficulty,
+	})
 }
 
-/// A block as expressed in the MimbleWimble protocol. The reward is
-/// non-explicit, assumed to be deducible from block height (similar to
-/// bitcoin's schedule) and expressed as a global transaction fee (added v.H),
-/// additive to the total of fees ever collected.
+/// A block as expressed in the MimbleWimble protocol. The subsidy is
+/// non-explicit, deducible from block height (see consensus::block_subsidy)
+/// and expressed as a global overage (added v.H). The coinbase output gets it
+/// along with all the fees of the block transactions.
+#[derive(Clone)]
 pub struct Block {
 	// hash_mem: Hash,
 	pub header: BlockHeader,
@@ -163,6 +244,12 @@ impl Readable<Block> for Block {
 
 		let (input_len, output_len, proof_len) =
 			ser_multiread!(reader, read_u64, read_u64, read_u64);
+		// don't even bother reading blocks too heavy or too large to ever be valid
+		if consensus::exceeds_block_limits(input_len as usize,
+		                                   output_len as usize,
+		                                   proof_len as usize) {
+			return Err(ser::Error::TooLargeReadErr);
+		}
 
 		let inputs = try!((0..input_len).map(|_| Input::read(reader)).collect());
 		let outputs = try!((0..output_len).map(|_| Output::read(reader)).collect());
@@ -188,7 +275,7 @@ impl Committed for Block {
 		&self.outputs
 	}
 	fn overage(&self) -> i64 {
-		(REWARD as i64) - (self.total_fees() as i64)
+		consensus::block_subsidy(self.header.height) as i64
 	}
 }
 
@@ -206,21 +293,44 @@ impl Default for Block {
 
 impl Block {
 	/// Builds a new block from the header of the previous block, a vector of
-	/// transactions and the private key that will receive the reward. Checks
-	/// that all transactions are valid and calculates the Merkle tree.
-	pub fn new(prev: &BlockHeader,
-	           txs: Vec<&mut Transaction>,
-	           reward_key: SecretKey)
-	           -> Result<Block, secp::Error> {
+	/// transactions and the provider of the output that will receive the
+	/// reward, a private key or a wallet. Checks that all transactions are
+	/// valid and calculates the Merkle tree.
+	pub fn new<R: RewardProvider>(prev: &BlockHeader,
+	                              txs: Vec<&mut Transaction>,
+	                              reward_provider: R)
+	                              -> Result<Block, secp::Error> {
+
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+		// the reward output collects the subsidy and all the fees, which can't
+		// add up if they're worth more than any amount can be
+		let fees = consensus::sum_amounts(txs.iter().map(|tx| tx.fee()));
+		let reward = try!(fees.and_then(|fees| consensus::reward(prev.height + 1, fees))
+			.ok_or(secp::Error::IncorrectCommitSum));
+		let (reward_out, reward_proof) =
+			try!(reward_provider.reward_output(prev.height + 1, reward, &secp));
+		Block::with_reward(prev, txs, reward_out, reward_proof)
+	}
+
+	/// Builds a new block from the header of the previous block, a vector of
+	/// transactions and an already built reward output with the proof of its
+	/// remainder. Checks that all transactions are valid and calculates the
+	/// Merkle tree, the reward only gets checked to pay the subsidy and fees
+	/// when the block is.
+	pub fn with_reward(prev: &BlockHeader,
+	                   txs: Vec<&mut Transaction>,
+	                   reward_out: Output,
+	                   reward_proof: TxProof)
+	                   -> Result<Block, secp::Error> {
 
 		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
-		let (reward_out, reward_proof) = try!(Block::reward_output(reward_key, &secp));
 
 		// note: the following reads easily but may not be the most efficient due to
 		// repeated iterations, revisit if a problem
 
 		// validate each transaction and gather their proofs
-		let mut proofs = try_map_vec!(txs, |tx| tx.verify_sig(&secp));
+		let mut proofs = try_map_vec!(txs, |tx| tx.verify_kernels(&secp)).concat();
 		proofs.push(reward_proof);
 
 		// build vectors with all inputs and all outputs, ordering them by hash
@@ -242,17 +352,21 @@ impl Block {
 
 		inputs.sort_by_key(|inp| inp.hash());
 		outputs.sort_by_key(|out| out.hash());
+		proofs.sort_by_key(|proof| proof.hash());
 
 		// calculate the overall Merkle tree and fees
 
 		Ok(Block {
 				header: BlockHeader {
+					version: consensus::header_version(prev.height + 1),
 					height: prev.height + 1,
 					timestamp: time::now(),
 					previous: prev.hash(),
-					total_difficulty: Difficulty::from_hash(&prev.hash()) +
-					                  prev.total_difficulty.clone(),
+					// at the default difficulty of one until the block is given its
+					// actual target, which the total has to follow
+					total_difficulty: prev.total_difficulty.clone() + Difficulty::one(),
 					cuckoo_len: prev.cuckoo_len,
+					secondary_scaling: prev.secondary_scaling,
 					..Default::default()
 				},
 				inputs: inputs,
@@ -266,36 +380,97 @@ impl Block {
 		self.header.hash()
 	}
 
-	pub fn total_fees(&self) -> u64 {
-		self.proofs.iter().map(|p| p.fee).sum()
+	/// Sum of the fees of all the block transactions, none if they're worth
+	/// more than consensus::MAX_AMOUNT together.
+	pub fn total_fees(&self) -> Option<u64> {
+		consensus::sum_amounts(self.proofs.iter().map(|p| p.fee))
 	}
 
-	/// Matches any output with a potential spending input, eliminating them
-	/// from the block. Provides a simple way to compact the block. The
-	/// elimination is stable with respect to inputs and outputs order.
-	pub fn compact(&self) -> Block {
-		// the chosen ones
-		let mut new_inputs = vec![];
+	/// Weight of the block, counted against consensus::MAX_BLOCK_WEIGHT.
+	pub fn weight(&self) -> usize {
+		consensus::block_weight(self.inputs.len(), self.outputs.len(), self.proofs.len())
+	}
 
-		// build a set of all output hashes
-		let mut out_set = HashSet::new();
-		for out in &self.outputs {
-			out_set.insert(out.hash());
-		}
-		// removes from the set any hash referenced by an input, keeps the inputs that
-		// don't have a match
-		for inp in &self.inputs {
-			if !out_set.remove(&inp.output_hash()) {
-				new_inputs.push(*inp);
+	/// Weight of the block transactions, the block without the output and
+	/// proof of its reward. What the fees pay for.
+	fn txs_weight(&self) -> usize {
+		consensus::block_weight(self.inputs.len(),
+		                        self.outputs.len().saturating_sub(1),
+		                        self.proofs.len().saturating_sub(1))
+	}
+
+	/// Fee the block transactions pay per thousand units of their weight, see
+	/// consensus::fee_rate.
+	pub fn fee_rate(&self) -> u64 {
+		consensus::fee_rate(self.total_fees().unwrap_or(0), self.txs_weight())
+	}
+
+	/// Whether the block transactions pay enough fees, each of them at least
+	/// consensus::MIN_FEE and all together enough for their weight. Only the
+	/// proof of the reward goes without fee.
+	pub fn pays_min_fees(&self) -> bool {
+		let unpaid = self.proofs.iter().filter(|p| p.fee < consensus::MIN_FEE).count();
+		let fees = self.total_fees().unwrap_or(0);
+		unpaid <= 1 && fees >= consensus::weight_fee(self.txs_weight())
+	}
+
+	/// Whether the block inputs, outputs and proofs are all in their canonical
+	/// ordering, sorted by hash.
+	pub fn is_sorted(&self) -> bool {
+		sorted_by_hash(&self.inputs) && sorted_by_hash(&self.outputs) &&
+		sorted_by_hash(&self.proofs)
+	}
+
+	/// Whether all the block transactions can be included at the block
+	/// height, none of their lock heights being above it.
+	pub fn kernels_unlocked(&self) -> bool {
+		self.proofs.iter().all(|p| p.lock_height <= self.header.height)
+	}
+
+	/// Calculates the Merkle root of the block inputs and outputs, which the
+	/// header commits to in its tx_merkle field.
+	pub fn merkle_root(&self) -> Hash {
+		merkle_inputs_outputs(&self.inputs, &self.outputs)
+	}
+
+	/// Value the coinbase output of the block has to have, its subsidy plus
+	/// its transactions fees. None if that's more than any amount can be.
+	pub fn reward(&self) -> Option<u64> {
+		self.total_fees().and_then(|fees| consensus::reward(self.header.height, fees))
+	}
+
+	/// Finds the output the block reward was paid to, if still in the block.
+	/// The reward has its own proof, without fee, whose remainder is the reward
+	/// value minus the reward output commitment, so only a coinbase output worth
+	/// exactly the reward is found, and none if the reward can't be summed.
+	pub fn coinbase_output(&self, secp: &Secp256k1) -> Result<Option<Output>, secp::Error> {
+		Ok(try!(self.coinbase(secp)).map(|(out, _)| out))
+	}
+
+	/// Finds the output the block reward was paid to along with the proof of
+	/// the reward, see `coinbase_output`.
+	pub fn coinbase(&self, secp: &Secp256k1) -> Result<Option<(Output, TxProof)>, secp::Error> {
+		let reward = match self.reward() {
+			Some(reward) => reward,
+			None => return Ok(None),
+		};
+		let over_commit = try!(secp.commit_value(reward));
+		for proof in self.proofs.iter().filter(|p| p.fee == 0) {
+			let commit = try!(secp.commit_sum(vec![over_commit], vec![proof.remainder]));
+			if let Some(out) = self.outputs
+				.iter()
+				.find(|out| out.is_coinbase() && out.commitment() == Some(commit)) {
+				return Ok(Some((*out, proof.clone())));
 			}
 		}
-		// we got ourselves a keep list in that set
-		let new_outputs = self.outputs
-			.iter()
-			.filter(|out| out_set.contains(&(out.hash())))
-			.map(|&out| out)
-			.collect::<Vec<Output>>();
+		Ok(None)
+	}
 
+	/// Matches any output with a potential spending input, eliminating them
+	/// from the block. Provides a simple way to compact the block. The
+	/// elimination is stable with respect to inputs and outputs order.
+	pub fn compact(&self) -> Block {
+		let (new_inputs, new_outputs) = cut_through(&self.inputs, &self.outputs);
 		let tx_merkle = merkle_inputs_outputs(&new_inputs, &new_outputs);
 
 		Block {
@@ -326,6 +501,7 @@ impl Block {
 
 		all_inputs.sort_by_key(|inp| inp.hash());
 		all_outputs.sort_by_key(|out| out.hash());
+		all_proofs.sort_by_key(|proof| proof.hash());
 
 		Block {
 				// compact will fix the merkle tree
@@ -345,6 +521,41 @@ impl Block {
 	/// Checks the block is valid by verifying the overall commitments sums and
 	/// proofs.
 	pub fn verify(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
+		try!(self.verify_sums(secp));
+
+		// verify all signatures with the commitment as pk
+		for proof in &self.proofs {
+			try!(proof.verify(secp));
+		}
+		// and all range proofs against their output commitment
+		verify_range_proofs(&self.outputs, secp)
+	}
+
+	/// Same validation as verify, except the signature checks, which dominate
+	/// the validation time of any large block along with the range proofs, are
+	/// spread over all available cores.
+	pub fn verify_parallel(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
+		try!(self.verify_kernels(secp));
+		verify_range_proofs(&self.outputs, secp)
+	}
+
+	/// Partial validation of the block, checking the overall commitments sums
+	/// and the proof signatures (spread over all available cores) but not the
+	/// range proofs. Those can be checked separately with verify_range_proofs.
+	pub fn verify_kernels(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
+		try!(self.verify_sums(secp));
+
+		let sig_res: Vec<Result<(), secp::Error>> =
+			self.proofs.par_iter().map(|proof| proof.verify(secp)).collect();
+		for res in sig_res {
+			try!(res);
+		}
+		Ok(())
+	}
+
+	// Checks the sum of all inputs and outputs commitments matches the sum of
+	// the proofs remainders.
+	fn verify_sums(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
 		// sum all inputs and outs commitments
 		let io_sum = try!(self.sum_commitments(secp));
 		// sum all proofs commitments
@@ -356,27 +567,40 @@ impl Block {
 			// TODO more specific error
 			return Err(secp::Error::IncorrectCommitSum);
 		}
-
-		// verify all signatures with the commitment as pk
-		for proof in &self.proofs {
-			try!(proof.verify(secp));
-		}
 		Ok(())
 	}
+}
+
+/// Provides the output a block reward goes to, along with the proof of its
+/// remainder. Lets the miner build blocks whose reward ends up spendable by a
+/// wallet, which can derive a new key for every block.
+pub trait RewardProvider {
+	/// Builds the blinded coinbase output of the provided reward and its
+	/// signed proof, for the block at the provided height.
+	fn reward_output(&self,
+	                 height: u64,
+	                 reward: u64,
+	                 secp: &Secp256k1)
+	                 -> Result<(Output, TxProof), secp::Error>;
+}
 
-	// Builds the blinded output and related signature proof for the block reward.
-	fn reward_output(skey: secp::key::SecretKey,
+/// A private key receives the reward directly.
+impl RewardProvider for SecretKey {
+	fn reward_output(&self,
+	                 _: u64,
+	                 reward: u64,
 	                 secp: &Secp256k1)
 	                 -> Result<(Output, TxProof), secp::Error> {
 		let msg = try!(secp::Message::from_slice(&[0; secp::constants::MESSAGE_SIZE]));
-		let sig = try!(secp.sign(&msg, &skey));
-		let output = Output::OvertOutput {
-				value: REWARD,
-				blindkey: skey,
+		let sig = try!(secp.sign(&msg, self));
+		let output = try!(Output::OvertOutput {
+				features: OutputFeatures::Coinbase,
+				value: reward,
+				blindkey: *self,
 			}
-			.blind(&secp);
+			.blind(&secp));
 
-		let over_commit = try!(secp.commit_value(REWARD as u64));
+		let over_commit = try!(secp.commit_value(reward));
 		let out_commit = output.commitment().unwrap();
 		let remainder = try!(secp.commit_sum(vec![over_commit], vec![out_commit]));
 
@@ -384,22 +608,63 @@ impl Block {
 			remainder: remainder,
 			sig: sig.serialize_der(&secp),
 			fee: 0,
+			lock_height: 0,
+			relative_height: 0,
 		};
 		Ok((output, proof))
 	}
 }
 
+impl<'a, R: RewardProvider + ?Sized> RewardProvider for &'a R {
+	fn reward_output(&self,
+	                 height: u64,
+	                 reward: u64,
+	                 secp: &Secp256k1)
+	                 -> Result<(Output, TxProof), secp::Error> {
+		(**self).reward_output(height, reward, secp)
+	}
+}
+
+/// Checks the range proofs of all the provided outputs. Bulletproofs are all
+/// verified at once in a single multi-exponentiation, while the range proofs
+/// of outputs from before those are spread over all available cores.
+pub fn verify_range_proofs(outputs: &Vec<Output>, secp: &Secp256k1) -> Result<(), secp::Error> {
+	let mut commits = vec![];
+	let mut proofs = vec![];
+	let mut legacy = vec![];
+	for output in outputs {
+		match output {
+			&Output::BlindOutput { commit, proof, .. } if is_bullet_proof(&proof) => {
+				commits.push(commit);
+				proofs.push(proof);
+			}
+			_ => legacy.push(output),
+		}
+	}
+	if commits.len() > 0 {
+		try!(secp.verify_bullet_proof_multi(commits, proofs));
+	}
+	let range_res: Vec<Result<(), secp::Error>> =
+		legacy.par_iter().map(|output| output.verify_proof(secp)).collect();
+	for res in range_res {
+		try!(res);
+	}
+	Ok(())
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
-	use core::{Input, Output, Transaction};
+	use core::{Output, OutputFeatures, Transaction};
 	use core::hash::{Hash, Hashed};
+	use core::transaction::Builder;
 	use core::test::{tx1i1o, tx2i1o};
 
 	use secp::{self, Secp256k1};
 	use secp::key::SecretKey;
 	use rand::Rng;
 	use rand::os::OsRng;
+	use std::cell::RefCell;
 
 	fn new_secp() -> Secp256k1 {
 		secp::Secp256k1::with_caps(secp::ContextFlag::Commit)
@@ -412,19 +677,15 @@ mod test {
 		Block::new(&BlockHeader::default(), txs, skey).unwrap()
 	}
 
-	// utility producing a transaction that spends the above
+	// utility producing a blinded transaction that spends the above
 	fn txspend1i1o<R: Rng>(secp: &Secp256k1, rng: &mut R, oout: Output, outh: Hash) -> Transaction {
-		if let Output::OvertOutput { blindkey, value } = oout {
-			Transaction::new(vec![Input::OvertInput {
-				                      output: outh,
-				                      value: value,
-				                      blindkey: blindkey,
-			                      }],
-			                 vec![Output::OvertOutput {
-				                      value: 3,
-				                      blindkey: SecretKey::new(secp, rng),
-			                      }],
-			                 1)
+		if let Output::OvertOutput { blindkey, value, .. } = oout {
+			Builder::new()
+				.input(outh, value, blindkey)
+				.output(3, SecretKey::new(secp, rng))
+				.fee(1)
+				.build(secp)
+				.unwrap()
 		} else {
 			panic!();
 		}
@@ -443,8 +704,7 @@ mod test {
 		let mut btx2 = tx2.blind(&secp).unwrap();
 
 		// spending tx2
-		let spending = txspend1i1o(secp, &mut rng, tx2.outputs[0], btx2.outputs[0].hash());
-		let mut btx3 = spending.blind(&secp).unwrap();
+		let mut btx3 = txspend1i1o(secp, &mut rng, tx2.outputs[0], btx2.outputs[0].hash());
 		let b = new_block(vec![&mut btx1, &mut btx2, &mut btx3], secp);
 
 		// block should have been automatically compacted (including reward output) and
@@ -468,8 +728,7 @@ mod test {
 		let mut btx2 = tx2.blind(&secp).unwrap();
 
 		// spending tx2
-		let spending = txspend1i1o(secp, &mut rng, tx2.outputs[0], btx2.outputs[0].hash());
-		let mut btx3 = spending.blind(&secp).unwrap();
+		let mut btx3 = txspend1i1o(secp, &mut rng, tx2.outputs[0], btx2.outputs[0].hash());
 
 		let b1 = new_block(vec![&mut btx1, &mut btx2], secp);
 		b1.verify(&secp).unwrap();
@@ -481,4 +740,273 @@ mod test {
 		assert_eq!(b3.inputs.len(), 3);
 		assert_eq!(b3.outputs.len(), 4);
 	}
+
+	#[test]
+	// parallel verification gives the same result as the sequential one
+	fn verify_parallel() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let tx1 = tx2i1o(secp, &mut rng);
+		let mut btx1 = tx1.blind(&secp).unwrap();
+		let tx2 = tx1i1o(secp, &mut rng);
+		let mut btx2 = tx2.blind(&secp).unwrap();
+
+		let mut b = new_block(vec![&mut btx1, &mut btx2], secp);
+		b.verify(&secp).unwrap();
+		b.verify_parallel(&secp).unwrap();
+
+		// swapping signatures leaves the sums intact but the proofs invalid
+		b.proofs[0].sig = b.proofs[1].sig.clone();
+		assert!(b.verify(&secp).is_err());
+		assert!(b.verify_parallel(&secp).is_err());
+	}
+
+	#[test]
+	// bulletproofs verified in one batch, along with older range proofs
+	fn batch_range_proofs() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let tx1 = tx2i1o(secp, &mut rng);
+		let mut btx1 = tx1.blind(&secp).unwrap();
+		let tx2 = tx1i1o(secp, &mut rng);
+		let mut btx2 = tx2.blind(&secp).unwrap();
+		let b = new_block(vec![&mut btx1, &mut btx2], secp);
+		verify_range_proofs(&b.outputs, secp).unwrap();
+		verify_range_proofs(&vec![], secp).unwrap();
+
+		let skey = SecretKey::new(secp, &mut rng);
+		let commit = secp.commit(5, skey).unwrap();
+		let legacy = Output::BlindOutput {
+			features: OutputFeatures::Plain,
+			commit: commit,
+			proof: secp.range_proof(0, 5, skey, commit),
+		};
+		let mut outputs = b.outputs.clone();
+		outputs.push(legacy);
+		verify_range_proofs(&outputs, secp).unwrap();
+
+		// a single proof swapped with another one fails the whole batch
+		let mut b2 = b.clone();
+		if let (Output::BlindOutput { features, commit, .. }, Output::BlindOutput { proof, .. }) =
+		       (b2.outputs[0], b2.outputs[1]) {
+			b2.outputs[0] = Output::BlindOutput {
+				features: features,
+				commit: commit,
+				proof: proof,
+			};
+		}
+		assert!(verify_range_proofs(&b2.outputs, secp).is_err());
+		assert!(b2.verify(&secp).is_err());
+		assert!(b2.verify_parallel(&secp).is_err());
+	}
+
+	#[test]
+	// the header commits to the block body, changing it breaks the commitment
+	fn merkle_root_matches_body() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let tx1 = tx2i1o(secp, &mut rng);
+		let mut btx1 = tx1.blind(&secp).unwrap();
+		let mut b = new_block(vec![&mut btx1], secp);
+		assert_eq!(b.merkle_root(), b.header.tx_merkle);
+
+		b.outputs.pop();
+		assert!(b.merkle_root() != b.header.tx_merkle);
+	}
+
+	#[test]
+	// blocks are built, merged and compacted sorted
+	fn sorted_body() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut btx1 = tx2i1o(secp, &mut rng).blind(&secp).unwrap();
+		let mut btx2 = tx1i1o(secp, &mut rng).blind(&secp).unwrap();
+		let b1 = new_block(vec![&mut btx1, &mut btx2], secp);
+		assert!(b1.is_sorted());
+		let mut btx3 = tx1i1o(secp, &mut rng).blind(&secp).unwrap();
+		let b2 = new_block(vec![&mut btx3], secp);
+		let merged = b1.merge(b2);
+		assert!(merged.is_sorted());
+
+		let mut b3 = merged.clone();
+		b3.proofs.reverse();
+		assert!(!b3.is_sorted());
+		let mut b4 = merged.clone();
+		b4.outputs.reverse();
+		assert!(!b4.is_sorted());
+	}
+
+	#[test]
+	// the reward output can be told apart from the transactions outputs
+	fn finds_coinbase_output() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let tx1 = tx2i1o(secp, &mut rng);
+		let mut btx1 = tx1.blind(&secp).unwrap();
+		let skey = SecretKey::new(secp, &mut rng);
+		let b = Block::new(&BlockHeader::default(), vec![&mut btx1], skey).unwrap();
+
+		// paid the subsidy of the first block and the transaction fee
+		let coinbase = b.coinbase_output(secp).unwrap().unwrap();
+		assert_eq!(b.reward(), Some(consensus::REWARD + 1));
+		let reward_commit = secp.commit(consensus::REWARD + 1, skey).unwrap();
+		assert_eq!(coinbase.commitment(), Some(reward_commit));
+		assert!(btx1.outputs.iter().all(|out| out.commitment() != coinbase.commitment()));
+
+		// flagged as such, unlike the transaction outputs
+		assert!(coinbase.is_coinbase());
+		assert!(btx1.outputs.iter().all(|out| !out.is_coinbase()));
+	}
+
+	#[test]
+	fn block_fees() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut btx1 = tx2i1o(secp, &mut rng).blind(&secp).unwrap();
+		let mut btx2 = tx1i1o(secp, &mut rng).blind(&secp).unwrap();
+		let b = new_block(vec![&mut btx1, &mut btx2], secp);
+		assert_eq!(b.total_fees(), Some(2));
+		assert!(b.pays_min_fees());
+
+		// the reward weighs on the block but isn't paid for
+		assert_eq!(b.weight(), btx1.weight() + btx2.weight() + consensus::tx_weight(0, 1));
+		assert_eq!(b.fee_rate(), 2000 / (btx1.weight() + btx2.weight()) as u64);
+
+		// only the reward goes without fee
+		let mut b2 = b.clone();
+		let paying = b2.proofs.iter().position(|p| p.fee > 0).unwrap();
+		b2.proofs[paying].fee = 0;
+		assert!(!b2.pays_min_fees());
+
+		// and the fees have to cover the weight of the transactions
+		let mut b3 = b.clone();
+		for _ in 0..100 {
+			b3.outputs.push(b.outputs[0]);
+		}
+		assert!(!b3.pays_min_fees());
+		b3.proofs[0].fee = 51;
+		assert!(b3.pays_min_fees());
+		assert!(b3.fee_rate() >= consensus::FEE_PER_KILO_WEIGHT);
+
+		// fees summing over the largest amount don't add up to anything
+		b3.proofs[1].fee = u64::max_value();
+		assert_eq!(b3.total_fees(), None);
+		assert_eq!(b3.reward(), None);
+		assert!(!b3.pays_min_fees());
+		assert!(b3.coinbase_output(secp).unwrap().is_none());
+	}
+
+	#[test]
+	// a block paying itself more or less than its reward doesn't add up
+	fn wrong_reward() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let tx1 = tx2i1o(secp, &mut rng);
+		let mut btx1 = tx1.blind(&secp).unwrap();
+		let b = new_block(vec![&mut btx1], secp);
+		b.verify(&secp).unwrap();
+
+		// same block at a height with another subsidy
+		let mut b2 = b.clone();
+		b2.header.height = consensus::REWARD_HALVING_INTERVAL;
+		assert!(b2.coinbase_output(secp).unwrap().is_none());
+		assert!(b2.verify(&secp).is_err());
+
+		// or claiming fees it doesn't have
+		let mut b3 = b.clone();
+		b3.proofs[0].fee += 1;
+		assert!(b3.coinbase_output(secp).unwrap().is_none());
+		assert!(b3.verify(&secp).is_err());
+	}
+
+	#[test]
+	// the reward can go to anything providing its output, like a wallet
+	fn provided_reward() {
+		struct Wallet {
+			key: SecretKey,
+			heights: RefCell<Vec<u64>>,
+		}
+		impl RewardProvider for Wallet {
+			fn reward_output(&self,
+			                 height: u64,
+			                 reward: u64,
+			                 secp: &Secp256k1)
+			                 -> Result<(Output, TxProof), secp::Error> {
+				self.heights.borrow_mut().push(height);
+				self.key.reward_output(height, reward, secp)
+			}
+		}
+
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+		let wallet = Wallet {
+			key: SecretKey::new(secp, &mut rng),
+			heights: RefCell::new(vec![]),
+		};
+
+		let mut btx1 = tx2i1o(secp, &mut rng).blind(&secp).unwrap();
+		let b = Block::new(&BlockHeader::default(), vec![&mut btx1], &wallet).unwrap();
+		b.verify(&secp).unwrap();
+		assert_eq!(*wallet.heights.borrow(), vec![1]);
+		let coinbase = b.coinbase_output(secp).unwrap().unwrap();
+		let reward_commit = secp.commit(consensus::REWARD + 1, wallet.key).unwrap();
+		assert_eq!(coinbase.commitment(), Some(reward_commit));
+
+		// a reward built beforehand has to pay the subsidy and fees
+		let mut btx2 = tx1i1o(secp, &mut rng).blind(&secp).unwrap();
+		let (out, proof) = wallet.key.reward_output(1, consensus::REWARD + 1, secp).unwrap();
+		let b2 = Block::with_reward(&BlockHeader::default(), vec![&mut btx2], out, proof).unwrap();
+		b2.verify(&secp).unwrap();
+		let (out, proof) = wallet.key.reward_output(1, consensus::REWARD, secp).unwrap();
+		let b3 = Block::with_reward(&BlockHeader::default(), vec![&mut btx2], out, proof).unwrap();
+		assert!(b3.verify(&secp).is_err());
+	}
+
+	#[test]
+	fn header_ser_versions() {
+		let mut h = BlockHeader::default();
+		h.height = 3;
+		h.secondary_scaling = 12;
+		let vec = ser::ser_vec(&h).unwrap();
+		assert_eq!(vec[0], ser::SER_VERSION);
+		let dh: BlockHeader = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dh.hash(), h.hash());
+		assert_eq!(dh.secondary_scaling, 12);
+
+		// fields added by a newer version get skipped
+		let mut newer = vec.clone();
+		newer[0] = ser::SER_VERSION + 1;
+		let len = newer.len() - 3 + 4;
+		newer[1] = (len >> 8) as u8;
+		newer[2] = len as u8;
+		newer.extend_from_slice(&[1, 2, 3, 4]);
+		newer.push(9);
+		let mut source = &newer[..];
+		let dh: BlockHeader = ser::deserialize(&mut source).unwrap();
+		assert_eq!(dh.hash(), h.hash());
+		assert_eq!(source, &[9u8][..]);
+
+		// headers written before serialization versions or with unpacked
+		// proof of work nonces still read
+		let unpacked = ser::ser_vec(&HeaderFields(&h, false)).unwrap();
+		let dh: BlockHeader = ser::deserialize(&mut &unpacked[..]).unwrap();
+		assert_eq!(dh.hash(), h.hash());
+		let mut v1 = vec![1, (unpacked.len() >> 8) as u8, unpacked.len() as u8];
+		v1.extend_from_slice(&unpacked);
+		let dh: BlockHeader = ser::deserialize(&mut &v1[..]).unwrap();
+		assert_eq!(dh.hash(), h.hash());
+		assert!(vec.len() < v1.len());
+
+		// but not with a length too short for their fields
+		let mut short = vec.clone();
+		short[2] -= 1;
+		assert!(ser::deserialize::<BlockHeader>(&mut &short[..]).is_err());
+	}
 }
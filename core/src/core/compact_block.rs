@@ -0,0 +1,296 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compact blocks, what a block can be relayed as to peers that likely know
+//! most of its transactions already: the header, the coinbase and a short id
+//! for each transaction proof. The full block gets rebuilt from known
+//! transactions, only the missing ones having to be asked for.
+
+use std::collections::{HashMap, HashSet};
+
+use byteorder::{ByteOrder, BigEndian};
+use secp::{self, Secp256k1};
+
+use consensus;
+use core::{Block, BlockHeader, Output, Transaction, TxProof};
+use core::hash::{Hash, Hashed};
+use pow::siphash::{siphash24, siphash_keys};
+use ser::{self, Readable, Reader, Writeable, Writer};
+
+/// Short identifier of a transaction in a compact block, the 6 lowest bytes
+/// of the siphash of its proof hash, keyed by the block hash and the compact
+/// block nonce so collisions can't be prepared in advance.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ShortId(pub [u8; 6]);
+
+impl ShortId {
+	fn from_proof(keys: [u64; 4], proof: &TxProof) -> ShortId {
+		let h = proof.hash();
+		let mut bytes = [0; 8];
+		BigEndian::write_u64(&mut bytes, siphash24(keys, BigEndian::read_u64(&h.0[..8])));
+		let mut id = [0; 6];
+		id.copy_from_slice(&bytes[2..]);
+		ShortId(id)
+	}
+}
+
+/// Errors building a compact block or rebuilding the full block from it.
+#[derive(Debug)]
+pub enum Error {
+	/// The block doesn't pay its reward to a coinbase output
+	NoCoinbase,
+	/// Transactions of the block we don't know of, to ask for
+	MissingTxs(Vec<ShortId>),
+	/// The rebuilt block doesn't match its header, from a short id collision
+	/// or a compact block not matching its block
+	MerkleMismatch,
+	/// Error from the underlying secp lib
+	Secp(secp::Error),
+}
+
+/// A block with only short ids of its transactions, see the module doc.
+#[derive(Clone)]
+pub struct CompactBlock {
+	pub header: BlockHeader,
+	/// Output the block reward is paid to
+	pub coinbase_output: Output,
+	/// Proof of the block reward
+	pub coinbase_proof: TxProof,
+	/// Nonce keying the short ids along with the block hash, picked for each
+	/// compact block
+	pub nonce: u64,
+	/// Short id of the proof of each transaction of the block
+	pub short_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+	/// Compact block of the provided block, its short ids keyed with the
+	/// provided nonce.
+	pub fn from_block(b: &Block, nonce: u64, secp: &Secp256k1) -> Result<CompactBlock, Error> {
+		let coinbase = try!(b.coinbase(secp).map_err(Error::Secp));
+		let (coinbase_output, coinbase_proof) = try!(coinbase.ok_or(Error::NoCoinbase));
+		let keys = short_id_keys(&b.hash(), nonce);
+		let coinbase_h = coinbase_proof.hash();
+		let short_ids = b.proofs
+			.iter()
+			.filter(|p| p.hash() != coinbase_h)
+			.map(|p| ShortId::from_proof(keys, p))
+			.collect();
+		Ok(CompactBlock {
+			header: b.header.clone(),
+			coinbase_output: coinbase_output,
+			coinbase_proof: coinbase_proof,
+			nonce: nonce,
+			short_ids: short_ids,
+		})
+	}
+
+	pub fn hash(&self) -> Hash {
+		self.header.hash()
+	}
+
+	/// Short id the proof of a transaction has in this compact block.
+	pub fn short_id(&self, proof: &TxProof) -> ShortId {
+		ShortId::from_proof(short_id_keys(&self.hash(), self.nonce), proof)
+	}
+
+	/// Rebuilds the full block out of the provided transactions, which can
+	/// include any transaction not in the block. Fails with the short ids of
+	/// the block transactions not provided if any. Aggregated transactions
+	/// are known by each of their proofs.
+	pub fn to_block(&self, txs: &[Transaction], secp: &Secp256k1) -> Result<Block, Error> {
+		let keys = short_id_keys(&self.hash(), self.nonce);
+		let mut known = HashMap::new();
+		for (n, tx) in txs.iter().enumerate() {
+			if let Ok(tx_proofs) = tx.verify_kernels(secp) {
+				for proof in tx_proofs {
+					known.insert(ShortId::from_proof(keys, &proof), (proof, n));
+				}
+			}
+		}
+
+		let mut inputs = vec![];
+		let mut outputs = vec![];
+		let mut proofs = vec![];
+		let mut missing = vec![];
+		let mut included = HashSet::new();
+		for id in &self.short_ids {
+			match known.get(id) {
+				Some(&(ref proof, n)) => {
+					if included.insert(n) {
+						inputs.extend_from_slice(&txs[n].inputs);
+						outputs.extend_from_slice(&txs[n].outputs);
+					}
+					proofs.push(proof.clone());
+				}
+				None => missing.push(*id),
+			}
+		}
+		if !missing.is_empty() {
+			return Err(Error::MissingTxs(missing));
+		}
+		outputs.push(self.coinbase_output);
+		proofs.push(self.coinbase_proof.clone());
+
+		// same ordering and cut-through as when the block was built
+		inputs.sort_by_key(|inp| inp.hash());
+		outputs.sort_by_key(|out| out.hash());
+		proofs.sort_by_key(|proof| proof.hash());
+		let b = Block {
+				header: self.header.clone(),
+				inputs: inputs,
+				outputs: outputs,
+				proofs: proofs,
+			}
+			.compact();
+		if b.merkle_root() != self.header.tx_merkle {
+			return Err(Error::MerkleMismatch);
+		}
+		Ok(b)
+	}
+}
+
+// siphash keys of the short ids of the block with the provided hash, from the
+// hash of the block hash and the nonce
+fn short_id_keys(h: &Hash, nonce: u64) -> [u64; 4] {
+	let mut data = h.to_vec();
+	let mut nonce_bytes = [0; 8];
+	BigEndian::write_u64(&mut nonce_bytes, nonce);
+	data.extend_from_slice(&nonce_bytes);
+	let keyed = data[..].hash();
+	siphash_keys(BigEndian::read_u64(&keyed.0[..8]),
+	             BigEndian::read_u64(&keyed.0[8..16]))
+}
+
+impl Writeable for CompactBlock {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(self.header.write(writer));
+		try!(self.coinbase_output.write(writer));
+		try!(self.coinbase_proof.write(writer));
+		ser_multiwrite!(writer,
+		                [write_u64, self.nonce],
+		                [write_u64, self.short_ids.len() as u64]);
+		for id in &self.short_ids {
+			try!(writer.write_fixed_bytes(&id.0));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<CompactBlock> for CompactBlock {
+	fn read(reader: &mut Reader) -> Result<CompactBlock, ser::Error> {
+		let header = try!(BlockHeader::read(reader));
+		let coinbase_output = try!(Output::read(reader));
+		let coinbase_proof = try!(TxProof::read(reader));
+		let (nonce, id_len) = ser_multiread!(reader, read_u64, read_u64);
+		// no more transactions than a block can have proofs
+		if id_len > consensus::MAX_BLOCK_PROOFS as u64 {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut short_ids = Vec::with_capacity(id_len as usize);
+		for _ in 0..id_len {
+			let bytes = try!(reader.read_fixed_bytes(6));
+			let mut id = [0; 6];
+			id.copy_from_slice(&bytes);
+			short_ids.push(ShortId(id));
+		}
+		Ok(CompactBlock {
+			header: header,
+			coinbase_output: coinbase_output,
+			coinbase_proof: coinbase_proof,
+			nonce: nonce,
+			short_ids: short_ids,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::{Block, BlockHeader};
+	use core::test::{tx1i1o, tx2i1o};
+	use ser::{deserialize, ser_vec};
+
+	use secp::{self, Secp256k1};
+	use secp::key::SecretKey;
+	use rand::os::OsRng;
+
+	fn new_secp() -> Secp256k1 {
+		secp::Secp256k1::with_caps(secp::ContextFlag::Commit)
+	}
+
+	#[test]
+	// the full block gets rebuilt from its transactions, in any order and
+	// among others
+	fn compact_roundtrip() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut btx1 = tx2i1o(secp, &mut rng).blind(&secp).unwrap();
+		let mut btx2 = tx1i1o(secp, &mut rng).blind(&secp).unwrap();
+		let other = tx1i1o(secp, &mut rng).blind(&secp).unwrap();
+		let skey = SecretKey::new(secp, &mut rng);
+		let b = Block::new(&BlockHeader::default(), vec![&mut btx1, &mut btx2], skey).unwrap();
+
+		let cb = CompactBlock::from_block(&b, 7, secp).unwrap();
+		assert_eq!(cb.hash(), b.hash());
+		assert_eq!(cb.short_ids.len(), 2);
+		let rebuilt = cb.to_block(&[other, btx2, btx1], secp).unwrap();
+		assert_eq!(rebuilt.hash(), b.hash());
+		assert_eq!(rebuilt.merkle_root(), b.merkle_root());
+		assert_eq!(rebuilt.proofs.len(), b.proofs.len());
+		rebuilt.verify(secp).unwrap();
+	}
+
+	#[test]
+	fn compact_missing_txs() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut btx1 = tx2i1o(secp, &mut rng).blind(&secp).unwrap();
+		let mut btx2 = tx1i1o(secp, &mut rng).blind(&secp).unwrap();
+		let proof2 = btx2.verify_sig(secp).unwrap();
+		let skey = SecretKey::new(secp, &mut rng);
+		let b = Block::new(&BlockHeader::default(), vec![&mut btx1, &mut btx2], skey).unwrap();
+
+		let cb = CompactBlock::from_block(&b, 7, secp).unwrap();
+		match cb.to_block(&[btx1], secp) {
+			Err(Error::MissingTxs(ids)) => assert_eq!(ids, vec![cb.short_id(&proof2)]),
+			res => panic!("unexpected result {:?}", res.map(|b| b.hash())),
+		}
+
+		// the short ids change with the nonce
+		let cb2 = CompactBlock::from_block(&b, 8, secp).unwrap();
+		assert!(cb2.short_id(&proof2) != cb.short_id(&proof2));
+	}
+
+	#[test]
+	fn compact_ser_deser() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut btx1 = tx2i1o(secp, &mut rng).blind(&secp).unwrap();
+		let skey = SecretKey::new(secp, &mut rng);
+		let b = Block::new(&BlockHeader::default(), vec![&mut btx1], skey).unwrap();
+
+		let cb = CompactBlock::from_block(&b, 7, secp).unwrap();
+		let vec = ser_vec(&cb).unwrap();
+		assert!(vec.len() < ser_vec(&b).unwrap().len());
+		let dcb: CompactBlock = deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dcb.hash(), cb.hash());
+		assert_eq!(dcb.nonce, 7);
+		assert_eq!(dcb.short_ids, cb.short_ids);
+		assert_eq!(dcb.to_block(&[btx1], secp).unwrap().hash(), b.hash());
+	}
+}
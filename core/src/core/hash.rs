@@ -18,7 +18,7 @@
 //!
 
 use byteorder::{ByteOrder, BigEndian};
-use std::fmt;
+use std::{fmt, str};
 use tiny_keccak::Keccak;
 
 use ser::{self, AsFixedBytes, Reader, Readable, Error};
@@ -39,6 +39,22 @@ impl fmt::Display for Hash {
 	}
 }
 
+impl str::FromStr for Hash {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Hash, Error> {
+		let v = try!(ser::from_hex(s));
+		if v.len() != 32 {
+			return Err(Error::CorruptedData);
+		}
+		let mut a = [0; 32];
+		a.copy_from_slice(&v);
+		Ok(Hash(a))
+	}
+}
+
+impl_serde_string!(Hash);
+
 impl Hash {
 	/// Converts the hash to a byte vector
 	pub fn to_vec(&self) -> Vec<u8> {
@@ -50,6 +66,11 @@ impl Hash {
 	}
 }
 
+impl ser::Writeable for Hash {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		writer.write_fixed_bytes(self)
+	}
+}
 
 impl Readable<Hash> for Hash {
 	fn read(reader: &mut Reader) -> Result<Hash, ser::Error> {
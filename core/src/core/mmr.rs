@@ -0,0 +1,457 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Merkle Mountain Range, an append-only list of hashes committed to by a
+//! single root. Each complete binary tree of leaves is a mountain and the root
+//! bags the peaks of all mountains together. Leaves can be pruned: a pruned
+//! leaf becomes the zero hash and so does any node with only pruned leaves
+//! beneath it, so those never need to be kept around.
+//!
+//! Nodes are addressed by their height, 0 for the leaves, and their index
+//! from the left among the nodes at that height.
+//!
+//! The same range backs any list of commitments a header needs a root of,
+//! outputs, kernels or range proofs, each leaf being the hash of an element.
+
+use std::collections::HashMap;
+
+use core::hash::{Hash, Hashed, ZERO_HASH};
+use ser::{self, Readable, Reader, Writeable, Writer};
+use super::HPair;
+
+/// Where the nodes of a range are read from, when they haven't been changed
+/// in memory. Nodes with the zero hash don't have to be found.
+pub trait NodeSource {
+	/// Error when a node can't be read
+	type Error;
+
+	/// The node at the provided height and index, if any
+	fn node(&self, height: u8, idx: u64) -> Result<Option<Hash>, Self::Error>;
+}
+
+/// A source without any node, for ranges entirely kept in memory.
+impl NodeSource for () {
+	type Error = ();
+
+	fn node(&self, _: u8, _: u64) -> Result<Option<Hash>, ()> {
+		Ok(None)
+	}
+}
+
+/// A range whose nodes are read from a source, with the changes made since
+/// kept in memory until saved.
+#[derive(Clone)]
+pub struct MountainRange<S> {
+	source: S,
+	size: u64,
+	changes: HashMap<(u8, u64), Hash>,
+}
+
+impl<S: NodeSource> MountainRange<S> {
+	/// A range with the provided number of leaves, whose nodes are in the
+	/// source.
+	pub fn new(source: S, size: u64) -> MountainRange<S> {
+		MountainRange {
+			source: source,
+			size: size,
+			changes: HashMap::new(),
+		}
+	}
+
+	/// Number of leaves in the range, including pruned ones.
+	pub fn size(&self) -> u64 {
+		self.size
+	}
+
+	/// Appends a leaf, returning its index.
+	pub fn push(&mut self, leaf: Hash) -> Result<u64, S::Error> {
+		let idx = self.size;
+		self.size += 1;
+		try!(self.set_leaf(idx, leaf));
+		Ok(idx)
+	}
+
+	/// Appends the hash of an element as leaf, returning its index.
+	pub fn push_elmt<T: Hashed + ?Sized>(&mut self, elmt: &T) -> Result<u64, S::Error> {
+		self.push(elmt.hash())
+	}
+
+	/// Prunes the leaf at the provided index.
+	pub fn prune(&mut self, idx: u64) -> Result<(), S::Error> {
+		self.set_leaf(idx, ZERO_HASH)
+	}
+
+	/// Puts back the pruned leaf at the provided index.
+	pub fn restore(&mut self, idx: u64, leaf: Hash) -> Result<(), S::Error> {
+		self.set_leaf(idx, leaf)
+	}
+
+	/// Removes all the leaves from the provided index on, undoing the pushes
+	/// that got the range past that size.
+	pub fn truncate(&mut self, size: u64) {
+		// a node only depends on the leaves beneath it, the ones entirely
+		// before the new size are left as they are
+		let mut height = 0;
+		while self.size >> height > 0 {
+			for idx in (size >> height)..(self.size >> height) {
+				self.changes.insert((height, idx), ZERO_HASH);
+			}
+			height += 1;
+		}
+		self.size = size;
+	}
+
+	/// Root of the range, bagging its peaks from right to left.
+	pub fn root(&self) -> Result<Hash, S::Error> {
+		let peaks = try!(self.peaks());
+		let hashes = map_vec!(peaks, |&(_, _, h)| h);
+		Ok(bag_peaks(&hashes))
+	}
+
+	/// Height, index and hash of the top node of each mountain, from the
+	/// highest and leftmost one to the lowest and rightmost one.
+	pub fn peaks(&self) -> Result<Vec<(u8, u64, Hash)>, S::Error> {
+		let mut peaks = vec![];
+		let mut leaves = 0;
+		for height in (0..64u8).rev() {
+			if self.size & (1u64 << height) != 0 {
+				let idx = leaves >> height;
+				peaks.push((height, idx, try!(self.get(height, idx))));
+				leaves += 1u64 << height;
+			}
+		}
+		Ok(peaks)
+	}
+
+	/// Hash of the node at the provided height and index, the zero hash if
+	/// it's pruned or not in the range.
+	pub fn node(&self, height: u8, idx: u64) -> Result<Hash, S::Error> {
+		if height >= 64 || idx >= self.size >> height {
+			return Ok(ZERO_HASH);
+		}
+		self.get(height, idx)
+	}
+
+	/// Proof that the leaf at the provided index is in the range, under its
+	/// current root. None if the leaf isn't in the range or was pruned.
+	pub fn merkle_proof(&self, idx: u64) -> Result<Option<MerkleProof>, S::Error> {
+		if idx >= self.size || try!(self.is_pruned(idx)) {
+			return Ok(None);
+		}
+		let peaks = try!(self.peaks());
+		let mut path = vec![];
+		let mut other_peaks = vec![];
+		for (height, peak_idx, peak) in peaks {
+			if idx >> height == peak_idx {
+				// siblings from the leaf up to the peak of its mountain
+				for h in 0..height {
+					path.push(try!(self.get(h, (idx >> h) ^ 1)));
+				}
+			} else {
+				other_peaks.push(peak);
+			}
+		}
+		Ok(Some(MerkleProof {
+			size: self.size,
+			idx: idx,
+			path: path,
+			peaks: other_peaks,
+		}))
+	}
+
+	/// Whether the leaf at the provided index was pruned.
+	pub fn is_pruned(&self, idx: u64) -> Result<bool, S::Error> {
+		Ok(idx < self.size && try!(self.get(0, idx)) == ZERO_HASH)
+	}
+
+	/// Nodes changed since the range was created, to save them back to the
+	/// source. The ones now having the zero hash can be removed instead.
+	pub fn changes(&self) -> &HashMap<(u8, u64), Hash> {
+		&self.changes
+	}
+
+	fn set_leaf(&mut self, idx: u64, leaf: Hash) -> Result<(), S::Error> {
+		self.changes.insert((0, idx), leaf);
+		// recompute the nodes above as long as their mountain is complete
+		let mut height = 0;
+		let mut idx = idx;
+		while (idx / 2 + 1) << (height + 1) <= self.size {
+			let left = try!(self.get(height, idx & !1));
+			let right = try!(self.get(height, idx | 1));
+			height += 1;
+			idx /= 2;
+			self.changes.insert((height, idx), parent(left, right));
+		}
+		Ok(())
+	}
+
+	fn get(&self, height: u8, idx: u64) -> Result<Hash, S::Error> {
+		if let Some(h) = self.changes.get(&(height, idx)) {
+			return Ok(*h);
+		}
+		self.source.node(height, idx).map(|h| h.unwrap_or(ZERO_HASH))
+	}
+}
+
+/// Proof that a leaf is in a range of the provided size: the siblings of the
+/// nodes from the leaf up to the peak of its mountain, which give that peak,
+/// and the peaks of the other mountains, which all bag into the root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+	/// Number of leaves in the range
+	pub size: u64,
+	/// Index of the leaf
+	pub idx: u64,
+	/// Siblings of the nodes from the leaf up to its peak
+	pub path: Vec<Hash>,
+	/// Peaks of the other mountains, from left to right
+	pub peaks: Vec<Hash>,
+}
+
+impl MerkleProof {
+	/// Whether the proof shows the provided leaf is in a range with the
+	/// provided root.
+	pub fn verify(&self, root: &Hash, leaf: &Hash) -> bool {
+		if self.idx >= self.size || *leaf == ZERO_HASH {
+			return false;
+		}
+		let mut peaks = vec![];
+		let mut others = self.peaks.iter();
+		let mut leaves = 0;
+		for height in (0..64u8).rev() {
+			if self.size & (1u64 << height) == 0 {
+				continue;
+			}
+			if self.idx >> height == leaves >> height {
+				if self.path.len() != height as usize {
+					return false;
+				}
+				let mut node = *leaf;
+				for (h, sibling) in self.path.iter().enumerate() {
+					node = if (self.idx >> h) & 1 == 0 {
+						parent(node, *sibling)
+					} else {
+						parent(*sibling, node)
+					};
+				}
+				peaks.push(node);
+			} else {
+				match others.next() {
+					Some(peak) => peaks.push(*peak),
+					None => return false,
+				}
+			}
+			leaves += 1u64 << height;
+		}
+		others.next().is_none() && bag_peaks(&peaks) == *root
+	}
+}
+
+impl Writeable for MerkleProof {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		ser_multiwrite!(writer,
+		                [write_u64, self.size],
+		                [write_u64, self.idx],
+		                [write_u8, self.path.len() as u8],
+		                [write_u8, self.peaks.len() as u8]);
+		for h in self.path.iter().chain(self.peaks.iter()) {
+			try!(h.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<MerkleProof> for MerkleProof {
+	fn read(reader: &mut Reader) -> Result<MerkleProof, ser::Error> {
+		let (size, idx, path_len, peaks_len) =
+			ser_multiread!(reader, read_u64, read_u64, read_u8, read_u8);
+		// no range has more than 64 mountains, nor any mountain a height over 64
+		if path_len > 64 || peaks_len > 64 {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let path = try!((0..path_len).map(|_| Hash::read(reader)).collect());
+		let peaks = try!((0..peaks_len).map(|_| Hash::read(reader)).collect());
+		Ok(MerkleProof {
+			size: size,
+			idx: idx,
+			path: path,
+			peaks: peaks,
+		})
+	}
+}
+
+/// Bags the provided peaks, ordered from left to right, into a single root.
+/// Each peak is paired with the bag of all the peaks on its right.
+pub fn bag_peaks(peaks: &[Hash]) -> Hash {
+	let mut peaks = peaks.iter().rev();
+	match peaks.next() {
+		// same as an empty Merkle tree
+		None => [].hash(),
+		Some(last) => peaks.fold(*last, |acc, peak| HPair(*peak, acc).hash()),
+	}
+}
+
+/// Parent of two sibling nodes, the zero hash if both are pruned.
+pub fn parent(left: Hash, right: Hash) -> Hash {
+	if left == ZERO_HASH && right == ZERO_HASH {
+		ZERO_HASH
+	} else {
+		HPair(left, right).hash()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::hash::{Hash, Hashed, ZERO_HASH};
+
+	fn leaf(n: u8) -> Hash {
+		[n].hash()
+	}
+
+	fn range(n: u8) -> MountainRange<()> {
+		let mut mmr = MountainRange::new((), 0);
+		for i in 0..n {
+			assert_eq!(mmr.push(leaf(i)).unwrap(), i as u64);
+		}
+		mmr
+	}
+
+	#[test]
+	fn push_changes_root() {
+		let mut mmr = range(0);
+		let mut roots = vec![mmr.root().unwrap()];
+		for i in 0..7 {
+			mmr.push(leaf(i)).unwrap();
+			let root = mmr.root().unwrap();
+			assert!(!roots.contains(&root));
+			roots.push(root);
+		}
+		assert_eq!(mmr.size(), 7);
+		assert_eq!(range(7).root().unwrap(), mmr.root().unwrap());
+	}
+
+	#[test]
+	fn prune_and_restore() {
+		let mut mmr = range(5);
+		let root = mmr.root().unwrap();
+		mmr.prune(2).unwrap();
+		assert!(mmr.root().unwrap() != root);
+		mmr.restore(2, leaf(2)).unwrap();
+		assert_eq!(mmr.root().unwrap(), root);
+
+		// a mountain with all its leaves pruned is just zeros
+		mmr.prune(0).unwrap();
+		mmr.prune(1).unwrap();
+		assert_eq!(mmr.changes()[&(1, 0)], ZERO_HASH);
+		mmr.prune(3).unwrap();
+		assert!(mmr.changes()[&(2, 0)] != ZERO_HASH);
+		mmr.prune(2).unwrap();
+		assert_eq!(mmr.changes()[&(2, 0)], ZERO_HASH);
+	}
+
+	#[test]
+	fn peaks_bag_into_root() {
+		let mmr = range(11);
+		let peaks = mmr.peaks().unwrap();
+		let positions = map_vec!(peaks, |&(height, idx, _)| (height, idx));
+		assert_eq!(positions, vec![(3, 0), (1, 4), (0, 10)]);
+		assert_eq!(peaks[2].2, leaf(10));
+		assert_eq!(peaks[1].2, parent(leaf(8), leaf(9)));
+		let hashes = map_vec!(peaks, |&(_, _, h)| h);
+		assert_eq!(bag_peaks(&hashes), mmr.root().unwrap());
+		assert_eq!(range(0).peaks().unwrap(), vec![]);
+
+		// nodes outside of the range are just zeros
+		assert_eq!(mmr.node(0, 10).unwrap(), leaf(10));
+		assert_eq!(mmr.node(0, 11).unwrap(), ZERO_HASH);
+		assert_eq!(mmr.node(2, 2).unwrap(), ZERO_HASH);
+		assert_eq!(mmr.node(64, 0).unwrap(), ZERO_HASH);
+	}
+
+	#[test]
+	fn pruned_leaves() {
+		let mut mmr = range(4);
+		assert!(!mmr.is_pruned(1).unwrap());
+		mmr.prune(1).unwrap();
+		assert!(mmr.is_pruned(1).unwrap());
+		assert!(!mmr.is_pruned(4).unwrap());
+
+		// pushing elements is the same as pushing their hash
+		let mut mmr2 = range(4);
+		mmr2.push_elmt(&[4u8][..]).unwrap();
+		let mut mmr3 = range(4);
+		mmr3.push(leaf(4)).unwrap();
+		assert_eq!(mmr2.root().unwrap(), mmr3.root().unwrap());
+	}
+
+	#[test]
+	fn merkle_proofs() {
+		for n in 1..20 {
+			let mut mmr = range(n);
+			let root = mmr.root().unwrap();
+			for i in 0..n {
+				let proof = mmr.merkle_proof(i as u64).unwrap().unwrap();
+				assert!(proof.verify(&root, &leaf(i)));
+				assert!(!proof.verify(&root, &leaf(n)));
+				assert!(!proof.verify(&[].hash(), &leaf(i)));
+			}
+			assert!(mmr.merkle_proof(n as u64).unwrap().is_none());
+
+			// still valid with a pruned sibling, not for the pruned leaf
+			if n > 1 {
+				mmr.prune(1).unwrap();
+				let root = mmr.root().unwrap();
+				assert!(mmr.merkle_proof(0).unwrap().unwrap().verify(&root, &leaf(0)));
+				assert!(mmr.merkle_proof(1).unwrap().is_none());
+			}
+		}
+
+		// a proof only holds for the leaf it was made for
+		let mmr = range(7);
+		let root = mmr.root().unwrap();
+		let mut proof = mmr.merkle_proof(4).unwrap().unwrap();
+		proof.idx = 5;
+		assert!(!proof.verify(&root, &leaf(4)));
+		proof.idx = 4;
+		proof.size = 8;
+		assert!(!proof.verify(&root, &leaf(4)));
+	}
+
+	#[test]
+	fn merkle_proof_ser() {
+		let mmr = range(11);
+		let proof = mmr.merkle_proof(9).unwrap().unwrap();
+		let mut vec = Vec::new();
+		ser::serialize(&mut vec, &proof).unwrap();
+		let dproof: MerkleProof = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dproof, proof);
+		assert!(dproof.verify(&mmr.root().unwrap(), &leaf(9)));
+	}
+
+	#[test]
+	fn truncate_undoes_push() {
+		let mut mmr = range(3);
+		let root = mmr.root().unwrap();
+		for i in 3..6 {
+			mmr.push(leaf(i)).unwrap();
+		}
+		mmr.truncate(3);
+		assert_eq!(mmr.size(), 3);
+		assert_eq!(mmr.root().unwrap(), root);
+		assert_eq!(mmr.changes()[&(2, 0)], ZERO_HASH);
+		mmr.push(leaf(3)).unwrap();
+		assert_eq!(mmr.root().unwrap(), range(4).root().unwrap());
+	}
+}
@@ -15,20 +15,23 @@
 //! Core types
 
 pub mod block;
+pub mod compact_block;
 pub mod hash;
+pub mod mmr;
 pub mod target;
 pub mod transaction;
 #[allow(dead_code)]
 
-use std::fmt;
+use std::{cmp, fmt, str};
 use std::cmp::Ordering;
 
 use secp::{self, Secp256k1};
 use secp::pedersen::*;
 
 use consensus::PROOFSIZE;
-pub use self::block::{Block, BlockHeader};
-pub use self::transaction::{Transaction, Input, Output, TxProof};
+pub use self::block::{Block, BlockHeader, RewardProvider};
+pub use self::compact_block::{CompactBlock, ShortId};
+pub use self::transaction::{Transaction, Input, Output, OutputFeatures, TxProof};
 use self::hash::{Hash, Hashed, HashWriter, ZERO_HASH};
 use ser::{Writeable, Writer, Reader, Readable, Error};
 
@@ -36,15 +39,19 @@ use ser::{Writeable, Writer, Reader, Readable, Error};
 /// commitments. Handles the collection of the commitments as well as their
 /// summing, taking potential explicit overages of fees into account.
 pub trait Committed {
-	/// Gathers commitments and sum them.
-	fn sum_commitments(&self, secp: &Secp256k1) -> Result<Commitment, secp::Error> {
-		// first, verify each range proof
+	/// Verifies the range proof of each output.
+	fn verify_proofs(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
 		let ref outputs = self.outputs_committed();
 		for output in *outputs {
 			try!(output.verify_proof(secp))
 		}
+		Ok(())
+	}
 
-		// then gather the commitments
+	/// Gathers commitments and sum them. Range proofs aren't verified, see
+	/// verify_proofs.
+	fn sum_commitments(&self, secp: &Secp256k1) -> Result<Commitment, secp::Error> {
+		// gather the commitments
 		let mut input_commits = filter_map_vec!(self.inputs_committed(), |inp| inp.commitment());
 		let mut output_commits = filter_map_vec!(self.outputs_committed(), |out| out.commitment());
 
@@ -134,8 +141,63 @@ impl Proof {
 	pub fn to_difficulty(self) -> target::Difficulty {
 		target::Difficulty::from_hash(&self.hash())
 	}
+
+	/// Writes the proof with its nonces packed on as many bits as the largest
+	/// takes, preceded by that number of bits. Nonces being under the
+	/// easiness of their graph, that's about its size shift rather than the
+	/// full 32 bits.
+	pub fn write_packed(&self, writer: &mut Writer) -> Result<(), Error> {
+		let bits = self.packed_bits();
+		try!(writer.write_u8(bits));
+		::ser::write_packed(writer, &self.0, bits)
+	}
+
+	/// Reads a proof written by `write_packed`.
+	pub fn read_packed(reader: &mut Reader) -> Result<Proof, Error> {
+		let bits = try!(reader.read_u8());
+		let nonces = try!(::ser::read_packed(reader, PROOFSIZE, bits));
+		let mut pow = [0u32; PROOFSIZE];
+		pow.copy_from_slice(&nonces);
+		let proof = Proof(pow);
+		// a single packing for each proof
+		if bits != proof.packed_bits() {
+			return Err(Error::CorruptedData);
+		}
+		Ok(proof)
+	}
+
+	// bits the largest nonce takes, one at least
+	fn packed_bits(&self) -> u8 {
+		let max = self.0.iter().max().cloned().unwrap_or(0);
+		cmp::max(1, 32 - max.leading_zeros()) as u8
+	}
+}
+
+/// Displays the proof as the lowercase hex of its serialization, the
+/// big-endian cycle nonces.
+impl fmt::Display for Proof {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for n in self.0.iter() {
+			try!(write!(f, "{:08x}", n));
+		}
+		Ok(())
+	}
 }
 
+impl str::FromStr for Proof {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Proof, Error> {
+		let v = try!(::ser::from_hex(s));
+		if v.len() != PROOFSIZE * 4 {
+			return Err(Error::CorruptedData);
+		}
+		::ser::deserialize(&mut &v[..])
+	}
+}
+
+impl_serde_string!(Proof);
+
 impl Readable<Proof> for Proof {
 	fn read(reader: &mut Reader) -> Result<Proof, Error> {
 		let mut pow = [0u32; PROOFSIZE];
@@ -155,6 +217,38 @@ impl Writeable for Proof {
 	}
 }
 
+/// Family of the proof of work of a block. Both are Cuckoo Cycles of the same
+/// size, the primary one over a graph fast to generate, leaving memory as the
+/// main cost and so friendly to custom hardware, the secondary one over a
+/// graph whose edges come in blocks of siphashes, trading memory for compute
+/// and favoring GPUs. The difficulty of secondary proofs gets scaled so both
+/// families keep their share of the blocks, see
+/// consensus::next_secondary_scaling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PowFamily {
+	Primary,
+	Secondary,
+}
+
+impl PowFamily {
+	/// Code of the family in serialized headers.
+	pub fn to_u8(&self) -> u8 {
+		match *self {
+			PowFamily::Primary => 0,
+			PowFamily::Secondary => 1,
+		}
+	}
+
+	/// Family of the provided code, if any.
+	pub fn from_u8(code: u8) -> Option<PowFamily> {
+		match code {
+			0 => Some(PowFamily::Primary),
+			1 => Some(PowFamily::Secondary),
+			_ => None,
+		}
+	}
+}
+
 /// Two hashes that will get hashed together in a Merkle tree to build the next
 /// level up.
 struct HPair(Hash, Hash);
@@ -204,7 +298,8 @@ impl MerkleRow {
 #[cfg(test)]
 mod test {
 	use super::*;
-	use core::hash::ZERO_HASH;
+	use consensus::PROOFSIZE;
+	use core::hash::{Hash, Hashed, ZERO_HASH};
 	use secp;
 	use secp::Secp256k1;
 	use secp::key::SecretKey;
@@ -230,6 +325,7 @@ mod test {
 			                               blindkey: skey,
 		                               }],
 		                          vec![Output::OvertOutput {
+			                               features: OutputFeatures::Plain,
 			                               value: 1,
 			                               blindkey: skey,
 		                               }],
@@ -280,6 +376,100 @@ mod test {
 		b.verify(&secp).unwrap();
 	}
 
+	#[test]
+	fn hex_roundtrip() {
+		let h = ZERO_HASH.hash();
+		let hex = h.to_string();
+		assert_eq!(hex.len(), 64);
+		assert_eq!(hex, ::ser::to_hex(&h.0));
+		assert_eq!(hex.parse::<Hash>().unwrap(), h);
+		assert!(hex.to_uppercase().parse::<Hash>().is_err());
+		assert!(hex[2..].parse::<Hash>().is_err());
+		assert!(hex[1..].parse::<Hash>().is_err());
+
+		let mut nonces = [0; PROOFSIZE];
+		for n in 0..PROOFSIZE {
+			nonces[n] = (n as u32) << 20 | 0xf3;
+		}
+		let proof = Proof(nonces);
+		let hex = proof.to_string();
+		assert_eq!(hex, ::ser::to_hex(&::ser::ser_vec(&proof).unwrap()));
+		assert_eq!(hex.parse::<Proof>().unwrap(), proof);
+		assert!(hex[8..].parse::<Proof>().is_err());
+		assert!(format!("{}00", hex).parse::<Proof>().is_err());
+	}
+
+	struct Packed(Proof);
+
+	// a proof packed on the provided number of bits, whatever its nonces
+	struct PackedOn(Proof, u8);
+
+	impl Writeable for PackedOn {
+		fn write(&self, writer: &mut Writer) -> Result<(), Error> {
+			try!(writer.write_u8(self.1));
+			::ser::write_packed(writer, &(self.0).0, self.1)
+		}
+	}
+
+	impl Writeable for Packed {
+		fn write(&self, writer: &mut Writer) -> Result<(), Error> {
+			self.0.write_packed(writer)
+		}
+	}
+
+	impl Readable<Packed> for Packed {
+		fn read(reader: &mut Reader) -> Result<Packed, Error> {
+			Proof::read_packed(reader).map(Packed)
+		}
+	}
+
+	#[test]
+	fn packed_proof() {
+		let mut nonces = [0; PROOFSIZE];
+		for n in 0..PROOFSIZE {
+			nonces[n] = (n as u32) * 12345 + 7;
+		}
+		let proof = Proof(nonces);
+		// the largest nonce takes 19 bits
+		let vec = ::ser::ser_vec(&Packed(proof)).unwrap();
+		assert_eq!(vec[0], 19);
+		assert_eq!(vec.len(), 1 + (PROOFSIZE * 19 + 7) / 8);
+		let Packed(dproof) = ::ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dproof, proof);
+
+		// full width nonces and zeros pack too
+		for proof in &[Proof([u32::max_value(); PROOFSIZE]), Proof::zero()] {
+			let vec = ::ser::ser_vec(&Packed(*proof)).unwrap();
+			let Packed(dproof) = ::ser::deserialize(&mut &vec[..]).unwrap();
+			assert_eq!(dproof, *proof);
+		}
+
+		// only the narrowest packing with zero padding reads
+		let wider = ::ser::ser_vec(&PackedOn(proof, 20)).unwrap();
+		assert!(::ser::deserialize::<Packed>(&mut &wider[..]).is_err());
+		assert!(::ser::ser_vec(&PackedOn(proof, 18)).is_err());
+		let mut padded = vec.clone();
+		*padded.last_mut().unwrap() |= 1;
+		assert!(::ser::deserialize::<Packed>(&mut &padded[..]).is_err());
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn serde_as_hex() {
+		use serde_json;
+
+		let h = ZERO_HASH.hash();
+		let json = serde_json::to_string(&h).unwrap();
+		assert_eq!(json, format!("\"{}\"", h));
+		assert_eq!(serde_json::from_str::<Hash>(&json).unwrap(), h);
+		assert!(serde_json::from_str::<Hash>("\"00\"").is_err());
+
+		let proof = Proof::zero();
+		let json = serde_json::to_string(&proof).unwrap();
+		assert_eq!(json, format!("\"{}\"", proof));
+		assert_eq!(serde_json::from_str::<Proof>(&json).unwrap(), proof);
+	}
+
 	// utility producing a transaction with 2 inputs and a single outputs
 	pub fn tx2i1o<R: Rng>(secp: &Secp256k1, rng: &mut R) -> Transaction {
 		let outh = ZERO_HASH;
@@ -294,6 +484,7 @@ mod test {
 			                      blindkey: SecretKey::new(secp, rng),
 		                      }],
 		                 vec![Output::OvertOutput {
+			                      features: OutputFeatures::Plain,
 			                      value: 20,
 			                      blindkey: SecretKey::new(secp, rng),
 		                      }],
@@ -309,6 +500,7 @@ mod test {
 			                      blindkey: SecretKey::new(secp, rng),
 		                      }],
 		                 vec![Output::OvertOutput {
+			                      features: OutputFeatures::Plain,
 			                      value: 4,
 			                      blindkey: SecretKey::new(secp, rng),
 		                      }],
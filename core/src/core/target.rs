@@ -17,6 +17,7 @@
 //! the related difficulty, defined as the maximum target divided by the hash.
 
 use byteorder::{ByteOrder, BigEndian};
+use std::cmp;
 use std::ops::Add;
 
 use bigint::BigUint;
@@ -48,12 +49,29 @@ impl Difficulty {
 	}
 
 	/// Computes the difficulty from a hash. Divides the maximum target by the
-	/// provided hash.
+	/// provided hash, rounding down, so only good for display and estimates,
+	/// see `is_met_by` to check a hash against a difficulty.
 	pub fn from_hash(h: &Hash) -> Difficulty {
 		let max_target = BigUint::from_bytes_be(&MAX_TARGET);
-		let h_num = BigUint::from_bytes_be(h.to_slice());
+		let h_num = cmp::max(BigUint::from_bytes_be(h.to_slice()), BigUint::new(vec![1]));
 		Difficulty { num: max_target / h_num }
 	}
+
+	/// Whether the provided hash meets the difficulty, being at most the
+	/// maximum target divided by the difficulty. Compares the full 256 bits of
+	/// the hash with the exact target, nothing rounded.
+	pub fn is_met_by(&self, h: &Hash) -> bool {
+		self.is_met_by_scaled(h, 1)
+	}
+
+	/// Same as is_met_by for a hash whose difficulty gets multiplied by the
+	/// provided scaling, as the one of secondary proofs of work does. The
+	/// target gets multiplied instead, before any division.
+	pub fn is_met_by_scaled(&self, h: &Hash, scaling: u32) -> bool {
+		let h_num = BigUint::from_bytes_be(h.to_slice());
+		let max_target = BigUint::from_bytes_be(&MAX_TARGET) * BigUint::new(vec![scaling]);
+		h_num * self.num.clone() <= max_target
+	}
 }
 
 impl Add<Difficulty> for Difficulty {
@@ -78,3 +96,40 @@ impl Readable<Difficulty> for Difficulty {
 		Ok(Difficulty { num: BigUint::from_bytes_be(&data[..]) })
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::hash::{Hash, ZERO_HASH};
+
+	#[test]
+	fn full_target_comparison() {
+		// a hash right under a target the difficulty doesn't divide evenly
+		let mut h = [0; 32];
+		h[0] = 0x08;
+		let h = Hash(h);
+		assert_eq!(Difficulty::from_hash(&h), Difficulty::from_num(1));
+		assert!(Difficulty::from_num(1).is_met_by(&h));
+		assert!(!Difficulty::from_num(2).is_met_by(&h));
+
+		// rounding the difficulty of the hash down before scaling would lose
+		// almost all of a scaling's worth
+		assert!(Difficulty::from_num(19).is_met_by_scaled(&h, 10));
+		assert!(!Difficulty::from_num(20).is_met_by_scaled(&h, 10));
+
+		// agrees with the rounded difficulty when unscaled
+		for i in 1..64u8 {
+			let mut h = [0xff; 32];
+			h[0] = 0;
+			h[1] = i;
+			let h = Hash(h);
+			let diff = Difficulty::from_hash(&h);
+			assert!(diff.is_met_by(&h));
+			assert!(!(diff + Difficulty::one()).is_met_by(&h));
+		}
+
+		// the zero hash meets any difficulty
+		assert!(Difficulty::from_hash(&ZERO_HASH).is_met_by(&ZERO_HASH));
+		assert!(Difficulty::from_num(u32::max_value()).is_met_by(&ZERO_HASH));
+	}
+}
@@ -14,11 +14,16 @@
 
 //! Transactions
 
+use std::cmp;
+use std::collections::HashSet;
+
 use byteorder::{ByteOrder, BigEndian};
 use secp::{self, Secp256k1, Message, Signature};
+use secp::constants::{BULLET_PROOF_SIZE, MAX_PROOF_SIZE};
 use secp::key::SecretKey;
 use secp::pedersen::{RangeProof, Commitment};
 
+use consensus;
 use core::Committed;
 use core::MerkleRow;
 use core::hash::{Hash, Hashed};
@@ -26,8 +31,8 @@ use ser::{self, Reader, Writer, Readable, Writeable};
 
 /// A proof that a transaction sums to zero. Includes both the transaction's
 /// Pedersen commitment and the signature, that guarantees that the commitments
-/// amount to zero. The signature signs the fee, which is retained for
-/// signature validation.
+/// amount to zero. The signature signs the fee, lock height and relative
+/// height, which are retained for signature validation.
 #[derive(Debug, Clone)]
 pub struct TxProof {
 	/// Remainder of the sum of all transaction commitments. If the transaction
@@ -35,52 +40,98 @@ pub struct TxProof {
 	/// is hence a valid public key.
 	pub remainder: Commitment,
 	/// The signature proving the remainder is a valid public key, which signs
-	/// the transaction fee.
+	/// the transaction fee, lock height and relative height.
 	pub sig: Vec<u8>,
 	/// Fee originally included in the transaction this proof is for.
 	pub fee: u64,
+	/// Height of the first block the transaction this proof is for can be
+	/// included in, 0 for any block.
+	pub lock_height: u64,
+	/// Number of blocks no kernel with the same remainder can be in before
+	/// the one with this proof, the block it's in included, so a kernel can
+	/// only be replayed once that many blocks went by. 0 for a plain kernel,
+	/// which can never be on the chain twice.
+	pub relative_height: u64,
 }
 
 impl Writeable for TxProof {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
 		try!(writer.write_fixed_bytes(&self.remainder));
-		try!(writer.write_bytes(&self.sig));
-		writer.write_u64(self.fee)
+		ser_multiwrite!(writer,
+		                [write_bytes, &self.sig],
+		                [write_u64, self.fee],
+		                [write_u64, self.lock_height],
+		                [write_u64, self.relative_height]);
+		Ok(())
 	}
 }
 
 impl Readable<TxProof> for TxProof {
 	fn read(reader: &mut Reader) -> Result<TxProof, ser::Error> {
 		let remainder = try!(Commitment::read(reader));
-		let (sig, fee) = ser_multiread!(reader, read_vec, read_u64);
+		let (sig, fee, lock_height, relative_height) =
+			ser_multiread!(reader, read_vec, read_u64, read_u64, read_u64);
 		Ok(TxProof {
 			remainder: remainder,
 			sig: sig,
 			fee: fee,
+			lock_height: lock_height,
+			relative_height: relative_height,
 		})
 	}
 }
 
 impl TxProof {
 	/// Verify the transaction proof validity. Entails handling the commitment
-	/// as a public key and checking the signature verifies with the fee as
-	/// message.
+	/// as a public key and checking the signature verifies with the fee, lock
+	/// height and relative height as message.
 	pub fn verify(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
-		let msg = try!(Message::from_slice(&u64_to_32bytes(self.fee)));
+		let msg = try!(Message::from_slice(&self.message()));
 		let pubk = try!(self.remainder.to_pubkey(secp));
 		let sig = try!(Signature::from_der(secp, &self.sig));
 		secp.verify(&msg, &sig, &pubk)
 	}
+
+	/// Fee paid by the transaction this proof is for.
+	pub fn fee(&self) -> u64 {
+		self.fee
+	}
+
+	/// Height of the first block the transaction this proof is for can be
+	/// included in.
+	pub fn lock_height(&self) -> u64 {
+		self.lock_height
+	}
+
+	/// Number of blocks before the one with this proof no identical kernel
+	/// can be in, 0 for a plain kernel.
+	pub fn relative_height(&self) -> u64 {
+		self.relative_height
+	}
+
+	// what the signature of the proof signs
+	fn message(&self) -> [u8; 32] {
+		kernel_message(self.fee, self.lock_height, self.relative_height)
+	}
 }
 
 /// A transaction
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Transaction {
 	hash_mem: Option<Hash>,
 	pub fee: u64,
+	/// Height of the first block the transaction can be included in, signed
+	/// along with the fee
+	pub lock_height: u64,
+	/// Number of blocks no identical kernel can be in before the one with
+	/// the transaction, 0 for a plain kernel, see TxProof
+	pub relative_height: u64,
 	pub zerosig: Vec<u8>,
 	pub inputs: Vec<Input>,
 	pub outputs: Vec<Output>,
+	/// Proofs of the transactions aggregated into this one, the fee and
+	/// signature above being those of the transaction aggregated first
+	pub proofs: Vec<TxProof>,
 }
 
 /// Implementation of Writeable for a fully blinded transaction, defines how to
@@ -89,15 +140,21 @@ impl Writeable for Transaction {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
 		ser_multiwrite!(writer,
 		                [write_u64, self.fee],
+		                [write_u64, self.lock_height],
+		                [write_u64, self.relative_height],
 		                [write_bytes, &self.zerosig],
 		                [write_u64, self.inputs.len() as u64],
-		                [write_u64, self.outputs.len() as u64]);
+		                [write_u64, self.outputs.len() as u64],
+		                [write_u64, self.proofs.len() as u64]);
 		for inp in &self.inputs {
 			try!(inp.write(writer));
 		}
 		for out in &self.outputs {
 			try!(out.write(writer));
 		}
+		for proof in &self.proofs {
+			try!(proof.write(writer));
+		}
 		Ok(())
 	}
 }
@@ -106,17 +163,34 @@ impl Writeable for Transaction {
 /// transaction from a binary stream.
 impl Readable<Transaction> for Transaction {
 	fn read(reader: &mut Reader) -> Result<Transaction, ser::Error> {
-		let (fee, zerosig, input_len, output_len) =
-			ser_multiread!(reader, read_u64, read_vec, read_u64, read_u64);
+		let (fee, lock_height, relative_height, zerosig) =
+			ser_multiread!(reader, read_u64, read_u64, read_u64, read_vec);
+		let (input_len, output_len, proof_len) =
+			ser_multiread!(reader, read_u64, read_u64, read_u64);
+		// a transaction too large to ever be valid isn't worth reading, nor one
+		// aggregating more transactions than a block can have
+		if consensus::exceeds_tx_limits(input_len as usize, output_len as usize) ||
+		   proof_len >= consensus::MAX_BLOCK_PROOFS as u64 {
+			return Err(ser::Error::TooLargeReadErr);
+		}
 
-		let inputs = try!((0..input_len).map(|_| Input::read(reader)).collect());
-		let outputs = try!((0..output_len).map(|_| Output::read(reader)).collect());
+		let inputs: Vec<Input> = try!((0..input_len).map(|_| Input::read(reader)).collect());
+		let outputs: Vec<Output> = try!((0..output_len).map(|_| Output::read(reader)).collect());
+		let proofs: Vec<TxProof> = try!((0..proof_len).map(|_| TxProof::read(reader)).collect());
+		// only the canonical ordering is accepted, so a transaction can't be
+		// malleated by reordering it
+		if !sorted_by_hash(&inputs) || !sorted_by_hash(&outputs) || !sorted_by_hash(&proofs) {
+			return Err(ser::Error::CorruptedData);
+		}
 
 		Ok(Transaction {
 			fee: fee,
+			lock_height: lock_height,
+			relative_height: relative_height,
 			zerosig: zerosig,
 			inputs: inputs,
 			outputs: outputs,
+			proofs: proofs,
 			..Default::default()
 		})
 	}
@@ -131,7 +205,8 @@ impl Committed for Transaction {
 		&self.outputs
 	}
 	fn overage(&self) -> i64 {
-		-(self.fee as i64)
+		// a fee over the largest amount can't be paid, the sum won't match
+		-(cmp::min(self.fee(), consensus::MAX_AMOUNT) as i64)
 	}
 }
 
@@ -147,9 +222,12 @@ impl Transaction {
 		Transaction {
 			hash_mem: None,
 			fee: 0,
+			lock_height: 0,
+			relative_height: 0,
 			zerosig: vec![],
 			inputs: vec![],
 			outputs: vec![],
+			proofs: vec![],
 		}
 	}
 
@@ -159,12 +237,53 @@ impl Transaction {
 		Transaction {
 			hash_mem: None,
 			fee: fee,
+			lock_height: 0,
+			relative_height: 0,
 			zerosig: vec![],
 			inputs: inputs,
 			outputs: outputs,
+			proofs: vec![],
 		}
 	}
 
+	/// Fee the transaction pays, along with the transactions aggregated into
+	/// it. More than consensus::MAX_AMOUNT, which can't be paid, if they're
+	/// worth more than any amount can be together.
+	pub fn fee(&self) -> u64 {
+		let fees = Some(self.fee).into_iter().chain(self.proofs.iter().map(|p| p.fee));
+		consensus::sum_amounts(fees).unwrap_or(::std::u64::MAX)
+	}
+
+	/// Height of the first block the transaction can be included in, the
+	/// highest lock height of the transactions aggregated into it.
+	pub fn lock_height(&self) -> u64 {
+		self.proofs.iter().map(|p| p.lock_height).fold(self.lock_height, cmp::max)
+	}
+
+	/// Weight of the transaction, counted against the max block weight once
+	/// in a block.
+	pub fn weight(&self) -> usize {
+		consensus::tx_weight(self.inputs.len(), self.outputs.len())
+	}
+
+	/// Fee the transaction pays per thousand units of its weight, see
+	/// consensus::fee_rate.
+	pub fn fee_rate(&self) -> u64 {
+		consensus::fee_rate(self.fee(), self.weight())
+	}
+
+	/// Whether the transaction pays at least the minimum fee for its weight,
+	/// see consensus::min_fee.
+	pub fn pays_min_fee(&self) -> bool {
+		self.fee() >= consensus::min_fee(self.inputs.len(), self.outputs.len())
+	}
+
+	/// Whether the transaction has more inputs or outputs than consensus allows
+	/// a single transaction, see consensus::exceeds_tx_limits.
+	pub fn exceeds_limits(&self) -> bool {
+		consensus::exceeds_tx_limits(self.inputs.len(), self.outputs.len())
+	}
+
 	/// The hash of a transaction is the Merkle tree of its inputs and outputs
 	/// hashes. None of the rest is required.
 	fn hash(&mut self) -> Hash {
@@ -177,26 +296,32 @@ impl Transaction {
 	/// Takes a transaction and fully blinds it. Following the MW
 	/// algorithm: calculates the commitments for each inputs and outputs
 	/// using the values and blinding factors, takes the blinding factors
-	/// remainder and uses it for an empty signature.
+	/// remainder and uses it for an empty signature. The blinded inputs and
+	/// outputs are sorted by hash, their canonical ordering.
 	pub fn blind(&self, secp: &Secp256k1) -> Result<Transaction, secp::Error> {
 		// we compute the sum of blinding factors to get the k remainder
 		let remainder = try!(self.blind_sum(secp));
 
 		// next, blind the inputs and outputs if they haven't been yet
-		let blind_inputs = map_vec!(self.inputs, |inp| inp.blind(secp));
-		let blind_outputs = map_vec!(self.outputs, |out| out.blind(secp));
+		let mut blind_inputs = map_vec!(self.inputs, |inp| inp.blind(secp));
+		let mut blind_outputs = try_map_vec!(self.outputs, |out| out.blind(secp));
+		blind_inputs.sort_by_key(|inp| inp.hash());
+		blind_outputs.sort_by_key(|out| out.hash());
 
 		// and sign with the remainder so the signature can be checked to match with
 		// the k.G commitment leftover, that should also be the pubkey
-		let msg = try!(Message::from_slice(&u64_to_32bytes(self.fee)));
+		let msg = try!(Message::from_slice(&self.kernel_message()));
 		let sig = try!(secp.sign(&msg, &remainder));
 
 		Ok(Transaction {
 			hash_mem: None,
 			fee: self.fee,
+			lock_height: self.lock_height,
+			relative_height: self.relative_height,
 			zerosig: sig.serialize_der(secp),
 			inputs: blind_inputs,
 			outputs: blind_outputs,
+			proofs: self.proofs.clone(),
 		})
 	}
 
@@ -214,14 +339,20 @@ impl Transaction {
 	/// to verify the embedded signature. The rational is that if the values
 	/// sum to zero as they should in r.G + v.H then only k.G the remainder
 	/// of the sum of r.G should be left. And r.G is the definition of a
-	/// public key generated using r as a private key.
+	/// public key generated using r as a private key. Of an aggregated
+	/// transaction, only the first signature is verified, see verify_kernels.
 	pub fn verify_sig(&self, secp: &Secp256k1) -> Result<TxProof, secp::Error> {
-		let rsum = try!(self.sum_commitments(secp));
+		try!(self.verify_proofs(secp));
+		let mut rsum = try!(self.sum_commitments(secp));
+		// the transactions aggregated in account for their own remainders
+		if !self.proofs.is_empty() {
+			rsum = try!(secp.commit_sum(vec![rsum], map_vec!(self.proofs, |p| p.remainder)));
+		}
 
 		// pretend the sum is a public key (which it is, being of the form r.G) and
 		// verify the transaction sig with it
 		let pubk = try!(rsum.to_pubkey(secp));
-		let msg = try!(Message::from_slice(&u64_to_32bytes(self.fee)));
+		let msg = try!(Message::from_slice(&self.kernel_message()));
 		let sig = try!(Signature::from_der(secp, &self.zerosig));
 		try!(secp.verify(&msg, &sig, &pubk));
 
@@ -229,8 +360,193 @@ impl Transaction {
 			remainder: rsum,
 			sig: self.zerosig.clone(),
 			fee: self.fee,
+			lock_height: self.lock_height,
+			relative_height: self.relative_height,
 		})
 	}
+
+	// what the transaction signature signs
+	fn kernel_message(&self) -> [u8; 32] {
+		kernel_message(self.fee, self.lock_height, self.relative_height)
+	}
+}
+
+impl Transaction {
+	/// Verifies the signatures of the transaction and of all the transactions
+	/// aggregated into it, giving their proofs.
+	pub fn verify_kernels(&self, secp: &Secp256k1) -> Result<Vec<TxProof>, secp::Error> {
+		let mut proofs = vec![try!(self.verify_sig(secp))];
+		for proof in &self.proofs {
+			try!(proof.verify(secp));
+			proofs.push(proof.clone());
+		}
+		Ok(proofs)
+	}
+
+	/// Aggregates valid transactions into a single one, cancelling the outputs
+	/// spent by other inputs and keeping the proofs of all transactions.
+	pub fn aggregate(txs: Vec<Transaction>, secp: &Secp256k1) -> Result<Transaction, secp::Error> {
+		let mut inputs = vec![];
+		let mut outputs = vec![];
+		let mut proofs = vec![];
+		for tx in &txs {
+			proofs.extend(try!(tx.verify_kernels(secp)));
+			inputs.extend_from_slice(&tx.inputs);
+			outputs.extend_from_slice(&tx.outputs);
+		}
+		Ok(Transaction::with_proofs(inputs, outputs, proofs))
+	}
+
+	/// Takes the provided transactions, each aggregated into this one, back
+	/// out of it. Transactions with outputs spent by the others in the
+	/// aggregate can't be taken out, their outputs being gone.
+	pub fn deaggregate(&self,
+	                   txs: &[Transaction],
+	                   secp: &Secp256k1)
+	                   -> Result<Transaction, secp::Error> {
+		let mut removed_inputs = HashSet::new();
+		let mut removed_outputs = HashSet::new();
+		let mut removed_proofs = HashSet::new();
+		for tx in txs {
+			removed_inputs.extend(tx.inputs.iter().map(|inp| inp.hash()));
+			removed_outputs.extend(tx.outputs.iter().map(|out| out.hash()));
+			for proof in try!(tx.verify_kernels(secp)) {
+				removed_proofs.insert(proof.hash());
+			}
+		}
+		let inputs = self.inputs
+			.iter()
+			.filter(|inp| !removed_inputs.contains(&inp.hash()))
+			.cloned()
+			.collect();
+		let outputs = self.outputs
+			.iter()
+			.filter(|out| !removed_outputs.contains(&out.hash()))
+			.cloned()
+			.collect();
+		let proofs = try!(self.verify_kernels(secp))
+			.into_iter()
+			.filter(|proof| !removed_proofs.contains(&proof.hash()))
+			.collect::<Vec<_>>();
+		if proofs.is_empty() {
+			return Err(secp::Error::IncorrectCommitSum);
+		}
+		let tx = Transaction::with_proofs(inputs, outputs, proofs);
+		try!(tx.verify_kernels(secp));
+		Ok(tx)
+	}
+
+	// transaction with the provided inputs, outputs cut-through and sorted,
+	// its signature and fee from the first of the provided proofs
+	fn with_proofs(inputs: Vec<Input>,
+	               outputs: Vec<Output>,
+	               mut proofs: Vec<TxProof>)
+	               -> Transaction {
+		let (mut inputs, mut outputs) = cut_through(&inputs, &outputs);
+		inputs.sort_by_key(|inp| inp.hash());
+		outputs.sort_by_key(|out| out.hash());
+		let (fee, lock_height, relative_height, zerosig) = if proofs.is_empty() {
+			(0, 0, 0, vec![])
+		} else {
+			let first = proofs.remove(0);
+			(first.fee, first.lock_height, first.relative_height, first.sig)
+		};
+		proofs.sort_by_key(|proof| proof.hash());
+		Transaction {
+			hash_mem: None,
+			fee: fee,
+			lock_height: lock_height,
+			relative_height: relative_height,
+			zerosig: zerosig,
+			inputs: inputs,
+			outputs: outputs,
+			proofs: proofs,
+		}
+	}
+}
+
+/// Builds a transaction out of the outputs it spends and creates, along with
+/// their values and blinding factors, its fee, lock height and relative
+/// height. What gets built is fully blinded and signed, checked to be valid.
+pub struct Builder {
+	inputs: Vec<Input>,
+	outputs: Vec<Output>,
+	fee: u64,
+	lock_height: u64,
+	relative_height: u64,
+}
+
+impl Default for Builder {
+	fn default() -> Builder {
+		Builder::new()
+	}
+}
+
+impl Builder {
+	/// A builder for a transaction without any input or output, nor fee,
+	/// lock height or relative height.
+	pub fn new() -> Builder {
+		Builder {
+			inputs: vec![],
+			outputs: vec![],
+			fee: 0,
+			lock_height: 0,
+			relative_height: 0,
+		}
+	}
+
+	/// Spends the output with the provided hash, of the provided value and
+	/// blinding factor.
+	pub fn input(mut self, output: Hash, value: u64, blindkey: SecretKey) -> Builder {
+		self.inputs.push(Input::OvertInput {
+			output: output,
+			value: value,
+			blindkey: blindkey,
+		});
+		self
+	}
+
+	/// Creates an output of the provided value and blinding factor.
+	pub fn output(mut self, value: u64, blindkey: SecretKey) -> Builder {
+		self.outputs.push(Output::OvertOutput {
+			features: OutputFeatures::Plain,
+			value: value,
+			blindkey: blindkey,
+		});
+		self
+	}
+
+	/// Sets the fee the transaction pays, which its signature signs.
+	pub fn fee(mut self, fee: u64) -> Builder {
+		self.fee = fee;
+		self
+	}
+
+	/// Sets the height of the first block the transaction can be included
+	/// in, which its signature signs too.
+	pub fn lock_height(mut self, lock_height: u64) -> Builder {
+		self.lock_height = lock_height;
+		self
+	}
+
+	/// Sets the number of blocks before the one with the transaction no
+	/// identical kernel can be in, also signed. Lets the same kernel be
+	/// replayed once that many blocks went by, like payment channels need.
+	pub fn relative_height(mut self, relative_height: u64) -> Builder {
+		self.relative_height = relative_height;
+		self
+	}
+
+	/// Blinds and signs the transaction, which fails if its inputs don't sum
+	/// up to its outputs and fee.
+	pub fn build(self, secp: &Secp256k1) -> Result<Transaction, secp::Error> {
+		let mut tx = Transaction::new(self.inputs, self.outputs, self.fee);
+		tx.lock_height = self.lock_height;
+		tx.relative_height = self.relative_height;
+		let tx = try!(tx.blind(secp));
+		try!(tx.verify_sig(secp));
+		Ok(tx)
+	}
 }
 
 /// A transaction input, mostly a reference to an output being spent by the
@@ -296,42 +612,131 @@ impl Input {
 	}
 }
 
+/// Features of an output, telling the block reward apart from the outputs of
+/// transactions. Serialized as a byte ahead of the output commitment, so the
+/// output hash commits to them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFeatures {
+	/// Output of a transaction
+	Plain,
+	/// Output of the block reward, only spendable once it's mature
+	Coinbase,
+}
+
+impl Writeable for OutputFeatures {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		writer.write_u8(match *self {
+			OutputFeatures::Plain => 0,
+			OutputFeatures::Coinbase => 1,
+		})
+	}
+}
+
+impl Readable<OutputFeatures> for OutputFeatures {
+	fn read(reader: &mut Reader) -> Result<OutputFeatures, ser::Error> {
+		match try!(reader.read_u8()) {
+			0 => Ok(OutputFeatures::Plain),
+			1 => Ok(OutputFeatures::Coinbase),
+			f => {
+				Err(ser::Error::UnexpectedData {
+					expected: vec![0, 1],
+					received: vec![f],
+				})
+			}
+		}
+	}
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Output {
 	BlindOutput {
+		features: OutputFeatures,
 		commit: Commitment,
 		proof: RangeProof,
 	},
-	OvertOutput { value: u64, blindkey: SecretKey },
+	OvertOutput {
+		features: OutputFeatures,
+		value: u64,
+		blindkey: SecretKey,
+	},
 }
 
+/// Serialization version of an output with a bulletproof, written after its
+/// commitment and followed by the proof, always BULLET_PROOF_SIZE long.
+/// Outputs from before bulletproofs have the length of their range proof
+/// there instead, whose first byte is always 0 as no range proof is that long.
+pub const BULLET_PROOF_OUTPUT_VERSION: u8 = 1;
+
 /// Implementation of Writeable for a transaction Output, defines how to write
 /// an Output as binary.
 impl Writeable for Output {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
-		// The hash of an output is only the hash of its commitment.
+		// The hash of an output is only the hash of its features and commitment.
+		try!(self.features().write(writer));
 		try!(writer.write_fixed_bytes(&self.commitment().unwrap()));
 		if writer.serialization_mode() == ser::SerializationMode::Full {
-			try!(writer.write_bytes(&self.proof().unwrap().bytes()))
+			let proof = self.proof().unwrap();
+			if is_bullet_proof(&proof) {
+				try!(writer.write_u8(BULLET_PROOF_OUTPUT_VERSION));
+				try!(writer.write_fixed_bytes(&proof.bytes()));
+			} else {
+				try!(writer.write_bytes(&proof.bytes()));
+			}
 		}
 		Ok(())
 	}
 }
 
 /// Implementation of Readable for a transaction Output, defines how to read
-/// an Output from a binary stream.
+/// an Output from a binary stream, with a bulletproof or the range proof of
+/// an output from before those.
 impl Readable<Output> for Output {
 	fn read(reader: &mut Reader) -> Result<Output, ser::Error> {
+		let features = try!(OutputFeatures::read(reader));
 		let commit = try!(Commitment::read(reader));
-		let proof = try!(RangeProof::read(reader));
+		let bytes = match try!(reader.read_u8()) {
+			BULLET_PROOF_OUTPUT_VERSION => try!(reader.read_fixed_bytes(BULLET_PROOF_SIZE)),
+			0 => {
+				// the rest of the length of the range proof
+				let len = try!(reader.read_fixed_bytes(7))
+					.iter()
+					.fold(0, |len, &b| len << 8 | b as usize);
+				if len > MAX_PROOF_SIZE {
+					return Err(ser::Error::TooLargeReadErr);
+				}
+				try!(reader.read_fixed_bytes(len))
+			}
+			v => {
+				return Err(ser::Error::UnexpectedData {
+					expected: vec![BULLET_PROOF_OUTPUT_VERSION],
+					received: vec![v],
+				})
+			}
+		};
+		let mut proof = [0; MAX_PROOF_SIZE];
+		proof[..bytes.len()].copy_from_slice(&bytes);
 		Ok(Output::BlindOutput {
+			features: features,
 			commit: commit,
-			proof: proof,
+			proof: RangeProof {
+				proof: proof,
+				plen: bytes.len(),
+			},
 		})
 	}
 }
 
 impl Output {
+	pub fn features(&self) -> OutputFeatures {
+		match self {
+			&Output::BlindOutput { features, .. } => features,
+			&Output::OvertOutput { features, .. } => features,
+		}
+	}
+	/// Whether the output is the reward of the block it's in.
+	pub fn is_coinbase(&self) -> bool {
+		self.features() == OutputFeatures::Coinbase
+	}
 	pub fn commitment(&self) -> Option<Commitment> {
 		match self {
 			&Output::BlindOutput { commit, .. } => Some(commit),
@@ -350,23 +755,28 @@ impl Output {
 			_ => None,
 		}
 	}
-	pub fn blind(&self, secp: &Secp256k1) -> Output {
+	pub fn blind(&self, secp: &Secp256k1) -> Result<Output, secp::Error> {
 		match self {
-			&Output::OvertOutput { value, blindkey } => {
-				let commit = secp.commit(value, blindkey).unwrap();
-				let rproof = secp.range_proof(0, value, blindkey, commit);
-				Output::BlindOutput {
+			&Output::OvertOutput { features, value, blindkey } => {
+				let commit = try!(secp.commit(value, blindkey));
+				let rproof = try!(secp.bullet_proof(value, blindkey));
+				Ok(Output::BlindOutput {
+					features: features,
 					commit: commit,
 					proof: rproof,
-				}
+				})
 			}
-			_ => *self,
+			_ => Ok(*self),
 		}
 	}
-	/// Validates the range proof using the commitment
+	/// Validates the range proof using the commitment, a bulletproof or the
+	/// range proof of an output from before those.
 	pub fn verify_proof(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
 		match self {
-			&Output::BlindOutput { commit, proof } => {
+			&Output::BlindOutput { commit, proof, .. } if is_bullet_proof(&proof) => {
+				secp.verify_bullet_proof(commit, proof)
+			}
+			&Output::BlindOutput { commit, proof, .. } => {
 				secp.verify_range_proof(commit, proof).map(|_| ())
 			}
 			_ => Ok(()),
@@ -374,6 +784,20 @@ impl Output {
 	}
 }
 
+/// Hash of the output with the provided features and commitment, the same as
+/// the hash of the output itself as it doesn't cover the range proof.
+pub fn output_hash(features: OutputFeatures, commit: &Commitment) -> Hash {
+	let mut bytes = ser::ser_vec(&features).unwrap();
+	bytes.extend_from_slice(commit.bytes());
+	bytes[..].hash()
+}
+
+/// Whether the range proof is a bulletproof rather than one from before
+/// those, which are always a lot longer.
+pub fn is_bullet_proof(proof: &RangeProof) -> bool {
+	proof.len() == BULLET_PROOF_SIZE
+}
+
 /// Utility function to calculate the Merkle root of vectors of inputs and
 /// outputs.
 pub fn merkle_inputs_outputs(inputs: &Vec<Input>, outputs: &Vec<Output>) -> Hash {
@@ -382,22 +806,58 @@ pub fn merkle_inputs_outputs(inputs: &Vec<Input>, outputs: &Vec<Output>) -> Hash
 	MerkleRow::new(all_hs).root()
 }
 
-fn u64_to_32bytes(n: u64) -> [u8; 32] {
+/// Cut-through of inputs and outputs, eliminating any output spent by one of
+/// the inputs along with that input. Stable with respect to the order of the
+/// inputs and outputs left.
+pub fn cut_through(inputs: &[Input], outputs: &[Output]) -> (Vec<Input>, Vec<Output>) {
+	// build a set of all output hashes
+	let mut out_set = HashSet::new();
+	for out in outputs {
+		out_set.insert(out.hash());
+	}
+	// removes from the set any hash referenced by an input, keeps the inputs that
+	// don't have a match
+	let mut new_inputs = vec![];
+	for inp in inputs {
+		if !out_set.remove(&inp.output_hash()) {
+			new_inputs.push(*inp);
+		}
+	}
+	// we got ourselves a keep list in that set
+	let new_outputs = outputs.iter()
+		.filter(|out| out_set.contains(&(out.hash())))
+		.map(|&out| out)
+		.collect::<Vec<Output>>();
+	(new_inputs, new_outputs)
+}
+
+/// Whether the items are in their canonical ordering, sorted by hash. Used for
+/// inputs, outputs and proofs of transactions and blocks.
+pub fn sorted_by_hash<T: Hashed>(items: &[T]) -> bool {
+	items.windows(2).all(|w| w[0].hash() <= w[1].hash())
+}
+
+// message a transaction signature signs, its fee without lock height or
+// relative height as before those so the signatures from then still verify
+fn kernel_message(fee: u64, lock_height: u64, relative_height: u64) -> [u8; 32] {
 	let mut bytes = [0; 32];
-	BigEndian::write_u64(&mut bytes[24..32], n);
+	BigEndian::write_u64(&mut bytes[8..16], relative_height);
+	BigEndian::write_u64(&mut bytes[16..24], lock_height);
+	BigEndian::write_u64(&mut bytes[24..32], fee);
 	bytes
 }
 
 #[cfg(test)]
 mod test {
 	use super::*;
+	use core::{Block, BlockHeader};
 	use core::hash::Hashed;
 	use core::hash::ZERO_HASH;
 	use core::test::{tx1i1o, tx2i1o};
 	use ser::{deserialize, serialize};
 
 	use secp::{self, Secp256k1};
-	use secp::key::SecretKey;
+	use secp::key::{self, SecretKey};
 	use rand::os::OsRng;
 
 	fn new_secp() -> Secp256k1 {
@@ -413,8 +873,8 @@ mod test {
 		let btx = tx.blind(&secp).unwrap();
 		let mut vec = Vec::new();
 		serialize(&mut vec, &btx).expect("serialized failed");
-		assert!(vec.len() > 5320);
-		assert!(vec.len() < 5340);
+		assert!(vec.len() > 900);
+		assert!(vec.len() < 920);
 	}
 
 	#[test]
@@ -456,33 +916,271 @@ mod test {
 		assert_eq!(dtx.hash(), dtx2.hash());
 	}
 
+	#[test]
+	fn tx_fees() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut tx = tx2i1o(secp, &mut rng);
+		assert_eq!(tx.weight(), consensus::tx_weight(2, 1));
+		assert_eq!(tx.fee_rate(), 1000 / tx.weight() as u64);
+		assert!(tx.pays_min_fee());
+
+		// the proof keeps the fee of its transaction
+		let btx = tx.blind(&secp).unwrap();
+		assert_eq!(btx.verify_sig(&secp).unwrap().fee(), tx.fee());
+
+		tx.fee = 0;
+		assert!(!tx.pays_min_fee());
+		for _ in 0..100 {
+			tx.outputs.push(tx.outputs[0]);
+		}
+		tx.fee = consensus::MIN_FEE;
+		assert!(!tx.pays_min_fee());
+		tx.fee = consensus::min_fee(2, 101);
+		assert!(tx.pays_min_fee());
+		assert_eq!(tx.fee_rate(), consensus::FEE_PER_KILO_WEIGHT);
+	}
+
+	#[test]
+	fn tx_limits() {
+		let tx = Transaction::new(vec![], vec![], 1);
+		assert!(!tx.exceeds_limits());
+
+		// a transaction claiming one input too many doesn't even get read
+		let mut vec = Vec::new();
+		serialize(&mut vec, &tx).expect("serialization failed");
+		BigEndian::write_u64(&mut vec[32..40], consensus::MAX_TX_INPUTS as u64 + 1);
+		match deserialize::<Transaction>(&mut &vec[..]) {
+			Err(ser::Error::TooLargeReadErr) => {}
+			Err(e) => panic!("unexpected error {:?}", e),
+			Ok(_) => panic!("too large transaction read"),
+		}
+	}
+
+	#[test]
+	// blinding sorts the outputs, only read in that order
+	fn tx_canonical_order() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let tx = Transaction::new(vec![Input::OvertInput {
+			                              output: ZERO_HASH,
+			                              value: 10,
+			                              blindkey: SecretKey::new(secp, &mut rng),
+		                              }],
+		                          vec![Output::OvertOutput {
+			                               features: OutputFeatures::Plain,
+			                               value: 4,
+			                               blindkey: SecretKey::new(secp, &mut rng),
+		                               },
+		                               Output::OvertOutput {
+			                               features: OutputFeatures::Plain,
+			                               value: 5,
+			                               blindkey: SecretKey::new(secp, &mut rng),
+		                               }],
+		                          1);
+		let mut btx = tx.blind(&secp).unwrap();
+		assert!(btx.outputs[0].hash() < btx.outputs[1].hash());
+		let mut vec = Vec::new();
+		serialize(&mut vec, &btx).expect("serialization failed");
+		assert!(deserialize::<Transaction>(&mut &vec[..]).is_ok());
+
+		btx.outputs.swap(0, 1);
+		let mut vec = Vec::new();
+		serialize(&mut vec, &btx).expect("serialization failed");
+		match deserialize::<Transaction>(&mut &vec[..]) {
+			Err(ser::Error::CorruptedData) => {}
+			Err(e) => panic!("unexpected error {:?}", e),
+			Ok(_) => panic!("unsorted transaction read"),
+		}
+	}
+
+	#[test]
+	fn build_tx() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let skey = SecretKey::new(secp, &mut rng);
+		let tx = Builder::new()
+			.input(ZERO_HASH, 10, skey)
+			.output(6, SecretKey::new(secp, &mut rng))
+			.output(3, SecretKey::new(secp, &mut rng))
+			.fee(1)
+			.build(secp)
+			.unwrap();
+		assert_eq!(tx.fee(), 1);
+		assert_eq!(tx.inputs.len(), 1);
+		assert_eq!(tx.outputs.len(), 2);
+		assert!(tx.inputs[0].commitment().is_some());
+		assert!(sorted_by_hash(&tx.outputs));
+		tx.verify_sig(secp).unwrap();
+
+		// amounts that don't add up don't make a transaction
+		let unbalanced = Builder::new()
+			.input(ZERO_HASH, 10, skey)
+			.output(6, SecretKey::new(secp, &mut rng))
+			.fee(1)
+			.build(secp);
+		assert!(unbalanced.is_err());
+	}
+
+	#[test]
+	// the lock height is signed along with the fee
+	fn lock_height() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut tx = Builder::new()
+			.input(ZERO_HASH, 10, SecretKey::new(secp, &mut rng))
+			.output(9, SecretKey::new(secp, &mut rng))
+			.fee(1)
+			.lock_height(5)
+			.build(secp)
+			.unwrap();
+		assert_eq!(tx.lock_height(), 5);
+		let proof = tx.verify_sig(secp).unwrap();
+		assert_eq!(proof.lock_height(), 5);
+		proof.verify(secp).unwrap();
+
+		let mut vec = Vec::new();
+		serialize(&mut vec, &proof).expect("serialization failed");
+		let dproof: TxProof = deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dproof.lock_height(), 5);
+		dproof.verify(secp).unwrap();
+
+		tx.lock_height = 0;
+		assert!(tx.verify_sig(secp).is_err());
+		let mut unlocked = proof.clone();
+		unlocked.lock_height = 0;
+		assert!(unlocked.verify(secp).is_err());
+	}
+
+	#[test]
+	fn relative_height() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let mut tx = Builder::new()
+			.input(ZERO_HASH, 10, SecretKey::new(secp, &mut rng))
+			.output(9, SecretKey::new(secp, &mut rng))
+			.fee(1)
+			.relative_height(3)
+			.build(secp)
+			.unwrap();
+		let proof = tx.verify_sig(secp).unwrap();
+		assert_eq!(proof.relative_height(), 3);
+		assert_eq!(proof.lock_height(), 0);
+
+		let mut vec = Vec::new();
+		serialize(&mut vec, &tx).expect("serialization failed");
+		let dtx: Transaction = deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dtx.relative_height, 3);
+		let mut vec = Vec::new();
+		serialize(&mut vec, &proof).expect("serialization failed");
+		let dproof: TxProof = deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dproof.relative_height(), 3);
+		dproof.verify(secp).unwrap();
+
+		// the relative height is signed, a plain kernel can't be made out of it
+		tx.relative_height = 0;
+		assert!(tx.verify_sig(secp).is_err());
+		let mut plain = proof.clone();
+		plain.relative_height = 0;
+		assert!(plain.verify(secp).is_err());
+	}
+
+	#[test]
+	fn aggregate_deaggregate() {
+		let mut rng = OsRng::new().unwrap();
+		let ref secp = new_secp();
+
+		let (key_a, key_b, key_c) = (SecretKey::new(secp, &mut rng),
+		                             SecretKey::new(secp, &mut rng),
+		                             SecretKey::new(secp, &mut rng));
+		let a = Builder::new()
+			.input(ZERO_HASH, 10, SecretKey::new(secp, &mut rng))
+			.output(9, key_a)
+			.fee(1)
+			.build(secp)
+			.unwrap();
+		let b = Builder::new()
+			.input(a.outputs[0].hash(), 9, key_a)
+			.output(8, key_b)
+			.fee(1)
+			.build(secp)
+			.unwrap();
+		let c_in = SecretKey::new(secp, &mut rng);
+		let c = || {
+			Builder::new()
+				.input(Hash([1; 32]), 6, c_in)
+				.output(4, key_c)
+				.fee(2)
+				.build(secp)
+				.unwrap()
+		};
+
+		// the output of a spent by b is cut through
+		let b_out = b.outputs[0].hash();
+		let mut agg = Transaction::aggregate(vec![a, b, c()], secp).unwrap();
+		assert_eq!(agg.inputs.len(), 2);
+		assert_eq!(agg.outputs.len(), 2);
+		assert_eq!(agg.fee(), 4);
+		assert_eq!(agg.verify_kernels(secp).unwrap().len(), 3);
+		let mut vec = Vec::new();
+		serialize(&mut vec, &agg).expect("serialization failed");
+		let dagg: Transaction = deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dagg.proofs.len(), 2);
+		assert_eq!(dagg.fee(), 4);
+
+		// taking c back out leaves a and b aggregated
+		let ab = agg.deaggregate(&[c()], secp).unwrap();
+		assert_eq!(ab.fee(), 2);
+		assert_eq!(ab.outputs[0].hash(), b_out);
+		// and taking those out leaves c
+		let c2 = agg.deaggregate(&[ab], secp).unwrap();
+		assert_eq!(c2.fee(), 2);
+		assert!(c2.proofs.is_empty());
+		assert_eq!(c2.outputs[0].hash(), c().outputs[0].hash());
+		// nothing is left without any transaction
+		assert!(c2.deaggregate(&[c()], secp).is_err());
+
+		// an aggregated transaction makes a block like any other
+		let skey = SecretKey::new(secp, &mut rng);
+		let blk = Block::new(&BlockHeader::default(), vec![&mut agg], skey).unwrap();
+		assert_eq!(blk.proofs.len(), 4);
+		blk.verify(secp).unwrap();
+	}
+
 	#[test]
 	fn blind_overt_output() {
 		let ref secp = new_secp();
 		let mut rng = OsRng::new().unwrap();
 
 		let oo = Output::OvertOutput {
+			features: OutputFeatures::Plain,
 			value: 42,
 			blindkey: SecretKey::new(secp, &mut rng),
 		};
-		if let Output::BlindOutput { commit, proof } = oo.blind(secp) {
+		if let Output::BlindOutput { commit, proof, .. } = oo.blind(secp).unwrap() {
 			// checks the blind output is sane and verifies
 			assert!(commit.len() > 0);
-			assert!(proof.bytes().len() > 5000);
-			secp.verify_range_proof(commit, proof).unwrap();
+			assert_eq!(proof.bytes().len(), BULLET_PROOF_SIZE);
+			secp.verify_bullet_proof(commit, proof).unwrap();
 
 			// checks that changing the value changes the proof and commitment
 			let oo2 = Output::OvertOutput {
+				features: OutputFeatures::Plain,
 				value: 32,
 				blindkey: SecretKey::new(secp, &mut rng),
 			};
-			if let Output::BlindOutput { commit: c2, proof: p2 } = oo2.blind(secp) {
+			if let Output::BlindOutput { commit: c2, proof: p2, .. } = oo2.blind(secp).unwrap() {
 				assert!(c2 != commit);
 				assert!(p2.bytes() != proof.bytes());
-				secp.verify_range_proof(c2, p2).unwrap();
+				secp.verify_bullet_proof(c2, p2).unwrap();
 
 				// checks that swapping the proofs fails the validation
-				if let Ok(_) = secp.verify_range_proof(commit, p2) {
+				if let Ok(_) = secp.verify_bullet_proof(commit, p2) {
 					panic!("verification successful on wrong proof");
 				}
 			} else {
@@ -493,27 +1191,85 @@ mod test {
 		}
 	}
 
+	#[test]
+	// no proof can be made for a commitment to nothing, blinding an output
+	// errors instead of retrying forever
+	fn blind_null_output() {
+		let ref secp = new_secp();
+
+		assert!(secp.bullet_proof(0, key::ZERO_KEY).is_err());
+		let oo = Output::OvertOutput {
+			features: OutputFeatures::Plain,
+			value: 0,
+			blindkey: key::ZERO_KEY,
+		};
+		assert!(oo.blind(secp).is_err());
+	}
+
 	#[test]
 	fn hash_output() {
 		let ref secp = new_secp();
 		let mut rng = OsRng::new().unwrap();
 
 		let oo = Output::OvertOutput {
+				features: OutputFeatures::Plain,
 				value: 42,
 				blindkey: SecretKey::new(secp, &mut rng),
 			}
-			.blind(secp);
+			.blind(secp)
+			.unwrap();
 		let oo2 = Output::OvertOutput {
+				features: OutputFeatures::Plain,
 				value: 32,
 				blindkey: SecretKey::new(secp, &mut rng),
 			}
-			.blind(secp);
+			.blind(secp)
+			.unwrap();
 		let h = oo.hash();
 		assert!(h != ZERO_HASH);
 		let h2 = oo2.hash();
 		assert!(h != h2);
 	}
 
+	#[test]
+	// the features of an output are serialized and committed to by its hash
+	fn output_features() {
+		let ref secp = new_secp();
+		let mut rng = OsRng::new().unwrap();
+
+		let key = SecretKey::new(secp, &mut rng);
+		let out = Output::OvertOutput {
+				features: OutputFeatures::Plain,
+				value: 42,
+				blindkey: key,
+			}
+			.blind(secp)
+			.unwrap();
+		assert!(!out.is_coinbase());
+		let coinbase = Output::BlindOutput {
+			features: OutputFeatures::Coinbase,
+			commit: out.commitment().unwrap(),
+			proof: out.proof().unwrap(),
+		};
+		assert!(coinbase.is_coinbase());
+		let commit = out.commitment().unwrap();
+		assert!(out.hash() != coinbase.hash());
+		coinbase.verify_proof(secp).unwrap();
+
+		let mut vec = Vec::new();
+		serialize(&mut vec, &coinbase).expect("serialization failed");
+		assert_eq!(vec[0], 1);
+		let dout: Output = deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dout.features(), OutputFeatures::Coinbase);
+		assert_eq!(dout.hash(), coinbase.hash());
+		assert_eq!(output_hash(OutputFeatures::Coinbase, &commit), coinbase.hash());
+		assert_eq!(output_hash(OutputFeatures::Plain, &commit), out.hash());
+
+		// unknown features don't read
+		vec[0] = 2;
+		assert!(deserialize::<Output>(&mut &vec[..]).is_err());
+	}
+
 	#[test]
 	fn blind_tx() {
 		let ref secp = new_secp();
@@ -523,12 +1279,61 @@ mod test {
 		let btx = tx.blind(&secp).unwrap();
 		btx.verify_sig(&secp).unwrap(); // unwrap will panic if invalid
 
-		// checks that the range proof on our blind output is sufficiently hiding
+		// checks our blind output has a bulletproof, which covers the whole
+		// range of values
 		if let Output::BlindOutput { proof, .. } = btx.outputs[0] {
-			let info = secp.range_proof_info(proof);
-			assert!(info.min == 0);
-			assert!(info.max == u64::max_value());
+			assert!(is_bullet_proof(&proof));
 		}
+		btx.outputs[0].verify_proof(&secp).unwrap();
+	}
+
+	#[test]
+	fn legacy_range_proof_output() {
+		let ref secp = new_secp();
+		let mut rng = OsRng::new().unwrap();
+
+		// an output from before bulletproofs, with a range proof
+		let key = SecretKey::new(secp, &mut rng);
+		let commit = secp.commit(42, key).unwrap();
+		let out = Output::BlindOutput {
+			features: OutputFeatures::Plain,
+			commit: commit,
+			proof: secp.range_proof(0, 42, key, commit),
+		};
+		let mut vec = Vec::new();
+		serialize(&mut vec, &out).expect("serialization failed");
+		assert_eq!(vec[34], 0);
+		let dout: Output = deserialize(&mut &vec[..]).unwrap();
+		assert!(!is_bullet_proof(&dout.proof().unwrap()));
+		dout.verify_proof(secp).unwrap();
+
+		// while one with a bulletproof gets the serialization version first
+		let bout = Output::OvertOutput {
+				features: OutputFeatures::Plain,
+				value: 42,
+				blindkey: key,
+			}
+			.blind(secp)
+			.unwrap();
+		let mut bvec = Vec::new();
+		serialize(&mut bvec, &bout).expect("serialization failed");
+		assert_eq!(bvec.len(), 1 + 33 + 1 + BULLET_PROOF_SIZE);
+		assert_eq!(bvec[34], BULLET_PROOF_OUTPUT_VERSION);
+		let dbout: Output = deserialize(&mut &bvec[..]).unwrap();
+		assert_eq!(dbout.commitment(), Some(commit));
+		dbout.verify_proof(secp).unwrap();
+
+		// and the commitment of one doesn't go with the proof of the other
+		let swapped = Output::BlindOutput {
+			features: OutputFeatures::Plain,
+			commit: secp.commit(42, SecretKey::new(secp, &mut rng)).unwrap(),
+			proof: dbout.proof().unwrap(),
+		};
+		assert!(swapped.verify_proof(secp).is_err());
+
+		// an unknown version doesn't read
+		bvec[34] = 2;
+		assert!(deserialize::<Output>(&mut &bvec[..]).is_err());
 	}
 
 	#[test]
@@ -12,33 +12,183 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Definition of the genesis block. Placeholder for now.
+//! Definition of the genesis blocks and of the parameters of the networks
+//! they start. Placeholder for now.
+
+use std::fmt;
 
 use time;
 
 use core;
+use consensus;
 use consensus::DEFAULT_SIZESHIFT;
 use core::hash::Hashed;
 use core::target::Difficulty;
 
-// Genesis block definition. It has no rewards, no inputs, no outputs, no
-// fees and a height of zero.
+/// The networks a chain can be for, each with its own genesis block and
+/// consensus parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainTypes {
+	/// The main network
+	Mainnet,
+	/// Public test network, cheaper to mine on than the main one
+	Testnet,
+	/// Local network for development and simulations, with tiny proofs of
+	/// work and rewards maturing quickly
+	Usernet,
+	/// Automated tests, with tiny proofs of work like the local network but
+	/// blocks coming faster and rewards maturing right away
+	AutomatedTesting,
+}
+
+impl ChainTypes {
+	/// Genesis block and consensus parameters of the network.
+	pub fn params(&self) -> ChainParams {
+		match *self {
+			ChainTypes::Mainnet => {
+				ChainParams {
+					chain_type: *self,
+					genesis: genesis(),
+					block_time_sec: consensus::BLOCK_TIME_SEC as u64,
+					min_sizeshift: DEFAULT_SIZESHIFT,
+					max_sizeshift: consensus::MAX_SIZESHIFT,
+					coinbase_maturity: consensus::COINBASE_MATURITY,
+					cut_through_horizon: consensus::CUT_THROUGH_HORIZON as u64,
+					hard_forks: consensus::HARD_FORK_HEIGHTS.to_vec(),
+				}
+			}
+			ChainTypes::Testnet => {
+				ChainParams {
+					chain_type: *self,
+					genesis: genesis_at(2017, 5, 1, 20),
+					block_time_sec: consensus::BLOCK_TIME_SEC as u64,
+					min_sizeshift: 20,
+					max_sizeshift: consensus::MAX_SIZESHIFT,
+					coinbase_maturity: consensus::COINBASE_MATURITY,
+					cut_through_horizon: consensus::CUT_THROUGH_HORIZON as u64,
+					hard_forks: consensus::HARD_FORK_HEIGHTS.to_vec(),
+				}
+			}
+			ChainTypes::Usernet => {
+				ChainParams {
+					chain_type: *self,
+					genesis: genesis_at(2017, 0, 1, 12),
+					block_time_sec: consensus::BLOCK_TIME_SEC as u64,
+					min_sizeshift: 12,
+					max_sizeshift: 12,
+					coinbase_maturity: 3,
+					cut_through_horizon: 60,
+					hard_forks: consensus::HARD_FORK_HEIGHTS.to_vec(),
+				}
+			}
+			ChainTypes::AutomatedTesting => {
+				ChainParams {
+					chain_type: *self,
+					genesis: genesis_at(2016, 0, 1, 12),
+					block_time_sec: 10,
+					min_sizeshift: 12,
+					max_sizeshift: 12,
+					coinbase_maturity: 1,
+					cut_through_horizon: 10,
+					hard_forks: consensus::HARD_FORK_HEIGHTS.to_vec(),
+				}
+			}
+		}
+	}
+}
+
+/// Everything about the network a chain is for that isn't the same for all
+/// networks. Starts with the parameters of one of the chain types, any can
+/// be changed afterward.
+#[derive(Clone)]
+pub struct ChainParams {
+	/// Network the parameters are for
+	pub chain_type: ChainTypes,
+	/// First block of the chain
+	pub genesis: core::Block,
+	/// Block interval, in seconds, the difficulty gets adjusted for, see
+	/// consensus::BLOCK_TIME_SEC
+	pub block_time_sec: u64,
+	/// Cuckoo Cycle size shift the network starts at, usually the one of the
+	/// genesis block, see consensus::DEFAULT_SIZESHIFT
+	pub min_sizeshift: u8,
+	/// Maximum Cuckoo Cycle size shift, the size shift of the network
+	/// graduates up to it, see consensus::graduated_sizeshift
+	pub max_sizeshift: u8,
+	/// Number of blocks a coinbase output has to wait before it can be spent,
+	/// see consensus::COINBASE_MATURITY
+	pub coinbase_maturity: u64,
+	/// Number of blocks in the past when cross-block cut-through starts, see
+	/// consensus::CUT_THROUGH_HORIZON
+	pub cut_through_horizon: u64,
+	/// Heights of the hard forks of the network, see
+	/// consensus::HARD_FORK_HEIGHTS
+	pub hard_forks: Vec<u64>,
+}
+
+impl ChainParams {
+	/// Difficulty of the block following the window of blocks provided as
+	/// timestamp and difficulty pairs, parent first, see
+	/// consensus::next_difficulty.
+	pub fn next_difficulty(&self, window: &[(i64, Difficulty)]) -> Difficulty {
+		consensus::next_difficulty_with(window, self.block_time_sec)
+	}
+
+	/// Minimum Cuckoo Cycle size shift of the block at the provided height on
+	/// the network, see consensus::graduated_sizeshift.
+	pub fn min_sizeshift_at(&self, height: u64) -> u8 {
+		consensus::graduated_sizeshift(height, self.min_sizeshift, self.max_sizeshift)
+	}
+
+	/// Version the header of the block at the provided height has to have on
+	/// the network, see consensus::header_version.
+	pub fn header_version(&self, height: u64) -> u16 {
+		consensus::header_version_at(height, &self.hard_forks)
+	}
+}
+
+impl fmt::Debug for ChainParams {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f,
+		       "ChainParams({:?}, genesis {}, block time {}s, sizeshifts {}-{}, maturity {}, \
+		        horizon {})",
+		       self.chain_type,
+		       self.genesis.hash(),
+		       self.block_time_sec,
+		       self.min_sizeshift,
+		       self.max_sizeshift,
+		       self.coinbase_maturity,
+		       self.cut_through_horizon)
+	}
+}
+
+// Genesis block definition of the main network. It has no rewards, no inputs,
+// no outputs, no fees and a height of zero.
 pub fn genesis() -> core::Block {
+	genesis_at(1997, 7, 4, DEFAULT_SIZESHIFT)
+}
+
+// A genesis block with the provided date, month counted from zero, and
+// Cuckoo Cycle size shift.
+fn genesis_at(year: i32, month: i32, day: i32, cuckoo_len: u8) -> core::Block {
 	core::Block {
 		header: core::BlockHeader {
+			version: consensus::HEADER_VERSION,
 			height: 0,
 			previous: core::hash::Hash([0xff; 32]),
 			timestamp: time::Tm {
-				tm_year: 1997 - 1900,
-				tm_mon: 7,
-				tm_mday: 4,
+				tm_year: year - 1900,
+				tm_mon: month,
+				tm_mday: day,
 				..time::empty_tm()
 			},
-			cuckoo_len: DEFAULT_SIZESHIFT,
+			cuckoo_len: cuckoo_len,
 			difficulty: Difficulty::one(),
 			total_difficulty: Difficulty::one(),
 			utxo_merkle: [].hash(),
 			tx_merkle: [].hash(),
+			pow_family: core::PowFamily::Primary,
+			secondary_scaling: consensus::INITIAL_SECONDARY_SCALING,
 			nonce: 0,
 			pow: core::Proof::zero(), // TODO get actual PoW solution
 		},
@@ -47,3 +197,24 @@ pub fn genesis() -> core::Block {
 		proofs: vec![],
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn networks_have_own_genesis() {
+		let mainnet = ChainTypes::Mainnet.params();
+		assert_eq!(mainnet.genesis.hash(), genesis().hash());
+		let testnet = ChainTypes::Testnet.params();
+		let usernet = ChainTypes::Usernet.params();
+		let testing = ChainTypes::AutomatedTesting.params();
+		assert!(testnet.genesis.hash() != mainnet.genesis.hash());
+		assert!(usernet.genesis.hash() != testnet.genesis.hash());
+		assert!(testing.genesis.hash() != usernet.genesis.hash());
+		for params in vec![mainnet, testnet, usernet, testing] {
+			assert_eq!(params.genesis.header.cuckoo_len, params.min_sizeshift_at(0));
+			assert_eq!(params.genesis.header.version, params.header_version(0));
+		}
+	}
+}
@@ -0,0 +1,80 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The chain type the whole process runs with, set once at startup, which
+//! the default chain configuration goes by. Code with a `ChainParams` at hand
+//! takes the consensus parameters from it rather than from here. Until set,
+//! the chain type is the main network.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use genesis::ChainTypes;
+
+// code of the chain type, 0 until set
+static CHAIN_TYPE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the chain type of the process. Can only be set once, setting it again
+/// to the same chain type is fine but to another one fails with the chain
+/// type already set.
+pub fn set_chain_type(chain_type: ChainTypes) -> Result<(), ChainTypes> {
+	let code = to_code(chain_type);
+	match CHAIN_TYPE.compare_exchange(0, code, Ordering::SeqCst, Ordering::SeqCst) {
+		Ok(_) => Ok(()),
+		Err(current) if current == code => Ok(()),
+		Err(current) => Err(from_code(current)),
+	}
+}
+
+/// The chain type of the process, the main network unless set otherwise.
+pub fn chain_type() -> ChainTypes {
+	from_code(CHAIN_TYPE.load(Ordering::SeqCst))
+}
+
+fn to_code(chain_type: ChainTypes) -> usize {
+	match chain_type {
+		ChainTypes::Mainnet => 1,
+		ChainTypes::Testnet => 2,
+		ChainTypes::Usernet => 3,
+		ChainTypes::AutomatedTesting => 4,
+	}
+}
+
+fn from_code(code: usize) -> ChainTypes {
+	match code {
+		2 => ChainTypes::Testnet,
+		3 => ChainTypes::Usernet,
+		4 => ChainTypes::AutomatedTesting,
+		_ => ChainTypes::Mainnet,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	// the only test setting the chain type, as it's the same for all tests
+	fn set_once() {
+		assert_eq!(chain_type(), ChainTypes::Mainnet);
+
+		set_chain_type(ChainTypes::AutomatedTesting).unwrap();
+		assert_eq!(chain_type(), ChainTypes::AutomatedTesting);
+
+		// setting it again only works without changing it
+		set_chain_type(ChainTypes::AutomatedTesting).unwrap();
+		assert_eq!(set_chain_type(ChainTypes::Testnet),
+		           Err(ChainTypes::AutomatedTesting));
+		assert_eq!(chain_type(), ChainTypes::AutomatedTesting);
+	}
+}
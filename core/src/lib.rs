@@ -25,9 +25,14 @@ extern crate byteorder;
 extern crate crypto;
 extern crate num_bigint as bigint;
 extern crate rand;
+extern crate rayon;
 extern crate secp256k1zkp as secp;
 extern crate time;
 extern crate tiny_keccak;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 #[macro_use]
 pub mod macros;
@@ -35,5 +40,8 @@ pub mod macros;
 pub mod consensus;
 pub mod core;
 pub mod genesis;
+pub mod global;
 pub mod pow;
 pub mod ser;
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -99,3 +99,38 @@ macro_rules! ser_multiwrite {
     $( try!($wrtr.$write_call($val)) );*
   }
 }
+
+/// Implements serde (behind the serde feature) for a type with a Display and
+/// FromStr, serializing it as its string. Used for our binary types, which
+/// display as hex.
+macro_rules! impl_serde_string {
+  ($thing:ident) => {
+    #[cfg(feature = "serde")]
+    impl ::serde::Serialize for $thing {
+      fn serialize<S>(&self, s: &mut S) -> Result<(), S::Error>
+        where S: ::serde::Serializer
+      {
+        s.visit_str(&self.to_string())
+      }
+    }
+
+    #[cfg(feature = "serde")]
+    impl ::serde::Deserialize for $thing {
+      fn deserialize<D>(d: &mut D) -> Result<$thing, D::Error>
+        where D: ::serde::Deserializer
+      {
+        struct Visitor;
+        impl ::serde::de::Visitor for Visitor {
+          type Value = $thing;
+
+          fn visit_str<E>(&mut self, v: &str) -> Result<$thing, E>
+            where E: ::serde::de::Error
+          {
+            v.parse().map_err(|e: ::ser::Error| E::syntax(&e.to_string()))
+          }
+        }
+        d.visit(Visitor)
+      }
+    }
+  }
+}
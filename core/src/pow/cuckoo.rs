@@ -24,8 +24,8 @@ use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
 use consensus::PROOFSIZE;
-use core::Proof;
-use pow::siphash::siphash24;
+use core::{PowFamily, Proof};
+use pow::siphash::{siphash24, siphash_block, siphash_keys};
 
 const MAXPATHLEN: usize = 8192;
 
@@ -46,6 +46,7 @@ pub struct Cuckoo {
 	mask: u64,
 	size: u64,
 	v: [u64; 4],
+	family: PowFamily,
 }
 
 impl Cuckoo {
@@ -53,6 +54,12 @@ impl Cuckoo {
 	/// generate a seed. In practice for PoW applications the byte array is a
 	/// serialized block header.
 	pub fn new(header: &[u8], sizeshift: u32) -> Cuckoo {
+		Cuckoo::with_family(header, sizeshift, PowFamily::Primary)
+	}
+
+	/// Initializes a new Cuckoo Cycle setup over the graph of the provided
+	/// proof of work family.
+	pub fn with_family(header: &[u8], sizeshift: u32, family: PowFamily) -> Cuckoo {
 		let size = 1 << sizeshift;
 		let mut hasher = Sha256::new();
 		let mut hashed = [0; 32];
@@ -61,23 +68,23 @@ impl Cuckoo {
 
 		let k0 = u8_to_u64(hashed, 0);
 		let k1 = u8_to_u64(hashed, 8);
-		let mut v = [0; 4];
-		v[0] = k0 ^ 0x736f6d6570736575;
-		v[1] = k1 ^ 0x646f72616e646f6d;
-		v[2] = k0 ^ 0x6c7967656e657261;
-		v[3] = k1 ^ 0x7465646279746573;
 		Cuckoo {
-			v: v,
+			v: siphash_keys(k0, k1),
 			size: size,
 			mask: (1 << sizeshift) / 2 - 1,
+			family: family,
 		}
 	}
 
 	/// Generates a node in the cuckoo graph generated from our seed. A node is
 	/// simply materialized as a u64 from a nonce and an offset (generally 0 or
-	/// 1).
-	fn new_node(&self, nonce: u64, uorv: u64) -> u64 {
-		return ((siphash24(self.v, 2 * nonce + uorv) & self.mask) << 1) | uorv;
+	/// 1). Secondary graphs hash their nodes in blocks.
+	pub fn new_node(&self, nonce: u64, uorv: u64) -> u64 {
+		let hash = match self.family {
+			PowFamily::Primary => siphash24(self.v, 2 * nonce + uorv),
+			PowFamily::Secondary => siphash_block(self.v, 2 * nonce + uorv),
+		};
+		((hash & self.mask) << 1) | uorv
 	}
 
 	/// Creates a new edge in the cuckoo graph generated by our seed from a
@@ -165,7 +172,12 @@ enum CycleSol {
 
 impl Miner {
 	pub fn new(header: &[u8], ease: u32, sizeshift: u32) -> Miner {
-		let cuckoo = Cuckoo::new(header, sizeshift);
+		Miner::with_family(header, ease, sizeshift, PowFamily::Primary)
+	}
+
+	/// Miner over the graph of the provided proof of work family.
+	pub fn with_family(header: &[u8], ease: u32, sizeshift: u32, family: PowFamily) -> Miner {
+		let cuckoo = Cuckoo::with_family(header, sizeshift, family);
 		let size = 1 << sizeshift;
 		let graph = vec![0; size + 1];
 		let easiness = (ease as u64) * (size as u64) / 100;
@@ -385,4 +397,15 @@ mod test {
 			assert!(Cuckoo::new(&h, 18).verify(nonces, 75));
 		}
 	}
+
+	#[test]
+	fn mine_validate_secondary() {
+		for n in 1..3 {
+			let h = [n; 32];
+			let nonces = Miner::with_family(&h, 75, 16, PowFamily::Secondary).mine().unwrap();
+			assert!(Cuckoo::with_family(&h, 16, PowFamily::Secondary).verify(nonces, 75));
+			// a proof only holds in the graph of its family
+			assert!(!Cuckoo::new(&h, 16).verify(nonces, 75));
+		}
+	}
 }
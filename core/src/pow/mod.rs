@@ -17,18 +17,23 @@
 //! build new blocks. In addition, mining new blocks should also be as
 //! difficult on high end custom-made hardware (ASICs) as on commodity hardware
 //! or smartphones. For this reason we use Cuckoo Cycle (see the cuckoo
-//! module for more information).
+//! module for more information). Blocks can use either of two families of
+//! Cuckoo graphs, the secondary one kept at its share of the blocks by the
+//! scaling of its difficulty.
 //!
 //! Note that this miner implementation is here mostly for tests and
 //! reference. It's not optimized for speed.
 
-mod siphash;
+pub mod siphash;
 pub mod cuckoo;
+pub mod solvers;
 
 use time;
 
+use bigint::BigUint;
+
 use consensus::EASINESS;
-use core::{Block, Proof};
+use core::{Block, BlockHeader, PowFamily, Proof};
 use core::hash::{Hash, Hashed};
 use core::target::Difficulty;
 use pow::cuckoo::{Cuckoo, Miner, Error};
@@ -37,9 +42,10 @@ use ser;
 use ser::{Writeable, Writer};
 
 /// Subset of a block header that goes into hashing for proof of work.
-/// Basically the whole thing minus the PoW solution itself and the total
-/// difficulty (yet unknown). We also add the count of every variable length
-/// elements in a header to make lying on those much harder.
+/// Basically the whole thing minus the PoW solution itself and the
+/// difficulties. Only requires the header so the proof of work can be checked
+/// before the block body is even downloaded, the body is committed to by the
+/// header merkle roots anyway.
 #[derive(Debug)]
 pub struct PowHeader {
 	pub nonce: u64,
@@ -48,9 +54,8 @@ pub struct PowHeader {
 	pub timestamp: time::Tm,
 	pub utxo_merkle: Hash,
 	pub tx_merkle: Hash,
-	pub n_in: u64,
-	pub n_out: u64,
-	pub n_proofs: u64,
+	pub pow_family: PowFamily,
+	pub secondary_scaling: u32,
 }
 
 /// The binary definition of a PoW header is material for consensus as that's
@@ -64,15 +69,13 @@ impl Writeable for PowHeader {
 		try!(writer.write_i64(self.timestamp.to_timespec().sec));
 		try!(writer.write_fixed_bytes(&self.utxo_merkle));
 		try!(writer.write_fixed_bytes(&self.tx_merkle));
-		try!(writer.write_u64(self.n_in));
-		try!(writer.write_u64(self.n_out));
-		writer.write_u64(self.n_proofs)
+		try!(writer.write_u8(self.pow_family.to_u8()));
+		writer.write_u32(self.secondary_scaling)
 	}
 }
 
 impl PowHeader {
-	pub fn from_block(b: &Block) -> PowHeader {
-		let ref h = b.header;
+	pub fn from_header(h: &BlockHeader) -> PowHeader {
 		PowHeader {
 			nonce: h.nonce,
 			height: h.height,
@@ -80,26 +83,53 @@ impl PowHeader {
 			timestamp: h.timestamp,
 			utxo_merkle: h.utxo_merkle,
 			tx_merkle: h.tx_merkle,
-			n_in: b.inputs.len() as u64,
-			n_out: b.outputs.len() as u64,
-			n_proofs: b.proofs.len() as u64,
+			pow_family: h.pow_family,
+			secondary_scaling: h.secondary_scaling,
 		}
 	}
 }
 
 /// Validates the proof of work of a given header.
-pub fn verify(b: &Block) -> bool {
-	verify_size(b, b.header.cuckoo_len as u32)
+pub fn verify(bh: &BlockHeader) -> bool {
+	verify_size(bh, bh.cuckoo_len as u32)
 }
 
-pub fn verify_size(b: &Block, cuckoo_sz: u32) -> bool {
-	let hash = PowHeader::from_block(b).hash();
-	// make sure the pow hash shows a difficulty at least as large as the target
-	// difficulty
-	if b.header.difficulty > b.header.pow.to_difficulty() {
+pub fn verify_size(bh: &BlockHeader, cuckoo_sz: u32) -> bool {
+	let hash = PowHeader::from_header(bh).hash();
+	// make sure the proof hash is within the target of the header difficulty
+	if !meets_difficulty(bh.pow, bh.pow_family, bh.secondary_scaling, &bh.difficulty) {
 		return false;
 	}
-	Cuckoo::new(hash.to_slice(), cuckoo_sz).verify(b.header.pow, EASINESS as u64)
+	Cuckoo::with_family(hash.to_slice(), cuckoo_sz, bh.pow_family)
+		.verify(bh.pow, EASINESS as u64)
+}
+
+/// Whether a proof of the provided family meets the provided difficulty, the
+/// secondary ones having theirs multiplied by the provided scaling. Compares
+/// the proof hash with the exact target, unlike comparing the difficulty the
+/// proof shows.
+pub fn meets_difficulty(proof: Proof,
+                        family: PowFamily,
+                        secondary_scaling: u32,
+                        difficulty: &Difficulty)
+                        -> bool {
+	match family {
+		PowFamily::Primary => difficulty.is_met_by(&proof.hash()),
+		PowFamily::Secondary => difficulty.is_met_by_scaled(&proof.hash(), secondary_scaling),
+	}
+}
+
+/// Difficulty a proof of the provided family shows, the secondary ones having
+/// theirs multiplied by the provided scaling. Rounded down, see
+/// `meets_difficulty` to check a proof against a difficulty.
+pub fn proof_difficulty(proof: Proof, family: PowFamily, secondary_scaling: u32) -> Difficulty {
+	let difficulty = proof.to_difficulty();
+	match family {
+		PowFamily::Primary => difficulty,
+		PowFamily::Secondary => {
+			Difficulty { num: difficulty.num * BigUint::new(vec![secondary_scaling]) }
+		}
+	}
 }
 
 /// Runs a naive single-threaded proof of work computation over the provided
@@ -116,7 +146,7 @@ pub fn pow20(b: &Block, diff: Difficulty) -> Result<(Proof, u64), Error> {
 }
 
 pub fn pow_size(b: &Block, diff: Difficulty, sizeshift: u32) -> Result<(Proof, u64), Error> {
-	let mut pow_header = PowHeader::from_block(b);
+	let mut pow_header = PowHeader::from_header(&b.header);
 	let start_nonce = pow_header.nonce;
 
 	// try to find a cuckoo cycle on that header hash
@@ -127,8 +157,10 @@ pub fn pow_size(b: &Block, diff: Difficulty, sizeshift: u32) -> Result<(Proof, u
 
 		// if we found a cycle (not guaranteed) and the proof hash is higher that the
 		// diff, we're all good
-		if let Ok(proof) = Miner::new(pow_hash.to_slice(), EASINESS, sizeshift).mine() {
-			if proof.to_difficulty() >= diff {
+		let family = pow_header.pow_family;
+		let mut miner = Miner::with_family(pow_hash.to_slice(), EASINESS, sizeshift, family);
+		if let Ok(proof) = miner.mine() {
+			if meets_difficulty(proof, family, pow_header.secondary_scaling, &diff) {
 				return Ok((proof, pow_header.nonce));
 			}
 		}
@@ -160,6 +192,24 @@ mod test {
 		b.header.pow = proof;
 		b.header.nonce = nonce;
 		b.header.cuckoo_len = 20;
-		assert!(verify(&b));
+		assert!(verify(&b.header));
+	}
+
+	#[test]
+	fn secondary_pow() {
+		let mut b = genesis::genesis();
+		b.header.pow_family = PowFamily::Secondary;
+		b.header.secondary_scaling = 100;
+		b.header.cuckoo_len = 16;
+		let (proof, nonce) = pow_size(&b, Difficulty::from_num(100), 16).unwrap();
+		b.header.pow = proof;
+		b.header.nonce = nonce;
+		b.header.difficulty = Difficulty::from_num(100);
+		assert!(verify(&b.header));
+		// the proof only holds in the secondary graph
+		assert!(meets_difficulty(proof, PowFamily::Secondary, 100, &Difficulty::from_num(100)));
+		assert!(proof_difficulty(proof, PowFamily::Secondary, 100) >= Difficulty::from_num(100));
+		b.header.pow_family = PowFamily::Primary;
+		assert!(!verify(&b.header));
 	}
 }
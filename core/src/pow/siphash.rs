@@ -15,8 +15,50 @@
 //! Simple implementation of the siphash 2-4 hashing function from
 //! Jean-Philippe Aumasson and Daniel J. Bernstein.
 
+/// Number of siphashes in a block of secondary Cuckoo edges, see
+/// `siphash_block`.
+pub const EDGE_BLOCK_SIZE: u64 = 64;
+
+/// Initial siphash state of the key made of the two provided u64s.
+pub fn siphash_keys(k0: u64, k1: u64) -> [u64; 4] {
+	[k0 ^ 0x736f6d6570736575,
+	 k1 ^ 0x646f72616e646f6d,
+	 k0 ^ 0x6c7967656e657261,
+	 k1 ^ 0x7465646279746573]
+}
+
 /// Implements siphash 2-4 specialized for a 4 u64 array key and a u64 nonce
 pub fn siphash24(v: [u64; 4], nonce: u64) -> u64 {
+	let mut state = v;
+	sip_hash(&mut state, nonce);
+	state[0] ^ state[1] ^ state[2] ^ state[3]
+}
+
+/// Siphash 2-4 of the provided nonce within its block of EDGE_BLOCK_SIZE
+/// nonces. The siphash state carries over from one nonce of the block to the
+/// next, starting from the key, and each hash gets xored with the last one of
+/// the block, any hash of the block costing all the ones of the block.
+pub fn siphash_block(v: [u64; 4], nonce: u64) -> u64 {
+	let start = nonce & !(EDGE_BLOCK_SIZE - 1);
+	let mut state = v;
+	let mut hash = 0;
+	let mut last = 0;
+	for n in start..(start + EDGE_BLOCK_SIZE) {
+		sip_hash(&mut state, n);
+		last = state[0] ^ state[1] ^ state[2] ^ state[3];
+		if n == nonce {
+			hash = last;
+		}
+	}
+	if nonce == start + EDGE_BLOCK_SIZE - 1 {
+		hash
+	} else {
+		hash ^ last
+	}
+}
+
+// runs the siphash 2-4 rounds of a nonce over the provided state
+fn sip_hash(v: &mut [u64; 4], nonce: u64) {
 	let mut v0 = v[0];
 	let mut v1 = v[1];
 	let mut v2 = v[2];
@@ -62,7 +104,10 @@ pub fn siphash24(v: [u64; 4], nonce: u64) -> u64 {
 	round!();
 	round!();
 
-	return v0 ^ v1 ^ v2 ^ v3;
+	v[0] = v0;
+	v[1] = v1;
+	v[2] = v2;
+	v[3] = v3;
 }
 
 #[cfg(test)]
@@ -78,4 +123,17 @@ mod test {
 		assert_eq!(siphash24([9, 7, 6, 7], 12), 1305683875471634734);
 		assert_eq!(siphash24([9, 7, 6, 7], 10), 11589833042187638814);
 	}
+
+	#[test]
+	fn hash_block() {
+		// the first hash of a block is the first hash of the key, xored with the
+		// last one of the block, which doesn't get xored
+		let last = siphash_block([1, 2, 3, 4], EDGE_BLOCK_SIZE - 1);
+		assert_eq!(siphash_block([1, 2, 3, 4], 0), siphash24([1, 2, 3, 4], 0) ^ last);
+		assert!(siphash_block([1, 2, 3, 4], 5) != siphash24([1, 2, 3, 4], 5));
+		// each block starts over from the key
+		assert_eq!(siphash_block([1, 2, 3, 4], EDGE_BLOCK_SIZE),
+		           siphash24([1, 2, 3, 4], EDGE_BLOCK_SIZE) ^
+		           siphash_block([1, 2, 3, 4], 2 * EDGE_BLOCK_SIZE - 1));
+	}
 }
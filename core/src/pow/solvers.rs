@@ -0,0 +1,389 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cuckoo Cycle solvers trimming the graph before looking for cycles, after
+//! John Tromp's lean and mean miners. Edges with an endpoint of degree one
+//! can't be part of a cycle, so rounds of trimming them, alternately on each
+//! side of the graph, leave only a small fraction of the edges to look for
+//! cycles in.
+//!
+//! The lean solver keeps a bit per edge and two per node, recomputing the
+//! siphashes of the edges left at each round. The mean solver keeps both
+//! endpoints of every edge left around, never hashing anything twice, for
+//! several times the memory of the whole graph. Which one fits best depends on
+//! the memory available, see `new_solver`.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+
+use consensus::PROOFSIZE;
+use core::{PowFamily, Proof};
+use pow::cuckoo::{Cuckoo, Error};
+
+const MAXPATHLEN: usize = 8192;
+
+/// A Cuckoo Cycle solver, set up for graphs of a given size and easiness.
+/// Keeps its memory from one graph to the next, so a miner can go through
+/// headers without allocating anything.
+pub trait CuckooSolver: Send {
+	/// Short name of the solver, for logging.
+	fn name(&self) -> &'static str;
+
+	/// Looks for a cycle of PROOFSIZE edges in the graph of the provided family
+	/// seeded by the provided header, failing with NoSolutionError if there's
+	/// none.
+	fn solve(&mut self, header: &[u8], family: PowFamily) -> Result<Proof, Error>;
+}
+
+/// Builds the fastest solver whose memory fits in half of what's available,
+/// the mean one on most machines and the lean one on small ones or when the
+/// available memory is unknown.
+pub fn new_solver(ease: u32, sizeshift: u32) -> Box<CuckooSolver> {
+	match available_memory() {
+		Some(avail) if MeanSolver::memory(ease, sizeshift) <= avail / 2 => {
+			Box::new(MeanSolver::new(ease, sizeshift))
+		}
+		_ => Box::new(LeanSolver::new(ease, sizeshift)),
+	}
+}
+
+/// Memory available for new allocations, in bytes, as reported by the
+/// MemAvailable line of /proc/meminfo. None where there's no such file.
+pub fn available_memory() -> Option<u64> {
+	let mut meminfo = String::new();
+	if File::open("/proc/meminfo").and_then(|mut f| f.read_to_string(&mut meminfo)).is_err() {
+		return None;
+	}
+	meminfo.lines()
+		.find(|line| line.starts_with("MemAvailable:"))
+		.and_then(|line| line.split_whitespace().nth(1))
+		.and_then(|kb| kb.parse::<u64>().ok())
+		.map(|kb| kb * 1024)
+}
+
+/// Solver trimming edges with a bitmap of the edges left and two bitmaps
+/// counting the degree of each node up to two. Takes a bit per node of the
+/// graph besides the edges bitmap.
+pub struct LeanSolver {
+	easiness: u64,
+	sizeshift: u32,
+	alive: Vec<u64>,
+	once: Vec<u64>,
+	twice: Vec<u64>,
+}
+
+impl LeanSolver {
+	/// Lean solver for graphs of 2^sizeshift nodes, with ease percents of
+	/// their edges.
+	pub fn new(ease: u32, sizeshift: u32) -> LeanSolver {
+		let easiness = easiness(ease, sizeshift);
+		let half = 1u64 << (sizeshift - 1);
+		LeanSolver {
+			easiness: easiness,
+			sizeshift: sizeshift,
+			alive: vec![0; words(easiness)],
+			once: vec![0; words(half)],
+			twice: vec![0; words(half)],
+		}
+	}
+
+	/// Bytes of memory taken by a lean solver.
+	pub fn memory(ease: u32, sizeshift: u32) -> u64 {
+		let half = 1u64 << (sizeshift - 1);
+		8 * (words(easiness(ease, sizeshift)) + 2 * words(half)) as u64
+	}
+
+	// trims the edges with an endpoint of degree one on the provided side,
+	// returning whether any was
+	fn trim(&mut self, cuckoo: &Cuckoo, uorv: u64) -> bool {
+		for w in self.once.iter_mut().chain(self.twice.iter_mut()) {
+			*w = 0;
+		}
+		for nonce in alive_nonces(&self.alive) {
+			let node = (cuckoo.new_node(nonce, uorv) >> 1) as usize;
+			if get_bit(&self.once, node) {
+				set_bit(&mut self.twice, node);
+			} else {
+				set_bit(&mut self.once, node);
+			}
+		}
+		let mut trimmed = false;
+		for nonce in alive_nonces(&self.alive).collect::<Vec<_>>() {
+			let node = (cuckoo.new_node(nonce, uorv) >> 1) as usize;
+			if !get_bit(&self.twice, node) {
+				clear_bit(&mut self.alive, nonce as usize);
+				trimmed = true;
+			}
+		}
+		trimmed
+	}
+}
+
+impl CuckooSolver for LeanSolver {
+	fn name(&self) -> &'static str {
+		"lean"
+	}
+
+	fn solve(&mut self, header: &[u8], family: PowFamily) -> Result<Proof, Error> {
+		let cuckoo = Cuckoo::with_family(header, self.sizeshift, family);
+		for w in self.alive.iter_mut() {
+			*w = !0;
+		}
+		let extra = self.alive.len() as u64 * 64 - self.easiness;
+		if extra > 0 {
+			let last = self.alive.len() - 1;
+			self.alive[last] = !0 >> extra;
+		}
+		loop {
+			let trimmed_u = self.trim(&cuckoo, 0);
+			let trimmed_v = self.trim(&cuckoo, 1);
+			if !trimmed_u && !trimmed_v {
+				break;
+			}
+		}
+		let edges = alive_nonces(&self.alive)
+			.map(|nonce| {
+				(nonce as u32, cuckoo.new_node(nonce, 0) as u32, cuckoo.new_node(nonce, 1) as u32)
+			})
+			.collect::<Vec<_>>();
+		find_cycle(&edges)
+	}
+}
+
+/// Solver trimming a list of all the edges left, along with both their
+/// endpoints, with a byte counting the degree of each node. Never hashes
+/// anything twice but takes 12 bytes per edge, several times the size of
+/// the graph.
+pub struct MeanSolver {
+	easiness: u64,
+	sizeshift: u32,
+	edges: Vec<(u32, u32, u32)>,
+	degrees: Vec<u8>,
+}
+
+impl MeanSolver {
+	/// Mean solver for graphs of 2^sizeshift nodes, with ease percents of
+	/// their edges.
+	pub fn new(ease: u32, sizeshift: u32) -> MeanSolver {
+		let easiness = easiness(ease, sizeshift);
+		MeanSolver {
+			easiness: easiness,
+			sizeshift: sizeshift,
+			edges: Vec::with_capacity(easiness as usize),
+			degrees: vec![0; 1 << (sizeshift - 1)],
+		}
+	}
+
+	/// Bytes of memory taken by a mean solver.
+	pub fn memory(ease: u32, sizeshift: u32) -> u64 {
+		12 * easiness(ease, sizeshift) + (1 << (sizeshift - 1))
+	}
+
+	// trims the edges with an endpoint of degree one on the provided side,
+	// returning whether any was
+	fn trim(&mut self, uorv: u64) -> bool {
+		for d in self.degrees.iter_mut() {
+			*d = 0;
+		}
+		for &(_, u, v) in &self.edges {
+			let node = (if uorv == 0 { u } else { v } >> 1) as usize;
+			self.degrees[node] = cmp::min(self.degrees[node], 1) + 1;
+		}
+		let before = self.edges.len();
+		let degrees = &self.degrees;
+		self.edges.retain(|&(_, u, v)| degrees[(if uorv == 0 { u } else { v } >> 1) as usize] > 1);
+		self.edges.len() < before
+	}
+}
+
+impl CuckooSolver for MeanSolver {
+	fn name(&self) -> &'static str {
+		"mean"
+	}
+
+	fn solve(&mut self, header: &[u8], family: PowFamily) -> Result<Proof, Error> {
+		let cuckoo = Cuckoo::with_family(header, self.sizeshift, family);
+		self.edges.clear();
+		for nonce in 0..self.easiness {
+			self.edges
+				.push((nonce as u32, cuckoo.new_node(nonce, 0) as u32, cuckoo.new_node(nonce, 1) as u32));
+		}
+		loop {
+			let trimmed_u = self.trim(0);
+			let trimmed_v = self.trim(1);
+			if !trimmed_u && !trimmed_v {
+				break;
+			}
+		}
+		find_cycle(&self.edges)
+	}
+}
+
+// Looks for a cycle of PROOFSIZE edges among the provided ones, given as
+// nonce and both endpoints in increasing nonce order. Same as the simple
+// miner, but with the graph in a map as only a few nodes are left.
+fn find_cycle(edges: &[(u32, u32, u32)]) -> Result<Proof, Error> {
+	let mut graph = HashMap::new();
+	let mut us = vec![];
+	let mut vs = vec![];
+	for &(_, u, v) in edges {
+		try!(path(&graph, u, &mut us));
+		try!(path(&graph, v, &mut vs));
+		let (mut nu, mut nv) = (us.len() - 1, vs.len() - 1);
+		if us[nu] == vs[nv] {
+			// same root, closing a cycle
+			let min = cmp::min(nu, nv);
+			nu -= min;
+			nv -= min;
+			while us[nu] != vs[nv] {
+				nu += 1;
+				nv += 1;
+			}
+			if nu + nv + 1 == PROOFSIZE {
+				return solution(edges, &us[..nu + 1], &vs[..nv + 1]);
+			}
+			continue;
+		}
+		// reverse the shorter path and hang it off the other one
+		if nu < nv {
+			while nu != 0 {
+				nu -= 1;
+				graph.insert(us[nu + 1], us[nu]);
+			}
+			graph.insert(us[0], vs[0]);
+		} else {
+			while nv != 0 {
+				nv -= 1;
+				graph.insert(vs[nv + 1], vs[nv]);
+			}
+			graph.insert(vs[0], us[0]);
+		}
+	}
+	Err(Error::NoSolutionError)
+}
+
+// Fills the provided path with the nodes from the provided one to its root.
+fn path(graph: &HashMap<u32, u32>, start: u32, path: &mut Vec<u32>) -> Result<(), Error> {
+	path.clear();
+	path.push(start);
+	let mut node = start;
+	while let Some(&next) = graph.get(&node) {
+		if path.len() >= MAXPATHLEN {
+			return Err(Error::PathError);
+		}
+		path.push(next);
+		node = next;
+	}
+	Ok(())
+}
+
+// The proof made of the nonces of the edges of the cycle closed by the first
+// nodes of both paths, which end at the same node.
+fn solution(edges: &[(u32, u32, u32)], us: &[u32], vs: &[u32]) -> Result<Proof, Error> {
+	let mut cycle = HashSet::new();
+	cycle.insert((us[0], vs[0]));
+	// u's in even position and v's in odd on the first path, the other way
+	// around on the second
+	for pair in us.windows(2) {
+		cycle.insert(if pair[0] & 1 == 0 { (pair[0], pair[1]) } else { (pair[1], pair[0]) });
+	}
+	for pair in vs.windows(2) {
+		cycle.insert(if pair[0] & 1 == 0 { (pair[0], pair[1]) } else { (pair[1], pair[0]) });
+	}
+	let mut sol = [0; PROOFSIZE];
+	let mut n = 0;
+	for &(nonce, u, v) in edges {
+		if n < PROOFSIZE && cycle.remove(&(u, v)) {
+			sol[n] = nonce;
+			n += 1;
+		}
+	}
+	if n == PROOFSIZE {
+		Ok(Proof(sol))
+	} else {
+		Err(Error::NoSolutionError)
+	}
+}
+
+fn easiness(ease: u32, sizeshift: u32) -> u64 {
+	(ease as u64) * (1u64 << sizeshift) / 100
+}
+
+fn words(bits: u64) -> usize {
+	((bits + 63) / 64) as usize
+}
+
+fn get_bit(bits: &[u64], i: usize) -> bool {
+	bits[i / 64] & (1 << (i % 64)) != 0
+}
+
+fn set_bit(bits: &mut [u64], i: usize) {
+	bits[i / 64] |= 1 << (i % 64);
+}
+
+fn clear_bit(bits: &mut [u64], i: usize) {
+	bits[i / 64] &= !(1 << (i % 64));
+}
+
+// iterates over the positions of the bits set in the provided bitmap
+fn alive_nonces<'a>(bits: &'a [u64]) -> Box<Iterator<Item = u64> + 'a> {
+	Box::new(bits.iter().enumerate().filter(|&(_, w)| *w != 0).flat_map(|(i, &w)| {
+		(0..64u64).filter(move |b| w & (1 << b) != 0).map(move |b| i as u64 * 64 + b)
+	}))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use pow::cuckoo::{Cuckoo, Miner};
+
+	fn solvers(ease: u32, sizeshift: u32) -> Vec<Box<CuckooSolver>> {
+		vec![Box::new(LeanSolver::new(ease, sizeshift)), Box::new(MeanSolver::new(ease, sizeshift))]
+	}
+
+	#[test]
+	fn same_as_simple_miner() {
+		for solver in solvers(75, 20).iter_mut() {
+			let expected = Miner::new(&[49], 75, 20).mine().unwrap();
+			let proof = solver.solve(&[49], PowFamily::Primary).unwrap();
+			assert_eq!(proof, expected, "{} solver", solver.name());
+		}
+	}
+
+	#[test]
+	fn solve_validate() {
+		for solver in solvers(75, 18).iter_mut() {
+			for n in 1..5 {
+				let h = [n; 32];
+				let proof = solver.solve(&h, PowFamily::Primary).unwrap();
+				assert!(Cuckoo::new(&h, 18).verify(proof, 75));
+			}
+		}
+		for solver in solvers(75, 16).iter_mut() {
+			let h = [1; 32];
+			let proof = solver.solve(&h, PowFamily::Secondary).unwrap();
+			assert!(Cuckoo::with_family(&h, 16, PowFamily::Secondary).verify(proof, 75));
+		}
+	}
+
+	#[test]
+	fn memory_requirements() {
+		// a bit per edge and per node, or 12 bytes per edge
+		assert_eq!(LeanSolver::memory(50, 30), (1 << 26) + (1 << 27));
+		assert_eq!(MeanSolver::memory(50, 30), 12 * (1 << 29) + (1 << 29));
+		assert!(LeanSolver::memory(50, 30) < MeanSolver::memory(50, 30) / 20);
+	}
+}
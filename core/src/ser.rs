@@ -99,6 +99,15 @@ pub enum SerializationMode {
 	SigHash,
 }
 
+/// Version of the serialization of block headers written by this code. Newer
+/// versions only ever add fields at the end, which older ones skip when
+/// reading, except for version 2 packing the proof of work nonces, see
+/// `Proof::write_packed`.
+pub const SER_VERSION: u8 = 2;
+
+/// First serialization version with packed proof of work nonces.
+pub const PACKED_POW_VERSION: u8 = 2;
+
 /// Implementations defined how different numbers and binary structures are
 /// written to an underlying stream or container (depending on implementation).
 pub trait Writer {
@@ -210,6 +219,87 @@ pub fn ser_vec(thing: &Writeable) -> Result<Vec<u8>, Error> {
 	Ok(vec)
 }
 
+/// Writes the provided values on the provided number of bits each, most
+/// significant bit first, padding the last byte with zeros. Fails with a value
+/// that doesn't fit.
+pub fn write_packed(writer: &mut Writer, values: &[u32], bits: u8) -> Result<(), Error> {
+	if bits == 0 || bits > 32 {
+		return Err(Error::CorruptedData);
+	}
+	let mut bytes = vec![0u8; (values.len() * bits as usize + 7) / 8];
+	let mut pos = 0;
+	for &v in values {
+		if bits < 32 && v >> bits != 0 {
+			return Err(Error::CorruptedData);
+		}
+		for i in (0..bits).rev() {
+			if (v >> i) & 1 == 1 {
+				bytes[pos / 8] |= 0x80 >> (pos % 8);
+			}
+			pos += 1;
+		}
+	}
+	writer.write_fixed_bytes(&bytes)
+}
+
+/// Reads the provided number of values written by `write_packed` on the
+/// provided number of bits each. Padding bits have to be zeros, so values
+/// only have one packing.
+pub fn read_packed(reader: &mut Reader, count: usize, bits: u8) -> Result<Vec<u32>, Error> {
+	if bits == 0 || bits > 32 {
+		return Err(Error::CorruptedData);
+	}
+	let len = (count * bits as usize + 7) / 8;
+	let bytes = try!(reader.read_fixed_bytes(len));
+	let bit = |pos: usize| (bytes[pos / 8] >> (7 - pos % 8)) & 1;
+	let mut values = Vec::with_capacity(count);
+	let mut pos = 0;
+	for _ in 0..count {
+		let mut v = 0u32;
+		for _ in 0..bits {
+			v = (v << 1) | bit(pos) as u32;
+			pos += 1;
+		}
+		values.push(v);
+	}
+	if (pos..len * 8).any(|pos| bit(pos) != 0) {
+		return Err(Error::CorruptedData);
+	}
+	Ok(values)
+}
+
+/// Encodes bytes as lowercase hex, the text form of all our binary types.
+pub fn to_hex(bytes: &[u8]) -> String {
+	let mut s = String::with_capacity(bytes.len() * 2);
+	for b in bytes {
+		s.push_str(&format!("{:02x}", b));
+	}
+	s
+}
+
+/// Decodes lowercase hex, anything else (uppercase included, so each value
+/// has a single text form) being corrupted data.
+pub fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+	if s.len() % 2 != 0 {
+		return Err(Error::CorruptedData);
+	}
+	let mut bytes = Vec::with_capacity(s.len() / 2);
+	let mut hi = 0;
+	for (i, c) in s.bytes().enumerate() {
+		let n = match c {
+			b'0'...b'9' => c - b'0',
+			b'a'...b'f' => c - b'a' + 10,
+			_ => return Err(Error::CorruptedData),
+		};
+		if i % 2 == 0 {
+			hi = n << 4;
+		} else {
+			bytes.push(hi | n);
+		}
+	}
+	Ok(bytes)
+}
+
 struct BinReader<'a> {
 	source: &'a mut Read,
 }
@@ -326,6 +416,7 @@ impl_slice_bytes!(Vec<u8>);
 impl_slice_bytes!([u8; 1]);
 impl_slice_bytes!([u8; 2]);
 impl_slice_bytes!([u8; 4]);
+impl_slice_bytes!([u8; 6]);
 impl_slice_bytes!([u8; 8]);
 
 impl<'a> AsFixedBytes for &'a [u8] {
@@ -0,0 +1,232 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generation of valid chains for tests, only built with the testing feature.
+//!
+//! Blocks come out the same for the same seed and parameters, down to their
+//! hashes, as all keys are derived from the seed. Only the range proofs
+//! differ, their nonces being random, which none of the hashes cover.
+
+use std::collections::{HashMap, VecDeque};
+
+use rand::{SeedableRng, XorShiftRng};
+use secp::{self, Secp256k1};
+use secp::key::SecretKey;
+use time;
+
+use consensus;
+use core::{Block, BlockHeader, OutputFeatures, Transaction};
+use core::hash::{Hash, Hashed};
+use core::mmr::MountainRange;
+use core::transaction::{output_hash, Builder};
+use genesis::ChainParams;
+use pow;
+
+// An output the generator knows the value and key of, so it can spend it
+struct Spendable {
+	hash: Hash,
+	value: u64,
+	key: SecretKey,
+	height: u64,
+	coinbase: bool,
+}
+
+/// Builds blocks one after the other on top of the genesis of the provided
+/// chain parameters. Each block pays its reward to a key of the generator and
+/// includes up to a configured number of transactions, each spending one of
+/// the outputs of earlier blocks into two new ones. The first blocks may have
+/// fewer transactions, until enough rewards mature. Blocks follow each other
+/// by a configured number of seconds and carry the difficulty, secondary
+/// scaling and output root a chain with the same parameters expects.
+pub struct ChainGenerator {
+	params: ChainParams,
+	secp: Secp256k1,
+	rng: XorShiftRng,
+	txs_per_block: usize,
+	block_secs: i64,
+	headers: Vec<BlockHeader>,
+	outputs: MountainRange<()>,
+	leaves: HashMap<Hash, u64>,
+	spendable: VecDeque<Spendable>,
+}
+
+impl ChainGenerator {
+	/// A generator of blocks on top of the genesis of the provided chain
+	/// parameters, with keys derived from the provided seed. Defaults to
+	/// empty blocks a minute apart.
+	pub fn new(params: ChainParams, seed: u32) -> ChainGenerator {
+		let genesis = params.genesis.header.clone();
+		ChainGenerator {
+			params: params,
+			secp: Secp256k1::with_caps(secp::ContextFlag::Commit),
+			rng: XorShiftRng::from_seed([seed, 0x9e3779b9, 0x7f4a7c15, 0xf39cc060]),
+			txs_per_block: 0,
+			block_secs: 60,
+			headers: vec![genesis],
+			outputs: MountainRange::new((), 0),
+			leaves: HashMap::new(),
+			spendable: VecDeque::new(),
+		}
+	}
+
+	/// Number of transactions each block includes, as long as there are
+	/// enough outputs to spend.
+	pub fn txs_per_block(mut self, txs_per_block: usize) -> ChainGenerator {
+		self.txs_per_block = txs_per_block;
+		self
+	}
+
+	/// Seconds between the timestamps of a block and the next one.
+	pub fn block_secs(mut self, block_secs: i64) -> ChainGenerator {
+		self.block_secs = block_secs;
+		self
+	}
+
+	/// Header of the last block generated, the genesis before any.
+	pub fn head(&self) -> &BlockHeader {
+		self.headers.last().unwrap()
+	}
+
+	/// Generates the provided number of blocks, each on top of the previous.
+	pub fn blocks(&mut self, n: usize) -> Vec<Block> {
+		(0..n).map(|_| self.next_block()).collect()
+	}
+
+	/// Generates the next block, on top of the last one generated.
+	pub fn next_block(&mut self) -> Block {
+		let prev = self.head().clone();
+		let height = prev.height + 1;
+
+		let mut txs = vec![];
+		let mut created = vec![];
+		while txs.len() < self.txs_per_block {
+			match self.take_spendable(height) {
+				Some(spent) => txs.push(self.spend(spent, height, &mut created)),
+				None => break,
+			}
+		}
+
+		let reward_key = SecretKey::new(&self.secp, &mut self.rng);
+		let mut b = Block::new(&prev, txs.iter_mut().collect(), reward_key)
+			.expect("generated block");
+		b.header.version = self.params.header_version(height);
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(self.block_secs);
+
+		for input in &b.inputs {
+			if let Some(leaf) = self.leaves.remove(&input.output_hash()) {
+				self.outputs.prune(leaf).unwrap();
+			}
+		}
+		for out in &b.outputs {
+			let leaf = self.outputs.push(out.hash()).unwrap();
+			self.leaves.insert(out.hash(), leaf);
+		}
+		b.header.utxo_merkle = self.outputs.root().unwrap();
+
+		let ancestors = self.headers.iter().rev().take(consensus::DIFFICULTY_ADJUST_WINDOW + 1);
+		let window = ancestors.clone()
+			.map(|bh| (bh.timestamp.to_timespec().sec, bh.difficulty.clone()))
+			.collect::<Vec<_>>();
+		let difficulty = self.params.next_difficulty(&window);
+		let scalings = ancestors.map(|bh| (bh.pow_family, bh.secondary_scaling))
+			.collect::<Vec<_>>();
+		b.header.secondary_scaling = consensus::next_secondary_scaling(height, &scalings);
+		let (proof, nonce) = pow::pow_size(&b, difficulty.clone(), prev.cuckoo_len as u32)
+			.expect("generated block proof of work");
+		b.header.pow = proof;
+		b.header.nonce = nonce;
+		b.header.total_difficulty = prev.total_difficulty.clone() + difficulty.clone();
+		b.header.difficulty = difficulty;
+
+		// the reward and the outputs of the transactions become spendable
+		let reward = b.reward().unwrap();
+		let reward_commit = self.secp.commit(reward, reward_key).unwrap();
+		self.spendable.push_back(Spendable {
+			hash: output_hash(OutputFeatures::Coinbase, &reward_commit),
+			value: reward,
+			key: reward_key,
+			height: height,
+			coinbase: true,
+		});
+		self.spendable.extend(created);
+		self.headers.push(b.header.clone());
+		b
+	}
+
+	// Takes the oldest output that can be spent in a block at the provided
+	// height, rewards having to be mature.
+	fn take_spendable(&mut self, height: u64) -> Option<Spendable> {
+		let maturity = self.params.coinbase_maturity;
+		let pos = self.spendable
+			.iter()
+			.position(|s| !s.coinbase || height >= s.height.saturating_add(maturity));
+		pos.and_then(|pos| self.spendable.remove(pos))
+	}
+
+	// Builds a transaction spending the provided output into two new ones,
+	// paying the minimum fee, and adds them to the created outputs.
+	fn spend(&mut self, spent: Spendable, height: u64, created: &mut Vec<Spendable>) -> Transaction {
+		let fee = consensus::min_fee(1, 2);
+		let first = (spent.value - fee) / 2;
+		let second = spent.value - fee - first;
+		let mut tx = Builder::new().input(spent.hash, spent.value, spent.key).fee(fee);
+		for &value in &[first, second] {
+			let key = SecretKey::new(&self.secp, &mut self.rng);
+			let commit = self.secp.commit(value, key).unwrap();
+			tx = tx.output(value, key);
+			created.push(Spendable {
+				hash: output_hash(OutputFeatures::Plain, &commit),
+				value: value,
+				key: key,
+				height: height,
+				coinbase: false,
+			});
+		}
+		tx.build(&self.secp).expect("generated transaction")
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use genesis::ChainTypes;
+
+	#[test]
+	fn deterministic_chain() {
+		let params = ChainTypes::AutomatedTesting.params();
+		let mut gen1 = ChainGenerator::new(params.clone(), 1).txs_per_block(2);
+		let mut gen2 = ChainGenerator::new(params.clone(), 1).txs_per_block(2);
+		let mut gen3 = ChainGenerator::new(params.clone(), 2).txs_per_block(2);
+		let blocks = gen1.blocks(4);
+		let hashes = blocks.iter().map(|b| b.hash()).collect::<Vec<_>>();
+		assert_eq!(hashes, gen2.blocks(4).iter().map(|b| b.hash()).collect::<Vec<_>>());
+		assert!(hashes[0] != gen3.next_block().hash());
+
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let mut prev = params.genesis.header.clone();
+		for b in &blocks {
+			b.verify(&secp).unwrap();
+			assert_eq!(b.header.previous, prev.hash());
+			assert_eq!(b.header.height, prev.height + 1);
+			assert_eq!((b.header.timestamp - prev.timestamp).num_seconds(), 60);
+			prev = b.header.clone();
+		}
+
+		// the first reward matures a block later, each transaction then
+		// spending an output into two
+		let outputs = blocks.iter().map(|b| b.outputs.len()).collect::<Vec<_>>();
+		assert_eq!(outputs, vec![1, 3, 5, 5]);
+		assert_eq!(gen1.head().hash(), hashes[3]);
+	}
+}
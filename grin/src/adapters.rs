@@ -12,78 +12,308 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
 
 use chain::{self, ChainAdapter};
 use core::core;
-use p2p::{NetAdapter, Server};
+use core::core::hash::{Hash, Hashed};
+use core::core::target::Difficulty;
+use p2p::{self, Misbehavior, NetAdapter, Peer, Server};
+use pool::{DandelionConfig, PoolError, TransactionPool};
 use util::OneTime;
 
 /// Implementation of the NetAdapter for the blockchain. Gets notified when new
 /// blocks and transactions are received and forwards to the chain and pool
-/// implementations.
+/// implementations, the transactions the pool accepts getting relayed to our
+/// other peers. The ones received along the Dandelion stem mostly go on along
+/// the stem to our current relay peer, the others getting broadcast.
 pub struct NetToChainAdapter {
-	/// the reference copy of the current chain state
-	chain_head: Arc<Mutex<chain::Tip>>,
-	chain_store: Arc<chain::ChainStore>,
-	chain_adapter: Arc<ChainToNetAdapter>,
+	chain: Arc<chain::Chain>,
+	tx_pool: Arc<RwLock<TransactionPool>>,
+	p2p: OneTime<Arc<Server>>,
+	dandelion_config: DandelionConfig,
+	// peer we relay stem transactions to, with since when
+	stem_relay: Mutex<Option<(SocketAddr, Instant)>>,
 }
 
 impl NetAdapter for NetToChainAdapter {
-	fn transaction_received(&self, tx: core::Transaction) {
-		unimplemented!();
-	}
-	fn block_received(&self, b: core::Block) {
-		// TODO delegate to a separate thread to avoid holding up the caller
-		debug!("Received block {} from network, going to process.",
-		       b.hash());
-		// pushing the new block through the chain pipeline
-		let store = self.chain_store.clone();
-		let chain_adapter = self.chain_adapter.clone();
-		let res = chain::process_block(&b, store, chain_adapter, chain::NONE);
-
-		// log errors and update the shared head reference on success
-		if let Err(e) = res {
-			debug!("Block {} refused by chain: {:?}", b.hash(), e);
-		} else if let Ok(Some(tip)) = res {
-			let chain_head = self.chain_head.clone();
-			let mut head = chain_head.lock().unwrap();
-			*head = tip;
+	fn transaction_received(&self, tx: core::Transaction, addr: SocketAddr) {
+		let res = self.tx_pool.write().unwrap().add_to_pool(tx.clone());
+		match res {
+			Ok(txhash) => {
+				debug!("Transaction {} from {} accepted in pool, relaying.", txhash, addr);
+				self.p2p.borrow().broadcast_transaction(&tx, Some(addr));
+			}
+			Err(PoolError::InvalidTx(e)) => {
+				warn!("Invalid transaction from {} refused by pool: {:?}", addr, e);
+				self.p2p.borrow().peer_misbehaved(addr, Misbehavior::InvalidTransaction);
+			}
+			Err(e) => debug!("Transaction from {} refused by pool: {:?}", addr, e),
+		}
+	}
+	fn stem_transaction_received(&self, tx: core::Transaction, addr: SocketAddr) {
+		let embargo = self.embargo();
+		let res = self.tx_pool.write().unwrap().add_to_stempool(tx.clone(), embargo);
+		match res {
+			Ok(txhash) => {
+				let roll = rand::thread_rng().gen_range(0, 100);
+				let stem = roll < self.dandelion_config.stem_probability;
+				let relay = if stem { self.stem_relay(addr) } else { None };
+				match relay.map(|r| r.send_stem_transaction(&tx)) {
+					Some(Ok(())) => debug!("Stem transaction {} from {} relayed.", txhash, addr),
+					_ => {
+						debug!("Stem transaction {} from {} fluffed.", txhash, addr);
+						self.fluff(&txhash);
+					}
+				}
+			}
+			Err(PoolError::InvalidTx(e)) => {
+				warn!("Invalid stem transaction from {} refused by pool: {:?}", addr, e);
+				self.p2p.borrow().peer_misbehaved(addr, Misbehavior::InvalidTransaction);
+			}
+			Err(e) => debug!("Stem transaction from {} refused by pool: {:?}", addr, e),
+		}
+	}
+	fn block_received(&self, b: core::Block, addr: SocketAddr) {
+		let bhash = b.hash();
+		debug!("Received block {} from {}, going to process.", bhash, addr);
+		// queue the new block for the chain pipeline, so we don't hold up the
+		// caller, rejections get reported back through the chain adapter
+		if let Err(e) = self.chain.queue_block(b, chain::NONE, Some(addr)) {
+			debug!("Block {} from {} refused by chain: {:?}", bhash, addr, e);
+		}
+	}
+	fn total_difficulty(&self) -> Difficulty {
+		self.chain.head().total_difficulty
+	}
+	fn get_block(&self, h: Hash) -> Option<core::Block> {
+		self.chain.get_block(&h).ok()
+	}
+	fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+		self.tx_pool.read().unwrap().get(&h)
+	}
+	fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
+		match self.chain.locate_headers(&locator, p2p::MAX_BLOCK_HEADERS) {
+			Ok(headers) => headers,
+			Err(e) => {
+				error!("Could not locate headers: {:?}", e);
+				vec![]
+			}
+		}
+	}
+	fn headers_received(&self, headers: Vec<core::BlockHeader>, addr: SocketAddr) {
+		debug!("Received {} headers from {}, going to process.", headers.len(), addr);
+		for bh in headers {
+			let bhash = bh.hash();
+			if let Err(e) = self.chain.process_block_header(&bh, chain::NONE) {
+				// the rest builds on it, no use going on
+				if e.is_bad_data() {
+					warn!("Invalid header {} from {} refused by chain: {:?}", bhash, addr, e);
+					self.p2p.borrow().peer_misbehaved(addr, Misbehavior::InvalidBlock);
+				} else {
+					debug!("Header {} from {} refused by chain: {:?}", bhash, addr, e);
+				}
+				break;
+			}
+			// fetch the bodies of the valid headers we don't have yet
+			if self.chain.get_block(&bhash).is_err() {
+				if !self.p2p.borrow().request_block(bhash, None) {
+					debug!("No peer to request block {} from.", bhash);
+				}
+			}
 		}
 	}
 }
 
 impl NetToChainAdapter {
-	pub fn new(chain_head: Arc<Mutex<chain::Tip>>,
-	           chain_store: Arc<chain::ChainStore>,
-	           chain_adapter: Arc<ChainToNetAdapter>)
+	pub fn new(chain_ref: Arc<chain::Chain>,
+	           tx_pool: Arc<RwLock<TransactionPool>>,
+	           dandelion_config: DandelionConfig)
 	           -> NetToChainAdapter {
 		NetToChainAdapter {
-			chain_head: chain_head,
-			chain_store: chain_store,
-			chain_adapter: chain_adapter,
+			chain: chain_ref,
+			tx_pool: tx_pool,
+			p2p: OneTime::new(),
+			dandelion_config: dandelion_config,
+			stem_relay: Mutex::new(None),
+		}
+	}
+	pub fn init(&self, p2p: Arc<Server>) {
+		self.p2p.init(p2p);
+	}
+
+	/// Broadcasts the stem transactions whose embargo expired, in case a peer
+	/// along the stem dropped them.
+	pub fn fluff_expired(&self) {
+		let expired = self.tx_pool.write().unwrap().fluff_expired();
+		for tx in expired {
+			debug!("Embargo of stem transaction {} expired, fluffing.", tx.hash());
+			self.p2p.borrow().broadcast_transaction(&tx, None);
+		}
+	}
+
+	// moves the stem transaction with the provided hash to the pool and
+	// broadcasts it to all our peers, the one it came from included, after
+	// the stem transactions it spends from
+	fn fluff(&self, txhash: &Hash) {
+		let fluffed = self.tx_pool.write().unwrap().fluff(txhash);
+		for tx in fluffed {
+			self.p2p.borrow().broadcast_transaction(&tx, None);
+		}
+	}
+
+	// the configured embargo with up to half of it more at random, so peers
+	// can't tell how far along the stem we are from when we fluff
+	fn embargo(&self) -> Duration {
+		let secs = self.dandelion_config.embargo_secs;
+		Duration::from_secs(secs + rand::thread_rng().gen_range(0, secs / 2 + 1))
+	}
+
+	// our current stem relay peer, a new one picked at random among our
+	// peers when it's been the same one for too long or it's gone, none if
+	// it's the peer with the provided address, the stem transaction coming
+	// from it
+	fn stem_relay(&self, source: SocketAddr) -> Option<Arc<Peer>> {
+		let p2p = self.p2p.borrow();
+		let mut relay = self.stem_relay.lock().unwrap();
+		let relay_secs = Duration::from_secs(self.dandelion_config.relay_secs);
+		let current = match *relay {
+			Some((addr, since)) if since.elapsed() < relay_secs => p2p.get_peer(addr),
+			_ => None,
+		};
+		let peer = match current {
+			Some(peer) => peer,
+			None => {
+				let peers = p2p.peers_with(p2p::UNKNOWN);
+				let peer = match rand::thread_rng().choose(&peers) {
+					Some(peer) => peer.clone(),
+					None => return None,
+				};
+				debug!("Relaying stem transactions to {} from now on.", peer.info().addr);
+				*relay = Some((peer.info().addr, Instant::now()));
+				peer
+			}
+		};
+		if peer.info().addr == source {
+			None
+		} else {
+			Some(peer)
 		}
 	}
 }
 
 /// Implementation of the ChainAdapter for the network. Gets notified when the
 /// blockchain accepted a new block and forwards it to the network for
-/// broadcast.
+/// broadcast to all peers but the one it came from. The transactions of the
+/// pool the block spends the inputs of get evicted.
 pub struct ChainToNetAdapter {
 	p2p: OneTime<Arc<Server>>,
+	tx_pool: OneTime<Arc<RwLock<TransactionPool>>>,
 }
 
 impl ChainAdapter for ChainToNetAdapter {
-	fn block_accepted(&self, b: &core::Block) {
-		self.p2p.borrow().broadcast_block(b);
+	fn block_accepted(&self, b: &core::Block, source: Option<SocketAddr>) {
+		self.tx_pool.borrow().write().unwrap().reconcile_block(b);
+		self.p2p.borrow().broadcast_block(b, source);
+	}
+	fn block_rejected(&self,
+	                  bh: &core::BlockHeader,
+	                  e: &chain::pipe::Error,
+	                  source: Option<SocketAddr>) {
+		// the peer that sent us an invalid block gets closer to a ban
+		if e.is_bad_data() {
+			match source {
+				Some(addr) => {
+					warn!("Invalid block {} from {} refused by chain: {:?}",
+					      bh.hash(),
+					      addr,
+					      e);
+					self.p2p.borrow().peer_misbehaved(addr, Misbehavior::InvalidBlock);
+				}
+				None => warn!("Invalid block {} refused by chain: {:?}", bh.hash(), e),
+			}
+		}
 	}
 }
 
 impl ChainToNetAdapter {
 	pub fn new() -> ChainToNetAdapter {
-		ChainToNetAdapter { p2p: OneTime::new() }
+		ChainToNetAdapter {
+			p2p: OneTime::new(),
+			tx_pool: OneTime::new(),
+		}
 	}
-	pub fn init(&self, p2p: Arc<Server>) {
+	pub fn init(&self, p2p: Arc<Server>, tx_pool: Arc<RwLock<TransactionPool>>) {
 		self.p2p.init(p2p);
+		self.tx_pool.init(tx_pool);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::env;
+
+	use futures::Future;
+	use tokio_core::reactor;
+
+	use pool::test::{chain_with_rewards, spend};
+
+	// p2p server keeping its peers under a new temporary directory
+	fn p2p_server(name: &str, port: u16, adapter: Arc<NetAdapter>) -> Arc<Server> {
+		let dir = env::temp_dir().join(format!("grin-adapters-{}-{}", name, rand::random::<u32>()));
+		let config = p2p::P2PConfig { port: port, ..p2p::P2PConfig::default() };
+		Arc::new(Server::new(dir.to_str().unwrap().to_string(), config, adapter).unwrap())
+	}
+
+	#[test]
+	fn stem_transaction_fluffed_without_relay() {
+		let (chain, rewards) = chain_with_rewards("fluff");
+		let tx_pool = Arc::new(RwLock::new(TransactionPool::new(chain.clone())));
+		let config = DandelionConfig { stem_probability: 100, ..DandelionConfig::default() };
+		let adapter = Arc::new(NetToChainAdapter::new(chain, tx_pool.clone(), config));
+		adapter.init(p2p_server("fluff", 14460, adapter.clone()));
+
+		let (tx, _) = spend(rewards[0].0, 0, rewards[0].1, 2);
+		adapter.stem_transaction_received(tx, "127.0.0.1:14461".parse().unwrap());
+		assert_eq!(tx_pool.read().unwrap().size(), 1);
+		assert_eq!(tx_pool.read().unwrap().stempool_size(), 0);
+	}
+
+	#[test]
+	fn stem_transaction_relayed_until_embargo() {
+		let mut evtlp = reactor::Core::new().unwrap();
+		let h = evtlp.handle();
+		let (chain, rewards) = chain_with_rewards("relay");
+		let tx_pool = Arc::new(RwLock::new(TransactionPool::new(chain.clone())));
+		let config = DandelionConfig {
+			stem_probability: 100,
+			embargo_secs: 0,
+			..DandelionConfig::default()
+		};
+		let adapter = Arc::new(NetToChainAdapter::new(chain, tx_pool.clone(), config));
+		let server = p2p_server("relay", 14462, adapter.clone());
+		adapter.init(server.clone());
+		let relay = p2p_server("relay-peer", 14463, Arc::new(p2p::DummyAdapter {}));
+		h.spawn(server.start(h.clone()).map_err(|_| ()));
+		h.spawn(relay.start(h.clone()).map_err(|_| ()));
+		let relay_addr = "127.0.0.1:14463".parse().unwrap();
+		h.spawn(server.connect_peer(relay_addr, h.clone()).map_err(|_| ()));
+		evtlp.run(reactor::Timeout::new(Duration::from_millis(500), &h).unwrap()).unwrap();
+		assert_eq!(server.peers_count(), 1);
+
+		let (tx, _) = spend(rewards[0].0, 0, rewards[0].1, 2);
+		adapter.stem_transaction_received(tx, "127.0.0.1:14464".parse().unwrap());
+		assert_eq!(tx_pool.read().unwrap().size(), 0);
+		assert_eq!(tx_pool.read().unwrap().stempool_size(), 1);
+
+		// nobody along the stem broadcast it in time, we do
+		adapter.fluff_expired();
+		assert_eq!(tx_pool.read().unwrap().size(), 1);
+		assert_eq!(tx_pool.read().unwrap().stempool_size(), 0);
 	}
 }
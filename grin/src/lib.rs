@@ -26,6 +26,7 @@ extern crate log;
 extern crate env_logger;
 extern crate futures;
 extern crate rand;
+extern crate serde_json;
 extern crate time;
 extern crate tokio_core;
 
@@ -38,6 +39,13 @@ extern crate secp256k1zkp as secp;
 
 mod adapters;
 mod miner;
+pub mod plugin;
+mod pool;
+mod seed;
 mod server;
+mod stratum;
 
+pub use miner::{MiningStats, SolverStats};
+pub use pool::DandelionConfig;
 pub use server::{Server, ServerConfig};
+pub use stratum::StratumConfig;
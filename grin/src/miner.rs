@@ -16,79 +16,306 @@
 //! block and mine the block to produce a valid header with its proof-of-work.
 
 use rand::{self, Rng};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
 use time;
 
-use adapters::ChainToNetAdapter;
 use core::consensus;
 use core::core;
-use core::core::hash::{Hash, Hashed};
+use core::core::RewardProvider;
+use core::core::hash::Hashed;
 use core::core::target::Difficulty;
 use core::pow;
-use core::pow::cuckoo;
+use core::pow::solvers;
 use chain;
+use plugin;
+use pool::TransactionPool;
 use secp;
 
+/// Number of nonces in each job handed to a solver plugin
+const PLUGIN_NONCES: u64 = 64;
+
+/// Pays the reward of every block to a new random key, thrown away right
+/// after. Only good for testing, nobody can ever spend the reward.
+pub struct ThrowawayKeys;
+
+impl core::RewardProvider for ThrowawayKeys {
+	fn reward_output(&self,
+	                 height: u64,
+	                 reward: u64,
+	                 secp: &secp::Secp256k1)
+	                 -> Result<(core::Output, core::TxProof), secp::Error> {
+		let mut rng = rand::OsRng::new().unwrap();
+		let skey = secp::key::SecretKey::new(secp, &mut rng);
+		skey.reward_output(height, reward, secp)
+	}
+}
+
+/// Counters of the work of a solver, a mining thread or a stratum client,
+/// since it started.
+#[derive(Debug, Clone, Default)]
+pub struct SolverStats {
+	/// Name of the solver
+	pub name: String,
+	/// Graphs searched for a cycle, one per nonce tried
+	pub attempts: u64,
+	/// Cycles found, whatever their difficulty
+	pub solutions: u64,
+	/// Proofs accepted for a block by the chain
+	pub blocks: u64,
+	/// Proofs refused, by the chain or failing verification
+	pub rejected: u64,
+	/// Seconds spent searching
+	pub secs: f64,
+}
+
+impl SolverStats {
+	/// Graphs searched per second, on average.
+	pub fn graphs_per_sec(&self) -> f64 {
+		if self.secs > 0.0 {
+			self.attempts as f64 / self.secs
+		} else {
+			0.0
+		}
+	}
+}
+
+/// Statistics of all the solvers mining for the server, for status reports to
+/// poll.
+#[derive(Debug, Clone, Default)]
+pub struct MiningStats {
+	/// Statistics of each solver, in the order they started
+	pub solvers: Vec<SolverStats>,
+}
+
+impl MiningStats {
+	/// Sum of the statistics of all solvers. Their seconds overlap, so the
+	/// total takes the longest rather than adding them, and the graphs per
+	/// second add up.
+	pub fn total(&self) -> SolverStats {
+		let mut total = SolverStats { name: "total".to_string(), ..SolverStats::default() };
+		for s in &self.solvers {
+			total.attempts += s.attempts;
+			total.solutions += s.solutions;
+			total.blocks += s.blocks;
+			total.rejected += s.rejected;
+			total.secs = total.secs.max(s.secs);
+		}
+		total
+	}
+
+	/// Adds a solver with the provided name, returning its index among the
+	/// solvers.
+	pub fn add_solver(&mut self, name: &str) -> usize {
+		self.solvers.push(SolverStats { name: name.to_string(), ..SolverStats::default() });
+		self.solvers.len() - 1
+	}
+}
+
+/// A block template for the solvers to work on, numbered in the order the
+/// templates were built in.
+pub struct MiningJob {
+	/// Number of the job, increasing with every new template
+	pub id: u64,
+	/// Block template, only missing its proof of work
+	pub block: core::Block,
+}
+
+/// Keeps the job the solvers work on up to date, building a new block
+/// template every time the chain head changes or transactions get added to
+/// the pool. A new job replaces the previous one at once, solvers notice the
+/// change from the job number and move on instead of mining a stale template.
+pub struct JobManager {
+	chain: Arc<chain::Chain>,
+	tx_pool: Arc<RwLock<TransactionPool>>,
+	reward_provider: Mutex<Box<core::RewardProvider + Send>>,
+	job: RwLock<Arc<MiningJob>>,
+	latest_id: AtomicUsize,
+}
+
+impl JobManager {
+	/// Builds a first job on top of the current head, with the transactions
+	/// of the provided pool, and starts rebuilding it on separate threads
+	/// every time the head changes or the pool gets new transactions.
+	pub fn start(chain: Arc<chain::Chain>,
+	             tx_pool: Arc<RwLock<TransactionPool>>,
+	             reward_provider: Box<core::RewardProvider + Send>)
+	             -> Arc<JobManager> {
+		let heads = chain.subscribe_head();
+		let txs = tx_pool.write().unwrap().subscribe();
+		let head = chain.head_header().unwrap();
+		let block = build_block(&chain, &tx_pool, &head, &*reward_provider);
+		let manager = Arc::new(JobManager {
+			chain: chain,
+			tx_pool: tx_pool,
+			reward_provider: Mutex::new(reward_provider),
+			job: RwLock::new(Arc::new(MiningJob {
+				id: 0,
+				block: block,
+			})),
+			latest_id: AtomicUsize::new(0),
+		});
+		let m = manager.clone();
+		thread::spawn(move || for _ in heads.iter() {
+			m.rebuild();
+		});
+		// transactions often come in bursts, one rebuild for all the ones
+		// added while the previous rebuild ran
+		let m = manager.clone();
+		thread::spawn(move || while txs.recv().is_ok() {
+			while txs.try_recv().is_ok() {}
+			m.rebuild();
+		});
+		manager
+	}
+
+	/// The job to work on.
+	pub fn current(&self) -> Arc<MiningJob> {
+		self.job.read().unwrap().clone()
+	}
+
+	/// Whether the provided job is still the one to work on. Cheap enough to
+	/// be checked between every solver run.
+	pub fn is_current(&self, job: &MiningJob) -> bool {
+		self.latest_id.load(Ordering::Acquire) as u64 == job.id
+	}
+
+	/// Builds a new job on top of the current head, with the transactions
+	/// currently in the pool, and swaps it with the previous one. Called on
+	/// head changes, new transactions in the pool and blocks found.
+	pub fn rebuild(&self) {
+		// one rebuild at a time so a template can't replace a newer one
+		let reward_provider = self.reward_provider.lock().unwrap();
+		let head = match self.chain.head_header() {
+			Ok(head) => head,
+			Err(e) => {
+				error!("Could not build mining job, no chain head: {:?}", e);
+				return;
+			}
+		};
+		let block = build_block(&self.chain, &self.tx_pool, &head, &**reward_provider);
+		let mut job = self.job.write().unwrap();
+		let id = job.id + 1;
+		debug!("New mining job {} at height {}.", id, block.header.height);
+		*job = Arc::new(MiningJob {
+			id: id,
+			block: block,
+		});
+		self.latest_id.store(id as usize, Ordering::Release);
+	}
+}
+
 pub struct Miner {
-	chain_head: Arc<Mutex<chain::Tip>>,
-	chain_store: Arc<chain::ChainStore>,
-	/// chain adapter to net
-	chain_adapter: Arc<ChainToNetAdapter>,
+	chain: Arc<chain::Chain>,
+	jobs: Arc<JobManager>,
+	plugin: Option<String>,
+	stats: Arc<Mutex<MiningStats>>,
 }
 
 impl Miner {
-	/// Creates a new Miner. Needs references to the chain state and its
-	/// storage.
-	pub fn new(chain_head: Arc<Mutex<chain::Tip>>,
-	           chain_store: Arc<chain::ChainStore>,
-	           chain_adapter: Arc<ChainToNetAdapter>)
+	/// Creates a new Miner. Needs a reference to the chain state, the pool
+	/// of the transactions to mine, the provider of the outputs the block
+	/// rewards go to and optionally the command of a solver plugin to hand
+	/// the proof of work to. Starts keeping the mining job up to date right
+	/// away. Reports its work in the provided statistics.
+	pub fn new(chain_ref: Arc<chain::Chain>,
+	           tx_pool: Arc<RwLock<TransactionPool>>,
+	           reward_provider: Box<core::RewardProvider + Send>,
+	           plugin: Option<String>,
+	           stats: Arc<Mutex<MiningStats>>)
 	           -> Miner {
 		Miner {
-			chain_head: chain_head,
-			chain_store: chain_store,
-			chain_adapter: chain_adapter,
+			jobs: JobManager::start(chain_ref.clone(), tx_pool, reward_provider),
+			chain: chain_ref,
+			plugin: plugin,
+			stats: stats,
 		}
 	}
 
-	/// Starts the mining loop, building a new block on top of the existing
-	/// chain anytime required and looking for PoW solution.
+	/// Starts the mining loop, looking for a PoW solution on the current job
+	/// until one is found or the job gets replaced.
 	pub fn run_loop(&self) {
 		info!("Starting miner loop.");
-		loop {
-			// get the latest chain state and build a block on top of it
-			let head: core::BlockHeader;
-			let mut latest_hash: Hash;
-			{
-				head = self.chain_store.head_header().unwrap();
-				latest_hash = self.chain_head.lock().unwrap().last_block_h;
+		let mut plugin = self.plugin.as_ref().and_then(|command| {
+			match plugin::Plugin::start(command) {
+				Ok(plugin) => Some(plugin),
+				Err(e) => {
+					error!("Could not start solver plugin {}, mining in process: {:?}",
+					       command,
+					       e);
+					None
+				}
 			}
-			let mut b = self.build_block(&head);
-			let mut pow_header = pow::PowHeader::from_block(&b);
+		});
+		let name = match plugin {
+			Some(ref plugin) => format!("plugin {}", plugin.command()),
+			None => "in-process".to_string(),
+		};
+		let stats_idx = self.stats.lock().unwrap().add_solver(&name);
+		loop {
+			// work on the latest block template until it gets replaced, by a
+			// new head or new transactions
+			let job = self.jobs.current();
+			let mut b = job.block.clone();
+			let mut pow_header = pow::PowHeader::from_header(&b.header);
 
-			// look for a pow for at most 2 sec on the same block (to give a chance to new
-			// transactions) and as long as the head hasn't changed
-			let deadline = time::get_time().sec + 2;
 			let mut sol = None;
-			debug!("Mining at Cuckoo{} for at most 2 secs on block {}.",
+			let mut solver = match plugin {
+				Some(_) => None,
+				None => Some(solvers::new_solver(consensus::EASINESS, b.header.cuckoo_len as u32)),
+			};
+			debug!("Mining job {} at Cuckoo{} with the {} solver on block {}.",
+			       job.id,
 			       b.header.cuckoo_len,
-			       latest_hash);
+			       solver.as_ref().map(|s| s.name()).unwrap_or("plugin"),
+			       b.header.previous);
 			let mut iter_count = 0;
-			while head.hash() == latest_hash && time::get_time().sec < deadline {
-				let pow_hash = pow_header.hash();
-				let mut miner = cuckoo::Miner::new(pow_hash.to_slice(),
-				                                   consensus::EASINESS,
-				                                   b.header.cuckoo_len as u32);
-				if let Ok(proof) = miner.mine() {
-					if proof.to_difficulty() >= b.header.difficulty {
-						sol = Some(proof);
-						break;
+			while self.jobs.is_current(&job) {
+				let start = Instant::now();
+				let (mut attempts, mut solutions) = (0, 0);
+				if let Some(ref mut plugin) = plugin {
+					// the plugin goes through a range of nonces, its proofs are
+					// only trusted once verified
+					b.header.nonce = pow_header.nonce;
+					let job = plugin::Job::new(&b.header, PLUGIN_NONCES, consensus::EASINESS);
+					attempts = PLUGIN_NONCES;
+					match plugin.solve(&job) {
+						Ok(candidates) => {
+							solutions = candidates.len() as u64;
+							if let Some(c) = candidates.into_iter().find(|c| c.verify(&job, &b.header)) {
+								pow_header.nonce = c.nonce;
+								sol = Some(c.proof);
+							}
+						}
+						Err(e) => error!("Solver plugin {} failed: {:?}", plugin.command(), e),
+					}
+					if sol.is_none() {
+						pow_header.nonce = pow_header.nonce.wrapping_add(PLUGIN_NONCES);
+					}
+				} else if let Some(ref mut solver) = solver {
+					let pow_hash = pow_header.hash();
+					attempts = 1;
+					if let Ok(proof) = solver.solve(pow_hash.to_slice(), core::PowFamily::Primary) {
+						solutions = 1;
+						if b.header.difficulty.is_met_by(&proof.hash()) {
+							sol = Some(proof);
+						}
+					}
+					if sol.is_none() {
+						pow_header.nonce += 1;
 					}
 				}
-				pow_header.nonce += 1;
-				{
-					latest_hash = self.chain_head.lock().unwrap().last_block_h;
-				}
+				self.update_stats(stats_idx, |s| {
+					s.attempts += attempts;
+					s.solutions += solutions;
+					s.secs += duration_secs(start);
+				});
 				iter_count += 1;
+				if sol.is_some() {
+					break;
+				}
 			}
 
 			// if we found a solution, push our block out
@@ -96,46 +323,78 @@ impl Miner {
 				info!("Found valid proof of work, adding block {}.", b.hash());
 				b.header.pow = proof;
 				b.header.nonce = pow_header.nonce;
-				let res = chain::process_block(&b,
-				                               self.chain_store.clone(),
-				                               self.chain_adapter.clone(),
-				                               chain::NONE);
-				if let Err(e) = res {
-					error!("Error validating mined block: {:?}", e);
-				} else if let Ok(Some(tip)) = res {
-					let chain_head = self.chain_head.clone();
-					let mut head = chain_head.lock().unwrap();
-					*head = tip;
+				match self.chain.process_block(&b, chain::NONE) {
+					Ok(_) => self.update_stats(stats_idx, |s| s.blocks += 1),
+					Err(e) => {
+						error!("Error validating mined block: {:?}", e);
+						self.update_stats(stats_idx, |s| s.rejected += 1);
+					}
+				}
+				// don't wait for the head change to come around to move on
+				if self.jobs.is_current(&job) {
+					self.jobs.rebuild();
 				}
 			} else {
-				debug!("No solution found after {} iterations, continuing...",
+				debug!("No solution found after {} iterations, new job...",
 				       iter_count)
 			}
 		}
 	}
 
-	/// Builds a new block with the chain head as previous and eligible
-	/// transactions from the pool.
-	fn build_block(&self, head: &core::BlockHeader) -> core::Block {
-		let mut now_sec = time::get_time().sec;
-		let head_sec = head.timestamp.to_timespec().sec;
-		if now_sec == head_sec {
-			now_sec += 1;
-		}
-		let (difficulty, cuckoo_len) =
-			consensus::next_target(now_sec, head_sec, head.difficulty.clone(), head.cuckoo_len);
+	fn update_stats<F>(&self, idx: usize, f: F)
+		where F: FnOnce(&mut SolverStats)
+	{
+		f(&mut self.stats.lock().unwrap().solvers[idx]);
+	}
+}
 
-		let mut rng = rand::OsRng::new().unwrap();
-		let secp_inst = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-		// TODO get a new key from the user's wallet or something
-		let skey = secp::key::SecretKey::new(&secp_inst, &mut rng);
-
-		// TODO populate inputs and outputs from pool transactions
-		let mut b = core::Block::new(head, vec![], skey).unwrap();
-		b.header.nonce = rng.gen();
-		b.header.cuckoo_len = cuckoo_len;
-		b.header.difficulty = difficulty;
-		b.header.timestamp = time::at(time::Timespec::new(now_sec, 0));
-		b
+/// Seconds elapsed since the provided instant, with their fraction.
+pub fn duration_secs(start: Instant) -> f64 {
+	let elapsed = start.elapsed();
+	elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+/// Builds a new block template on top of the provided head, with the
+/// transactions of the provided pool paying the highest fee rates, see
+/// `TransactionPool::prepare_mineable_transactions`, and its reward going to
+/// the output of the provided reward provider. Only misses its proof of work.
+pub fn build_block(chain: &chain::Chain,
+                   tx_pool: &RwLock<TransactionPool>,
+                   head: &core::BlockHeader,
+                   reward_provider: &core::RewardProvider)
+                   -> core::Block {
+	let mut now_sec = time::get_time().sec;
+	let head_sec = head.timestamp.to_timespec().sec;
+	if now_sec == head_sec {
+		now_sec += 1;
+	}
+	let (difficulty, cuckoo_len) = chain.next_difficulty(head).unwrap();
+
+	let mut rng = rand::OsRng::new().unwrap();
+
+	let mut txs = tx_pool.read().unwrap().prepare_mineable_transactions(head.height + 1);
+	let mut b = match core::Block::new(head, txs.iter_mut().collect(), reward_provider) {
+		Ok(b) => b,
+		Err(e) => {
+			// the pool only has valid transactions, still mine if it's wrong
+			error!("Could not build block with {} pool transactions: {:?}",
+			       txs.len(),
+			       e);
+			core::Block::new(head, vec![], reward_provider).unwrap()
+		}
+	};
+	b.header.version = chain.params().header_version(b.header.height);
+	b.header.nonce = rng.gen();
+	b.header.cuckoo_len = cuckoo_len;
+	// mines primary proofs only, the secondary ones are left to GPU miners
+	b.header.secondary_scaling = chain.next_secondary_scaling(head).unwrap();
+	b.header.total_difficulty = head.total_difficulty.clone() + difficulty.clone();
+	b.header.difficulty = difficulty;
+	b.header.timestamp = time::at(time::Timespec::new(now_sec, 0));
+	// fails if the head moved on already, the mining loop starts over then
+	match chain.utxo_root(&b) {
+		Ok(root) => b.header.utxo_merkle = root,
+		Err(e) => debug!("Could not compute the output MMR root: {:?}", e),
 	}
+	b
 }
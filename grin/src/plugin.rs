@@ -0,0 +1,274 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protocol to run external Cuckoo solvers, typically on GPUs, as plugins of
+//! the miner. The plugin is a separate process reading jobs on its standard
+//! input and writing the proofs it found on its standard output, as one JSON
+//! object per line.
+//!
+//! A job is the serialized pre-PoW header along with a range of nonces:
+//!
+//! `{"header":"<hex>","start_nonce":n,"nonces":k,"cuckoo_size":s,"easiness":e,"family":f}`
+//!
+//! For each nonce of the range, the plugin overwrites the first 8 bytes of the
+//! header with the nonce in big-endian, seeds the Cuckoo graph of size 2^s and
+//! family f (0 for primary, 1 for secondary) with the SHA3-256 of the header
+//! and looks for a cycle among its first e percents of edges. It then replies
+//! with all the cycles it found over the range, at any difficulty:
+//!
+//! `{"proofs":[{"nonce":n,"proof":"<hex>"},...]}`
+//!
+//! Or `{"error":"<message>"}` if it can't take the job. Nothing the plugin
+//! returns is trusted, the miner verifies every proof before using it.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use serde_json;
+use serde_json::builder::ObjectBuilder;
+use serde_json::Value;
+
+use core::core::{BlockHeader, PowFamily, Proof};
+use core::ser;
+use core::pow;
+
+/// Errors talking to a solver plugin.
+#[derive(Debug)]
+pub enum Error {
+	/// The plugin process couldn't be started or talked to
+	IOErr(io::Error),
+	/// The plugin replied with something that isn't in the protocol
+	ProtocolErr(String),
+	/// The plugin reported it couldn't take the job
+	PluginErr(String),
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Error {
+		Error::IOErr(e)
+	}
+}
+
+/// Work for a plugin, looking for proofs of a header over a range of nonces.
+#[derive(Debug, Clone)]
+pub struct Job {
+	/// Serialized pre-PoW header, see `pow::PowHeader`
+	pub header: Vec<u8>,
+	/// First nonce to try
+	pub start_nonce: u64,
+	/// Number of nonces to try after the first one included, wrapping around
+	pub nonces: u64,
+	/// Size shift of the Cuckoo graph
+	pub cuckoo_size: u32,
+	/// Percents of the edges of the graph making up its easiness
+	pub easiness: u32,
+	/// Family of the Cuckoo graph
+	pub family: PowFamily,
+}
+
+impl Job {
+	/// Job over the provided number of nonces of the provided header, starting
+	/// from its own nonce.
+	pub fn new(header: &BlockHeader, nonces: u64, easiness: u32) -> Job {
+		Job {
+			header: ser::ser_vec(&pow::PowHeader::from_header(header)).unwrap(),
+			start_nonce: header.nonce,
+			nonces: nonces,
+			cuckoo_size: header.cuckoo_len as u32,
+			easiness: easiness,
+			family: header.pow_family,
+		}
+	}
+}
+
+/// A proof a plugin found for a nonce, still to be verified.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+	/// Nonce of the header the proof is for
+	pub nonce: u64,
+	/// Proof found by the plugin
+	pub proof: Proof,
+}
+
+impl Candidate {
+	/// Whether the candidate is a valid proof of work for the provided header,
+	/// with its nonce in the range of the job and at the header difficulty.
+	pub fn verify(&self, job: &Job, header: &BlockHeader) -> bool {
+		if self.nonce.wrapping_sub(job.start_nonce) >= job.nonces {
+			return false;
+		}
+		let mut header = header.clone();
+		header.nonce = self.nonce;
+		header.pow = self.proof;
+		pow::verify(&header)
+	}
+}
+
+/// A running solver plugin process.
+pub struct Plugin {
+	command: String,
+	child: Child,
+	stdin: ChildStdin,
+	stdout: BufReader<ChildStdout>,
+}
+
+impl Plugin {
+	/// Starts the plugin run by the provided command, its program followed by
+	/// its arguments separated by whitespaces.
+	pub fn start(command: &str) -> Result<Plugin, Error> {
+		let mut args = command.split_whitespace();
+		let program = try!(args.next()
+			.ok_or(Error::ProtocolErr("empty plugin command".to_string())));
+		let mut child = try!(Command::new(program)
+			.args(&args.collect::<Vec<_>>())
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn());
+		let stdin = child.stdin.take().unwrap();
+		let stdout = BufReader::new(child.stdout.take().unwrap());
+		Ok(Plugin {
+			command: command.to_string(),
+			child: child,
+			stdin: stdin,
+			stdout: stdout,
+		})
+	}
+
+	/// Command the plugin was started with.
+	pub fn command(&self) -> &str {
+		&self.command
+	}
+
+	/// Sends the provided job to the plugin and waits for the proofs it found.
+	pub fn solve(&mut self, job: &Job) -> Result<Vec<Candidate>, Error> {
+		let req = ObjectBuilder::new()
+			.insert("header", ser::to_hex(&job.header))
+			.insert("start_nonce", job.start_nonce)
+			.insert("nonces", job.nonces)
+			.insert("cuckoo_size", job.cuckoo_size)
+			.insert("easiness", job.easiness)
+			.insert("family", job.family.to_u8())
+			.unwrap();
+		let line = try!(serde_json::to_string(&req)
+			.map_err(|e| Error::ProtocolErr(format!("{:?}", e))));
+		try!(writeln!(self.stdin, "{}", line));
+		try!(self.stdin.flush());
+
+		let mut line = String::new();
+		if try!(self.stdout.read_line(&mut line)) == 0 {
+			return Err(Error::IOErr(io::Error::new(io::ErrorKind::UnexpectedEof,
+			                                       "plugin exited")));
+		}
+		parse_reply(&line)
+	}
+}
+
+impl Drop for Plugin {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+// Candidates in a reply line of a plugin
+fn parse_reply(line: &str) -> Result<Vec<Candidate>, Error> {
+	let bad = || Error::ProtocolErr(format!("unexpected plugin reply: {}", line.trim()));
+	let reply: Value = try!(serde_json::from_str(line).map_err(|_| bad()));
+	if let Some(msg) = reply.find("error") {
+		return Err(Error::PluginErr(msg.as_string().unwrap_or("").to_string()));
+	}
+	let proofs = try!(reply.find("proofs").and_then(|p| p.as_array()).ok_or_else(&bad));
+	let mut candidates = vec![];
+	for p in proofs {
+		let nonce = try!(p.find("nonce").and_then(|n| n.as_u64()).ok_or_else(&bad));
+		let proof = try!(p.find("proof")
+			.and_then(|p| p.as_string())
+			.and_then(|p| p.parse().ok())
+			.ok_or_else(&bad));
+		candidates.push(Candidate {
+			nonce: nonce,
+			proof: proof,
+		});
+	}
+	Ok(candidates)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::genesis::ChainTypes;
+
+	#[test]
+	fn parse_replies() {
+		let proof = Proof::zero();
+		let line = format!("{{\"proofs\":[{},{}]}}\n",
+		                   format!("{{\"nonce\":3,\"proof\":\"{}\"}}", proof),
+		                   format!("{{\"nonce\":5,\"proof\":\"{}\"}}", proof));
+		let candidate = |nonce| {
+			Candidate {
+				nonce: nonce,
+				proof: proof,
+			}
+		};
+		assert_eq!(parse_reply(&line).unwrap(), vec![candidate(3), candidate(5)]);
+		assert!(parse_reply("{\"proofs\":[]}").unwrap().is_empty());
+
+		match parse_reply("{\"error\":\"no device\"}") {
+			Err(Error::PluginErr(ref msg)) if msg == "no device" => {}
+			res => panic!("unexpected result {:?}", res),
+		}
+		for line in &["proofs",
+		              "{}",
+		              "{\"proofs\":[{\"nonce\":3}]}",
+		              "{\"proofs\":[{\"nonce\":3,\"proof\":\"00\"}]}"] {
+			match parse_reply(line) {
+				Err(Error::ProtocolErr(_)) => {}
+				res => panic!("unexpected result {:?}", res),
+			}
+		}
+	}
+
+	#[test]
+	fn candidates_in_job_range() {
+		let b = ChainTypes::AutomatedTesting.params().genesis;
+		let sizeshift = b.header.cuckoo_len as u32;
+		let (proof, nonce) = pow::pow_size(&b, b.header.difficulty.clone(), sizeshift).unwrap();
+		let candidate = Candidate {
+			nonce: nonce,
+			proof: proof,
+		};
+		let mut job = Job::new(&b.header, 1, 100);
+		job.start_nonce = nonce;
+		assert!(candidate.verify(&job, &b.header));
+
+		// the range wraps around
+		job.start_nonce = nonce.wrapping_sub(2);
+		job.nonces = 3;
+		assert!(candidate.verify(&job, &b.header));
+		job.nonces = 2;
+		assert!(!candidate.verify(&job, &b.header));
+		job.start_nonce = nonce.wrapping_add(1);
+		job.nonces = u64::max_value();
+		assert!(!candidate.verify(&job, &b.header));
+
+		// a proof only goes with its nonce
+		let other = Candidate {
+			nonce: nonce.wrapping_add(1),
+			proof: proof,
+		};
+		job.start_nonce = nonce;
+		job.nonces = 2;
+		assert!(!other.verify(&job, &b.header));
+	}
+}
@@ -0,0 +1,735 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool of the unconfirmed transactions we accepted, waiting to be mined.
+//! A transaction gets in when it pays the minimum fee and each of its inputs
+//! spends an output either still unspent on our chain or created by another
+//! transaction of the pool, that no transaction of the pool spends already.
+//! Inputs only carry the hash of the output they spend, so the commitments
+//! of those outputs are looked up to check the transaction signatures and
+//! sums, the transactions being kept with them to go in a block as they are.
+//! Miners take the ones paying the highest fee rate first. Transactions leave
+//! the pool once a block accepted by the chain spends any of their inputs, or
+//! an output they spend won't ever exist, and come back if a reorg undoes it.
+//!
+//! Transactions relayed along the Dandelion stem are kept apart, in the
+//! stempool, until they get broadcast, "fluffed": they're neither served to
+//! peers asking for them nor mined until then. Each one gets an embargo, after
+//! which we broadcast it ourselves, in case a peer along the stem dropped it.
+//! The outputs they create can only be spent by other stem transactions until
+//! then, a transaction getting fluffed along with the ones it spends from.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use chain::{self, ChainStoreRead};
+use core::consensus;
+use core::core;
+use core::core::hash::{Hash, Hashed};
+use secp;
+use secp::pedersen::Commitment;
+use util::LruCache;
+
+/// Most transactions the pool holds, the stempool included
+const MAX_POOL_SIZE: usize = 5000;
+
+/// Most recent blocks we remember the transactions they took out of the pool
+/// for, to get them back if a reorg undoes the blocks
+const MINED_BLOCKS: usize = 100;
+
+/// Configuration of the Dandelion relay of transactions.
+#[derive(Debug, Clone, Copy)]
+pub struct DandelionConfig {
+	/// Seconds we keep relaying stem transactions to the same peer, before
+	/// picking another one at random
+	pub relay_secs: u64,
+	/// Seconds we wait for a stem transaction to be broadcast before doing
+	/// it ourselves, up to half of it more being added at random
+	pub embargo_secs: u64,
+	/// Percents of the stem transactions relayed along the stem, the others
+	/// being broadcast
+	pub stem_probability: u8,
+}
+
+impl Default for DandelionConfig {
+	fn default() -> DandelionConfig {
+		DandelionConfig {
+			relay_secs: 600,
+			embargo_secs: 180,
+			stem_probability: 90,
+		}
+	}
+}
+
+/// Reasons a transaction doesn't get in the pool.
+#[derive(Debug)]
+pub enum PoolError {
+	/// The signatures or range proofs of the transaction don't verify
+	InvalidTx(secp::Error),
+	/// The transaction doesn't pay the minimum fee for its size
+	LowFee,
+	/// The transaction has more inputs or outputs than a block would allow
+	TooLarge,
+	/// The transaction can't be included in the next block yet
+	ImmatureLockHeight(u64),
+	/// The transaction is in the pool, or the stempool, already
+	AlreadyInPool,
+	/// An input spends an output neither unspent on our chain nor created by
+	/// a transaction of the pool, or of the stempool for a stem transaction
+	UnknownOutput(Hash),
+	/// An input spends an output another transaction of the pool spends
+	DoubleSpend(Hash),
+	/// An input spends a coinbase output that isn't mature yet
+	ImmatureCoinbase(Hash),
+	/// The chain store failed looking up a spent output
+	StoreErr(chain::types::Error),
+	/// The pool holds as many transactions as it can
+	PoolFull,
+}
+
+/// The pool of unconfirmed transactions, validated against our chain, along
+/// with the stempool.
+pub struct TransactionPool {
+	chain: Arc<chain::Chain>,
+	txs: HashMap<Hash, core::Transaction>,
+	// transactions along the stem, with when their embargo expires
+	stem_txs: HashMap<Hash, (core::Transaction, Instant)>,
+	// hashes of the outputs spent by the transactions of both pools, with
+	// the one spending each
+	spent: HashMap<Hash, Hash>,
+	// outputs created by the transactions of the pool, by hash
+	created: HashMap<Hash, Commitment>,
+	// outputs created by the transactions of the stempool, by hash, with the
+	// transaction creating each
+	stem_created: HashMap<Hash, (Hash, Commitment)>,
+	// notified of the hash of every transaction added to the pool
+	subscribers: Vec<Sender<Hash>>,
+	// hash of the last block of our chain the pool got reconciled with
+	head: Hash,
+	// transactions taken out of the pool by each recent block
+	mined: LruCache<Hash, Vec<core::Transaction>>,
+}
+
+impl TransactionPool {
+	/// Empty pool of transactions validated against the provided chain.
+	pub fn new(chain: Arc<chain::Chain>) -> TransactionPool {
+		let head = chain.head().last_block_h;
+		TransactionPool {
+			chain: chain,
+			txs: HashMap::new(),
+			stem_txs: HashMap::new(),
+			spent: HashMap::new(),
+			created: HashMap::new(),
+			stem_created: HashMap::new(),
+			subscribers: vec![],
+			head: head,
+			mined: LruCache::new(MINED_BLOCKS),
+		}
+	}
+
+	/// Validates the provided transaction and adds it to the pool, giving its
+	/// hash. A transaction of the stempool gets moved to the pool, as it got
+	/// broadcast.
+	pub fn add_to_pool(&mut self, tx: core::Transaction) -> Result<Hash, PoolError> {
+		let txhash = tx.hash();
+		if !self.fluff(&txhash).is_empty() {
+			return Ok(txhash);
+		}
+		try!(self.check_new(&txhash));
+		let tx = try!(self.validate(&tx, false));
+		self.add_spent_created(txhash, &tx, false);
+		self.txs.insert(txhash, tx);
+		self.notify(txhash);
+		Ok(txhash)
+	}
+
+	/// Validates the provided transaction and adds it to the stempool, with
+	/// an embargo expiring after the provided duration, giving its hash.
+	pub fn add_to_stempool(&mut self,
+	                       tx: core::Transaction,
+	                       embargo: Duration)
+	                       -> Result<Hash, PoolError> {
+		let txhash = tx.hash();
+		try!(self.check_new(&txhash));
+		let tx = try!(self.validate(&tx, true));
+		self.add_spent_created(txhash, &tx, true);
+		self.stem_txs.insert(txhash, (tx, Instant::now() + embargo));
+		Ok(txhash)
+	}
+
+	/// Moves the transaction of the stempool with the provided hash to the
+	/// pool, if it was in the stempool, along with the transactions of the
+	/// stempool it spends the outputs of. Gives them back to be broadcast,
+	/// the ones spent from first.
+	pub fn fluff(&mut self, h: &Hash) -> Vec<core::Transaction> {
+		let tx = match self.stem_txs.remove(h) {
+			Some((tx, _)) => tx,
+			None => return vec![],
+		};
+		let mut fluffed = vec![];
+		for input in &tx.inputs {
+			let parent = self.stem_created.get(&input.output_hash()).map(|&(parent, _)| parent);
+			if let Some(parent) = parent {
+				fluffed.extend(self.fluff(&parent));
+			}
+		}
+		for output in &tx.outputs {
+			if let Some((_, commit)) = self.stem_created.remove(&output.hash()) {
+				self.created.insert(output.hash(), commit);
+			}
+		}
+		self.txs.insert(*h, tx.clone());
+		self.notify(*h);
+		fluffed.push(tx);
+		fluffed
+	}
+
+	/// Moves the transactions of the stempool whose embargo expired to the
+	/// pool, giving them back to be broadcast.
+	pub fn fluff_expired(&mut self) -> Vec<core::Transaction> {
+		let now = Instant::now();
+		let expired = self.stem_txs
+			.iter()
+			.filter(|&(_, &(_, until))| until <= now)
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		let mut fluffed = vec![];
+		for h in expired {
+			fluffed.extend(self.fluff(&h));
+		}
+		fluffed
+	}
+
+	/// The transaction of the pool with the provided hash, if any. The ones
+	/// of the stempool aren't given out.
+	pub fn get(&self, h: &Hash) -> Option<core::Transaction> {
+		self.txs.get(h).cloned()
+	}
+
+	/// Transactions of the pool to mine in a block at the provided height,
+	/// the ones paying the highest fee rate first, each one after the
+	/// transactions creating the outputs it spends, as many as fit in a block
+	/// along with its reward. The transactions of the stempool, and the ones
+	/// spending their outputs, are left out.
+	pub fn prepare_mineable_transactions(&self, height: u64) -> Vec<core::Transaction> {
+		let mut candidates = self.txs
+			.values()
+			.filter(|tx| tx.lock_height() <= height)
+			.collect::<Vec<_>>();
+		candidates.sort_by(|a, b| b.fee_rate().cmp(&a.fee_rate()));
+
+		// the reward takes an output and a proof of the block
+		let (mut input_len, mut output_len, mut proof_len) = (0, 1, 1);
+		let mut selected = vec![];
+		let mut selected_outputs = HashSet::new();
+		loop {
+			// a transaction whose parents got selected may be one of the first
+			// ones, go through them again until none gets added
+			let before = selected.len();
+			candidates.retain(|tx| {
+				let ready = tx.inputs.iter().all(|input| {
+					let output = input.output_hash();
+					!self.created.contains_key(&output) || selected_outputs.contains(&output)
+				});
+				if !ready {
+					return true;
+				}
+				let (inputs, outputs, proofs) = (input_len + tx.inputs.len(),
+				                                 output_len + tx.outputs.len(),
+				                                 proof_len + tx.proofs.len() + 1);
+				if !consensus::exceeds_block_limits(inputs, outputs, proofs) {
+					input_len = inputs;
+					output_len = outputs;
+					proof_len = proofs;
+					selected_outputs.extend(tx.outputs.iter().map(|out| out.hash()));
+					selected.push((*tx).clone());
+				}
+				false
+			});
+			if selected.len() == before {
+				return selected;
+			}
+		}
+	}
+
+	/// Receiver of the hash of every transaction added to the pool from now
+	/// on, broadcast ones from the stempool included.
+	pub fn subscribe(&mut self) -> Receiver<Hash> {
+		let (tx, rx) = channel();
+		self.subscribers.push(tx);
+		rx
+	}
+
+	/// Number of transactions in the pool, the stempool excluded.
+	pub fn size(&self) -> usize {
+		self.txs.len()
+	}
+
+	/// Number of transactions in the stempool.
+	pub fn stempool_size(&self) -> usize {
+		self.stem_txs.len()
+	}
+
+	/// Updates the pools for the provided block, accepted by the chain. When it
+	/// is our new head, the transactions spending any of the outputs it spends
+	/// leave, the ones it includes among them, along with the transactions
+	/// spending outputs that won't ever exist because of it. When it's the head
+	/// of a fork taking over, the transactions the blocks undone took out come
+	/// back, as long as they're still valid, and the blocks of the fork take
+	/// theirs out. A block of a fork that isn't our head changes nothing.
+	pub fn reconcile_block(&mut self, b: &core::Block) {
+		let bh = b.hash();
+		match self.chain.read_only_store().head() {
+			Ok(ref head) if head.last_block_h == bh => {}
+			_ => return,
+		}
+		if b.header.previous == self.head {
+			self.reconcile_connected(b);
+			self.head = bh;
+			return;
+		}
+
+		let (undone, replayed) = match self.reorg_blocks(b) {
+			Ok(blocks) => blocks,
+			Err(e) => {
+				warn!("Could not find the blocks undone by a reorg to {}: {:?}", bh, e);
+				(vec![], vec![])
+			}
+		};
+		let mut readded = vec![];
+		for h in undone {
+			if let Some(txs) = self.mined.remove(&h) {
+				readded.extend(txs);
+			}
+		}
+		for rb in &replayed {
+			self.reconcile_connected(rb);
+		}
+		self.reconcile_connected(b);
+		self.head = bh;
+
+		// a transaction can come back before the ones it spends from, go
+		// through them again until none gets in
+		loop {
+			let before = readded.len();
+			readded.retain(|tx| self.add_to_pool(tx.clone()).is_err());
+			if readded.is_empty() || readded.len() == before {
+				break;
+			}
+		}
+	}
+
+	// takes the transactions of both pools spending the outputs the provided
+	// block spends out, and the ones spending outputs they create that the
+	// block doesn't, remembering them all for the block
+	fn reconcile_connected(&mut self, b: &core::Block) {
+		let block_outputs = b.outputs.iter().map(|out| out.hash()).collect::<HashSet<_>>();
+		let mut evicted = b.inputs
+			.iter()
+			.filter_map(|input| self.spent.get(&input.output_hash()).cloned())
+			.collect::<Vec<_>>();
+		let mut mined = vec![];
+		while let Some(txhash) = evicted.pop() {
+			let tx = match self.remove(&txhash) {
+				Some(tx) => tx,
+				None => continue,
+			};
+			// cut through in the block or never created, either way the
+			// outputs missing from it can't be spent anymore
+			for output in &tx.outputs {
+				let h = output.hash();
+				if !block_outputs.contains(&h) {
+					evicted.extend(self.spent.get(&h).cloned());
+				}
+			}
+			mined.push(tx);
+		}
+		self.mined.insert(b.hash(), mined);
+	}
+
+	// hashes of the blocks from our last head back to where the fork of the
+	// provided block branches off, and the blocks of that fork from there up
+	// to the provided one, excluded, in chain order
+	fn reorg_blocks(&self,
+	                b: &core::Block)
+	                -> Result<(Vec<Hash>, Vec<core::Block>), chain::types::Error> {
+		let store = self.chain.read_only_store();
+		let mut old = try!(store.get_block_header(&self.head));
+		let mut new = try!(store.get_block_header(&b.header.previous));
+		let mut undone = vec![];
+		let mut replayed = vec![];
+		while old.hash() != new.hash() {
+			if old.height >= new.height {
+				undone.push(old.hash());
+				old = try!(store.get_block_header(&old.previous));
+			} else {
+				replayed.push(new.hash());
+				new = try!(store.get_block_header(&new.previous));
+			}
+		}
+		replayed.reverse();
+		let replayed = try!(replayed.iter().map(|h| store.get_block(h)).collect());
+		Ok((undone, replayed))
+	}
+
+	// removes the transaction with the provided hash from either pool
+	fn remove(&mut self, txhash: &Hash) -> Option<core::Transaction> {
+		let tx = match self.txs.remove(txhash) {
+			Some(tx) => tx,
+			None => {
+				match self.stem_txs.remove(txhash) {
+					Some((tx, _)) => tx,
+					None => return None,
+				}
+			}
+		};
+		for input in &tx.inputs {
+			self.spent.remove(&input.output_hash());
+		}
+		for output in &tx.outputs {
+			self.created.remove(&output.hash());
+			self.stem_created.remove(&output.hash());
+		}
+		Some(tx)
+	}
+
+	fn check_new(&self, txhash: &Hash) -> Result<(), PoolError> {
+		if self.txs.contains_key(txhash) || self.stem_txs.contains_key(txhash) {
+			return Err(PoolError::AlreadyInPool);
+		}
+		if self.txs.len() + self.stem_txs.len() >= MAX_POOL_SIZE {
+			return Err(PoolError::PoolFull);
+		}
+		Ok(())
+	}
+
+	fn add_spent_created(&mut self, txhash: Hash, tx: &core::Transaction, stem: bool) {
+		for input in &tx.inputs {
+			self.spent.insert(input.output_hash(), txhash);
+		}
+		for output in &tx.outputs {
+			if let Some(commit) = output.commitment() {
+				if stem {
+					self.stem_created.insert(output.hash(), (txhash, commit));
+				} else {
+					self.created.insert(output.hash(), commit);
+				}
+			}
+		}
+	}
+
+	// notifies the subscribers of a new transaction, dropping the ones gone
+	fn notify(&mut self, txhash: Hash) {
+		self.subscribers.retain(|s| s.send(txhash).is_ok());
+	}
+
+	// validates the provided transaction, for the stempool or the pool, giving
+	// it back with the commitments of the outputs its inputs spend, as it goes
+	// in a block
+	fn validate(&self, tx: &core::Transaction, stem: bool) -> Result<core::Transaction, PoolError> {
+		if tx.exceeds_limits() {
+			return Err(PoolError::TooLarge);
+		}
+		if !tx.pays_min_fee() {
+			return Err(PoolError::LowFee);
+		}
+		let next_height = self.chain.head().height + 1;
+		if tx.lock_height() > next_height {
+			return Err(PoolError::ImmatureLockHeight(tx.lock_height()));
+		}
+
+		let mut resolved = tx.clone();
+		for input in resolved.inputs.iter_mut() {
+			let output = input.output_hash();
+			if self.spent.contains_key(&output) {
+				return Err(PoolError::DoubleSpend(output));
+			}
+			let commit = try!(self.spent_commitment(output, next_height, stem));
+			*input = core::Input::BlindInput {
+				output: output,
+				commit: commit,
+			};
+		}
+
+		// the most expensive check last
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		try!(resolved.verify_kernels(&secp).map_err(&PoolError::InvalidTx));
+		Ok(resolved)
+	}
+
+	// commitment of the output with the provided hash, created by a
+	// transaction of the pool, of the stempool for a stem transaction, or
+	// unspent on our chain, in which case it has to be mature by the provided
+	// height if it's a coinbase
+	fn spent_commitment(&self,
+	                    output: Hash,
+	                    height: u64,
+	                    stem: bool)
+	                    -> Result<Commitment, PoolError> {
+		if let Some(commit) = self.created.get(&output) {
+			return Ok(*commit);
+		}
+		if stem {
+			if let Some(&(_, commit)) = self.stem_created.get(&output) {
+				return Ok(commit);
+			}
+		}
+		let store = self.chain.read_only_store();
+		let pos = match store.get_unspent(&output) {
+			Ok(pos) => pos,
+			Err(chain::types::Error::NotFoundErr) => return Err(PoolError::UnknownOutput(output)),
+			Err(e) => return Err(PoolError::StoreErr(e)),
+		};
+		let maturity = self.chain.params().coinbase_maturity;
+		if pos.coinbase && height < pos.height.saturating_add(maturity) {
+			return Err(PoolError::ImmatureCoinbase(output));
+		}
+		let header = try!(store.get_header_by_height(pos.height).map_err(&PoolError::StoreErr));
+		let block = try!(store.get_block(&header.hash()).map_err(&PoolError::StoreErr));
+		block.outputs
+			.iter()
+			.find(|out| out.hash() == output)
+			.and_then(|out| out.commitment())
+			.ok_or(PoolError::UnknownOutput(output))
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+	use std::env;
+
+	use rand::{self, OsRng};
+	use time;
+
+	use chain::types::NoopAdapter;
+	use core::core::transaction::Builder;
+	use core::genesis::ChainTypes;
+	use core::pow;
+	use secp::key::SecretKey;
+
+	// chain of the automated testing network, where coinbase outputs can be
+	// spent by the next block, stored under a new temporary directory
+	pub fn new_chain(name: &str) -> Arc<chain::Chain> {
+		let dir = env::temp_dir().join(format!("grin-pool-{}-{}", name, rand::random::<u32>()));
+		let config = chain::ChainConfig::for_chain(ChainTypes::AutomatedTesting);
+		let chain = chain::Chain::init(dir.to_str().unwrap().to_string(),
+		                               Arc::new(NoopAdapter {}),
+		                               config)
+			.unwrap();
+		Arc::new(chain)
+	}
+
+	pub fn new_key() -> SecretKey {
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		SecretKey::new(&secp, &mut OsRng::new().unwrap())
+	}
+
+	// mines a block with the provided transactions on top of the head of the
+	// chain, its reward going to the provided key
+	pub fn mine(chain: &chain::Chain,
+	            txs: Vec<&mut core::Transaction>,
+	            key: SecretKey)
+	            -> core::Block {
+		let prev = chain.head_header().unwrap();
+		let (difficulty, cuckoo_len) = chain.next_difficulty(&prev).unwrap();
+		let mut b = core::Block::new(&prev, txs, key).unwrap();
+		b.header.version = chain.params().header_version(b.header.height);
+		b.header.cuckoo_len = cuckoo_len;
+		b.header.secondary_scaling = chain.next_secondary_scaling(&prev).unwrap();
+		b.header.total_difficulty = prev.total_difficulty.clone() + difficulty.clone();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(10);
+		b.header.utxo_merkle = chain.utxo_root(&b).unwrap();
+		let (proof, nonce) = pow::pow_size(&b, difficulty.clone(), cuckoo_len as u32).unwrap();
+		b.header.pow = proof;
+		b.header.nonce = nonce;
+		b.header.difficulty = difficulty;
+		b
+	}
+
+	// transaction spending the provided output, worth the block reward less
+	// the provided fees already, to a new key also given back
+	pub fn spend(output: Hash,
+	             spent: u64,
+	             key: SecretKey,
+	             fee: u64)
+	             -> (core::Transaction, SecretKey) {
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let out_key = new_key();
+		let tx = Builder::new()
+			.input(output, consensus::REWARD - spent, key)
+			.output(consensus::REWARD - spent - fee, out_key)
+			.fee(fee)
+			.build(&secp)
+			.unwrap();
+		(tx, out_key)
+	}
+
+	fn hashes(txs: Vec<core::Transaction>) -> Vec<Hash> {
+		txs.iter().map(|tx| tx.hash()).collect()
+	}
+
+	// chain with two blocks, whose rewards to the keys given back are mature
+	pub fn chain_with_rewards(name: &str) -> (Arc<chain::Chain>, Vec<(Hash, SecretKey)>) {
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let chain = new_chain(name);
+		let mut rewards = vec![];
+		for _ in 0..2 {
+			let key = new_key();
+			let b = mine(&chain, vec![], key);
+			chain.process_block(&b, chain::NONE).unwrap();
+			rewards.push((b.coinbase_output(&secp).unwrap().unwrap().hash(), key));
+		}
+		(chain, rewards)
+	}
+
+	#[test]
+	fn mineable_by_fee_rate_after_parents() {
+		let (chain, rewards) = chain_with_rewards("mineable");
+		let mut pool = TransactionPool::new(chain);
+
+		let (parent, parent_key) = spend(rewards[0].0, 0, rewards[0].1, 2);
+		let (child, _) = spend(parent.outputs[0].hash(), 2, parent_key, 100);
+		let (other, _) = spend(rewards[1].0, 0, rewards[1].1, 5);
+		match pool.add_to_pool(child.clone()) {
+			Err(PoolError::UnknownOutput(_)) => {}
+			res => panic!("unexpected result {:?}", res),
+		}
+		pool.add_to_pool(parent.clone()).unwrap();
+		pool.add_to_pool(child.clone()).unwrap();
+		pool.add_to_pool(other.clone()).unwrap();
+		match pool.add_to_pool(spend(rewards[0].0, 0, rewards[0].1, 3).0) {
+			Err(PoolError::DoubleSpend(_)) => {}
+			res => panic!("unexpected result {:?}", res),
+		}
+
+		// the child pays the most but has to wait for its parent
+		let mineable = hashes(pool.prepare_mineable_transactions(3));
+		assert_eq!(mineable, vec![other.hash(), parent.hash(), child.hash()]);
+	}
+
+	#[test]
+	fn stem_outputs_spent_by_stem_only() {
+		let (chain, rewards) = chain_with_rewards("stem");
+		let mut pool = TransactionPool::new(chain);
+
+		let (parent, parent_key) = spend(rewards[0].0, 0, rewards[0].1, 2);
+		let (child, child_key) = spend(parent.outputs[0].hash(), 2, parent_key, 2);
+		pool.add_to_stempool(parent.clone(), Duration::from_secs(60)).unwrap();
+		match pool.add_to_pool(child.clone()) {
+			Err(PoolError::UnknownOutput(_)) => {}
+			res => panic!("unexpected result {:?}", res),
+		}
+		pool.add_to_stempool(child.clone(), Duration::from_secs(60)).unwrap();
+		assert_eq!(pool.size(), 0);
+		assert_eq!(pool.stempool_size(), 2);
+		assert!(pool.prepare_mineable_transactions(3).is_empty());
+		assert!(pool.get(&parent.hash()).is_none());
+
+		// broadcasting the child broadcasts its parent first
+		let fluffed = hashes(pool.fluff(&child.hash()));
+		assert_eq!(fluffed, vec![parent.hash(), child.hash()]);
+		assert_eq!(pool.size(), 2);
+		assert_eq!(pool.stempool_size(), 0);
+		let (grandchild, _) = spend(child.outputs[0].hash(), 4, child_key, 2);
+		pool.add_to_pool(grandchild).unwrap();
+	}
+
+	#[test]
+	fn reconcile_evicts_descendants() {
+		let (chain, rewards) = chain_with_rewards("evict");
+		let mut pool = TransactionPool::new(chain.clone());
+
+		let (parent, parent_key) = spend(rewards[0].0, 0, rewards[0].1, 2);
+		let (child, child_key) = spend(parent.outputs[0].hash(), 2, parent_key, 2);
+		let (grandchild, _) = spend(child.outputs[0].hash(), 4, child_key, 2);
+		let (other, _) = spend(rewards[1].0, 0, rewards[1].1, 2);
+		for tx in vec![parent, child, grandchild, other.clone()] {
+			pool.add_to_pool(tx).unwrap();
+		}
+
+		// a double spend of the parent gets mined, its outputs won't exist
+		let (mut conflict, _) = spend(rewards[0].0, 0, rewards[0].1, 1);
+		let b = mine(&chain, vec![&mut conflict], new_key());
+		chain.process_block(&b, chain::NONE).unwrap();
+		pool.reconcile_block(&b);
+		assert_eq!(pool.size(), 1);
+		assert!(pool.get(&other.hash()).is_some());
+	}
+
+	#[test]
+	fn reconcile_keeps_spenders_of_mined_outputs() {
+		let (chain, rewards) = chain_with_rewards("keep");
+		let mut pool = TransactionPool::new(chain.clone());
+
+		let (parent, parent_key) = spend(rewards[0].0, 0, rewards[0].1, 2);
+		let (child, _) = spend(parent.outputs[0].hash(), 2, parent_key, 2);
+		pool.add_to_pool(parent.clone()).unwrap();
+		pool.add_to_pool(child.clone()).unwrap();
+
+		// the parent alone gets mined, the child spends from the chain now
+		let b3 = mine(&chain, vec![&mut parent.clone()], new_key());
+		chain.process_block(&b3, chain::NONE).unwrap();
+		pool.reconcile_block(&b3);
+		assert_eq!(pool.size(), 1);
+		let mineable = hashes(pool.prepare_mineable_transactions(4));
+		assert_eq!(mineable, vec![child.hash()]);
+
+		let b4 = mine(&chain, vec![&mut child.clone()], new_key());
+		chain.process_block(&b4, chain::NONE).unwrap();
+		pool.reconcile_block(&b4);
+		assert_eq!(pool.size(), 0);
+	}
+
+	#[test]
+	fn reorg_gives_transactions_back() {
+		let (chain, rewards) = chain_with_rewards("reorg");
+		// another chain with the same blocks, to build a fork on
+		let fork = new_chain("reorg-fork");
+		let store = chain.read_only_store();
+		for height in 1..3 {
+			let header = store.get_header_by_height(height).unwrap();
+			fork.process_block(&store.get_block(&header.hash()).unwrap(), chain::NONE).unwrap();
+		}
+		let mut pool = TransactionPool::new(chain.clone());
+
+		let (parent, parent_key) = spend(rewards[0].0, 0, rewards[0].1, 2);
+		let (child, _) = spend(parent.outputs[0].hash(), 2, parent_key, 2);
+		pool.add_to_pool(parent.clone()).unwrap();
+		pool.add_to_pool(child.clone()).unwrap();
+		let b3 = mine(&chain, vec![&mut parent.clone(), &mut child.clone()], new_key());
+		chain.process_block(&b3, chain::NONE).unwrap();
+		pool.reconcile_block(&b3);
+		assert_eq!(pool.size(), 0);
+
+		// a block of a fork not taking over changes nothing
+		let f3 = mine(&fork, vec![], new_key());
+		fork.process_block(&f3, chain::NONE).unwrap();
+		chain.process_block(&f3, chain::NONE).unwrap();
+		pool.reconcile_block(&f3);
+		assert_eq!(pool.size(), 0);
+
+		// once it does, both transactions come back
+		let f4 = mine(&fork, vec![], new_key());
+		fork.process_block(&f4, chain::NONE).unwrap();
+		chain.process_block(&f4, chain::NONE).unwrap();
+		assert_eq!(chain.head().last_block_h, f4.hash());
+		pool.reconcile_block(&f4);
+		assert_eq!(pool.size(), 2);
+		let mineable = hashes(pool.prepare_mineable_transactions(5));
+		assert_eq!(mineable, vec![parent.hash(), child.hash()]);
+	}
+}
@@ -0,0 +1,124 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bootstrapping of the connections to the peer-to-peer network when the
+//! server starts. The addresses the DNS seeds resolve to and the configured
+//! peers come first, followed by the peers we've been connected to before,
+//! so a node can join even when the seeds are down, or when it has none.
+//! Once started, the connections get checked periodically, to keep enough of
+//! them and to replace the peers performing the worst.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use tokio_core::reactor;
+
+use p2p;
+use server::ServerConfig;
+
+/// Most peers connected to when starting
+const MAX_SEED_PEERS: usize = 8;
+
+/// Seconds between checks that we have enough outbound peers
+const CONNECT_MORE_SECS: u64 = 30;
+
+/// Seconds between replacements of the worst outbound peer, which has to be
+/// connected for at least as long
+const REPLACE_WORST_SECS: u64 = 600;
+
+/// Connects the provided p2p server to the peers to bootstrap from, see
+/// `candidates`.
+pub fn connect_seeds(p2p: &Arc<p2p::Server>, config: &ServerConfig, h: &reactor::Handle) {
+	let p2p_config = config.p2p_config;
+	let own_addr = SocketAddr::new(p2p_config.host, p2p_config.port);
+	let addrs = candidates(&config.dns_seeds,
+	                       &config.seed_peers,
+	                       p2p_config.port,
+	                       &p2p.known_peers());
+	if addrs.is_empty() {
+		warn!("No seed nor known peer to connect to.");
+	}
+	for addr in addrs.into_iter().filter(|addr| *addr != own_addr) {
+		debug!("Connecting to seed peer {}.", addr);
+		h.spawn(p2p.connect_peer(addr, h.clone()).map_err(move |e| {
+			debug!("Could not connect to seed peer {}: {:?}", addr, e);
+		}));
+	}
+}
+
+/// Periodically connects the provided p2p server to more known peers when it
+/// has fewer outbound peers than configured, and replaces the worst one when
+/// it has as many.
+pub fn manage_connections(p2p: Arc<p2p::Server>, h: &reactor::Handle) {
+	let (p, hc) = (p2p.clone(), h.clone());
+	let more = reactor::Interval::new(Duration::from_secs(CONNECT_MORE_SECS), h).unwrap();
+	let connect_more = more.for_each(move |_| {
+		p.connect_more_peers(hc.clone());
+		Ok(())
+	});
+	h.spawn(connect_more.map_err(|e| warn!("Peer connections check failed: {:?}", e)));
+
+	let hc = h.clone();
+	let min_age = Duration::from_secs(REPLACE_WORST_SECS);
+	let replace = reactor::Interval::new(min_age, h).unwrap();
+	let replace_worst = replace.for_each(move |_| {
+		p2p.replace_worst_peer(min_age, hc.clone());
+		Ok(())
+	});
+	h.spawn(replace_worst.map_err(|e| warn!("Worst peer replacement failed: {:?}", e)));
+}
+
+/// Addresses of the peers to connect to when starting, up to MAX_SEED_PEERS
+/// of them. The DNS seeds get resolved first, each as host:port or as a host
+/// alone with the provided port, then come the static peers and only then
+/// the known peers that aren't banned, most recently seen first, the
+/// ones we only heard of from other peers last.
+pub fn candidates(dns_seeds: &[String],
+                  seed_peers: &[SocketAddr],
+                  port: u16,
+                  known_peers: &[p2p::PeerData])
+                  -> Vec<SocketAddr> {
+	let mut addrs = vec![];
+	let resolved = dns_seeds.iter().flat_map(|seed| resolve(seed, port));
+	let known = known_peers.iter()
+		.filter(|p| !p.is_banned())
+		.map(|p| p.addr);
+	for addr in resolved.chain(seed_peers.iter().cloned()).chain(known) {
+		if addrs.len() >= MAX_SEED_PEERS {
+			break;
+		}
+		if !addrs.contains(&addr) {
+			addrs.push(addr);
+		}
+	}
+	addrs
+}
+
+// all the addresses a DNS seed resolves to, none if it doesn't
+fn resolve(seed: &str, port: u16) -> Vec<SocketAddr> {
+	let host = if seed.contains(':') {
+		seed.to_string()
+	} else {
+		format!("{}:{}", seed, port)
+	};
+	match host.to_socket_addrs() {
+		Ok(addrs) => addrs.collect(),
+		Err(e) => {
+			warn!("Could not resolve DNS seed {}: {:?}", seed, e);
+			vec![]
+		}
+	}
+}
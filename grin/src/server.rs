@@ -16,19 +16,27 @@
 //! the peer-to-peer server, the blockchain and the transaction pool) and acts
 //! as a facade.
 
+use std::io;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 
 use futures::Future;
 use tokio_core::reactor;
 
 use adapters::{NetToChainAdapter, ChainToNetAdapter};
 use chain;
-use chain::ChainStore;
 use core;
 use miner;
 use p2p;
+use pool;
+use seed;
+use store;
+use stratum;
+
+/// Seconds we wait for a block we asked a peer for before asking another one
+const BLOCK_REQUEST_SECS: u64 = 10;
 
 /// Errors than can be reported by a server implementation, mostly wraps
 /// underlying components errors.
@@ -40,6 +48,13 @@ pub enum Error {
 	PeerErr(core::ser::Error),
 	/// Data store error
 	StoreErr(chain::types::Error),
+	/// The process already runs with another chain type than the configured
+	/// one, see `core::global`
+	ChainTypeErr(core::genesis::ChainTypes),
+	/// The stratum server could not listen on its address
+	StratumErr(io::Error),
+	/// The store of the peers we know of could not be opened
+	PeerStoreErr(store::Error),
 }
 
 /// Full server configuration, aggregating configurations required for the
@@ -48,10 +63,27 @@ pub enum Error {
 pub struct ServerConfig {
 	/// Directory under which the rocksdb stores will be created
 	pub db_root: String,
-	/// Allows overriding the default cuckoo cycle size
+	/// Allows overriding the cuckoo cycle size of the chain, 0 keeps the
+	/// graduation schedule of the network
 	pub cuckoo_size: u8,
 	/// Configuration for the peer-to-peer server
 	pub p2p_config: p2p::P2PConfig,
+	/// Host names resolving to the addresses of peers to connect to when
+	/// starting, with the p2p port unless given as host:port
+	pub dns_seeds: Vec<String>,
+	/// Addresses of peers to connect to when starting, along with the ones
+	/// the DNS seeds resolve to
+	pub seed_peers: Vec<SocketAddr>,
+	/// Configuration for the chain, including the network it's for
+	pub chain_config: chain::ChainConfig,
+	/// Seconds between compactions of the chain, reclaiming the space of
+	/// stale forks, 0 to never compact
+	pub compact_secs: u64,
+	/// Command starting an external solver plugin the miner hands the proof
+	/// of work to, see the `plugin` module for its protocol
+	pub solver_plugin: Option<String>,
+	/// Configuration of the Dandelion relay of transactions
+	pub dandelion_config: pool::DandelionConfig,
 }
 
 impl Default for ServerConfig {
@@ -60,6 +92,12 @@ impl Default for ServerConfig {
 			db_root: ".grin".to_string(),
 			cuckoo_size: 0,
 			p2p_config: p2p::P2PConfig::default(),
+			dns_seeds: vec![],
+			seed_peers: vec![],
+			chain_config: chain::ChainConfig::default(),
+			compact_secs: 600,
+			solver_plugin: None,
+			dandelion_config: pool::DandelionConfig::default(),
 		}
 	}
 }
@@ -70,66 +108,57 @@ pub struct Server {
 	evt_handle: reactor::Handle,
 	/// handle to our network server
 	p2p: Arc<p2p::Server>,
-	/// the reference copy of the current chain state
-	chain_head: Arc<Mutex<chain::Tip>>,
-	/// data store access
-	chain_store: Arc<chain::ChainStore>,
-	/// chain adapter to net, required for miner and anything that submits
-	/// blocks
-	chain_adapter: Arc<ChainToNetAdapter>,
+	/// the blockchain, with its current state and block processing
+	chain: Arc<chain::Chain>,
+	/// the pool of unconfirmed transactions, mined by our miners
+	tx_pool: Arc<RwLock<pool::TransactionPool>>,
+	/// statistics of the miners and stratum clients started so far
+	mining_stats: Arc<Mutex<miner::MiningStats>>,
 }
 
 impl Server {
 	/// Instantiates and starts a new server.
 	pub fn start(config: ServerConfig) -> Result<Server, Error> {
-		let (chain_store, head) = try!(store_head(&config));
-		let shared_head = Arc::new(Mutex::new(head));
-
-		let chain_adapter = Arc::new(ChainToNetAdapter::new());
-		let net_adapter = Arc::new(NetToChainAdapter::new(shared_head.clone(),
-		                                                  chain_store.clone(),
-		                                                  chain_adapter.clone()));
-		let server = Arc::new(p2p::Server::new(config.p2p_config, net_adapter));
-		chain_adapter.init(server.clone());
-
 		let mut evtlp = reactor::Core::new().unwrap();
 		let handle = evtlp.handle();
-		evtlp.run(server.start(handle.clone())).unwrap();
 
+		let (chain, tx_pool, server) = try!(build_chain_p2p(&config));
+		seed::connect_seeds(&server, &config, &handle);
+		seed::manage_connections(server.clone(), &handle);
+		evtlp.run(server.start(handle.clone())).unwrap();
+		start_compactor(chain.clone(), config.compact_secs);
+		start_block_requests_retry(server.clone());
+		start_future_blocks(chain.clone());
 
 		warn!("Grin server started.");
 		Ok(Server {
 			config: config,
 			evt_handle: handle.clone(),
 			p2p: server,
-			chain_head: shared_head,
-			chain_store: chain_store,
-			chain_adapter: chain_adapter,
+			chain: chain,
+			tx_pool: tx_pool,
+			mining_stats: Arc::new(Mutex::new(miner::MiningStats::default())),
 		})
 	}
 
 	/// Instantiates a new server associated with the provided future reactor.
 	pub fn future(config: ServerConfig, evt_handle: &reactor::Handle) -> Result<Server, Error> {
-		let (chain_store, head) = try!(store_head(&config));
-		let shared_head = Arc::new(Mutex::new(head));
-
-		let chain_adapter = Arc::new(ChainToNetAdapter::new());
-		let net_adapter = Arc::new(NetToChainAdapter::new(shared_head.clone(),
-		                                                  chain_store.clone(),
-		                                                  chain_adapter.clone()));
-		let server = Arc::new(p2p::Server::new(config.p2p_config, net_adapter));
-		chain_adapter.init(server.clone());
-
+		let (chain, tx_pool, server) = try!(build_chain_p2p(&config));
 		evt_handle.spawn(server.start(evt_handle.clone()).map_err(|_| ()));
+		seed::connect_seeds(&server, &config, evt_handle);
+		seed::manage_connections(server.clone(), evt_handle);
+		start_compactor(chain.clone(), config.compact_secs);
+		start_block_requests_retry(server.clone());
+		start_future_blocks(chain.clone());
 
 		warn!("Grin server started.");
 		Ok(Server {
 			config: config,
 			evt_handle: evt_handle.clone(),
 			p2p: server,
-			chain_head: shared_head,
-			chain_store: chain_store,
-			chain_adapter: chain_adapter,
+			chain: chain,
+			tx_pool: tx_pool,
+			mining_stats: Arc::new(Mutex::new(miner::MiningStats::default())),
 		})
 	}
 
@@ -141,45 +170,135 @@ impl Server {
 	}
 
 	/// Start mining for blocks on a separate thread. Relies on a toy miner,
-	/// mostly for testing.
+	/// mostly for testing, whose rewards go to keys thrown away right after.
 	pub fn start_miner(&self) {
-		let miner = miner::Miner::new(self.chain_head.clone(),
-		                              self.chain_store.clone(),
-		                              self.chain_adapter.clone());
+		self.start_miner_with(Box::new(miner::ThrowawayKeys));
+	}
+
+	/// Start mining for blocks on a separate thread, with the rewards going to
+	/// the outputs built by the provided reward provider, typically a wallet.
+	pub fn start_miner_with(&self, reward_provider: Box<core::core::RewardProvider + Send>) {
+		let miner = miner::Miner::new(self.chain.clone(),
+		                              self.tx_pool.clone(),
+		                              reward_provider,
+		                              self.config.solver_plugin.clone(),
+		                              self.mining_stats.clone());
 		thread::spawn(move || {
 			miner.run_loop();
 		});
 	}
 
+	/// Start a stratum server for mining clients to connect to, with the
+	/// rewards of the blocks they find going to the outputs built by the
+	/// provided reward provider.
+	pub fn start_stratum(&self,
+	                     config: stratum::StratumConfig,
+	                     reward_provider: Box<core::core::RewardProvider + Send>)
+	                     -> Result<(), Error> {
+		stratum::start(self.chain.clone(),
+		               self.tx_pool.clone(),
+		               config,
+		               reward_provider,
+		               self.mining_stats.clone())
+			.map_err(&Error::StratumErr)
+	}
+
 	pub fn head(&self) -> chain::Tip {
-		let head = self.chain_head.clone();
-		let h = head.lock().unwrap();
-		h.clone()
+		self.chain.head()
+	}
+
+	/// Statistics of the work of the miners and stratum clients, each one
+	/// separately and all together with `MiningStats::total`
+	pub fn mining_stats(&self) -> miner::MiningStats {
+		self.mining_stats.lock().unwrap().clone()
+	}
+
+	/// Statistics on the chain, including the space its store takes on disk
+	pub fn chain_stats(&self) -> Result<chain::ChainStats, Error> {
+		self.chain.stats().map_err(&Error::StoreErr)
+	}
+}
+
+// Helper function to build the chain, the transaction pool and the p2p server,
+// wiring them together through their adapters
+fn build_chain_p2p(config: &ServerConfig)
+                   -> Result<(Arc<chain::Chain>, Arc<RwLock<pool::TransactionPool>>, Arc<p2p::Server>),
+                             Error> {
+	let mut chain_config = config.chain_config.clone();
+	try!(core::global::set_chain_type(chain_config.params.chain_type)
+		.map_err(&Error::ChainTypeErr));
+	if config.cuckoo_size > 0 {
+		chain_config.params.genesis.header.cuckoo_len = config.cuckoo_size;
+		chain_config.params.min_sizeshift = config.cuckoo_size;
+		chain_config.params.max_sizeshift = config.cuckoo_size;
 	}
+
+	let chain_adapter = Arc::new(ChainToNetAdapter::new());
+	let chain = Arc::new(try!(chain::Chain::init(config.db_root.clone(),
+	                                             chain_adapter.clone(),
+	                                             chain_config)
+		.map_err(&Error::StoreErr)));
+
+	let tx_pool = Arc::new(RwLock::new(pool::TransactionPool::new(chain.clone())));
+	let net_adapter = Arc::new(NetToChainAdapter::new(chain.clone(),
+	                                                  tx_pool.clone(),
+	                                                  config.dandelion_config));
+	let server = Arc::new(try!(p2p::Server::new(config.db_root.clone(),
+	                                            config.p2p_config,
+	                                            net_adapter.clone())
+		.map_err(&Error::PeerStoreErr)));
+	chain_adapter.init(server.clone(), tx_pool.clone());
+	net_adapter.init(server.clone());
+	start_embargo_monitor(net_adapter);
+	Ok((chain, tx_pool, server))
+}
+
+// Broadcasts the stem transactions whose embargo expired, checking every few
+// seconds on a separate thread
+fn start_embargo_monitor(net_adapter: Arc<NetToChainAdapter>) {
+	thread::spawn(move || loop {
+		thread::sleep(Duration::from_secs(5));
+		net_adapter.fluff_expired();
+	});
+}
+
+// Asks other peers for the blocks requested to peers that didn't send them in
+// time, every couple of seconds on a separate thread
+fn start_block_requests_retry(p2p: Arc<p2p::Server>) {
+	thread::spawn(move || loop {
+		thread::sleep(Duration::from_secs(2));
+		p2p.retry_stale_block_requests(Duration::from_secs(BLOCK_REQUEST_SECS));
+	});
 }
 
-// Helper function to create the chain storage and check if it already has a
-// genesis block
-fn store_head(config: &ServerConfig)
-              -> Result<(Arc<chain::store::ChainKVStore>, chain::Tip), Error> {
-	let chain_store = try!(chain::store::ChainKVStore::new(config.db_root.clone())
-		.map_err(&Error::StoreErr));
-
-	// check if we have a head in store, otherwise the genesis block is it
-	let head = match chain_store.head() {
-		Ok(tip) => tip,
-		Err(chain::types::Error::NotFoundErr) => {
-			debug!("No genesis block found, creating and saving one.");
-			let mut gen = core::genesis::genesis();
-			if config.cuckoo_size > 0 {
-				gen.header.cuckoo_len = config.cuckoo_size;
+// Processes the blocks held for being too far in the future once they aren't
+// anymore, checking every few seconds on a separate thread
+fn start_future_blocks(chain: Arc<chain::Chain>) {
+	thread::spawn(move || loop {
+		thread::sleep(Duration::from_secs(5));
+		chain.process_future_blocks();
+	});
+}
+
+// Compacts the chain every provided number of seconds on a separate thread,
+// logging how much space the store takes after each compaction
+fn start_compactor(chain: Arc<chain::Chain>, secs: u64) {
+	if secs == 0 {
+		return;
+	}
+	thread::spawn(move || loop {
+		thread::sleep(Duration::from_secs(secs));
+		if let Err(e) = chain.compact() {
+			error!("Chain compaction failed: {:?}", e);
+			continue;
+		}
+		match chain.stats() {
+			Ok(stats) => {
+				info!("Chain at height {}, store taking {} bytes on disk.",
+				      stats.height,
+				      stats.store_size)
 			}
-			try!(chain_store.save_block(&gen).map_err(&Error::StoreErr));
-			let tip = chain::types::Tip::new(gen.hash());
-			try!(chain_store.save_head(&tip).map_err(&Error::StoreErr));
-			tip
+			Err(e) => error!("Could not get chain stats: {:?}", e),
 		}
-		Err(e) => return Err(Error::StoreErr(e)),
-	};
-	Ok((Arc::new(chain_store), head))
+	});
 }
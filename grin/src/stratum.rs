@@ -0,0 +1,420 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stratum-style server for pooled mining. Mining clients connect over TCP
+//! and exchange JSON objects with the server, one per line.
+//!
+//! The server pushes a new job to all its clients every time the chain head
+//! changes, and to each client when it connects:
+//!
+//! `{"method":"job","params":{"job_id":n,"height":h,"header":"<hex>",
+//! "cuckoo_size":s,"easiness":e,"family":f,"difficulty":d}}`
+//!
+//! The header is the serialized pre-PoW header, hashed for each nonce the same
+//! way solver plugins do, see the `plugin` module. Clients submit the proofs
+//! they find at the share difficulty d or more with:
+//!
+//! `{"id":i,"method":"submit","params":{"job_id":n,"nonce":k,"proof":"<hex>"}}`
+//!
+//! Which the server answers with `{"id":i,"result":"share"}`, or
+//! `{"id":i,"result":"block"}` when the proof was enough for a block, or
+//! `{"id":i,"error":"<message>"}`. Clients can also ask for the current job
+//! with the `getjob` method, which the server answers with its parameters as
+//! result. Submissions to the few jobs before the current one are still
+//! accepted, as they may have been in flight when the job changed.
+
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use serde_json;
+use serde_json::builder::ObjectBuilder;
+use serde_json::Value;
+
+use chain;
+use core::consensus;
+use core::core;
+use core::core::hash::Hashed;
+use core::core::target::Difficulty;
+use core::pow;
+use core::ser;
+use miner;
+use pool::TransactionPool;
+
+/// Number of jobs submissions are accepted for, the current one included
+const MAX_JOBS: usize = 4;
+
+/// Maximum length of a line sent by a client, well over any valid request
+const MAX_LINE_LEN: usize = 4096;
+
+/// Configuration of the stratum server.
+#[derive(Debug, Clone)]
+pub struct StratumConfig {
+	/// Address the server listens on for mining clients
+	pub addr: SocketAddr,
+	/// Difficulty the proofs submitted as shares must reach
+	pub share_difficulty: u32,
+}
+
+// A block template given out to the clients, along with the nonces already
+// submitted for it
+struct Job {
+	id: u64,
+	block: core::Block,
+	nonces: Mutex<HashSet<u64>>,
+}
+
+impl Job {
+	fn to_json(&self, share_difficulty: u32) -> Value {
+		let header = &self.block.header;
+		ObjectBuilder::new()
+			.insert("job_id", self.id)
+			.insert("height", header.height)
+			.insert("header", ser::to_hex(&ser::ser_vec(&pow::PowHeader::from_header(header)).unwrap()))
+			.insert("cuckoo_size", header.cuckoo_len)
+			.insert("easiness", consensus::EASINESS)
+			.insert("family", header.pow_family.to_u8())
+			.insert("difficulty", share_difficulty)
+			.unwrap()
+	}
+}
+
+struct Stratum {
+	chain: Arc<chain::Chain>,
+	tx_pool: Arc<RwLock<TransactionPool>>,
+	reward_provider: Mutex<Box<core::RewardProvider + Send>>,
+	share_difficulty: u32,
+	jobs: RwLock<VecDeque<Arc<Job>>>,
+	clients: Mutex<Vec<(u64, Arc<Mutex<TcpStream>>)>>,
+	next_client: AtomicUsize,
+	stats: Arc<Mutex<miner::MiningStats>>,
+}
+
+/// Starts a stratum server listening on the configured address, mining the
+/// transactions of the provided pool, with the rewards of the blocks found by
+/// its clients going to the provided reward provider. Runs on its own
+/// threads, one per client, each client reporting the shares it submitted in
+/// the provided statistics.
+pub fn start(chain: Arc<chain::Chain>,
+             tx_pool: Arc<RwLock<TransactionPool>>,
+             config: StratumConfig,
+             reward_provider: Box<core::RewardProvider + Send>,
+             stats: Arc<Mutex<miner::MiningStats>>)
+             -> io::Result<()> {
+	let listener = try!(TcpListener::bind(config.addr));
+	let heads = chain.subscribe_head();
+	let stratum = Arc::new(Stratum {
+		chain: chain,
+		tx_pool: tx_pool,
+		reward_provider: Mutex::new(reward_provider),
+		share_difficulty: config.share_difficulty,
+		jobs: RwLock::new(VecDeque::new()),
+		clients: Mutex::new(vec![]),
+		next_client: AtomicUsize::new(0),
+		stats: stats,
+	});
+	stratum.new_job();
+
+	// a new job for everyone every time the head changes
+	let s = stratum.clone();
+	thread::spawn(move || for _ in heads.iter() {
+		s.new_job();
+		s.broadcast_job();
+	});
+
+	info!("Stratum server listening on {}.", config.addr);
+	thread::spawn(move || for conn in listener.incoming() {
+		match conn {
+			Ok(stream) => {
+				let s = stratum.clone();
+				thread::spawn(move || s.handle_client(stream));
+			}
+			Err(e) => warn!("Could not accept stratum client: {:?}", e),
+		}
+	});
+	Ok(())
+}
+
+impl Stratum {
+	// builds a new job on top of the current head, replacing the oldest one
+	fn new_job(&self) {
+		let block = match self.chain.head_header() {
+			Ok(head) => {
+				miner::build_block(&self.chain,
+				                   &self.tx_pool,
+				                   &head,
+				                   &**self.reward_provider.lock().unwrap())
+			}
+			Err(e) => {
+				error!("Could not build stratum job, no chain head: {:?}", e);
+				return;
+			}
+		};
+		let mut jobs = self.jobs.write().unwrap();
+		let id = jobs.back().map(|j| j.id + 1).unwrap_or(0);
+		debug!("New stratum job {} at height {}.", id, block.header.height);
+		jobs.push_back(Arc::new(Job {
+			id: id,
+			block: block,
+			nonces: Mutex::new(HashSet::new()),
+		}));
+		if jobs.len() > MAX_JOBS {
+			jobs.pop_front();
+		}
+	}
+
+	fn current_job(&self) -> Option<Arc<Job>> {
+		self.jobs.read().unwrap().back().cloned()
+	}
+
+	fn job_notification(&self) -> Option<Value> {
+		self.current_job().map(|job| {
+			ObjectBuilder::new()
+				.insert("method", "job")
+				.insert("params", job.to_json(self.share_difficulty))
+				.unwrap()
+		})
+	}
+
+	// sends the current job to all clients, dropping the ones gone
+	fn broadcast_job(&self) {
+		if let Some(notif) = self.job_notification() {
+			let mut clients = self.clients.lock().unwrap();
+			clients.retain(|&(_, ref c)| send(&mut c.lock().unwrap(), &notif).is_ok());
+		}
+	}
+
+	// reads and answers the requests of a client until it disconnects
+	fn handle_client(&self, stream: TcpStream) {
+		let peer = stream.peer_addr().ok();
+		debug!("Stratum client {:?} connected.", peer);
+		let writer = match stream.try_clone() {
+			Ok(w) => Arc::new(Mutex::new(w)),
+			Err(e) => {
+				warn!("Could not set up stratum client {:?}: {:?}", peer, e);
+				return;
+			}
+		};
+		if let Some(notif) = self.job_notification() {
+			if send(&mut writer.lock().unwrap(), &notif).is_err() {
+				return;
+			}
+		}
+		let client_id = self.next_client.fetch_add(1, Ordering::Relaxed) as u64;
+		self.clients.lock().unwrap().push((client_id, writer.clone()));
+		let connected = Instant::now();
+		let stats_idx = match peer {
+			Some(addr) => self.stats.lock().unwrap().add_solver(&format!("stratum {}", addr)),
+			None => self.stats.lock().unwrap().add_solver("stratum"),
+		};
+
+		let mut reader = BufReader::new(stream);
+		loop {
+			// reading no more than the maximum length plus the line end
+			let mut line = vec![];
+			match (&mut reader).take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line) {
+				Ok(0) | Err(_) => break,
+				Ok(_) => {}
+			}
+			if line.len() > MAX_LINE_LEN && line.last() != Some(&b'\n') {
+				warn!("Stratum client {:?} sent a line too long, disconnecting.", peer);
+				break;
+			}
+			let line = match String::from_utf8(line) {
+				Ok(line) => line,
+				Err(_) => break,
+			};
+			if line.trim().is_empty() {
+				continue;
+			}
+			let reply = self.handle_request(line.trim(), stats_idx, connected);
+			if send(&mut writer.lock().unwrap(), &reply).is_err() {
+				break;
+			}
+		}
+		self.clients.lock().unwrap().retain(|&(id, _)| id != client_id);
+		debug!("Stratum client {:?} disconnected.", peer);
+	}
+
+	fn handle_request(&self, line: &str, stats_idx: usize, connected: Instant) -> Value {
+		let req: Value = match serde_json::from_str(line) {
+			Ok(req) => req,
+			Err(_) => return reply(Value::Null, Err("malformed request".to_string())),
+		};
+		let id = req.find("id").cloned().unwrap_or(Value::Null);
+		let result = match req.find("method").and_then(|m| m.as_string()) {
+			Some("getjob") => {
+				self.current_job()
+					.map(|job| job.to_json(self.share_difficulty))
+					.ok_or("no job".to_string())
+			}
+			Some("submit") => {
+				let res = self.submit(req.find("params"));
+				let mut stats = self.stats.lock().unwrap();
+				let s = &mut stats.solvers[stats_idx];
+				match res {
+					Ok("block") => s.blocks += 1,
+					Ok(_) => s.solutions += 1,
+					Err(_) => s.rejected += 1,
+				}
+				s.secs = miner::duration_secs(connected);
+				res.map(|res| Value::String(res.to_string()))
+			}
+			_ => Err("unknown method".to_string()),
+		};
+		reply(id, result)
+	}
+
+	// validates a submitted proof at the share difficulty, submitting the
+	// block to the chain if the proof is enough for it
+	fn submit(&self, params: Option<&Value>) -> Result<&'static str, String> {
+		let params = try!(params.ok_or("missing params".to_string()));
+		let job_id = try!(params.find("job_id").and_then(|j| j.as_u64()).ok_or("bad job_id".to_string()));
+		let nonce = try!(params.find("nonce").and_then(|n| n.as_u64()).ok_or("bad nonce".to_string()));
+		let proof = try!(params.find("proof")
+			.and_then(|p| p.as_string())
+			.and_then(|p| p.parse::<core::Proof>().ok())
+			.ok_or("bad proof".to_string()));
+
+		let job = try!(self.jobs
+			.read()
+			.unwrap()
+			.iter()
+			.find(|j| j.id == job_id)
+			.cloned()
+			.ok_or("stale job".to_string()));
+		if job.nonces.lock().unwrap().contains(&nonce) {
+			return Err("duplicate share".to_string());
+		}
+
+		let mut b = job.block.clone();
+		b.header.nonce = nonce;
+		b.header.pow = proof;
+		let difficulty = b.header.difficulty.clone();
+		// a proof good enough for a block is good enough for a share
+		b.header.difficulty = cmp::min(Difficulty::from_num(self.share_difficulty), difficulty.clone());
+		if !pow::verify(&b.header) {
+			return Err("invalid share".to_string());
+		}
+		// only once verified, a bad proof doesn't burn the nonce for the good one
+		if !job.nonces.lock().unwrap().insert(nonce) {
+			return Err("duplicate share".to_string());
+		}
+		b.header.difficulty = difficulty;
+		if !pow::meets_difficulty(proof,
+		                          b.header.pow_family,
+		                          b.header.secondary_scaling,
+		                          &b.header.difficulty) {
+			return Ok("share");
+		}
+
+		info!("Stratum share is a block, adding block {}.", b.hash());
+		match self.chain.process_block(&b, chain::NONE) {
+			Ok(_) => Ok("block"),
+			Err(e) => {
+				error!("Error validating stratum block: {:?}", e);
+				Err(format!("block rejected: {:?}", e))
+			}
+		}
+	}
+}
+
+fn reply(id: Value, result: Result<Value, String>) -> Value {
+	let builder = ObjectBuilder::new().insert("id", id);
+	let builder = match result {
+		Ok(res) => builder.insert("result", res),
+		Err(msg) => builder.insert("error", msg),
+	};
+	builder.unwrap()
+}
+
+// writes the provided value as a line to the provided client
+fn send(stream: &mut TcpStream, msg: &Value) -> io::Result<()> {
+	let line = try!(serde_json::to_string(msg)
+		.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e))));
+	try!(writeln!(stream, "{}", line));
+	stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	use miner::ThrowawayKeys;
+	use pool::test::new_chain;
+
+	fn new_stratum(name: &str) -> Stratum {
+		let chain = new_chain(name);
+		let stratum = Stratum {
+			chain: chain.clone(),
+			tx_pool: Arc::new(RwLock::new(TransactionPool::new(chain))),
+			reward_provider: Mutex::new(Box::new(ThrowawayKeys)),
+			share_difficulty: 1,
+			jobs: RwLock::new(VecDeque::new()),
+			clients: Mutex::new(vec![]),
+			next_client: AtomicUsize::new(0),
+			stats: Arc::new(Mutex::new(miner::MiningStats::default())),
+		};
+		stratum.new_job();
+		stratum
+	}
+
+	// params of the submission of a proof of work for the block of the job
+	// with the provided id, at the provided difficulty
+	fn solve(job_id: u64, b: &core::Block, difficulty: Difficulty) -> Value {
+		let (proof, nonce) = pow::pow_size(b, difficulty, b.header.cuckoo_len as u32).unwrap();
+		ObjectBuilder::new()
+			.insert("job_id", job_id)
+			.insert("nonce", nonce)
+			.insert("proof", proof.to_string())
+			.unwrap()
+	}
+
+	#[test]
+	fn submit_shares_and_blocks() {
+		let stratum = new_stratum("stratum");
+		let job = stratum.current_job().unwrap();
+
+		// the same template needing way more work for a block
+		let mut hard = job.block.clone();
+		hard.header.difficulty = Difficulty::from_num(1 << 30);
+		stratum.jobs.write().unwrap().push_back(Arc::new(Job {
+			id: job.id + 1,
+			block: hard.clone(),
+			nonces: Mutex::new(HashSet::new()),
+		}));
+		let share = solve(job.id + 1, &hard, Difficulty::one());
+		// a bad proof doesn't keep the good one for the same nonce out
+		let bad = ObjectBuilder::new()
+			.insert("job_id", job.id + 1)
+			.insert("nonce", share.find("nonce").unwrap().as_u64().unwrap())
+			.insert("proof", core::Proof::zero().to_string())
+			.unwrap();
+		assert_eq!(stratum.submit(Some(&bad)), Err("invalid share".to_string()));
+		assert_eq!(stratum.submit(Some(&share)), Ok("share"));
+		assert_eq!(stratum.submit(Some(&share)), Err("duplicate share".to_string()));
+		let stale = solve(job.id + MAX_JOBS as u64, &hard, Difficulty::one());
+		assert_eq!(stratum.submit(Some(&stale)), Err("stale job".to_string()));
+		assert_eq!(stratum.chain.head().height, 0);
+
+		let block = solve(job.id, &job.block, job.block.header.difficulty.clone());
+		assert_eq!(stratum.submit(Some(&block)), Ok("block"));
+		assert_eq!(stratum.chain.head().height, 1);
+	}
+}
@@ -19,8 +19,10 @@ extern crate grin_chain as chain;
 
 extern crate env_logger;
 extern crate futures;
+extern crate rand;
 extern crate tokio_core;
 
+use std::env;
 use std::io;
 use std::thread;
 use std::time;
@@ -36,14 +38,17 @@ fn simulate_servers() {
   let mut evtlp = reactor::Core::new().unwrap();
   let handle = evtlp.handle();
 
-  // instantiates 5 servers on different ports
+  // instantiates 5 servers on different ports, each with a new db
+  let run = rand::random::<u32>();
   let mut servers = vec![];
   for n in 0..5 {
+      let db_root = env::temp_dir().join(format!("grin-simulnet-{}-{}", run, n));
       let s = grin::Server::future(
           grin::ServerConfig{
-            db_root: format!("target/grin-{}", n),
-            cuckoo_size: 12,
-            p2p_config: p2p::P2PConfig{port: 10000+n, ..p2p::P2PConfig::default()}
+            db_root: db_root.to_str().unwrap().to_string(),
+            p2p_config: p2p::P2PConfig{port: 10000+n, ..p2p::P2PConfig::default()},
+            chain_config: chain::ChainConfig::for_chain(core::genesis::ChainTypes::Usernet),
+            ..grin::ServerConfig::default()
           }, &handle).unwrap();
       servers.push(s);
   }
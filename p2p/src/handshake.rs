@@ -13,51 +13,68 @@
 // limitations under the License.
 
 use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
 use futures::Future;
-use futures::future::ok;
+use futures::future::err;
 use rand::Rng;
 use rand::os::OsRng;
 use tokio_core::net::TcpStream;
-use tokio_core::io::{write_all, read_exact, read_to_end};
 
-use core::ser::{serialize, deserialize, Error};
+use core::core::target::Difficulty;
+use core::ser::Error;
 use msg::*;
 use types::*;
 use protocol::ProtocolV1;
 
 const NONCES_CAP: usize = 100;
 
+// A handshake in progress, resolving to the connection, its protocol and
+// what the peer advertised
+type Handshaking = Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>>;
+
 /// Handles the handshake negotiation when two peers connect and decides on
-/// protocol.
+/// protocol. Both sides advertise their protocol version, capabilities, total
+/// difficulty and user agent, the side initiating the connection also the
+/// port it listens on. Peers with another protocol version are refused.
 pub struct Handshake {
 	/// Ring buffer of nonces sent to detect self connections without requiring
 	/// a node id.
 	nonces: Arc<RwLock<VecDeque<u64>>>,
+	/// Capabilities we advertise
+	capabilities: Capabilities,
+	/// Port we accept connections on
+	listen_port: u16,
 }
 
 unsafe impl Sync for Handshake {}
 unsafe impl Send for Handshake {}
 
 impl Handshake {
-	/// Creates a new handshake handler
-	pub fn new() -> Handshake {
-		Handshake { nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))) }
+	/// Creates a new handshake handler, advertising the capabilities and port
+	/// of the provided configuration.
+	pub fn new(config: P2PConfig) -> Handshake {
+		Handshake {
+			nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))),
+			capabilities: config.capabilities,
+			listen_port: config.port,
+		}
 	}
 
-	/// Handles connecting to a new remote peer, starting the version handshake.
-	pub fn connect(&self,
-	               conn: TcpStream)
-	               -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
+	/// Handles connecting to a new remote peer, starting the version handshake
+	/// with the provided total difficulty of our chain.
+	pub fn connect(&self, total_difficulty: Difficulty, conn: TcpStream) -> Handshaking {
 		// prepare the first part of the hanshake
 		let nonce = self.next_nonce();
 		let hand = Hand {
 			version: PROTOCOL_VERSION,
-			capabilities: FULL_SYNC,
+			capabilities: self.capabilities,
 			nonce: nonce,
+			total_difficulty: total_difficulty,
 			sender_addr: SockAddr(conn.local_addr().unwrap()),
 			receiver_addr: SockAddr(conn.peer_addr().unwrap()),
+			listen_port: self.listen_port,
 			user_agent: USER_AGENT.to_string(),
 		};
 
@@ -65,69 +82,74 @@ impl Handshake {
 		Box::new(write_msg(conn, hand, Type::Hand)
 			.and_then(|conn| read_msg::<Shake>(conn))
 			.and_then(|(conn, shake)| {
-				if shake.version != 1 {
-					Err(Error::UnexpectedData {
-						expected: vec![PROTOCOL_VERSION as u8],
-						received: vec![shake.version as u8],
-					})
-				} else {
-					let peer_info = PeerInfo {
-						capabilities: shake.capabilities,
-						user_agent: shake.user_agent,
-						addr: conn.peer_addr().unwrap(),
-						version: shake.version,
-					};
+				try!(check_version(shake.version));
+				let addr = conn.peer_addr().unwrap();
+				let peer_info = PeerInfo {
+					capabilities: shake.capabilities,
+					user_agent: shake.user_agent,
+					addr: addr,
+					listen_addr: addr,
+					version: shake.version,
+					total_difficulty: shake.total_difficulty,
+					direction: Direction::Outbound,
+				};
 
-					info!("Connected to peer {:?}", peer_info);
-					// when more than one protocol version is supported, choosing should go here
-					Ok((conn, ProtocolV1::new(), peer_info))
-				}
+				info!("Connected to peer {:?}", peer_info);
+				// when more than one protocol version is supported, choosing should go here
+				Ok((conn, ProtocolV1::new(), peer_info))
 			}))
 	}
 
 	/// Handles receiving a connection from a new remote peer that started the
-	/// version handshake.
-	pub fn handshake(&self,
-	                 conn: TcpStream)
-	                 -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
+	/// version handshake, replying with the provided total difficulty of our
+	/// chain. Peers with another protocol version get an error message before
+	/// being refused.
+	pub fn handshake(&self, total_difficulty: Difficulty, conn: TcpStream) -> Handshaking {
 		let nonces = self.nonces.clone();
+		let capabilities = self.capabilities;
 		Box::new(read_msg::<Hand>(conn)
-			.and_then(move |(conn, hand)| {
-				if hand.version != 1 {
-					return Err(Error::UnexpectedData {
-						expected: vec![PROTOCOL_VERSION as u8],
-						received: vec![hand.version as u8],
-					});
+			.and_then(move |(conn, hand)| -> Handshaking {
+				if let Err(e) = check_version(hand.version) {
+					let msg = PeerError {
+						code: ErrCodes::UnsupportedVersion as u32,
+						message: format!("unsupported protocol version {}, expected {}",
+						                 hand.version,
+						                 PROTOCOL_VERSION),
+					};
+					return Box::new(write_msg(conn, msg, Type::Error).and_then(|_| Err(e)));
 				}
 				{
 					// check the nonce to see if we could be trying to connect to ourselves
 					let nonces = nonces.read().unwrap();
 					if nonces.contains(&hand.nonce) {
-						return Err(Error::UnexpectedData {
+						return Box::new(err(Error::UnexpectedData {
 							expected: vec![],
 							received: vec![],
-						});
+						}));
 					}
 				}
 				// all good, keep peer info
+				let addr = conn.peer_addr().unwrap();
 				let peer_info = PeerInfo {
 					capabilities: hand.capabilities,
 					user_agent: hand.user_agent,
-					addr: conn.peer_addr().unwrap(),
+					addr: addr,
+					listen_addr: SocketAddr::new(addr.ip(), hand.listen_port),
 					version: hand.version,
+					total_difficulty: hand.total_difficulty,
+					direction: Direction::Inbound,
 				};
 				// send our reply with our info
 				let shake = Shake {
 					version: PROTOCOL_VERSION,
-					capabilities: FULL_SYNC,
+					capabilities: capabilities,
+					total_difficulty: total_difficulty,
 					user_agent: USER_AGENT.to_string(),
 				};
-				Ok((conn, shake, peer_info))
-			})
-			.and_then(|(conn, shake, peer_info)| {
-				write_msg(conn, shake, Type::Shake)
-				  // when more than one protocol version is supported, choosing should go here
-					.map(|conn| (conn, ProtocolV1::new(), peer_info))
+				info!("Accepted peer {:?}", peer_info);
+				// when more than one protocol version is supported, choosing should go here
+				Box::new(write_msg(conn, shake, Type::Shake)
+					.map(|conn| (conn, ProtocolV1::new(), peer_info)))
 			}))
 	}
 
@@ -144,3 +166,14 @@ impl Handshake {
 		nonce
 	}
 }
+
+// Checks the protocol version of a peer is one we can talk to
+fn check_version(version: u32) -> Result<(), Error> {
+	if version != PROTOCOL_VERSION {
+		return Err(Error::UnexpectedData {
+			expected: vec![PROTOCOL_VERSION as u8],
+			received: vec![version as u8],
+		});
+	}
+	Ok(())
+}
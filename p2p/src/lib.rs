@@ -26,6 +26,8 @@ extern crate bitflags;
 extern crate enum_primitive;
 #[macro_use]
 extern crate grin_core as core;
+extern crate grin_store;
+extern crate grin_util as util;
 #[macro_use]
 extern crate log;
 extern crate futures;
@@ -40,8 +42,11 @@ mod msg;
 mod peer;
 mod protocol;
 mod server;
+mod store;
 mod types;
 
 pub use server::{Server, DummyAdapter};
 pub use peer::Peer;
-pub use types::{P2PConfig, NetAdapter};
+pub use store::{PeerStore, PeerData, State};
+pub use types::{P2PConfig, NetAdapter, PeerInfo, Direction, Capabilities, UNKNOWN, FULL_SYNC,
+                Misbehavior, BAN_SCORE, MAX_BLOCK_HEADERS};
@@ -21,13 +21,17 @@ use futures::future::{Future, ok};
 use tokio_core::net::TcpStream;
 use tokio_core::io::{write_all, read_exact};
 
+use core::core::BlockHeader;
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
 use core::ser::{self, Writeable, Readable, Writer, Reader};
 use core::consensus::MAX_MSG_LEN;
 
 use types::*;
 
-/// Current latest version of the protocol
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Current latest version of the protocol, peers with any other version are
+/// refused during the handshake
+pub const PROTOCOL_VERSION: u32 = 2;
 /// Grin's user agent with current version (TODO externalize)
 pub const USER_AGENT: &'static str = "MW/Grin 0.1";
 
@@ -37,6 +41,10 @@ const MAGIC: [u8; 2] = [0x1e, 0xc5];
 /// Size in bytes of a message header
 pub const HEADER_LEN: u64 = 11;
 
+/// Most block hashes in a locator, way more than a locator of our main chain
+/// ever needs
+const MAX_LOCATOR_HASHES: u32 = 64;
+
 /// Codes for each error that can be produced reading a message.
 pub enum ErrCodes {
 	UnsupportedVersion = 100,
@@ -55,6 +63,11 @@ enum_from_primitive! {
     PeerAddrs,
     Block,
     Transaction,
+    GetTransaction,
+    GetHeaders,
+    Headers,
+    GetBlock,
+    StemTransaction,
   }
 }
 
@@ -174,10 +187,14 @@ pub struct Hand {
 	pub capabilities: Capabilities,
 	/// randomly generated for each handshake, helps detect self
 	pub nonce: u64,
+	/// total difficulty of the chain of the sender
+	pub total_difficulty: Difficulty,
 	/// network address of the sender
 	pub sender_addr: SockAddr,
 	/// network address of the receiver
 	pub receiver_addr: SockAddr,
+	/// port the sender accepts connections on
+	pub listen_port: u16,
 	/// name of version of the software
 	pub user_agent: String,
 }
@@ -188,26 +205,33 @@ impl Writeable for Hand {
 		                [write_u32, self.version],
 		                [write_u32, self.capabilities.bits()],
 		                [write_u64, self.nonce]);
-		self.sender_addr.write(writer);
-		self.receiver_addr.write(writer);
-		writer.write_bytes(&self.user_agent)
+		try!(self.total_difficulty.write(writer));
+		try!(self.sender_addr.write(writer));
+		try!(self.receiver_addr.write(writer));
+		ser_multiwrite!(writer,
+		                [write_u16, self.listen_port],
+		                [write_bytes, &self.user_agent]);
+		Ok(())
 	}
 }
 
 impl Readable<Hand> for Hand {
 	fn read(reader: &mut Reader) -> Result<Hand, ser::Error> {
 		let (version, capab, nonce) = ser_multiread!(reader, read_u32, read_u32, read_u64);
+		let total_difficulty = try!(Difficulty::read(reader));
 		let sender_addr = try!(SockAddr::read(reader));
 		let receiver_addr = try!(SockAddr::read(reader));
-		let ua = try!(reader.read_vec());
+		let (listen_port, ua) = ser_multiread!(reader, read_u16, read_vec);
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
 		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
 		Ok(Hand {
 			version: version,
 			capabilities: capabilities,
 			nonce: nonce,
+			total_difficulty: total_difficulty,
 			sender_addr: sender_addr,
 			receiver_addr: receiver_addr,
+			listen_port: listen_port,
 			user_agent: user_agent,
 		})
 	}
@@ -220,6 +244,8 @@ pub struct Shake {
 	pub version: u32,
 	/// sender capabilities
 	pub capabilities: Capabilities,
+	/// total difficulty of the chain of the sender
+	pub total_difficulty: Difficulty,
 	/// name of version of the software
 	pub user_agent: String,
 }
@@ -228,20 +254,23 @@ impl Writeable for Shake {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
 		ser_multiwrite!(writer,
 		                [write_u32, self.version],
-		                [write_u32, self.capabilities.bits()],
-		                [write_bytes, &self.user_agent]);
-		Ok(())
+		                [write_u32, self.capabilities.bits()]);
+		try!(self.total_difficulty.write(writer));
+		writer.write_bytes(&self.user_agent)
 	}
 }
 
 impl Readable<Shake> for Shake {
 	fn read(reader: &mut Reader) -> Result<Shake, ser::Error> {
-		let (version, capab, ua) = ser_multiread!(reader, read_u32, read_u32, read_vec);
+		let (version, capab) = ser_multiread!(reader, read_u32, read_u32);
+		let total_difficulty = try!(Difficulty::read(reader));
+		let ua = try!(reader.read_vec());
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
 		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
 		Ok(Shake {
 			version: version,
 			capabilities: capabilities,
+			total_difficulty: total_difficulty,
 			user_agent: user_agent,
 		})
 	}
@@ -294,6 +323,60 @@ impl Readable<PeerAddrs> for PeerAddrs {
 	}
 }
 
+/// Ask for the headers of the main chain of the remote peer following the
+/// most recent block of the locator it has, see `Chain::get_locator`.
+pub struct Locator {
+	pub hashes: Vec<Hash>,
+}
+
+impl Writeable for Locator {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u32(self.hashes.len() as u32));
+		for h in &self.hashes {
+			try!(h.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<Locator> for Locator {
+	fn read(reader: &mut Reader) -> Result<Locator, ser::Error> {
+		let len = try!(reader.read_u32());
+		if len > MAX_LOCATOR_HASHES {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let hashes = try_map_vec!([0..len], |_| Hash::read(reader));
+		Ok(Locator { hashes: hashes })
+	}
+}
+
+/// Headers of our main chain, in response to GetHeaders, at most
+/// MAX_BLOCK_HEADERS of them one after the other.
+pub struct Headers {
+	pub headers: Vec<BlockHeader>,
+}
+
+impl Writeable for Headers {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u32(self.headers.len() as u32));
+		for bh in &self.headers {
+			try!(bh.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<Headers> for Headers {
+	fn read(reader: &mut Reader) -> Result<Headers, ser::Error> {
+		let len = try!(reader.read_u32());
+		if len as u64 > MAX_BLOCK_HEADERS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let headers = try_map_vec!([0..len], |_| BlockHeader::read(reader));
+		Ok(Headers { headers: headers })
+	}
+}
+
 /// We found some issue in the communication, sending an error back, usually
 /// followed by closing the connection.
 pub struct PeerError {
@@ -391,3 +474,45 @@ impl Readable<Empty> for Empty {
 		Ok(Empty {})
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::core::hash::{Hashed, ZERO_HASH};
+
+	#[test]
+	fn locator_round_trip() {
+		let hashes = (0..MAX_LOCATOR_HASHES as u8).map(|n| Hash([n; 32])).collect::<Vec<_>>();
+		let data = ser::ser_vec(&Locator { hashes: hashes.clone() }).unwrap();
+		let locator = ser::deserialize::<Locator>(&mut &data[..]).unwrap();
+		assert_eq!(locator.hashes, hashes);
+
+		// one more hash and it's refused before reading any
+		let too_many = vec![ZERO_HASH; MAX_LOCATOR_HASHES as usize + 1];
+		let data = ser::ser_vec(&Locator { hashes: too_many }).unwrap();
+		match ser::deserialize::<Locator>(&mut &data[..]) {
+			Err(ser::Error::TooLargeReadErr) => {}
+			res => panic!("unexpected result {:?}", res.map(|l| l.hashes)),
+		}
+	}
+
+	#[test]
+	fn headers_round_trip() {
+		let mut bh = BlockHeader::default();
+		let mut headers = vec![bh.clone()];
+		bh.height = 1;
+		bh.previous = headers[0].hash();
+		headers.push(bh);
+		let data = ser::ser_vec(&Headers { headers: headers.clone() }).unwrap();
+		let read = ser::deserialize::<Headers>(&mut &data[..]).unwrap();
+		assert_eq!(read.headers.iter().map(|bh| bh.hash()).collect::<Vec<_>>(),
+		           headers.iter().map(|bh| bh.hash()).collect::<Vec<_>>());
+
+		let too_many = vec![BlockHeader::default(); MAX_BLOCK_HEADERS as usize + 1];
+		let data = ser::ser_vec(&Headers { headers: too_many }).unwrap();
+		match ser::deserialize::<Headers>(&mut &data[..]) {
+			Err(ser::Error::TooLargeReadErr) => {}
+			res => panic!("unexpected result {:?}", res.map(|h| h.headers.len())),
+		}
+	}
+}
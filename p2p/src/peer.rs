@@ -13,11 +13,14 @@
 // limitations under the License.
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::Future;
 use tokio_core::net::TcpStream;
 
 use core::core;
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
 use core::ser::Error;
 use handshake::Handshake;
 use types::*;
@@ -25,33 +28,42 @@ use types::*;
 pub struct Peer {
 	info: PeerInfo,
 	proto: Box<Protocol>,
+	connected: Instant,
 }
 
 unsafe impl Sync for Peer {}
 unsafe impl Send for Peer {}
 
 impl Peer {
+	/// Connects to a remote peer over the provided connection, handshaking
+	/// with the provided total difficulty of our chain.
 	pub fn connect(conn: TcpStream,
+	               total_difficulty: Difficulty,
 	               hs: &Handshake)
 	               -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
-		let connect_peer = hs.connect(conn).and_then(|(conn, proto, info)| {
+		let connect_peer = hs.connect(total_difficulty, conn).and_then(|(conn, proto, info)| {
 			Ok((conn,
 			    Peer {
 				info: info,
 				proto: Box::new(proto),
+				connected: Instant::now(),
 			}))
 		});
 		Box::new(connect_peer)
 	}
 
+	/// Accepts a remote peer that connected over the provided connection,
+	/// handshaking with the provided total difficulty of our chain.
 	pub fn accept(conn: TcpStream,
+	              total_difficulty: Difficulty,
 	              hs: &Handshake)
 	              -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
-		let hs_peer = hs.handshake(conn).and_then(|(conn, proto, info)| {
+		let hs_peer = hs.handshake(total_difficulty, conn).and_then(|(conn, proto, info)| {
 			Ok((conn,
 			    Peer {
 				info: info,
 				proto: Box::new(proto),
+				connected: Instant::now(),
 			}))
 		});
 		Box::new(hs_peer)
@@ -64,6 +76,16 @@ impl Peer {
 		self.proto.handle(conn, na)
 	}
 
+	/// What the remote peer advertised about itself during the handshake.
+	pub fn info(&self) -> &PeerInfo {
+		&self.info
+	}
+
+	/// How long since the handshake with the remote peer completed.
+	pub fn connected_for(&self) -> Duration {
+		self.connected.elapsed()
+	}
+
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
 		self.proto.transmitted_bytes()
 	}
@@ -72,13 +94,59 @@ impl Peer {
 		self.proto.send_ping()
 	}
 
-	/// Sends the provided block to the remote peer. The request may be dropped
+	/// Asks the remote peer for the addresses of the peers it knows of with
+	/// the provided capabilities.
+	pub fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error> {
+		self.proto.send_peer_request(capab)
+	}
+
+	/// Asks the remote peer for the headers of its main chain following the
+	/// most recent block of the provided locator it has.
+	pub fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
+		self.proto.send_header_request(locator)
+	}
+
+	/// Sends the provided block to the remote peer. The request gets dropped
 	/// if the remote peer is known to already have the block.
 	pub fn send_block(&self, b: &core::Block) -> Result<(), Error> {
-		// TODO do not send if the peer sent us the block in the first place
 		self.proto.send_block(b)
 	}
 
+	/// Asks the remote peer for the block with the provided hash, keeping
+	/// track of the request until the block comes.
+	pub fn send_block_request(&self, h: Hash) -> Result<(), Error> {
+		self.proto.send_block_request(h)
+	}
+
+	/// Hashes of the blocks we asked the remote peer for that didn't come
+	/// yet.
+	pub fn pending_block_requests(&self) -> Vec<Hash> {
+		self.proto.pending_block_requests()
+	}
+
+	/// Hashes of the blocks we asked the remote peer for over the provided
+	/// duration ago that didn't come yet, no longer tracked from then on.
+	pub fn stale_block_requests(&self, timeout: Duration) -> Vec<Hash> {
+		self.proto.stale_block_requests(timeout)
+	}
+
+	/// Sends the provided transaction to the remote peer. The request gets
+	/// dropped if the remote peer is known to already have the transaction.
+	pub fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
+		self.proto.send_transaction(tx)
+	}
+
+	/// Sends the provided transaction to the remote peer along the Dandelion
+	/// stem, whether it's known to have the transaction or not.
+	pub fn send_stem_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
+		self.proto.send_stem_transaction(tx)
+	}
+
+	/// Asks the remote peer for the transaction with the provided hash.
+	pub fn send_transaction_request(&self, h: Hash) -> Result<(), Error> {
+		self.proto.send_transaction_request(h)
+	}
+
 	pub fn stop(&self) {
 		self.proto.close();
 	}
@@ -12,25 +12,42 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
 use std::iter;
+use std::net::SocketAddr;
 use std::ops::DerefMut;
 use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
 
 use futures;
 use futures::{Stream, Future};
 use futures::stream;
+use futures::sync::oneshot;
 use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
 use tokio_core::io::{Io, WriteHalf, ReadHalf, write_all, read_exact};
 use tokio_core::net::TcpStream;
 
 use core::core;
+use core::core::hash::{Hash, Hashed};
 use core::ser;
 use msg::*;
 use types::*;
+use util::LruCache;
+
+/// Number of block hashes remembered as known to the remote peer
+const MAX_SEEN_BLOCKS: usize = 1000;
+
+/// Number of transaction hashes remembered as known to the remote peer
+const MAX_SEEN_TXS: usize = 5000;
 
 pub struct ProtocolV1 {
-	outbound_chan: RefCell<Option<UnboundedSender<Vec<u8>>>>,
+	// Sends messages to the connection writer, from any thread, the ones
+	// asking peers for blocks or relaying transactions included.
+	outbound_chan: Mutex<Option<UnboundedSender<Vec<u8>>>>,
+
+	// Ends the connection when we close it.
+	close_chan: Mutex<Option<oneshot::Sender<()>>>,
 
 	// Bytes we've sent.
 	sent_bytes: Arc<Mutex<u64>>,
@@ -40,15 +57,28 @@ pub struct ProtocolV1 {
 
 	// Counter for read errors.
 	error_count: Mutex<u64>,
+
+	// Blocks the remote peer sent us or we sent it, not to be sent again.
+	seen_blocks: Arc<Mutex<LruCache<Hash, ()>>>,
+
+	// Transactions the remote peer sent us or we sent it, same as blocks.
+	seen_txs: Arc<Mutex<LruCache<Hash, ()>>>,
+
+	// Blocks we asked the remote peer for, with when we did.
+	requested_blocks: Arc<Mutex<HashMap<Hash, Instant>>>,
 }
 
 impl ProtocolV1 {
 	pub fn new() -> ProtocolV1 {
 		ProtocolV1 {
-			outbound_chan: RefCell::new(None),
+			outbound_chan: Mutex::new(None),
+			close_chan: Mutex::new(None),
 			sent_bytes: Arc::new(Mutex::new(0)),
 			received_bytes: Arc::new(Mutex::new(0)),
 			error_count: Mutex::new(0),
+			seen_blocks: Arc::new(Mutex::new(LruCache::new(MAX_SEEN_BLOCKS))),
+			seen_txs: Arc::new(Mutex::new(LruCache::new(MAX_SEEN_TXS))),
+			requested_blocks: Arc::new(Mutex::new(HashMap::new())),
 		}
 	}
 }
@@ -58,24 +88,36 @@ impl Protocol for ProtocolV1 {
 	          conn: TcpStream,
 	          adapter: Arc<NetAdapter>)
 	          -> Box<Future<Item = (), Error = ser::Error>> {
+		let addr = conn.peer_addr().unwrap();
 		let (reader, writer) = conn.split();
 
 		// prepare the channel that will transmit data to the connection writer
 		let (tx, rx) = futures::sync::mpsc::unbounded();
 		{
-			let mut out_mut = self.outbound_chan.borrow_mut();
+			let mut out_mut = self.outbound_chan.lock().unwrap();
 			*out_mut = Some(tx.clone());
 		}
 
 		// setup the reading future, getting messages from the peer and processing them
-		let read_msg = self.read_msg(tx, reader, adapter).map(|_| ());
+		let read_msg = self.read_msg(tx, reader, adapter, addr).map(|_| ());
 
 		// setting the writing future, getting messages from our system and sending
 		// them out
 		let write_msg = self.write_msg(rx, writer).map(|_| ());
 
-		// select between our different futures and return them
-		Box::new(read_msg.select(write_msg).map(|_| ()).map_err(|(e, _)| e))
+		// and the one completing when we close the connection, or when the
+		// protocol gets dropped
+		let (close_tx, close_rx) = oneshot::channel();
+		{
+			let mut close_mut = self.close_chan.lock().unwrap();
+			*close_mut = Some(close_tx);
+		}
+		let close = close_rx.then(|_| Ok::<(), ser::Error>(()));
+
+		// select between our different futures and return them, dropping the
+		// connection as soon as one of them completes
+		let run = read_msg.select(write_msg).map(|_| ()).map_err(|(e, _)| e);
+		Box::new(run.select(close).map(|_| ()).map_err(|(e, _)| e))
 	}
 
 	/// Bytes sent and received by this peer to the remote peer.
@@ -85,25 +127,95 @@ impl Protocol for ProtocolV1 {
 		(sent, recv)
 	}
 
-	/// Sends a ping message to the remote peer. Will panic if handle has never
-	/// been called on this protocol.
+	/// Sends a ping message to the remote peer. Fails if handle has never
+	/// been called on this protocol or once it's closed.
 	fn send_ping(&self) -> Result<(), ser::Error> {
 		self.send_msg(Type::Ping, &Empty {})
 	}
 
-	/// Serializes and sends a block to our remote peer
+	/// Serializes and sends a block to our remote peer, unless it sent us
+	/// the block or we sent it already
 	fn send_block(&self, b: &core::Block) -> Result<(), ser::Error> {
+		{
+			let mut seen_blocks = self.seen_blocks.lock().unwrap();
+			let bhash = b.hash();
+			if seen_blocks.contains_key(&bhash) {
+				return Ok(());
+			}
+			seen_blocks.insert(bhash, ());
+		}
 		self.send_msg(Type::Block, b)
 	}
 
-	/// Serializes and sends a transaction to our remote peer
+	/// Asks our remote peer for the block with the provided hash, tracking the
+	/// request until the block comes
+	fn send_block_request(&self, h: Hash) -> Result<(), ser::Error> {
+		self.requested_blocks.lock().unwrap().insert(h, Instant::now());
+		self.send_msg(Type::GetBlock, &h)
+	}
+
+	/// Blocks we asked our remote peer for that didn't come yet
+	fn pending_block_requests(&self) -> Vec<Hash> {
+		self.requested_blocks.lock().unwrap().keys().cloned().collect()
+	}
+
+	/// Blocks we asked our remote peer for over the provided duration ago,
+	/// which we stop waiting for
+	fn stale_block_requests(&self, timeout: Duration) -> Vec<Hash> {
+		let mut requested = self.requested_blocks.lock().unwrap();
+		let stale = requested.iter()
+			.filter(|&(_, at)| at.elapsed() >= timeout)
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		for h in &stale {
+			requested.remove(h);
+		}
+		stale
+	}
+
+	/// Serializes and sends a transaction to our remote peer, unless it sent
+	/// us the transaction or we sent it already
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), ser::Error> {
+		{
+			let mut seen_txs = self.seen_txs.lock().unwrap();
+			let txhash = tx.hash();
+			if seen_txs.contains_key(&txhash) {
+				return Ok(());
+			}
+			seen_txs.insert(txhash, ());
+		}
 		self.send_msg(Type::Transaction, tx)
 	}
 
-	/// Close the connection to the remote peer
+	/// Serializes and sends a transaction to our remote peer along the stem,
+	/// the peer not being assumed to have it once broadcast
+	fn send_stem_transaction(&self, tx: &core::Transaction) -> Result<(), ser::Error> {
+		self.send_msg(Type::StemTransaction, tx)
+	}
+
+	/// Asks our remote peer for the transaction with the provided hash
+	fn send_transaction_request(&self, h: Hash) -> Result<(), ser::Error> {
+		self.send_msg(Type::GetTransaction, &h)
+	}
+
+	/// Asks our remote peer for the addresses of the peers it knows of
+	fn send_peer_request(&self, capab: Capabilities) -> Result<(), ser::Error> {
+		self.send_msg(Type::GetPeerAddrs, &GetPeerAddrs { capabilities: capab })
+	}
+
+	/// Asks our remote peer for the headers following the most recent block of
+	/// the provided locator it has
+	fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), ser::Error> {
+		self.send_msg(Type::GetHeaders, &Locator { hashes: locator })
+	}
+
+	/// Close the connection to the remote peer, nothing more getting sent
+	/// to it
 	fn close(&self) {
-		// TODO some kind of shutdown signal
+		self.outbound_chan.lock().unwrap().take();
+		if let Some(close) = self.close_chan.lock().unwrap().take() {
+			close.complete(());
+		}
 	}
 }
 
@@ -113,7 +225,8 @@ impl ProtocolV1 {
 	fn read_msg(&self,
 	            sender: UnboundedSender<Vec<u8>>,
 	            reader: ReadHalf<TcpStream>,
-	            adapter: Arc<NetAdapter>)
+	            adapter: Arc<NetAdapter>,
+	            addr: SocketAddr)
 	            -> Box<Future<Item = ReadHalf<TcpStream>, Error = ser::Error>> {
 
 		// infinite iterator stream so we repeat the message reading logic until the
@@ -122,10 +235,16 @@ impl ProtocolV1 {
 
 		// setup the reading future, getting messages from the peer and processing them
 		let recv_bytes = self.received_bytes.clone();
+		let seen_blocks = self.seen_blocks.clone();
+		let seen_txs = self.seen_txs.clone();
+		let requested_blocks = self.requested_blocks.clone();
 		let read_msg = iter.fold(reader, move |reader, _| {
 			let mut sender_inner = sender.clone();
 			let recv_bytes = recv_bytes.clone();
 			let adapter = adapter.clone();
+			let seen_blocks = seen_blocks.clone();
+			let seen_txs = seen_txs.clone();
+			let requested_blocks = requested_blocks.clone();
 
 			// first read the message header
 			read_exact(reader, vec![0u8; HEADER_LEN as usize])
@@ -146,8 +265,17 @@ impl ProtocolV1 {
 					*recv_bytes += header.serialized_len() + header.msg_len;
 
 					// and handle the different message types
-					if let Err(e) = handle_payload(adapter, &header, buf, &mut sender_inner) {
+					let res = handle_payload(adapter.clone(),
+					                         addr,
+					                         &header,
+					                         buf,
+					                         &mut sender_inner,
+					                         &seen_blocks,
+					                         &seen_txs,
+					                         &requested_blocks);
+					if let Err(e) = res {
 						debug!("Invalid {:?} message: {}", header.msg_type, e);
+						adapter.peer_misbehaved(addr, Misbehavior::MalformedMessage);
 					}
 
 					reader
@@ -186,18 +314,27 @@ impl ProtocolV1 {
 		try!(ser::serialize(&mut data, &MsgHeader::new(t, body_data.len() as u64)));
 		data.append(&mut body_data);
 
-		let mut msg_send = self.outbound_chan.borrow_mut();
-		if let Err(e) = msg_send.deref_mut().as_mut().unwrap().send(data) {
-			warn!("Couldn't send message to remote peer: {}", e);
+		let mut msg_send = self.outbound_chan.lock().unwrap();
+		match msg_send.deref_mut().as_mut() {
+			Some(sender) => {
+				if let Err(e) = sender.send(data) {
+					warn!("Couldn't send message to remote peer: {}", e);
+				}
+				Ok(())
+			}
+			None => Err(ser::Error::IOErr(io::Error::new(io::ErrorKind::NotConnected, "closed"))),
 		}
-		Ok(())
 	}
 }
 
 fn handle_payload(adapter: Arc<NetAdapter>,
+                  addr: SocketAddr,
                   header: &MsgHeader,
                   buf: Vec<u8>,
-                  sender: &mut UnboundedSender<Vec<u8>>)
+                  sender: &mut UnboundedSender<Vec<u8>>,
+                  seen_blocks: &Mutex<LruCache<Hash, ()>>,
+                  seen_txs: &Mutex<LruCache<Hash, ()>>,
+                  requested_blocks: &Mutex<HashMap<Hash, Instant>>)
                   -> Result<(), ser::Error> {
 	match header.msg_type {
 		Type::Ping => {
@@ -207,11 +344,69 @@ fn handle_payload(adapter: Arc<NetAdapter>,
 		Type::Pong => {}
 		Type::Transaction => {
 			let tx = try!(ser::deserialize::<core::Transaction>(&mut &buf[..]));
-			adapter.transaction_received(tx);
+			// no need to send it back to the peer
+			seen_txs.lock().unwrap().insert(tx.hash(), ());
+			adapter.transaction_received(tx, addr);
+		}
+		Type::StemTransaction => {
+			// the peer still needs the transaction once broadcast
+			let tx = try!(ser::deserialize::<core::Transaction>(&mut &buf[..]));
+			adapter.stem_transaction_received(tx, addr);
+		}
+		Type::GetTransaction => {
+			let h = try!(ser::deserialize::<Hash>(&mut &buf[..]));
+			if let Some(tx) = adapter.get_transaction(h) {
+				seen_txs.lock().unwrap().insert(h, ());
+				let mut body = try!(ser::ser_vec(&tx));
+				let mut data = try!(ser::ser_vec(&MsgHeader::new(Type::Transaction,
+				                                                 body.len() as u64)));
+				data.append(&mut body);
+				sender.send(data);
+			}
 		}
 		Type::Block => {
 			let b = try!(ser::deserialize::<core::Block>(&mut &buf[..]));
-			adapter.block_received(b);
+			// no need to send it back to the peer, nor to wait for it anymore
+			let bhash = b.hash();
+			seen_blocks.lock().unwrap().insert(bhash, ());
+			requested_blocks.lock().unwrap().remove(&bhash);
+			adapter.block_received(b, addr);
+		}
+		Type::GetBlock => {
+			let h = try!(ser::deserialize::<Hash>(&mut &buf[..]));
+			if let Some(b) = adapter.get_block(h) {
+				seen_blocks.lock().unwrap().insert(h, ());
+				let mut body = try!(ser::ser_vec(&b));
+				let mut data = try!(ser::ser_vec(&MsgHeader::new(Type::Block, body.len() as u64)));
+				data.append(&mut body);
+				sender.send(data);
+			}
+		}
+		Type::GetHeaders => {
+			let locator = try!(ser::deserialize::<Locator>(&mut &buf[..]));
+			let headers = adapter.locate_headers(locator.hashes);
+			let mut body = try!(ser::ser_vec(&Headers { headers: headers }));
+			let mut data = try!(ser::ser_vec(&MsgHeader::new(Type::Headers, body.len() as u64)));
+			data.append(&mut body);
+			sender.send(data);
+		}
+		Type::Headers => {
+			let headers = try!(ser::deserialize::<Headers>(&mut &buf[..]));
+			adapter.headers_received(headers.headers, addr);
+		}
+		Type::GetPeerAddrs => {
+			let get_peers = try!(ser::deserialize::<GetPeerAddrs>(&mut &buf[..]));
+			let peer_addrs = adapter.find_peer_addrs(get_peers.capabilities);
+			let mut body = try!(ser::ser_vec(&PeerAddrs {
+				peers: peer_addrs.into_iter().map(SockAddr).collect(),
+			}));
+			let mut data = try!(ser::ser_vec(&MsgHeader::new(Type::PeerAddrs, body.len() as u64)));
+			data.append(&mut body);
+			sender.send(data);
+		}
+		Type::PeerAddrs => {
+			let peer_addrs = try!(ser::deserialize::<PeerAddrs>(&mut &buf[..]));
+			adapter.peer_addrs_received(peer_addrs.peers.into_iter().map(|sa| sa.0).collect());
 		}
 		_ => {
 			debug!("unknown message type {:?}", header.msg_type);
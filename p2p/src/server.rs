@@ -16,9 +16,12 @@
 //! other peers in the network.
 
 use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::u64;
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use futures;
@@ -26,26 +29,200 @@ use futures::{Future, Stream};
 use futures::future::IntoFuture;
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor;
+use time;
 
 use core::core;
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
 use core::ser::Error;
+use grin_store;
 use handshake::Handshake;
 use peer::Peer;
+use store::{PeerData, PeerStore, State};
 use types::*;
 
+/// Most peer addresses shared with a peer asking for them
+const MAX_PEER_ADDRS: usize = 10;
+
+/// Most known peers considered when looking for new ones to connect to
+const MAX_CANDIDATE_PEERS: usize = 100;
+
+// What misbehavior scores and bans go by: the address we connected to for
+// outbound peers, only the IP for inbound peers as they pick the port they
+// advertise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Offender {
+	Addr(SocketAddr),
+	Ip(IpAddr),
+}
+
+impl Offender {
+	fn of(info: &PeerInfo) -> Offender {
+		match info.direction {
+			Direction::Inbound => Offender::Ip(info.addr.ip()),
+			Direction::Outbound => Offender::Addr(info.listen_addr),
+		}
+	}
+
+	fn is_banned(&self, store: &PeerStore) -> bool {
+		match *self {
+			Offender::Addr(addr) => {
+				match store.get_peer(addr) {
+					Ok(Some(peer_data)) => peer_data.is_banned(),
+					_ => false,
+				}
+			}
+			Offender::Ip(ip) => store.is_ip_banned(ip).unwrap_or(false),
+		}
+	}
+}
+
 /// A no-op network adapter used for testing.
 pub struct DummyAdapter {}
 impl NetAdapter for DummyAdapter {
-	fn transaction_received(&self, tx: core::Transaction) {}
-	fn block_received(&self, b: core::Block) {}
+	fn transaction_received(&self, tx: core::Transaction, addr: SocketAddr) {}
+	fn block_received(&self, b: core::Block, addr: SocketAddr) {}
+	fn total_difficulty(&self) -> Difficulty {
+		Difficulty::one()
+	}
+}
+
+// Adapter the peers get, answering their requests for peer addresses from the
+// peer store, keeping score of their misbehavior and forwarding everything
+// else to the adapter of the server
+struct PeersAdapter {
+	adapter: Arc<NetAdapter>,
+	store: Arc<PeerStore>,
+	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+	// misbehavior scores of the peers
+	scores: Mutex<HashMap<Offender, u32>>,
+	ban_secs: i64,
+}
+
+impl PeersAdapter {
+	// what the peer with the provided connection address gets scored and
+	// banned by, an address we connected to if we're not connected to it
+	fn offender(&self, addr: SocketAddr) -> Offender {
+		let peers = self.peers.read().unwrap();
+		match peers.iter().find(|p| p.info().addr == addr) {
+			Some(peer) => Offender::of(peer.info()),
+			None => Offender::Addr(addr),
+		}
+	}
+
+	// bans the peer with the provided connection address for the configured
+	// time, disconnecting it if we're connected to it, along with the other
+	// inbound peers from the same IP when it's inbound
+	fn ban(&self, addr: SocketAddr) {
+		let offender = self.offender(addr);
+		warn!("Banning peer {:?} for {} secs.", offender, self.ban_secs);
+		self.scores.lock().unwrap().remove(&offender);
+		let until = time::now_utc().to_timespec().sec + self.ban_secs;
+		let res = match offender {
+			Offender::Addr(addr) => self.store.ban_peer(addr, until),
+			Offender::Ip(ip) => self.store.ban_ip(ip, until),
+		};
+		if let Err(e) = res {
+			warn!("Could not ban peer {:?}: {:?}", offender, e);
+		}
+		let banned = {
+			let peers = self.peers.read().unwrap();
+			peers.iter()
+				.filter(|p| p.info().addr == addr || Offender::of(p.info()) == offender)
+				.map(|p| p.info().addr)
+				.collect::<Vec<_>>()
+		};
+		for addr in banned {
+			if let Some(peer) = remove_peer(&self.peers, addr) {
+				peer.stop();
+			}
+		}
+	}
+}
+
+impl NetAdapter for PeersAdapter {
+	fn transaction_received(&self, tx: core::Transaction, addr: SocketAddr) {
+		self.adapter.transaction_received(tx, addr);
+	}
+	fn stem_transaction_received(&self, tx: core::Transaction, addr: SocketAddr) {
+		self.adapter.stem_transaction_received(tx, addr);
+	}
+	fn block_received(&self, b: core::Block, addr: SocketAddr) {
+		self.adapter.block_received(b, addr);
+	}
+	fn total_difficulty(&self) -> Difficulty {
+		self.adapter.total_difficulty()
+	}
+	fn get_block(&self, h: Hash) -> Option<core::Block> {
+		self.adapter.get_block(h)
+	}
+	fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+		self.adapter.get_transaction(h)
+	}
+	fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
+		self.adapter.locate_headers(locator)
+	}
+	fn headers_received(&self, headers: Vec<core::BlockHeader>, addr: SocketAddr) {
+		self.adapter.headers_received(headers, addr);
+	}
+	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr> {
+		let peers = self.store.find_peers(capab, MAX_PEER_ADDRS);
+		peers.into_iter().map(|p| p.addr).collect()
+	}
+	fn peer_addrs_received(&self, addrs: Vec<SocketAddr>) {
+		for addr in addrs.into_iter().take(MAX_PEER_ADDRS) {
+			match self.store.exists_peer(addr) {
+				Ok(true) => {}
+				Ok(false) => {
+					debug!("Heard of new peer {}.", addr);
+					let peer_data = PeerData {
+						addr: addr,
+						capabilities: UNKNOWN,
+						user_agent: "".to_string(),
+						last_seen: 0,
+						state: State::Healthy,
+						banned_until: 0,
+					};
+					if let Err(e) = self.store.save_peer(&peer_data) {
+						warn!("Could not save peer {}: {:?}", addr, e);
+					}
+				}
+				Err(e) => warn!("Could not look up peer {}: {:?}", addr, e),
+			}
+		}
+	}
+	fn peer_misbehaved(&self, addr: SocketAddr, misbehavior: Misbehavior) {
+		let offender = self.offender(addr);
+		let score = {
+			let mut scores = self.scores.lock().unwrap();
+			let score = scores.entry(offender).or_insert(0);
+			*score += misbehavior.score();
+			*score
+		};
+		debug!("Peer {:?} misbehaved with {:?}, score now {}.",
+		       offender,
+		       misbehavior,
+		       score);
+		if score >= BAN_SCORE {
+			self.ban(addr);
+		}
+	}
 }
 
 /// P2P server implementation, handling bootstrapping to find and connect to
 /// peers, receiving connections from other peers and keep track of all of them.
+/// Every peer we complete a handshake with gets saved in the peer store, along
+/// with the ones our peers share with us, and we share them in turn. Peers
+/// misbehaving too much get banned for a while, the ones that connected to
+/// us by IP as they pick the port they advertise. The number of peers
+/// connected to us and of peers we connect to are both limited, the latter
+/// being kept in different IP ranges.
 pub struct Server {
 	config: P2PConfig,
 	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
-	adapter: Arc<NetAdapter>,
+	store: Arc<PeerStore>,
+	adapter: Arc<PeersAdapter>,
+	handshake: Arc<Handshake>,
 	stop: RefCell<Option<futures::sync::oneshot::Sender<()>>>,
 }
 
@@ -54,14 +231,28 @@ unsafe impl Send for Server {}
 
 // TODO TLS
 impl Server {
-	/// Creates a new idle p2p server with no peers
-	pub fn new(config: P2PConfig, adapter: Arc<NetAdapter>) -> Server {
-		Server {
+	/// Creates a new idle p2p server with no peers, keeping the peers it
+	/// connects with in a store under the provided directory.
+	pub fn new(db_root: String,
+	           config: P2PConfig,
+	           adapter: Arc<NetAdapter>)
+	           -> Result<Server, grin_store::Error> {
+		let store = Arc::new(try!(PeerStore::new(db_root)));
+		let peers = Arc::new(RwLock::new(Vec::new()));
+		Ok(Server {
 			config: config,
-			peers: Arc::new(RwLock::new(Vec::new())),
-			adapter: adapter,
+			peers: peers.clone(),
+			store: store.clone(),
+			adapter: Arc::new(PeersAdapter {
+				adapter: adapter,
+				store: store,
+				peers: peers,
+				scores: Mutex::new(HashMap::new()),
+				ban_secs: config.ban_secs,
+			}),
+			handshake: Arc::new(Handshake::new(config)),
 			stop: RefCell::new(None),
-		}
+		})
 	}
 
 	/// Starts the p2p server. Opens a TCP port to allow incoming
@@ -71,24 +262,43 @@ impl Server {
 		let socket = TcpListener::bind(&addr, &h.clone()).unwrap();
 		warn!("P2P server started on {}", addr);
 
-		let hs = Arc::new(Handshake::new());
+		let hs = self.handshake.clone();
 		let peers = self.peers.clone();
+		let store = self.store.clone();
 		let adapter = self.adapter.clone();
 
 		// main peer acceptance future handling handshake
 		let hp = h.clone();
-		let peers = socket.incoming().map_err(|e| Error::IOErr(e)).map(move |(conn, addr)| {
+		let config = self.config;
+		let incoming = socket.incoming().map_err(|e| Error::IOErr(e));
+		let peers = incoming.map(move |(conn, addr)| -> Box<Future<Item = (), Error = Error>> {
 			let adapter = adapter.clone();
 			let peers = peers.clone();
 
-			// accept the peer and add it to the server map
-			let peer_accept = add_to_peers(peers, Peer::accept(conn, &hs.clone()));
+			// a banned IP doesn't get anything out of us, not even a handshake
+			if Offender::Ip(addr.ip()).is_banned(&store) {
+				debug!("Refusing peer {} from a banned IP.", addr);
+				return Box::new(futures::failed(refused("banned")));
+			}
+
+			// accept the peer and add it to the server map, if there's room for it
+			let total_difficulty = adapter.total_difficulty();
+			let peer_accept = add_to_peers(peers.clone(),
+			                               store.clone(),
+			                               config,
+			                               Peer::accept(conn, total_difficulty, &hs));
 
 			// wire in a future to timeout the accept after 5 secs
 			let timed_peer = with_timeout(Box::new(peer_accept), &hp);
 
-			// run the main peer protocol
-			timed_peer.and_then(move |(conn, peer)| peer.clone().run(conn, adapter))
+			// run the main peer protocol, until the connection ends
+			Box::new(timed_peer.and_then(move |(conn, peer)| {
+				let addr = peer.info().addr;
+				peer.run(conn, adapter).then(move |res| {
+					remove_peer(&peers, addr);
+					res
+				})
+			}))
 		});
 
 		// spawn each peer future to its own task
@@ -118,43 +328,278 @@ impl Server {
 		}))
 	}
 
-	/// Asks the server to connect to a new peer.
+	/// Asks the server to connect to a new peer, which gets asked for the
+	/// peers it knows of in turn.
 	pub fn connect_peer(&self,
 	                    addr: SocketAddr,
 	                    h: reactor::Handle)
 	                    -> Box<Future<Item = (), Error = Error>> {
+		if let Err(e) = self.check_connect(addr) {
+			return Box::new(futures::failed(e));
+		}
 		let peers = self.peers.clone();
+		let store = self.store.clone();
+		let config = self.config;
 		let adapter = self.adapter.clone();
+		let hs = self.handshake.clone();
+		let total_difficulty = self.adapter.total_difficulty();
+		let timeout_adapter = self.adapter.clone();
+		let run_peers = self.peers.clone();
 
 		let socket = TcpStream::connect(&addr, &h).map_err(|e| Error::IOErr(e));
 		let request = socket.and_then(move |socket| {
 				let peers = peers.clone();
 
-				// connect to the peer and add it to the server map, wiring it a timeout for
-				// the handhake
-				let peer_connect = add_to_peers(peers, Peer::connect(socket, &Handshake::new()));
-				with_timeout(Box::new(peer_connect), &h)
+				// connect to the peer and add it to the server map, if there's still room
+				// for it, wiring it a timeout for the handhake
+				let peer_connect = add_to_peers(peers,
+				                                store,
+				                                config,
+				                                Peer::connect(socket, total_difficulty, &hs));
+				with_timeout(Box::new(peer_connect), &h).map_err(move |e| {
+					if let Error::IOErr(ref ioe) = e {
+						if ioe.kind() == io::ErrorKind::TimedOut {
+							timeout_adapter.peer_misbehaved(addr, Misbehavior::Timeout);
+						}
+					}
+					e
+				})
 			})
-			.and_then(move |(socket, peer)| peer.run(socket, adapter));
+			.and_then(move |(socket, peer)| {
+				let addr = peer.info().addr;
+				let run = peer.run(socket, adapter).then(move |res| {
+					remove_peer(&run_peers, addr);
+					res
+				});
+				if let Err(e) = peer.send_peer_request(FULL_SYNC) {
+					debug!("Error asking peer for its peers: {}", e);
+				}
+				run
+			});
 		Box::new(request)
 	}
 
-	/// Broadcasts the provided block to all our peers. A peer implementation
-	/// may drop the broadcast request if it knows the remote peer already has
-	/// the block.
-	pub fn broadcast_block(&self, b: &core::Block) {
-		let peers = self.peers.write().unwrap();
-		for p in peers.deref() {
+	/// Connects to known peers until we have the configured number of
+	/// outbound peers, each in an IP range none of the others is in.
+	pub fn connect_more_peers(&self, h: reactor::Handle) {
+		let outbound = self.peers_in(Direction::Outbound);
+		let missing = (self.config.max_outbound as usize).saturating_sub(outbound.len());
+		if missing > 0 {
+			for addr in self.candidate_peers(&outbound, missing) {
+				self.spawn_connect(addr, &h);
+			}
+		}
+	}
+
+	/// Replaces the outbound peer that performed the worst among the ones
+	/// connected for at least the provided duration by another known peer,
+	/// when we have as many outbound peers as configured. The worst one has
+	/// the highest misbehavior score, then sent us the least, and gets
+	/// disconnected. Nothing changes when there's no other peer to connect to.
+	pub fn replace_worst_peer(&self, min_age: Duration, h: reactor::Handle) {
+		let outbound = self.peers_in(Direction::Outbound);
+		if outbound.len() < self.config.max_outbound as usize {
+			return;
+		}
+		let worst = {
+			let scores = self.adapter.scores.lock().unwrap();
+			outbound.iter()
+				.filter(|p| p.connected_for() >= min_age)
+				.max_by_key(|p| {
+					let score = scores.get(&Offender::of(p.info())).cloned().unwrap_or(0);
+					(score, u64::MAX - p.transmitted_bytes().1)
+				})
+				.cloned()
+		};
+		let worst = match worst {
+			Some(worst) => worst,
+			None => return,
+		};
+		let keep = outbound.into_iter()
+			.filter(|p| p.info().addr != worst.info().addr)
+			.collect::<Vec<_>>();
+		if let Some(addr) = self.candidate_peers(&keep, 1).pop() {
+			debug!("Replacing worst peer {} by {}.", worst.info().addr, addr);
+			if let Some(peer) = remove_peer(&self.peers, worst.info().addr) {
+				peer.stop();
+			}
+			self.spawn_connect(addr, &h);
+		}
+	}
+
+	/// Broadcasts the provided block to all our peers but the one with the
+	/// provided connection address, which sent it to us. Peers already known
+	/// to have the block, having sent it or got it before, get skipped too.
+	pub fn broadcast_block(&self, b: &core::Block, source: Option<SocketAddr>) {
+		let peers = self.peers.read().unwrap();
+		for p in peers.iter().filter(|p| Some(p.info().addr) != source) {
 			if let Err(e) = p.send_block(b) {
 				debug!("Error sending block to peer: {}", e);
 			}
 		}
 	}
 
+	/// Asks a peer able to serve full blocks for the block with the provided
+	/// hash, unless we're waiting for it from one already. Goes to the peer
+	/// with the fewest requests pending, avoiding the one with the provided
+	/// connection address unless there's no other. Returns whether the block
+	/// got requested.
+	pub fn request_block(&self, h: Hash, avoid: Option<SocketAddr>) -> bool {
+		let mut peers = self.peers_with(FULL_SYNC)
+			.into_iter()
+			.map(|p| (p.pending_block_requests(), p))
+			.collect::<Vec<_>>();
+		if peers.iter().any(|&(ref pending, _)| pending.contains(&h)) {
+			return true;
+		}
+		peers.sort_by_key(|&(ref pending, ref p)| (Some(p.info().addr) == avoid, pending.len()));
+		for (_, p) in peers {
+			match p.send_block_request(h) {
+				Ok(()) => return true,
+				Err(e) => debug!("Error requesting block from peer: {}", e),
+			}
+		}
+		false
+	}
+
+	/// Asks other peers for the blocks requested to a peer that didn't send
+	/// them within the provided duration, see `request_block`.
+	pub fn retry_stale_block_requests(&self, timeout: Duration) {
+		let stale = {
+			let peers = self.peers.read().unwrap();
+			peers.iter()
+				.map(|p| (p.info().addr, p.stale_block_requests(timeout)))
+				.filter(|&(_, ref hashes)| !hashes.is_empty())
+				.collect::<Vec<_>>()
+		};
+		for (addr, hashes) in stale {
+			debug!("Peer {} stalled on {} block requests, asking again.", addr, hashes.len());
+			for h in hashes {
+				if !self.request_block(h, Some(addr)) {
+					debug!("No peer to request block {} from.", h);
+				}
+			}
+		}
+	}
+
+	/// Broadcasts the provided transaction to all our peers but the one with
+	/// the provided connection address, which sent it to us. Peers already
+	/// known to have the transaction get skipped too, as with blocks.
+	pub fn broadcast_transaction(&self, tx: &core::Transaction, source: Option<SocketAddr>) {
+		let peers = self.peers.read().unwrap();
+		for p in peers.iter().filter(|p| Some(p.info().addr) != source) {
+			if let Err(e) = p.send_transaction(tx) {
+				debug!("Error sending transaction to peer: {}", e);
+			}
+		}
+	}
+
+	/// All the peers we completed a handshake with at some point, including
+	/// in earlier runs, or heard of from other peers, the most recently seen
+	/// first.
+	pub fn known_peers(&self) -> Vec<PeerData> {
+		self.store.all_peers()
+	}
+
+	/// The peer with the provided connection address, if we're connected to
+	/// it.
+	pub fn get_peer(&self, addr: SocketAddr) -> Option<Arc<Peer>> {
+		let peers = self.peers.read().unwrap();
+		peers.iter().find(|p| p.info().addr == addr).cloned()
+	}
+
+	/// The peers connected in the provided direction.
+	pub fn peers_in(&self, direction: Direction) -> Vec<Arc<Peer>> {
+		let peers = self.peers.read().unwrap();
+		peers.iter().filter(|p| p.info().direction == direction).cloned().collect()
+	}
+
 	pub fn peers_count(&self) -> u32 {
 		self.peers.read().unwrap().len() as u32
 	}
 
+	/// Peers that advertised all the provided capabilities during their
+	/// handshake, the ones requests needing them can go to.
+	pub fn peers_with(&self, capabilities: Capabilities) -> Vec<Arc<Peer>> {
+		let peers = self.peers.read().unwrap();
+		peers.iter().filter(|p| p.info().capabilities.contains(capabilities)).cloned().collect()
+	}
+
+	/// The peer with the most total difficulty among the ones with all the
+	/// provided capabilities, as advertised during their handshake.
+	pub fn most_work_peer(&self, capabilities: Capabilities) -> Option<Arc<Peer>> {
+		self.peers_with(capabilities)
+			.into_iter()
+			.max_by_key(|p| p.info().total_difficulty.clone())
+	}
+
+	/// Bans the peer with the provided connection address, which gets
+	/// disconnected. We won't keep a connection with it again, nor share its
+	/// address, until the ban expires after the configured time. A peer that
+	/// connected to us gets its whole IP banned, other peers from it included.
+	pub fn ban_peer(&self, addr: SocketAddr) {
+		self.adapter.ban(addr);
+	}
+
+	/// Adds the provided misbehavior to the score of the peer with the
+	/// provided connection address, banning it once the score reaches
+	/// BAN_SCORE.
+	pub fn peer_misbehaved(&self, addr: SocketAddr, misbehavior: Misbehavior) {
+		self.adapter.peer_misbehaved(addr, misbehavior);
+	}
+
+	// whether we can connect to the peer with the provided address, not being
+	// connected to it already and having room for it among our outbound peers
+	fn check_connect(&self, addr: SocketAddr) -> Result<(), Error> {
+		let peers = self.peers.read().unwrap();
+		if addr == SocketAddr::new(self.config.host, self.config.port) ||
+		   peers.iter().any(|p| p.info().listen_addr == addr) {
+			return Err(refused("already connected"));
+		}
+		check_room(peers.iter().map(|p| p.info()),
+		           Direction::Outbound,
+		           addr,
+		           self.config.max_outbound)
+	}
+
+	// up to the provided number of known peers we aren't connected to, each
+	// in a different IP range, none of the provided outbound peers being in
+	// the same one
+	fn candidate_peers(&self, outbound: &[Arc<Peer>], count: usize) -> Vec<SocketAddr> {
+		let own_addr = SocketAddr::new(self.config.host, self.config.port);
+		let connected = {
+			let peers = self.peers.read().unwrap();
+			peers.iter().map(|p| p.info().listen_addr).collect::<Vec<_>>()
+		};
+		let mut ranges = outbound.iter()
+			.filter_map(|p| ip_range(p.info().addr.ip()))
+			.collect::<Vec<_>>();
+		let mut addrs = vec![];
+		for p in self.store.find_peers(UNKNOWN, MAX_CANDIDATE_PEERS) {
+			if addrs.len() >= count {
+				break;
+			}
+			if p.addr == own_addr || connected.contains(&p.addr) {
+				continue;
+			}
+			if let Some(range) = ip_range(p.addr.ip()) {
+				if ranges.contains(&range) {
+					continue;
+				}
+				ranges.push(range);
+			}
+			addrs.push(p.addr);
+		}
+		addrs
+	}
+
+	fn spawn_connect(&self, addr: SocketAddr, h: &reactor::Handle) {
+		debug!("Connecting to peer {}.", addr);
+		h.spawn(self.connect_peer(addr, h.clone()).map_err(move |e| {
+			debug!("Could not connect to peer {}: {:?}", addr, e);
+		}));
+	}
+
 	/// Stops the server. Disconnect from all peers at the same time.
 	pub fn stop(self) {
 		let peers = self.peers.write().unwrap();
@@ -165,21 +610,109 @@ impl Server {
 	}
 }
 
-// Adds the peer built by the provided future in the peers map
+// Adds the peer built by the provided future in the peers map, saving it in
+// the peer store, unless it got banned or there's no room for it. A peer that
+// connected to us is refused as well when the address it advertises is banned.
 fn add_to_peers<A>(peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+                   store: Arc<PeerStore>,
+                   config: P2PConfig,
                    peer_fut: A)
                    -> Box<Future<Item = Result<(TcpStream, Arc<Peer>), ()>, Error = Error>>
 	where A: IntoFuture<Item = (TcpStream, Peer), Error = Error> + 'static
 {
-	let peer_add = peer_fut.into_future().map(move |(conn, peer)| {
+	let peer_add = peer_fut.into_future().and_then(move |(conn, peer)| {
+		let listen_addr = peer.info().listen_addr;
+		for offender in &[Offender::of(peer.info()), Offender::Addr(listen_addr)] {
+			if offender.is_banned(&store) {
+				debug!("Refusing banned peer {:?}.", offender);
+				return Err(Error::UnexpectedData {
+					expected: vec![],
+					received: vec![],
+				});
+			}
+		}
+		// a ban we still know of stays, even once expired
+		let (state, banned_until) = match store.get_peer(listen_addr) {
+			Ok(Some(known)) => (known.state, known.banned_until),
+			_ => (State::Healthy, 0),
+		};
+		let peer_data = PeerData {
+			addr: listen_addr,
+			capabilities: peer.info().capabilities,
+			user_agent: peer.info().user_agent.clone(),
+			last_seen: time::now_utc().to_timespec().sec,
+			state: state,
+			banned_until: banned_until,
+		};
+		if let Err(e) = store.save_peer(&peer_data) {
+			warn!("Could not save peer {}: {:?}", peer_data.addr, e);
+		}
 		let apeer = Arc::new(peer);
 		let mut peers = peers.write().unwrap();
+		let (direction, addr) = (apeer.info().direction, apeer.info().addr);
+		let max = match direction {
+			Direction::Inbound => config.max_inbound,
+			Direction::Outbound => config.max_outbound,
+		};
+		if let Err(e) = check_room(peers.iter().map(|p| p.info()), direction, addr, max) {
+			debug!("Refusing peer {}: {:?}", addr, e);
+			return Err(e);
+		}
 		peers.push(apeer.clone());
-		Ok((conn, apeer))
+		Ok(Ok((conn, apeer)))
 	});
 	Box::new(peer_add)
 }
 
+// Removes the peer with the provided connection address from the peers map,
+// giving it back if it was there
+fn remove_peer(peers: &RwLock<Vec<Arc<Peer>>>, addr: SocketAddr) -> Option<Arc<Peer>> {
+	let mut peers = peers.write().unwrap();
+	peers.iter().position(|p| p.info().addr == addr).map(|pos| peers.remove(pos))
+}
+
+// Whether there's room among the provided peers for one more in the provided
+// direction, up to the provided maximum, and with the provided address. An
+// outbound peer can't be in the IP range of another.
+fn check_room<'a, I>(peers: I,
+                     direction: Direction,
+                     addr: SocketAddr,
+                     max: u32)
+                     -> Result<(), Error>
+	where I: Iterator<Item = &'a PeerInfo>
+{
+	let same_dir = peers.filter(|p| p.direction == direction).collect::<Vec<_>>();
+	if same_dir.len() >= max as usize {
+		return Err(refused("too many peers"));
+	}
+	let range = ip_range(addr.ip());
+	if direction == Direction::Outbound && range.is_some() &&
+	   same_dir.iter().any(|p| ip_range(p.addr.ip()) == range) {
+		return Err(refused("already connected to a peer in the same IP range"));
+	}
+	Ok(())
+}
+
+// The IP range of the provided address, its /16 for IPv4 and its /32 for IPv6.
+// None for loopback addresses, so a few local nodes can connect to each other.
+fn ip_range(ip: IpAddr) -> Option<IpAddr> {
+	match ip {
+		IpAddr::V4(ip) if !ip.is_loopback() => {
+			let o = ip.octets();
+			Some(IpAddr::V4(Ipv4Addr::new(o[0], o[1], 0, 0)))
+		}
+		IpAddr::V6(ip) if !ip.is_loopback() => {
+			let s = ip.segments();
+			Some(IpAddr::V6(Ipv6Addr::new(s[0], s[1], 0, 0, 0, 0, 0, 0)))
+		}
+		_ => None,
+	}
+}
+
+fn refused(msg: &str) -> Error {
+	Error::IOErr(io::Error::new(io::ErrorKind::ConnectionRefused, msg))
+}
+
 // Adds a timeout to a future
 fn with_timeout<T: 'static>(fut: Box<Future<Item = Result<T, ()>, Error = Error>>,
                             h: &reactor::Handle)
@@ -189,9 +722,67 @@ fn with_timeout<T: 'static>(fut: Box<Future<Item = Result<T, ()>, Error = Error>
 		.then(|res| {
 			match res {
 				Ok((Ok(inner), _timeout)) => Ok(inner),
-				Ok((_, _accept)) => Err(Error::TooLargeReadErr),
+				Ok((_, _accept)) => {
+					Err(Error::IOErr(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")))
+				}
 				Err((e, _other)) => Err(e),
 			}
 		});
 	Box::new(timed)
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn peer_info(direction: Direction, addr: &str) -> PeerInfo {
+		let addr = addr.parse().unwrap();
+		PeerInfo {
+			capabilities: FULL_SYNC,
+			user_agent: "test".to_string(),
+			version: 1,
+			addr: addr,
+			listen_addr: addr,
+			total_difficulty: Difficulty::one(),
+			direction: direction,
+		}
+	}
+
+	#[test]
+	fn room_by_direction() {
+		let peers = vec![peer_info(Direction::Inbound, "10.0.0.1:13414"),
+		                 peer_info(Direction::Outbound, "10.1.0.1:13414")];
+		let addr = "10.2.0.1:13414".parse().unwrap();
+		assert!(check_room(peers.iter(), Direction::Inbound, addr, 2).is_ok());
+		assert!(check_room(peers.iter(), Direction::Inbound, addr, 1).is_err());
+		assert!(check_room(peers.iter(), Direction::Outbound, addr, 2).is_ok());
+		assert!(check_room(peers.iter(), Direction::Outbound, addr, 1).is_err());
+	}
+
+	#[test]
+	fn outbound_in_distinct_ranges() {
+		let peers = vec![peer_info(Direction::Outbound, "10.1.2.3:13414"),
+		                 peer_info(Direction::Inbound, "10.2.2.3:13414"),
+		                 peer_info(Direction::Outbound, "127.0.0.1:13415")];
+		let room = |direction, addr: &str| {
+			check_room(peers.iter(), direction, addr.parse().unwrap(), 8).is_ok()
+		};
+		assert!(!room(Direction::Outbound, "10.1.200.1:13414"));
+		assert!(room(Direction::Outbound, "10.2.2.4:13414"));
+		assert!(room(Direction::Outbound, "10.3.2.3:13414"));
+		// local nodes can all connect to each other
+		assert!(room(Direction::Outbound, "127.0.0.1:13416"));
+		// peers connecting to us pick their IP, it doesn't matter
+		assert!(room(Direction::Inbound, "10.1.2.4:13414"));
+	}
+
+	#[test]
+	fn ip_ranges() {
+		let range = |ip: &str| ip_range(ip.parse().unwrap());
+		assert_eq!(range("10.1.2.3"), Some("10.1.0.0".parse().unwrap()));
+		assert_eq!(range("10.1.200.1"), range("10.1.2.3"));
+		assert_eq!(range("2001:db8:1:2::1"), Some("2001:db8::".parse().unwrap()));
+		assert_eq!(range("127.0.0.1"), None);
+		assert_eq!(range("::1"), None);
+	}
+}
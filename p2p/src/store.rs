@@ -0,0 +1,218 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage of the peers we completed a handshake with or heard of from other
+//! peers, so we can get back to them when starting again, without going
+//! through the seeds, and share them with the peers asking for addresses.
+
+use std::net::{IpAddr, SocketAddr};
+
+use num::FromPrimitive;
+use rand::{thread_rng, Rng};
+use time;
+
+use core::ser::{self, Readable, Reader, Writeable, Writer};
+use grin_store::{self, Error};
+use msg::SockAddr;
+use types::*;
+
+const STORE_SUBPATH: &'static str = "peers";
+
+const SEP: u8 = ':' as u8;
+
+const PEER_PREFIX: u8 = 'p' as u8;
+const BAN_PREFIX: u8 = 'b' as u8;
+
+enum_from_primitive! {
+  /// Whether we're willing to connect with a peer, a ban only lasting until
+  /// the time it expires.
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum State {
+    Healthy,
+    Banned,
+  }
+}
+
+/// What we know of a peer we've been connected to, or only heard of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerData {
+	/// Address the peer accepts connections on
+	pub addr: SocketAddr,
+	/// Capabilities the peer advertised, unknown until we connect to it
+	pub capabilities: Capabilities,
+	/// User agent the peer advertised, empty until we connect to it
+	pub user_agent: String,
+	/// Timestamp in seconds of the last handshake with the peer, 0 if we
+	/// never had one
+	pub last_seen: i64,
+	/// Whether the peer got banned
+	pub state: State,
+	/// Timestamp in seconds a ban of the peer expires at
+	pub banned_until: i64,
+}
+
+impl PeerData {
+	/// Whether the peer is banned, with a ban that didn't expire yet.
+	pub fn is_banned(&self) -> bool {
+		self.state == State::Banned && time::now_utc().to_timespec().sec < self.banned_until
+	}
+}
+
+impl Writeable for PeerData {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(SockAddr(self.addr).write(writer));
+		ser_multiwrite!(writer,
+		                [write_u32, self.capabilities.bits()],
+		                [write_bytes, &self.user_agent],
+		                [write_i64, self.last_seen],
+		                [write_u8, self.state as u8],
+		                [write_i64, self.banned_until]);
+		Ok(())
+	}
+}
+
+impl Readable<PeerData> for PeerData {
+	fn read(reader: &mut Reader) -> Result<PeerData, ser::Error> {
+		let addr = try!(SockAddr::read(reader));
+		let (capab, ua, last_seen, st, banned_until) =
+			ser_multiread!(reader, read_u32, read_vec, read_i64, read_u8, read_i64);
+		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
+		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
+		let state = try!(State::from_u8(st).ok_or(ser::Error::CorruptedData));
+		Ok(PeerData {
+			addr: addr.0,
+			capabilities: capabilities,
+			user_agent: user_agent,
+			last_seen: last_seen,
+			state: state,
+			banned_until: banned_until,
+		})
+	}
+}
+
+// Ban of all the peers connecting from an IP, until a timestamp in seconds
+struct IpBan(i64);
+
+impl Writeable for IpBan {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		writer.write_i64(self.0)
+	}
+}
+
+impl Readable<IpBan> for IpBan {
+	fn read(reader: &mut Reader) -> Result<IpBan, ser::Error> {
+		Ok(IpBan(try!(reader.read_i64())))
+	}
+}
+
+/// Store of the peers we know of, keyed by their address, and of the IPs
+/// banned whatever the port.
+pub struct PeerStore {
+	db: grin_store::Store,
+}
+
+impl PeerStore {
+	/// Opens the store of peers under the provided directory.
+	pub fn new(root_path: String) -> Result<PeerStore, Error> {
+		let db = try!(grin_store::Store::open(&format!("{}/{}", root_path, STORE_SUBPATH)));
+		Ok(PeerStore { db: db })
+	}
+
+	/// Saves the provided peer, replacing what we knew of it.
+	pub fn save_peer(&self, p: &PeerData) -> Result<(), Error> {
+		self.db.put_ser(&peer_key(p.addr), p)
+	}
+
+	/// What we know of the peer with the provided address, if anything.
+	pub fn get_peer(&self, addr: SocketAddr) -> Result<Option<PeerData>, Error> {
+		self.db.get_ser(&peer_key(addr))
+	}
+
+	/// Whether we know of a peer with the provided address.
+	pub fn exists_peer(&self, addr: SocketAddr) -> Result<bool, Error> {
+		self.db.get(&peer_key(addr)).map(|p| p.is_some())
+	}
+
+	/// Bans the peer with the provided address until the provided timestamp
+	/// in seconds, if we know of it.
+	pub fn ban_peer(&self, addr: SocketAddr, until: i64) -> Result<(), Error> {
+		match try!(self.get_peer(addr)) {
+			Some(mut peer) => {
+				peer.state = State::Banned;
+				peer.banned_until = until;
+				self.save_peer(&peer)
+			}
+			None => Ok(()),
+		}
+	}
+
+	/// Bans all the peers connecting from the provided IP, whatever port they
+	/// advertise, until the provided timestamp in seconds.
+	pub fn ban_ip(&self, ip: IpAddr, until: i64) -> Result<(), Error> {
+		self.db.put_ser(&ban_key(ip), &IpBan(until))
+	}
+
+	/// Whether the peers connecting from the provided IP are banned, with a
+	/// ban that didn't expire yet.
+	pub fn is_ip_banned(&self, ip: IpAddr) -> Result<bool, Error> {
+		let ban = try!(self.db.get_ser::<IpBan>(&ban_key(ip)));
+		Ok(ban.map(|b| time::now_utc().to_timespec().sec < b.0).unwrap_or(false))
+	}
+
+	/// Forgets the peer with the provided address.
+	pub fn delete_peer(&self, addr: SocketAddr) -> Result<(), Error> {
+		self.db.delete(&peer_key(addr))
+	}
+
+	/// All the peers we know of, the most recently seen first.
+	pub fn all_peers(&self) -> Vec<PeerData> {
+		let mut peers = self.peers().collect::<Vec<_>>();
+		peers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+		peers
+	}
+
+	/// Up to the provided count of peers that aren't banned and have all the
+	/// provided capabilities, picked at random.
+	pub fn find_peers(&self, capab: Capabilities, count: usize) -> Vec<PeerData> {
+		let mut peers = self.peers()
+			.filter(|p| !p.is_banned() && p.capabilities.contains(capab))
+			.collect::<Vec<_>>();
+		thread_rng().shuffle(&mut peers);
+		peers.truncate(count);
+		peers
+	}
+
+	// the peers that can be read, a corrupt one only getting logged
+	fn peers<'a>(&'a self) -> Box<Iterator<Item = PeerData> + 'a> {
+		Box::new(self.db.iter::<PeerData>(&[PEER_PREFIX, SEP]).filter_map(|res| match res {
+			Ok(p) => Some(p),
+			Err(e) => {
+				warn!("Skipping unreadable peer: {:?}", e);
+				None
+			}
+		}))
+	}
+}
+
+fn peer_key(addr: SocketAddr) -> Vec<u8> {
+	let mut k = vec![PEER_PREFIX, SEP];
+	k.extend_from_slice(addr.to_string().as_bytes());
+	k
+}
+
+fn ban_key(ip: IpAddr) -> Vec<u8> {
+	let mut k = vec![BAN_PREFIX, SEP];
+	k.extend_from_slice(ip.to_string().as_bytes());
+	k
+}
@@ -14,11 +14,14 @@
 
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::Future;
 use tokio_core::net::TcpStream;
 
 use core::core;
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
 use core::ser::Error;
 
 /// Configuration for the peer-to-peer server.
@@ -26,6 +29,14 @@ use core::ser::Error;
 pub struct P2PConfig {
 	pub host: IpAddr,
 	pub port: u16,
+	/// What we advertise being able to do to the peers we connect with
+	pub capabilities: Capabilities,
+	/// Seconds a peer stays banned for once its misbehavior got too far
+	pub ban_secs: i64,
+	/// Most peers connected to us we keep connections with
+	pub max_inbound: u32,
+	/// Most peers we connect to, no two in the same IP range
+	pub max_outbound: u32,
 }
 
 /// Default address for peer-to-peer connections.
@@ -35,27 +46,80 @@ impl Default for P2PConfig {
 		P2PConfig {
 			host: ipaddr,
 			port: 13414,
+			capabilities: FULL_SYNC,
+			ban_secs: 3 * 3600,
+			max_inbound: 32,
+			max_outbound: 8,
 		}
 	}
 }
 
 bitflags! {
-  /// Options for block validation
+  /// What a peer can do for us, advertised during the handshake.
   pub flags Capabilities: u32 {
     /// We don't know (yet) what the peer can do.
     const UNKNOWN = 0b00000000,
-    /// Runs with the easier version of the Proof of Work, mostly to make testing easier.
+    /// Has the full history of blocks and can serve it to peers syncing.
     const FULL_SYNC = 0b00000001,
   }
 }
 
-/// General information about a connected peer that's useful to other modules.
-#[derive(Debug)]
+/// Score a peer gets banned at, its misbehaviors adding up
+pub const BAN_SCORE: u32 = 100;
+
+/// Most block headers sent in response to a single request for headers
+pub const MAX_BLOCK_HEADERS: u64 = 512;
+
+/// Ways a peer can misbehave, each adding to its score until it gets banned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+	/// Sent a block, or a header, our chain refused as invalid
+	InvalidBlock,
+	/// Sent a transaction with invalid signatures or range proofs
+	InvalidTransaction,
+	/// Sent a message we couldn't read
+	MalformedMessage,
+	/// Didn't complete the handshake in time
+	Timeout,
+}
+
+impl Misbehavior {
+	/// How much the misbehavior adds to the score of the peer.
+	pub fn score(&self) -> u32 {
+		match *self {
+			Misbehavior::InvalidBlock => 50,
+			Misbehavior::InvalidTransaction => 50,
+			Misbehavior::MalformedMessage => 20,
+			Misbehavior::Timeout => 10,
+		}
+	}
+}
+
+/// Which side initiated the connection with a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+	/// The peer connected to us
+	Inbound,
+	/// We connected to the peer
+	Outbound,
+}
+
+/// General information about a connected peer that's useful to other modules,
+/// as the peer advertised it during the handshake.
+#[derive(Debug, Clone)]
 pub struct PeerInfo {
 	pub capabilities: Capabilities,
 	pub user_agent: String,
 	pub version: u32,
+	/// Address of the connection with the peer
 	pub addr: SocketAddr,
+	/// Address the peer accepts connections on, the same as addr when we
+	/// initiated the connection
+	pub listen_addr: SocketAddr,
+	/// Total difficulty of the chain of the peer at the time of the handshake
+	pub total_difficulty: Difficulty,
+	/// Whether the peer connected to us or we connected to it
+	pub direction: Direction,
 }
 
 /// A given communication protocol agreed upon between 2 peers (usually
@@ -74,12 +138,41 @@ pub trait Protocol {
 	/// Sends a ping message to the remote peer.
 	fn send_ping(&self) -> Result<(), Error>;
 
-	/// Relays a block to the remote peer.
+	/// Relays a block to the remote peer, unless it already has it, having
+	/// sent it to us or got it from us before.
 	fn send_block(&self, b: &core::Block) -> Result<(), Error>;
 
-	/// Relays a transaction to the remote peer.
+	/// Asks the remote peer for the block with the provided hash, keeping
+	/// track of the request until the block comes.
+	fn send_block_request(&self, h: Hash) -> Result<(), Error>;
+
+	/// Hashes of the blocks we asked the remote peer for that didn't come
+	/// yet.
+	fn pending_block_requests(&self) -> Vec<Hash>;
+
+	/// Hashes of the blocks we asked the remote peer for over the provided
+	/// duration ago that didn't come yet, no longer tracked from then on.
+	fn stale_block_requests(&self, timeout: Duration) -> Vec<Hash>;
+
+	/// Relays a transaction to the remote peer, unless it already has it,
+	/// having sent it to us or got it from us before.
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error>;
 
+	/// Relays a transaction along the Dandelion stem to the remote peer, which
+	/// either keeps it along the stem or broadcasts it.
+	fn send_stem_transaction(&self, tx: &core::Transaction) -> Result<(), Error>;
+
+	/// Asks the remote peer for the transaction with the provided hash.
+	fn send_transaction_request(&self, h: Hash) -> Result<(), Error>;
+
+	/// Asks the remote peer for the addresses of the peers it knows of with
+	/// the provided capabilities.
+	fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error>;
+
+	/// Asks the remote peer for the headers of its main chain following the
+	/// most recent block of the provided locator it has.
+	fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error>;
+
 	/// How many bytes have been sent/received to/from the remote peer.
 	fn transmitted_bytes(&self) -> (u64, u64);
 
@@ -91,9 +184,60 @@ pub trait Protocol {
 /// forwarding or querying of blocks and transactions from the network among
 /// other things.
 pub trait NetAdapter {
-	/// A valid transaction has been received from one of our peers
-	fn transaction_received(&self, tx: core::Transaction);
+	/// A transaction has been received from one of our peers, the one with
+	/// the provided address
+	fn transaction_received(&self, tx: core::Transaction, addr: SocketAddr);
+
+	/// A block has been received from one of our peers, the one with the
+	/// provided address
+	fn block_received(&self, b: core::Block, addr: SocketAddr);
+
+	/// A transaction has been received from one of our peers along the
+	/// Dandelion stem, not to be broadcast right away. Handled as any other
+	/// transaction by default, broadcasting it.
+	fn stem_transaction_received(&self, tx: core::Transaction, addr: SocketAddr) {
+		self.transaction_received(tx, addr);
+	}
+
+	/// Total difficulty of our chain, advertised to the peers we connect with
+	fn total_difficulty(&self) -> Difficulty;
+
+	/// The block with the provided hash, for a peer asking for it. None by
+	/// default, as if we didn't have it.
+	fn get_block(&self, h: Hash) -> Option<core::Block> {
+		None
+	}
+
+	/// The transaction with the provided hash, for a peer asking for it. None
+	/// by default, as if we didn't have it.
+	fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+		None
+	}
+
+	/// Headers of our main chain following the most recent block of the
+	/// provided locator we have, up to MAX_BLOCK_HEADERS, for a peer asking
+	/// for them. None by default.
+	fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
+		vec![]
+	}
+
+	/// Headers sent by one of our peers, the one with the provided address,
+	/// in response to our request. Ignored by default.
+	fn headers_received(&self, headers: Vec<core::BlockHeader>, addr: SocketAddr) {}
+
+	/// Addresses of peers with the provided capabilities to share with a peer
+	/// asking for them. None by default, the p2p server answering with the
+	/// ones of its peer store.
+	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr> {
+		vec![]
+	}
+
+	/// Peer addresses shared by one of our peers. Ignored by default, the p2p
+	/// server saving the ones it didn't know of in its peer store.
+	fn peer_addrs_received(&self, addrs: Vec<SocketAddr>) {}
 
-	/// A block has been received from one of our peers
-	fn block_received(&self, b: core::Block);
+	/// The peer with the provided connection address misbehaved. Ignored by
+	/// default, the p2p server scoring the peer and banning it past
+	/// BAN_SCORE.
+	fn peer_misbehaved(&self, addr: SocketAddr, misbehavior: Misbehavior) {}
 }
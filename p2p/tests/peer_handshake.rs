@@ -16,8 +16,10 @@ extern crate grin_core as core;
 extern crate grin_p2p as p2p;
 extern crate env_logger;
 extern crate futures;
+extern crate rand;
 extern crate tokio_core;
 
+use std::env;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time;
@@ -26,10 +28,12 @@ use futures::future::Future;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::{self, Core};
 
+use core::core::target::Difficulty;
 use core::ser;
 use p2p::Peer;
 
 // Starts a server and connects a client peer to it to check handshake, followed by a ping/pong exchange to make sure the connection is live.
+// Both sides should also know what the other advertised during the handshake, the server saving it.
 #[test]
 fn peer_handshake() {
   env_logger::init().unwrap();
@@ -38,11 +42,14 @@ fn peer_handshake() {
   let handle = evtlp.handle();
   let p2p_conf = p2p::P2PConfig::default();
   let net_adapter = Arc::new(p2p::DummyAdapter{});
-  let server = p2p::Server::new(p2p_conf, net_adapter.clone());
+  // a new directory, without the peers of earlier runs such as the banned client
+  let dir = env::temp_dir().join(format!("grin-p2p-handshake-{}", rand::random::<u32>()));
+  let server = p2p::Server::new(dir.to_str().unwrap().to_string(), p2p_conf, net_adapter.clone()).unwrap();
   let run_server = server.start(handle.clone());
 
   let phandle = handle.clone();
   let rhandle = handle.clone();
+  let bhandle = handle.clone();
   let timeout = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
   let timeout_send = reactor::Timeout::new(time::Duration::new(2, 0), &handle).unwrap();
   handle.spawn(timeout.map_err(|e| ser::Error::IOErr(e)).and_then(move |_| {
@@ -50,8 +57,15 @@ fn peer_handshake() {
     let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
     let socket = TcpStream::connect(&addr, &phandle).map_err(|e| ser::Error::IOErr(e));
     socket.and_then(move |socket| {
-      Peer::connect(socket, &p2p::handshake::Handshake::new())
+      let mut client_conf = p2p::P2PConfig::default();
+      client_conf.port = 13415;
+      Peer::connect(socket, Difficulty::from_num(10), &p2p::handshake::Handshake::new(client_conf))
 		}).and_then(move |(socket, peer)| {
+      // the server advertises its dummy adapter difficulty
+      assert_eq!(peer.info().total_difficulty, Difficulty::one());
+      assert!(peer.info().capabilities.contains(p2p::FULL_SYNC));
+      assert_eq!(peer.info().listen_addr, addr);
+      assert_eq!(peer.info().direction, p2p::Direction::Outbound);
       rhandle.spawn(peer.run(socket, net_adapter.clone()).map_err(|e| {
         panic!("Client run failed: {}", e);
       }));
@@ -62,10 +76,34 @@ fn peer_handshake() {
       assert!(sent > 0);
       assert!(recv > 0);
       Ok(())
-    }).and_then(|_| {
+    }).and_then(move |_| {
       assert!(server.peers_count() > 0);
-      server.stop();
-      Ok(())
+      let client = server.most_work_peer(p2p::FULL_SYNC).unwrap();
+      assert_eq!(client.info().total_difficulty, Difficulty::from_num(10));
+      assert_eq!(client.info().listen_addr.port(), 13415);
+      assert_eq!(client.info().direction, p2p::Direction::Inbound);
+      // and should be remembered for next time
+      assert!(server.known_peers().iter().any(|p| p.addr == client.info().listen_addr));
+
+      // misbehaving enough bans, disconnecting
+      server.peer_misbehaved(client.info().addr, p2p::Misbehavior::InvalidBlock);
+      assert_eq!(server.peers_count(), 1);
+      server.peer_misbehaved(client.info().addr, p2p::Misbehavior::InvalidBlock);
+      assert_eq!(server.peers_count(), 0);
+
+      // the ban going by the IP of a peer that connected to us, another port
+      // advertised doesn't get it a handshake
+      let socket = TcpStream::connect(&addr, &bhandle).map_err(|e| ser::Error::IOErr(e));
+      socket.and_then(|socket| {
+        let mut client_conf = p2p::P2PConfig::default();
+        client_conf.port = 13416;
+        Peer::connect(socket, Difficulty::from_num(10), &p2p::handshake::Handshake::new(client_conf))
+      }).then(move |res| {
+        assert!(res.is_err());
+        assert_eq!(server.peers_count(), 0);
+        server.stop();
+        Ok(())
+      })
     })
   }).map_err(|e| {
     panic!("Client connection failed: {}", e);
@@ -0,0 +1,266 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate rand;
+extern crate tokio_core;
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::Future;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::{Hash, Hashed};
+use core::core::target::Difficulty;
+use p2p::{Direction, NetAdapter};
+
+// Peers connected to a server limited to one inbound peer, the second one
+// gets dropped right after its handshake.
+#[test]
+fn inbound_limit() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let conf = p2p::P2PConfig { max_inbound: 1, ..p2p::P2PConfig::default() };
+  let server = start_server("inbound", 14414, conf, dummy(), &handle);
+  let client1 = start_server("inbound-c1", 14415, p2p::P2PConfig::default(), dummy(), &handle);
+  let client2 = start_server("inbound-c2", 14416, p2p::P2PConfig::default(), dummy(), &handle);
+  wait(&mut evtlp, 100);
+
+  handle.spawn(client1.connect_peer(local(14414), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+  handle.spawn(client2.connect_peer(local(14414), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+
+  assert_eq!(server.peers_in(Direction::Inbound).len(), 1);
+  assert_eq!(server.peers_in(Direction::Outbound).len(), 0);
+  assert_eq!(client1.peers_count(), 1);
+  assert_eq!(client2.peers_count(), 0);
+}
+
+// A server limited to one outbound peer doesn't connect to a second one.
+#[test]
+fn outbound_limit() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let conf = p2p::P2PConfig { max_outbound: 1, ..p2p::P2PConfig::default() };
+  let server = start_server("outbound", 14420, conf, dummy(), &handle);
+  let _peer1 = start_server("outbound-p1", 14421, p2p::P2PConfig::default(), dummy(), &handle);
+  let _peer2 = start_server("outbound-p2", 14422, p2p::P2PConfig::default(), dummy(), &handle);
+  wait(&mut evtlp, 100);
+
+  handle.spawn(server.connect_peer(local(14421), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+  assert!(evtlp.run(server.connect_peer(local(14422), handle.clone())).is_err());
+  // nor again to the one it's connected to
+  assert!(evtlp.run(server.connect_peer(local(14421), handle.clone())).is_err());
+  assert_eq!(server.peers_in(Direction::Outbound).len(), 1);
+}
+
+// With all its outbound peers, a server replaces the worst one by a peer it
+// heard of from it, disconnecting from the worst one.
+#[test]
+fn replace_worst_peer() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let conf = p2p::P2PConfig { max_outbound: 1, ..p2p::P2PConfig::default() };
+  let server = start_server("replace", 14430, conf, dummy(), &handle);
+  let worst = start_server("replace-worst", 14431, p2p::P2PConfig::default(), dummy(), &handle);
+  let _other = start_server("replace-other", 14432, p2p::P2PConfig::default(), dummy(), &handle);
+  wait(&mut evtlp, 100);
+
+  // the other peer gets known to the worst one, which shares it
+  handle.spawn(worst.connect_peer(local(14432), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+  handle.spawn(server.connect_peer(local(14431), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+  assert!(server.known_peers().iter().any(|p| p.addr == local(14432)));
+
+  server.replace_worst_peer(Duration::from_secs(0), handle.clone());
+  wait(&mut evtlp, 500);
+  let outbound = server.peers_in(Direction::Outbound);
+  assert_eq!(outbound.len(), 1);
+  assert_eq!(outbound[0].info().listen_addr, local(14432));
+  assert_eq!(worst.peers_in(Direction::Inbound).len(), 0);
+  assert_eq!(worst.peers_in(Direction::Outbound).len(), 1);
+}
+
+// A block gets requested to a single peer, then to another one when the
+// first one doesn't send it in time.
+#[test]
+fn block_request_retry() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let server = start_server("retry", 14440, p2p::P2PConfig::default(), dummy(), &handle);
+  let _peer1 = start_server("retry-p1", 14441, p2p::P2PConfig::default(), dummy(), &handle);
+  let _peer2 = start_server("retry-p2", 14442, p2p::P2PConfig::default(), dummy(), &handle);
+  wait(&mut evtlp, 100);
+  handle.spawn(server.connect_peer(local(14441), handle.clone()).map_err(|_| ()));
+  handle.spawn(server.connect_peer(local(14442), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+
+  let h = Hash([1; 32]);
+  assert!(server.request_block(h, None));
+  assert!(server.request_block(h, None));
+  let asked = pending_on(&server, h);
+  assert_eq!(asked.len(), 1);
+
+  // none of them has it, the request stays pending until it goes stale
+  wait(&mut evtlp, 200);
+  server.retry_stale_block_requests(Duration::from_secs(10));
+  assert_eq!(pending_on(&server, h), asked);
+  server.retry_stale_block_requests(Duration::from_secs(0));
+  let retried = pending_on(&server, h);
+  assert_eq!(retried.len(), 1);
+  assert!(retried[0] != asked[0]);
+}
+
+// Headers get located from the hashes of a locator and sent back, locators
+// with too many hashes being dropped.
+#[test]
+fn locator_headers() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let server_adapter = Arc::new(HeadersAdapter::new());
+  let client_adapter = Arc::new(HeadersAdapter::new());
+  let conf = p2p::P2PConfig::default();
+  let _server = start_server("headers", 14450, conf, server_adapter.clone(), &handle);
+  let client = start_server("headers-c", 14451, conf, client_adapter.clone(), &handle);
+  wait(&mut evtlp, 100);
+  handle.spawn(client.connect_peer(local(14450), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+
+  let peer = client.get_peer(local(14450)).unwrap();
+  let locator = vec![Hash([1; 32]), Hash([2; 32])];
+  peer.send_header_request(locator.clone()).unwrap();
+  wait(&mut evtlp, 500);
+  assert_eq!(*server_adapter.locators.lock().unwrap(), vec![locator]);
+  let sent = headers().iter().map(|bh| bh.hash()).collect::<Vec<_>>();
+  assert_eq!(*client_adapter.received.lock().unwrap(), sent);
+
+  peer.send_header_request(vec![Hash([1; 32]); 65]).unwrap();
+  wait(&mut evtlp, 500);
+  assert_eq!(server_adapter.locators.lock().unwrap().len(), 1);
+  assert_eq!(client_adapter.received.lock().unwrap().len(), sent.len());
+}
+
+// A peer banned by the address we connected to gets disconnected, and isn't
+// let back in by connecting to us from that address, its ban being kept.
+#[test]
+fn banned_listen_addr() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let server = start_server("banned", 14460, p2p::P2PConfig::default(), dummy(), &handle);
+  let peer = start_server("banned-p", 14461, p2p::P2PConfig::default(), dummy(), &handle);
+  wait(&mut evtlp, 100);
+  handle.spawn(server.connect_peer(local(14461), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+  assert_eq!(peer.peers_in(Direction::Inbound).len(), 1);
+
+  server.ban_peer(local(14461));
+  wait(&mut evtlp, 500);
+  assert_eq!(peer.peers_count(), 0);
+
+  handle.spawn(peer.connect_peer(local(14460), handle.clone()).map_err(|_| ()));
+  wait(&mut evtlp, 500);
+  assert_eq!(server.peers_count(), 0);
+  let known = server.known_peers();
+  assert!(known.iter().any(|p| p.addr == local(14461) && p.is_banned()));
+}
+
+// Starts a p2p server on the provided local port, keeping its peers under a
+// new temporary directory
+fn start_server(name: &str,
+                port: u16,
+                config: p2p::P2PConfig,
+                adapter: Arc<NetAdapter>,
+                handle: &reactor::Handle)
+                -> Arc<p2p::Server> {
+  let dir = env::temp_dir().join(format!("grin-p2p-{}-{}", name, rand::random::<u32>()));
+  let config = p2p::P2PConfig { port: port, ..config };
+  let server = p2p::Server::new(dir.to_str().unwrap().to_string(), config, adapter).unwrap();
+  let server = Arc::new(server);
+  handle.spawn(server.start(handle.clone()).map_err(|e| {
+    panic!("Server failed: {:?}", e);
+  }));
+  server
+}
+
+fn dummy() -> Arc<NetAdapter> {
+  Arc::new(p2p::DummyAdapter {})
+}
+
+fn local(port: u16) -> SocketAddr {
+  SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+}
+
+// Runs the event loop for the provided milliseconds
+fn wait(evtlp: &mut Core, millis: u64) {
+  let timeout = reactor::Timeout::new(Duration::from_millis(millis), &evtlp.handle()).unwrap();
+  evtlp.run(timeout).unwrap();
+}
+
+// Addresses of the peers of the server waiting for the block with the
+// provided hash
+fn pending_on(server: &p2p::Server, h: Hash) -> Vec<SocketAddr> {
+  server.peers_in(Direction::Outbound)
+    .iter()
+    .filter(|p| p.pending_block_requests().contains(&h))
+    .map(|p| p.info().addr)
+    .collect()
+}
+
+fn headers() -> Vec<core::core::BlockHeader> {
+  let mut bh = core::core::BlockHeader::default();
+  let mut headers = vec![bh.clone()];
+  bh.height = 1;
+  bh.previous = headers[0].hash();
+  headers.push(bh);
+  headers
+}
+
+// Answers requests for headers with the same ones, keeping the locators it
+// got and the hashes of the headers it received
+struct HeadersAdapter {
+  locators: Mutex<Vec<Vec<Hash>>>,
+  received: Mutex<Vec<Hash>>,
+}
+
+impl HeadersAdapter {
+  fn new() -> HeadersAdapter {
+    HeadersAdapter {
+      locators: Mutex::new(vec![]),
+      received: Mutex::new(vec![]),
+    }
+  }
+}
+
+impl NetAdapter for HeadersAdapter {
+  fn transaction_received(&self, _: core::core::Transaction, _: SocketAddr) {}
+  fn block_received(&self, _: core::core::Block, _: SocketAddr) {}
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::core::BlockHeader> {
+    self.locators.lock().unwrap().push(locator);
+    headers()
+  }
+  fn headers_received(&self, headers: Vec<core::core::BlockHeader>, _: SocketAddr) {
+    self.received.lock().unwrap().extend(headers.iter().map(|bh| bh.hash()));
+  }
+}
@@ -0,0 +1,75 @@
+#ifndef _SECP256K1_BULLETPROOF_
+# define _SECP256K1_BULLETPROOF_
+
+# include "secp256k1.h"
+
+# ifdef __cplusplus
+extern "C" {
+# endif
+
+#include <stdint.h>
+
+/** Length in bytes of a bulletproof, whatever the value it proves in range. */
+#define SECP256K1_BULLETPROOF_SIZE 688
+
+/** Initialize a context for usage with bulletproofs, computing the generators they're made with. */
+void secp256k1_bulletproof_context_initialize(secp256k1_context* ctx);
+
+/** Author a bulletproof that a committed value is within [0..2^64).
+ *  Returns 1: Proof successfully created.
+ *          0: Error
+ *  In:     ctx:    pointer to a context object, initialized for bulletproofs, signing and Pedersen commitment (cannot be NULL)
+ *          proof:  pointer to array to receive the proof, at least SECP256K1_BULLETPROOF_SIZE bytes. (cannot be NULL)
+ *          value:  Actual value of the commitment.
+ *          blind:  32-byte blinding factor of the commitment, which is value * H + blind * G. (cannot be NULL)
+ *          nonce:  32-byte secret nonce the randomness of the proof is derived from. (cannot be NULL)
+ *  In/out: plen:   pointer to an integer with the size of the proof buffer and the size of the constructed proof.
+ *
+ *  This can randomly fail with probability around one in 2^128. If this happens, retry with a different nonce.
+ */
+SECP256K1_WARN_UNUSED_RESULT int secp256k1_bulletproof_rangeproof_prove(
+  const secp256k1_context* ctx,
+  unsigned char *proof,
+  int *plen,
+  uint64_t value,
+  const unsigned char *blind,
+  const unsigned char *nonce
+) SECP256K1_ARG_NONNULL(1) SECP256K1_ARG_NONNULL(2) SECP256K1_ARG_NONNULL(3) SECP256K1_ARG_NONNULL(5) SECP256K1_ARG_NONNULL(6);
+
+/** Verify a bulletproof that a committed value is within [0..2^64).
+ *  Returns 1: Value is within the range.
+ *          0: Proof failed or other error.
+ *  In:   ctx: pointer to a context object, initialized for bulletproofs and Pedersen commitment (cannot be NULL)
+ *        commit: the 33-byte commitment being proved. (cannot be NULL)
+ *        proof: pointer to character array with the proof. (cannot be NULL)
+ *        plen: length of proof in bytes.
+ */
+SECP256K1_WARN_UNUSED_RESULT int secp256k1_bulletproof_rangeproof_verify(
+  const secp256k1_context* ctx,
+  const unsigned char *commit,
+  const unsigned char *proof,
+  int plen
+) SECP256K1_ARG_NONNULL(1) SECP256K1_ARG_NONNULL(2) SECP256K1_ARG_NONNULL(3);
+
+/** Verify many bulletproofs at once, in a fraction of the time it takes to verify them one by one.
+ *  Returns 1: All the values are within the range.
+ *          0: Any proof failed or other error.
+ *  In:   ctx: pointer to a context object, initialized for bulletproofs and Pedersen commitment (cannot be NULL)
+ *        commits: array of pointers to the 33-byte commitments being proved.
+ *        proofs: array of pointers to character arrays with the proof of each commitment.
+ *        plens: array with the length of each proof in bytes.
+ *        n: number of commitments and proofs.
+ */
+SECP256K1_WARN_UNUSED_RESULT int secp256k1_bulletproof_rangeproof_verify_multi(
+  const secp256k1_context* ctx,
+  const unsigned char * const *commits,
+  const unsigned char * const *proofs,
+  const int *plens,
+  int n
+) SECP256K1_ARG_NONNULL(1);
+
+# ifdef __cplusplus
+}
+# endif
+
+#endif
@@ -0,0 +1,35 @@
+/**********************************************************************
+ * Copyright (c) 2017 The Grin Developers                             *
+ * Distributed under the MIT software license, see the accompanying   *
+ * file COPYING or http://www.opensource.org/licenses/mit-license.php.*
+ **********************************************************************/
+
+#ifndef _SECP256K1_BULLETPROOF_H_
+#define _SECP256K1_BULLETPROOF_H_
+
+#include "scalar.h"
+#include "group.h"
+
+/* Values are proven to be in [0, 2^64), the inner product argument halving
+ * its vectors in 6 rounds. */
+#define SECP256K1_BULLETPROOF_BITS 64
+#define SECP256K1_BULLETPROOF_ROUNDS 6
+
+/* The G_i then H_i vector generators, followed by the inner product one. */
+#define SECP256K1_BULLETPROOF_GENS (2 * SECP256K1_BULLETPROOF_BITS + 1)
+
+/* A, S, T1, T2, then taux, mu, t, then L and R of each round, then a and b. */
+#define SECP256K1_BULLETPROOF_LEN (4 * 33 + 3 * 32 + 2 * SECP256K1_BULLETPROOF_ROUNDS * 33 + 2 * 32)
+
+typedef struct {
+    secp256k1_ge_storage (*gens)[SECP256K1_BULLETPROOF_GENS];
+} secp256k1_bulletproof_context;
+
+static void secp256k1_bulletproof_context_init(secp256k1_bulletproof_context *ctx);
+static void secp256k1_bulletproof_context_build(secp256k1_bulletproof_context *ctx, const secp256k1_callback *cb);
+static void secp256k1_bulletproof_context_clone(secp256k1_bulletproof_context *dst,
+                                                const secp256k1_bulletproof_context *src, const secp256k1_callback *cb);
+static void secp256k1_bulletproof_context_clear(secp256k1_bulletproof_context *ctx);
+static int secp256k1_bulletproof_context_is_built(const secp256k1_bulletproof_context *ctx);
+
+#endif
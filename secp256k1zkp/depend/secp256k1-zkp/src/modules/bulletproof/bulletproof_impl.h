@@ -0,0 +1,762 @@
+/**********************************************************************
+ * Copyright (c) 2017 The Grin Developers                             *
+ * Distributed under the MIT software license, see the accompanying   *
+ * file COPYING or http://www.opensource.org/licenses/mit-license.php.*
+ **********************************************************************/
+
+#ifndef _SECP256K1_BULLETPROOF_IMPL_H_
+#define _SECP256K1_BULLETPROOF_IMPL_H_
+
+#include <string.h>
+
+#include "scalar.h"
+#include "group.h"
+#include "ecmult_const.h"
+#include "hash_impl.h"
+
+#include "modules/rangeproof/pedersen_impl.h"
+#include "modules/bulletproof/bulletproof.h"
+
+/* Number of points whose multiples get tabulated at once by the multi-exponentiation. */
+#define SECP256K1_BULLETPROOF_MULTIEXP_CHUNK 64
+
+/* Points of the verification multi-exponentiation shared by all proofs: G, H,
+ * U, then the vector generators. */
+#define SECP256K1_BULLETPROOF_SHARED_POINTS (3 + 2 * SECP256K1_BULLETPROOF_BITS)
+
+/* Points of each proof in it: the commitment, T1, T2, A, S, then L and R of
+ * each round. */
+#define SECP256K1_BULLETPROOF_PROOF_POINTS (5 + 2 * SECP256K1_BULLETPROOF_ROUNDS)
+
+static void secp256k1_bulletproof_context_init(secp256k1_bulletproof_context *ctx) {
+    ctx->gens = NULL;
+}
+
+/* Nothing-up-my-sleeve generator: the point with an even y whose x is the first
+ * sha256 of the tag, the generator index and a counter that's on the curve. */
+static void secp256k1_bulletproof_generator(secp256k1_ge *r, uint32_t index) {
+    static const unsigned char tag[] = "Bulletproof generator";
+    unsigned char buf[32];
+    secp256k1_sha256_t sha;
+    secp256k1_fe x;
+    uint32_t counter = 0;
+    while (1) {
+        secp256k1_sha256_initialize(&sha);
+        secp256k1_sha256_write(&sha, tag, sizeof(tag) - 1);
+        buf[0] = index >> 24; buf[1] = index >> 16; buf[2] = index >> 8; buf[3] = index;
+        buf[4] = counter >> 24; buf[5] = counter >> 16; buf[6] = counter >> 8; buf[7] = counter;
+        secp256k1_sha256_write(&sha, buf, 8);
+        secp256k1_sha256_finalize(&sha, buf);
+        if (secp256k1_fe_set_b32(&x, buf) && secp256k1_ge_set_xo_var(r, &x, 0)) {
+            return;
+        }
+        counter++;
+    }
+}
+
+static void secp256k1_bulletproof_context_build(secp256k1_bulletproof_context *ctx, const secp256k1_callback *cb) {
+    secp256k1_ge ge;
+    int i;
+
+    if (ctx->gens != NULL) {
+        return;
+    }
+
+    ctx->gens = (secp256k1_ge_storage (*)[SECP256K1_BULLETPROOF_GENS])checked_malloc(cb, sizeof(*ctx->gens));
+    for (i = 0; i < SECP256K1_BULLETPROOF_GENS; i++) {
+        secp256k1_bulletproof_generator(&ge, i);
+        secp256k1_ge_to_storage(&(*ctx->gens)[i], &ge);
+    }
+}
+
+static int secp256k1_bulletproof_context_is_built(const secp256k1_bulletproof_context *ctx) {
+    return ctx->gens != NULL;
+}
+
+static void secp256k1_bulletproof_context_clone(secp256k1_bulletproof_context *dst,
+                                                const secp256k1_bulletproof_context *src, const secp256k1_callback *cb) {
+    if (src->gens == NULL) {
+        dst->gens = NULL;
+    } else {
+        dst->gens = (secp256k1_ge_storage (*)[SECP256K1_BULLETPROOF_GENS])checked_malloc(cb, sizeof(*dst->gens));
+        memcpy(dst->gens, src->gens, sizeof(*dst->gens));
+    }
+}
+
+static void secp256k1_bulletproof_context_clear(secp256k1_bulletproof_context *ctx) {
+    free(ctx->gens);
+    ctx->gens = NULL;
+}
+
+/* r = sum(scalars[i] * points[i]), in variable time, so only for public
+ * scalars as the verifier's. Each chunk of points gets a table of its first 15
+ * multiples, then all the points of the chunk share the same doublings, adding
+ * the multiple of each 4 bits of their scalar. */
+static void secp256k1_bulletproof_multiexp(secp256k1_gej *r, const secp256k1_ge *points, const secp256k1_scalar *scalars,
+                                           size_t n, const secp256k1_callback *cb) {
+    secp256k1_gej *precj;
+    secp256k1_ge *prec;
+    secp256k1_gej acc;
+    size_t start, len, i;
+    unsigned int digit;
+    int j, w;
+
+    secp256k1_gej_set_infinity(r);
+    if (n == 0) {
+        return;
+    }
+    precj = (secp256k1_gej *)checked_malloc(cb, sizeof(*precj) * 15 * SECP256K1_BULLETPROOF_MULTIEXP_CHUNK);
+    prec = (secp256k1_ge *)checked_malloc(cb, sizeof(*prec) * 15 * SECP256K1_BULLETPROOF_MULTIEXP_CHUNK);
+    for (start = 0; start < n; start += SECP256K1_BULLETPROOF_MULTIEXP_CHUNK) {
+        len = n - start;
+        if (len > SECP256K1_BULLETPROOF_MULTIEXP_CHUNK) {
+            len = SECP256K1_BULLETPROOF_MULTIEXP_CHUNK;
+        }
+        for (i = 0; i < len; i++) {
+            secp256k1_gej_set_ge(&precj[i * 15], &points[start + i]);
+            for (j = 1; j < 15; j++) {
+                secp256k1_gej_add_ge_var(&precj[i * 15 + j], &precj[i * 15 + j - 1], &points[start + i], NULL);
+            }
+        }
+        secp256k1_ge_set_all_gej_var(len * 15, prec, precj, cb);
+
+        secp256k1_gej_set_infinity(&acc);
+        for (w = 63; w >= 0; w--) {
+            for (j = 0; j < 4; j++) {
+                secp256k1_gej_double_var(&acc, &acc, NULL);
+            }
+            for (i = 0; i < len; i++) {
+                digit = secp256k1_scalar_get_bits_var(&scalars[start + i], w * 4, 4);
+                if (digit != 0) {
+                    secp256k1_gej_add_ge_var(&acc, &acc, &prec[i * 15 + digit - 1], NULL);
+                }
+            }
+        }
+        secp256k1_gej_add_var(r, r, &acc, NULL);
+    }
+    free(precj);
+    free(prec);
+}
+
+/* r += a in constant time, whether either of them is the point at infinity or
+ * not. Some point gets added in place of an a at infinity, the sum then being
+ * discarded. */
+static void secp256k1_bulletproof_gej_add_const(secp256k1_gej *r, const secp256k1_gej *a) {
+    secp256k1_gej copy = *a;
+    secp256k1_gej sum;
+    secp256k1_ge ge;
+    int skip;
+    secp256k1_ge_set_gej(&ge, &copy);
+    skip = ge.infinity;
+    secp256k1_fe_cmov(&ge.x, &secp256k1_ge_const_g.x, skip);
+    secp256k1_fe_cmov(&ge.y, &secp256k1_ge_const_g.y, skip);
+    ge.infinity = 0;
+    secp256k1_gej_add_ge(&sum, r, &ge);
+    secp256k1_fe_cmov(&r->x, &sum.x, !skip);
+    secp256k1_fe_cmov(&r->y, &sum.y, !skip);
+    secp256k1_fe_cmov(&r->z, &sum.z, !skip);
+    r->infinity = (r->infinity & skip) | (sum.infinity & !skip);
+    secp256k1_gej_clear(&copy);
+    secp256k1_gej_clear(&sum);
+    secp256k1_ge_clear(&ge);
+}
+
+/* r = sum(scalars[i] * points[i]) in constant time, for the prover whose
+ * scalars are all derived from secrets: the blinding factors, the bits of the
+ * value and the folded vectors. Each multiple is computed with ecmult_const and
+ * added whatever it is, so neither the scalars nor which of them are zero
+ * show in the timing. */
+static void secp256k1_bulletproof_multiexp_const(secp256k1_gej *r, const secp256k1_ge *points,
+                                                 const secp256k1_scalar *scalars, size_t n) {
+    secp256k1_gej term;
+    size_t i;
+    secp256k1_gej_set_infinity(r);
+    for (i = 0; i < n; i++) {
+        secp256k1_ecmult_const(&term, &points[i], &scalars[i]);
+        secp256k1_bulletproof_gej_add_const(r, &term);
+    }
+    secp256k1_gej_clear(&term);
+}
+
+/* Serializes a point in 33 bytes, which fails if it's the point at infinity. */
+static int secp256k1_bulletproof_serialize_point(unsigned char *out, const secp256k1_gej *pj) {
+    secp256k1_gej copy = *pj;
+    secp256k1_ge p;
+    size_t sz = 33;
+    if (secp256k1_gej_is_infinity(pj)) {
+        return 0;
+    }
+    secp256k1_ge_set_gej(&p, &copy);
+    return secp256k1_eckey_pubkey_serialize(&p, out, &sz, 1);
+}
+
+/* Next challenge of the transcript: the hash of the transcript so far with the
+ * serialized points and scalars, as a scalar. Fails if the hash isn't a valid
+ * non-zero scalar, which happens with negligible probability. */
+static int secp256k1_bulletproof_challenge(secp256k1_scalar *r, unsigned char *transcript,
+                                           const unsigned char *points, size_t npoints,
+                                           const secp256k1_scalar *scalars, size_t nscalars) {
+    secp256k1_sha256_t sha;
+    unsigned char buf[32];
+    size_t i;
+    int overflow;
+    secp256k1_sha256_initialize(&sha);
+    secp256k1_sha256_write(&sha, transcript, 32);
+    secp256k1_sha256_write(&sha, points, npoints * 33);
+    for (i = 0; i < nscalars; i++) {
+        secp256k1_scalar_get_b32(buf, &scalars[i]);
+        secp256k1_sha256_write(&sha, buf, 32);
+    }
+    secp256k1_sha256_finalize(&sha, transcript);
+    secp256k1_scalar_set_b32(r, transcript, &overflow);
+    return !overflow && !secp256k1_scalar_is_zero(r);
+}
+
+/* Start of the transcript, binding the proof to its commitment. */
+static void secp256k1_bulletproof_transcript_init(unsigned char *transcript, const unsigned char *commit) {
+    secp256k1_sha256_t sha;
+    secp256k1_sha256_initialize(&sha);
+    secp256k1_sha256_write(&sha, commit, 33);
+    secp256k1_sha256_finalize(&sha, transcript);
+}
+
+static void secp256k1_bulletproof_random_scalar(secp256k1_scalar *r, secp256k1_rfc6979_hmac_sha256_t *rng) {
+    unsigned char buf[32];
+    int overflow;
+    do {
+        secp256k1_rfc6979_hmac_sha256_generate(rng, buf, 32);
+        secp256k1_scalar_set_b32(r, buf, &overflow);
+    } while (overflow || secp256k1_scalar_is_zero(r));
+}
+
+static void secp256k1_bulletproof_load_gens(secp256k1_ge *gens, const secp256k1_bulletproof_context *bp_ctx) {
+    int i;
+    for (i = 0; i < SECP256K1_BULLETPROOF_GENS; i++) {
+        secp256k1_ge_from_storage(&gens[i], &(*bp_ctx->gens)[i]);
+    }
+}
+
+/* Proves value in commit = value * H + gamma * G is in [0, 2^64), following
+ * the Bulletproofs paper with G as blinding generator and H as value generator.
+ * The inner product argument keeps the vector generators G_i and H_i as they
+ * are, tracking instead the coefficient each one has in the folded vectors.
+ * Every point is built from secrets, the blinding factor gamma showing through
+ * taux if tau1 or tau2 leaked, so all of them are computed in constant time. */
+static int secp256k1_bulletproof_prove_impl(const secp256k1_bulletproof_context *bp_ctx, unsigned char *proof,
+                                            uint64_t value, const secp256k1_scalar *gamma,
+                                            const unsigned char *commit, const unsigned char *nonce) {
+    secp256k1_ge points[SECP256K1_BULLETPROOF_GENS + 1];
+    secp256k1_scalar scalars[SECP256K1_BULLETPROOF_GENS + 1];
+    secp256k1_scalar al[SECP256K1_BULLETPROOF_BITS], ar[SECP256K1_BULLETPROOF_BITS];
+    secp256k1_scalar sl[SECP256K1_BULLETPROOF_BITS], sr[SECP256K1_BULLETPROOF_BITS];
+    secp256k1_scalar gcoef[SECP256K1_BULLETPROOF_BITS], hcoef[SECP256K1_BULLETPROOF_BITS];
+    secp256k1_scalar alpha, rho, tau1, tau2, y, z, zz, x, w, u, uinv, yn, two, yinv;
+    secp256k1_scalar l0, r0, t1, t2, t, taux, mu, cl, cr, tmp, one;
+    secp256k1_rfc6979_hmac_sha256_t rng;
+    unsigned char transcript[32];
+    unsigned char seed[97];
+    unsigned char *ptr;
+    secp256k1_gej pj;
+    int i, j, len, half, m;
+
+    const int nbits = SECP256K1_BULLETPROOF_BITS;
+    secp256k1_ge *gs = &points[1];
+
+    /* points are G, G_i, H_i, U, the scalars the coefficients of each */
+    points[0] = secp256k1_ge_const_g;
+    secp256k1_bulletproof_load_gens(&points[1], bp_ctx);
+
+    memcpy(seed, nonce, 32);
+    secp256k1_scalar_get_b32(&seed[32], gamma);
+    memcpy(&seed[64], commit, 33);
+    secp256k1_rfc6979_hmac_sha256_initialize(&rng, seed, 97);
+    secp256k1_bulletproof_random_scalar(&alpha, &rng);
+    secp256k1_bulletproof_random_scalar(&rho, &rng);
+    secp256k1_bulletproof_random_scalar(&tau1, &rng);
+    secp256k1_bulletproof_random_scalar(&tau2, &rng);
+    for (i = 0; i < nbits; i++) {
+        secp256k1_bulletproof_random_scalar(&sl[i], &rng);
+        secp256k1_bulletproof_random_scalar(&sr[i], &rng);
+    }
+    secp256k1_rfc6979_hmac_sha256_finalize(&rng);
+    memset(seed, 0, sizeof(seed));
+
+    /* a_L the bits of the value, a_R = a_L - 1 */
+    secp256k1_scalar_set_int(&one, 1);
+    for (i = 0; i < nbits; i++) {
+        secp256k1_scalar_set_int(&al[i], (value >> i) & 1);
+        secp256k1_scalar_negate(&tmp, &one);
+        secp256k1_scalar_add(&ar[i], &al[i], &tmp);
+    }
+
+    ptr = proof;
+    secp256k1_bulletproof_transcript_init(transcript, commit);
+
+    /* A = alpha * G + <a_L, G_i> + <a_R, H_i>, S the same with rho, s_L and s_R */
+    scalars[0] = alpha;
+    for (i = 0; i < nbits; i++) {
+        scalars[1 + i] = al[i];
+        scalars[1 + nbits + i] = ar[i];
+    }
+    secp256k1_bulletproof_multiexp_const(&pj, points, scalars, 1 + 2 * nbits);
+    if (!secp256k1_bulletproof_serialize_point(ptr, &pj)) {
+        return 0;
+    }
+    scalars[0] = rho;
+    for (i = 0; i < nbits; i++) {
+        scalars[1 + i] = sl[i];
+        scalars[1 + nbits + i] = sr[i];
+    }
+    secp256k1_bulletproof_multiexp_const(&pj, points, scalars, 1 + 2 * nbits);
+    if (!secp256k1_bulletproof_serialize_point(ptr + 33, &pj)) {
+        return 0;
+    }
+    if (!secp256k1_bulletproof_challenge(&y, transcript, ptr, 2, NULL, 0) ||
+        !secp256k1_bulletproof_challenge(&z, transcript, NULL, 0, NULL, 0)) {
+        return 0;
+    }
+    ptr += 2 * 33;
+    secp256k1_scalar_sqr(&zz, &z);
+
+    /* l(X) = l0 + s_L * X, r(X) = r0 + y^n o s_R * X, with l0 = a_L - z and
+     * r0 = y^n o (a_R + z) + z^2 * 2^n, t1 and t2 the coefficients of X and X^2
+     * of their inner product. Reusing s_L and s_R for l1 and r1, and a_L and
+     * a_R for l0 and r0. */
+    secp256k1_scalar_set_int(&yn, 1);
+    secp256k1_scalar_set_int(&two, 1);
+    secp256k1_scalar_set_int(&t1, 0);
+    secp256k1_scalar_set_int(&t2, 0);
+    for (i = 0; i < nbits; i++) {
+        secp256k1_scalar_negate(&tmp, &z);
+        secp256k1_scalar_add(&l0, &al[i], &tmp);
+        secp256k1_scalar_add(&r0, &ar[i], &z);
+        secp256k1_scalar_mul(&r0, &r0, &yn);
+        secp256k1_scalar_mul(&tmp, &zz, &two);
+        secp256k1_scalar_add(&r0, &r0, &tmp);
+        secp256k1_scalar_mul(&sr[i], &sr[i], &yn);
+
+        secp256k1_scalar_mul(&tmp, &l0, &sr[i]);
+        secp256k1_scalar_add(&t1, &t1, &tmp);
+        secp256k1_scalar_mul(&tmp, &sl[i], &r0);
+        secp256k1_scalar_add(&t1, &t1, &tmp);
+        secp256k1_scalar_mul(&tmp, &sl[i], &sr[i]);
+        secp256k1_scalar_add(&t2, &t2, &tmp);
+
+        al[i] = l0;
+        ar[i] = r0;
+        secp256k1_scalar_mul(&yn, &yn, &y);
+        secp256k1_scalar_add(&two, &two, &two);
+    }
+
+    /* T1 = t1 * H + tau1 * G, T2 = t2 * H + tau2 * G */
+    {
+        secp256k1_ge tpoints[2];
+        secp256k1_scalar tscalars[2];
+        tpoints[0] = secp256k1_ge_const_g2;
+        tpoints[1] = secp256k1_ge_const_g;
+        tscalars[0] = t1;
+        tscalars[1] = tau1;
+        secp256k1_bulletproof_multiexp_const(&pj, tpoints, tscalars, 2);
+        if (!secp256k1_bulletproof_serialize_point(ptr, &pj)) {
+            return 0;
+        }
+        tscalars[0] = t2;
+        tscalars[1] = tau2;
+        secp256k1_bulletproof_multiexp_const(&pj, tpoints, tscalars, 2);
+        if (!secp256k1_bulletproof_serialize_point(ptr + 33, &pj)) {
+            return 0;
+        }
+    }
+    if (!secp256k1_bulletproof_challenge(&x, transcript, ptr, 2, NULL, 0)) {
+        return 0;
+    }
+    ptr += 2 * 33;
+
+    /* l = l0 + l1 * x, r = r0 + r1 * x, t = <l, r> */
+    secp256k1_scalar_set_int(&t, 0);
+    for (i = 0; i < nbits; i++) {
+        secp256k1_scalar_mul(&tmp, &sl[i], &x);
+        secp256k1_scalar_add(&al[i], &al[i], &tmp);
+        secp256k1_scalar_mul(&tmp, &sr[i], &x);
+        secp256k1_scalar_add(&ar[i], &ar[i], &tmp);
+        secp256k1_scalar_mul(&tmp, &al[i], &ar[i]);
+        secp256k1_scalar_add(&t, &t, &tmp);
+    }
+    /* taux = tau2 * x^2 + tau1 * x + z^2 * gamma, mu = alpha + rho * x */
+    secp256k1_scalar_mul(&taux, &tau2, &x);
+    secp256k1_scalar_add(&taux, &taux, &tau1);
+    secp256k1_scalar_mul(&taux, &taux, &x);
+    secp256k1_scalar_mul(&tmp, &zz, gamma);
+    secp256k1_scalar_add(&taux, &taux, &tmp);
+    secp256k1_scalar_mul(&mu, &rho, &x);
+    secp256k1_scalar_add(&mu, &mu, &alpha);
+    secp256k1_scalar_get_b32(ptr, &taux);
+    secp256k1_scalar_get_b32(ptr + 32, &mu);
+    secp256k1_scalar_get_b32(ptr + 64, &t);
+    ptr += 3 * 32;
+    scalars[0] = taux;
+    scalars[1] = mu;
+    scalars[2] = t;
+    if (!secp256k1_bulletproof_challenge(&w, transcript, NULL, 0, scalars, 3)) {
+        return 0;
+    }
+
+    /* inner product argument that <l, r> = t, with the generators G_i,
+     * H'_i = y^-i * H_i and w * U */
+    secp256k1_scalar_inverse_var(&yinv, &y);
+    secp256k1_scalar_set_int(&tmp, 1);
+    for (i = 0; i < nbits; i++) {
+        secp256k1_scalar_set_int(&gcoef[i], 1);
+        hcoef[i] = tmp;
+        secp256k1_scalar_mul(&tmp, &tmp, &yinv);
+    }
+    len = nbits;
+    for (j = 0; j < SECP256K1_BULLETPROOF_ROUNDS; j++) {
+        half = len / 2;
+        secp256k1_scalar_set_int(&cl, 0);
+        secp256k1_scalar_set_int(&cr, 0);
+        for (i = 0; i < half; i++) {
+            secp256k1_scalar_mul(&tmp, &al[i], &ar[i + half]);
+            secp256k1_scalar_add(&cl, &cl, &tmp);
+            secp256k1_scalar_mul(&tmp, &al[i + half], &ar[i]);
+            secp256k1_scalar_add(&cr, &cr, &tmp);
+        }
+        /* L = <a_lo, G_hi> + <b_hi, H'_lo> + cL * w * U, the folded generator
+         * at m being the sum of the original ones at i with i mod len = m */
+        for (i = 0; i < nbits; i++) {
+            m = i & (len - 1);
+            if (m >= half) {
+                secp256k1_scalar_mul(&scalars[1 + i], &al[m - half], &gcoef[i]);
+                secp256k1_scalar_set_int(&scalars[1 + nbits + i], 0);
+            } else {
+                secp256k1_scalar_set_int(&scalars[1 + i], 0);
+                secp256k1_scalar_mul(&scalars[1 + nbits + i], &ar[m + half], &hcoef[i]);
+            }
+        }
+        secp256k1_scalar_mul(&scalars[1 + 2 * nbits], &cl, &w);
+        secp256k1_bulletproof_multiexp_const(&pj, gs, &scalars[1], 2 * nbits + 1);
+        if (!secp256k1_bulletproof_serialize_point(ptr, &pj)) {
+            return 0;
+        }
+        /* R = <a_hi, G_lo> + <b_lo, H'_hi> + cR * w * U */
+        for (i = 0; i < nbits; i++) {
+            m = i & (len - 1);
+            if (m < half) {
+                secp256k1_scalar_mul(&scalars[1 + i], &al[m + half], &gcoef[i]);
+                secp256k1_scalar_set_int(&scalars[1 + nbits + i], 0);
+            } else {
+                secp256k1_scalar_set_int(&scalars[1 + i], 0);
+                secp256k1_scalar_mul(&scalars[1 + nbits + i], &ar[m - half], &hcoef[i]);
+            }
+        }
+        secp256k1_scalar_mul(&scalars[1 + 2 * nbits], &cr, &w);
+        secp256k1_bulletproof_multiexp_const(&pj, gs, &scalars[1], 2 * nbits + 1);
+        if (!secp256k1_bulletproof_serialize_point(ptr + 33, &pj)) {
+            return 0;
+        }
+        if (!secp256k1_bulletproof_challenge(&u, transcript, ptr, 2, NULL, 0)) {
+            return 0;
+        }
+        ptr += 2 * 33;
+        secp256k1_scalar_inverse_var(&uinv, &u);
+
+        /* G' = u^-1 * G_lo + u * G_hi, H' = u * H'_lo + u^-1 * H'_hi,
+         * a' = u * a_lo + u^-1 * a_hi, b' = u^-1 * b_lo + u * b_hi */
+        for (i = 0; i < nbits; i++) {
+            m = i & (len - 1);
+            if (m < half) {
+                secp256k1_scalar_mul(&gcoef[i], &gcoef[i], &uinv);
+                secp256k1_scalar_mul(&hcoef[i], &hcoef[i], &u);
+            } else {
+                secp256k1_scalar_mul(&gcoef[i], &gcoef[i], &u);
+                secp256k1_scalar_mul(&hcoef[i], &hcoef[i], &uinv);
+            }
+        }
+        for (i = 0; i < half; i++) {
+            secp256k1_scalar_mul(&al[i], &al[i], &u);
+            secp256k1_scalar_mul(&tmp, &al[i + half], &uinv);
+            secp256k1_scalar_add(&al[i], &al[i], &tmp);
+            secp256k1_scalar_mul(&ar[i], &ar[i], &uinv);
+            secp256k1_scalar_mul(&tmp, &ar[i + half], &u);
+            secp256k1_scalar_add(&ar[i], &ar[i], &tmp);
+        }
+        len = half;
+    }
+    secp256k1_scalar_get_b32(ptr, &al[0]);
+    secp256k1_scalar_get_b32(ptr + 32, &ar[0]);
+
+    secp256k1_scalar_clear(&alpha);
+    secp256k1_scalar_clear(&rho);
+    secp256k1_scalar_clear(&tau1);
+    secp256k1_scalar_clear(&tau2);
+    memset(al, 0, sizeof(al));
+    memset(ar, 0, sizeof(ar));
+    memset(sl, 0, sizeof(sl));
+    memset(sr, 0, sizeof(sr));
+    memset(scalars, 0, sizeof(scalars));
+    return 1;
+}
+
+/* Parses a proof point, which must be a valid compressed point. */
+static int secp256k1_bulletproof_parse_point(secp256k1_ge *r, const unsigned char *in) {
+    if (in[0] != 0x02 && in[0] != 0x03) {
+        return 0;
+    }
+    return secp256k1_eckey_pubkey_parse(r, in, 33);
+}
+
+/* Parses a proof scalar, which must be lower than the group order. */
+static int secp256k1_bulletproof_parse_scalar(secp256k1_scalar *r, const unsigned char *in) {
+    int overflow;
+    secp256k1_scalar_set_b32(r, in, &overflow);
+    return !overflow;
+}
+
+/* Transcript of a proof being verified, its challenges and scalars. */
+typedef struct {
+    secp256k1_scalar y, z, x, w, c, taux, mu, t, a, b;
+    secp256k1_scalar us[SECP256K1_BULLETPROOF_ROUNDS], uinvs[SECP256K1_BULLETPROOF_ROUNDS];
+    unsigned char transcript[32];
+} secp256k1_bulletproof_verify_state;
+
+/* Parses a proof, replaying its transcript. Its points go in the order of
+ * their coefficients in the multi-exponentiation: the commitment V, T1, T2,
+ * A, S, then L_j and R_j. */
+static int secp256k1_bulletproof_verify_parse(secp256k1_bulletproof_verify_state *st, secp256k1_ge *points,
+                                              const unsigned char *commit, const unsigned char *proof) {
+    secp256k1_scalar scalars[3];
+    const unsigned char *ptr;
+    int j;
+
+    if (!secp256k1_eckey_pubkey_parse(&points[0], commit, 33)) {
+        return 0;
+    }
+    ptr = proof;
+    secp256k1_bulletproof_transcript_init(st->transcript, commit);
+    if (!secp256k1_bulletproof_parse_point(&points[3], ptr) ||
+        !secp256k1_bulletproof_parse_point(&points[4], ptr + 33) ||
+        !secp256k1_bulletproof_challenge(&st->y, st->transcript, ptr, 2, NULL, 0) ||
+        !secp256k1_bulletproof_challenge(&st->z, st->transcript, NULL, 0, NULL, 0)) {
+        return 0;
+    }
+    ptr += 2 * 33;
+    if (!secp256k1_bulletproof_parse_point(&points[1], ptr) ||
+        !secp256k1_bulletproof_parse_point(&points[2], ptr + 33) ||
+        !secp256k1_bulletproof_challenge(&st->x, st->transcript, ptr, 2, NULL, 0)) {
+        return 0;
+    }
+    ptr += 2 * 33;
+    if (!secp256k1_bulletproof_parse_scalar(&st->taux, ptr) ||
+        !secp256k1_bulletproof_parse_scalar(&st->mu, ptr + 32) ||
+        !secp256k1_bulletproof_parse_scalar(&st->t, ptr + 64)) {
+        return 0;
+    }
+    ptr += 3 * 32;
+    scalars[0] = st->taux;
+    scalars[1] = st->mu;
+    scalars[2] = st->t;
+    if (!secp256k1_bulletproof_challenge(&st->w, st->transcript, NULL, 0, scalars, 3)) {
+        return 0;
+    }
+    for (j = 0; j < SECP256K1_BULLETPROOF_ROUNDS; j++) {
+        if (!secp256k1_bulletproof_parse_point(&points[5 + 2 * j], ptr) ||
+            !secp256k1_bulletproof_parse_point(&points[5 + 2 * j + 1], ptr + 33) ||
+            !secp256k1_bulletproof_challenge(&st->us[j], st->transcript, ptr, 2, NULL, 0)) {
+            return 0;
+        }
+        ptr += 2 * 33;
+        secp256k1_scalar_inverse_var(&st->uinvs[j], &st->us[j]);
+    }
+    if (!secp256k1_bulletproof_parse_scalar(&st->a, ptr) ||
+        !secp256k1_bulletproof_parse_scalar(&st->b, ptr + 32)) {
+        return 0;
+    }
+    scalars[0] = st->a;
+    scalars[1] = st->b;
+    return secp256k1_bulletproof_challenge(&st->c, st->transcript, NULL, 0, scalars, 2);
+}
+
+/* Adds the coefficients of a parsed proof, all multiplied by its weight, to
+ * the ones of the shared points G, H, U, G_i and H_i, and sets the ones of its
+ * own points. The polynomial commitment equation gets multiplied by the c
+ * challenge on top, so that it can't cancel out the inner product one. */
+static void secp256k1_bulletproof_verify_add(secp256k1_scalar *shared, secp256k1_scalar *scalars,
+                                             const secp256k1_bulletproof_verify_state *st,
+                                             const secp256k1_scalar *weight) {
+    secp256k1_scalar zz, ab, delta, yn, yinv, yinvn, two, sumy, s, sinv, wc, tmp;
+    int i, j;
+
+    const int nbits = SECP256K1_BULLETPROOF_BITS;
+    const int nrounds = SECP256K1_BULLETPROOF_ROUNDS;
+    secp256k1_scalar *gs = &shared[3];
+    secp256k1_scalar *hs = &shared[3 + SECP256K1_BULLETPROOF_BITS];
+
+    secp256k1_scalar_sqr(&zz, &st->z);
+    secp256k1_scalar_mul(&ab, &st->a, &st->b);
+    secp256k1_scalar_inverse_var(&yinv, &st->y);
+    secp256k1_scalar_mul(&wc, weight, &st->c);
+
+    /* G_i: -z - a * s_i, H_i: z + (z^2 * 2^i - b * s_i^-1) * y^-i, with s_i the
+     * product over the rounds of u_j where bit i is in the high half, u_j^-1
+     * where it's in the low half */
+    secp256k1_scalar_set_int(&yn, 1);
+    secp256k1_scalar_set_int(&yinvn, 1);
+    secp256k1_scalar_set_int(&two, 1);
+    secp256k1_scalar_set_int(&sumy, 0);
+    for (i = 0; i < nbits; i++) {
+        secp256k1_scalar_set_int(&s, 1);
+        secp256k1_scalar_set_int(&sinv, 1);
+        for (j = 0; j < nrounds; j++) {
+            if (i & (nbits >> (j + 1))) {
+                secp256k1_scalar_mul(&s, &s, &st->us[j]);
+                secp256k1_scalar_mul(&sinv, &sinv, &st->uinvs[j]);
+            } else {
+                secp256k1_scalar_mul(&s, &s, &st->uinvs[j]);
+                secp256k1_scalar_mul(&sinv, &sinv, &st->us[j]);
+            }
+        }
+        secp256k1_scalar_mul(&tmp, &st->a, &s);
+        secp256k1_scalar_add(&tmp, &tmp, &st->z);
+        secp256k1_scalar_mul(&tmp, &tmp, weight);
+        secp256k1_scalar_negate(&tmp, &tmp);
+        secp256k1_scalar_add(&gs[i], &gs[i], &tmp);
+
+        secp256k1_scalar_mul(&s, &zz, &two);
+        secp256k1_scalar_mul(&tmp, &st->b, &sinv);
+        secp256k1_scalar_negate(&tmp, &tmp);
+        secp256k1_scalar_add(&s, &s, &tmp);
+        secp256k1_scalar_mul(&s, &s, &yinvn);
+        secp256k1_scalar_add(&s, &s, &st->z);
+        secp256k1_scalar_mul(&s, &s, weight);
+        secp256k1_scalar_add(&hs[i], &hs[i], &s);
+
+        secp256k1_scalar_add(&sumy, &sumy, &yn);
+        secp256k1_scalar_mul(&yn, &yn, &st->y);
+        secp256k1_scalar_mul(&yinvn, &yinvn, &yinv);
+        secp256k1_scalar_add(&two, &two, &two);
+    }
+
+    /* delta = (z - z^2) * <1, y^n> - z^3 * <1, 2^n>, 2^n having become 2^64
+     * so <1, 2^n> is two - 1 */
+    secp256k1_scalar_negate(&tmp, &zz);
+    secp256k1_scalar_add(&delta, &st->z, &tmp);
+    secp256k1_scalar_mul(&delta, &delta, &sumy);
+    secp256k1_scalar_set_int(&tmp, 1);
+    secp256k1_scalar_negate(&tmp, &tmp);
+    secp256k1_scalar_add(&tmp, &two, &tmp);
+    secp256k1_scalar_mul(&tmp, &tmp, &zz);
+    secp256k1_scalar_mul(&tmp, &tmp, &st->z);
+    secp256k1_scalar_negate(&tmp, &tmp);
+    secp256k1_scalar_add(&delta, &delta, &tmp);
+
+    /* G: c * taux - mu */
+    secp256k1_scalar_mul(&s, &wc, &st->taux);
+    secp256k1_scalar_mul(&tmp, weight, &st->mu);
+    secp256k1_scalar_negate(&tmp, &tmp);
+    secp256k1_scalar_add(&s, &s, &tmp);
+    secp256k1_scalar_add(&shared[0], &shared[0], &s);
+    /* H: c * (t - delta) */
+    secp256k1_scalar_negate(&tmp, &delta);
+    secp256k1_scalar_add(&s, &st->t, &tmp);
+    secp256k1_scalar_mul(&s, &s, &wc);
+    secp256k1_scalar_add(&shared[1], &shared[1], &s);
+    /* U: w * (t - a * b) */
+    secp256k1_scalar_negate(&tmp, &ab);
+    secp256k1_scalar_add(&s, &st->t, &tmp);
+    secp256k1_scalar_mul(&s, &s, &st->w);
+    secp256k1_scalar_mul(&s, &s, weight);
+    secp256k1_scalar_add(&shared[2], &shared[2], &s);
+    /* V: -c * z^2, T1: -c * x, T2: -c * x^2 */
+    secp256k1_scalar_mul(&scalars[0], &wc, &zz);
+    secp256k1_scalar_negate(&scalars[0], &scalars[0]);
+    secp256k1_scalar_mul(&scalars[1], &wc, &st->x);
+    secp256k1_scalar_negate(&scalars[1], &scalars[1]);
+    secp256k1_scalar_mul(&scalars[2], &scalars[1], &st->x);
+    /* A: 1, S: x */
+    scalars[3] = *weight;
+    secp256k1_scalar_mul(&scalars[4], weight, &st->x);
+    /* L_j: u_j^2, R_j: u_j^-2 */
+    for (j = 0; j < nrounds; j++) {
+        secp256k1_scalar_sqr(&scalars[5 + 2 * j], &st->us[j]);
+        secp256k1_scalar_mul(&scalars[5 + 2 * j], &scalars[5 + 2 * j], weight);
+        secp256k1_scalar_sqr(&scalars[5 + 2 * j + 1], &st->uinvs[j]);
+        secp256k1_scalar_mul(&scalars[5 + 2 * j + 1], &scalars[5 + 2 * j + 1], weight);
+    }
+}
+
+/* Verifies n proofs at once by checking both the polynomial commitment and the
+ * inner product argument equations of each in a single multi-exponentiation:
+ * all their points, the generators and the commitments times their coefficient
+ * must sum to the point at infinity. The equations of each proof get multiplied
+ * by a weight drawn from the transcripts of all of them, so that an invalid
+ * proof can't be made up for by another one. */
+static int secp256k1_bulletproof_verify_impl(const secp256k1_bulletproof_context *bp_ctx,
+                                             const unsigned char * const *commits,
+                                             const unsigned char * const *proofs,
+                                             size_t n, const secp256k1_callback *cb) {
+    secp256k1_ge *points;
+    secp256k1_scalar *scalars;
+    secp256k1_bulletproof_verify_state *states;
+    secp256k1_scalar weight;
+    secp256k1_sha256_t sha;
+    unsigned char transcript[32];
+    secp256k1_gej pj;
+    size_t npoints, k;
+    int i;
+    int ret = 0;
+
+    if (n == 0) {
+        return 1;
+    }
+    npoints = SECP256K1_BULLETPROOF_SHARED_POINTS + n * SECP256K1_BULLETPROOF_PROOF_POINTS;
+    points = (secp256k1_ge *)checked_malloc(cb, sizeof(*points) * npoints);
+    scalars = (secp256k1_scalar *)checked_malloc(cb, sizeof(*scalars) * npoints);
+    states = (secp256k1_bulletproof_verify_state *)checked_malloc(cb, sizeof(*states) * n);
+
+    /* shared points are G, H, U, then G_i and H_i, followed by the points of
+     * each proof */
+    {
+        secp256k1_ge gensu[SECP256K1_BULLETPROOF_GENS];
+        secp256k1_bulletproof_load_gens(gensu, bp_ctx);
+        points[0] = secp256k1_ge_const_g;
+        points[1] = secp256k1_ge_const_g2;
+        points[2] = gensu[2 * SECP256K1_BULLETPROOF_BITS];
+        for (i = 0; i < 2 * SECP256K1_BULLETPROOF_BITS; i++) {
+            points[3 + i] = gensu[i];
+        }
+    }
+    for (i = 0; i < SECP256K1_BULLETPROOF_SHARED_POINTS; i++) {
+        secp256k1_scalar_set_int(&scalars[i], 0);
+    }
+
+    secp256k1_sha256_initialize(&sha);
+    for (k = 0; k < n; k++) {
+        if (!secp256k1_bulletproof_verify_parse(&states[k],
+                &points[SECP256K1_BULLETPROOF_SHARED_POINTS + k * SECP256K1_BULLETPROOF_PROOF_POINTS],
+                commits[k], proofs[k])) {
+            goto done;
+        }
+        secp256k1_sha256_write(&sha, states[k].transcript, 32);
+    }
+    secp256k1_sha256_finalize(&sha, transcript);
+
+    for (k = 0; k < n; k++) {
+        if (!secp256k1_bulletproof_challenge(&weight, transcript, NULL, 0, NULL, 0)) {
+            goto done;
+        }
+        secp256k1_bulletproof_verify_add(scalars,
+            &scalars[SECP256K1_BULLETPROOF_SHARED_POINTS + k * SECP256K1_BULLETPROOF_PROOF_POINTS],
+            &states[k], &weight);
+    }
+
+    secp256k1_bulletproof_multiexp(&pj, points, scalars, npoints, cb);
+    ret = secp256k1_gej_is_infinity(&pj);
+
+done:
+    free(points);
+    free(scalars);
+    free(states);
+    return ret;
+}
+
+#endif
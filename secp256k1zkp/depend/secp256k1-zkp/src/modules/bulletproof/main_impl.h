@@ -0,0 +1,87 @@
+/**********************************************************************
+ * Copyright (c) 2017 The Grin Developers                             *
+ * Distributed under the MIT software license, see the accompanying   *
+ * file COPYING or http://www.opensource.org/licenses/mit-license.php.*
+ **********************************************************************/
+
+#ifndef SECP256K1_MODULE_BULLETPROOF_MAIN
+#define SECP256K1_MODULE_BULLETPROOF_MAIN
+
+#include "include/secp256k1_bulletproof.h"
+#include "modules/bulletproof/bulletproof_impl.h"
+
+void secp256k1_bulletproof_context_initialize(secp256k1_context* ctx) {
+    secp256k1_bulletproof_context_build(&ctx->bulletproof_ctx, &ctx->error_callback);
+}
+
+int secp256k1_bulletproof_rangeproof_prove(const secp256k1_context* ctx, unsigned char *proof, int *plen, uint64_t value,
+ const unsigned char *blind, const unsigned char *nonce) {
+    secp256k1_gej rj;
+    secp256k1_ge r;
+    secp256k1_scalar sec;
+    unsigned char commit[33];
+    size_t sz;
+    int overflow;
+    int ret = 0;
+    ARG_CHECK(ctx != NULL);
+    ARG_CHECK(proof != NULL);
+    ARG_CHECK(plen != NULL);
+    ARG_CHECK(blind != NULL);
+    ARG_CHECK(nonce != NULL);
+    ARG_CHECK(secp256k1_ecmult_gen_context_is_built(&ctx->ecmult_gen_ctx));
+    ARG_CHECK(secp256k1_pedersen_context_is_built(&ctx->pedersen_ctx));
+    ARG_CHECK(secp256k1_bulletproof_context_is_built(&ctx->bulletproof_ctx));
+    if (*plen < SECP256K1_BULLETPROOF_LEN) {
+        return 0;
+    }
+    secp256k1_scalar_set_b32(&sec, blind, &overflow);
+    if (overflow) {
+        return 0;
+    }
+    secp256k1_pedersen_ecmult(&ctx->ecmult_gen_ctx, &ctx->pedersen_ctx, &rj, &sec, value);
+    if (!secp256k1_gej_is_infinity(&rj)) {
+        secp256k1_ge_set_gej(&r, &rj);
+        sz = 33;
+        if (secp256k1_eckey_pubkey_serialize(&r, commit, &sz, 1)) {
+            ret = secp256k1_bulletproof_prove_impl(&ctx->bulletproof_ctx, proof, value, &sec, commit, nonce);
+        }
+    }
+    if (ret) {
+        *plen = SECP256K1_BULLETPROOF_LEN;
+    }
+    secp256k1_gej_clear(&rj);
+    secp256k1_ge_clear(&r);
+    secp256k1_scalar_clear(&sec);
+    return ret;
+}
+
+int secp256k1_bulletproof_rangeproof_verify(const secp256k1_context* ctx, const unsigned char *commit,
+ const unsigned char *proof, int plen) {
+    ARG_CHECK(ctx != NULL);
+    ARG_CHECK(commit != NULL);
+    ARG_CHECK(proof != NULL);
+    ARG_CHECK(secp256k1_bulletproof_context_is_built(&ctx->bulletproof_ctx));
+    if (plen != SECP256K1_BULLETPROOF_LEN) {
+        return 0;
+    }
+    return secp256k1_bulletproof_verify_impl(&ctx->bulletproof_ctx, &commit, &proof, 1, &ctx->error_callback);
+}
+
+int secp256k1_bulletproof_rangeproof_verify_multi(const secp256k1_context* ctx, const unsigned char * const *commits,
+ const unsigned char * const *proofs, const int *plens, int n) {
+    int i;
+    ARG_CHECK(ctx != NULL);
+    ARG_CHECK(n == 0 || commits != NULL);
+    ARG_CHECK(n == 0 || proofs != NULL);
+    ARG_CHECK(n == 0 || plens != NULL);
+    ARG_CHECK(n >= 0);
+    ARG_CHECK(secp256k1_bulletproof_context_is_built(&ctx->bulletproof_ctx));
+    for (i = 0; i < n; i++) {
+        if (plens[i] != SECP256K1_BULLETPROOF_LEN) {
+            return 0;
+        }
+    }
+    return secp256k1_bulletproof_verify_impl(&ctx->bulletproof_ctx, commits, proofs, n, &ctx->error_callback);
+}
+
+#endif
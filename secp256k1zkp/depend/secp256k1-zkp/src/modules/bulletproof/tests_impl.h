@@ -0,0 +1,154 @@
+/**********************************************************************
+ * Copyright (c) 2017 The Grin Developers                             *
+ * Distributed under the MIT software license, see the accompanying   *
+ * file COPYING or http://www.opensource.org/licenses/mit-license.php.*
+ **********************************************************************/
+
+#ifndef SECP256K1_MODULE_BULLETPROOF_TESTS
+#define SECP256K1_MODULE_BULLETPROOF_TESTS
+
+#include "include/secp256k1_bulletproof.h"
+
+void test_bulletproof_multiexp(void) {
+    secp256k1_ge points[70];
+    secp256k1_scalar scalars[70];
+    secp256k1_gej expected, res, tmp;
+    secp256k1_scalar zero;
+    int i;
+    secp256k1_scalar_set_int(&zero, 0);
+    secp256k1_gej_set_infinity(&expected);
+    for (i = 0; i < 70; i++) {
+        random_group_element_test(&points[i]);
+        random_scalar_order(&scalars[i]);
+        secp256k1_gej_set_ge(&tmp, &points[i]);
+        secp256k1_ecmult(&ctx->ecmult_ctx, &tmp, &tmp, &scalars[i], &zero);
+        secp256k1_gej_add_var(&expected, &expected, &tmp, NULL);
+    }
+    secp256k1_bulletproof_multiexp(&res, points, scalars, 70, &ctx->error_callback);
+    secp256k1_gej_neg(&expected, &expected);
+    secp256k1_gej_add_var(&res, &res, &expected, NULL);
+    CHECK(secp256k1_gej_is_infinity(&res));
+
+    /* the constant time one agrees, zero scalars and terms cancelling out
+     * included */
+    secp256k1_scalar_set_int(&scalars[3], 0);
+    secp256k1_scalar_negate(&scalars[5], &scalars[4]);
+    points[5] = points[4];
+    secp256k1_bulletproof_multiexp(&expected, points, scalars, 70, &ctx->error_callback);
+    secp256k1_bulletproof_multiexp_const(&res, points, scalars, 70);
+    secp256k1_gej_neg(&expected, &expected);
+    secp256k1_gej_add_var(&res, &res, &expected, NULL);
+    CHECK(secp256k1_gej_is_infinity(&res));
+    secp256k1_bulletproof_multiexp_const(&res, points, scalars, 1);
+    secp256k1_gej_set_ge(&tmp, &points[0]);
+    secp256k1_ecmult(&ctx->ecmult_ctx, &tmp, &tmp, &scalars[0], &zero);
+    secp256k1_gej_neg(&tmp, &tmp);
+    secp256k1_gej_add_var(&res, &res, &tmp, NULL);
+    CHECK(secp256k1_gej_is_infinity(&res));
+    secp256k1_bulletproof_multiexp_const(&res, &points[3], &scalars[3], 1);
+    CHECK(secp256k1_gej_is_infinity(&res));
+    secp256k1_bulletproof_multiexp_const(&res, &points[4], &scalars[4], 2);
+    CHECK(secp256k1_gej_is_infinity(&res));
+}
+
+void test_bulletproof_rangeproof(void) {
+    unsigned char proof[SECP256K1_BULLETPROOF_SIZE + 10];
+    unsigned char commit[33];
+    unsigned char commit2[33];
+    unsigned char blind[32];
+    unsigned char nonce[32];
+    secp256k1_scalar s;
+    uint64_t v;
+    int len;
+    int i;
+    static const uint64_t values[] = {0, 1, 5, 11, 65535, 65537, INT64_MAX, UINT64_MAX};
+
+    CHECK(SECP256K1_BULLETPROOF_SIZE == SECP256K1_BULLETPROOF_LEN);
+    for (i = 0; i < 8; i++) {
+        v = values[i];
+        random_scalar_order(&s);
+        secp256k1_scalar_get_b32(blind, &s);
+        secp256k1_rand256(nonce);
+        CHECK(secp256k1_pedersen_commit(ctx, commit, blind, v));
+        len = sizeof(proof);
+        CHECK(secp256k1_bulletproof_rangeproof_prove(ctx, proof, &len, v, blind, nonce));
+        CHECK(len == SECP256K1_BULLETPROOF_SIZE);
+        CHECK(secp256k1_bulletproof_rangeproof_verify(ctx, commit, proof, len));
+        CHECK(!secp256k1_bulletproof_rangeproof_verify(ctx, commit, proof, len - 1));
+
+        /* another commitment to the same value doesn't verify */
+        random_scalar_order(&s);
+        secp256k1_scalar_get_b32(blind, &s);
+        CHECK(secp256k1_pedersen_commit(ctx, commit2, blind, v));
+        CHECK(!secp256k1_bulletproof_rangeproof_verify(ctx, commit2, proof, len));
+
+        /* nor does a tampered proof */
+        proof[secp256k1_rand32() % len] ^= 1 << (secp256k1_rand32() % 8);
+        CHECK(!secp256k1_bulletproof_rangeproof_verify(ctx, commit, proof, len));
+    }
+
+    /* a too short buffer gets nothing */
+    len = SECP256K1_BULLETPROOF_SIZE - 1;
+    CHECK(!secp256k1_bulletproof_rangeproof_prove(ctx, proof, &len, 1, blind, nonce));
+}
+
+void test_bulletproof_rangeproof_multi(void) {
+    unsigned char proofs[4][SECP256K1_BULLETPROOF_SIZE];
+    unsigned char commits[4][33];
+    const unsigned char *cptrs[4];
+    const unsigned char *pptrs[4];
+    int plens[4];
+    unsigned char blind[32];
+    unsigned char nonce[32];
+    unsigned char tmp[33];
+    secp256k1_scalar s;
+    uint64_t v;
+    int i;
+
+    for (i = 0; i < 4; i++) {
+        v = secp256k1_rand32();
+        random_scalar_order(&s);
+        secp256k1_scalar_get_b32(blind, &s);
+        secp256k1_rand256(nonce);
+        CHECK(secp256k1_pedersen_commit(ctx, commits[i], blind, v));
+        plens[i] = SECP256K1_BULLETPROOF_SIZE;
+        CHECK(secp256k1_bulletproof_rangeproof_prove(ctx, proofs[i], &plens[i], v, blind, nonce));
+        cptrs[i] = commits[i];
+        pptrs[i] = proofs[i];
+    }
+    CHECK(secp256k1_bulletproof_rangeproof_verify_multi(ctx, cptrs, pptrs, plens, 4));
+    CHECK(secp256k1_bulletproof_rangeproof_verify_multi(ctx, cptrs, pptrs, plens, 1));
+    CHECK(secp256k1_bulletproof_rangeproof_verify_multi(ctx, NULL, NULL, NULL, 0));
+
+    /* proofs swapped between commitments */
+    cptrs[0] = commits[1];
+    cptrs[1] = commits[0];
+    CHECK(!secp256k1_bulletproof_rangeproof_verify_multi(ctx, cptrs, pptrs, plens, 4));
+    cptrs[0] = commits[0];
+    cptrs[1] = commits[1];
+
+    /* a wrong length */
+    plens[3] = SECP256K1_BULLETPROOF_SIZE - 1;
+    CHECK(!secp256k1_bulletproof_rangeproof_verify_multi(ctx, cptrs, pptrs, plens, 4));
+    plens[3] = SECP256K1_BULLETPROOF_SIZE;
+
+    /* a single tampered proof */
+    memcpy(tmp, &proofs[2][SECP256K1_BULLETPROOF_SIZE - 33], 33);
+    proofs[2][SECP256K1_BULLETPROOF_SIZE - 1] ^= 1;
+    CHECK(!secp256k1_bulletproof_rangeproof_verify_multi(ctx, cptrs, pptrs, plens, 4));
+    memcpy(&proofs[2][SECP256K1_BULLETPROOF_SIZE - 33], tmp, 33);
+    CHECK(secp256k1_bulletproof_rangeproof_verify_multi(ctx, cptrs, pptrs, plens, 4));
+}
+
+void run_bulletproof_tests(void) {
+    int i;
+    secp256k1_pedersen_context_initialize(ctx);
+    secp256k1_bulletproof_context_initialize(ctx);
+    test_bulletproof_multiexp();
+    for (i = 0; i < count / 16 + 1; i++) {
+        test_bulletproof_rangeproof();
+    }
+    test_bulletproof_rangeproof_multi();
+}
+
+#endif
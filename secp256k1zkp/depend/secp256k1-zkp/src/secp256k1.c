@@ -23,6 +23,10 @@
 # include "modules/rangeproof/rangeproof.h"
 #endif
 
+#ifdef ENABLE_MODULE_BULLETPROOF
+# include "modules/bulletproof/bulletproof.h"
+#endif
+
 #define ARG_CHECK(cond) do { \
     if (EXPECT(!(cond), 0)) { \
         secp256k1_callback_call(&ctx->illegal_callback, #cond); \
@@ -59,6 +63,9 @@ struct secp256k1_context_struct {
 #ifdef ENABLE_MODULE_RANGEPROOF
     secp256k1_pedersen_context pedersen_ctx;
     secp256k1_rangeproof_context rangeproof_ctx;
+#endif
+#ifdef ENABLE_MODULE_BULLETPROOF
+    secp256k1_bulletproof_context bulletproof_ctx;
 #endif
     secp256k1_callback illegal_callback;
     secp256k1_callback error_callback;
@@ -82,6 +89,9 @@ secp256k1_context* secp256k1_context_create(unsigned int flags) {
     secp256k1_pedersen_context_init(&ret->pedersen_ctx);
     secp256k1_rangeproof_context_init(&ret->rangeproof_ctx);
 #endif
+#ifdef ENABLE_MODULE_BULLETPROOF
+    secp256k1_bulletproof_context_init(&ret->bulletproof_ctx);
+#endif
 
     if (flags & SECP256K1_FLAGS_BIT_CONTEXT_SIGN) {
         secp256k1_ecmult_gen_context_build(&ret->ecmult_gen_ctx, &ret->error_callback);
@@ -102,6 +112,9 @@ secp256k1_context* secp256k1_context_clone(const secp256k1_context* ctx) {
 #ifdef ENABLE_MODULE_RANGEPROOF
     secp256k1_pedersen_context_clone(&ret->pedersen_ctx, &ctx->pedersen_ctx, &ctx->error_callback);
     secp256k1_rangeproof_context_clone(&ret->rangeproof_ctx, &ctx->rangeproof_ctx, &ctx->error_callback);
+#endif
+#ifdef ENABLE_MODULE_BULLETPROOF
+    secp256k1_bulletproof_context_clone(&ret->bulletproof_ctx, &ctx->bulletproof_ctx, &ctx->error_callback);
 #endif
     return ret;
 }
@@ -114,6 +127,9 @@ void secp256k1_context_destroy(secp256k1_context* ctx) {
         secp256k1_pedersen_context_clear(&ctx->pedersen_ctx);
         secp256k1_rangeproof_context_clear(&ctx->rangeproof_ctx);
 #endif
+#ifdef ENABLE_MODULE_BULLETPROOF
+        secp256k1_bulletproof_context_clear(&ctx->bulletproof_ctx);
+#endif
 
         free(ctx);
     }
@@ -584,3 +600,7 @@ int secp256k1_ec_pubkey_combine(const secp256k1_context* ctx, secp256k1_pubkey *
 #ifdef ENABLE_MODULE_RANGEPROOF
 # include "modules/rangeproof/main_impl.h"
 #endif
+
+#ifdef ENABLE_MODULE_BULLETPROOF
+# include "modules/bulletproof/main_impl.h"
+#endif
@@ -4441,6 +4441,10 @@ void run_ecdsa_openssl(void) {
 # include "modules/rangeproof/tests_impl.h"
 #endif
 
+#ifdef ENABLE_MODULE_BULLETPROOF
+# include "modules/bulletproof/tests_impl.h"
+#endif
+
 int main(int argc, char **argv) {
     unsigned char seed16[16] = {0};
     unsigned char run32[32] = {0};
@@ -4569,6 +4573,10 @@ int main(int argc, char **argv) {
     run_rangeproof_tests();
 #endif
 
+#ifdef ENABLE_MODULE_BULLETPROOF
+    run_bulletproof_tests();
+#endif
+
     secp256k1_rand256(run32);
     printf("random run = %02x%02x%02x%02x%02x%02x%02x%02x%02x%02x%02x%02x%02x%02x%02x%02x\n", run32[0], run32[1], run32[2], run32[3], run32[4], run32[5], run32[6], run32[7], run32[8], run32[9], run32[10], run32[11], run32[12], run32[13], run32[14], run32[15]);
 
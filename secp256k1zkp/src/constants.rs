@@ -50,6 +50,9 @@ pub const PEDERSEN_COMMITMENT_SIZE: usize = 33;
 /// The max size of a range proof
 pub const MAX_PROOF_SIZE: usize = 5134;
 
+/// The size of a bulletproof, whatever the value it proves in range
+pub const BULLET_PROOF_SIZE: usize = 688;
+
 /// The maximum size of a message embedded in a range proof
 pub const PROOF_MSG_SIZE: usize = 4096;
 
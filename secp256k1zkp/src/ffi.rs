@@ -59,6 +59,7 @@ pub struct Context(c_int);
 #[repr(C)]
 pub struct PublicKey([c_uchar; 64]);
 impl_array_newtype!(PublicKey, c_uchar, 64);
+impl_serde_seq!(PublicKey, c_uchar, 64);
 impl_raw_debug!(PublicKey);
 
 impl PublicKey {
@@ -82,12 +83,14 @@ impl hash::Hash for PublicKey {
 #[repr(C)]
 pub struct Signature([c_uchar; 64]);
 impl_array_newtype!(Signature, c_uchar, 64);
+impl_serde_seq!(Signature, c_uchar, 64);
 impl_raw_debug!(Signature);
 
 /// Library-internal representation of a Secp256k1 signature + recovery ID
 #[repr(C)]
 pub struct RecoverableSignature([c_uchar; 65]);
 impl_array_newtype!(RecoverableSignature, c_uchar, 65);
+impl_serde_seq!(RecoverableSignature, c_uchar, 65);
 impl_raw_debug!(RecoverableSignature);
 
 impl Signature {
@@ -116,6 +119,7 @@ impl RecoverableSignature {
 #[repr(C)]
 pub struct SharedSecret([c_uchar; 32]);
 impl_array_newtype!(SharedSecret, c_uchar, 32);
+impl_serde_seq!(SharedSecret, c_uchar, 32);
 impl_raw_debug!(SharedSecret);
 
 impl SharedSecret {
@@ -145,6 +149,7 @@ extern "C" {
 
     pub fn secp256k1_pedersen_context_initialize(ctx: *mut Context);
     pub fn secp256k1_rangeproof_context_initialize(ctx: *mut Context);
+    pub fn secp256k1_bulletproof_context_initialize(ctx: *mut Context);
 
     // TODO secp256k1_context_set_illegal_callback
     // TODO secp256k1_context_set_error_callback
@@ -378,4 +383,25 @@ extern "C" {
                                      min_bits: c_int,
                                      value: uint64_t)
                                      -> c_int;
+
+    pub fn secp256k1_bulletproof_rangeproof_prove(ctx: *const Context,
+                                                  proof: *mut c_uchar,
+                                                  plen: *mut c_int,
+                                                  value: uint64_t,
+                                                  blind: *const c_uchar,
+                                                  nonce: *const c_uchar)
+                                                  -> c_int;
+
+    pub fn secp256k1_bulletproof_rangeproof_verify(ctx: *const Context,
+                                                   commit: *const c_uchar,
+                                                   proof: *const c_uchar,
+                                                   plen: c_int)
+                                                   -> c_int;
+
+    pub fn secp256k1_bulletproof_rangeproof_verify_multi(ctx: *const Context,
+                                                         commits: *const *const c_uchar,
+                                                         proofs: *const *const c_uchar,
+                                                         plens: *const c_int,
+                                                         n: c_int)
+                                                         -> c_int;
 }
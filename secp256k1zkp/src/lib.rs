@@ -49,7 +49,7 @@ extern crate libc;
 extern crate rand;
 
 use libc::size_t;
-use std::{error, fmt, ops, ptr};
+use std::{error, fmt, ops, ptr, str};
 use rand::Rng;
 
 #[macro_use]
@@ -181,6 +181,28 @@ impl Signature {
     }
 }
 
+/// Displays the signature as the lowercase hex of its DER encoding
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secp = Secp256k1::with_caps(ContextFlag::None);
+        for i in self.serialize_der(&secp) {
+            try!(write!(f, "{:02x}", i));
+        }
+        Ok(())
+    }
+}
+
+impl str::FromStr for Signature {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Signature, Error> {
+        let secp = Secp256k1::with_caps(ContextFlag::None);
+        Signature::from_der(&secp, &try!(from_hex(s)))
+    }
+}
+
+impl_serde_string!(Signature);
+
 /// Creates a new signature from a FFI signature
 impl From<ffi::Signature> for Signature {
     #[inline]
@@ -300,6 +322,7 @@ impl ops::Index<ops::RangeFull> for Signature {
 /// A (hashed) message input to an ECDSA signature
 pub struct Message([u8; constants::MESSAGE_SIZE]);
 impl_array_newtype!(Message, u8, constants::MESSAGE_SIZE);
+impl_serde_seq!(Message, u8, constants::MESSAGE_SIZE);
 impl_pretty_debug!(Message);
 
 impl Message {
@@ -341,6 +364,8 @@ pub enum Error {
     IncorrectCommitSum,
     /// Range proof is invalid
     InvalidRangeProof,
+    /// Text isn't the lowercase hex encoding of the expected bytes
+    InvalidHex,
 }
 
 // Passthrough Debug to Display, since errors should be user-visible
@@ -366,8 +391,31 @@ impl error::Error for Error {
             Error::InvalidRecoveryId => "secp: bad recovery id",
             Error::IncorrectCommitSum => "secp: invalid pedersen commitment sum",
             Error::InvalidRangeProof => "secp: invalid range proof",
+            Error::InvalidHex => "secp: malformed hex string",
+        }
+    }
+}
+
+/// Decodes lowercase hex, the only text encoding we display our types with
+fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+    if s.len() % 2 != 0 {
+        return Err(Error::InvalidHex);
+    }
+    let mut ret = Vec::with_capacity(s.len() / 2);
+    let mut hi = 0;
+    for (i, c) in s.bytes().enumerate() {
+        let n = match c {
+            b'0'...b'9' => c - b'0',
+            b'a'...b'f' => c - b'a' + 10,
+            _ => return Err(Error::InvalidHex),
+        };
+        if i % 2 == 0 {
+            hi = n << 4;
+        } else {
+            ret.push(hi | n);
         }
     }
+    Ok(ret)
 }
 
 /// The secp256k1 engine, used to execute all signature operations
@@ -455,6 +503,7 @@ impl Secp256k1 {
             unsafe {
                 ffi::secp256k1_pedersen_context_initialize(ctx);
                 ffi::secp256k1_rangeproof_context_initialize(ctx);
+                ffi::secp256k1_bulletproof_context_initialize(ctx);
             }
         }
         Secp256k1 {
@@ -596,7 +645,7 @@ mod tests {
     use super::constants;
     use super::{Secp256k1, Signature, RecoverableSignature, Message, RecoveryId, ContextFlag};
     use super::Error::{InvalidMessage, InvalidPublicKey, IncorrectSignature, InvalidSignature,
-                       IncapableContext};
+                       IncapableContext, InvalidHex};
 
     macro_rules! hex (($hex:expr) => ($hex.from_hex().unwrap()));
 
@@ -715,6 +764,51 @@ mod tests {
         }
     }
 
+    #[test]
+    fn signature_hex_roundtrip() {
+        use json;
+
+        let s = Secp256k1::new();
+        let mut msg = [0; 32];
+        thread_rng().fill_bytes(&mut msg);
+        let msg = Message::from_slice(&msg).unwrap();
+        let (sk, _) = s.generate_keypair(&mut thread_rng()).unwrap();
+        let sig = s.sign(&msg, &sk).unwrap();
+
+        let hex = sig.to_string();
+        assert_eq!(hex.from_hex().unwrap(), sig.serialize_der(&s));
+        assert_eq!(hex.parse::<Signature>(), Ok(sig));
+        assert_eq!(hex.to_uppercase().parse::<Signature>(), Err(InvalidHex));
+        assert_eq!(hex[1..].parse::<Signature>(), Err(InvalidHex));
+        assert_eq!("3006".parse::<Signature>(), Err(InvalidSignature));
+
+        let encoded = json::to_string(&sig).unwrap();
+        assert_eq!(encoded, format!("\"{}\"", hex));
+        assert_eq!(json::from_str::<Signature>(&encoded).ok(), Some(sig));
+        assert!(json::from_str::<Signature>("\"zz\"").is_err());
+    }
+
+    #[test]
+    fn commitment_hex_roundtrip() {
+        use json;
+        use pedersen::Commitment;
+
+        let mut bytes = [0; constants::PEDERSEN_COMMITMENT_SIZE];
+        thread_rng().fill_bytes(&mut bytes);
+        let commit = Commitment(bytes);
+
+        let hex = commit.to_string();
+        assert_eq!(hex.len(), 2 * constants::PEDERSEN_COMMITMENT_SIZE);
+        assert_eq!(hex.from_hex().unwrap(), bytes.to_vec());
+        assert_eq!(hex.parse::<Commitment>(), Ok(commit));
+        assert_eq!(hex[2..].parse::<Commitment>(), Err(InvalidHex));
+        assert_eq!(format!("{}00", hex).parse::<Commitment>(), Err(InvalidHex));
+
+        let encoded = json::to_string(&commit).unwrap();
+        assert_eq!(encoded, format!("\"{}\"", hex));
+        assert_eq!(json::from_str::<Commitment>(&encoded).ok(), Some(commit));
+    }
+
     #[test]
     fn signature_lax_der() {
         macro_rules! check_lax_sig(
@@ -143,7 +143,12 @@ macro_rules! impl_array_newtype {
                 self[..].encode(s)
             }
         }
+    }
+}
 
+// Serializes a byte array newtype as the sequence of its bytes
+macro_rules! impl_serde_seq {
+    ($thing:ident, $ty:ty, $len:expr) => {
         impl ::serde::Deserialize for $thing {
             fn deserialize<D>(d: &mut D) -> Result<$thing, D::Error>
                 where D: ::serde::Deserializer
@@ -218,6 +223,69 @@ macro_rules! impl_raw_debug {
      }
 }
 
+// Displays a byte array newtype as lowercase hex and parses it back
+macro_rules! impl_hex_string {
+    ($thing:ident, $len:expr) => {
+        impl ::std::fmt::Display for $thing {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                for i in self[..].iter().cloned() {
+                    try!(write!(f, "{:02x}", i));
+                }
+                Ok(())
+            }
+        }
+
+        impl ::std::str::FromStr for $thing {
+            type Err = ::Error;
+
+            fn from_str(s: &str) -> Result<$thing, ::Error> {
+                let bytes = try!(::from_hex(s));
+                if bytes.len() != $len {
+                    return Err(::Error::InvalidHex);
+                }
+                let mut ret = [0; $len];
+                ret.copy_from_slice(&bytes);
+                Ok($thing(ret))
+            }
+        }
+    }
+}
+
+// Serializes anything with a Display and FromStr as its string
+macro_rules! impl_serde_string {
+    ($thing:ident) => {
+        impl ::serde::Deserialize for $thing {
+            fn deserialize<D>(d: &mut D) -> Result<$thing, D::Error>
+                where D: ::serde::Deserializer
+            {
+                struct Visitor {
+                    marker: ::std::marker::PhantomData<$thing>,
+                }
+                impl ::serde::de::Visitor for Visitor {
+                    type Value = $thing;
+
+                    #[inline]
+                    fn visit_str<E>(&mut self, v: &str) -> Result<$thing, E>
+                        where E: ::serde::de::Error
+                    {
+                        v.parse().map_err(|e: ::Error| E::syntax(&e.to_string()))
+                    }
+                }
+
+                d.visit(Visitor { marker: ::std::marker::PhantomData })
+            }
+        }
+
+        impl ::serde::Serialize for $thing {
+            fn serialize<S>(&self, s: &mut S) -> Result<(), S::Error>
+                where S: ::serde::Serializer
+            {
+                s.visit_str(&self.to_string())
+            }
+        }
+    }
+}
+
 macro_rules! map_vec {
   ($thing:expr, $mapfn:expr ) => {
     $thing.iter()
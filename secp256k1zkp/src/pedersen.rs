@@ -31,6 +31,8 @@ use rand::{Rng, OsRng};
 pub struct Commitment(pub [u8; constants::PEDERSEN_COMMITMENT_SIZE]);
 impl_array_newtype!(Commitment, u8, constants::PEDERSEN_COMMITMENT_SIZE);
 impl_pretty_debug!(Commitment);
+impl_hex_string!(Commitment, constants::PEDERSEN_COMMITMENT_SIZE);
+impl_serde_string!(Commitment);
 
 impl Commitment {
     /// Uninitialized commitment, use with caution
@@ -282,6 +284,85 @@ impl Secp256k1 {
         }
     }
 
+    /// Produces a bulletproof that the value is within [0, 2^64), relying on the blinding factor
+    /// of its commitment. Always BULLET_PROOF_SIZE bytes long, a fraction of a range proof.
+    /// Fails with an invalid blinding factor, or one committing to nothing with the value.
+    pub fn bullet_proof(&self, value: u64, blind: SecretKey) -> Result<RangeProof, Error> {
+        let mut rng = OsRng::new().unwrap();
+        let mut nonce = [0u8; 32];
+
+        let mut retried = false;
+        let mut proof = [0; constants::MAX_PROOF_SIZE];
+        let mut plen = constants::MAX_PROOF_SIZE as i32;
+        loop {
+            rng.fill_bytes(&mut nonce);
+            let success = unsafe {
+                // can fail with probability around one in 2^128, retrying
+                // once with another nonce then
+                ffi::secp256k1_bulletproof_rangeproof_prove(self.ctx,
+                                                            proof.as_mut_ptr(),
+                                                            &mut plen,
+                                                            value,
+                                                            blind.as_ptr(),
+                                                            nonce.as_ptr()) == 1
+            };
+            if success {
+                break;
+            }
+            if retried {
+                return Err(Error::InvalidRangeProof);
+            }
+            retried = true;
+            plen = constants::MAX_PROOF_SIZE as i32;
+        }
+        Ok(RangeProof {
+            proof: proof,
+            plen: plen as usize,
+        })
+    }
+
+    /// Verify a bulletproof that a committed value is within [0, 2^64).
+    pub fn verify_bullet_proof(&self, commit: Commitment, proof: RangeProof) -> Result<(), Error> {
+        let success = unsafe {
+            ffi::secp256k1_bulletproof_rangeproof_verify(self.ctx,
+                                                         commit.as_ptr(),
+                                                         proof.proof.as_ptr(),
+                                                         proof.plen as i32) == 1
+        };
+        if success {
+            Ok(())
+        } else {
+            Err(Error::InvalidRangeProof)
+        }
+    }
+
+    /// Verify many bulletproofs at once, each against the commitment at the same
+    /// position, all in a single multi-exponentiation. Much faster than verifying
+    /// them one by one but doesn't tell which one failed.
+    pub fn verify_bullet_proof_multi(&self,
+                                     commits: Vec<Commitment>,
+                                     proofs: Vec<RangeProof>)
+                                     -> Result<(), Error> {
+        if commits.len() != proofs.len() {
+            return Err(Error::InvalidRangeProof);
+        }
+        let cptrs = map_vec!(commits, |c| c.0.as_ptr());
+        let pptrs = map_vec!(proofs, |p| p.proof.as_ptr());
+        let plens = map_vec!(proofs, |p| p.plen as i32);
+        let success = unsafe {
+            ffi::secp256k1_bulletproof_rangeproof_verify_multi(self.ctx,
+                                                               cptrs.as_ptr(),
+                                                               pptrs.as_ptr(),
+                                                               plens.as_ptr(),
+                                                               cptrs.len() as i32) == 1
+        };
+        if success {
+            Ok(())
+        } else {
+            Err(Error::InvalidRangeProof)
+        }
+    }
+
     /// Verify a range proof proof and rewind the proof to recover information sent by its author.
     pub fn rewind_range_proof(&self,
                               commit: Commitment,
@@ -30,6 +30,8 @@ use std::{mem, ptr};
 pub struct Signature([u8; constants::SCHNORR_SIGNATURE_SIZE]);
 impl_array_newtype!(Signature, u8, constants::SCHNORR_SIGNATURE_SIZE);
 impl_pretty_debug!(Signature);
+impl_hex_string!(Signature, constants::SCHNORR_SIGNATURE_SIZE);
+impl_serde_string!(Signature);
 
 impl Signature {
     /// Deserializes a signature from a 64-byte vector
@@ -123,7 +125,7 @@ mod tests {
     use ContextFlag;
     use Message;
     use Secp256k1;
-    use Error::IncapableContext;
+    use Error::{IncapableContext, InvalidHex};
     use super::Signature;
 
     #[test]
@@ -194,4 +196,21 @@ mod tests {
         let sig2 = Signature::deserialize(&sig1.serialize());
         assert_eq!(sig1, sig2);
     }
+
+    #[test]
+    fn hex_roundtrip() {
+        let s = Secp256k1::new();
+
+        let mut msg = [0u8; 32];
+        thread_rng().fill_bytes(&mut msg);
+        let msg = Message::from_slice(&msg).unwrap();
+
+        let (sk, _) = s.generate_keypair(&mut thread_rng()).unwrap();
+
+        let sig = s.sign_schnorr(&msg, &sk).unwrap();
+        let hex = sig.to_string();
+        assert_eq!(hex.len(), 128);
+        assert_eq!(hex.parse::<Signature>(), Ok(sig));
+        assert_eq!(hex[..126].parse::<Signature>(), Err(InvalidHex));
+    }
 }
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Storage of core types using RocksDB.
+//! Storage of core types using RocksDB, optionally splitting the keys in
+//! column families by prefix, or LMDB when built with the `lmdb_backend`
+//! feature.
 
 #![deny(non_upper_case_globals)]
 #![deny(non_camel_case_types)]
@@ -21,13 +23,34 @@
 #![warn(missing_docs)]
 
 extern crate grin_core as core;
+#[cfg(feature = "lmdb_backend")]
+extern crate lmdb;
+#[cfg(feature = "lmdb_backend")]
+extern crate lmdb_sys;
 extern crate rocksdb;
 
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::path::Path;
 use std::sync::RwLock;
 
 use core::ser;
 
-use rocksdb::{DB, Options, Writable, DBCompactionStyle};
+#[cfg(feature = "lmdb_backend")]
+use lmdb::{Cursor, Transaction};
+use rocksdb::{DB, WriteBatch, Options, Writable, DBCompactionStyle, IteratorMode, Direction,
+              Column};
+
+/// Size of the memory map LMDB reads the db through, the most the db can
+/// ever grow to. Only address space gets reserved upfront.
+#[cfg(feature = "lmdb_backend")]
+const LMDB_MAP_SIZE: usize = 1 << 40;
+
+/// Number of entries copied to a backup in each of its writes.
+const BACKUP_BATCH_SIZE: usize = 1000;
 
 /// Main error type for this crate.
 #[derive(Debug)]
@@ -35,8 +58,13 @@ pub enum Error {
 	/// Wraps an error originating from RocksDB (which unfortunately returns
 	/// string errors).
 	RocksDbErr(String),
+	/// Wraps an error originating from LMDB, or using it when it isn't
+	/// built in
+	LmdbErr(String),
 	/// Wraps a serialization error for Writeable or Readable
 	SerErr(ser::Error),
+	/// Wraps an error accessing the db files directly
+	IOErr(io::Error),
 }
 
 impl From<String> for Error {
@@ -45,9 +73,40 @@ impl From<String> for Error {
 	}
 }
 
-/// Thread-safe rocksdb wrapper
+/// Database engines a store can keep its data in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+	/// RocksDB, the default
+	RocksDb,
+	/// RocksDB with a column family per key prefix, as provided when opening
+	/// the store. Each family gets compacted on its own, rewriting less data
+	/// on every compaction.
+	RocksDbColumns,
+	/// LMDB, reading through a memory map and writing one transaction at a
+	/// time. Requires the `lmdb_backend` feature.
+	Lmdb,
+}
+
+impl Default for Backend {
+	fn default() -> Backend {
+		Backend::RocksDb
+	}
+}
+
+// the underlying database of a store, depending on its backend
+enum Db {
+	RocksDb(RwLock<DB>),
+	RocksDbColumns(RwLock<DB>, Vec<(u8, Column)>),
+	#[cfg(feature = "lmdb_backend")]
+	Lmdb(lmdb::Environment, lmdb::Database),
+}
+
+/// Thread-safe wrapper of the database backing the store
 pub struct Store {
-	rdb: RwLock<DB>,
+	db: Db,
+	path: String,
+	backend: Backend,
+	columns: Vec<(u8, String)>,
 }
 
 unsafe impl Sync for Store {}
@@ -56,19 +115,99 @@ unsafe impl Send for Store {}
 impl Store {
 	/// Opens a new RocksDB at the specified location.
 	pub fn open(path: &str) -> Result<Store, Error> {
-		let mut opts = Options::new();
-		opts.create_if_missing(true);
-		opts.set_compaction_style(DBCompactionStyle::DBUniversalCompaction);
-		opts.set_max_open_files(256);
-		opts.set_use_fsync(false);
-		let db = try!(DB::open(&opts, &path));
-		Ok(Store { rdb: RwLock::new(db) })
+		Store::open_with(path, Backend::RocksDb)
+	}
+
+	/// Opens a new db at the specified location, with the provided backend.
+	pub fn open_with(path: &str, backend: Backend) -> Result<Store, Error> {
+		Store::open_with_columns(path, backend, &[])
+	}
+
+	/// Opens a new db at the specified location, with the provided backend.
+	/// With the `RocksDbColumns` backend, keys starting with one of the
+	/// prefixes go in the column family named after it and all others in the
+	/// default one. Other backends keep all keys together.
+	pub fn open_with_columns(path: &str,
+	                         backend: Backend,
+	                         columns: &[(u8, &str)])
+	                         -> Result<Store, Error> {
+		let db = match backend {
+			Backend::RocksDb => Db::RocksDb(RwLock::new(try!(DB::open(&rocksdb_opts(), &path)))),
+			Backend::RocksDbColumns => try!(open_rocksdb_columns(path, columns)),
+			Backend::Lmdb => try!(open_lmdb(path)),
+		};
+		Ok(Store {
+			db: db,
+			path: path.to_string(),
+			backend: backend,
+			columns: columns.iter().map(|&(prefix, name)| (prefix, name.to_string())).collect(),
+		})
+	}
+
+	/// Total size of the db files, in bytes.
+	pub fn size_on_disk(&self) -> Result<u64, Error> {
+		let entries = match fs::read_dir(&self.path) {
+			Ok(entries) => entries,
+			// nothing written to disk yet
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+			Err(e) => return Err(Error::IOErr(e)),
+		};
+		let mut size = 0;
+		for entry in entries {
+			let meta = try!(entry.and_then(|e| e.metadata()).map_err(Error::IOErr));
+			if meta.is_file() {
+				size += meta.len();
+			}
+		}
+		Ok(size)
+	}
+
+	/// Compacts the db, reclaiming the space of deleted entries right away
+	/// instead of whenever RocksDB gets to it. LMDB reuses the pages of
+	/// deleted entries for new ones, there's nothing to do with it.
+	pub fn compact(&self) -> Result<(), Error> {
+		match self.db {
+			Db::RocksDb(ref rdb) => rdb.read().unwrap().compact_range(None, None),
+			Db::RocksDbColumns(ref rdb, ref cols) => {
+				let db = rdb.read().unwrap();
+				db.compact_range(None, None);
+				for &(_, col) in cols {
+					db.compact_range_cf(col, None, None);
+				}
+			}
+			#[cfg(feature = "lmdb_backend")]
+			Db::Lmdb(..) => {}
+		}
+		Ok(())
+	}
+
+	/// Copies the whole db to a new one at the provided location, with the
+	/// same backend and column families. The copy is of the db as it was
+	/// when the backup started, writes to the db carry on in the meantime.
+	pub fn backup(&self, path: &str) -> Result<(), Error> {
+		if Path::new(path).exists() {
+			return Err(Error::IOErr(io::Error::new(io::ErrorKind::AlreadyExists,
+			                                       format!("{} already exists", path))));
+		}
+		let columns = self.columns
+			.iter()
+			.map(|&(prefix, ref name)| (prefix, name.as_str()))
+			.collect::<Vec<_>>();
+		let backup = try!(Store::open_with_columns(path, self.backend, &columns));
+		let mut ops = vec![];
+		try!(self.each_entry(|key, value| {
+			ops.push((key.to_vec(), Some(value.to_vec())));
+			if ops.len() >= BACKUP_BATCH_SIZE {
+				try!(backup.write(mem::replace(&mut ops, vec![])));
+			}
+			Ok(())
+		}));
+		backup.write(ops)
 	}
 
 	/// Writes a single key/value pair to the db
 	pub fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
-		let db = self.rdb.write().unwrap();
-		db.put(key, &value[..]).map_err(Error::RocksDbErr)
+		self.write(vec![(key.to_vec(), Some(value))])
 	}
 
 	/// Writes a single key and its `Writeable` value to the db. Encapsulates
@@ -83,8 +222,29 @@ impl Store {
 
 	/// Gets a value from the db, provided its key
 	pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
-		let db = self.rdb.read().unwrap();
-		db.get(key).map(|r| r.map(|o| o.to_vec())).map_err(Error::RocksDbErr)
+		match self.db {
+			Db::RocksDb(ref rdb) => {
+				let db = rdb.read().unwrap();
+				db.get(key).map(|r| r.map(|o| o.to_vec())).map_err(Error::RocksDbErr)
+			}
+			Db::RocksDbColumns(ref rdb, ref cols) => {
+				let db = rdb.read().unwrap();
+				let res = match column_of(cols, key) {
+					Some(col) => db.get_cf(col, key),
+					None => db.get(key),
+				};
+				res.map(|r| r.map(|o| o.to_vec())).map_err(Error::RocksDbErr)
+			}
+			#[cfg(feature = "lmdb_backend")]
+			Db::Lmdb(ref env, db) => {
+				let txn = try!(env.begin_ro_txn().map_err(&to_lmdb_err));
+				match txn.get(db, &key) {
+					Ok(value) => Ok(Some(value.to_vec())),
+					Err(lmdb::Error::NotFound) => Ok(None),
+					Err(e) => Err(to_lmdb_err(e)),
+				}
+			}
+		}
 	}
 
 	/// Gets a `Readable` value from the db, provided its key. Encapsulates
@@ -113,7 +273,368 @@ impl Store {
 
 	/// Deletes a key/value pair from the db
 	pub fn delete(&self, key: &[u8]) -> Result<(), Error> {
-		let db = self.rdb.write().unwrap();
-		db.delete(key).map_err(Error::RocksDbErr)
+		self.write(vec![(key.to_vec(), None)])
+	}
+
+	/// Builds a new batch of writes to the db, only applied when the batch is
+	/// written.
+	pub fn batch(&self) -> Batch {
+		Batch {
+			store: self,
+			ops: RefCell::new(vec![]),
+		}
+	}
+
+	/// Produces an iterator of `Readable` types moving forward from the
+	/// provided key prefix. The iteration stops at the first key that doesn't
+	/// start with the prefix, values that can't be deserialized coming as
+	/// errors.
+	pub fn iter<T: ser::Readable<T>>(&self, prefix: &[u8]) -> SerIterator<T> {
+		SerIterator {
+			iter: self.raw_iter(prefix),
+			prefix: prefix.to_vec(),
+			_marker: PhantomData,
+		}
+	}
+
+	/// Same as iter, producing the key along with each value.
+	pub fn iter_with_keys<T: ser::Readable<T>>(&self, prefix: &[u8]) -> SerKeyIterator<T> {
+		SerKeyIterator(self.iter(prefix))
+	}
+
+	/// Produces an iterator of the raw keys and values of all the entries
+	/// whose key starts with the provided prefix, in key order. Entries are
+	/// read as the iteration goes.
+	pub fn iter_prefix(&self, key_prefix: &[u8]) -> RawIterator {
+		RawIterator {
+			iter: self.raw_iter(key_prefix),
+			prefix: key_prefix.to_vec(),
+			to: None,
+		}
+	}
+
+	/// Produces an iterator of the raw keys and values of all the entries
+	/// with a key from the provided one included up to the second one
+	/// excluded, in key order. Both keys are expected to start with the same
+	/// prefix byte, as all keys of an index do.
+	pub fn iter_range(&self, from: &[u8], to: &[u8]) -> RawIterator {
+		RawIterator {
+			iter: self.raw_iter(from),
+			prefix: vec![],
+			to: Some(to.to_vec()),
+		}
+	}
+
+	// iterates over the entries from the provided key on, to the end of the
+	// column family it's in if any
+	fn raw_iter(&self, from: &[u8]) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)>> {
+		match self.db {
+			Db::RocksDb(ref rdb) => {
+				let db = rdb.read().unwrap();
+				Box::new(db.iterator(IteratorMode::From(from, Direction::Forward)))
+			}
+			Db::RocksDbColumns(ref rdb, ref cols) => {
+				let db = rdb.read().unwrap();
+				let mode = IteratorMode::From(from, Direction::Forward);
+				match column_of(cols, from) {
+					// a family that can't be read through has nothing to iterate
+					Some(col) => {
+						match db.iterator_cf(col, mode) {
+							Ok(iter) => Box::new(iter),
+							Err(_) => Box::new(vec![].into_iter()),
+						}
+					}
+					None => Box::new(db.iterator(mode)),
+				}
+			}
+			#[cfg(feature = "lmdb_backend")]
+			Db::Lmdb(ref env, db) => Box::new(lmdb_from(env, db, from).into_iter()),
+		}
+	}
+
+	// calls the provided function with every entry of the db, all read from
+	// the db as it was when called
+	fn each_entry<F>(&self, mut f: F) -> Result<(), Error>
+		where F: FnMut(&[u8], &[u8]) -> Result<(), Error>
+	{
+		match self.db {
+			Db::RocksDb(ref rdb) => {
+				// an iterator reads from a snapshot of the db taken when created
+				let iter = rdb.read().unwrap().iterator(IteratorMode::Start);
+				for (key, value) in iter {
+					try!(f(&key, &value));
+				}
+			}
+			Db::RocksDbColumns(ref rdb, ref cols) => {
+				// no write can come in between the iterators of all families
+				// while they get created under the lock
+				let mut iters = vec![];
+				{
+					let db = rdb.read().unwrap();
+					iters.push(db.iterator(IteratorMode::Start));
+					for &(_, col) in cols {
+						iters.push(try!(db.iterator_cf(col, IteratorMode::Start)));
+					}
+				}
+				for (key, value) in iters.into_iter().flat_map(|iter| iter) {
+					try!(f(&key, &value));
+				}
+			}
+			#[cfg(feature = "lmdb_backend")]
+			Db::Lmdb(ref env, db) => {
+				let txn = try!(env.begin_ro_txn().map_err(&to_lmdb_err));
+				let cursor = try!(txn.open_ro_cursor(db).map_err(&to_lmdb_err));
+				let mut next = cursor.get(None, None, lmdb_sys::MDB_FIRST);
+				while let Ok((Some(key), value)) = next {
+					try!(f(key, value));
+					next = cursor.get(None, None, lmdb_sys::MDB_NEXT);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	// applies all the provided puts, or deletes for none values, atomically
+	fn write(&self, ops: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<(), Error> {
+		match self.db {
+			Db::RocksDb(ref rdb) => {
+				let batch = WriteBatch::new();
+				for (key, value) in ops {
+					match value {
+						Some(value) => try!(batch.put(&key[..], &value[..])),
+						None => try!(batch.delete(&key[..])),
+					}
+				}
+				let db = rdb.write().unwrap();
+				db.write(batch).map_err(Error::RocksDbErr)
+			}
+			Db::RocksDbColumns(ref rdb, ref cols) => {
+				let batch = WriteBatch::new();
+				for (key, value) in ops {
+					let res = match (column_of(cols, &key), value) {
+						(Some(col), Some(value)) => batch.put_cf(col, &key[..], &value[..]),
+						(Some(col), None) => batch.delete_cf(col, &key[..]),
+						(None, Some(value)) => batch.put(&key[..], &value[..]),
+						(None, None) => batch.delete(&key[..]),
+					};
+					try!(res);
+				}
+				let db = rdb.write().unwrap();
+				db.write(batch).map_err(Error::RocksDbErr)
+			}
+			#[cfg(feature = "lmdb_backend")]
+			Db::Lmdb(ref env, db) => {
+				// waits for any other write transaction to be done
+				let mut txn = try!(env.begin_rw_txn().map_err(&to_lmdb_err));
+				for (key, value) in ops {
+					let res = match value {
+						Some(value) => txn.put(db, &key, &value, lmdb::WriteFlags::empty()),
+						// deleting a missing key is fine, as it is with RocksDB
+						None => {
+							match txn.del(db, &key, None) {
+								Err(lmdb::Error::NotFound) => Ok(()),
+								res => res,
+							}
+						}
+					};
+					try!(res.map_err(&to_lmdb_err));
+				}
+				txn.commit().map_err(&to_lmdb_err)
+			}
+		}
+	}
+}
+
+fn rocksdb_opts() -> Options {
+	let mut opts = Options::new();
+	opts.create_if_missing(true);
+	opts.set_compaction_style(DBCompactionStyle::DBUniversalCompaction);
+	opts.set_max_open_files(256);
+	opts.set_use_fsync(false);
+	opts
+}
+
+fn open_rocksdb_columns(path: &str, columns: &[(u8, &str)]) -> Result<Db, Error> {
+	let names = columns.iter().map(|&(_, name)| name).collect::<Vec<_>>();
+	let cf_opts = columns.iter().map(|_| rocksdb_opts()).collect::<Vec<_>>();
+	// all families have to be named when opening, which fails on a new db
+	// that doesn't have them yet
+	let db = match DB::open_cf(&rocksdb_opts(), path, &names, &cf_opts) {
+		Ok(db) => db,
+		Err(_) => {
+			let mut db = try!(DB::open(&rocksdb_opts(), path));
+			for name in &names {
+				try!(db.create_cf(name, &rocksdb_opts()));
+			}
+			db
+		}
+	};
+	let mut cols = vec![];
+	for &(prefix, name) in columns {
+		match db.cf_handle(name) {
+			Some(col) => cols.push((prefix, col)),
+			None => return Err(Error::RocksDbErr(format!("missing column family {}", name))),
+		}
+	}
+	Ok(Db::RocksDbColumns(RwLock::new(db), cols))
+}
+
+// the column family a key goes in, from its first byte
+fn column_of(cols: &[(u8, Column)], key: &[u8]) -> Option<Column> {
+	key.first().and_then(|b| cols.iter().find(|&&(prefix, _)| prefix == *b)).map(|&(_, col)| col)
+}
+
+#[cfg(feature = "lmdb_backend")]
+fn open_lmdb(path: &str) -> Result<Db, Error> {
+	try!(fs::create_dir_all(path).map_err(Error::IOErr));
+	let env = try!(lmdb::Environment::new()
+		.set_map_size(LMDB_MAP_SIZE)
+		.open(Path::new(path))
+		.map_err(&to_lmdb_err));
+	let db = try!(env.open_db(None).map_err(&to_lmdb_err));
+	Ok(Db::Lmdb(env, db))
+}
+
+#[cfg(not(feature = "lmdb_backend"))]
+fn open_lmdb(_: &str) -> Result<Db, Error> {
+	Err(Error::LmdbErr("built without the lmdb_backend feature".to_string()))
+}
+
+// all the entries from the provided key on that start with the same byte,
+// read right away as nothing read can outlive its transaction
+#[cfg(feature = "lmdb_backend")]
+fn lmdb_from(env: &lmdb::Environment,
+             db: lmdb::Database,
+             from: &[u8])
+             -> Vec<(Box<[u8]>, Box<[u8]>)> {
+	let mut entries = vec![];
+	if let Ok(txn) = env.begin_ro_txn() {
+		if let Ok(cursor) = txn.open_ro_cursor(db) {
+			let mut next = cursor.get(Some(from), None, lmdb_sys::MDB_SET_RANGE);
+			while let Ok((Some(key), value)) = next {
+				if from.first().map_or(false, |b| key.first() != Some(b)) {
+					break;
+				}
+				entries.push((key.to_vec().into_boxed_slice(), value.to_vec().into_boxed_slice()));
+				next = cursor.get(None, None, lmdb_sys::MDB_NEXT);
+			}
+		}
+	}
+	entries
+}
+
+#[cfg(feature = "lmdb_backend")]
+fn to_lmdb_err(e: lmdb::Error) -> Error {
+	Error::LmdbErr(format!("{}", e))
+}
+
+/// Batch of writes to the db, applied atomically once written. Nothing is
+/// visible in the db before that.
+pub struct Batch<'a> {
+	store: &'a Store,
+	ops: RefCell<Vec<(Vec<u8>, Option<Vec<u8>>)>>,
+}
+
+impl<'a> Batch<'a> {
+	/// Adds a key and its `Writeable` value to the batch. Encapsulates
+	/// serialization.
+	pub fn put_ser(&self, key: &[u8], value: &ser::Writeable) -> Result<(), Error> {
+		let ser_value = try!(ser::ser_vec(value).map_err(Error::SerErr));
+		self.ops.borrow_mut().push((key.to_vec(), Some(ser_value)));
+		Ok(())
+	}
+
+	/// Adds a key and its raw value to the batch
+	pub fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
+		self.ops.borrow_mut().push((key.to_vec(), Some(value)));
+		Ok(())
+	}
+
+	/// Adds the deletion of a key to the batch
+	pub fn delete(&self, key: &[u8]) -> Result<(), Error> {
+		self.ops.borrow_mut().push((key.to_vec(), None));
+		Ok(())
+	}
+
+	/// Writes the whole batch to the db in a single atomic operation
+	pub fn write(self) -> Result<(), Error> {
+		self.store.write(self.ops.into_inner())
+	}
+}
+
+/// An iterator that produces Readable instances back. Wraps the lower level
+/// iterator of the db and deserializes the returned values, producing an
+/// error for those that can't be.
+pub struct SerIterator<T>
+	where T: ser::Readable<T>
+{
+	iter: Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)>>,
+	prefix: Vec<u8>,
+	_marker: PhantomData<T>,
+}
+
+impl<T> Iterator for SerIterator<T>
+	where T: ser::Readable<T>
+{
+	type Item = Result<T, Error>;
+
+	fn next(&mut self) -> Option<Result<T, Error>> {
+		self.next_with_key().map(|res| res.map(|(_, v)| v))
+	}
+}
+
+impl<T> SerIterator<T>
+	where T: ser::Readable<T>
+{
+	fn next_with_key(&mut self) -> Option<Result<(Vec<u8>, T), Error>> {
+		let next = self.iter.next();
+		next.and_then(|(k, v)| {
+			if k.starts_with(&self.prefix[..]) {
+				let res = ser::deserialize(&mut &v[..]).map_err(Error::SerErr);
+				Some(res.map(|v| (k.to_vec(), v)))
+			} else {
+				None
+			}
+		})
+	}
+}
+
+/// An iterator over the raw keys and values of the db, see
+/// `Store::iter_prefix` and `Store::iter_range`.
+pub struct RawIterator {
+	iter: Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)>>,
+	prefix: Vec<u8>,
+	to: Option<Vec<u8>>,
+}
+
+impl Iterator for RawIterator {
+	type Item = (Vec<u8>, Vec<u8>);
+
+	fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+		let next = self.iter.next();
+		next.and_then(|(k, v)| {
+			let in_range = match self.to {
+				Some(ref to) => &k[..] < &to[..],
+				None => true,
+			};
+			if k.starts_with(&self.prefix[..]) && in_range {
+				Some((k.to_vec(), v.to_vec()))
+			} else {
+				None
+			}
+		})
+	}
+}
+
+/// Same as SerIterator, producing each value along with its key.
+pub struct SerKeyIterator<T>(SerIterator<T>) where T: ser::Readable<T>;
+
+impl<T> Iterator for SerKeyIterator<T>
+	where T: ser::Readable<T>
+{
+	type Item = Result<(Vec<u8>, T), Error>;
+
+	fn next(&mut self) -> Option<Result<(Vec<u8>, T), Error>> {
+		self.0.next_with_key()
 	}
 }
@@ -16,6 +16,9 @@
 /// within the grin codebase.
 
 use std::cell::{RefCell, Ref};
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Iter;
+use std::hash::Hash;
 #[allow(unused_imports)]
 use std::ops::Deref;
 
@@ -47,3 +50,78 @@ impl<T> OneTime<T> {
         Ref::map(self.inner.borrow(), |o| o.as_ref().unwrap())
     }
 }
+
+/// Fixed capacity map evicting its least recently used entry when full. Cheap
+/// enough for the small caches we keep around (recently seen blocks and the
+/// like), lookups move the entry to the most recently used position.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    order: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    /// Builds a new empty cache holding at most capacity entries.
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity,
+            entries: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Inserts a new entry, evicting the least recently used one if the cache
+    /// is full.
+    pub fn insert(&mut self, k: K, v: V) {
+        if self.entries.insert(k.clone(), v).is_some() {
+            self.touch(&k);
+            return;
+        }
+        self.order.push_back(k);
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
+    }
+
+    /// Gets the entry for the provided key, marking it as most recently used.
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        if self.entries.contains_key(k) {
+            self.touch(k);
+        }
+        self.entries.get(k)
+    }
+
+    /// Whether the cache holds an entry for the provided key.
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    /// Removes the entry for the provided key, returning it if present.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let v = self.entries.remove(k);
+        if v.is_some() {
+            self.order.retain(|o| o != k);
+        }
+        v
+    }
+
+    /// Number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Iterates over all entries in the cache, in no particular order and
+    /// without affecting their recency.
+    pub fn iter(&self) -> Iter<K, V> {
+        self.entries.iter()
+    }
+
+    fn touch(&mut self, k: &K) {
+        if let Some(pos) = self.order.iter().position(|o| o == k) {
+            let o = self.order.remove(pos).unwrap();
+            self.order.push_back(o);
+        }
+    }
+}